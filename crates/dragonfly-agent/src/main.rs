@@ -1,6 +1,7 @@
 use reqwest::Client;
 use anyhow::{Result, Context};
-use dragonfly_common::models::{MachineStatus, DiskInfo, Machine, RegisterRequest, RegisterResponse, StatusUpdateRequest, OsInstalledUpdateRequest};
+use dragonfly_common::models::{MachineStatus, DiskInfo, Machine, RegisterRequest, RegisterResponse, StatusUpdateRequest, OsInstalledUpdateRequest, ClockReportRequest, ClockReportResponse, TpmIdentity, PciDevice, PciReportRequest};
+use dragonfly_common::agent_protocol::{self, truncate_output, AgentCommand, AttestationChallenge, CommandKind, CommandResult, EnrollRequest, EnrollResponse, TpmQuote};
 use std::env;
 use std::fs;
 use std::path::Path;
@@ -31,6 +32,14 @@ struct Args {
     /// Tinkerbell IPXE URL (default: http://10.7.1.30:8080/hookos.ipxe)
     #[arg(long, default_value = "http://10.7.1.30:8080/hookos.ipxe")]
     ipxe_url: String,
+
+    /// Keep running and send a signed heartbeat to the server every N seconds (ignored in setup mode; needs enrollment)
+    #[arg(long, value_name = "SECONDS")]
+    heartbeat: Option<u64>,
+
+    /// Enroll for remote commands with this token (requires --heartbeat); commands are polled with each heartbeat
+    #[arg(long, requires = "heartbeat")]
+    enrollment_token: Option<String>,
 }
 
 // Enhanced OS detection with support for more distributions
@@ -374,6 +383,159 @@ fn detect_nameservers() -> Vec<String> {
     nameservers
 }
 
+// Read a DMI field such as sys_vendor, skipping the placeholders vendors leave in unset fields
+fn read_dmi(field: &str) -> Option<String> {
+    let value = fs::read_to_string(Path::new("/sys/class/dmi/id").join(field)).ok()?;
+    let value = value.trim();
+    let placeholder = value.is_empty()
+        || ["to be filled by o.e.m.", "default string", "system product name", "system manufacturer", "system serial number", "not specified"]
+            .contains(&value.to_lowercase().as_str());
+    (!placeholder).then(|| value.to_string())
+}
+
+// Where distributions install the PCI ID database
+const PCI_IDS_PATHS: [&str; 3] = ["/usr/share/hwdata/pci.ids", "/usr/share/misc/pci.ids", "/usr/share/pci.ids"];
+
+// Enumerate PCI devices from sysfs: GPUs, NVMe drives, NICs (and whether they can do SR-IOV)
+fn detect_pci_devices() -> Vec<PciDevice> {
+    let Ok(entries) = fs::read_dir("/sys/bus/pci/devices") else {
+        return Vec::new();
+    };
+    // sysfs IDs look like "0x10de\n"
+    let read_id = |path: &Path, file: &str| {
+        fs::read_to_string(path.join(file)).ok().map(|s| s.trim().trim_start_matches("0x").to_lowercase())
+    };
+
+    let mut devices = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let (Some(class), Some(vendor_id), Some(device_id)) =
+            (read_id(&path, "class"), read_id(&path, "vendor"), read_id(&path, "device")) else {
+            continue;
+        };
+        devices.push(PciDevice {
+            address: entry.file_name().to_string_lossy().into_owned(),
+            // The class file also holds the programming interface, which we don't need
+            class: class.get(..4).unwrap_or(&class).to_string(),
+            vendor_id,
+            device_id,
+            vendor_name: None,
+            device_name: None,
+            driver: fs::read_link(path.join("driver")).ok()
+                .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned())),
+            sriov_total_vfs: read_id(&path, "sriov_totalvfs").and_then(|v| v.parse().ok()).filter(|&n: &u32| n > 0),
+        });
+    }
+    devices.sort_by(|a, b| a.address.cmp(&b.address));
+    name_pci_devices(&mut devices);
+
+    tracing::info!("Detected {} PCI devices", devices.len());
+    devices
+}
+
+// Fill in vendor and device names from pci.ids, when the image ships it
+fn name_pci_devices(devices: &mut [PciDevice]) {
+    let Some(db) = PCI_IDS_PATHS.iter().find_map(|p| fs::read_to_string(p).ok()) else {
+        return;
+    };
+    let mut vendor: Option<&str> = None;
+    for line in db.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Device classes follow the vendor list
+        if line.starts_with("C ") {
+            break;
+        }
+        if let Some(rest) = line.strip_prefix('\t') {
+            // Two tabs is a subsystem, which we don't name
+            if rest.starts_with('\t') {
+                continue;
+            }
+            let (Some(vendor_id), Some((id, name))) = (vendor, rest.split_once("  ")) else {
+                continue;
+            };
+            for device in devices.iter_mut().filter(|d| d.vendor_id == vendor_id && d.device_id == id) {
+                device.device_name = Some(name.to_string());
+            }
+        } else if let Some((id, name)) = line.split_once("  ") {
+            vendor = Some(id);
+            for device in devices.iter_mut().filter(|d| d.vendor_id == id) {
+                device.vendor_name = Some(name.to_string());
+            }
+        }
+    }
+}
+
+// Check whether UEFI Secure Boot is enforced. The efivar holds 4 attribute bytes followed by the value.
+fn secure_boot_enabled() -> bool {
+    const SECURE_BOOT_VAR: &str = "/sys/firmware/efi/efivars/SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+    match fs::read(SECURE_BOOT_VAR) {
+        Ok(data) => data.last() == Some(&1),
+        Err(_) => false,
+    }
+}
+
+const TPM_DEVICE: &str = "/dev/tpmrm0";
+// Persistent handles the TCG provisioning guidance reserves for the EK and an AK
+const EK_HANDLE: &str = "0x81010001";
+const AK_HANDLE: &str = "0x81010002";
+
+fn tpm2(args: &[&str]) -> bool {
+    Command::new(args[0]).args(&args[1..]).output().is_ok_and(|output| output.status.success())
+}
+
+fn tpm_work_file(name: &str) -> Option<String> {
+    let dir = env::temp_dir().join("dragonfly-tpm");
+    fs::create_dir_all(&dir).ok()?;
+    Some(dir.join(name).to_string_lossy().into_owned())
+}
+
+// Read the TPM's endorsement and attestation keys, creating and persisting them on first use.
+// Needs tpm2-tools; machines without a TPM just don't report one.
+fn tpm_identity() -> Option<TpmIdentity> {
+    if !Path::new(TPM_DEVICE).exists() {
+        return None;
+    }
+    let (ek_pem, ak_pem) = (tpm_work_file("ek.pem")?, tpm_work_file("ak.pem")?);
+    if !tpm2(&["tpm2_readpublic", "-c", EK_HANDLE]) && !tpm2(&["tpm2_createek", "-c", EK_HANDLE, "-G", "rsa", "-u", &tpm_work_file("ek.pub")?]) {
+        warn!("Failed to create a TPM endorsement key; is tpm2-tools installed?");
+        return None;
+    }
+    if !tpm2(&["tpm2_readpublic", "-c", AK_HANDLE]) {
+        let ak_ctx = tpm_work_file("ak.ctx")?;
+        let created = tpm2(&["tpm2_createak", "-C", EK_HANDLE, "-c", &ak_ctx, "-G", "rsa", "-g", "sha256", "-s", "rsassa",
+                "-u", &tpm_work_file("ak.pub")?, "-n", &tpm_work_file("ak.name")?])
+            && tpm2(&["tpm2_evictcontrol", "-c", &ak_ctx, AK_HANDLE]);
+        if !created {
+            warn!("Failed to create a TPM attestation key");
+            return None;
+        }
+    }
+    if !tpm2(&["tpm2_readpublic", "-c", EK_HANDLE, "-f", "pem", "-o", &ek_pem]) || !tpm2(&["tpm2_readpublic", "-c", AK_HANDLE, "-f", "pem", "-o", &ak_pem]) {
+        warn!("Failed to read the TPM's public keys");
+        return None;
+    }
+    Some(TpmIdentity {
+        ek_public: fs::read_to_string(ek_pem).ok()?,
+        ak_public: fs::read_to_string(ak_pem).ok()?,
+    })
+}
+
+// Have the TPM sign the server's nonce with our attestation key
+fn tpm_quote(nonce: &str) -> Option<TpmQuote> {
+    let (message, signature) = (tpm_work_file("quote.msg")?, tpm_work_file("quote.sig")?);
+    if !tpm2(&["tpm2_quote", "-c", AK_HANDLE, "-l", "sha256:0,7", "-q", nonce, "-g", "sha256", "-m", &message, "-s", &signature, "-f", "plain"]) {
+        warn!("Failed to produce a TPM quote");
+        return None;
+    }
+    let hex = |bytes: Vec<u8>| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    Some(TpmQuote {
+        message: hex(fs::read(message).ok()?),
+        signature: hex(fs::read(signature).ok()?),
+    })
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
@@ -443,9 +605,10 @@ async fn main() -> Result<()> {
     info!("Detected RAM: {} bytes ({:.2} GiB)", total_ram_bytes, total_ram_gib);
     // --- End CPU/RAM Detection ---
     
-    // Detect disks and nameservers
+    // Detect disks, nameservers and PCI devices
     let disks = detect_disks();
     let nameservers = detect_nameservers();
+    let pci_devices = detect_pci_devices();
     
     // Detect OS - even in setup mode we want to check for existing OS
     let (os_name, os_version) = detect_os()?;
@@ -482,19 +645,23 @@ async fn main() -> Result<()> {
         .await
         .context("Failed to fetch existing machines")?;
     
-    if !existing_machines_response.status().is_success() {
+    // With require_login on the list is closed to us; registering by MAC updates a known machine anyway
+    let existing_machines: Vec<Machine> = if existing_machines_response.status() == reqwest::StatusCode::UNAUTHORIZED {
+        tracing::info!("Server requires a login to list machines; registering by MAC address");
+        Vec::new()
+    } else if !existing_machines_response.status().is_success() {
         let error_text = existing_machines_response.text().await?;
         anyhow::bail!("Failed to fetch existing machines: {}", error_text);
-    }
-    
-    let existing_machines: Vec<Machine> = existing_machines_response.json().await
-        .context("Failed to parse existing machines response")?;
+    } else {
+        existing_machines_response.json().await
+            .context("Failed to parse existing machines response")?
+    };
     
     // Find if this machine already exists by MAC address
     let existing_machine_option = existing_machines.iter().find(|m| m.mac_address == mac_address).cloned();
     
     // Process registration/update as before
-    let _machine_id = match existing_machine_option {
+    let machine_id = match existing_machine_option {
         Some(mut machine) => { // Make machine mutable
             // Machine exists, update its status, OS, and hardware info
             tracing::info!("Machine already exists with ID: {}, fetching current state...", machine.id);
@@ -581,6 +748,12 @@ async fn main() -> Result<()> {
                 info!("Successfully updated machine {} on server", machine.id);
             }
             
+            // Report our clock so the server can detect skew (which breaks TLS during image pulls)
+            report_clock(&client, &api_url, &machine.id.to_string()).await;
+
+            // Hardware can change between boots, so refresh the PCI inventory too
+            report_pci_devices(&client, &api_url, &machine.id.to_string(), pci_devices).await;
+            
             // We don't need to update status/os_installed separately anymore
             /*
             // Update machine status with the OS information
@@ -611,6 +784,21 @@ async fn main() -> Result<()> {
                 cpu_model: cpu_model.clone(), 
                 cpu_cores,
                 total_ram_bytes: Some(total_ram_bytes),
+                // Report our clock so the server can detect skew
+                agent_time: Some(chrono::Utc::now()),
+                // Report architecture and firmware so the server serves matching boot artifacts
+                cpu_arch: Some(env::consts::ARCH.to_string()),
+                uefi: Some(Path::new("/sys/firmware/efi").exists()),
+                secure_boot: Some(secure_boot_enabled()),
+                // Pinned by the server so nothing else can enroll as this machine later
+                tpm: tpm_identity(),
+                // Lets the server answer queries like "machines with 4 GPUs"
+                pci_devices,
+                // Lets machine profiles match on vendor and model
+                system_vendor: read_dmi("sys_vendor"),
+                system_model: read_dmi("product_name"),
+                // Lets operators search for a machine by the serial on its chassis
+                system_serial: read_dmi("product_serial"),
             };
             
             // Register the machine
@@ -632,6 +820,11 @@ async fn main() -> Result<()> {
             tracing::info!("Machine ID: {}", register_response.machine_id);
             tracing::info!("Next step: {}", register_response.next_step);
             
+            if register_response.next_step == "pending_approval" {
+                tracing::info!("Machine is pending approval by a Dragonfly admin, not updating status");
+                return Ok(());
+            }
+            
             // Update machine status with the OS information
             tracing::info!("Updating machine status with OS information...");
             let status_update = StatusUpdateRequest {
@@ -740,6 +933,8 @@ async fn main() -> Result<()> {
             // Reboot replaces the current process, so we won't reach here normally.
             // If reboot fails, the context error will propagate.
         }
+    } else if let Some(interval) = args.heartbeat {
+        run_heartbeat(&client, &api_url, &machine_id.to_string(), interval, args.enrollment_token.as_deref()).await;
     } else {
         tracing::info!("Agent finished running in non-setup mode.");
     }
@@ -747,6 +942,316 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Report the agent's current time to the server for clock skew detection.
+/// Failures are logged but never fatal.
+async fn report_clock(client: &Client, api_url: &str, machine_id: &str) {
+    let clock_report = ClockReportRequest {
+        agent_time: chrono::Utc::now(),
+    };
+    
+    let url = format!("{}/api/machines/{}/clock", api_url, machine_id);
+    match client.put(&url).json(&clock_report).send().await {
+        Ok(response) if response.status().is_success() => {
+            match response.json::<ClockReportResponse>().await {
+                Ok(report) => {
+                    if report.exceeds_threshold {
+                        warn!("Server reports clock skew of {}s: {}", report.skew_seconds, report.message);
+                    } else {
+                        info!("Clock skew relative to server: {}s", report.skew_seconds);
+                    }
+                }
+                Err(e) => warn!("Failed to parse clock report response: {}", e),
+            }
+        }
+        Ok(response) => {
+            warn!("Failed to report clock to server. Status: {}", response.status());
+        }
+        Err(e) => {
+            warn!("Failed to send clock report: {}", e);
+        }
+    }
+}
+
+async fn report_pci_devices(client: &Client, api_url: &str, machine_id: &str, devices: Vec<PciDevice>) {
+    let url = format!("{}/api/machines/{}/pci-devices", api_url, machine_id);
+    match client.put(&url).json(&PciReportRequest { devices }).send().await {
+        Ok(response) if response.status().is_success() => {
+            info!("Reported PCI devices to server");
+        }
+        Ok(response) => {
+            warn!("Failed to report PCI devices to server. Status: {}", response.status());
+        }
+        Err(e) => {
+            warn!("Failed to send PCI device report: {}", e);
+        }
+    }
+}
+
+/// Tell the server we're alive every `interval` seconds, forever.
+/// Failures are logged and retried on the next tick; the server marks us Offline if they persist.
+/// Heartbeats are signed, so they wait for enrollment. With an enrollment token, also picks up
+/// and runs remote commands on each tick.
+async fn run_heartbeat(client: &Client, api_url: &str, machine_id: &str, interval: u64, enrollment_token: Option<&str>) {
+    let path = format!("/api/machines/{}/heartbeat", machine_id);
+    let url = format!("{}{}", api_url, path);
+    let mut ticker = tokio::time::interval(std::time::Duration::from_secs(interval.max(1)));
+    info!("Sending heartbeats to {} every {}s", url, interval.max(1));
+    
+    loop {
+        ticker.tick().await;
+        let Some(secret) = ensure_enrolled(client, api_url, machine_id, enrollment_token).await else {
+            warn!("Not enrolled with the server, skipping heartbeat");
+            continue;
+        };
+        match signed(client.post(&url), &secret, "POST", &path, &[]).send().await {
+            Ok(response) if response.status().is_success() => {
+                tracing::debug!("Heartbeat acknowledged");
+            }
+            Ok(response) if response.status() == reqwest::StatusCode::UNAUTHORIZED => {
+                // Our secret was replaced or the machine re-registered; enroll again on the next tick
+                warn!("Server rejected our heartbeat signature, re-enrolling");
+                let _ = fs::remove_file(AGENT_SECRET_PATH);
+                continue;
+            }
+            Ok(response) => {
+                warn!("Heartbeat rejected by server. Status: {}", response.status());
+            }
+            Err(e) => {
+                warn!("Failed to send heartbeat: {}", e);
+            }
+        }
+        
+        if enrollment_token.is_some() {
+            poll_commands(client, api_url, machine_id, &secret).await;
+        }
+    }
+}
+
+const AGENT_SECRET_PATH: &str = "/var/lib/dragonfly-agent/agent-secret";
+
+/// Load our signing secret, enrolling with the server first if we don't have one yet
+async fn ensure_enrolled(client: &Client, api_url: &str, machine_id: &str, enrollment_token: Option<&str>) -> Option<String> {
+    if let Ok(secret) = fs::read_to_string(AGENT_SECRET_PATH) {
+        if !secret.trim().is_empty() {
+            return Some(secret.trim().to_string());
+        }
+    }
+    
+    let enrollment_token = enrollment_token?.to_string();
+    
+    // With a TPM, prove we're the machine the server pinned at registration
+    let attestation = if Path::new(TPM_DEVICE).exists() {
+        let url = format!("{}/api/machines/{}/agent/challenge", api_url, machine_id);
+        match client.post(&url).send().await {
+            Ok(response) if response.status().is_success() => match response.json::<AttestationChallenge>().await {
+                Ok(challenge) => tpm_quote(&challenge.nonce),
+                Err(e) => {
+                    warn!("Failed to parse attestation challenge: {}", e);
+                    None
+                }
+            },
+            Ok(response) => {
+                warn!("Attestation challenge refused by server. Status: {}", response.status());
+                None
+            }
+            Err(e) => {
+                warn!("Failed to request attestation challenge: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    
+    let request = EnrollRequest { enrollment_token, attestation };
+    let url = format!("{}/api/machines/{}/agent/enroll", api_url, machine_id);
+    let response = match client.post(&url).json(&request).send().await {
+        Ok(response) if response.status().is_success() => response,
+        Ok(response) => {
+            warn!("Enrollment rejected by server. Status: {}", response.status());
+            return None;
+        }
+        Err(e) => {
+            warn!("Failed to send enrollment request: {}", e);
+            return None;
+        }
+    };
+    let secret = match response.json::<EnrollResponse>().await {
+        Ok(enrolled) => enrolled.agent_secret,
+        Err(e) => {
+            warn!("Failed to parse enrollment response: {}", e);
+            return None;
+        }
+    };
+    
+    // Keep the secret private to root; it authorises running commands as this machine
+    let path = Path::new(AGENT_SECRET_PATH);
+    if let Some(parent) = path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    match fs::write(path, &secret) {
+        Ok(()) => {
+            use std::os::unix::fs::PermissionsExt;
+            let _ = fs::set_permissions(path, fs::Permissions::from_mode(0o600));
+            info!("Enrolled for remote commands");
+        }
+        Err(e) => warn!("Enrolled, but failed to save agent secret (will re-enroll on restart): {}", e),
+    }
+    Some(secret)
+}
+
+/// Add the timestamp and signature headers the server checks on agent requests
+fn signed(builder: reqwest::RequestBuilder, secret: &str, method: &str, path: &str, body: &[u8]) -> reqwest::RequestBuilder {
+    let timestamp = chrono::Utc::now().timestamp();
+    builder
+        .header(agent_protocol::TIMESTAMP_HEADER, timestamp.to_string())
+        .header(agent_protocol::SIGNATURE_HEADER, agent_protocol::sign(secret, method, path, timestamp, body))
+}
+
+/// Fetch and run at most one pending command
+async fn poll_commands(client: &Client, api_url: &str, machine_id: &str, secret: &str) {
+    let path = format!("/api/machines/{}/commands/next", machine_id);
+    let response = match signed(client.get(format!("{}{}", api_url, path)), secret, "GET", &path, &[]).send().await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Failed to poll for commands: {}", e);
+            return;
+        }
+    };
+    
+    match response.status() {
+        reqwest::StatusCode::NO_CONTENT => return,
+        reqwest::StatusCode::UNAUTHORIZED => {
+            // Our secret was replaced or the machine re-registered; enroll again on the next tick
+            warn!("Server rejected our agent secret, re-enrolling");
+            let _ = fs::remove_file(AGENT_SECRET_PATH);
+            return;
+        }
+        status if !status.is_success() => {
+            warn!("Failed to poll for commands. Status: {}", status);
+            return;
+        }
+        _ => {}
+    }
+    
+    let command = match response.json::<AgentCommand>().await {
+        Ok(command) => command,
+        Err(e) => {
+            warn!("Failed to parse command: {}", e);
+            return;
+        }
+    };
+    info!("Running remote command {}: {:?}", command.id, command.kind);
+    
+    let (exit_code, output) = match command.kind {
+        CommandKind::RotateRootPassword => rotate_root_password(client, api_url, machine_id, secret, command.id).await,
+        ref kind => run_command(kind, api_url).await,
+    };
+    let result = CommandResult { exit_code, output };
+    let path = format!("/api/machines/{}/commands/{}/result", machine_id, command.id);
+    let body = match serde_json::to_vec(&result) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Failed to encode command result: {}", e);
+            return;
+        }
+    };
+    let request = signed(client.post(format!("{}{}", api_url, path)), secret, "POST", &path, &body)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body);
+    match request.send().await {
+        Ok(response) if response.status().is_success() => info!("Reported result of command {}", command.id),
+        Ok(response) => warn!("Server rejected result of command {}. Status: {}", command.id, response.status()),
+        Err(e) => warn!("Failed to report result of command {}: {}", command.id, e),
+    }
+    
+    if command.kind == CommandKind::Reboot && exit_code == 0 {
+        info!("Rebooting as requested by Dragonfly");
+        if let Err(e) = Command::new("reboot").status() {
+            error!("Failed to reboot: {}", e);
+        }
+    }
+}
+
+/// Fetch the new root password for a rotation command and set it with chpasswd. The password goes
+/// over stdin, so it never shows up in the process list, and never into the reported output.
+async fn rotate_root_password(client: &Client, api_url: &str, machine_id: &str, secret: &str, command_id: i64) -> (i32, String) {
+    let path = format!("/api/machines/{}/commands/{}/root-password", machine_id, command_id);
+    let response = match signed(client.get(format!("{}{}", api_url, path)), secret, "GET", &path, &[]).send().await {
+        Ok(response) if response.status().is_success() => response,
+        Ok(response) => return (1, format!("Server refused the new root password. Status: {}", response.status())),
+        Err(e) => return (1, format!("Failed to fetch the new root password: {}", e)),
+    };
+    let password = match response.json::<serde_json::Value>().await {
+        Ok(body) => match body.get("password").and_then(|p| p.as_str()) {
+            Some(password) => password.to_string(),
+            None => return (1, "Server sent no root password".to_string()),
+        },
+        Err(e) => return (1, format!("Failed to parse the new root password: {}", e)),
+    };
+    
+    let mut process = match tokio::process::Command::new("chpasswd")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+    {
+        Ok(process) => process,
+        Err(e) => return (1, format!("Failed to start chpasswd: {}", e)),
+    };
+    if let Some(mut stdin) = process.stdin.take() {
+        use tokio::io::AsyncWriteExt;
+        if let Err(e) = stdin.write_all(format!("root:{}\n", password).as_bytes()).await {
+            return (1, format!("Failed to write to chpasswd: {}", e));
+        }
+    }
+    match process.wait_with_output().await {
+        Ok(output) if output.status.success() => (0, "Root password rotated".to_string()),
+        Ok(output) => (
+            output.status.code().unwrap_or(-1),
+            truncate_output(String::from_utf8_lossy(&output.stderr).replace(&password, "********")),
+        ),
+        Err(e) => (1, format!("chpasswd failed: {}", e)),
+    }
+}
+
+/// Run a command, returning its exit code and combined output
+async fn run_command(kind: &CommandKind, api_url: &str) -> (i32, String) {
+    const DEFAULT_SCRIPT_TIMEOUT_SECS: u64 = 600;
+    
+    let (mut process, timeout_secs) = match kind {
+        // Rebooting happens after the result is reported, otherwise the server would never hear back
+        CommandKind::Reboot => return (0, "Rebooting".to_string()),
+        CommandKind::GatherInventory => {
+            // A fresh one-shot run of the agent re-detects hardware and updates the machine
+            let exe = match env::current_exe() {
+                Ok(exe) => exe,
+                Err(e) => return (1, format!("Failed to locate agent binary: {}", e)),
+            };
+            let mut process = tokio::process::Command::new(exe);
+            process.arg("--server").arg(api_url);
+            (process, DEFAULT_SCRIPT_TIMEOUT_SECS)
+        }
+        CommandKind::RunScript { script, timeout_secs } => {
+            let mut process = tokio::process::Command::new("sh");
+            process.arg("-c").arg(script);
+            (process, timeout_secs.unwrap_or(DEFAULT_SCRIPT_TIMEOUT_SECS))
+        }
+        CommandKind::RotateRootPassword => return (1, "Root password rotation needs the agent's credentials".to_string()),
+    };
+    process.kill_on_drop(true);
+    
+    match tokio::time::timeout(std::time::Duration::from_secs(timeout_secs), process.output()).await {
+        Ok(Ok(output)) => {
+            let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+            text.push_str(&String::from_utf8_lossy(&output.stderr));
+            (output.status.code().unwrap_or(-1), truncate_output(text))
+        }
+        Ok(Err(e)) => (1, format!("Failed to start command: {}", e)),
+        Err(_) => (124, format!("Command timed out after {}s", timeout_secs)),
+    }
+}
+
 /// Check if there's a bootable OS on the system
 fn check_bootable_os() -> Result<bool> {
     // First check for EFI boot entries
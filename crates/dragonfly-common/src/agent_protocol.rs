@@ -0,0 +1,161 @@
+//! Wire types and request signing for commands Dragonfly sends to enrolled agents.
+//!
+//! An agent enrolls once with the server's enrollment token and receives a per-machine secret.
+//! Every later request is signed with HMAC-SHA256 over the method, path, timestamp and body,
+//! so a machine can only fetch and answer its own commands.
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+pub const TIMESTAMP_HEADER: &str = "X-Dragonfly-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Dragonfly-Signature";
+
+/// Signed requests older or newer than this are rejected to limit replays
+pub const MAX_SIGNATURE_AGE_SECS: i64 = 300;
+
+/// Longest command output that is kept. Agents truncate before sending, and the server again for agents that don't.
+pub const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+/// Work an agent can be asked to do
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CommandKind {
+    /// Re-detect hardware and re-register with the server
+    GatherInventory,
+    Reboot,
+    /// Run a shell script and report its output
+    RunScript {
+        script: String,
+        #[serde(default)]
+        timeout_secs: Option<u64>,
+    },
+    /// Set a new root password, fetched from the server when the command runs so it never
+    /// appears in the command queue
+    RotateRootPassword,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandStatus {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentCommand {
+    pub id: i64,
+    pub machine_id: Uuid,
+    pub kind: CommandKind,
+    pub status: CommandStatus,
+    pub requested_by: Option<String>,
+    pub exit_code: Option<i32>,
+    pub output: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnrollRequest {
+    pub enrollment_token: String,
+    /// Required when the machine registered a TPM identity
+    #[serde(default)]
+    pub attestation: Option<TpmQuote>,
+}
+
+/// Nonce an agent must have its TPM quote before enrolling
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttestationChallenge {
+    /// Hex-encoded; pass it to `tpm2_quote` as the qualifying data
+    pub nonce: String,
+}
+
+/// Hex-encoded output of `tpm2_quote`: the TPMS_ATTEST message and its signature by the attestation key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TpmQuote {
+    pub message: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnrollResponse {
+    pub agent_secret: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommandResult {
+    pub exit_code: i32,
+    pub output: String,
+}
+
+fn mac(secret: &str, method: &str, path: &str, timestamp: i64, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}\n{}\n{}\n", method.to_uppercase(), path, timestamp).as_bytes());
+    mac.update(body);
+    mac
+}
+
+/// Hex-encoded signature for a request
+pub fn sign(secret: &str, method: &str, path: &str, timestamp: i64, body: &[u8]) -> String {
+    mac(secret, method, path, timestamp, body)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Check a request's signature in constant time, and that its timestamp is recent
+pub fn verify(secret: &str, method: &str, path: &str, timestamp: i64, body: &[u8], signature: &str, now: i64) -> bool {
+    if (now - timestamp).abs() > MAX_SIGNATURE_AGE_SECS || !signature.len().is_multiple_of(2) {
+        return false;
+    }
+    let bytes: Option<Vec<u8>> = (0..signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(signature.get(i..i + 2)?, 16).ok())
+        .collect();
+    match bytes {
+        Some(bytes) => mac(secret, method, path, timestamp, body).verify_slice(&bytes).is_ok(),
+        None => false,
+    }
+}
+
+/// Cut output down to its last `MAX_OUTPUT_BYTES`, which usually say why a script failed
+pub fn truncate_output(output: String) -> String {
+    if output.len() <= MAX_OUTPUT_BYTES {
+        return output;
+    }
+    let mut start = output.len() - MAX_OUTPUT_BYTES;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("[output truncated]\n{}", &output[start..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signature = sign("secret", "GET", "/api/machines/x/commands/next", 1000, b"");
+        assert!(verify("secret", "get", "/api/machines/x/commands/next", 1000, b"", &signature, 1100));
+        assert!(!verify("other", "GET", "/api/machines/x/commands/next", 1000, b"", &signature, 1100));
+        assert!(!verify("secret", "GET", "/api/machines/y/commands/next", 1000, b"", &signature, 1100));
+        assert!(!verify("secret", "GET", "/api/machines/x/commands/next", 1000, b"", &signature, 2000));
+        assert!(!verify("secret", "GET", "/api/machines/x/commands/next", 1000, b"", "zz", 1000));
+    }
+
+    #[test]
+    fn test_truncate_output() {
+        assert_eq!(truncate_output("ok".to_string()), "ok");
+        let long = "é".repeat(MAX_OUTPUT_BYTES);
+        let truncated = truncate_output(long);
+        assert!(truncated.len() <= MAX_OUTPUT_BYTES + 20);
+        assert!(truncated.starts_with("[output truncated]"));
+    }
+}
@@ -1,6 +1,8 @@
 pub mod error;
 pub mod models;
 pub mod mac_to_words;
+pub mod agent_protocol;
+pub mod state_machine;
 
 pub use error::Error;
 pub use models::*;
@@ -31,6 +31,17 @@ pub struct Machine {
     pub cpu_cores: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub total_ram_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_skew_seconds: Option<i64>,  // Agent clock minus server clock, last reported
+}
+
+impl Machine {
+    /// What people call the machine: its hostname, else its memorable name, else its MAC
+    pub fn display_name(&self) -> String {
+        self.hostname.clone()
+            .or_else(|| self.memorable_name.clone())
+            .unwrap_or_else(|| self.mac_address.clone())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -40,9 +51,18 @@ pub enum MachineStatus {
     InstallingOS,          // Installing an OS via tinkerbell
     Ready,                 // Part of the cluster, serving K8s workloads
     Offline,               // Machine is offline (can be WoL'd)
+    PendingApproval,       // Newly discovered, waiting for an admin to approve it
+    Rejected,              // Admin rejected the machine, it will only boot from local disk
     Error(String),         // Error state with message
 }
 
+impl MachineStatus {
+    /// Whether the machine is held at the approval gate and must not be provisioned
+    pub fn is_approval_gated(&self) -> bool {
+        matches!(self, MachineStatus::PendingApproval | MachineStatus::Rejected)
+    }
+}
+
 impl fmt::Display for MachineStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -51,6 +71,8 @@ impl fmt::Display for MachineStatus {
             MachineStatus::InstallingOS => write!(f, "InstallingOS"),
             MachineStatus::Ready => write!(f, "Ready"),
             MachineStatus::Offline => write!(f, "Offline"),
+            MachineStatus::PendingApproval => write!(f, "Pending Approval"),
+            MachineStatus::Rejected => write!(f, "Rejected"),
             MachineStatus::Error(msg) => write!(f, "Error: {}", msg),
         }
     }
@@ -92,6 +114,31 @@ pub struct RegisterRequest {
     pub cpu_model: Option<String>,
     pub cpu_cores: Option<u32>,
     pub total_ram_bytes: Option<u64>,
+    #[serde(default)]
+    pub agent_time: Option<DateTime<Utc>>,  // Agent's wall clock at the time of the request
+    #[serde(default)]
+    pub cpu_arch: Option<String>,  // e.g. x86_64 or aarch64
+    #[serde(default)]
+    pub uefi: Option<bool>,  // Whether the machine booted via UEFI
+    #[serde(default)]
+    pub secure_boot: Option<bool>,  // Whether UEFI Secure Boot is enforced
+    #[serde(default)]
+    pub tpm: Option<TpmIdentity>,  // TPM keys, pinned the first time a machine reports them
+    #[serde(default)]
+    pub pci_devices: Vec<PciDevice>,  // GPUs, NVMe drives, NICs and the rest of the PCI bus
+    #[serde(default)]
+    pub system_vendor: Option<String>,  // From DMI, e.g. "Dell Inc."
+    #[serde(default)]
+    pub system_model: Option<String>,  // From DMI, e.g. "PowerEdge R650"
+    #[serde(default)]
+    pub system_serial: Option<String>,  // From DMI, the chassis serial or service tag
+}
+
+/// Public halves of a machine's TPM endorsement key and attestation key, as SubjectPublicKeyInfo PEM
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TpmIdentity {
+    pub ek_public: String,
+    pub ak_public: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -184,4 +231,38 @@ pub struct InstallationProgressUpdateRequest {
 pub struct InstallationProgressUpdateResponse {
     pub success: bool,
     pub message: String,
-} 
\ No newline at end of file
+} 
+/// A PCI device as the agent found it in sysfs
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PciDevice {
+    pub address: String,  // e.g. 0000:3b:00.0
+    pub class: String,  // Class and subclass, e.g. 0302 for a 3D controller
+    pub vendor_id: String,  // e.g. 10de
+    pub device_id: String,
+    #[serde(default)]
+    pub vendor_name: Option<String>,  // From pci.ids, when the agent has it
+    #[serde(default)]
+    pub device_name: Option<String>,
+    #[serde(default)]
+    pub driver: Option<String>,
+    #[serde(default)]
+    pub sriov_total_vfs: Option<u32>,  // Virtual functions the device can offer, if it supports SR-IOV
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PciReportRequest {
+    pub devices: Vec<PciDevice>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClockReportRequest {
+    pub agent_time: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClockReportResponse {
+    pub success: bool,
+    pub skew_seconds: i64,
+    pub exceeds_threshold: bool,
+    pub message: String,
+}
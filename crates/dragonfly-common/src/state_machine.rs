@@ -0,0 +1,168 @@
+//! Which machine status changes are allowed.
+//!
+//! Every status write on the server goes through [`MachineStatus::transition_to`], so a machine
+//! can't, for example, go from Offline straight to InstallingOS or skip the approval gate.
+
+use serde::{Deserialize, Serialize};
+use std::mem::discriminant;
+use thiserror::Error;
+
+use crate::models::MachineStatus;
+
+/// A validated status change
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Transition {
+    pub from: MachineStatus,
+    pub to: MachineStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("A machine can't go from {from} to {to}")]
+pub struct InvalidTransition {
+    pub from: MachineStatus,
+    pub to: MachineStatus,
+}
+
+/// Why a machine's status changed, kept in its status history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "detail", rename_all = "snake_case")]
+pub enum StatusCause {
+    /// The machine registered with Dragonfly
+    Registration,
+    /// A user acted on the machine; the detail names them
+    Admin(String),
+    /// The machine, or its agent, reported the status itself
+    Machine,
+    /// A provisioning workflow moved on; the detail names the step or outcome
+    Workflow(String),
+    /// A provisioning pipeline moved on to its next stage; the detail names it. The machine
+    /// stays InstallingOS, but each stage is kept in its history.
+    PipelineStage(String),
+    /// The machine stopped sending heartbeats
+    HeartbeatTimeout,
+    /// An offline machine sent a heartbeat again
+    HeartbeatResumed,
+    /// Dragonfly acted on its own, e.g. applying the default OS
+    Automatic(String),
+}
+
+impl StatusCause {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            StatusCause::Registration => "registration",
+            StatusCause::Admin(_) => "admin",
+            StatusCause::Machine => "machine",
+            StatusCause::Workflow(_) => "workflow",
+            StatusCause::PipelineStage(_) => "pipeline_stage",
+            StatusCause::HeartbeatTimeout => "heartbeat_timeout",
+            StatusCause::HeartbeatResumed => "heartbeat_resumed",
+            StatusCause::Automatic(_) => "automatic",
+        }
+    }
+
+    pub fn detail(&self) -> Option<&str> {
+        match self {
+            StatusCause::Admin(detail) | StatusCause::Workflow(detail) | StatusCause::PipelineStage(detail)
+            | StatusCause::Automatic(detail) => Some(detail),
+            _ => None,
+        }
+    }
+
+    /// Rebuild a cause from its stored kind and detail
+    pub fn from_parts(kind: &str, detail: Option<String>) -> Option<Self> {
+        let detail = || detail.clone().unwrap_or_default();
+        match kind {
+            "registration" => Some(StatusCause::Registration),
+            "admin" => Some(StatusCause::Admin(detail())),
+            "machine" => Some(StatusCause::Machine),
+            "workflow" => Some(StatusCause::Workflow(detail())),
+            "pipeline_stage" => Some(StatusCause::PipelineStage(detail())),
+            "heartbeat_timeout" => Some(StatusCause::HeartbeatTimeout),
+            "heartbeat_resumed" => Some(StatusCause::HeartbeatResumed),
+            "automatic" => Some(StatusCause::Automatic(detail())),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for StatusCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StatusCause::Registration => write!(f, "Machine registered"),
+            StatusCause::Admin(who) => write!(f, "Changed by {}", who),
+            StatusCause::Machine => write!(f, "Reported by the machine"),
+            StatusCause::Workflow(step) => write!(f, "Workflow: {}", step),
+            StatusCause::PipelineStage(stage) => write!(f, "Pipeline: {}", stage),
+            StatusCause::HeartbeatTimeout => write!(f, "Heartbeats stopped"),
+            StatusCause::HeartbeatResumed => write!(f, "Heartbeats resumed"),
+            StatusCause::Automatic(what) => write!(f, "{}", what),
+        }
+    }
+}
+
+impl Transition {
+    /// Whether the status actually changes; rewriting the same status isn't worth recording
+    pub fn is_change(&self) -> bool {
+        self.from != self.to
+    }
+}
+
+impl MachineStatus {
+    pub fn can_transition_to(&self, next: &MachineStatus) -> bool {
+        use MachineStatus::*;
+
+        // Staying put, or a new error message, is always fine
+        if discriminant(self) == discriminant(next) {
+            return true;
+        }
+        match (self, next) {
+            // Anything can fail, stop answering heartbeats, or be rejected by an admin
+            (_, Error(_)) | (_, Offline) | (_, Rejected) => true,
+            // The approval gate is only left through approve or reject
+            (PendingApproval, AwaitingAssignment) => true,
+            (Rejected, PendingApproval | AwaitingAssignment) => true,
+            (PendingApproval | Rejected, _) => false,
+            (AwaitingAssignment | ExistingOS | Ready | Error(_), InstallingOS) => true,
+            // Installation finished, or was cancelled
+            (InstallingOS, Ready | ExistingOS | AwaitingAssignment) => true,
+            (AwaitingAssignment | ExistingOS | Ready | Error(_), AwaitingAssignment | ExistingOS | Ready) => true,
+            // A machine coming back has to report in before it's reinstalled
+            (Offline, InstallingOS) => false,
+            (Offline, _) => true,
+            _ => false,
+        }
+    }
+
+    pub fn transition_to(&self, next: MachineStatus) -> Result<Transition, InvalidTransition> {
+        if self.can_transition_to(&next) {
+            Ok(Transition { from: self.clone(), to: next })
+        } else {
+            Err(InvalidTransition { from: self.clone(), to: next })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MachineStatus::*;
+
+    #[test]
+    fn test_transitions() {
+        assert!(AwaitingAssignment.transition_to(InstallingOS).is_ok());
+        assert!(InstallingOS.transition_to(Ready).is_ok());
+        assert!(Ready.transition_to(Error("disk failed".to_string())).is_ok());
+        assert!(Error("a".to_string()).can_transition_to(&Error("b".to_string())));
+        assert!(Offline.transition_to(InstallingOS).is_err());
+        assert!(PendingApproval.transition_to(InstallingOS).is_err());
+        assert!(Rejected.transition_to(Ready).is_err());
+        assert!(!Ready.transition_to(Ready).unwrap().is_change());
+
+        let cause = StatusCause::Workflow("stream-image failed".to_string());
+        assert_eq!(StatusCause::from_parts(cause.kind(), cause.detail().map(str::to_string)), Some(cause));
+        assert_eq!(StatusCause::from_parts("heartbeat_timeout", None), Some(StatusCause::HeartbeatTimeout));
+
+        let stage = StatusCause::PipelineStage("stage 2 of 3 (raid-config)".to_string());
+        assert_eq!(StatusCause::from_parts(stage.kind(), stage.detail().map(str::to_string)), Some(stage));
+    }
+}
@@ -8,6 +8,12 @@ fn main() {
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=src/input.css");
     println!("cargo:rerun-if-changed=templates"); 
+    println!("cargo:rerun-if-changed=proto");
+    println!("cargo:rerun-if-changed=migrations");
+    
+    // Generate the gRPC service code, with a bundled protoc so no system install is needed
+    env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().expect("No bundled protoc for this platform"));
+    tonic_prost_build::compile_protos("proto/dragonfly.proto").expect("Failed to compile proto/dragonfly.proto");
     
     // Define paths relative to the crate root (where build.rs is)
     let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
@@ -1,2 +0,0 @@
--- Add last_deployment_duration column to machines table
-ALTER TABLE machines ADD COLUMN last_deployment_duration BIGINT; 
\ No newline at end of file
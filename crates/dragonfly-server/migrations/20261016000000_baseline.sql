@@ -0,0 +1,613 @@
+-- The schema as it stood when Dragonfly moved to versioned migrations.
+--
+-- Every statement is idempotent: databases created before then already have most of these
+-- tables, and adopt this migration as their starting point once the server has added the
+-- columns that used to be added at startup.
+
+-- Machines, one row per registered machine
+CREATE TABLE IF NOT EXISTS machines (
+    id TEXT PRIMARY KEY,
+    mac_address TEXT UNIQUE NOT NULL,
+    ip_address TEXT NOT NULL,
+    hostname TEXT,
+    os_choice TEXT,
+    os_installed TEXT,
+    status TEXT NOT NULL,
+    disks TEXT, -- JSON array of disk info
+    nameservers TEXT, -- JSON array of nameservers
+    bmc_credentials TEXT, -- JSON object of BMC credentials
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    cpu_model TEXT,
+    cpu_cores INTEGER,
+    total_ram_bytes INTEGER,
+    installation_progress INTEGER DEFAULT 0,
+    installation_step TEXT,
+    last_deployment_duration INTEGER
+);
+
+-- The admin_credentials table (superseded by users; kept so older databases can be migrated)
+CREATE TABLE IF NOT EXISTS admin_credentials (
+    id INTEGER PRIMARY KEY,
+    username TEXT NOT NULL,
+    password_hash TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- The users table, one row per operator account
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    disabled BOOLEAN NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    last_login_at TEXT,
+    totp_secret TEXT,
+    totp_enabled INTEGER NOT NULL DEFAULT 0,
+    totp_recovery_codes TEXT,
+    totp_last_step INTEGER
+);
+
+-- The template_usage table for OS template popularity counters
+CREATE TABLE IF NOT EXISTS template_usage (
+    template_name TEXT PRIMARY KEY,
+    usage_count INTEGER NOT NULL DEFAULT 0,
+    last_used_at TEXT NOT NULL
+);
+
+-- The acl_entries table for per-machine and per-group permissions
+CREATE TABLE IF NOT EXISTS acl_entries (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    principal TEXT NOT NULL,
+    scope TEXT NOT NULL, -- JSON object of the ACL scope
+    permission TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+-- The ipxe_templates table for admin-edited iPXE boot templates
+CREATE TABLE IF NOT EXISTS ipxe_templates (
+    name TEXT PRIMARY KEY,
+    content TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- The template_scopes table restricting OS templates to a single project
+CREATE TABLE IF NOT EXISTS template_scopes (
+    template TEXT PRIMARY KEY,
+    project TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- The secure_boot_images table for per-template signed kernel/initrd metadata
+CREATE TABLE IF NOT EXISTS secure_boot_images (
+    template TEXT PRIMARY KEY,
+    image TEXT NOT NULL, -- JSON object of the signed boot image
+    updated_at TEXT NOT NULL
+);
+
+-- The agent_commands table for commands queued for enrolled agents
+CREATE TABLE IF NOT EXISTS agent_commands (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    machine_id TEXT NOT NULL,
+    kind TEXT NOT NULL, -- JSON object of the command
+    status TEXT NOT NULL,
+    requested_by TEXT,
+    exit_code INTEGER,
+    output TEXT,
+    created_at TEXT NOT NULL,
+    completed_at TEXT,
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE
+);
+
+-- The webhooks table for outbound event notifications
+CREATE TABLE IF NOT EXISTS webhooks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    url TEXT NOT NULL,
+    events TEXT NOT NULL, -- JSON array of event types
+    template TEXT,
+    content_type TEXT,
+    enabled BOOLEAN NOT NULL DEFAULT 1,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- The notification_channels table for chat integrations
+CREATE TABLE IF NOT EXISTS notification_channels (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    webhook_url TEXT NOT NULL,
+    events TEXT NOT NULL, -- JSON array of event types
+    enabled BOOLEAN NOT NULL DEFAULT 1,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- The smtp_settings table for the mail server alerts are sent through
+CREATE TABLE IF NOT EXISTS smtp_settings (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    config TEXT NOT NULL, -- JSON object of the SMTP settings
+    updated_at TEXT NOT NULL
+);
+
+-- The alert_rules table for emailed status alerts
+CREATE TABLE IF NOT EXISTS alert_rules (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    config TEXT NOT NULL, -- JSON object of the rule
+    enabled BOOLEAN NOT NULL DEFAULT 1,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- The replace_audit table recording every fleet-wide search and replace
+CREATE TABLE IF NOT EXISTS replace_audit (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    actor TEXT NOT NULL,
+    find TEXT NOT NULL,
+    replacement TEXT NOT NULL,
+    changes TEXT NOT NULL, -- JSON array of the changes made
+    created_at TEXT NOT NULL
+);
+
+-- The archived_machines table keeping the history of deleted machines
+CREATE TABLE IF NOT EXISTS archived_machines (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    machine_id TEXT NOT NULL,
+    record TEXT NOT NULL, -- JSON of the machine as it was when deleted
+    tags TEXT NOT NULL, -- JSON array
+    deleted_by TEXT NOT NULL,
+    deleted_at TEXT NOT NULL
+);
+
+-- The machine_tpm table pinning each machine's TPM keys
+CREATE TABLE IF NOT EXISTS machine_tpm (
+    machine_id TEXT PRIMARY KEY,
+    ek_public TEXT NOT NULL,
+    ak_public TEXT NOT NULL,
+    recorded_at TEXT NOT NULL,
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE
+);
+
+-- The disk_keys table escrowing encrypted disk recovery keys. Keys outlive their machine's record,
+-- since an archived machine's disks may still need unlocking.
+CREATE TABLE IF NOT EXISTS disk_keys (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    machine_id TEXT NOT NULL,
+    scheme TEXT NOT NULL,
+    sealed_key TEXT NOT NULL, -- hex nonce and ciphertext
+    created_at TEXT NOT NULL
+);
+
+-- The disk_key_access table auditing every reveal of an escrowed key
+CREATE TABLE IF NOT EXISTS disk_key_access (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    key_id INTEGER NOT NULL,
+    machine_id TEXT NOT NULL,
+    revealed_by TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    revealed_at TEXT NOT NULL
+);
+
+-- The status_history table recording every status change of every machine
+CREATE TABLE IF NOT EXISTS status_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    machine_id TEXT NOT NULL,
+    from_status TEXT, -- NULL when the machine was first registered
+    to_status TEXT NOT NULL,
+    cause TEXT,
+    detail TEXT,
+    changed_at TEXT NOT NULL,
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE
+);
+
+-- The custom_fields table holding admin-defined machine attributes
+CREATE TABLE IF NOT EXISTS custom_fields (
+    name TEXT PRIMARY KEY,
+    label TEXT NOT NULL,
+    field_type TEXT NOT NULL,
+    show_in_list INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL
+);
+
+-- The machine_field_values table holding each machine's custom field values as JSON
+CREATE TABLE IF NOT EXISTS machine_field_values (
+    machine_id TEXT NOT NULL,
+    field TEXT NOT NULL,
+    value TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (machine_id, field),
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE,
+    FOREIGN KEY (field) REFERENCES custom_fields(name) ON DELETE CASCADE
+);
+
+-- The sites table holding the datacenters or rooms racks are in
+CREATE TABLE IF NOT EXISTS sites (
+    name TEXT PRIMARY KEY,
+    description TEXT
+);
+
+-- The racks table
+CREATE TABLE IF NOT EXISTS racks (
+    name TEXT PRIMARY KEY,
+    site TEXT NOT NULL,
+    height_u INTEGER NOT NULL,
+    FOREIGN KEY (site) REFERENCES sites(name)
+);
+
+-- The machine_placements table recording which rack units each machine occupies
+CREATE TABLE IF NOT EXISTS machine_placements (
+    machine_id TEXT PRIMARY KEY,
+    rack TEXT NOT NULL,
+    position_u INTEGER NOT NULL,
+    height_u INTEGER NOT NULL,
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE,
+    FOREIGN KEY (rack) REFERENCES racks(name)
+);
+
+-- The bmc_jobs table recording power and boot-device jobs and how they ended
+CREATE TABLE IF NOT EXISTS bmc_jobs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    machine_id TEXT NOT NULL,
+    tasks TEXT NOT NULL,
+    backend TEXT NOT NULL,
+    job_name TEXT,
+    state TEXT NOT NULL,
+    message TEXT,
+    requested_by TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    finished_at TEXT,
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE
+);
+
+-- The template_variables table. Secret values are stored sealed, as hex nonce and ciphertext.
+CREATE TABLE IF NOT EXISTS template_variables (
+    name TEXT PRIMARY KEY,
+    value TEXT NOT NULL,
+    secret BOOLEAN NOT NULL DEFAULT 0,
+    updated_at TEXT NOT NULL
+);
+
+-- The render_tokens table holding the hash of each machine's current payload render token
+CREATE TABLE IF NOT EXISTS render_tokens (
+    machine_id TEXT PRIMARY KEY,
+    token_hash TEXT NOT NULL UNIQUE,
+    expires_at TEXT NOT NULL,
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE
+);
+
+-- The root_passwords table. Each machine has at most one active password and one pending
+-- rotation; passwords are sealed like secret template variables.
+CREATE TABLE IF NOT EXISTS root_passwords (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    machine_id TEXT NOT NULL,
+    sealed_password TEXT NOT NULL,
+    active BOOLEAN NOT NULL,
+    command_id INTEGER, -- the rotation command that set it
+    revealed_by TEXT,
+    revealed_at TEXT,
+    created_at TEXT NOT NULL,
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE
+);
+
+-- The os_lifecycle table recording when each OS template reaches end of life
+CREATE TABLE IF NOT EXISTS os_lifecycle (
+    template TEXT PRIMARY KEY,
+    eol_date TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- The machine_claims table recording which machines are set aside for which consumer
+CREATE TABLE IF NOT EXISTS machine_claims (
+    machine_id TEXT PRIMARY KEY,
+    claim_key TEXT NOT NULL UNIQUE,
+    claimed_by TEXT NOT NULL,
+    claimed_at TEXT NOT NULL
+);
+
+-- The install_outcomes table recording how each OS installation ended, for fleet statistics
+CREATE TABLE IF NOT EXISTS install_outcomes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    machine_id TEXT NOT NULL,
+    succeeded INTEGER NOT NULL,
+    finished_at TEXT NOT NULL
+);
+
+-- The artifact_storage table for the selected artifact storage backend
+CREATE TABLE IF NOT EXISTS artifact_storage (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    config TEXT NOT NULL, -- JSON object of the storage backend
+    updated_at TEXT NOT NULL
+);
+
+-- The discovery_policy table for the scheduled network discovery settings
+CREATE TABLE IF NOT EXISTS discovery_policy (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    policy TEXT NOT NULL, -- JSON object of the discovery policy
+    updated_at TEXT NOT NULL
+);
+
+-- The discovery_scans table for network discovery reports
+CREATE TABLE IF NOT EXISTS discovery_scans (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    started_at TEXT NOT NULL,
+    report TEXT NOT NULL -- JSON object of the scan's devices and diff
+);
+
+-- The freeze_audit table recording who froze and unfroze provisioning; the newest row is the current state
+CREATE TABLE IF NOT EXISTS freeze_audit (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    frozen BOOLEAN NOT NULL,
+    actor TEXT NOT NULL,
+    reason TEXT,
+    created_at TEXT NOT NULL
+);
+
+-- The break_glass_credentials table for one-time recovery logins and their audit trail
+CREATE TABLE IF NOT EXISTS break_glass_credentials (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    issued_by TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    used_at TEXT,
+    rotated_at TEXT,
+    revoked_at TEXT
+);
+
+-- The workflow_template_versions table holding every revision of API-managed Tinkerbell templates
+CREATE TABLE IF NOT EXISTS workflow_template_versions (
+    name TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    created_by TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (name, version)
+);
+
+-- The pipelines table of workflow templates run before a profile's OS install
+CREATE TABLE IF NOT EXISTS pipelines (
+    name TEXT PRIMARY KEY,
+    stages TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- The pipeline_runs table tracking each machine's progress through its pipeline
+CREATE TABLE IF NOT EXISTS pipeline_runs (
+    machine_id TEXT PRIMARY KEY,
+    pipeline TEXT NOT NULL,
+    stages TEXT NOT NULL,
+    current_stage INTEGER NOT NULL,
+    state TEXT NOT NULL,
+    error TEXT,
+    started_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE
+);
+
+-- The firmware_images table, the repository of BIOS/BMC/NIC firmware by vendor and model
+CREATE TABLE IF NOT EXISTS firmware_images (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    vendor TEXT NOT NULL,
+    model TEXT NOT NULL,
+    component TEXT NOT NULL,
+    version TEXT NOT NULL,
+    url TEXT,
+    sha256 TEXT,
+    size_bytes INTEGER,
+    created_by TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+-- The machine_firmware table of the firmware versions machines last reported
+CREATE TABLE IF NOT EXISTS machine_firmware (
+    machine_id TEXT NOT NULL,
+    component TEXT NOT NULL,
+    device TEXT NOT NULL DEFAULT '',
+    vendor TEXT NOT NULL,
+    model TEXT NOT NULL,
+    version TEXT NOT NULL,
+    previous_version TEXT,
+    message TEXT,
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (machine_id, component, device),
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE
+);
+
+-- The storage_profiles table of RAID and disk layouts
+CREATE TABLE IF NOT EXISTS storage_profiles (
+    name TEXT PRIMARY KEY,
+    profile TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- The machine_storage_profiles and template_storage_profiles tables attaching profiles
+CREATE TABLE IF NOT EXISTS machine_storage_profiles (
+    machine_id TEXT PRIMARY KEY,
+    profile TEXT NOT NULL,
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE,
+    FOREIGN KEY (profile) REFERENCES storage_profiles(name) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS template_storage_profiles (
+    template TEXT PRIMARY KEY,
+    profile TEXT NOT NULL,
+    FOREIGN KEY (profile) REFERENCES storage_profiles(name) ON DELETE CASCADE
+);
+
+-- The machine_pci_devices table of the PCI devices each machine's agent last reported
+CREATE TABLE IF NOT EXISTS machine_pci_devices (
+    machine_id TEXT NOT NULL,
+    address TEXT NOT NULL,
+    class TEXT NOT NULL,
+    vendor_id TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    vendor_name TEXT,
+    device_name TEXT,
+    driver TEXT,
+    sriov_total_vfs INTEGER,
+    PRIMARY KEY (machine_id, address),
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE
+);
+
+-- The machine_profiles table. Profiles are stored as JSON, like storage profiles.
+CREATE TABLE IF NOT EXISTS machine_profiles (
+    name TEXT PRIMARY KEY,
+    priority INTEGER NOT NULL DEFAULT 0,
+    profile TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS machine_profile_assignments (
+    machine_id TEXT PRIMARY KEY,
+    profile TEXT NOT NULL,
+    assigned_at TEXT NOT NULL,
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE,
+    FOREIGN KEY (profile) REFERENCES machine_profiles(name) ON DELETE CASCADE
+);
+
+-- The machine_system_info table of the vendor and model agents read from DMI
+CREATE TABLE IF NOT EXISTS machine_system_info (
+    machine_id TEXT PRIMARY KEY,
+    vendor TEXT,
+    model TEXT,
+    updated_at TEXT NOT NULL,
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE
+);
+
+-- The maintenance_windows table. Days are stored as a JSON array of weekday names.
+CREATE TABLE IF NOT EXISTS maintenance_windows (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    days TEXT NOT NULL,
+    start_time TEXT NOT NULL,
+    end_time TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT 1,
+    created_at TEXT NOT NULL
+);
+
+-- The provisioning_holds table of OS assignments waiting for a maintenance window
+CREATE TABLE IF NOT EXISTS provisioning_holds (
+    machine_id TEXT PRIMARY KEY,
+    os_choice TEXT NOT NULL,
+    requested_by TEXT NOT NULL,
+    queued_at TEXT NOT NULL,
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE
+);
+
+-- The install_limits table; a single row holding the limits as JSON
+CREATE TABLE IF NOT EXISTS install_limits (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    limits TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- The install_queue table of OS assignments waiting for an install slot
+CREATE TABLE IF NOT EXISTS install_queue (
+    machine_id TEXT PRIMARY KEY,
+    os_choice TEXT NOT NULL,
+    requested_by TEXT NOT NULL,
+    priority INTEGER NOT NULL DEFAULT 0,
+    queued_at TEXT NOT NULL,
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE
+);
+
+-- The rollouts table. The spec, state and members are stored as JSON.
+CREATE TABLE IF NOT EXISTS rollouts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    spec TEXT NOT NULL,
+    state TEXT NOT NULL,
+    batch INTEGER NOT NULL,
+    members TEXT NOT NULL,
+    created_by TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- The template_health_checks table of the checks run after each template's workflow
+CREATE TABLE IF NOT EXISTS template_health_checks (
+    template TEXT PRIMARY KEY,
+    checks TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- The machine_health_reports table holding each machine's latest check results
+CREATE TABLE IF NOT EXISTS machine_health_reports (
+    machine_id TEXT PRIMARY KEY,
+    report TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    FOREIGN KEY (machine_id) REFERENCES machines(id) ON DELETE CASCADE
+);
+
+-- The reimage_schedules table. The spec is stored as JSON.
+CREATE TABLE IF NOT EXISTS reimage_schedules (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    spec TEXT NOT NULL,
+    created_by TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    last_run_at TEXT,
+    next_run_at TEXT
+);
+
+-- The reimage_runs table of each schedule run's report, with its machines as JSON
+CREATE TABLE IF NOT EXISTS reimage_runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    schedule_id INTEGER NOT NULL,
+    schedule_name TEXT NOT NULL,
+    requested_by TEXT NOT NULL,
+    started_at TEXT NOT NULL,
+    finished_at TEXT,
+    machines TEXT NOT NULL,
+    skipped TEXT NOT NULL,
+    note TEXT,
+    FOREIGN KEY (schedule_id) REFERENCES reimage_schedules(id) ON DELETE CASCADE
+);
+
+-- The upgrades table recording each self-upgrade and how it ended
+CREATE TABLE IF NOT EXISTS upgrades (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    from_version TEXT NOT NULL,
+    to_version TEXT NOT NULL,
+    requested_by TEXT NOT NULL,
+    state TEXT NOT NULL,
+    message TEXT NOT NULL,
+    backup TEXT,
+    started_at TEXT NOT NULL,
+    finished_at TEXT
+);
+
+-- Single row of application settings
+CREATE TABLE IF NOT EXISTS app_settings (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    require_login BOOLEAN NOT NULL DEFAULT 0,
+    default_os TEXT,
+    setup_completed BOOLEAN NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- How long each action of each template took, for install time estimates
+CREATE TABLE IF NOT EXISTS template_timings (
+    template_name TEXT NOT NULL,
+    action_name TEXT NOT NULL,
+    durations TEXT NOT NULL,
+    PRIMARY KEY (template_name, action_name)
+);
+
+-- Carry the single admin credential over into the users table
+INSERT INTO users (id, username, password_hash, created_at, updated_at)
+SELECT id, username, password_hash, created_at, updated_at FROM admin_credentials
+WHERE id = (SELECT MAX(id) FROM admin_credentials)
+AND NOT EXISTS (SELECT 1 FROM users);
+
+-- Indices for the lookups on the boot, heartbeat and dashboard paths. MAC lookups ignore case,
+-- so they need their own index rather than the one behind the UNIQUE constraint.
+CREATE INDEX IF NOT EXISTS idx_machines_status ON machines(status);
+CREATE INDEX IF NOT EXISTS idx_machines_mac_address_nocase ON machines(mac_address COLLATE NOCASE);
+CREATE INDEX IF NOT EXISTS idx_machines_ip_address ON machines(ip_address);
+CREATE INDEX IF NOT EXISTS idx_status_history_machine_id ON status_history(machine_id);
+CREATE INDEX IF NOT EXISTS idx_agent_commands_machine_id ON agent_commands(machine_id, status);
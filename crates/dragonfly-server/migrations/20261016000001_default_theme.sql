@@ -0,0 +1,2 @@
+-- Theme a browser gets until it picks its own
+ALTER TABLE app_settings ADD COLUMN default_theme TEXT;
@@ -0,0 +1,3 @@
+-- Serve the iPXE endpoints only to signed-in users and exempt subnets
+ALTER TABLE app_settings ADD COLUMN require_boot_auth BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE app_settings ADD COLUMN boot_auth_exempt_subnets TEXT;
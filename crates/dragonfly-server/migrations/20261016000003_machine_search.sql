@@ -0,0 +1,44 @@
+-- Machine search: serial numbers from the agent, indices for structured terms, and a trigram
+-- full-text index over hostname, MAC address, IP address and serial number for free text
+ALTER TABLE machine_system_info ADD COLUMN serial TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_machines_hostname_nocase ON machines(hostname COLLATE NOCASE);
+CREATE INDEX IF NOT EXISTS idx_machines_os_installed_nocase ON machines(os_installed COLLATE NOCASE);
+CREATE INDEX IF NOT EXISTS idx_machines_os_choice_nocase ON machines(os_choice COLLATE NOCASE);
+CREATE INDEX IF NOT EXISTS idx_machine_field_values_field ON machine_field_values(field, machine_id);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS machine_search USING fts5(
+    machine_id UNINDEXED,
+    hostname,
+    mac_address,
+    ip_address,
+    serial,
+    tokenize = 'trigram'
+);
+
+INSERT INTO machine_search (machine_id, hostname, mac_address, ip_address, serial)
+SELECT m.id, m.hostname, m.mac_address, m.ip_address, s.serial
+FROM machines m LEFT JOIN machine_system_info s ON s.machine_id = m.id;
+
+CREATE TRIGGER IF NOT EXISTS machine_search_insert AFTER INSERT ON machines BEGIN
+    INSERT INTO machine_search (machine_id, hostname, mac_address, ip_address, serial)
+    VALUES (new.id, new.hostname, new.mac_address, new.ip_address,
+            (SELECT serial FROM machine_system_info WHERE machine_id = new.id));
+END;
+
+CREATE TRIGGER IF NOT EXISTS machine_search_update AFTER UPDATE OF hostname, mac_address, ip_address ON machines BEGIN
+    UPDATE machine_search SET hostname = new.hostname, mac_address = new.mac_address, ip_address = new.ip_address
+    WHERE machine_id = new.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS machine_search_delete AFTER DELETE ON machines BEGIN
+    DELETE FROM machine_search WHERE machine_id = old.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS machine_search_serial_insert AFTER INSERT ON machine_system_info BEGIN
+    UPDATE machine_search SET serial = new.serial WHERE machine_id = new.machine_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS machine_search_serial_update AFTER UPDATE OF serial ON machine_system_info BEGIN
+    UPDATE machine_search SET serial = new.serial WHERE machine_id = new.machine_id;
+END;
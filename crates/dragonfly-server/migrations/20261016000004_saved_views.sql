@@ -0,0 +1,13 @@
+-- Named machine list views, saved per user
+CREATE TABLE IF NOT EXISTS saved_views (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    owner TEXT NOT NULL,
+    name TEXT NOT NULL,
+    query TEXT NOT NULL,
+    sort TEXT,
+    columns TEXT, -- JSON array, NULL for the usual columns
+    pinned BOOLEAN NOT NULL DEFAULT 0,
+    is_default BOOLEAN NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL,
+    UNIQUE (owner, name)
+);
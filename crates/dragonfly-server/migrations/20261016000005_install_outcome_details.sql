@@ -0,0 +1,5 @@
+-- Which template each installation used and how long it took, for the dashboard trend charts
+ALTER TABLE install_outcomes ADD COLUMN template_name TEXT;
+ALTER TABLE install_outcomes ADD COLUMN duration_secs INTEGER;
+
+CREATE INDEX IF NOT EXISTS idx_install_outcomes_finished_at ON install_outcomes(finished_at);
@@ -0,0 +1,6 @@
+-- UI preferences of each user, as a JSON object; see `preferences::UiPreferences`
+CREATE TABLE IF NOT EXISTS user_preferences (
+    username TEXT PRIMARY KEY,
+    preferences TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+-- The public status board: whether it is served, its title and how often it reloads, as JSON
+ALTER TABLE app_settings ADD COLUMN status_board TEXT;
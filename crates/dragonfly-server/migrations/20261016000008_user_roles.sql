@@ -0,0 +1,4 @@
+-- Administrators may do anything; other accounts only what an ACL grants them. Accounts that
+-- exist already were administrators under the old rules and stay so.
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT 0;
+UPDATE users SET is_admin = 1;
@@ -0,0 +1,8 @@
+-- Rule/machine pairs already alerted on, so a restart doesn't email about them again. A pair is
+-- removed once the machine leaves the watched status.
+CREATE TABLE IF NOT EXISTS alert_firings (
+    rule_id INTEGER NOT NULL,
+    machine_id TEXT NOT NULL,
+    fired_at TEXT NOT NULL,
+    PRIMARY KEY (rule_id, machine_id)
+);
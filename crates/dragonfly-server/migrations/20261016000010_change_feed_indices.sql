@@ -0,0 +1,3 @@
+-- The machine change feed scans from its cursor's time rather than reading every machine
+CREATE INDEX IF NOT EXISTS idx_machines_updated_at ON machines(updated_at);
+CREATE INDEX IF NOT EXISTS idx_archived_machines_deleted_at ON archived_machines(deleted_at);
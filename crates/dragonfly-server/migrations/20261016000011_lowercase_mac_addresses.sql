@@ -0,0 +1,3 @@
+-- MACs are stored lower-case and colon-separated, so the UNIQUE index on mac_address catches the
+-- same NIC written two ways. A row whose normal form is already taken keeps its spelling.
+UPDATE OR IGNORE machines SET mac_address = lower(replace(trim(mac_address), '-', ':'));
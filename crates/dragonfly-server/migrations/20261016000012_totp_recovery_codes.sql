@@ -0,0 +1,14 @@
+-- Unused recovery code hashes, one row each, so using one is a single conditional DELETE and two
+-- logins can't both spend the same code. They were a JSON array in users.totp_recovery_codes.
+CREATE TABLE IF NOT EXISTS totp_recovery_codes (
+    user_id INTEGER NOT NULL,
+    code_hash TEXT NOT NULL,
+    PRIMARY KEY (user_id, code_hash),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+INSERT OR IGNORE INTO totp_recovery_codes (user_id, code_hash)
+SELECT users.id, codes.value FROM users, json_each(users.totp_recovery_codes) AS codes
+WHERE users.totp_recovery_codes IS NOT NULL;
+
+UPDATE users SET totp_recovery_codes = NULL;
@@ -0,0 +1,18 @@
+-- Columns machines and app_settings gained after the baseline. Databases from before versioned
+-- migrations have neither, so they are added here rather than in the baseline's CREATE TABLE.
+
+-- What the agent reports on each heartbeat, and the status to return to when it comes back
+ALTER TABLE machines ADD COLUMN clock_skew_seconds INTEGER;
+ALTER TABLE machines ADD COLUMN last_heartbeat TEXT;
+ALTER TABLE machines ADD COLUMN status_before_offline TEXT;
+ALTER TABLE machines ADD COLUMN agent_secret TEXT;
+ALTER TABLE machines ADD COLUMN vnc_target TEXT;
+-- Per-machine boot overrides
+ALTER TABLE machines ADD COLUMN boot_target TEXT;
+ALTER TABLE machines ADD COLUMN ipxe_override TEXT;
+
+ALTER TABLE app_settings ADD COLUMN hostname_policy TEXT;
+ALTER TABLE app_settings ADD COLUMN require_approval BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE app_settings ADD COLUMN boot_filter TEXT;
+ALTER TABLE app_settings ADD COLUMN generate_root_passwords BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE app_settings ADD COLUMN block_eol_assignments BOOLEAN NOT NULL DEFAULT 0;
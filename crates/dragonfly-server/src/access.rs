@@ -0,0 +1,166 @@
+//! Who can reach Dragonfly without signing in.
+//!
+//! With `require_login` set, every UI page but the status board sends anonymous visitors to the
+//! login page, and every read of the API answers `401`. Machines still reach what they need
+//! without a login: the agent's own routes and the render-token routes. With `require_boot_auth` set, the iPXE endpoints (`/<mac>`, `/grub/` and `/ipxe/`)
+//! only answer signed-in users and clients on the exempt subnets, which is where machines boot from.
+
+use anyhow::Result;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::{HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use std::net::{IpAddr, SocketAddr};
+use tracing::warn;
+
+use crate::auth::AuthSession;
+use crate::boot_filter::{in_subnet, parse_subnet};
+use crate::network;
+
+/// Pages anyone may load, so they can sign in, and the status board, which is only served when
+/// an admin has turned it on
+const PUBLIC_PATHS: &[&str] = &["/login", "/logout", "/static/", "/favicon.ico", "/status-board"];
+
+/// Where a request came from. X-Real-IP is only trusted from a reverse proxy on this host, so a
+/// client can't claim someone else's address or dodge the rate limiter.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let forwarded = headers.get("X-Real-IP")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    match forwarded {
+        Some(forwarded) if peer.is_loopback() => forwarded,
+        _ => peer,
+    }
+}
+
+pub(crate) fn request_client_ip(request: &Request<Body>) -> Option<IpAddr> {
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip())?;
+    Some(client_ip(peer, request.headers()))
+}
+
+/// iPXE scripts and boot artifacts, as opposed to the machine API
+fn is_boot_path(path: &str) -> bool {
+    network::is_provisioning_path(path) && !path.starts_with("/api/")
+}
+
+/// API routes machines use without a login: installers fetch their rendered config by token,
+/// and agents pick up commands. The liveness check returns nothing worth hiding.
+fn is_machine_api(path: &str) -> bool {
+    if path.starts_with("/api/render/") || path == "/api/heartbeat" {
+        return true;
+    }
+    let segments: Vec<&str> = path.trim_start_matches("/api/machines/").split('/').collect();
+    path.starts_with("/api/machines/") && matches!(
+        segments.as_slice(),
+        [_, "commands", "next"] | [_, "commands", _, "root-password"]
+    )
+}
+
+/// UI pages, and API requests that read the inventory: every GET, plus GraphQL queries, which
+/// are POSTed. Changes are checked by each handler, since agents report in through the same API.
+fn needs_login(method: &Method, path: &str) -> bool {
+    if let Some(api) = path.strip_prefix("/api/") {
+        let reads = method == Method::GET || method == Method::HEAD || api == "graphql";
+        return reads && !is_machine_api(path);
+    }
+    !PUBLIC_PATHS.iter().any(|public| path.starts_with(public))
+}
+
+/// Parse a newline/comma separated list of subnets in CIDR notation
+pub fn parse_subnets(list: &str) -> Result<Vec<String>> {
+    list.split(['\n', ','])
+        .map(str::trim)
+        .filter(|subnet| !subnet.is_empty())
+        .map(|subnet| parse_subnet(subnet).map(|_| subnet.to_string()))
+        .collect()
+}
+
+/// Refuse boot protection that no machine could get through
+pub fn check_boot_auth(require_boot_auth: bool, exempt_subnets: &[String]) -> Result<(), String> {
+    if require_boot_auth && exempt_subnets.is_empty() {
+        return Err("Protecting the boot endpoints needs at least one exempt subnet, or no machine can boot".to_string());
+    }
+    Ok(())
+}
+
+fn is_exempt(exempt_subnets: &[String], ip: Option<IpAddr>) -> bool {
+    let Some(ip) = ip else {
+        return false;
+    };
+    exempt_subnets.iter()
+        .filter_map(|subnet| parse_subnet(subnet).ok())
+        .any(|(net, prefix)| in_subnet(ip, net, prefix))
+}
+
+/// Layer enforcing `require_login` on pages and `require_boot_auth` on the iPXE endpoints
+pub async fn require_login(auth_session: AuthSession, request: Request, next: Next) -> Response {
+    if auth_session.user.is_some() {
+        return next.run(request).await;
+    }
+
+    let settings = crate::settings::current();
+    let path = request.uri().path();
+    if is_boot_path(path) {
+        let ip = request_client_ip(&request);
+        if settings.require_boot_auth && !is_exempt(&settings.boot_auth_exempt_subnets, ip) {
+            warn!("Refusing boot request for {} from {:?}: not on an exempt subnet", path, ip);
+            return (StatusCode::FORBIDDEN, "Boot endpoints are only served to exempt subnets").into_response();
+        }
+    } else if settings.require_login && needs_login(request.method(), path) {
+        if path.starts_with("/api/") {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        return Redirect::to("/login").into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_and_exemptions() {
+        assert!(is_boot_path("/aa:bb:cc:dd:ee:ff"));
+        assert!(is_boot_path("/ipxe/ubuntu/vmlinuz"));
+        assert!(!is_boot_path("/api/machines"));
+        assert!(needs_login(&Method::GET, "/machines"));
+        assert!(needs_login(&Method::GET, "/api/events"));
+        for read in ["/api/machines", "/api/machines/export", "/api/ws", "/api/summary", "/api/v1/stats/fleet", "/api/v1/stats/trends"] {
+            assert!(needs_login(&Method::GET, read), "{} is open", read);
+        }
+        assert!(needs_login(&Method::POST, "/api/graphql"));
+        assert!(!needs_login(&Method::POST, "/api/machines"));
+        assert!(!needs_login(&Method::GET, "/api/render/abc123/user-data"));
+        assert!(!needs_login(&Method::GET, "/api/machines/0b6e/commands/next"));
+        assert!(!needs_login(&Method::GET, "/api/machines/0b6e/commands/7/root-password"));
+        assert!(needs_login(&Method::GET, "/api/machines/0b6e/root-password"));
+        assert!(!needs_login(&Method::GET, "/login?next=/"));
+        assert!(!needs_login(&Method::GET, "/static/css/app.css"));
+        assert!(!needs_login(&Method::GET, "/status-board"));
+
+        let subnets = parse_subnets("10.0.5.0/24,\n fd00::/64").unwrap();
+        assert_eq!(subnets, vec!["10.0.5.0/24", "fd00::/64"]);
+        assert!(parse_subnets("10.0.5.0").is_err());
+        assert!(is_exempt(&subnets, "10.0.5.20".parse().ok()));
+        assert!(!is_exempt(&subnets, "10.0.6.20".parse().ok()));
+        assert!(!is_exempt(&subnets, None));
+        assert!(check_boot_auth(true, &[]).is_err());
+        assert!(check_boot_auth(true, &subnets).is_ok());
+        assert!(check_boot_auth(false, &[]).is_ok());
+    }
+
+    #[test]
+    fn test_client_ip_only_trusts_local_proxies() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Real-IP", "10.0.5.20".parse().unwrap());
+        let proxy: IpAddr = "127.0.0.1".parse().unwrap();
+        let remote: IpAddr = "192.168.1.9".parse().unwrap();
+        assert_eq!(client_ip(proxy, &headers), "10.0.5.20".parse::<IpAddr>().unwrap());
+        assert_eq!(client_ip(remote, &headers), remote);
+        assert_eq!(client_ip(proxy, &HeaderMap::new()), proxy);
+    }
+}
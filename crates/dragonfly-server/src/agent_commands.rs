@@ -0,0 +1,89 @@
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use dragonfly_common::agent_protocol::{self, CommandKind};
+use rand::RngCore;
+use serde_json::json;
+use std::env;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::db;
+use crate::policy::Permission;
+
+/// Shared token agents present once to enroll for remote commands. Enrollment is disabled when unset.
+pub fn enrollment_token() -> Option<String> {
+    env::var("DRAGONFLY_ENROLLMENT_TOKEN").ok().filter(|token| !token.is_empty())
+}
+
+/// Compare without bailing out at the first differing byte, so the token can't be guessed by timing
+pub fn token_matches(expected: &str, presented: &str) -> bool {
+    expected.len() == presented.len()
+        && expected.bytes().zip(presented.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Fresh per-machine secret used to sign agent requests
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Permission needed on a machine to queue a command for it
+pub fn required_permission(kind: &CommandKind) -> Permission {
+    match kind {
+        CommandKind::GatherInventory => Permission::Operate,
+        CommandKind::Reboot => Permission::Operate,
+        CommandKind::RunScript { .. } => Permission::Admin,
+        CommandKind::RotateRootPassword => Permission::Admin,
+    }
+}
+
+fn unauthorized(message: &str) -> Response {
+    (StatusCode::UNAUTHORIZED, Json(json!({
+        "error": "Unauthorized",
+        "message": message
+    }))).into_response()
+}
+
+/// Check that a request really comes from the agent enrolled for this machine
+pub async fn verify_agent_request(machine_id: &Uuid, method: &str, path: &str, headers: &HeaderMap, body: &[u8]) -> Result<(), Response> {
+    let secret = match db::get_agent_secret(machine_id).await {
+        Ok(Some(secret)) => secret,
+        Ok(None) => return Err(unauthorized("This machine's agent is not enrolled")),
+        Err(e) => {
+            error!("Failed to load agent secret for machine {}: {}", machine_id, e);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "error": "Database Error",
+                "message": e.to_string()
+            }))).into_response());
+        }
+    };
+
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let timestamp = header(agent_protocol::TIMESTAMP_HEADER).and_then(|value| value.parse::<i64>().ok());
+    let signature = header(agent_protocol::SIGNATURE_HEADER);
+    match (timestamp, signature) {
+        (Some(timestamp), Some(signature))
+            if agent_protocol::verify(&secret, method, path, timestamp, body, signature, Utc::now().timestamp()) => Ok(()),
+        _ => {
+            warn!("Rejected agent request for machine {} with a missing, stale or invalid signature", machine_id);
+            Err(unauthorized("Invalid or expired request signature"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("s3cret", "s3cret"));
+        assert!(!token_matches("s3cret", "s3crex"));
+        assert!(!token_matches("s3cret", "s3cre"));
+    }
+}
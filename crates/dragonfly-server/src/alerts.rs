@@ -0,0 +1,325 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use dragonfly_common::models::{Machine, MachineStatus};
+use lettre::message::{header::ContentType, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::db;
+use crate::template_vars::{self, VariableError};
+
+/// How often alert rules are evaluated
+pub const CHECK_INTERVAL_SECS: u64 = 60;
+
+/// Associated data the stored SMTP password is sealed with
+const PASSWORD_AAD: &[u8] = b"smtp_password";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// Upgrade a plain connection with STARTTLS, usually on port 587
+    #[default]
+    Starttls,
+    /// TLS from the first byte, usually on port 465
+    Tls,
+    /// No encryption; only for relays on a trusted network
+    None,
+}
+
+fn default_port() -> u16 {
+    587
+}
+
+/// The mail server alerts are sent through
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SmtpSettings {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: String,
+    /// Sender address, e.g. `Dragonfly <dragonfly@example.com>`
+    pub from: String,
+}
+
+impl SmtpSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.host.trim().is_empty() {
+            return Err("An SMTP host is required".to_string());
+        }
+        if self.from.parse::<Mailbox>().is_err() {
+            return Err(format!("'{}' is not a valid sender address", self.from));
+        }
+        Ok(())
+    }
+
+    /// Copy safe to show in the UI and API, without the password
+    pub fn redacted(&self) -> Self {
+        SmtpSettings { password: String::new(), ..self.clone() }
+    }
+}
+
+/// The SMTP settings with the password decrypted, if a mail server has been configured
+pub async fn smtp_settings() -> Result<Option<SmtpSettings>, VariableError> {
+    let Some(smtp) = db::get_smtp_settings().await? else {
+        return Ok(None);
+    };
+    if smtp.password.is_empty() {
+        return Ok(Some(smtp));
+    }
+    let password = template_vars::open(&template_vars::master_key()?, PASSWORD_AAD, &smtp.password)?;
+    Ok(Some(SmtpSettings { password, ..smtp }))
+}
+
+/// Save the SMTP settings, encrypting the password with the secrets key
+pub async fn save_smtp_settings(smtp: &SmtpSettings) -> Result<(), VariableError> {
+    let password = match smtp.password.is_empty() {
+        true => String::new(),
+        false => template_vars::seal(&template_vars::master_key()?, PASSWORD_AAD, &smtp.password)?,
+    };
+    db::save_smtp_settings(&SmtpSettings { password, ..smtp.clone() }).await?;
+    Ok(())
+}
+
+/// The statuses a rule can watch for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchedStatus {
+    Error,
+    Offline,
+    InstallingOs,
+    PendingApproval,
+    AwaitingAssignment,
+}
+
+impl WatchedStatus {
+    pub fn label(&self) -> &'static str {
+        match self {
+            WatchedStatus::Error => "Error",
+            WatchedStatus::Offline => "Offline",
+            WatchedStatus::InstallingOs => "Installing OS",
+            WatchedStatus::PendingApproval => "Pending Approval",
+            WatchedStatus::AwaitingAssignment => "Awaiting OS Assignment",
+        }
+    }
+
+    pub fn matches(&self, status: &MachineStatus) -> bool {
+        matches!(
+            (self, status),
+            (WatchedStatus::Error, MachineStatus::Error(_))
+                | (WatchedStatus::Offline, MachineStatus::Offline)
+                | (WatchedStatus::InstallingOs, MachineStatus::InstallingOS)
+                | (WatchedStatus::PendingApproval, MachineStatus::PendingApproval)
+                | (WatchedStatus::AwaitingAssignment, MachineStatus::AwaitingAssignment)
+        )
+    }
+}
+
+/// "Any machine in `status` for more than `minutes`", emailed to `recipients`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertRule {
+    #[serde(default)]
+    pub id: i64,
+    pub name: String,
+    pub status: WatchedStatus,
+    pub minutes: u32,
+    pub recipients: Vec<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl AlertRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("name must not be empty".to_string());
+        }
+        if self.minutes == 0 || self.minutes > 7 * 24 * 60 {
+            return Err("minutes must be between 1 and 10080 (one week)".to_string());
+        }
+        if self.recipients.is_empty() {
+            return Err("At least one recipient is required".to_string());
+        }
+        match self.recipients.iter().find(|r| r.parse::<Mailbox>().is_err()) {
+            Some(recipient) => Err(format!("'{}' is not a valid email address", recipient)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The machines each enabled rule currently matches: in the watched status since longer ago
+/// than the rule allows. `since` is when each machine last changed status.
+pub fn matching<'a>(rules: &[AlertRule], machines: &'a [Machine], since: &HashMap<Uuid, DateTime<Utc>>, now: DateTime<Utc>) -> Vec<(i64, Vec<&'a Machine>)> {
+    rules.iter()
+        .filter(|rule| rule.enabled)
+        .map(|rule| {
+            let stuck = machines.iter().filter(|m| {
+                let entered = since.get(&m.id).copied().unwrap_or(m.updated_at);
+                rule.status.matches(&m.status) && now - entered > Duration::minutes(rule.minutes as i64)
+            }).collect();
+            (rule.id, stuck)
+        })
+        .collect()
+}
+
+fn alert_body(rule: &AlertRule, machines: &[&Machine], since: &HashMap<Uuid, DateTime<Utc>>) -> String {
+    let mut body = format!("Alert rule '{}' matched {} machine(s) in {} for more than {} minutes:\n\n", rule.name, machines.len(), rule.status.label(), rule.minutes);
+    for machine in machines {
+        let entered = since.get(&machine.id).copied().unwrap_or(machine.updated_at);
+        body.push_str(&format!("  {} ({}, {}) - {} since {}\n", machine.display_name(), machine.mac_address, machine.ip_address, machine.status, entered.format("%Y-%m-%d %H:%M UTC")));
+    }
+    body
+}
+
+/// Send a plain-text email through the configured SMTP server
+pub async fn send_email(smtp: &SmtpSettings, recipients: &[String], subject: &str, body: String) -> Result<()> {
+    let builder = match smtp.security {
+        SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)?,
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)?,
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host),
+    };
+    let mut builder = builder.port(smtp.port).timeout(Some(std::time::Duration::from_secs(15)));
+    if let Some(username) = smtp.username.as_ref().filter(|u| !u.is_empty()) {
+        builder = builder.credentials(Credentials::new(username.clone(), smtp.password.clone()));
+    }
+
+    let mut message = Message::builder()
+        .from(smtp.from.parse::<Mailbox>().map_err(|e| anyhow!("Invalid sender address: {}", e))?)
+        .subject(subject);
+    for recipient in recipients {
+        message = message.to(recipient.parse::<Mailbox>().map_err(|e| anyhow!("Invalid recipient '{}': {}", recipient, e))?);
+    }
+    let message = message.header(ContentType::TEXT_PLAIN).body(body)?;
+
+    builder.build().send(message).await?;
+    Ok(())
+}
+
+/// Evaluate every rule once, emailing about machines that newly match
+pub async fn check_rules() -> Result<()> {
+    let rules = db::get_alert_rules().await?;
+    if rules.iter().all(|r| !r.enabled) {
+        return Ok(());
+    }
+    let (machines, since) = tokio::try_join!(db::get_all_machines(), db::get_status_entered_times())?;
+    let matches = matching(&rules, &machines, &since, Utc::now());
+
+    // Pairs already alerted on are stored, so a restart doesn't alert on them again. A pair is
+    // dropped once the machine leaves the status, so the next time it gets stuck sends a fresh alert.
+    let fired = db::get_alert_firings().await?;
+    let current: HashSet<(i64, Uuid)> = matches.iter()
+        .flat_map(|(rule_id, stuck)| stuck.iter().map(move |m| (*rule_id, m.id)))
+        .collect();
+    for (rule_id, machine_id) in fired.difference(&current) {
+        db::delete_alert_firing(*rule_id, machine_id).await?;
+    }
+    let new: Vec<(&AlertRule, Vec<&Machine>)> = matches.into_iter()
+        .filter_map(|(rule_id, stuck)| {
+            let rule = rules.iter().find(|r| r.id == rule_id)?;
+            let fresh: Vec<&Machine> = stuck.into_iter().filter(|m| !fired.contains(&(rule_id, m.id))).collect();
+            (!fresh.is_empty()).then_some((rule, fresh))
+        })
+        .collect();
+    if new.is_empty() {
+        return Ok(());
+    }
+
+    let Some(smtp) = smtp_settings().await? else {
+        warn!("{} alert rule(s) matched but no SMTP server is configured", new.len());
+        return Ok(());
+    };
+    for (rule, stuck) in new {
+        let subject = format!("[Dragonfly] {}: {} machine(s)", rule.name, stuck.len());
+        match send_email(&smtp, &rule.recipients, &subject, alert_body(rule, &stuck, &since)).await {
+            Ok(()) => {
+                info!("Sent alert '{}' for {} machine(s)", rule.name, stuck.len());
+                let ids: Vec<Uuid> = stuck.iter().map(|m| m.id).collect();
+                db::record_alert_firings(rule.id, &ids).await?;
+            },
+            // Not marked as fired, so the next check tries again
+            Err(e) => error!("Failed to send alert '{}': {}", rule.name, e),
+        }
+    }
+    Ok(())
+}
+
+/// Evaluate alert rules every minute until shutdown
+pub async fn start_alert_monitor(mut shutdown_rx: tokio::sync::watch::Receiver<()>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(CHECK_INTERVAL_SECS));
+        info!("Alert monitor started");
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = check_rules().await {
+                        error!("Failed to evaluate alert rules: {}", e);
+                    } else {
+                        debug!("Alert rules evaluated");
+                    }
+                },
+                _ = shutdown_rx.changed() => {
+                    info!("Shutdown signal received, stopping alert monitor.");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watched_status() {
+        assert!(WatchedStatus::Error.matches(&MachineStatus::Error("OS installation failed".to_string())));
+        assert!(WatchedStatus::InstallingOs.matches(&MachineStatus::InstallingOS));
+        assert!(!WatchedStatus::Offline.matches(&MachineStatus::Ready));
+    }
+
+    #[test]
+    fn test_matching() {
+        let now = Utc::now();
+        let rule = |id: i64, status: WatchedStatus, enabled: bool| AlertRule {
+            id,
+            name: format!("rule {}", id),
+            status,
+            minutes: 30,
+            recipients: vec!["ops@example.com".to_string()],
+            enabled,
+        };
+        let stuck = Machine { status: MachineStatus::Error("disk not found".to_string()), ..crate::test_support::machine("aa:bb:cc:dd:ee:01") };
+        let recent = Machine { status: MachineStatus::Error("disk not found".to_string()), ..crate::test_support::machine("aa:bb:cc:dd:ee:02") };
+        // No status history, so it counts from when the machine was last updated
+        let untracked = Machine { updated_at: now - Duration::hours(2), ..crate::test_support::machine("aa:bb:cc:dd:ee:03") };
+        let since = HashMap::from([(stuck.id, now - Duration::minutes(45)), (recent.id, now - Duration::minutes(5))]);
+        let machines = vec![stuck.clone(), recent, untracked.clone()];
+
+        let rules = vec![
+            rule(1, WatchedStatus::Error, true),
+            rule(2, WatchedStatus::Error, false),
+            rule(3, WatchedStatus::Offline, true),
+        ];
+        let ids = |matched: &[&Machine]| matched.iter().map(|m| m.id).collect::<Vec<_>>();
+        let matches = matching(&rules, &machines, &since, now);
+        assert_eq!(matches.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(ids(&matches[0].1), vec![stuck.id]);
+        assert!(matches[1].1.is_empty());
+
+        let offline = Machine { status: MachineStatus::Offline, ..untracked };
+        let matches = matching(&rules[2..], std::slice::from_ref(&offline), &since, now);
+        assert_eq!(ids(&matches[0].1), vec![offline.id]);
+    }
+}
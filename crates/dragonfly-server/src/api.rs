@@ -3,7 +3,7 @@ use axum::{
     Router,
     extract::{
         State, Path, Json, Form, FromRequest,
-        ConnectInfo,
+        ConnectInfo, Query, OriginalUri, ws::WebSocketUpgrade,
     },
     http::{StatusCode, header::HeaderValue, HeaderMap},
     response::{IntoResponse, Html, Response, sse::{Event, Sse, KeepAlive}, Redirect},
@@ -11,10 +11,25 @@ use axum::{
 use std::convert::Infallible;
 use serde_json::json;
 use uuid::Uuid;
-use dragonfly_common::models::{MachineStatus, HostnameUpdateRequest, HostnameUpdateResponse, OsInstalledUpdateRequest, OsInstalledUpdateResponse, BmcType, BmcCredentials, StatusUpdateRequest, BmcCredentialsUpdateRequest, InstallationProgressUpdateRequest, RegisterRequest, Machine};
+use dragonfly_common::models::{MachineStatus, HostnameUpdateRequest, HostnameUpdateResponse, OsInstalledUpdateRequest, OsInstalledUpdateResponse, BmcType, BmcCredentials, StatusUpdateRequest, BmcCredentialsUpdateRequest, InstallationProgressUpdateRequest, RegisterRequest, Machine, ClockReportRequest, ClockReportResponse, PciReportRequest};
 use crate::db::{self, RegisterResponse, ErrorResponse, OsAssignmentRequest, get_machine_tags, update_machine_tags as db_update_machine_tags};
 use crate::AppState;
 use crate::auth::AuthSession;
+use crate::policy::{self, AclEntry, Permission};
+use crate::webhooks::{self, WebhookEndpoint};
+use crate::notifications::{self, NotificationChannel};
+use crate::alerts::{self, AlertRule, SmtpSettings};
+use crate::operations::{self, Operation, OperationError};
+use crate::discovery::{self, DiscoveryPolicy};
+use crate::vnc::VncTarget;
+use crate::users::{self, UserError};
+use crate::artifact_store::{ArtifactStorage, S3Storage};
+use crate::custom_fields::{self, FieldDefinition};
+use crate::topology::{self, Placement, Rack, Site};
+use crate::template_vars::{TemplateVariable, VariableError};
+use crate::root_password::PasswordError;
+use dragonfly_common::agent_protocol::{truncate_output, CommandKind, CommandResult, EnrollRequest, EnrollResponse};
+use dragonfly_common::state_machine::{InvalidTransition, StatusCause};
 use std::collections::HashMap;
 use tracing::{info, error, warn, debug};
 use std::env;
@@ -51,27 +66,205 @@ use axum::http::Request;
 use std::net::SocketAddr;
 use axum::middleware::Next; // Add this import back
 use chrono::Utc;
+use crate::rate_limit::{AccountFrom, RateLimitLayer};
 
 pub fn api_router() -> Router<crate::AppState> {
     Router::new()
         .route("/machines", get(get_all_machines).post(register_machine))
         .route("/machines/install-status", get(get_install_status))
+        .route("/machines/import", post(import_machines))
+        .route("/machines/export", get(export_machines))
         .route("/machines/{id}/os", get(get_machine_os).post(assign_os))
+        .route("/machines/{id}/reimage", post(reimage_machine))
         .route("/machines/{id}/hostname", get(get_hostname_form).put(update_hostname))
+        .route("/machines/{id}/hostname/generate", post(generate_hostname))
         .route("/machines/{id}/status", put(update_status))
+        .route("/machines/{id}/status-history", get(get_status_history))
+        .route("/machines/{id}/ipxe", get(get_ipxe_override).put(update_ipxe_override))
+        .route("/machines/{id}/approve", post(approve_machine))
+        .route("/machines/{id}/reject", post(reject_machine))
         .route("/machines/{id}/status-and-progress", get(get_machine_status_and_progress_partial))
         .route("/machines/{id}/os-installed", put(update_os_installed))
+        .route("/machines/{id}/clock", put(report_clock))
+        .route("/machines/{id}/pci-devices", get(get_machine_pci_devices).put(report_pci_devices))
+        .route("/machines/{id}/health-checks", get(get_machine_health_report))
+        .route("/machines/{id}/heartbeat", post(machine_heartbeat))
+        // The enrollment token is a shared secret, so guessing it is limited per address and machine
+        .route("/machines/{id}/agent/enroll", post(enroll_agent).layer(RateLimitLayer::new(AccountFrom::PathSegment(1))))
+        .route("/machines/{id}/agent", delete(reset_agent_enrollment))
+        .route("/machines/{id}/agent/challenge", post(agent_attestation_challenge))
+        .route("/machines/{id}/tpm", get(get_machine_tpm).delete(clear_machine_tpm))
+        .route("/machines/{id}/disk-key", post(issue_disk_key))
+        .route("/machines/{id}/disk-keys", get(list_disk_keys))
+        .route("/machines/{id}/disk-keys/{key_id}/reveal", post(reveal_disk_key))
+        .route("/disk-key-audit", get(list_disk_key_accesses))
+        .route("/machines/{id}/commands", get(list_agent_commands).post(queue_agent_command))
+        .route("/machines/{id}/commands/next", get(next_agent_command))
+        .route("/machines/{id}/commands/{command_id}/result", post(report_command_result))
+        .route("/machines/{id}/commands/{command_id}/root-password", get(get_rotation_password))
+        .route("/machines/{id}/root-password", get(get_root_password))
+        .route("/machines/{id}/root-password/reveal", post(reveal_root_password))
         .route("/machines/{id}/bmc", post(update_bmc))
+        .route("/machines/{id}/bmc/jobs", get(list_bmc_jobs).post(start_bmc_job))
+        .route("/machines/{id}/console", get(machine_console))
+        .route("/machines/{id}/vnc", get(machine_vnc))
+        .route("/machines/{id}/vnc/target", get(get_vnc_target).put(update_vnc_target))
         .route("/machines/{id}/workflow-progress", get(get_workflow_progress))
+        .route("/machines/{id}/fields", get(get_machine_fields).put(update_machine_fields))
+        .route("/machines/{id}/placement", get(get_machine_placement).put(update_machine_placement).delete(delete_machine_placement))
+        .route("/machines/{id}/firmware", get(get_machine_firmware))
+        .route("/machines/{id}/storage-profile", get(get_machine_storage_profile).put(update_machine_storage_profile))
+        .route("/machines/{id}/machine-profile", get(get_machine_machine_profile).put(update_machine_machine_profile))
+        .route("/machines/{id}/pipeline", get(get_machine_pipeline_run))
+        .route("/machines/{id}/pipeline/resume", post(resume_machine_pipeline))
         .route("/machines/{id}/tags", get(api_get_machine_tags).put(api_update_machine_tags))
         .route("/machines/{id}/tags/{tag}", delete(api_delete_machine_tag))
         .route("/machines/{id}", get(get_machine).put(update_machine).delete(delete_machine))
+        .route("/archived-machines", get(list_archived_machines))
+        .route("/archived-machines/{id}", get(get_archived_machine))
         .route("/installation/progress", put(update_installation_progress))
+        .route("/templates", get(list_workflow_templates).post(create_workflow_template))
+        .route("/templates/stats", get(get_template_stats))
+        .route("/templates/{name}", get(get_workflow_template).put(update_workflow_template).delete(delete_workflow_template))
+        .route("/templates/{name}/render", get(render_workflow_template))
+        .route("/templates/{name}/storage-profile", put(update_template_storage_profile))
+        .route("/templates/{name}/health-checks", get(get_template_health_checks).put(update_template_health_checks))
+        .route("/templates/{name}/versions", get(list_workflow_template_versions))
+        .route("/templates/{name}/versions/{version}", get(get_workflow_template_version))
+        .route("/firmware", get(list_firmware_images).post(add_firmware_image))
+        .route("/firmware/{id}", delete(delete_firmware_image))
+        .route("/firmware/{id}/binary", put(upload_firmware_binary).layer(axum::extract::DefaultBodyLimit::max(crate::firmware::MAX_BINARY_BYTES)))
+        .route("/storage-profiles", get(list_storage_profiles).post(create_storage_profile))
+        .route("/storage-profiles/{name}", get(get_storage_profile).put(update_storage_profile).delete(delete_storage_profile))
+        .route("/machine-profiles", get(list_machine_profiles).post(create_machine_profile))
+        .route("/machine-profiles/apply", post(apply_machine_profiles))
+        .route("/machine-profiles/{name}", get(get_machine_profile).put(update_machine_profile).delete(delete_machine_profile))
+        .route("/maintenance-windows", get(list_maintenance_windows).post(add_maintenance_window))
+        .route("/maintenance-windows/{id}", put(update_maintenance_window).delete(delete_maintenance_window))
+        .route("/views", get(list_saved_views).post(add_saved_view))
+        .route("/views/{id}", get(get_saved_view).put(update_saved_view).delete(delete_saved_view))
+        .route("/preferences", get(get_preferences).put(update_preferences))
+        .route("/provisioning-holds", get(list_provisioning_holds))
+        .route("/provisioning-holds/{id}", delete(cancel_provisioning_hold))
+        .route("/provisioning-holds/{id}/release", post(release_provisioning_hold))
+        .route("/install-limits", get(get_install_limits).put(update_install_limits))
+        .route("/install-queue", get(get_install_queue))
+        .route("/install-queue/{id}", put(update_queued_install).delete(cancel_queued_install))
+        .route("/rollouts", get(list_rollouts).post(create_rollout))
+        .route("/rollouts/{id}", get(get_rollout))
+        .route("/rollouts/{id}/pause", post(pause_rollout))
+        .route("/rollouts/{id}/resume", post(resume_rollout))
+        .route("/rollouts/{id}/cancel", post(cancel_rollout))
+        .route("/reimage-schedules", get(list_reimage_schedules).post(create_reimage_schedule))
+        .route("/reimage-schedules/{id}", get(get_reimage_schedule).put(update_reimage_schedule).delete(delete_reimage_schedule))
+        .route("/reimage-schedules/{id}/run", post(run_reimage_schedule))
+        .route("/reimage-schedules/{id}/runs", get(list_reimage_runs))
+        .route("/pipelines", get(list_pipelines).post(create_pipeline))
+        .route("/pipelines/{name}", get(get_pipeline).put(update_pipeline).delete(delete_pipeline))
+        .route("/acls", get(list_acl_entries).post(add_acl_entry))
+        .route("/ipxe-templates", get(list_ipxe_templates))
+        .route("/ipxe-templates/{name}", put(update_ipxe_template).delete(reset_ipxe_template))
+        .route("/custom-fields", get(list_custom_fields).post(save_custom_field))
+        .route("/custom-fields/{name}", delete(delete_custom_field))
+        .route("/template-variables", get(list_template_variables).post(save_template_variable))
+        .route("/template-variables/{name}", delete(delete_template_variable))
+        .route("/search-replace", post(search_and_replace))
+        .route("/search-replace/audit", get(list_replace_audit))
+        .route("/render/{token}/user-data", get(get_rendered_user_data))
+        .route("/render/{token}/meta-data", get(get_rendered_meta_data))
+        .route("/render/{token}/storage", get(get_rendered_storage_script))
+        .route("/render/{token}/disk-key", post(issue_rendered_disk_key))
+        .route("/render/{token}/firmware", post(plan_firmware_updates))
+        .route("/render/{token}/firmware/report", post(report_firmware_results))
+        .route("/render/{token}/firmware/{id}", get(download_firmware_binary))
+        .route("/os-lifecycle", get(list_os_lifecycle))
+        .route("/os-lifecycle/{template}", put(update_os_lifecycle).delete(delete_os_lifecycle))
+        .route("/compliance", get(get_compliance))
+        .route("/sites", get(list_sites).post(save_site))
+        .route("/sites/{name}", delete(delete_site))
+        .route("/racks", get(list_racks).post(save_rack))
+        .route("/racks/{name}", get(get_rack_elevation).delete(delete_rack))
+        .route("/racks/{name}/assign-os", post(assign_os_to_rack))
+        .route("/template-scopes", get(list_template_scopes))
+        .route("/template-scopes/{template}", put(update_template_scope).delete(delete_template_scope))
+        .route("/secure-boot/images", get(list_signed_boot_images))
+        .route("/secure-boot/images/{template}", put(update_signed_boot_image).delete(delete_signed_boot_image))
+        .route("/acls/{id}", delete(delete_acl_entry))
+        .route("/webhooks", get(list_webhooks).post(add_webhook))
+        .route("/webhooks/{id}", put(update_webhook).delete(delete_webhook))
+        .route("/webhooks/{id}/test", post(test_webhook))
+        .route("/notification-channels", get(list_notification_channels).post(add_notification_channel))
+        .route("/notification-channels/{id}", put(update_notification_channel).delete(delete_notification_channel))
+        .route("/notification-channels/{id}/test", post(test_notification_channel))
+        .route("/smtp", get(get_smtp_settings).put(update_smtp_settings))
+        .route("/smtp/test", post(test_smtp_settings))
+        .route("/alert-rules", get(list_alert_rules).post(add_alert_rule))
+        .route("/alert-rules/{id}", put(update_alert_rule).delete(delete_alert_rule))
+        .route("/artifact-storage", get(get_artifact_storage).put(update_artifact_storage))
+        .route("/discovery/policy", get(get_discovery_policy).put(update_discovery_policy))
+        .route("/discovery/scans", get(list_discovery_scans).post(run_discovery_scan))
+        .route("/assignment-policy/simulate", post(simulate_assignment_policy))
+        .route("/discovery/scans/{id}", get(get_discovery_scan))
+        .route("/conflicts", get(list_conflicts))
+        .route("/hardware/drift", get(get_hardware_drift))
+        .route("/hardware/reconcile", post(reconcile_hardware))
+        .route("/break-glass", get(list_break_glass_credentials))
+        .route("/freeze", get(get_freeze).put(update_freeze))
+        .route("/freeze/audit", get(list_freeze_records))
+        .route("/admin/version", get(get_version))
+        .route("/admin/upgrade", post(start_upgrade))
+        .route("/admin/upgrades", get(list_upgrades))
+        .route("/settings", get(get_settings).put(update_settings))
+        .route("/scenarios", get(list_scenarios).post(upload_scenario))
+        .route("/scenarios/recording", get(get_scenario_recording).post(start_scenario_recording).delete(stop_scenario_recording))
+        .route("/scenarios/replay", get(get_scenario_replay).delete(cancel_scenario_replay))
+        .route("/scenarios/{name}", get(get_scenario).delete(delete_scenario))
+        .route("/scenarios/{name}/replay", post(replay_scenario))
+        .route("/users", get(list_users).post(create_user))
+        .route("/users/me/password", put(change_own_password))
+        .route("/users/me/totp", get(get_own_totp).post(begin_totp_enrollment).delete(disable_own_totp))
+        .route("/users/me/totp/confirm", post(confirm_totp_enrollment))
+        .route("/users/me/totp/recovery-codes", post(regenerate_recovery_codes))
+        .route("/users/{id}", put(update_user).delete(delete_user))
+        .route("/users/{id}/totp", delete(reset_user_totp))
         .route("/events", get(machine_events))
+        .route("/ws", get(event_socket))
+        .route("/graphql", get(graphiql).post(graphql))
         .route("/heartbeat", get(heartbeat))
+        .route("/metrics", get(get_metrics))
+        .route("/v1/observability/bundle", get(get_observability_bundle))
+        .route("/v1/config/effective", get(get_effective_config))
+        .route("/v1/stats/fleet", get(get_fleet_stats))
+        .route("/v1/stats/trends", get(get_provisioning_trends))
+        .route("/summary", get(get_fleet_summary))
+        .route("/v1/stack/health", get(get_stack_health))
+        .route("/v1/stack/{component}/logs", get(get_stack_logs))
+        .route("/v1/chatops/command", post(chatops_command))
+        .route("/v1/machines/changes", get(get_machine_changes))
+        .route("/v1/machines", post(create_machine_resource))
+        .route("/v1/machines/{id}", get(get_machine_resource).patch(update_machine_resource).delete(delete_machine_resource))
+        .route("/v1/machines/{id}/render", get(render_machine_summary))
+        .route("/v1/machine-claims", post(claim_machine))
+        .route("/v1/machine-claims/{key}", delete(release_machine_claim))
+        .route("/v1/batch", post(run_batch))
+        .route("/v1/operations", get(list_operations))
+        .route("/v1/operations/{id}", get(get_operation))
+        .route("/v1/operations/{id}/cancel", post(cancel_operation))
         // --- Proxmox Routes ---
         .route("/proxmox/connect", post(crate::handlers::proxmox::connect_proxmox_handler))
         .route("/proxmox/discover", get(crate::handlers::proxmox::discover_proxmox_handler))
+        .merge(chaos_routes())
+}
+
+// Fault injection only exists in builds made for integration tests
+#[cfg(feature = "chaos")]
+fn chaos_routes() -> Router<crate::AppState> {
+    Router::new().route("/admin/chaos", get(get_chaos).put(update_chaos).delete(clear_chaos))
+}
+
+#[cfg(not(feature = "chaos"))]
+fn chaos_routes() -> Router<crate::AppState> {
+    Router::new()
 }
 
 // Content constants
@@ -264,30 +457,16 @@ async fn register_machine(
     // Ensure the payload type is correct, matching the updated common struct
     Json(payload): Json<RegisterRequest>,
 ) -> Response {
-    // Pass the full payload (including new hardware fields) to the db function
-    info!("Registering machine with MAC: {}, CPU: {:?}, Cores: {:?}, RAM: {:?}", 
-          payload.mac_address, payload.cpu_model, payload.cpu_cores, payload.total_ram_bytes);
-    
-    match db::register_machine(&payload).await {
-        Ok(machine_id) => {
-            // Get the new machine to register with Tinkerbell
-            if let Ok(Some(machine)) = db::get_machine_by_id(&machine_id).await {
-                // Register with Tinkerbell (don't fail if this fails)
-                if let Err(e) = crate::tinkerbell::register_machine(&machine).await {
-                    warn!("Failed to register machine with Tinkerbell (continuing anyway): {}", e);
-                }
-            }
-            
-            // Emit machine discovered event
-            let _ = state.event_manager.send(format!("machine_discovered:{}", machine_id));
-            
-            let response = RegisterResponse {
-                machine_id,
-                next_step: "awaiting_os_assignment".to_string(),
+    match register_agent(&payload, &state.event_manager).await {
+        Ok(response) => (StatusCode::CREATED, Json(response)).into_response(),
+        Err(RegistrationError::AttestationFailed(message)) => {
+            let error_response = ErrorResponse {
+                error: "Attestation Failed".to_string(),
+                message,
             };
-            (StatusCode::CREATED, Json(response)).into_response()
+            (StatusCode::CONFLICT, Json(error_response)).into_response()
         },
-        Err(e) => {
+        Err(RegistrationError::Database(e)) => {
             error!("Failed to register machine: {}", e);
             let error_response = ErrorResponse {
                 error: "Registration Failed".to_string(),
@@ -298,9 +477,577 @@ async fn register_machine(
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum RegistrationError {
+    /// A known machine presented different TPM keys
+    #[error("{0}")]
+    AttestationFailed(String),
+    #[error(transparent)]
+    Database(#[from] anyhow::Error),
+}
+
+// Register a machine reported by an agent, over HTTP or gRPC
+pub async fn register_agent(payload: &RegisterRequest, event_manager: &crate::event_manager::EventManager) -> Result<RegisterResponse, RegistrationError> {
+    // Pass the full payload (including new hardware fields) to the db function
+    info!("Registering machine with MAC: {}, CPU: {:?}, Cores: {:?}, RAM: {:?}", 
+          payload.mac_address, payload.cpu_model, payload.cpu_cores, payload.total_ram_bytes);
+    
+    // Only newly adopted machines get a hostname from the policy
+    let existing = db::get_machine_by_mac(&payload.mac_address).await;
+    let is_new_machine = matches!(existing, Ok(None));
+    
+    // A known machine presenting different TPM keys is something else using its MAC address
+    if let (Ok(Some(machine)), Some(identity)) = (&existing, &payload.tpm) {
+        match crate::attestation::record_identity(&machine.id, identity).await {
+            Ok(Ok(())) => {},
+            Ok(Err(message)) => {
+                let _ = event_manager.send(format!("attestation_failed:{}", machine.id));
+                return Err(RegistrationError::AttestationFailed(message));
+            },
+            Err(e) => warn!("Failed to check TPM identity of machine {}: {}", machine.id, e),
+        }
+    }
+    // So is one that leaves the keys out, and with DRAGONFLY_REQUIRE_TPM nothing gets in without a TPM
+    if payload.tpm.is_none() {
+        let known = existing.as_ref().ok().and_then(|machine| machine.as_ref()).map(|machine| machine.id);
+        if let Err(message) = crate::attestation::check_missing_tpm(known.as_ref()).await? {
+            if let Some(id) = known {
+                let _ = event_manager.send(format!("attestation_failed:{}", id));
+            }
+            return Err(RegistrationError::AttestationFailed(message));
+        }
+    }
+    
+    let machine_id = db::register_machine(payload).await?;
+    
+    // Pin the TPM keys of a newly seen machine
+    if let (true, Some(identity)) = (is_new_machine, &payload.tpm) {
+        match crate::attestation::record_identity(&machine_id, identity).await {
+            Ok(Ok(())) => {},
+            Ok(Err(message)) => warn!("Not pinning TPM keys of machine {}: {}", machine_id, message),
+            Err(e) => warn!("Failed to record TPM identity of machine {}: {}", machine_id, e),
+        }
+    }
+    
+    if payload.system_vendor.is_some() || payload.system_model.is_some() || payload.system_serial.is_some() {
+        if let Err(e) = db::set_machine_system_info(
+            &machine_id,
+            payload.system_vendor.as_deref(),
+            payload.system_model.as_deref(),
+            payload.system_serial.as_deref(),
+        ).await {
+            warn!("Failed to record system vendor, model and serial of machine {}: {}", machine_id, e);
+        }
+    }
+    
+    // Match a newly seen machine to its profile, which can set its hostname policy, OS and network
+    let profile = if is_new_machine {
+        crate::machine_profiles::adopt(&machine_id).await.unwrap_or_else(|e| {
+            warn!("Failed to match machine {} against machine profiles: {}", machine_id, e);
+            None
+        })
+    } else {
+        None
+    };
+    
+    // Apply the hostname policy before the machine is registered with Tinkerbell
+    if is_new_machine {
+        if let Err(e) = crate::hostname_policy::apply_to_machine(&machine_id).await {
+            warn!("Failed to apply hostname policy to machine {}: {}", machine_id, e);
+        }
+    }
+    
+    // Remember the architecture and firmware the agent saw, so Tinkerbell hands out the right bootloader
+    if let Some(target) = crate::boot_arch::BootTarget::from_agent(payload.cpu_arch.as_deref(), payload.uefi, payload.secure_boot) {
+        if let Err(e) = db::update_boot_target(&machine_id, &target).await {
+            warn!("Failed to record boot target for machine {}: {}", machine_id, e);
+        }
+        // Stock iPXE won't start on these, so they can only reinstall through the signed chain
+        if let Some(shim) = crate::secure_boot::shim_path(&target).filter(|_| target.secure_boot) {
+            if crate::secure_boot::chain_enabled() {
+                info!("Machine {} has Secure Boot enabled; its DHCP boot filename should be /ipxe/{}", machine_id, shim);
+            } else {
+                warn!("Machine {} has Secure Boot enabled and cannot run iPXE; set DRAGONFLY_SECURE_BOOT_CHAIN=true and point its DHCP boot filename at /ipxe/{}", machine_id, shim);
+            }
+        }
+    }
+    
+    // Agents that don't enumerate PCI devices (gRPC, discovery, imports) leave the inventory alone
+    if !payload.pci_devices.is_empty() {
+        if let Err(e) = db::set_pci_devices(&machine_id, &payload.pci_devices).await {
+            warn!("Failed to record PCI devices for machine {}: {}", machine_id, e);
+        }
+    }
+    
+    // Get the new machine to register with Tinkerbell
+    let mut pending_approval = false;
+    if let Ok(Some(machine)) = db::get_machine_by_id(&machine_id).await {
+        if machine.status.is_approval_gated() {
+            // Machines awaiting approval stay out of Tinkerbell until approved
+            info!("Machine {} is {}, skipping Tinkerbell registration", machine_id, machine.status);
+            pending_approval = true;
+        } else if let Err(e) = crate::tinkerbell::register_machine(&machine).await {
+            // Register with Tinkerbell (don't fail if this fails)
+            warn!("Failed to register machine with Tinkerbell (continuing anyway): {}", e);
+        }
+    }
+    
+    // Zero-touch: a profile with an OS starts installing it straight away
+    if !pending_approval && profile.is_some_and(|profile| profile.os_template.is_some()) {
+        apply_default_os(&machine_id).await;
+    }
+    
+    // Record clock skew if the agent reported its time
+    if let Some(agent_time) = payload.agent_time {
+        if let Err(e) = record_clock_skew(&machine_id, agent_time).await {
+            warn!("Failed to record clock skew for machine {}: {}", machine_id, e);
+        }
+    }
+    
+    // Emit machine discovered event
+    let _ = event_manager.send(format!("machine_discovered:{}", machine_id));
+    
+    Ok(RegisterResponse {
+        machine_id,
+        next_step: if pending_approval { "pending_approval" } else { "awaiting_os_assignment" }.to_string(),
+    })
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ImportQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Overwrite the hostname, IP and labels of machines that already exist
+    #[serde(default)]
+    pub update: bool,
+}
+
+// Pre-create machines from a CSV (`Content-Type: text/csv`) or JSON manifest
+#[axum::debug_handler]
+async fn import_machines(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Query(query): Query<ImportQuery>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let is_csv = headers.get(axum::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/csv"));
+    let parsed = if is_csv { crate::import::parse_csv(&body) } else { crate::import::parse_json(&body) };
+    let entries = match parsed {
+        Ok(entries) => entries,
+        Err(message) => return json_error(StatusCode::BAD_REQUEST, "Bad Request", message),
+    };
+
+    let actor = policy::principal(&auth_session);
+    // A dry run only previews, so it answers straight away
+    if query.dry_run {
+        return match crate::import::import(entries, query.update, true, &actor).await {
+            Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+            Err(e) => {
+                error!("Failed to import machines: {}", e);
+                json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
+            }
+        };
+    }
+
+    let description = format!("Import {} machines", entries.len());
+    let operation = operations::start("machine_import", description, &actor.clone(), move |ctx| async move {
+        if ctx.is_cancelled() {
+            return Ok(json!({ "imported": false }));
+        }
+        let report = crate::import::import(entries, query.update, false, &actor).await?;
+        for id in report.created.iter().filter_map(|m| m.machine_id) {
+            let _ = state.event_manager.send(format!("machine_discovered:{}", id));
+        }
+        for id in report.updated.iter().filter_map(|m| m.machine_id) {
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+        }
+        Ok(serde_json::to_value(report)?)
+    });
+    operation_accepted(&operation)
+}
+
+// Download the inventory as CSV or JSON. Takes the same filters as the machine list, plus
+// `format=csv|json` and `columns=a,b,field.owner`.
+#[axum::debug_handler]
+async fn export_machines(
+    auth_session: AuthSession,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let format = match crate::export::Format::parse(query.get("format").map(String::as_str)) {
+        Ok(format) => format,
+        Err(message) => return json_error(StatusCode::BAD_REQUEST, "Bad Request", message),
+    };
+    let machines = match search_machines(&query).await {
+        Ok(machines) => machines,
+        Err(response) => return response,
+    };
+    let loaded = tokio::try_join!(
+        db::get_custom_fields(),
+        db::get_all_field_values(),
+        db::get_placements(),
+        db::get_racks(),
+    );
+    let (fields, field_values, placements, racks) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("Failed to load the inventory for export: {}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string());
+        }
+    };
+    let columns = match crate::export::parse_columns(query.get("columns").map(String::as_str), &fields) {
+        Ok(columns) => columns,
+        Err(message) => return json_error(StatusCode::BAD_REQUEST, "Bad Request", message),
+    };
+    let machines = match filter_by_custom_fields(machines, &query).await {
+        Ok(machines) => machines,
+        Err(response) => return response,
+    };
+    let machines = match filter_by_location(machines, &query).await {
+        Ok(machines) => machines,
+        Err(response) => return response,
+    };
+    let machines = match filter_by_capabilities(machines, &query).await {
+        Ok(machines) => machines,
+        Err(response) => return response,
+    };
+
+    let inventory = crate::export::Inventory { field_values, placements, racks };
+    let (content_type, body) = match format {
+        crate::export::Format::Csv => ("text/csv; charset=utf-8", inventory.to_csv(&machines, &columns)),
+        crate::export::Format::Json => ("application/json", serde_json::to_string_pretty(&inventory.to_json(&machines, &columns)).unwrap_or_default()),
+    };
+    let disposition = format!("attachment; filename=\"machines-{}.{}\"", Utc::now().format("%Y%m%d"), format.extension());
+    info!("Exported {} machines as {} for '{}'", machines.len(), format.extension(), policy::principal(&auth_session));
+    (
+        StatusCode::OK,
+        [(axum::http::header::CONTENT_TYPE, content_type.to_string()), (axum::http::header::CONTENT_DISPOSITION, disposition)],
+        body,
+    ).into_response()
+}
+
+// Apply many small machine updates in one request, atomically unless the batch says otherwise
+#[axum::debug_handler]
+async fn run_batch(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Json(request): Json<crate::batch::BatchRequest>,
+) -> Response {
+    if request.operations.len() > crate::batch::MAX_OPERATIONS {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", format!("A batch can hold at most {} operations", crate::batch::MAX_OPERATIONS));
+    }
+    let machine_ids: std::collections::BTreeSet<Uuid> = request.operations.iter().map(|op| op.machine_id()).collect();
+    for id in &machine_ids {
+        if let Err(response) = policy::authorize(&auth_session, id, Permission::Operate).await {
+            return response;
+        }
+    }
+
+    let report = match crate::batch::run(&request, &policy::principal(&auth_session), &state.event_manager).await {
+        Ok(report) => report,
+        Err(e) => {
+            error!("Failed to run batch: {}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string());
+        }
+    };
+
+    let status = if report.atomic && !report.committed { StatusCode::UNPROCESSABLE_ENTITY } else { StatusCode::OK };
+    (status, Json(report)).into_response()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChangesQuery {
+    pub since: Option<String>,
+    pub limit: Option<usize>,
+}
+
+// Machines created, updated or deleted since a cursor, for sync jobs that mirror the inventory
+#[axum::debug_handler]
+async fn get_machine_changes(
+    auth_session: AuthSession,
+    Query(query): Query<ChangesQuery>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let since = match query.since.as_deref().map(crate::changes::Cursor::parse).transpose() {
+        Ok(since) => since,
+        Err(message) => return json_error(StatusCode::BAD_REQUEST, "Bad Request", message),
+    };
+    let limit = query.limit.unwrap_or(crate::changes::DEFAULT_LIMIT).clamp(1, crate::changes::MAX_LIMIT);
+    match crate::changes::load(since, limit).await {
+        Ok(changes) => (StatusCode::OK, Json(changes)).into_response(),
+        Err(e) => {
+            error!("Failed to load machine changes: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
+        }
+    }
+}
+
+fn resource_error(e: crate::resources::ResourceError) -> Response {
+    use crate::resources::ResourceError;
+    let status = match &e {
+        ResourceError::NotFound => StatusCode::NOT_FOUND,
+        ResourceError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+        ResourceError::Invalid(_) => StatusCode::BAD_REQUEST,
+        ResourceError::Conflict(_) => StatusCode::CONFLICT,
+        ResourceError::Database(_) => {
+            error!("Machine resource operation failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    json_error(status, status.canonical_reason().unwrap_or("Error"), e.to_string())
+}
+
+fn resource_response(status: StatusCode, resource: crate::resources::MachineResource) -> Response {
+    let headers = [
+        (axum::http::header::ETAG, resource.etag()),
+        (axum::http::header::LOCATION, format!("/api/v1/machines/{}", resource.id)),
+    ];
+    (status, headers, Json(resource)).into_response()
+}
+
+fn if_match(headers: &HeaderMap) -> Option<&str> {
+    headers.get(axum::http::header::IF_MATCH).and_then(|v| v.to_str().ok())
+}
+
+// Assign the OS a resource asks for if it isn't already the machine's choice. Assigning starts an
+// installation, so it only happens when the choice changes.
+async fn converge_os(resource: crate::resources::MachineResource, os_choice: Option<&String>, auth_session: &AuthSession) -> Result<crate::resources::MachineResource, Response> {
+    match os_choice {
+        Some(os) if resource.os_choice.as_ref() != Some(os) => {
+            let response = assign_os_internal(resource.id, os.clone(), StatusCause::Admin(policy::principal(auth_session))).await;
+            if !response.status().is_success() {
+                return Err(response);
+            }
+            crate::resources::get(&resource.id).await.map_err(resource_error)
+        },
+        _ => Ok(resource),
+    }
+}
+
+// Create a machine, or converge the one with the same MAC address, so retries are safe
+#[axum::debug_handler]
+async fn create_machine_resource(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Json(spec): Json<crate::resources::MachineSpec>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let (resource, created) = match crate::resources::create(&spec, &policy::principal(&auth_session)).await {
+        Ok(result) => result,
+        Err(e) => return resource_error(e),
+    };
+    let event = if created { "machine_discovered" } else { "machine_updated" };
+    let _ = state.event_manager.send(format!("{}:{}", event, resource.id));
+    match converge_os(resource, spec.os_choice.as_ref(), &auth_session).await {
+        Ok(resource) => resource_response(if created { StatusCode::CREATED } else { StatusCode::OK }, resource),
+        Err(response) => response,
+    }
+}
+
+#[axum::debug_handler]
+async fn get_machine_resource(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
+    }
+
+    match crate::resources::get(&id).await {
+        Ok(resource) => resource_response(StatusCode::OK, resource),
+        Err(e) => resource_error(e),
+    }
+}
+
+#[axum::debug_handler]
+async fn update_machine_resource(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    Json(spec): Json<crate::resources::MachineSpec>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Operate).await {
+        return response;
+    }
+
+    let resource = match crate::resources::update(&id, &spec, if_match(&headers), &policy::principal(&auth_session)).await {
+        Ok(resource) => resource,
+        Err(e) => return resource_error(e),
+    };
+    let _ = state.event_manager.send(format!("machine_updated:{}", id));
+    match converge_os(resource, spec.os_choice.as_ref(), &auth_session).await {
+        Ok(resource) => resource_response(StatusCode::OK, resource),
+        Err(response) => response,
+    }
+}
+
+// Deleting a machine that is already gone succeeds, so retries are safe
+#[axum::debug_handler]
+async fn delete_machine_resource(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+
+    let resource = match crate::resources::check_delete(&id, if_match(&headers)).await {
+        Ok(Some(resource)) => resource,
+        Ok(None) => return StatusCode::NO_CONTENT.into_response(),
+        Err(e) => return resource_error(e),
+    };
+    if let Err(e) = crate::tinkerbell::delete_hardware(&resource.mac_address.replace(":", "-").to_lowercase()).await {
+        warn!("Failed to delete machine {} from Tinkerbell: {}", id, e);
+    }
+    match db::archive_machine(&id, &policy::principal(&auth_session)).await {
+        Ok(_) => {
+            let _ = state.event_manager.send(format!("machine_deleted:{}", id));
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Err(e) => resource_error(e.into()),
+    }
+}
+
+/// Status changes shown in a rendered machine summary
+const SUMMARY_HISTORY: usize = 10;
+
+#[derive(Deserialize)]
+struct RenderQuery {
+    format: Option<String>,
+}
+
+// The template and content type for a requested summary format; Markdown unless asked otherwise
+fn summary_template(format: Option<&str>) -> Result<(&'static str, &'static str), String> {
+    match format.unwrap_or("markdown") {
+        "markdown" | "md" => Ok(("embed/machine.md", "text/markdown; charset=utf-8")),
+        "html" => Ok(("embed/machine.html", "text/html; charset=utf-8")),
+        other => Err(format!("Unknown format '{}'; use markdown or html", other)),
+    }
+}
+
+// What the summary templates see; `base_url` is where the machine's own page links to
+fn summary_context(mut machine: Machine, history: &[db::StatusChange], tags: Vec<String>, base_url: Option<String>) -> serde_json::Value {
+    // The summary ends up in wikis and tickets, so BMC credentials stay out of the context entirely
+    machine.bmc_credentials = None;
+    // Newest first, leaving out heartbeat flapping so the highlights aren't all offline/online
+    let highlights: Vec<_> = history.iter().rev()
+        .filter(|change| !matches!(change.cause, Some(StatusCause::HeartbeatTimeout | StatusCause::HeartbeatResumed)))
+        .take(SUMMARY_HISTORY)
+        .map(|change| json!({
+            "from": change.from.as_ref().map(|status| status.to_string()),
+            "to": change.to.to_string(),
+            "cause": change.cause.as_ref().map(|cause| cause.to_string()),
+            "changed_at": change.changed_at.to_rfc3339(),
+        }))
+        .collect();
+    json!({
+        "machine": &machine,
+        "name": machine.hostname.as_ref().or(machine.memorable_name.as_ref()).unwrap_or(&machine.mac_address),
+        "status": machine.status.to_string(),
+        "os": machine.os_installed.as_ref().or(machine.os_choice.as_ref()).map(|os| format_os_name(os)),
+        "ram_gib": machine.total_ram_bytes.map(|bytes| format!("{:.1}", bytes as f64 / 1024f64.powi(3))),
+        "tags": tags,
+        "history": highlights,
+        "machine_url": base_url.map(|base| format!("{}/machines/{}", base.trim_end_matches('/'), machine.id)),
+        "generated_at": chrono::Utc::now().to_rfc3339(),
+    })
+}
+
+// A self-contained summary of a machine for pasting into wikis and tickets, rendered from the
+// embed/machine.md and embed/machine.html templates so operators can reword them
+#[axum::debug_handler]
+async fn render_machine_summary(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Query(query): Query<RenderQuery>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
+    }
+
+    let (template, content_type) = match summary_template(query.format.as_deref()) {
+        Ok(template) => template,
+        Err(message) => return json_error(StatusCode::BAD_REQUEST, "Bad Request", message),
+    };
+    let machine = match db::get_machine_by_id(&id).await {
+        Ok(Some(machine)) => machine,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine {} not found", id)),
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    let (history, tags) = match tokio::try_join!(db::get_status_history(&id), get_machine_tags(&id)) {
+        Ok(loaded) => loaded,
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    let context = summary_context(machine, &history, tags, env::var("DRAGONFLY_BASE_URL").ok());
+
+    match crate::ui::render_to_string(&state, template, context) {
+        Ok(body) => ([(axum::http::header::CONTENT_TYPE, content_type)], body).into_response(),
+        Err(e) => {
+            error!("Failed to render {} for machine {}: {}", template, id, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Template Error", e.to_string())
+        }
+    }
+}
+
+// Set aside a machine awaiting an OS; claiming again with the same key returns the same machine
+#[axum::debug_handler]
+async fn claim_machine(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Json(request): Json<crate::resources::ClaimRequest>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::resources::claim(&request, &policy::principal(&auth_session)).await {
+        Ok((resource, created)) => {
+            let _ = state.event_manager.send(format!("machine_updated:{}", resource.id));
+            resource_response(if created { StatusCode::CREATED } else { StatusCode::OK }, resource)
+        },
+        Err(e) => resource_error(e),
+    }
+}
+
+#[axum::debug_handler]
+async fn release_machine_claim(
+    auth_session: AuthSession,
+    Path(key): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::resources::release(&key).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => resource_error(e),
+    }
+}
+
 #[axum::debug_handler]
 async fn get_all_machines(
     auth_session: AuthSession,
+    Query(query): Query<HashMap<String, String>>,
     req: axum::http::Request<axum::body::Body>
 ) -> Response {
     // Check if this is an HTMX request
@@ -309,10 +1056,23 @@ async fn get_all_machines(
         .is_some();
     
     // Check if user is authenticated as admin
-    let is_admin = auth_session.user.is_some();
+    let is_admin = crate::auth::is_admin(&auth_session);
 
-    match db::get_all_machines().await {
+    match search_machines(&query).await {
         Ok(machines) => {
+            let machines = match filter_by_custom_fields(machines, &query).await {
+                Ok(machines) => machines,
+                Err(response) => return response,
+            };
+            let machines = match filter_by_location(machines, &query).await {
+                Ok(machines) => machines,
+                Err(response) => return response,
+            };
+            let machines = match filter_by_capabilities(machines, &query).await {
+                Ok(machines) => machines,
+                Err(response) => return response,
+            };
+
             // Get workflow info for machines that are installing OS
             let mut workflow_infos = HashMap::new();
             for machine in &machines {
@@ -447,6 +1207,8 @@ async fn get_all_machines(
                             MachineStatus::InstallingOS => "px-3 py-1 inline-flex text-sm leading-5 font-semibold rounded-full bg-yellow-100 text-yellow-800 dark:bg-yellow-400/10 dark:text-yellow-300 dark:border dark:border-yellow-500/20",
                             MachineStatus::AwaitingAssignment => "px-3 py-1 inline-flex text-sm leading-5 font-semibold rounded-full bg-blue-100 text-blue-800 dark:bg-blue-400/10 dark:text-blue-300 dark:border dark:border-blue-500/20",
                             MachineStatus::ExistingOS => "px-3 py-1 inline-flex text-sm leading-5 font-semibold rounded-full bg-sky-100 text-sky-800 dark:bg-sky-400/10 dark:text-sky-300 dark:border dark:border-sky-500/20",
+                            MachineStatus::PendingApproval => "px-3 py-1 inline-flex text-sm leading-5 font-semibold rounded-full bg-amber-100 text-amber-800 dark:bg-amber-400/10 dark:text-amber-300 dark:border dark:border-amber-500/20",
+                            MachineStatus::Rejected => "px-3 py-1 inline-flex text-sm leading-5 font-semibold rounded-full bg-gray-100 text-gray-800 dark:bg-gray-400/10 dark:text-gray-300 dark:border dark:border-gray-500/20",
                             _ => "px-3 py-1 inline-flex text-sm leading-5 font-semibold rounded-full bg-red-100 text-red-800 dark:bg-red-400/10 dark:text-red-300 dark:border dark:border-red-500/20"
                         },
                         match &machine.status { 
@@ -466,15 +1228,112 @@ async fn get_all_machines(
                 (StatusCode::OK, Json(machines)).into_response()
             }
         },
+        Err(response) => response,
+    }
+}
+
+// Apply `field.<name>=<filter>` query parameters to a machine list
+async fn filter_by_custom_fields(machines: Vec<Machine>, query: &HashMap<String, String>) -> Result<Vec<Machine>, Response> {
+    if !query.keys().any(|key| key.starts_with(custom_fields::FILTER_PREFIX)) {
+        return Ok(machines);
+    }
+    let database_error = |e: anyhow::Error| {
+        error!("Failed to load custom fields: {}", e);
+        let error_response = ErrorResponse {
+            error: "Database Error".to_string(),
+            message: e.to_string(),
+        };
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+    };
+    let fields = db::get_custom_fields().await.map_err(database_error)?;
+    let filters = custom_fields::parse_filters(query, &fields).map_err(|message| {
+        let error_response = ErrorResponse {
+            error: "Bad Request".to_string(),
+            message,
+        };
+        (StatusCode::BAD_REQUEST, Json(error_response)).into_response()
+    })?;
+    let values = db::get_all_field_values().await.map_err(database_error)?;
+
+    Ok(machines.into_iter()
+        .filter(|machine| {
+            let machine_values = values.get(&machine.id);
+            filters.iter().all(|(field, filter)| {
+                custom_fields::matches(field, machine_values.and_then(|v| v.get(&field.name)), filter)
+            })
+        })
+        .collect())
+}
+
+// The machines matching a `q=` search query, or all of them. Demo mode searches the simulated
+// fleet instead of the machines table.
+async fn search_machines(query: &HashMap<String, String>) -> Result<Vec<Machine>, Response> {
+    let search = crate::search::Search::parse(query.get("q").map(String::as_str).unwrap_or_default())
+        .map_err(|message| json_error(StatusCode::BAD_REQUEST, "Bad Request", message))?;
+    crate::search::find(&search).await.map_err(|e| {
+        error!("Failed to load machines: {}", e);
+        json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
+    })
+}
+
+// Apply `site=` and `rack=` query parameters to a machine list
+async fn filter_by_location(machines: Vec<Machine>, query: &HashMap<String, String>) -> Result<Vec<Machine>, Response> {
+    let site = query.get("site");
+    let rack = query.get("rack");
+    if site.is_none() && rack.is_none() {
+        return Ok(machines);
+    }
+    let (placements, racks) = match tokio::try_join!(db::get_placements(), db::get_racks()) {
+        Ok(result) => result,
         Err(e) => {
-            error!("Failed to retrieve machines: {}", e);
-            let error_response = ErrorResponse {
-                error: "Database Error".to_string(),
-                message: e.to_string(),
-            };
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+            error!("Failed to load rack placements: {}", e);
+            return Err(json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()));
         }
+    };
+    let locations: HashMap<Uuid, (&str, &str)> = placements.iter()
+        .filter_map(|p| racks.iter().find(|r| r.name == p.rack).map(|r| (p.machine_id, (r.site.as_str(), r.name.as_str()))))
+        .collect();
+
+    Ok(machines.into_iter()
+        .filter(|machine| match locations.get(&machine.id) {
+            Some((machine_site, machine_rack)) => {
+                site.map_or(true, |s| s == machine_site) && rack.map_or(true, |r| r == machine_rack)
+            },
+            None => false,
+        })
+        .collect())
+}
+
+// Apply `gpu=`, `min_gpus=`, `min_nvme=` and `sriov=` query parameters to a machine list,
+// then `limit=`, so "4 machines with A100s" is `?gpu=a100&limit=4`
+async fn filter_by_capabilities(machines: Vec<Machine>, query: &HashMap<String, String>) -> Result<Vec<Machine>, Response> {
+    let filter = crate::pci_devices::CapabilityFilter::from_query(query)
+        .map_err(|message| json_error(StatusCode::BAD_REQUEST, "Bad Request", message))?;
+    let limit = match query.get("limit").map(|limit| limit.parse::<usize>()) {
+        Some(Ok(limit)) => Some(limit),
+        Some(Err(_)) => return Err(json_error(StatusCode::BAD_REQUEST, "Bad Request", "limit must be a whole number".to_string())),
+        None => None,
+    };
+
+    let mut machines = match filter {
+        Some(filter) => {
+            let devices = db::get_all_pci_devices().await.map_err(|e| {
+                error!("Failed to load PCI devices: {}", e);
+                json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
+            })?;
+            machines.into_iter()
+                .filter(|machine| {
+                    let devices = devices.get(&machine.id).map(Vec::as_slice).unwrap_or_default();
+                    filter.matches(&crate::pci_devices::Capabilities::from_devices(devices))
+                })
+                .collect()
+        },
+        None => machines,
+    };
+    if let Some(limit) = limit {
+        machines.truncate(limit);
     }
+    Ok(machines)
 }
 
 #[axum::debug_handler]
@@ -527,14 +1386,12 @@ async fn get_machine(
 async fn assign_os(
     auth_session: AuthSession,
     Path(id): Path<Uuid>,
+    Query(query): Query<DryRunQuery>,
     req: axum::http::Request<axum::body::Body>,
 ) -> Response {
-    // Check if user is authenticated as admin
-    if auth_session.user.is_none() {
-        return (StatusCode::UNAUTHORIZED, Json(json!({
-            "error": "Unauthorized",
-            "message": "Admin authentication required for this operation"
-        }))).into_response();
+    // Check the caller's permissions on this machine
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Operate).await {
+        return response;
     }
 
     // Check content type to determine how to extract the OS choice
@@ -569,7 +1426,12 @@ async fn assign_os(
     };
     
     match os_choice {
-        Some(os_choice) => assign_os_internal(id, os_choice).await,
+        Some(os_choice) if query.dry_run => match db::get_machine_by_id(&id).await {
+            Ok(Some(machine)) => plan_reimage(&machine, &os_choice).await.into_response(),
+            Ok(None) => (StatusCode::NOT_FOUND, Json(json!({ "error": "Machine not found" }))).into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Database error: {}", e) }))).into_response(),
+        },
+        Some(os_choice) => assign_os_internal(id, os_choice, StatusCause::Admin(policy::principal(&auth_session))).await,
         None => {
             let error_response = ErrorResponse {
                 error: "Bad Request".to_string(),
@@ -580,12 +1442,236 @@ async fn assign_os(
     }
 }
 
+#[derive(Deserialize)]
+pub(crate) struct ReimageRequest {
+    /// Defaults to the OS the machine has now
+    #[serde(default)]
+    pub os_choice: Option<String>,
+    /// Set the machine to PXE boot and power-cycle it through its BMC once the workflow exists
+    #[serde(default)]
+    pub power_cycle: bool,
+}
+
+// Reinstall a machine from scratch: forget its last install, recreate its workflow so any
+// pipeline starts again from the first stage, and optionally power-cycle it into the installer
+#[axum::debug_handler]
+pub(crate) async fn reimage_machine(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Query(query): Query<DryRunQuery>,
+    Json(payload): Json<ReimageRequest>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Operate).await {
+        return response;
+    }
+
+    let machine = match db::get_machine_by_id(&id).await {
+        Ok(Some(machine)) => machine,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine {} not found", id)),
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    let Some(os_choice) = payload.os_choice.or_else(|| machine.os_choice.clone()) else {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", "This machine has no OS yet; choose one to install".to_string());
+    };
+    if payload.power_cycle && machine.bmc_credentials.is_none() {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", "This machine has no BMC credentials to power-cycle it with".to_string());
+    }
+    if query.dry_run {
+        let mut plan = plan_reimage(&machine, &os_choice).await;
+        if payload.power_cycle {
+            plan.notes.push("The machine will be set to PXE boot and power-cycled through its BMC".to_string());
+        }
+        return plan.into_response();
+    }
+
+    let principal = policy::principal(&auth_session);
+    info!("Reimaging machine {} with {} (requested by {})", id, os_choice, principal);
+    let result = reimage_internal(&machine, os_choice, payload.power_cycle, StatusCause::Admin(principal.clone()), &principal).await;
+    let _ = state.event_manager.send(format!("machine_updated:{}", id));
+    match result {
+        Ok(reimaged) if reimaged.status == "waiting" => (StatusCode::ACCEPTED, Json(reimaged)).into_response(),
+        Ok(reimaged) => (StatusCode::OK, Json(reimaged)).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// How far a reimage got
+#[derive(Debug, Serialize)]
+pub(crate) struct Reimaged {
+    /// "installing", or "waiting" when held for a maintenance window or queued for a slot
+    pub status: &'static str,
+    pub os_choice: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bmc_job: Option<crate::bmc::BmcJob>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+// Shared implementation: clear the last install's state and assign the OS again, then
+// power-cycle the machine into its new workflow. Reimage schedules run through here.
+pub(crate) async fn reimage_internal(
+    machine: &Machine,
+    os_choice: String,
+    power_cycle: bool,
+    cause: StatusCause,
+    requested_by: &str,
+) -> Result<Reimaged, Response> {
+    let id = machine.id;
+    // An assignment that would be refused must not cost the machine its install state
+    check_assignment(&id, &os_choice).await?;
+
+    // Nothing from the last install may carry over: a stale health report could mark the new
+    // install Ready, and a stale hold or queued install would reinstall it again afterwards
+    let wiped = async {
+        db::delete_health_report(&id).await?;
+        db::delete_pipeline_run(&id).await?;
+        db::take_provisioning_hold(&id).await?;
+        db::take_queued_install(&id).await?;
+        db::update_installation_progress(&id, 0, Some("Reimage requested")).await
+    };
+    if let Err(e) = wiped.await {
+        error!("Failed to clear install state of machine {}: {}", id, e);
+        return Err(json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()));
+    }
+
+    let response = assign_os_internal(id, os_choice.clone(), cause).await;
+    match response.status() {
+        StatusCode::OK => {},
+        // Held for a maintenance window or queued for an install slot; power-cycling now would
+        // boot the machine before its workflow exists
+        StatusCode::ACCEPTED => {
+            let note = power_cycle.then(|| "The install is waiting, so the machine wasn't power-cycled; power it on once the install starts".to_string());
+            return Ok(Reimaged { status: "waiting", os_choice, bmc_job: None, note });
+        },
+        _ => return Err(response),
+    }
+
+    let mut reimaged = Reimaged { status: "installing", os_choice, bmc_job: None, note: None };
+    if power_cycle {
+        let tasks = vec![
+            crate::bmc::BmcTask::BootDevice(crate::bmc::BootDevice::Pxe),
+            crate::bmc::BmcTask::Power(crate::bmc::PowerAction::Cycle),
+        ];
+        match crate::bmc::start_job(machine, tasks, requested_by).await {
+            Ok(job) => reimaged.bmc_job = Some(job),
+            Err(message) => {
+                warn!("Reimage of machine {} started but the power cycle failed: {}", id, message);
+                reimaged.note = Some(format!("The install started but the machine couldn't be power-cycled: {}", message));
+            },
+        }
+    }
+    Ok(reimaged)
+}
+
+/// Why an OS can't be assigned, as the status and message to refuse with. Address conflicts come
+/// first, as provisioning a machine whose address another machine also claims would image the wrong box.
+fn assignment_refusal(conflicts: &[String], scope: Result<(), String>, lifecycle: Result<(), String>) -> Option<(StatusCode, String)> {
+    if !conflicts.is_empty() {
+        return Some((StatusCode::CONFLICT, format!("This machine has an address conflict: {}. Resolve the conflict before provisioning it", conflicts.join("; "))));
+    }
+    // A project's own templates must never be installed on another project's machines, and
+    // end-of-life templates may be kept from being installed anywhere new
+    scope.and(lifecycle).err().map(|reason| (StatusCode::FORBIDDEN, reason))
+}
+
+// Refuse an assignment before anything about the machine changes
+async fn check_assignment(id: &Uuid, os_choice: &str) -> Result<(), Response> {
+    let conflicts = crate::conflicts::for_machine(id).await.unwrap_or_else(|e| {
+        warn!("Failed to check machine {} for address conflicts: {}", id, e);
+        Vec::new()
+    });
+    let scope = match crate::projects::check_template(id, os_choice).await {
+        Ok(scope) => scope,
+        Err(e) => {
+            error!("Failed to check template scope for machine {}: {}", id, e);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to check template scope: {}", e)).into_response());
+        }
+    };
+    let lifecycle = crate::os_lifecycle::check_assignment(os_choice).await.unwrap_or_else(|e| {
+        warn!("Failed to check end of life of {} for machine {}: {}", os_choice, id, e);
+        Ok(())
+    });
+
+    match assignment_refusal(&conflicts, scope, lifecycle) {
+        Some((status, reason)) => {
+            warn!("Refusing to assign OS to machine {}: {}", id, reason);
+            let error_html = format!(r###"
+                <div class="p-4 mb-4 text-sm text-red-700 bg-red-100 rounded-lg" role="alert">
+                    <span class="font-medium">Error!</span> {}.
+                </div>
+            "###, reason);
+            Err((status, [(axum::http::header::CONTENT_TYPE, "text/html")], error_html).into_response())
+        },
+        None => Ok(()),
+    }
+}
+
 // Shared implementation
-async fn assign_os_internal(id: Uuid, os_choice: String) -> Response {
+pub(crate) async fn assign_os_internal(id: Uuid, os_choice: String, cause: StatusCause) -> Response {
     info!("Assigning OS {} to machine {}", os_choice, id);
     
-    match db::assign_os(&id, &os_choice).await {
+    if let Err(response) = check_assignment(&id, &os_choice).await {
+        return response;
+    }
+    
+    // Outside maintenance windows the install waits for the next one
+    match crate::maintenance::hold(&id, &os_choice, &cause).await {
+        Ok(Some(hold)) => {
+            let when = hold.next_window
+                .map(|opening| format!(", which opens {}", opening.format("%a %d %b %H:%M UTC")))
+                .unwrap_or_default();
+            let html = format!(r###"
+                <div class="p-4 mb-4 text-sm text-amber-700 bg-amber-100 rounded-lg" role="alert">
+                    <span class="font-medium">Queued.</span> {} will be installed in the next maintenance window{}.
+                    <p class="mt-2">Release the hold on the Maintenance page to install it now.</p>
+                </div>
+            "###, os_choice, when);
+            return (StatusCode::ACCEPTED, [(axum::http::header::CONTENT_TYPE, "text/html")], html).into_response();
+        },
+        Ok(None) => {},
+        Err(e) => {
+            error!("Failed to check maintenance windows for machine {}: {}", id, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to check maintenance windows: {}", e)).into_response();
+        }
+    }
+    
+    start_os_install(id, os_choice, cause).await
+}
+
+// Start an install unless too many are already running, in which case it waits in the install
+// queue. Held assignments are released through here.
+pub(crate) async fn start_os_install(id: Uuid, os_choice: String, cause: StatusCause) -> Response {
+    let _admission = crate::install_queue::ADMISSION.lock().await;
+    match crate::install_queue::admit(&id, &os_choice, &cause).await {
+        Ok(Some(queued)) => {
+            let html = format!(r###"
+                <div class="p-4 mb-4 text-sm text-amber-700 bg-amber-100 rounded-lg" role="alert">
+                    <span class="font-medium">Queued.</span> {} will start installing when a slot frees up: {} is reached.
+                    <p class="mt-2">The install queue is on the Maintenance page.</p>
+                </div>
+            "###, os_choice, queued.waiting_for.unwrap_or_default());
+            return (StatusCode::ACCEPTED, [(axum::http::header::CONTENT_TYPE, "text/html")], html).into_response();
+        },
+        Ok(None) => {},
+        Err(e) => {
+            error!("Failed to check install limits for machine {}: {}", id, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to check install limits: {}", e)).into_response();
+        }
+    }
+    
+    install_os_now(id, os_choice, cause).await
+}
+
+// Assign the OS and create its workflow, now. Queued installs are started through here.
+pub(crate) async fn install_os_now(id: Uuid, os_choice: String, cause: StatusCause) -> Response {
+    match db::assign_os(&id, &os_choice, &cause).await {
         Ok(true) => {
+            // Count this assignment towards the template's popularity
+            if let Err(e) = db::record_template_usage(&os_choice).await {
+                warn!("Failed to record usage for template {}: {}", os_choice, e);
+            }
+            
             // Get the machine to create a workflow for OS installation
             let machine_name = if let Ok(Some(machine)) = db::get_machine_by_id(&id).await {
                 // Create a workflow for OS installation
@@ -691,6 +1777,15 @@ async fn assign_os_internal(id: Uuid, os_choice: String) -> Response {
             "###, id);
             (StatusCode::NOT_FOUND, [(axum::http::header::CONTENT_TYPE, "text/html")], error_html).into_response()
         },
+        Err(e) if e.is::<InvalidTransition>() || e.is::<db::StatusConflict>() => {
+            warn!("Refused to assign OS to machine {}: {}", id, e);
+            let error_html = format!(r###"
+                <div class="p-4 mb-4 text-sm text-red-700 bg-red-100 rounded-lg" role="alert">
+                    <span class="font-medium">Error!</span> {}.
+                </div>
+            "###, e);
+            (StatusCode::CONFLICT, [(axum::http::header::CONTENT_TYPE, "text/html")], error_html).into_response()
+        },
         Err(e) => {
             error!("Failed to assign OS to machine {}: {}", id, e);
             let error_html = format!(r###"
@@ -703,10 +1798,196 @@ async fn assign_os_internal(id: Uuid, os_choice: String) -> Response {
     }
 }
 
+// Status changes from logged-in users are theirs; anything else was reported by the machine
+fn status_cause(auth_session: &AuthSession) -> StatusCause {
+    match &auth_session.user {
+        Some(user) => StatusCause::Admin(user.username.clone()),
+        None => StatusCause::Machine,
+    }
+}
+
+// Assign the OS of the machine's profile, or else the configured default OS (if any), to a
+// machine and start its installation
+async fn apply_default_os(id: &Uuid) {
+    let profile_os = match crate::machine_profiles::for_machine(id).await {
+        Ok(profile) => profile.and_then(|profile| profile.os_template.map(|os| (os, profile.name))),
+        Err(e) => {
+            warn!("Failed to load machine profile of machine {}: {}", id, e);
+            None
+        }
+    };
+    let (default_os, cause) = match profile_os {
+        Some((os, profile)) => {
+            let cause = StatusCause::Automatic(format!("Machine profile {} assigned {}", profile, os));
+            (os, cause)
+        },
+        // Check if a default OS is configured
+        None => match db::get_app_settings().await.ok().and_then(|settings| settings.default_os) {
+            Some(os) => {
+                let cause = StatusCause::Automatic(format!("Default OS {} applied", os));
+                (os, cause)
+            },
+            None => return,
+        },
+    };
+    if let Ok(reasons) = crate::conflicts::for_machine(id).await {
+        if !reasons.is_empty() {
+            warn!("Not applying default OS to machine {} until its address conflict is resolved: {}", id, reasons.join("; "));
+            return;
+        }
+    }
+    if let Ok(Err(reason)) = crate::os_lifecycle::check_assignment(&default_os).await {
+        warn!("Not applying default OS to machine {}: {}", id, reason);
+        return;
+    }
+    match crate::maintenance::hold(id, &default_os, &cause).await {
+        Ok(Some(_)) => return,
+        Ok(None) => {},
+        Err(e) => {
+            warn!("Not applying default OS to machine {}: failed to check maintenance windows: {}", id, e);
+            return;
+        }
+    }
+    let _admission = crate::install_queue::ADMISSION.lock().await;
+    match crate::install_queue::admit(id, &default_os, &cause).await {
+        Ok(Some(_)) => return,
+        Ok(None) => {},
+        Err(e) => {
+            warn!("Not applying default OS to machine {}: failed to check install limits: {}", id, e);
+            return;
+        }
+    }
+    info!("Applying default OS '{}' to newly registered machine {}", default_os, id);
+    // Assign the OS and trigger installation
+    if let Ok(true) = db::assign_os(id, &default_os, &cause).await {
+        // Update Tinkerbell workflow
+        if let Ok(Some(updated_machine)) = db::get_machine_by_id(id).await {
+            if let Err(e) = crate::tinkerbell::create_workflow(&updated_machine, &default_os).await {
+                warn!("Failed to create Tinkerbell workflow for default OS (continuing anyway): {}", e);
+            } else {
+                info!("Created Tinkerbell workflow for default OS installation");
+            }
+        }
+    }
+}
+
+#[axum::debug_handler]
+pub(crate) async fn approve_machine(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    // Check the caller's permissions on this machine
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+
+    let machine = match db::get_machine_by_id(&id).await {
+        Ok(Some(machine)) => machine,
+        Ok(None) => {
+            return (StatusCode::NOT_FOUND, Json(json!({ "error": "Machine not found" }))).into_response();
+        },
+        Err(e) => {
+            error!("Error fetching machine {} for approval: {}", id, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Database error: {}", e) }))).into_response();
+        }
+    };
+
+    if !machine.status.is_approval_gated() {
+        let error_response = ErrorResponse {
+            error: "Invalid State".to_string(),
+            message: format!("Machine {} is not awaiting approval (status: {})", id, machine.status),
+        };
+        return (StatusCode::CONFLICT, Json(error_response)).into_response();
+    }
+
+    info!("Approving machine {} ({})", id, machine.mac_address);
+
+    match db::update_status(&id, MachineStatus::AwaitingAssignment, &StatusCause::Admin(policy::principal(&auth_session))).await {
+        Ok(_) => {
+            // Now that it's approved, the machine can be registered with Tinkerbell
+            if let Ok(Some(machine)) = db::get_machine_by_id(&id).await {
+                if let Err(e) = crate::tinkerbell::register_machine(&machine).await {
+                    warn!("Failed to register machine with Tinkerbell (continuing anyway): {}", e);
+                }
+            }
+            apply_default_os(&id).await;
+
+            // Emit machine updated event
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+
+            (StatusCode::OK, Json(json!({ "success": true, "message": format!("Machine {} approved", id) }))).into_response()
+        },
+        Err(e) if e.is::<InvalidTransition>() || e.is::<db::StatusConflict>() => {
+            let error_response = ErrorResponse {
+                error: "Invalid State".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::CONFLICT, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to approve machine {}: {}", id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Database error: {}", e) }))).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+pub(crate) async fn reject_machine(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Query(query): Query<DryRunQuery>,
+) -> Response {
+    // Check the caller's permissions on this machine
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+
+    info!("Rejecting machine {}", id);
+
+    if query.dry_run {
+        return match db::get_machine_by_id(&id).await {
+            Ok(Some(machine)) => plan_reject(&machine).into_response(),
+            Ok(None) => (StatusCode::NOT_FOUND, Json(json!({ "error": "Machine not found" }))).into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Database error: {}", e) }))).into_response(),
+        };
+    }
+
+    match db::update_status(&id, MachineStatus::Rejected, &StatusCause::Admin(policy::principal(&auth_session))).await {
+        Ok(true) => {
+            // Make sure a rejected machine can't be provisioned by Tinkerbell either
+            if let Ok(Some(machine)) = db::get_machine_by_id(&id).await {
+                let mac_address = machine.mac_address.replace(":", "-").to_lowercase();
+                if let Err(e) = crate::tinkerbell::delete_hardware(&mac_address).await {
+                    debug!("No Tinkerbell hardware removed for rejected machine {}: {}", id, e);
+                }
+            }
+
+            // Emit machine updated event
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+
+            (StatusCode::OK, Json(json!({ "success": true, "message": format!("Machine {} rejected", id) }))).into_response()
+        },
+        Ok(false) => {
+            (StatusCode::NOT_FOUND, Json(json!({ "error": "Machine not found" }))).into_response()
+        },
+        Err(e) => {
+            error!("Failed to reject machine {}: {}", id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Database error: {}", e) }))).into_response()
+        }
+    }
+}
+
+// Whether a plain status update has to be refused because only approve or reject may move the machine
+fn held_at_approval_gate(current: &MachineStatus, requested: &MachineStatus) -> bool {
+    current.is_approval_gated() && !requested.is_approval_gated()
+}
+
 #[axum::debug_handler]
 async fn update_status(
     State(state): State<AppState>,
-    _auth_session: AuthSession,
+    auth_session: AuthSession,
     Path(id): Path<Uuid>,
     req: axum::http::Request<axum::body::Body>,
 ) -> Response {
@@ -762,9 +2043,21 @@ async fn update_status(
         }
     };
 
+    // Machines held at the approval gate can only leave it via approve/reject
+    if let Ok(Some(machine)) = db::get_machine_by_id(&id).await {
+        if held_at_approval_gate(&machine.status, &status) {
+            info!("Machine {} is {}, ignoring status update to {:?}", id, machine.status, status);
+            return Html(format!(r#"
+                <div class="p-4 mb-4 text-sm text-yellow-700 bg-yellow-100 rounded-lg" role="alert">
+                    <span class="font-medium">Pending approval.</span> Machine {} must be approved before its status can change.
+                </div>
+            "#, id)).into_response();
+        }
+    }
+
     info!("Updating status for machine {} to {:?}", id, status);
     
-    match db::update_status(&id, status.clone()).await {
+    match db::update_status(&id, status.clone(), &status_cause(&auth_session)).await {
         Ok(true) => {
             // Get the updated machine to update Tinkerbell
             if let Ok(Some(machine)) = db::get_machine_by_id(&id).await {
@@ -775,23 +2068,7 @@ async fn update_status(
                 
                 // If the status is AwaitingAssignment, check if we should apply a default OS
                 if status == MachineStatus::AwaitingAssignment {
-                    // Check if a default OS is configured
-                    if let Ok(settings) = db::get_app_settings().await {
-                        if let Some(default_os) = settings.default_os {
-                            info!("Applying default OS '{}' to newly registered machine {}", default_os, id);
-                            // Assign the OS and trigger installation
-                            if let Ok(true) = db::assign_os(&id, &default_os).await {
-                                // Update Tinkerbell workflow
-                                if let Ok(Some(updated_machine)) = db::get_machine_by_id(&id).await {
-                                    if let Err(e) = crate::tinkerbell::create_workflow(&updated_machine, &default_os).await {
-                                        warn!("Failed to create Tinkerbell workflow for default OS (continuing anyway): {}", e);
-                                    } else {
-                                        info!("Created Tinkerbell workflow for default OS installation");
-                                    }
-                                }
-                            }
-                        }
-                    }
+                    apply_default_os(&id).await;
                 }
             }
             
@@ -818,6 +2095,13 @@ async fn update_status(
                 </div>
             "#, id)).into_response()
         },
+        Err(e) if e.is::<InvalidTransition>() || e.is::<db::StatusConflict>() => {
+            Html(format!(r#"
+                <div class="p-4 mb-4 text-sm text-yellow-700 bg-yellow-100 rounded-lg" role="alert">
+                    <span class="font-medium">Not allowed.</span> {}.
+                </div>
+            "#, e)).into_response()
+        },
         Err(e) => {
             error!("Failed to update status for machine {}: {}", id, e);
             Html(format!(r#"
@@ -830,50 +2114,18 @@ async fn update_status(
 }
 
 #[axum::debug_handler]
-async fn update_hostname(
-    State(state): State<AppState>,
+async fn get_status_history(
     auth_session: AuthSession,
     Path(id): Path<Uuid>,
-    Json(payload): Json<HostnameUpdateRequest>,
 ) -> Response {
-    // Check if user is authenticated as admin
-    if auth_session.user.is_none() {
-        return (StatusCode::UNAUTHORIZED, Json(json!({
-            "error": "Unauthorized",
-            "message": "Admin authentication required for this operation"
-        }))).into_response();
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
     }
 
-    info!("Updating hostname for machine {} to {}", id, payload.hostname);
-    
-    match db::update_hostname(&id, &payload.hostname).await {
-        Ok(true) => {
-            // Get the updated machine to update Tinkerbell
-            if let Ok(Some(machine)) = db::get_machine_by_id(&id).await {
-                // Update the machine in Tinkerbell (don't fail if this fails)
-                if let Err(e) = crate::tinkerbell::register_machine(&machine).await {
-                    warn!("Failed to update machine in Tinkerbell (continuing anyway): {}", e);
-                }
-            }
-            
-            // Emit machine updated event
-            let _ = state.event_manager.send(format!("machine_updated:{}", id));
-            
-            let response = HostnameUpdateResponse {
-                success: true,
-                message: format!("Hostname updated for machine {}", id),
-            };
-            (StatusCode::OK, Json(response)).into_response()
-        },
-        Ok(false) => {
-            let error_response = ErrorResponse {
-                error: "Not Found".to_string(),
-                message: format!("Machine with ID {} not found", id),
-            };
-            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
-        },
+    match db::get_status_history(&id).await {
+        Ok(history) => (StatusCode::OK, Json(history)).into_response(),
         Err(e) => {
-            error!("Failed to update hostname for machine {}: {}", id, e);
+            error!("Failed to get status history of machine {}: {}", id, e);
             let error_response = ErrorResponse {
                 error: "Database Error".to_string(),
                 message: e.to_string(),
@@ -883,1857 +2135,7825 @@ async fn update_hostname(
     }
 }
 
+fn json_error(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(ErrorResponse { error: error.to_string(), message })).into_response()
+}
+
 #[axum::debug_handler]
-async fn update_os_installed(
+async fn list_custom_fields() -> Response {
+    match db::get_custom_fields().await {
+        Ok(fields) => (StatusCode::OK, Json(fields)).into_response(),
+        Err(e) => {
+            error!("Failed to list custom fields: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn save_custom_field(
     State(state): State<AppState>,
-    Path(id): Path<Uuid>,
-    Json(payload): Json<OsInstalledUpdateRequest>,
+    auth_session: AuthSession,
+    Json(field): Json<FieldDefinition>,
 ) -> Response {
-    info!("Updating OS installed for machine {} to {}", id, payload.os_installed);
-    
-    match db::update_os_installed(&id, &payload.os_installed).await {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(message) = custom_fields::validate_definition(&field) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+
+    match db::save_custom_field(&field).await {
         Ok(true) => {
-            // Emit machine updated event
-            let _ = state.event_manager.send(format!("machine_updated:{}", id));
-            
-            let response = OsInstalledUpdateResponse {
-                success: true,
-                message: format!("OS installed updated for machine {}", id),
-            };
-            (StatusCode::OK, Json(response)).into_response()
-        },
-        Ok(false) => {
-            // Add a warning log here to confirm if this path is hit
-            warn!("Machine with ID {} not found when attempting to update OS installed.", id);
-            let error_response = ErrorResponse {
-                error: "Not Found".to_string(),
-                message: format!("Machine with ID {} not found", id),
-            };
-            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+            info!("Custom field '{}' saved by {}", field.name, policy::principal(&auth_session));
+            let _ = state.event_manager.send(format!("custom_field_updated:{}", field.name));
+            (StatusCode::OK, Json(field)).into_response()
         },
+        Ok(false) => json_error(
+            StatusCode::CONFLICT,
+            "Conflict",
+            format!("'{}' already exists with another type; delete it first to change its type", field.name),
+        ),
         Err(e) => {
-            error!("Failed to update OS installed for machine {}: {}", id, e);
-            let error_response = ErrorResponse {
-                error: "Database Error".to_string(),
-                message: e.to_string(),
-            };
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+            error!("Failed to save custom field '{}': {}", field.name, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
         }
     }
 }
 
+// Deleting a field also deletes every machine's value for it
 #[axum::debug_handler]
-async fn update_bmc(
+async fn delete_custom_field(
     State(state): State<AppState>,
     auth_session: AuthSession,
-    Path(id): Path<Uuid>,
-    Form(payload): Form<BmcCredentialsUpdateRequest>,
+    Path(name): Path<String>,
 ) -> Response {
-    // Check if user is authenticated as admin
-    if auth_session.user.is_none() {
-        return (StatusCode::UNAUTHORIZED, Json(json!({
-            "error": "Unauthorized",
-            "message": "Admin authentication required for this operation"
-        }))).into_response();
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
     }
 
-    info!("Updating BMC credentials for machine {}", id);
-    
-    // Create BMC credentials from the form data
-    let bmc_type = match payload.bmc_type.as_str() {
-        "IPMI" => BmcType::IPMI,
-        "Redfish" => BmcType::Redfish,
-        _ => BmcType::Other(payload.bmc_type.clone()), // Clone string
-    };
-    
-    let credentials = BmcCredentials {
-        address: payload.bmc_address,
-        username: payload.bmc_username,
-        password: Some(payload.bmc_password), // Assume password is provided
-        bmc_type,
-    };
-    
-    match db::update_bmc_credentials(&id, &credentials).await {
+    match db::delete_custom_field(&name).await {
         Ok(true) => {
-            // Emit machine updated event
-            let _ = state.event_manager.send(format!("machine_updated:{}", id));
-            
-            (StatusCode::OK, Html(format!(r#"
-                <div class="p-4 mb-4 text-sm text-green-700 bg-green-100 rounded-lg" role="alert">
-                    <span class="font-medium">Success!</span> BMC credentials updated.
-                </div>
-                <script>
-                    setTimeout(function() {{
-                        window.location.reload();
-                    }}, 1500);
-                </script>
-            "#))).into_response()
-        },
-        Ok(false) => {
-            let error_message = format!("Machine with ID {} not found", id);
-            (StatusCode::NOT_FOUND, Html(format!(r#"
-                <div class="p-4 mb-4 text-sm text-red-700 bg-red-100 rounded-lg" role="alert">
-                    <span class="font-medium">Error!</span> {}.
-                </div>
-            "#, error_message))).into_response()
+            info!("Custom field '{}' deleted by {}", name, policy::principal(&auth_session));
+            let _ = state.event_manager.send(format!("custom_field_updated:{}", name));
+            StatusCode::NO_CONTENT.into_response()
         },
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No custom field named '{}'", name)),
         Err(e) => {
-            error!("Failed to update BMC credentials for machine {}: {}", id, e);
-            let error_message = format!("Database error: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, Html(format!(r#"
-                <div class="p-4 mb-4 text-sm text-red-700 bg-red-100 rounded-lg" role="alert">
-                    <span class="font-medium">Error!</span> {}.
-                </div>
-            "#, error_message))).into_response()
+            error!("Failed to delete custom field '{}': {}", name, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
         }
     }
 }
 
-// Handler to get the hostname edit form
 #[axum::debug_handler]
-async fn get_hostname_form(
+async fn get_machine_fields(
+    auth_session: AuthSession,
     Path(id): Path<Uuid>,
-) -> impl IntoResponse {
-    match db::get_machine_by_id(&id).await {
-        Ok(Some(machine)) => {
-            let current_hostname = machine.hostname.unwrap_or_default();
-            // Use raw string literals to avoid escaping issues
-            let html = format!(
-                r###"
-                <div class="sm:flex sm:items-start">
-                    <div class="mt-3 text-center sm:mt-0 sm:text-left w-full">
-                        <h3 class="text-base font-semibold leading-6 text-gray-900">
-                            Update Machine Hostname
-                        </h3>
-                        <div class="mt-2">
-                            <form hx-post="/machines/{}/hostname" hx-target="#hostname-modal">
-                                <label for="hostname" class="block text-sm font-medium text-gray-700">Hostname</label>
-                                <input type="text" name="hostname" id="hostname" value="{}" class="mt-1 block w-full rounded-md border-gray-300 shadow-sm focus:border-indigo-500 focus:ring-indigo-500 sm:text-sm" placeholder="Enter hostname">
-                                <div class="mt-5 sm:mt-4 sm:flex sm:flex-row-reverse">
-                                    <button type="submit" class="inline-flex w-full justify-center rounded-md bg-indigo-600 px-3 py-2 text-sm font-semibold text-white shadow-sm hover:bg-indigo-500 sm:ml-3 sm:w-auto">
-                                        Update
-                                    </button>
-                                    <button type="button" class="mt-3 inline-flex w-full justify-center rounded-md bg-white px-3 py-2 text-sm font-semibold text-gray-900 shadow-sm ring-1 ring-inset ring-gray-300 hover:bg-gray-50 sm:mt-0 sm:w-auto" onclick="document.getElementById('hostname-modal').classList.add('hidden')">
-                                        Cancel
-                                    </button>
-                                </div>
-                            </form>
-                        </div>
-                    </div>
-                </div>
-                "###,
-                id, current_hostname
-            );
-            
-            (StatusCode::OK, [(axum::http::header::CONTENT_TYPE, "text/html")], html)
-        },
-        Ok(None) => {
-            let error_html = format!(
-                r###"<div class="p-4 text-red-500">Machine with ID {} not found</div>"###,
-                id
-            );
-            (StatusCode::NOT_FOUND, [(axum::http::header::CONTENT_TYPE, "text/html")], error_html)
-        },
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
+    }
+
+    match db::get_machine_field_values(&id).await {
+        Ok(values) => (StatusCode::OK, Json(values)).into_response(),
         Err(e) => {
-            let error_html = format!(
-                r###"<div class="p-4 text-red-500">Error: {}</div>"###,
-                e
-            );
-            (StatusCode::INTERNAL_SERVER_ERROR, [(axum::http::header::CONTENT_TYPE, "text/html")], error_html)
+            error!("Failed to get custom fields of machine {}: {}", id, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
         }
     }
 }
 
-// Handler for initial iPXE script generation (DHCP points here)
-// Determines whether to chain to HookOS or the Dragonfly Agent
-pub async fn ipxe_script(Path(mac): Path<String>) -> Response {
-    if !mac.contains(':') || mac.split(':').count() != 6 {
-        warn!("Received invalid MAC format in iPXE request: {}", mac);
-        return (StatusCode::BAD_REQUEST, "Invalid MAC Address Format").into_response();
+// Set some of a machine's custom fields; fields left out keep their value and null clears one
+#[axum::debug_handler]
+async fn update_machine_fields(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<HashMap<String, serde_json::Value>>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Operate).await {
+        return response;
     }
 
-    info!("Generating initial iPXE script for MAC: {}", mac);
-
-    // Read required base URL from environment variable
-    let base_url = match env::var("DRAGONFLY_BASE_URL") {
-        Ok(url) => url,
-        Err(_) => {
-            error!("CRITICAL: DRAGONFLY_BASE_URL environment variable is not set. iPXE booting requires this configuration.");
-            let error_response = ErrorResponse {
-                error: "Configuration Error".to_string(),
-                message: "Server is missing required DRAGONFLY_BASE_URL configuration.".to_string(),
-            };
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response();
+    let fields = match db::get_custom_fields().await {
+        Ok(fields) => fields,
+        Err(e) => {
+            error!("Failed to load custom fields: {}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string());
         }
     };
 
-    match db::get_machine_by_mac(&mac).await {
-        Ok(Some(_)) => {
-            // Known machine: Chain to Dragonfly's OS installation hook script (hookos.ipxe)
-            info!("Known MAC {}, chaining to HookOS script", mac);
-            let script = format!("#!ipxe\nchain {}/ipxe/hookos.ipxe", base_url);
-            (StatusCode::OK, [(axum::http::header::CONTENT_TYPE, "text/plain")], script).into_response()
-        },
-        Ok(None) => {
-            // Unknown machine: Chain to the Dragonfly agent script
-            info!("Unknown MAC {}, chaining to Dragonfly Agent iPXE script", mac);
-            let script = format!("#!ipxe\nchain {}/ipxe/dragonfly-agent.ipxe", base_url);
-            (StatusCode::OK, [(axum::http::header::CONTENT_TYPE, "text/plain")], script).into_response()
-        },
+    let mut updates = Vec::new();
+    for (name, value) in payload {
+        let Some(field) = fields.iter().find(|f| f.name == name) else {
+            return json_error(StatusCode::BAD_REQUEST, "Bad Request", format!("Unknown custom field '{}'", name));
+        };
+        let value = match value {
+            serde_json::Value::Null => None,
+            value => match custom_fields::normalize_value(field, &value) {
+                Ok(value) => Some(value),
+                Err(message) => return json_error(StatusCode::BAD_REQUEST, "Bad Request", message),
+            },
+        };
+        updates.push((name, value));
+    }
+
+    if let Err(e) = db::set_machine_field_values(&id, &updates).await {
+        error!("Failed to update custom fields of machine {}: {}", id, e);
+        return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string());
+    }
+    let _ = state.event_manager.send(format!("machine_updated:{}", id));
+
+    match db::get_machine_field_values(&id).await {
+        Ok(values) => (StatusCode::OK, Json(values)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn list_sites(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    match db::get_sites().await {
+        Ok(sites) => (StatusCode::OK, Json(sites)).into_response(),
         Err(e) => {
-            error!("Database error while looking up MAC {}: {}", mac, e);
-            let error_response = ErrorResponse {
-                error: "Database Error".to_string(),
-                message: e.to_string(),
-            };
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+            error!("Failed to list sites: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
         }
     }
 }
 
 #[axum::debug_handler]
-async fn delete_machine(
+async fn save_site(
     State(state): State<AppState>,
     auth_session: AuthSession,
-    Path(id): Path<Uuid>,
+    Json(site): Json<Site>,
 ) -> Response {
-    // Check if user is authenticated as admin
-    if auth_session.user.is_none() {
-        return (StatusCode::UNAUTHORIZED, Json(json!({
-            "error": "Unauthorized",
-            "message": "Admin authentication required for this operation"
-        }))).into_response();
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(message) = topology::validate_name(&site.name) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
     }
 
-    info!("Request to delete machine: {}", id);
-
-    // Get the machine to find its MAC address
-    match db::get_machine_by_id(&id).await {
-        Ok(Some(machine)) => {
-            // Delete from Tinkerbell
-            let mac_address = machine.mac_address.replace(":", "-").to_lowercase();
-            
-            let tinkerbell_result = match crate::tinkerbell::delete_hardware(&mac_address).await {
-                Ok(_) => {
-                    info!("Successfully deleted machine from Tinkerbell: {}", mac_address);
-                    true
-                },
-                Err(e) => {
-                    warn!("Failed to delete machine from Tinkerbell: {}", e);
-                    false
-                }
-            };
-
-            // Delete from database
-            match db::delete_machine(&id).await {
-                Ok(true) => {
-                    let message = if tinkerbell_result {
-                        "Machine successfully deleted from Dragonfly and Tinkerbell."
-                    } else {
-                        "Machine deleted from Dragonfly but there was an issue removing it from Tinkerbell."
-                    };
-                    
-                    // Emit machine deleted event
-                    let _ = state.event_manager.send(format!("machine_deleted:{}", id));
-                    
-                    (StatusCode::OK, Json(json!({ "success": true, "message": message }))).into_response()
-                },
-                Ok(false) => {
-                    (StatusCode::NOT_FOUND, Json(json!({ "error": "Machine not found in database" }))).into_response()
-                },
-                Err(e) => {
-                    error!("Failed to delete machine from database: {}", e);
-                    (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Database error: {}", e) }))).into_response()
-                }
-            }
-        },
-        Ok(None) => {
-            (StatusCode::NOT_FOUND, Json(json!({ "error": "Machine not found" }))).into_response()
+    match db::save_site(&site).await {
+        Ok(()) => {
+            let _ = state.event_manager.send(format!("topology_updated:{}", site.name));
+            (StatusCode::OK, Json(site)).into_response()
         },
         Err(e) => {
-            error!("Error fetching machine for deletion: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Database error: {}", e) }))).into_response()
+            error!("Failed to save site {}: {}", site.name, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
         }
     }
 }
 
-// Add this function to handle machine updates
 #[axum::debug_handler]
-async fn update_machine(
+async fn delete_site(
     State(state): State<AppState>,
-    // Use AuthSession directly, not Option<AuthSession>
     auth_session: AuthSession,
-    // Add ConnectInfo to get client IP
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    Path(id): Path<Uuid>,
-    Json(mut machine_payload): Json<Machine>,
+    Path(name): Path<String>,
 ) -> Response {
-    let client_ip = addr.ip().to_string();
-    info!("Update request for machine {} from IP: {}", id, client_ip);
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
 
-    // Authorization Logic
-    // Check if an admin user is logged in
-    let is_admin = auth_session.user.is_some();
+    match db::get_racks().await {
+        Ok(racks) if racks.iter().any(|rack| rack.site == name) => {
+            return json_error(StatusCode::CONFLICT, "Conflict", format!("Site {} still has racks; delete or move them first", name));
+        },
+        Ok(_) => {},
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
 
-    let authorized = if is_admin {
-        // Admin is always authorized
-        info!("Admin user authorized update for machine {}", id);
-        true
-    } else {
-        // Not an admin, check if it's the agent based on IP
-        info!("Request is not from an admin, checking IP for agent authorization...");
-        match db::get_machine_by_id(&id).await {
-            Ok(Some(stored_machine)) => {
-                if stored_machine.ip_address == client_ip {
-                    info!("Agent IP {} matches stored IP for machine {}. Authorizing update.", client_ip, id);
-                    true // IP matches, allow update
-                } else {
-                    warn!("Agent IP {} does NOT match stored IP {} for machine {}. Denying update.",
-                          client_ip, stored_machine.ip_address, id);
-                    false // IP mismatch
-                }
-            },
-            Ok(None) => {
-                warn!("Machine {} not found during IP authorization check.", id);
-                false // Machine not found
-                },
-                Err(e) => {
-                error!("Database error during IP authorization check for machine {}: {}", id, e);
-                false // Database error
-            }
+    match db::delete_site(&name).await {
+        Ok(true) => {
+            let _ = state.event_manager.send(format!("topology_updated:{}", name));
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No site named {}", name)),
+        Err(e) => {
+            error!("Failed to delete site {}: {}", name, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
         }
-    };
+    }
+}
 
-    if !authorized {
-        // Use 403 Forbidden for authorization failures
-        // (axum-login middleware handles 401 for missing authentication if configured)
-        return (StatusCode::FORBIDDEN, Json(json!({
-            "error": "Forbidden",
-            "message": "You are not authorized to update this machine."
-        }))).into_response();
+#[axum::debug_handler]
+async fn list_racks(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    match db::get_racks().await {
+        Ok(racks) => (StatusCode::OK, Json(racks)).into_response(),
+        Err(e) => {
+            error!("Failed to list racks: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
+        }
     }
+}
 
-    // --- Proceed with Update (if authorized) ---
-    
-    // Ensure the ID from the path matches the payload ID
-    if machine_payload.id != id {
-        return (StatusCode::BAD_REQUEST, Json(json!({
-            "error": "ID Mismatch",
-            "message": "The machine ID in the URL path does not match the ID in the request body."
-        }))).into_response();
+#[axum::debug_handler]
+async fn save_rack(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Json(rack): Json<Rack>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(message) = topology::validate_name(&rack.name) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+    if rack.height_u == 0 || rack.height_u > 60 {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", "Racks are 1 to 60U tall".to_string());
     }
 
-    info!("Updating machine {} with full payload (Authorized by admin: {})", id, is_admin);
-    
-    // Set the updated_at timestamp before saving
-    machine_payload.updated_at = Utc::now();
+    let (sites, placements) = match tokio::try_join!(db::get_sites(), db::get_placements()) {
+        Ok(result) => result,
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    if !sites.iter().any(|site| site.name == rack.site) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", format!("No site named {}", rack.site));
+    }
+    // Shrinking a rack mustn't leave machines hanging off the top
+    if let Some(placement) = placements.iter().find(|p| p.rack == rack.name && p.position_u + p.height_u - 1 > rack.height_u) {
+        return json_error(
+            StatusCode::CONFLICT,
+            "Conflict",
+            format!("Machine {} sits at U{}, above the new height", placement.machine_id, placement.position_u),
+        );
+    }
 
-    // Call the updated db::update_machine function
-    match db::update_machine(&machine_payload).await {
-                Ok(true) => {
-            // Emit machine updated event
-            let _ = state.event_manager.send(format!("machine_updated:{}", id));
-            
-            // Return the updated machine object
-            (StatusCode::OK, Json(machine_payload)).into_response()
-                },
-                Ok(false) => {
-            // This case should ideally not happen if the ID check above passed
-            // but handle it just in case (e.g., race condition with deletion)
-            (StatusCode::NOT_FOUND, Json(json!({
-                "error": "Not Found",
-                "message": format!("Machine with ID {} not found during update attempt.", id)
-            }))).into_response()
-                },
-                Err(e) => {
-            error!("Failed to update machine {}: {}", id, e);
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
-                "error": "Database Error",
-                "message": e.to_string()
-            }))).into_response()
+    match db::save_rack(&rack).await {
+        Ok(()) => {
+            let _ = state.event_manager.send(format!("topology_updated:{}", rack.name));
+            (StatusCode::OK, Json(rack)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to save rack {}: {}", rack.name, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
         }
     }
 }
 
-// Handler to get the OS assignment form
-async fn get_machine_os(Path(id): Path<Uuid>) -> Response {
-    Html(format!(r#"
-        <div class="sm:flex sm:items-start">
-            <div class="mt-3 text-center sm:mt-0 sm:text-left w-full">
-                <h3 class="text-lg leading-6 font-medium text-gray-900">
-                    Assign Operating System
-                </h3>
-                <div class="mt-2">
-                    <form hx-post="/api/machines/{}/os" hx-swap="none" @submit="osModal = false">
-                        <div class="mt-4">
-                            <label for="os_choice" class="block text-sm font-medium text-gray-700">Operating System</label>
-                            <select
-                                id="os_choice"
-                                name="os_choice"
-                                class="mt-1 block w-full pl-3 pr-10 py-2 text-base border-gray-300 focus:outline-none focus:ring-indigo-500 focus:border-indigo-500 sm:text-sm rounded-md"
-                            >
-                                <option value="ubuntu-2204">Ubuntu 22.04</option>
-                                <option value="ubuntu-2404">Ubuntu 24.04</option>
-                                <option value="debian-12">Debian 12</option>
-                                <option value="proxmox">Proxmox VE</option>
-                                <option value="talos">Talos</option>
-                            </select>
-                        </div>
-                        <div class="mt-5 sm:mt-4 sm:flex sm:flex-row-reverse">
-                            <button
-                                type="submit"
-                                class="inline-flex w-full justify-center rounded-md bg-indigo-600 px-3 py-2 text-sm font-semibold text-white shadow-sm hover:bg-indigo-500 sm:ml-3 sm:w-auto"
-                            >
-                                Assign
-                            </button>
-                            <button
-                                type="button"
-                                class="mt-3 inline-flex w-full justify-center rounded-md bg-white px-3 py-2 text-sm font-semibold text-gray-900 shadow-sm ring-1 ring-inset ring-gray-300 hover:bg-gray-50 sm:mt-0 sm:w-auto"
-                                @click="osModal = false"
-                            >
-                                Cancel
-                            </button>
-                        </div>
-                    </form>
-                </div>
-            </div>
-        </div>
-    "#, id)).into_response()
+// A rack's elevation: its units from the top down, with the machine in each and its status
+#[axum::debug_handler]
+async fn get_rack_elevation(auth_session: AuthSession, Path(name): Path<String>) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    let rack = match db::get_rack(&name).await {
+        Ok(Some(rack)) => rack,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "Not Found", format!("No rack named {}", name)),
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    match tokio::try_join!(db::get_placements(), db::get_all_machines()) {
+        Ok((placements, machines)) => {
+            let machines: HashMap<Uuid, &Machine> = machines.iter().map(|m| (m.id, m)).collect();
+            (StatusCode::OK, Json(topology::elevation(&rack, &placements, &machines))).into_response()
+        },
+        Err(e) => {
+            error!("Failed to build elevation of rack {}: {}", name, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
+        }
+    }
 }
 
-// Handler to get the status update form 
-pub async fn get_machine_status(Path(id): Path<Uuid>) -> impl IntoResponse {
-    let html = format!(r#"
-        <div class="sm:flex sm:items-start">
-            <div class="mt-3 text-center sm:mt-0 sm:text-left w-full">
-                <h3 class="text-lg leading-6 font-medium text-gray-900">
-                    Update Machine Status
-                </h3>
-                <div class="mt-2">
-                    <form hx-post="/machines/{}/status" hx-swap="none" @submit="statusModal = false">
-                        <div class="mb-4">
-                            <label for="status" class="block text-sm font-medium text-gray-700">Status</label>
-                            <select name="status" id="status" class="mt-1 block w-full pl-3 pr-10 py-2 text-base border-gray-300 focus:outline-none focus:ring-indigo-500 focus:border-indigo-500 sm:text-sm rounded-md">
-                                <option value="Ready">Ready</option>
-                                <option value="AwaitingAssignment">Awaiting OS Assignment</option>
-                                <option value="InstallingOS">Installing OS</option>
-                                <option value="Error">Error</option>
-                            </select>
-                        </div>
-                        <div class="mt-5 sm:mt-6">
-                            <button type="submit" class="inline-flex justify-center w-full rounded-md border border-transparent shadow-sm px-4 py-2 bg-indigo-600 text-base font-medium text-white hover:bg-indigo-700 focus:outline-none focus:ring-2 focus:ring-offset-2 focus:ring-indigo-500 sm:text-sm">
-                                Update Status
-                            </button>
-                        </div>
-                    </form>
-                </div>
-            </div>
-        </div>
-    "#, id);
+#[axum::debug_handler]
+async fn delete_rack(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
 
-    Html(html)
+    match db::get_placements().await {
+        Ok(placements) if placements.iter().any(|p| p.rack == name) => {
+            return json_error(StatusCode::CONFLICT, "Conflict", format!("Rack {} still has machines in it; move them first", name));
+        },
+        Ok(_) => {},
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+
+    match db::delete_rack(&name).await {
+        Ok(true) => {
+            let _ = state.event_manager.send(format!("topology_updated:{}", name));
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No rack named {}", name)),
+        Err(e) => {
+            error!("Failed to delete rack {}: {}", name, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
+        }
+    }
 }
 
-// Rename from sse_events to machine_events to match the function name used in the working implementation
-async fn machine_events(
-    State(state): State<AppState>,
-) -> Sse<impl Stream<Item = std::result::Result<Event, Infallible>>> {
-    let rx = state.event_manager.subscribe(); // Remove mut
-    
-    let stream = stream::unfold(rx, |mut rx| async move {
-        match rx.recv().await {
-            Ok(event_string) => {
-                // FIX: Correct parsing and variable naming
-                let parts: Vec<&str> = event_string.splitn(2, ':').collect();
-                let (event_type, event_payload_str) = if parts.len() == 2 { // Renamed event_id_str to event_payload_str for clarity
-                    (parts[0], Some(parts[1]))
-                } else {
-                    (event_string.as_str(), None)
-                };
+// Assign an OS to every machine in a rack that is waiting for one. Each machine goes through the
+// same checks as a single assignment; the ones that fail them are reported and left alone.
+#[axum::debug_handler]
+async fn assign_os_to_rack(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+    Json(payload): Json<OsAssignmentRequest>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
 
-                // Special handling for ip_download_progress to send raw JSON payload
-                if event_type == "ip_download_progress" {
-                    if let Some(payload_str) = event_payload_str {
-                        // Directly use the JSON string as data for this specific event type
-                let sse_event = Event::default()
-                    .event(event_type)
-                            .data(payload_str); // Use the payload string directly
-                        Some((Ok(sse_event), rx))
-                    } else {
-                         warn!("Received ip_download_progress event without payload: {}", event_string);
-                         // Optionally send a comment or skip
-                         let comment_event = Event::default().comment("Warning: ip_download_progress event received without payload.");
-                         Some((Ok(comment_event), rx))
-                    }
-                } else {
-                    // Existing logic for other events (like machine_updated, machine_discovered, etc.)
-                    let data_payload = if let Some(id_str) = event_payload_str { // Use the renamed variable
-                        json!({ "type": event_type, "id": id_str })
-                    } else {
-                        // Ensure there's always a payload, even without ID
-                        json!({ "type": event_type })
-                    };
+    let (placements, machines) = match tokio::try_join!(db::get_placements(), db::get_all_machines()) {
+        Ok(result) => result,
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    let in_rack: Vec<Machine> = placements.iter()
+        .filter(|p| p.rack == name)
+        .filter_map(|p| machines.iter().find(|m| m.id == p.machine_id).cloned())
+        .collect();
+    if in_rack.is_empty() {
+        return json_error(StatusCode::NOT_FOUND, "Not Found", format!("Rack {} has no machines", name));
+    }
 
-                    // Serialize JSON to string for SSE data field
-                    match serde_json::to_string(&data_payload) {
-                        Ok(json_string) => {
-                            let sse_event = Event::default()
-                                .event(event_type)
-                                .data(json_string);
-                Some((Ok(sse_event), rx))
-                        },
-                        Err(e) => {
-                            error!("Failed to serialize SSE event data to JSON: {}", e);
-                            let comment_event = Event::default().comment("Internal error: failed to serialize event.");
-                            Some((Ok(comment_event), rx))
-                        }
-                    }
-                }
-            },
-            Err(_) => None,
+    let actor = policy::principal(&auth_session);
+    let description = format!("Install {} on rack {}", payload.os_choice, name);
+    let operation = operations::start("rack_assign_os", description, &actor.clone(), move |ctx| async move {
+        let mut assigned = Vec::new();
+        let mut skipped = Vec::new();
+        for (i, machine) in in_rack.iter().enumerate() {
+            if ctx.is_cancelled() {
+                break;
+            }
+            ctx.set_progress(i, in_rack.len());
+            if machine.status != MachineStatus::AwaitingAssignment {
+                skipped.push(json!({ "id": machine.id, "reason": format!("Machine is {}", machine.status) }));
+                continue;
+            }
+            let cause = StatusCause::Admin(format!("{} via rack {}", actor, name));
+            let response = assign_os_internal(machine.id, payload.os_choice.clone(), cause).await;
+            if response.status().is_success() {
+                assigned.push(machine.id);
+            } else {
+                skipped.push(json!({ "id": machine.id, "reason": format!("Assignment failed ({})", response.status()) }));
+            }
         }
+        ctx.set_progress(assigned.len() + skipped.len(), in_rack.len());
+        info!("{} assigned {} to {} machines in rack {}", actor, payload.os_choice, assigned.len(), name);
+        Ok(json!({ "assigned": assigned, "skipped": skipped }))
     });
+    operation_accepted(&operation)
+}
 
-    Sse::new(stream).keep_alive(
-        KeepAlive::new()
-            .interval(Duration::from_secs(1))
-            .text("ping"),
-    )
+#[axum::debug_handler]
+async fn get_machine_placement(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
+    }
+
+    match db::get_placements().await {
+        Ok(placements) => match placements.into_iter().find(|p| p.machine_id == id) {
+            Some(placement) => (StatusCode::OK, Json(placement)).into_response(),
+            None => json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine {} isn't placed in a rack", id)),
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
 }
 
-async fn generate_ipxe_script(script_name: &str) -> Result<String, dragonfly_common::Error> {
-    info!("Generating IPXE script: {}", script_name);
- 
-    match script_name {
-        "hookos.ipxe" => {
-            // Get Dragonfly base URL (required)
-            let base_url_str = env::var("DRAGONFLY_BASE_URL")
-                .map_err(|_| {
-                    error!("CRITICAL: DRAGONFLY_BASE_URL environment variable is not set. HookOS iPXE script requires this.");
-                    Error::Internal("Server is missing required DRAGONFLY_BASE_URL configuration.".to_string())
-                })?;
+#[axum::debug_handler]
+async fn update_machine_placement(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Json(mut placement): Json<Placement>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+    placement.machine_id = id;
 
-            // --- Derive Tinkerbell defaults from DRAGONFLY_BASE_URL ---
-            let default_tinkerbell_host = Url::parse(&base_url_str)
-                .ok()
-                .and_then(|url| url.host_str().map(String::from))
-                .unwrap_or_else(|| {
-                    warn!("Could not parse DRAGONFLY_BASE_URL host, using fallback '127.0.0.1' for Tinkerbell defaults.");
-                    "127.0.0.1".to_string()
-                });
-            
-            const DEFAULT_GRPC_PORT: u16 = 42113;
-            let default_grpc_authority = format!("{}:{}", default_tinkerbell_host, DEFAULT_GRPC_PORT);
-            let default_syslog_host = default_tinkerbell_host.clone(); // Default syslog host is just the host part
-            // -----------------------------------------------------------
+    let rack = match db::get_rack(&placement.rack).await {
+        Ok(Some(rack)) => rack,
+        Ok(None) => return json_error(StatusCode::BAD_REQUEST, "Bad Request", format!("No rack named {}", placement.rack)),
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    let placements = match db::get_placements().await {
+        Ok(placements) => placements,
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    if let Err(message) = topology::check_placement(&rack, &placement, &placements) {
+        return json_error(StatusCode::CONFLICT, "Conflict", message);
+    }
 
-            // Get Tinkerbell config, using derived values as defaults
-            let grpc_authority = env::var("TINKERBELL_GRPC_AUTHORITY")
-                .unwrap_or_else(|_| {
-                    info!("TINKERBELL_GRPC_AUTHORITY not set, deriving default: {}", default_grpc_authority);
-                    default_grpc_authority
-                });
-            let syslog_host = env::var("TINKERBELL_SYSLOG_HOST")
-                .unwrap_or_else(|_| {
-                     info!("TINKERBELL_SYSLOG_HOST not set, deriving default: {}", default_syslog_host);
-                     default_syslog_host
-                 });
-            let tinkerbell_tls = env::var("TINKERBELL_TLS")
-                .map(|s| s.parse().unwrap_or(false))
-                .unwrap_or(false);
+    match db::set_placement(&placement).await {
+        Ok(()) => {
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            (StatusCode::OK, Json(placement)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to place machine {}: {}", id, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
+        }
+    }
+}
 
-            // Format the HookOS iPXE script using Dragonfly URL for artifacts and Tinkerbell details for params
-            Ok(format!(r#"#!ipxe
+#[axum::debug_handler]
+async fn delete_machine_placement(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
 
-echo Loading HookOS via Dragonfly...
+    match db::clear_placement(&id).await {
+        Ok(_) => {
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
 
-set arch ${{buildarch}}
-# Dragonfly + Tinkerbell only supports 64 bit archectures.
-# The build architecture does not necessarily represent the architecture of the machine on which iPXE is running.
-# https://ipxe.org/cfg/buildarch
+#[axum::debug_handler]
+async fn update_hostname(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<HostnameUpdateRequest>,
+) -> Response {
+    // Check the caller's permissions on this machine
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Operate).await {
+        return response;
+    }
 
-iseq ${{arch}} i386 && set arch x86_64 ||
-iseq ${{arch}} arm32 && set arch aarch64 ||
-iseq ${{arch}} arm64 && set arch aarch64 ||
-set base-url {}
-set retries:int32 0
-set retry_delay:int32 0
+    info!("Updating hostname for machine {} to {}", id, payload.hostname);
+    
+    match db::update_hostname(&id, &payload.hostname).await {
+        Ok(true) => {
+            // Get the updated machine to update Tinkerbell
+            if let Ok(Some(machine)) = db::get_machine_by_id(&id).await {
+                // Update the machine in Tinkerbell (don't fail if this fails)
+                if let Err(e) = crate::tinkerbell::register_machine(&machine).await {
+                    warn!("Failed to update machine in Tinkerbell (continuing anyway): {}", e);
+                }
+            }
+            
+            // Emit machine updated event
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            
+            let response = HostnameUpdateResponse {
+                success: true,
+                message: format!("Hostname updated for machine {}", id),
+            };
+            (StatusCode::OK, Json(response)).into_response()
+        },
+        Ok(false) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Machine with ID {} not found", id),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to update hostname for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
 
-set worker_id ${{mac}}
-set grpc_authority {}
-set syslog_host {}
-set tinkerbell_tls {}
+#[axum::debug_handler]
+async fn generate_hostname(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    // Check the caller's permissions on this machine
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Operate).await {
+        return response;
+    }
 
-echo worker_id=${{mac}}
-echo grpc_authority={}
-echo syslog_host={}
-echo tinkerbell_tls={}
+    info!("Regenerating hostname for machine {} from policy", id);
+    
+    match crate::hostname_policy::apply_to_machine(&id).await {
+        Ok(Some(hostname)) => {
+            // Push the new hostname to Tinkerbell so templates pick it up
+            if let Ok(Some(machine)) = db::get_machine_by_id(&id).await {
+                if let Err(e) = crate::tinkerbell::register_machine(&machine).await {
+                    warn!("Failed to update machine in Tinkerbell (continuing anyway): {}", e);
+                }
+            }
+            
+            // Emit machine updated event
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            
+            let response = HostnameUpdateResponse {
+                success: true,
+                message: format!("Hostname for machine {} set to {}", id, hostname),
+            };
+            (StatusCode::OK, Json(response)).into_response()
+        },
+        Ok(None) => {
+            let error_response = ErrorResponse {
+                error: "No Hostname".to_string(),
+                message: "No hostname policy is configured, or it produced no hostname for this machine".to_string(),
+            };
+            (StatusCode::UNPROCESSABLE_ENTITY, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to generate hostname for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Hostname Policy Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
 
-set idx:int32 0
-:retry_kernel
-kernel ${{base-url}}/ipxe/hookos/vmlinuz-${{arch}} \
-syslog_host=${{syslog_host}} grpc_authority=${{grpc_authority}} tinkerbell_tls=${{tinkerbell_tls}} worker_id=${{worker_id}} hw_addr=${{mac}} \
-console=tty1 console=tty2 console=ttyAMA0,115200 console=ttyAMA1,115200 console=ttyS0,115200 console=ttyS1,115200 tink_worker_image=quay.io/tinkerbell/tink-worker:v0.12.1 \
-intel_iommu=on iommu=pt initrd=initramfs-${{arch}} && goto download_initrd || iseq ${{idx}} ${{retries}} && goto kernel-error || inc idx && echo retry in ${{retry_delay}} seconds ; sleep ${{retry_delay}} ; goto retry_kernel
+#[axum::debug_handler]
+async fn update_os_installed(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<OsInstalledUpdateRequest>,
+) -> Response {
+    info!("Updating OS installed for machine {} to {}", id, payload.os_installed);
+    
+    match db::update_os_installed(&id, &payload.os_installed).await {
+        Ok(true) => {
+            // Emit machine updated event
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            
+            let response = OsInstalledUpdateResponse {
+                success: true,
+                message: format!("OS installed updated for machine {}", id),
+            };
+            (StatusCode::OK, Json(response)).into_response()
+        },
+        Ok(false) => {
+            // Add a warning log here to confirm if this path is hit
+            warn!("Machine with ID {} not found when attempting to update OS installed.", id);
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Machine with ID {} not found", id),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to update OS installed for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
 
-:download_initrd
-set idx:int32 0
-:retry_initrd
-initrd ${{base-url}}/ipxe/hookos/initramfs-${{arch}} && goto boot || iseq ${{idx}} ${{retries}} && goto initrd-error || inc idx && echo retry in ${{retry_delay}} seconds ; sleep ${{retry_delay}} ; goto retry_initrd
+// Default clock skew (in seconds) beyond which a machine gets a warning
+pub const DEFAULT_CLOCK_SKEW_THRESHOLD_SECS: i64 = 30;
 
-:boot
-set idx:int32 0
-:retry_boot
-boot || iseq ${{idx}} ${{retries}} && goto boot-error || inc idx && echo retry in ${{retry_delay}} seconds ; sleep ${{retry_delay}} ; goto retry_boot
+// Get the clock skew warning threshold, overridable via DRAGONFLY_CLOCK_SKEW_THRESHOLD
+pub fn clock_skew_threshold_secs() -> i64 {
+    parse_clock_skew_threshold(env::var("DRAGONFLY_CLOCK_SKEW_THRESHOLD").ok())
+}
 
-:kernel-error
-echo Failed to load kernel
-imgfree
-exit
+fn parse_clock_skew_threshold(value: Option<String>) -> i64 {
+    value
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_CLOCK_SKEW_THRESHOLD_SECS)
+}
 
-:initrd-error
-echo Failed to load initrd
-imgfree
-exit
+// Agent clock minus server clock, so a machine running fast has positive skew
+fn clock_skew(agent_time: chrono::DateTime<Utc>, server_time: chrono::DateTime<Utc>) -> i64 {
+    (agent_time - server_time).num_seconds()
+}
 
-:boot-error
-echo Failed to boot
-imgfree
-exit
-"#, 
-            base_url_str, // Use Dragonfly base URL for artifacts
-            grpc_authority, // Use determined gRPC authority (env var or derived default)
-            syslog_host,    // Use determined syslog host (env var or derived default)
-            tinkerbell_tls, // Use determined TLS setting
-            grpc_authority, // for echo
-            syslog_host,    // for echo
-            tinkerbell_tls  // for echo
-            ))
-        },
-        "dragonfly-agent.ipxe" => {
-            // Get Dragonfly base URL for agent artifacts
-            let base_url = env::var("DRAGONFLY_BASE_URL")
-                .map_err(|_| {
-                    error!("CRITICAL: DRAGONFLY_BASE_URL environment variable is not set. Agent iPXE script requires this.");
-                    Error::Internal("Server is missing required DRAGONFLY_BASE_URL configuration.".to_string())
-                })?;
-                
-            // Format the Dragonfly Agent iPXE script
-            Ok(format!(r#"#!ipxe
-kernel {}/ipxe/dragonfly-agent/vmlinuz \
-  ip=dhcp \
-  alpine_repo=http://dl-cdn.alpinelinux.org/alpine/v3.21/main \
-  modules=loop,squashfs,sd-mod,usb-storage \
-  initrd=initramfs-lts \
-  modloop={}/ipxe/dragonfly-agent/modloop \
-  apkovl={}/ipxe/dragonfly-agent/localhost.apkovl.tar.gz \
-  rw
-initrd {}/ipxe/dragonfly-agent/initramfs-lts
-boot
-"#, 
-            base_url, // for kernel path
-            base_url, // for modloop path
-            base_url, // for apkovl path
-            base_url  // for initrd path
-            ))
-        },
-        _ => {
-            warn!("Cannot generate unknown IPXE script: {}", script_name); // Log the specific script name
-            Err(Error::NotFound) // Use the unit variant correctly
-        },
+// Compare an agent's reported time against ours and store the difference.
+// Large skew breaks TLS during image pulls, so we warn loudly about it.
+async fn record_clock_skew(id: &Uuid, agent_time: chrono::DateTime<Utc>) -> anyhow::Result<i64> {
+    let skew_seconds = clock_skew(agent_time, Utc::now());
+    let threshold = clock_skew_threshold_secs();
+    
+    if skew_seconds.abs() > threshold {
+        warn!("Machine {} clock is skewed by {}s (threshold {}s); NTP will be configured during provisioning",
+              id, skew_seconds, threshold);
     }
+    
+    db::update_clock_skew(id, skew_seconds).await?;
+    Ok(skew_seconds)
 }
 
-fn create_streaming_response(
-    stream: ReceiverStream<Result<Bytes, Error>>,
-    content_type: &str,
-    content_length: Option<u64>,
-    content_range: Option<String>
+// Lightweight liveness ping from the machine's enrolled agent. Machines that stop sending these are marked Offline.
+#[axum::debug_handler]
+async fn machine_heartbeat(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
 ) -> Response {
-    // Map the stream from Result<Bytes> to Result<Frame<Bytes>, BoxError>
-    let mapped_stream = stream.map(|result| {
-        match result {
-            Ok(bytes) => {
-                // Removed check for empty EOF marker
-                // Simply map non-empty bytes to a data frame
-                Ok(Frame::data(bytes))
-            },
-            Err(e) => Err(Box::new(e) as Box<dyn std::error::Error + Send + Sync>),
+    // Anyone else could keep a dead machine online, or bring it back from Offline
+    if let Err(response) = crate::agent_commands::verify_agent_request(&id, "POST", uri.path(), &headers, &body).await {
+        return response;
+    }
+    match db::get_machine_by_id(&id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Machine with ID {} not found", id),
+            };
+            return (StatusCode::NOT_FOUND, Json(error_response)).into_response();
+        },
+        Err(e) => {
+            error!("Failed to look up machine {} for heartbeat: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response();
         }
-    });
-    
-    // Create a stream body with explicit end signal
-    let body = StreamBody::new(mapped_stream);
-    
-    // Determine status code based on whether it's a partial response
-    let status_code = if content_range.is_some() {
-        StatusCode::PARTIAL_CONTENT
-    } else {
-        StatusCode::OK
+    }
+
+    match db::record_heartbeat(&id).await {
+        Ok(restored) => {
+            if restored.is_some() {
+                let _ = state.event_manager.send(format!("machine_online:{}", id));
+                let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            }
+            (StatusCode::OK, Json(json!({
+                "success": true,
+                "offline_after_seconds": crate::heartbeat::offline_after_secs(),
+            }))).into_response()
+        },
+        Err(e) => {
+            error!("Failed to record heartbeat for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Exchange the shared enrollment token for a per-machine secret the agent signs later requests with
+#[axum::debug_handler]
+async fn enroll_agent(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<EnrollRequest>,
+) -> Response {
+    let Some(expected) = crate::agent_commands::enrollment_token() else {
+        let error_response = ErrorResponse {
+            error: "Enrollment Disabled".to_string(),
+            message: "Set DRAGONFLY_ENROLLMENT_TOKEN on the server to enable agent enrollment".to_string(),
+        };
+        return (StatusCode::FORBIDDEN, Json(error_response)).into_response();
     };
-    
-    // Start building the response
-    let mut builder = Response::builder()
-        .status(status_code)
-        .header(axum::http::header::CONTENT_TYPE, content_type)
-        // Always accept ranges
-        .header(axum::http::header::ACCEPT_RANGES, "bytes")
-        // Always set no compression
-        .header(axum::http::header::CONTENT_ENCODING, "identity");
+    if !crate::agent_commands::token_matches(&expected, &payload.enrollment_token) {
+        warn!("Rejected agent enrollment for machine {} with an invalid token", id);
+        let error_response = ErrorResponse {
+            error: "Unauthorized".to_string(),
+            message: "Invalid enrollment token".to_string(),
+        };
+        return (StatusCode::UNAUTHORIZED, Json(error_response)).into_response();
+    }
 
-    if let Some(length) = content_length {
-        // If Content-Length is known, set it and DO NOT use chunked encoding.
-        // This applies to both 200 OK and 206 Partial Content.
-        builder = builder.header(axum::http::header::CONTENT_LENGTH, length.to_string());
-    } else {
-        // Only use chunked encoding if length is truly unknown (should typically only be for 200 OK).
-        // It's an error to have a 206 response without Content-Length.
-        if status_code == StatusCode::OK { 
-            builder = builder.header(axum::http::header::TRANSFER_ENCODING, "chunked");
-        } else {
-            // This case (206 without Content-Length) ideally shouldn't happen with our logic.
-            // Log a warning if it does.
-            warn!("Attempting to create 206 response without Content-Length!");
+    // Machines with a pinned TPM must prove they hold its attestation key
+    match crate::attestation::check_enrollment(&id, payload.attestation.as_ref()).await {
+        Ok(Ok(())) => {},
+        Ok(Err(message)) => {
+            warn!("Rejected agent enrollment for machine {}: {}", id, message);
+            let _ = state.event_manager.send(format!("attestation_failed:{}", id));
+            let error_response = ErrorResponse {
+                error: "Attestation Failed".to_string(),
+                message,
+            };
+            return (StatusCode::FORBIDDEN, Json(error_response)).into_response();
+        },
+        Err(e) => {
+            error!("Failed to check TPM attestation for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response();
         }
     }
-    
-    // Include Content-Range if it's a partial response
-    if let Some(range_header_value) = content_range {
-        builder = builder.header(axum::http::header::CONTENT_RANGE, range_header_value);
+
+    // Anyone holding the shared token could otherwise take over an enrolled machine's commands
+    let agent_secret = crate::agent_commands::generate_secret();
+    match db::set_agent_secret(&id, &agent_secret).await {
+        Ok(true) => (StatusCode::OK, Json(EnrollResponse { agent_secret })).into_response(),
+        Ok(false) if matches!(db::get_machine_by_id(&id).await, Ok(Some(_))) => {
+            warn!("Refused agent enrollment for machine {}, which is already enrolled", id);
+            let error_response = ErrorResponse {
+                error: "Already Enrolled".to_string(),
+                message: "This machine's agent is already enrolled; an admin must reset it first".to_string(),
+            };
+            (StatusCode::CONFLICT, Json(error_response)).into_response()
+        },
+        Ok(false) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Machine with ID {} not found", id),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to enroll agent for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
     }
-    
-    // Build the final response
-    builder.body(Body::new(body))
-        .unwrap_or_else(|_| {
-            Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::new(Empty::new()))
-                .unwrap()
-        })
 }
 
+// Let a reinstalled machine's agent enroll again
+#[axum::debug_handler]
+async fn reset_agent_enrollment(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
 
-async fn read_file_as_stream(
-    path: &StdPath,
-    range_header: Option<&HeaderValue>, // Add parameter for Range header
-    state: Option<&AppState>, // Add optional state for event emission
-    machine_id: Option<Uuid> // Add optional machine ID for tracking
-) -> Result<(ReceiverStream<Result<Bytes, Error>>, Option<u64>, Option<String>), Error> { // Return size and Content-Range
-    info!("[STREAM_READ] Beginning read_file_as_stream for path: {}, range: {:?}, machine_id: {:?}", 
-          path.display(), range_header.map(|h| h.to_str().unwrap_or("invalid")), machine_id);
+    match db::clear_agent_secret(&id).await {
+        Ok(true) => {
+            warn!("Agent enrollment of machine {} reset by {}", id, policy::principal(&auth_session));
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Ok(false) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: "This machine's agent is not enrolled".to_string(),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to reset agent enrollment of machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
 
-    let mut file = fs::File::open(path).await.map_err(|e| Error::Internal(format!("Failed to open file {}: {}", path.display(), e)))?; // Added mut back
-    let (tx, rx) = mpsc::channel::<Result<Bytes, Error>>(32);
-    let path_buf = path.to_path_buf();
-    
-    // Get total file size
-    let metadata = fs::metadata(path).await.map_err(|e| Error::Internal(format!("Failed to get metadata {}: {}", path.display(), e)))?;
-    let total_size = metadata.len();
-    
-    // Get file name for progress tracking
-    let file_name = path.file_name()
-                        .and_then(|name| name.to_str())
-                        .map(String::from);
-    
-    let (start, _end, response_length, content_range_header) = // Marked end as unused
-        if let Some(range_val) = range_header {
-            if let Ok(range_str) = range_val.to_str() {
-                if let Some((start, end)) = parse_range_header(range_str, total_size, file_name.as_deref(), state).await {
-                    let length = end - start + 1;
-                    let content_range = format!("bytes {}-{}/{}", start, end, total_size);
-                    // info!("Serving range request: {} for file {}", content_range, path.display()); // Commented out log
-                    (start, end, length, Some(content_range))
-                } else {
-                    warn!("Invalid Range header format: {}", range_str);
-                    // Invalid range, serve the whole file
-                    (0, total_size.saturating_sub(1), total_size, None)
-                }
-            } else {
-                warn!("Invalid Range header value (not UTF-8)");
-                // Invalid range, serve the whole file
-                (0, total_size.saturating_sub(1), total_size, None)
+// Nonce the agent's TPM quotes to prove it's the machine whose keys were pinned at discovery
+#[axum::debug_handler]
+async fn agent_attestation_challenge(Path(id): Path<Uuid>) -> Response {
+    match db::get_machine_by_id(&id).await {
+        Ok(Some(_)) => match crate::attestation::issue_challenge(&id, chrono::Utc::now()) {
+            Some(challenge) => (StatusCode::OK, Json(challenge)).into_response(),
+            None => {
+                warn!("Refusing attestation challenge for machine {}: too many are pending", id);
+                json_error(StatusCode::SERVICE_UNAVAILABLE, "Too Many Challenges", "Too many attestation challenges are pending; try again shortly".to_string())
             }
-        } else {
-            // No range header, serve the whole file
-            (0, total_size.saturating_sub(1), total_size, None)
-        };
+        },
+        Ok(None) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Machine with ID {} not found", id),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to issue attestation challenge for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
 
-    let response_content_length = Some(response_length);
-    let content_range_header_clone = content_range_header.clone(); // Clone for the task
-    // Clone state and machine_id needed for the background task *before* spawning
-    // Ensures owned values are moved into the async block, avoiding lifetime issues.
-    let task_state_owned = state.cloned(); // Creates Option<AppState>
-    let task_machine_id_copied = machine_id; // Copies Option<Uuid>
+#[axum::debug_handler]
+async fn get_machine_tpm(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
+    }
 
-    tokio::spawn(async move {
-        // Handle Range requests differently: read the whole range at once
-        if content_range_header_clone.is_some() { // Use the clone
-            if start > 0 {
-                if let Err(e) = file.seek(std::io::SeekFrom::Start(start)).await {
-                    error!("Failed to seek file {}: {}", path_buf.display(), e);
-                    let _ = tx.send(Err(Error::Internal(format!("File seek error: {}", e)))).await;
-                    return;
-                }
-            }
-            
-            // Allocate buffer for the exact range size
-            let mut buffer = Vec::with_capacity(response_length as usize); // Use with_capacity
-            
-            // Create a reader limited to the exact range size
-            let mut limited_reader = file.take(response_length);
-            
-            // Read the exact range using the limited reader
-            match limited_reader.read_to_end(&mut buffer).await {
-                Ok(_) => {
-                    // Track progress for range requests too
-                    // For range requests, we use the start offset as an indicator of download progress
-                    if let (Some(state_ref), Some(machine_id_captured)) = (&task_state_owned, task_machine_id_copied) {
-                        if total_size > 0 {
-                            // Use start position + current range size as effective progress indicator
-                            let bytes_read = buffer.len() as u64;
-                            let effective_progress = start + bytes_read;
-                            
-                            info!("[RANGE_READ] Range request: start={}, bytes_read={}, total_size={}, effective_progress={}",
-                                  start, bytes_read, total_size, effective_progress);
-                                  
-                            // Clone state for progress tracking
-                            let owned_state = state_ref.clone();
-                            
-                            // Spawn progress tracking in a separate task
-                            tokio::spawn(async move {
-                                track_download_progress(Some(machine_id_captured), effective_progress, total_size, owned_state).await;
-                            });
-                        }
-                    }
-                
-                    // Send the complete range as a single chunk
-                    if tx.send(Ok(Bytes::from(buffer))).await.is_err() {
-                        warn!("Client stream receiver dropped for file {} while sending range", path_buf.display());
-                    }
-                    // Task finishes, tx is dropped, stream closes.
-                },
-                Err(e) => {
-                    error!("Failed to read exact range for file {}: {}", path_buf.display(), e);
-                    let _ = tx.send(Err(Error::Internal(format!("File read_exact error: {}", e)))).await;
-                }
-            }
-        } else {
-            // Original streaming logic for full file requests
-            let mut buffer = vec![0; 65536]; // 64KB buffer
-            let mut remaining = response_length; // For full file, response_length == total_size
-            let mut total_bytes_sent: u64 = 0;
+    match crate::attestation::status(&id).await {
+        Ok(Some(status)) => (StatusCode::OK, Json(status)).into_response(),
+        Ok(None) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: "No TPM identity is recorded for this machine".to_string(),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to get TPM identity of machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
 
-            while remaining > 0 {
-                let read_size = std::cmp::min(remaining as usize, buffer.len());
-                match file.read(&mut buffer[..read_size]).await {
-                    Ok(0) => {
-                        //info!("Reached EOF while serving file {} (remaining: {} bytes)", path_buf.display(), remaining);
-                        break; // EOF reached
-                    },
-                    Ok(n) => { // Handles n > 0
-                        let chunk = Bytes::copy_from_slice(&buffer[0..n]);
-                        remaining -= n as u64;
-                        total_bytes_sent += n as u64; // Add this line to update total bytes sent!
+// Forget the pinned TPM keys so the next registration pins new ones, e.g. after a motherboard swap
+#[axum::debug_handler]
+async fn clear_machine_tpm(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
 
-                        // ADDED LOG: Log bytes read and total sent
-                        debug!(path = %path_buf.display(), bytes_read = n, total_bytes_sent = total_bytes_sent, total_size = total_size, "[STREAM_READ_LOOP] Read chunk");
+    match db::clear_tpm_identity(&id).await {
+        Ok(true) => {
+            warn!("TPM identity of machine {} cleared by {}", id, policy::principal(&auth_session));
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Ok(false) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: "No TPM identity is recorded for this machine".to_string(),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to clear TPM identity of machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
 
-                        // Use the owned/copied state and machine_id captured by the 'move' closure
-                        // Match against the Option<&AppState> and Option<Uuid> directly
-                        if let (Some(state_ref), Some(machine_id_captured)) = (&task_state_owned, task_machine_id_copied) {
-                            if total_size > 0 { // Avoid division by zero
-                                debug!("[PROGRESS_DEBUG][CACHE_READ] Calling track_download_progress (machine_id: {}, sent: {}, total: {})", machine_id_captured, total_bytes_sent, total_size);
-                                // Clone the AppState here to get an owned value for the inner task.
-                                let owned_state = state_ref.clone(); // <-- Add this line
-                                // Spawn progress tracking in a separate task to avoid blocking the stream
-                                tokio::spawn(async move {
-                                    // Pass the already owned AppState.
-                                    track_download_progress(Some(machine_id_captured), total_bytes_sent, total_size, owned_state).await; // <-- Use owned_state here
-                                });
-                            } // else: Skipping progress track because total_size is 0 (logged elsewhere if needed)
-                        } // else: Skipping progress track because machine_id or state is missing
+#[derive(Deserialize)]
+pub struct IssueDiskKeyRequest {
+    pub scheme: crate::escrow::Scheme,
+}
 
-                        if tx.send(Ok(chunk)).await.is_err() {
-                            warn!("Client stream receiver dropped for file {}", path_buf.display());
-                            break; // Exit loop if receiver is gone
-                        }
-                    },
-                    Err(e) => {
-                        let err = Error::Internal(format!("File read error for {}: {}", path_buf.display(), e));
-                        if tx.send(Err(err)).await.is_err() {
-                            warn!("Client stream receiver dropped while sending error for {}", path_buf.display());
-                        }
-                        break; // Exit loop on read error
-                    }
-                }
-            }
+#[derive(Deserialize)]
+pub struct RevealDiskKeyRequest {
+    pub reason: String,
+}
+
+fn escrow_error_response(e: crate::escrow::EscrowError) -> Response {
+    use crate::escrow::EscrowError;
+    let (status, error) = match &e {
+        EscrowError::Disabled => (StatusCode::SERVICE_UNAVAILABLE, "Escrow Disabled"),
+        EscrowError::Invalid(_) => (StatusCode::BAD_REQUEST, "Invalid Request"),
+        EscrowError::NotFound => (StatusCode::NOT_FOUND, "Not Found"),
+        EscrowError::Database(_) => {
+            error!("Disk key escrow failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database Error")
         }
-        
-        // Task finishes, tx is dropped, stream closes.
-        debug!("Finished streaming task for: {}", path_buf.display());
-    });
-    
-    // Return the stream, the length of the *content being sent*, and the *original* Content-Range header string
-    Ok((tokio_stream::wrappers::ReceiverStream::new(rx), response_content_length, content_range_header))
+    };
+    let error_response = ErrorResponse {
+        error: error.to_string(),
+        message: e.to_string(),
+    };
+    (status, Json(error_response)).into_response()
 }
 
-// Serve iPXE artifacts (scripts and binaries)
-// Function to serve an iPXE artifact file from a configured directory
-pub async fn serve_ipxe_artifact(
+async fn issue_disk_key_response(id: &Uuid, body: &[u8]) -> Response {
+    let payload: IssueDiskKeyRequest = match serde_json::from_slice(body) {
+        Ok(payload) => payload,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, "Bad Request", format!("Invalid disk key request: {}", e)),
+    };
+    match crate::escrow::issue(id, payload.scheme).await {
+        Ok(key) => (StatusCode::OK, Json(json!({ "scheme": payload.scheme, "key": key }))).into_response(),
+        Err(e) => escrow_error_response(e),
+    }
+}
+
+// Called by the machine's enrolled agent to get a fresh recovery key for the disks it's about to encrypt
+#[axum::debug_handler]
+async fn issue_disk_key(
+    Path(id): Path<Uuid>,
+    OriginalUri(uri): OriginalUri,
     headers: HeaderMap,
-    Path(requested_path): Path<String>,
-    State(state): State<AppState>, // Add AppState to access event manager and client_ip
+    body: axum::body::Bytes,
 ) -> Response {
-    // Define constants for directories and URLs
-    const DEFAULT_ARTIFACT_DIR: &str = "/var/lib/dragonfly/ipxe-artifacts";
-    const ARTIFACT_DIR_ENV_VAR: &str = "DRAGONFLY_IPXE_ARTIFACT_DIR";
-    const ALLOWED_IPXE_SCRIPTS: &[&str] = &["hookos", "dragonfly-agent"]; // Define allowlist
-    const AGENT_APKOVL_PATH: &str = "/var/lib/dragonfly/ipxe-artifacts/dragonfly-agent/localhost.apkovl.tar.gz";
-    const AGENT_BINARY_URL: &str = "https://github.com/Zorlin/dragonfly/raw/refs/heads/main/dragonfly-agent-musl"; // TODO: Make configurable
-    
-    // --- Get Machine ID from Client IP --- 
-    let client_ip = state.client_ip.lock().await.clone();
-    let machine_id = if let Some(ip) = &client_ip {
-        // ADDED LOG: Log the IP being looked up
-        info!("[PROGRESS_DEBUG] Looking up machine by IP: {}", ip);
-        match db::get_machine_by_ip(ip).await {
-            Ok(Some(machine)) => {
-                // ADDED LOG: Log successful lookup
-                info!("[PROGRESS_DEBUG] Found machine ID {} for IP {}", machine.id, ip);
-                Some(machine.id)
-            },
-            Ok(None) => {
-                // Changed to INFO for visibility
-                info!("[PROGRESS_DEBUG] No machine found for IP {} requesting artifact {}", ip, requested_path);
-                None
-            },
-            Err(e) => {
-                // Changed to INFO for visibility
-                info!("[PROGRESS_DEBUG] DB error looking up machine by IP {}: {}", ip, e);
-                None
-            }
-        }
-    } else {
-        // Changed to INFO for visibility
-        info!("[PROGRESS_DEBUG] Client IP not found in state for artifact request {}", requested_path);
-        None
-    };
-    // ----------------------------------
+    // The signature covers the raw body, so check it before parsing
+    if let Err(response) = crate::agent_commands::verify_agent_request(&id, "POST", uri.path(), &headers, &body).await {
+        return response;
+    }
+    issue_disk_key_response(&id, &body).await
+}
 
-    // Get the base directory from env var or use default
-    let base_dir = env::var(ARTIFACT_DIR_ENV_VAR)
-        .unwrap_or_else(|_| {
-            debug!("{} not set, using default: {}", ARTIFACT_DIR_ENV_VAR, DEFAULT_ARTIFACT_DIR);
-            DEFAULT_ARTIFACT_DIR.to_string()
-        });
-    let base_path = PathBuf::from(base_dir);
-    
-    // Path sanitization - Allow '/' but prevent '..'
-    if requested_path.contains("..") || requested_path.contains('\\') {
-        warn!("Attempted iPXE artifact path traversal using '..' or '\': {}", requested_path);
-        return (StatusCode::BAD_REQUEST, "Invalid artifact path").into_response();
+#[axum::debug_handler]
+async fn list_disk_keys(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
     }
-    
-    let artifact_path = base_path.join(&requested_path);
 
-    // --- Serve from Cache First ---
-    if artifact_path.exists() {
-        info!("[SERVE_ARTIFACT] Cached artifact exists at {}, will use read_file_as_stream", artifact_path.display());
-        // Determine content type AND if it's an IPXE script
-        let (content_type, is_ipxe) = if requested_path.ends_with(".ipxe") {
-            ("text/plain", true)
-        } else if requested_path.ends_with(".tar.gz") {
-            ("application/gzip", false) // Ensure this returns a tuple
-        } else {
-            ("application/octet-stream", false) // Ensure this returns a tuple
-        };
+    match db::get_disk_keys(&id).await {
+        Ok(keys) => (StatusCode::OK, Json(keys)).into_response(),
+        Err(e) => escrow_error_response(e.into()),
+    }
+}
 
-        // Allowlist check for IPXE scripts from cache
-        if is_ipxe { // Check the boolean flag
-            let stem = StdPath::new(&requested_path).file_stem().and_then(|s| s.to_str());
-            if let Some(stem_str) = stem {
-                if !ALLOWED_IPXE_SCRIPTS.contains(&stem_str) {
-                    warn!("Attempt to serve non-allowlisted IPXE script stem from cache: {}", stem_str);
-                    return (StatusCode::NOT_FOUND, "iPXE Script Not Found").into_response();
-                }
-            } else {
-                 warn!("Could not extract stem from IPXE script path: {}", requested_path);
-                 return (StatusCode::BAD_REQUEST, "Invalid IPXE Script Path").into_response();
-            }
-        }
-        
-        // Serve allowed script or binary artifact from cache using streaming
-        // Pass the potentially found machine_id for progress tracking
-        match read_file_as_stream(&artifact_path, headers.get(axum::http::header::RANGE), Some(&state), machine_id).await {
-            Ok((stream, file_size, content_range)) => {
-                info!("Streaming cached artifact from disk: {}", requested_path);
-                return create_streaming_response(stream, content_type, file_size, content_range); // Pass content_range
-            },
-            Err(e) => {
-                error!("Failed to stream cached iPXE artifact: {}", e);
-                return (StatusCode::INTERNAL_SERVER_ERROR, "Error reading iPXE artifact").into_response();
-            }
-        }
-    } else {
-        // --- File Not Found: Generate or Download --- 
-        info!("[SERVE_ARTIFACT] Artifact {} not found locally, will need to generate or download", requested_path);
-        
-        // FIRST check if it is the specific apkovl path that needs generation
-        // Compare against the RELATIVE path expected from the URL
-        if requested_path == "dragonfly-agent/localhost.apkovl.tar.gz" {
-            // --- Special Case: Generate apkovl on demand ---
-            // Use the full absolute path for generation logic
-            let generation_target_path = PathBuf::from(AGENT_APKOVL_PATH);
-            info!("Generating {} on demand...", generation_target_path.display());
+// Decrypt an escrowed recovery key; every reveal is audited with who asked and why
+#[axum::debug_handler]
+async fn reveal_disk_key(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path((id, key_id)): Path<(Uuid, i64)>,
+    Json(payload): Json<RevealDiskKeyRequest>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
 
-            let base_url = match env::var("DRAGONFLY_BASE_URL") {
-                Ok(url) => url,
-                Err(_) => {
-                    error!("Cannot generate apkovl: DRAGONFLY_BASE_URL environment variable is not set.");
-                    return (StatusCode::INTERNAL_SERVER_ERROR, "Server configuration error for apkovl generation").into_response();
-                }
-            };
+    match crate::escrow::reveal(&policy::principal(&auth_session), &id, key_id, &payload.reason).await {
+        Ok(key) => {
+            let _ = state.event_manager.send(format!("disk_key_revealed:{}", id));
+            (StatusCode::OK, Json(json!({ "key": key }))).into_response()
+        },
+        Err(e) => escrow_error_response(e),
+    }
+}
 
-            match generate_agent_apkovl(&generation_target_path, &base_url, AGENT_BINARY_URL).await {
-                Ok(()) => {
-                    info!("Successfully generated {}, now serving...", generation_target_path.display());
-                    // Serve the newly generated file (no range needed here as it was just created)
-                    match read_file_as_stream(&generation_target_path, None, None, None).await { 
-                        Ok((stream, file_size, _)) => {
-                            return create_streaming_response(stream, "application/gzip", file_size, None); 
-                        },
-                        Err(e) => {
-                            error!("Failed to stream newly generated apkovl {}: {}", generation_target_path.display(), e);
-                            return (StatusCode::INTERNAL_SERVER_ERROR, "Error reading newly generated apkovl").into_response();
-                        }
-                    }
-                },
-                Err(e) => {
-                    error!("Failed to generate {}: {}", generation_target_path.display(), e);
-                    return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to generate {}: {}", generation_target_path.display(), e)).into_response();
-                }
-            }
-        } 
-        // NEXT check if it's a generic .ipxe script that needs generation
-        else if requested_path.ends_with(".ipxe") {
-            // --- Generate iPXE scripts on the fly ---
-            // Use the relative path for script generation lookup
-            match generate_ipxe_script(&requested_path).await {
-                Ok(script) => {
-                    info!("Generated {} script dynamically.", requested_path);
-                    // Cache in background using the full artifact_path
-                    let path_clone = artifact_path.clone(); 
-                    let script_clone = script.clone();
-                    let requested_path_clone = requested_path.clone(); // Clone for the task
-                    tokio::spawn(async move {
-                        // Ensure parent directory exists before writing
-                        if let Some(parent) = path_clone.parent() {
-                             if let Err(e) = fs::create_dir_all(parent).await {
-                                 warn!("Failed to create directory for caching {}: {}", requested_path_clone, e);
-                                 return; 
-                             }
-                         }
-                        if let Err(e) = fs::write(&path_clone, &script_clone).await {
-                             warn!("Failed to cache generated {} script: {}", requested_path_clone, e);
-                        }
-                    });
-                    
-                    // For iPXE scripts, let's build our own response
-                    let content_length = script.len() as u64;
-                    
-                    // Create a response that's optimized for iPXE
-                    return Response::builder()
-                        .status(StatusCode::OK)
-                        .header(axum::http::header::CONTENT_TYPE, "text/plain")
-                        .header(axum::http::header::CONTENT_LENGTH, content_length.to_string())
-                        .header(axum::http::header::CONTENT_ENCODING, "identity") // No compression
-                        .body(Body::from(script))
-                        .unwrap_or_else(|_| {
-                            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to build response").into_response()
-                        });
-                },
-                Err(Error::NotFound { .. }) => {
-                    warn!("IPXE script {} not found or could not be generated.", requested_path);
-                    // Fall through to final 404
-                },
-                Err(e) => {
-                    // Other error during generation (e.g., missing env var)
-                    error!("Failed to generate {} script: {}", requested_path, e);
-                    return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to generate script: {}", e)).into_response();
+#[axum::debug_handler]
+async fn list_disk_key_accesses(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_disk_key_accesses().await {
+        Ok(accesses) => (StatusCode::OK, Json(accesses)).into_response(),
+        Err(e) => escrow_error_response(e.into()),
+    }
+}
+
+fn root_password_error(e: PasswordError) -> Response {
+    let (status, message) = match e {
+        PasswordError::Secret(e) => return template_variable_error(e),
+        PasswordError::Database(e) => {
+            error!("Root password operation failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        },
+        e @ (PasswordError::NotFound | PasswordError::NoRotation(_)) => (StatusCode::NOT_FOUND, e.to_string()),
+        e @ PasswordError::AlreadyRevealed { .. } => (StatusCode::CONFLICT, e.to_string()),
+    };
+    json_error(status, status.canonical_reason().unwrap_or("Error"), message)
+}
+
+// Whether the machine has a generated root password and who has seen it, never the password
+#[axum::debug_handler]
+async fn get_root_password(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+
+    match db::get_root_password_info(&id).await {
+        Ok(Some(info)) => (StatusCode::OK, Json(info)).into_response(),
+        Ok(None) => root_password_error(PasswordError::NotFound),
+        Err(e) => root_password_error(e.into()),
+    }
+}
+
+// Each password can be revealed once; rotating it through the agent makes a new one to reveal
+#[axum::debug_handler]
+async fn reveal_root_password(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+
+    match crate::root_password::reveal(&policy::principal(&auth_session), &id).await {
+        Ok(password) => {
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            (StatusCode::OK, Json(json!({ "password": password }))).into_response()
+        },
+        Err(e) => root_password_error(e),
+    }
+}
+
+// Fetched by the agent while it runs a rotation command
+#[axum::debug_handler]
+async fn get_rotation_password(
+    Path((id, command_id)): Path<(Uuid, i64)>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(response) = crate::agent_commands::verify_agent_request(&id, "GET", uri.path(), &headers, &[]).await {
+        return response;
+    }
+
+    match crate::root_password::issue_for_rotation(&id, command_id).await {
+        Ok(password) => (StatusCode::OK, Json(json!({ "password": password }))).into_response(),
+        Err(e) => root_password_error(e),
+    }
+}
+
+#[axum::debug_handler]
+async fn list_agent_commands(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    // Check the caller's permissions on this machine
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
+    }
+
+    match db::get_agent_commands(&id).await {
+        Ok(commands) => (StatusCode::OK, Json(commands)).into_response(),
+        Err(e) => {
+            error!("Failed to list commands for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to list commands: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn queue_agent_command(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Json(kind): Json<CommandKind>,
+) -> Response {
+    // Running arbitrary scripts needs more than rebooting does
+    if let Err(response) = policy::authorize(&auth_session, &id, crate::agent_commands::required_permission(&kind)).await {
+        return response;
+    }
+
+    match db::get_agent_secret(&id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => {
+            let error_response = ErrorResponse {
+                error: "Agent Not Enrolled".to_string(),
+                message: "This machine's agent has not enrolled for remote commands".to_string(),
+            };
+            return (StatusCode::CONFLICT, Json(error_response)).into_response();
+        },
+        Err(e) => {
+            error!("Failed to check agent enrollment for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response();
+        }
+    }
+
+    let requested_by = policy::principal(&auth_session);
+    let queued = match db::add_agent_command(&id, &kind, Some(&requested_by)).await {
+        Ok(command_id) => db::get_agent_command(command_id).await
+            .and_then(|command| command.ok_or_else(|| anyhow::anyhow!("command {} disappeared after queueing", command_id))),
+        Err(e) => Err(e),
+    };
+    match queued {
+        Ok(command) => {
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            (StatusCode::CREATED, Json(command)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to queue command for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to queue command: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Polled by enrolled agents; hands out the oldest pending command, or 204 when there is none
+#[axum::debug_handler]
+async fn next_agent_command(
+    Path(id): Path<Uuid>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(response) = crate::agent_commands::verify_agent_request(&id, "GET", uri.path(), &headers, &[]).await {
+        return response;
+    }
+
+    match db::claim_next_agent_command(&id).await {
+        Ok(Some(command)) => (StatusCode::OK, Json(command)).into_response(),
+        Ok(None) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            error!("Failed to fetch next command for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn report_command_result(
+    State(state): State<AppState>,
+    Path((id, command_id)): Path<(Uuid, i64)>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Response {
+    // The signature covers the raw body, so check it before parsing
+    if let Err(response) = crate::agent_commands::verify_agent_request(&id, "POST", uri.path(), &headers, &body).await {
+        return response;
+    }
+    let result: CommandResult = match serde_json::from_slice(&body) {
+        Ok(result) => result,
+        Err(e) => {
+            let error_response = ErrorResponse {
+                error: "Bad Request".to_string(),
+                message: format!("Invalid command result: {}", e),
+            };
+            return (StatusCode::BAD_REQUEST, Json(error_response)).into_response();
+        }
+    };
+
+    let output = truncate_output(result.output);
+    match db::complete_agent_command(&id, command_id, result.exit_code, &output).await {
+        Ok(true) => {
+            info!("Command {} on machine {} finished with exit code {}", command_id, id, result.exit_code);
+            if let Ok(Some(command)) = db::get_agent_command(command_id).await {
+                if command.kind == CommandKind::RotateRootPassword {
+                    if let Err(e) = crate::root_password::finish_rotation(&id, command_id, result.exit_code == 0).await {
+                        error!("Failed to record root password rotation {} for machine {}: {}", command_id, id, e);
+                    }
                 }
             }
-            // If we fall through here, it means generate_ipxe_script returned NotFound
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            (StatusCode::OK, Json(json!({ "success": true }))).into_response()
+        },
+        Ok(false) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("No running command {} for machine {}", command_id, id),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to record result of command {} for machine {}: {}", command_id, id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// A machine's PCI devices and the capabilities they add up to
+#[axum::debug_handler]
+async fn get_machine_pci_devices(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
+    }
+    match db::get_pci_devices(&id).await {
+        Ok(devices) => Json(serde_json::json!({
+            "capabilities": crate::pci_devices::Capabilities::from_devices(&devices),
+            "devices": devices,
+        })).into_response(),
+        Err(e) => {
+            error!("Failed to load PCI devices for machine {}: {}", id, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
+        }
+    }
+}
+
+// The machine's latest post-install health checks
+#[axum::debug_handler]
+async fn get_machine_health_report(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
+    }
+    match db::get_health_report(&id).await {
+        Ok(Some(report)) => Json(report).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine {} hasn't been health checked", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Agents report their PCI devices on every boot, since cards come and go
+#[axum::debug_handler]
+async fn report_pci_devices(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<PciReportRequest>,
+) -> Response {
+    match db::get_machine_by_id(&id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine with ID {} not found", id)),
+        Err(e) => {
+            error!("Failed to look up machine {} for PCI device report: {}", id, e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string());
+        }
+    }
+    match db::set_pci_devices(&id, &payload.devices).await {
+        Ok(()) => {
+            info!("Machine {} reported {} PCI devices", id, payload.devices.len());
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Err(e) => {
+            error!("Failed to record PCI devices for machine {}: {}", id, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn report_clock(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<ClockReportRequest>,
+) -> Response {
+    match db::get_machine_by_id(&id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Machine with ID {} not found", id),
+            };
+            return (StatusCode::NOT_FOUND, Json(error_response)).into_response();
+        },
+        Err(e) => {
+            error!("Failed to look up machine {} for clock report: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response();
+        }
+    }
+    
+    match record_clock_skew(&id, payload.agent_time).await {
+        Ok(skew_seconds) => {
+            // Emit machine updated event
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            
+            let exceeds_threshold = skew_seconds.abs() > clock_skew_threshold_secs();
+            let message = if exceeds_threshold {
+                format!("Clock skew of {}s exceeds threshold; NTP will be configured", skew_seconds)
+            } else {
+                "Clock within tolerance".to_string()
+            };
+            let response = ClockReportResponse {
+                success: true,
+                skew_seconds,
+                exceeds_threshold,
+                message,
+            };
+            (StatusCode::OK, Json(response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to record clock skew for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn update_bmc(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Form(payload): Form<BmcCredentialsUpdateRequest>,
+) -> Response {
+    // Check the caller's permissions on this machine
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+
+    info!("Updating BMC credentials for machine {}", id);
+    
+    // Create BMC credentials from the form data
+    let bmc_type = match payload.bmc_type.as_str() {
+        "IPMI" => BmcType::IPMI,
+        "Redfish" => BmcType::Redfish,
+        _ => BmcType::Other(payload.bmc_type.clone()), // Clone string
+    };
+    
+    let credentials = BmcCredentials {
+        address: payload.bmc_address,
+        username: payload.bmc_username,
+        password: Some(payload.bmc_password), // Assume password is provided
+        bmc_type,
+    };
+    
+    match db::update_bmc_credentials(&id, &credentials).await {
+        Ok(true) => {
+            // Emit machine updated event
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            
+            (StatusCode::OK, Html(format!(r#"
+                <div class="p-4 mb-4 text-sm text-green-700 bg-green-100 rounded-lg" role="alert">
+                    <span class="font-medium">Success!</span> BMC credentials updated.
+                </div>
+                <script>
+                    setTimeout(function() {{
+                        window.location.reload();
+                    }}, 1500);
+                </script>
+            "#))).into_response()
+        },
+        Ok(false) => {
+            let error_message = format!("Machine with ID {} not found", id);
+            (StatusCode::NOT_FOUND, Html(format!(r#"
+                <div class="p-4 mb-4 text-sm text-red-700 bg-red-100 rounded-lg" role="alert">
+                    <span class="font-medium">Error!</span> {}.
+                </div>
+            "#, error_message))).into_response()
+        },
+        Err(e) => {
+            error!("Failed to update BMC credentials for machine {}: {}", id, e);
+            let error_message = format!("Database error: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Html(format!(r#"
+                <div class="p-4 mb-4 text-sm text-red-700 bg-red-100 rounded-lg" role="alert">
+                    <span class="font-medium">Error!</span> {}.
+                </div>
+            "#, error_message))).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BmcJobRequest {
+    tasks: Vec<crate::bmc::BmcTask>,
+}
+
+// Power or boot-device control, through Rufio when the stack has it and ipmitool otherwise
+#[axum::debug_handler]
+async fn start_bmc_job(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<BmcJobRequest>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Operate).await {
+        return response;
+    }
+
+    let machine = match db::get_machine_by_id(&id).await {
+        Ok(Some(machine)) => machine,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine with ID {} not found", id)),
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+
+    match crate::bmc::start_job(&machine, payload.tasks, &policy::principal(&auth_session)).await {
+        Ok(job) => (StatusCode::ACCEPTED, Json(job)).into_response(),
+        Err(message) => json_error(StatusCode::BAD_REQUEST, "BMC Error", message),
+    }
+}
+
+#[axum::debug_handler]
+async fn list_bmc_jobs(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
+    }
+
+    match db::get_bmc_jobs(&id, 50).await {
+        Ok(jobs) => (StatusCode::OK, Json(jobs)).into_response(),
+        Err(e) => {
+            error!("Failed to list BMC jobs of machine {}: {}", id, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
+        }
+    }
+}
+
+// Open the machine's serial console (IPMI SOL) over a WebSocket, for the terminal on the machine page
+#[axum::debug_handler]
+async fn machine_console(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    // The console accepts keystrokes, so it needs the same permission as running scripts
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+
+    let credentials = match db::get_machine_by_id(&id).await {
+        Ok(Some(machine)) => machine.bmc_credentials,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(json!({ "error": "Machine not found" }))).into_response(),
+        Err(e) => {
+            error!("Failed to load machine {} for console: {}", id, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Database error: {}", e) }))).into_response();
+        }
+    };
+    let Some(credentials) = credentials else {
+        let error_response = ErrorResponse {
+            error: "No BMC".to_string(),
+            message: format!("Machine {} has no BMC credentials", id),
+        };
+        return (StatusCode::CONFLICT, Json(error_response)).into_response();
+    };
+    let host = match crate::console::console_host(&credentials) {
+        Ok(host) => host,
+        Err(message) => {
+            let error_response = ErrorResponse {
+                error: "Console Unavailable".to_string(),
+                message,
+            };
+            return (StatusCode::CONFLICT, Json(error_response)).into_response();
+        }
+    };
+
+    info!("{} opened the serial console of machine {}", policy::principal(&auth_session), id);
+    ws.on_upgrade(move |socket| crate::console::run_sol_session(socket, id, credentials, host))
+}
+
+// Proxy the VNC server of the machine's BMC KVM over a WebSocket for noVNC on the machine page
+#[axum::debug_handler]
+async fn machine_vnc(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    // Keyboard and mouse input reach the machine, so this needs the same permission as the serial console
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+
+    let machine = match db::get_machine_by_id(&id).await {
+        Ok(Some(machine)) => machine,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(json!({ "error": "Machine not found" }))).into_response(),
+        Err(e) => {
+            error!("Failed to load machine {} for VNC: {}", id, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Database error: {}", e) }))).into_response();
+        }
+    };
+    let target = db::get_vnc_target(&id).await.ok().flatten().unwrap_or_default();
+    let address = match target.resolve(&machine) {
+        Ok(address) => address,
+        Err(message) => {
+            let error_response = ErrorResponse {
+                error: "VNC Unavailable".to_string(),
+                message,
+            };
+            return (StatusCode::CONFLICT, Json(error_response)).into_response();
+        }
+    };
+
+    info!("{} opened a VNC session to machine {} ({})", policy::principal(&auth_session), id, address);
+    ws.on_upgrade(move |socket| crate::vnc::run_vnc_session(socket, id, address))
+}
+
+#[axum::debug_handler]
+async fn get_vnc_target(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
+    }
+
+    match db::get_vnc_target(&id).await {
+        Ok(target) => (StatusCode::OK, Json(target.unwrap_or_default())).into_response(),
+        Err(e) => {
+            error!("Failed to load VNC target for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to load VNC target: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn update_vnc_target(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Json(target): Json<VncTarget>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+
+    match db::update_vnc_target(&id, &target).await {
+        Ok(true) => (StatusCode::OK, Json(target)).into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, Json(json!({ "error": "Machine not found" }))).into_response(),
+        Err(e) => {
+            error!("Failed to update VNC target for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to update VNC target: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Handler to get the hostname edit form
+#[axum::debug_handler]
+async fn get_hostname_form(
+    Path(id): Path<Uuid>,
+) -> impl IntoResponse {
+    match db::get_machine_by_id(&id).await {
+        Ok(Some(machine)) => {
+            let current_hostname = machine.hostname.unwrap_or_default();
+            // Use raw string literals to avoid escaping issues
+            let html = format!(
+                r###"
+                <div class="sm:flex sm:items-start">
+                    <div class="mt-3 text-center sm:mt-0 sm:text-left w-full">
+                        <h3 class="text-base font-semibold leading-6 text-gray-900">
+                            Update Machine Hostname
+                        </h3>
+                        <div class="mt-2">
+                            <form hx-post="/machines/{}/hostname" hx-target="#hostname-modal">
+                                <label for="hostname" class="block text-sm font-medium text-gray-700">Hostname</label>
+                                <input type="text" name="hostname" id="hostname" value="{}" class="mt-1 block w-full rounded-md border-gray-300 shadow-sm focus:border-indigo-500 focus:ring-indigo-500 sm:text-sm" placeholder="Enter hostname">
+                                <div class="mt-5 sm:mt-4 sm:flex sm:flex-row-reverse">
+                                    <button type="submit" class="inline-flex w-full justify-center rounded-md bg-indigo-600 px-3 py-2 text-sm font-semibold text-white shadow-sm hover:bg-indigo-500 sm:ml-3 sm:w-auto">
+                                        Update
+                                    </button>
+                                    <button type="button" class="mt-3 inline-flex w-full justify-center rounded-md bg-white px-3 py-2 text-sm font-semibold text-gray-900 shadow-sm ring-1 ring-inset ring-gray-300 hover:bg-gray-50 sm:mt-0 sm:w-auto" onclick="document.getElementById('hostname-modal').classList.add('hidden')">
+                                        Cancel
+                                    </button>
+                                </div>
+                            </form>
+                        </div>
+                    </div>
+                </div>
+                "###,
+                id, current_hostname
+            );
+            
+            (StatusCode::OK, [(axum::http::header::CONTENT_TYPE, "text/html")], html)
+        },
+        Ok(None) => {
+            let error_html = format!(
+                r###"<div class="p-4 text-red-500">Machine with ID {} not found</div>"###,
+                id
+            );
+            (StatusCode::NOT_FOUND, [(axum::http::header::CONTENT_TYPE, "text/html")], error_html)
+        },
+        Err(e) => {
+            let error_html = format!(
+                r###"<div class="p-4 text-red-500">Error: {}</div>"###,
+                e
+            );
+            (StatusCode::INTERNAL_SERVER_ERROR, [(axum::http::header::CONTENT_TYPE, "text/html")], error_html)
+        }
+    }
+}
+
+// iPXE script for a machine held at the approval gate: `exit 1` hands the boot on to the next device
+fn declined_boot_script(status: &MachineStatus) -> String {
+    format!(
+        "#!ipxe\necho This machine is {} in Dragonfly and will not be provisioned.\necho Booting from local disk...\nsleep 5\nexit 1",
+        status.to_string().to_lowercase()
+    )
+}
+
+// Handler for initial iPXE script generation (DHCP points here)
+// Determines whether to chain to HookOS or the Dragonfly Agent
+pub async fn ipxe_script(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(mac): Path<String>,
+    Query(hints): Query<crate::boot_arch::BootHints>,
+) -> Response {
+    if !mac.contains(':') || mac.split(':').count() != 6 {
+        warn!("Received invalid MAC format in iPXE request: {}", mac);
+        return (StatusCode::BAD_REQUEST, "Invalid MAC Address Format").into_response();
+    }
+
+    // Keep machines outside the configured allow/deny lists away from Dragonfly entirely
+    let client_ip = crate::access::client_ip(addr.ip(), &headers);
+    match db::get_app_settings().await {
+        Ok(settings) => {
+            if let Some(reason) = settings.boot_filter.check(&mac, Some(client_ip)) {
+                warn!("Refusing iPXE request from MAC {} ({}): {}", mac, client_ip, reason);
+                let script = "#!ipxe\necho This machine is not managed by Dragonfly.\necho Booting from local disk...\nsleep 5\nexit 1";
+                return (StatusCode::OK, [(axum::http::header::CONTENT_TYPE, "text/plain")], script).into_response();
+            }
+        },
+        Err(e) => {
+            // Fail closed: a filter we can't read might be the one protecting this machine
+            error!("Failed to load boot filter for MAC {}: {}", mac, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response();
+        }
+    }
+
+    info!("Generating initial iPXE script for MAC: {}", mac);
+
+    // Read required base URL from environment variable
+    let base_url = match env::var("DRAGONFLY_BASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            error!("CRITICAL: DRAGONFLY_BASE_URL environment variable is not set. iPXE booting requires this configuration.");
+            let error_response = ErrorResponse {
+                error: "Configuration Error".to_string(),
+                message: "Server is missing required DRAGONFLY_BASE_URL configuration.".to_string(),
+            };
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response();
+        }
+    };
+
+    match crate::machine_cache::get_machine_by_mac(&mac).await {
+        Ok(Some(machine)) if machine.status.is_approval_gated() => {
+            // Not approved: politely decline and fall back to the next boot device (local disk)
+            info!("MAC {} is {}, sending boot-to-local-disk script", mac, machine.status);
+            (StatusCode::OK, [(axum::http::header::CONTENT_TYPE, "text/plain")], declined_boot_script(&machine.status)).into_response()
+        },
+        Ok(Some(machine)) => {
+            // A hand-written script attached to the machine takes precedence over the generated one
+            match db::get_ipxe_override(&machine.id).await {
+                Ok(Some(script)) => {
+                    info!("Known MAC {}, serving custom iPXE script", mac);
+                    return (StatusCode::OK, [(axum::http::header::CONTENT_TYPE, "text/plain")], script).into_response();
+                },
+                Ok(None) => {},
+                Err(e) => warn!("Failed to load iPXE override for MAC {}, using generated script: {}", mac, e),
+            }
+
+            // Prefer what the client told us over what we recorded earlier, and keep Tinkerbell in sync
+            let stored_target = db::get_boot_target(&machine.id).await.ok().flatten();
+            let target = match hints.detect() {
+                Some(detected) if stored_target != Some(detected) => {
+                    info!("Detected boot target {:?} for MAC {}", detected, mac);
+                    if let Err(e) = db::update_boot_target(&machine.id, &detected).await {
+                        warn!("Failed to record boot target for MAC {}: {}", mac, e);
+                    } else if let Err(e) = crate::tinkerbell::register_machine(&machine).await {
+                        warn!("Failed to update Tinkerbell hardware for MAC {}: {}", mac, e);
+                    }
+                    Some(detected)
+                },
+                detected => detected.or(stored_target),
+            };
+
+            // Known machine: by default chains to Dragonfly's OS installation hook script (hookos.ipxe)
+            info!("Known MAC {}, rendering machine iPXE template", mac);
+            render_boot_script_response(&base_url, &mac, Some(&machine), target.as_ref()).await
+        },
+        Ok(None) => {
+            // Unknown machine: by default chains to the Dragonfly agent script
+            info!("Unknown MAC {}, rendering discovery iPXE template", mac);
+            render_boot_script_response(&base_url, &mac, None, hints.detect().as_ref()).await
+        },
+        Err(e) => {
+            error!("Database error while looking up MAC {}: {}", mac, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+async fn render_boot_script_response(base_url: &str, mac: &str, machine: Option<&Machine>, target: Option<&crate::boot_arch::BootTarget>) -> Response {
+    match crate::ipxe_templates::render_boot_script(base_url, mac, machine, target).await {
+        Ok(script) => (StatusCode::OK, [(axum::http::header::CONTENT_TYPE, "text/plain")], script).into_response(),
+        Err(e) => {
+            error!("Failed to render iPXE script for MAC {}: {}", mac, e);
+            let error_response = ErrorResponse {
+                error: "Template Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IpxeTemplateUpdate {
+    pub content: String,
+}
+
+#[axum::debug_handler]
+async fn list_ipxe_templates(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let mut names: Vec<String> = crate::ipxe_templates::TEMPLATE_NAMES.iter().map(|name| name.to_string()).collect();
+    match db::get_scoped_ipxe_template_names().await {
+        Ok(scoped) => names.extend(scoped),
+        Err(e) => warn!("Failed to list project iPXE templates: {}", e),
+    }
+
+    let mut templates = Vec::new();
+    for name in &names {
+        match crate::ipxe_templates::get_template(name).await {
+            Ok(template) => templates.push(template),
+            Err(e) => {
+                error!("Failed to load iPXE template '{}': {}", name, e);
+                let error_response = ErrorResponse {
+                    error: "Database Error".to_string(),
+                    message: format!("Failed to load iPXE template '{}': {}", name, e),
+                };
+                return (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response();
+            }
+        }
+    }
+    (StatusCode::OK, Json(templates)).into_response()
+}
+
+#[axum::debug_handler]
+async fn update_ipxe_template(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+    Json(payload): Json<IpxeTemplateUpdate>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    if let Err(message) = crate::ipxe_templates::validate_name(&name) {
+        let error_response = ErrorResponse {
+            error: "Not Found".to_string(),
+            message,
+        };
+        return (StatusCode::NOT_FOUND, Json(error_response)).into_response();
+    }
+
+    let content = payload.content.replace("\r\n", "\n");
+    if let Err(e) = crate::ipxe_templates::validate(&content) {
+        let error_response = ErrorResponse {
+            error: "Invalid Template".to_string(),
+            message: e.to_string(),
+        };
+        return (StatusCode::BAD_REQUEST, Json(error_response)).into_response();
+    }
+
+    match db::save_ipxe_template(&name, &content).await {
+        Ok(()) => (StatusCode::OK, Json(crate::ipxe_templates::IpxeTemplate { name, content, custom: true })).into_response(),
+        Err(e) => {
+            error!("Failed to save iPXE template '{}': {}", name, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to save iPXE template: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WorkflowTemplateCreate {
+    pub name: String,
+    pub content: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WorkflowTemplateUpdate {
+    pub content: String,
+}
+
+fn workflow_template_error(e: crate::workflow_templates::TemplateError) -> Response {
+    use crate::workflow_templates::TemplateError;
+    let status = match &e {
+        TemplateError::Invalid(_) => StatusCode::BAD_REQUEST,
+        TemplateError::NotFound(_) => StatusCode::NOT_FOUND,
+        TemplateError::Tinkerbell(_) => {
+            warn!("Workflow template operation failed in Tinkerbell: {}", e);
+            StatusCode::BAD_GATEWAY
+        },
+        TemplateError::Database(_) => {
+            error!("Workflow template operation failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    json_error(status, status.canonical_reason().unwrap_or("Error"), e.to_string())
+}
+
+// Tinkerbell workflow templates, shipped and stored, with the version in use
+#[axum::debug_handler]
+async fn list_workflow_templates(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::workflow_templates::list().await {
+        Ok(templates) => (StatusCode::OK, Json(templates)).into_response(),
+        Err(e) => workflow_template_error(e),
+    }
+}
+
+#[axum::debug_handler]
+async fn get_workflow_template(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::workflow_templates::get(&name).await {
+        Ok(Some(template)) => (StatusCode::OK, Json(template)).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No workflow template named '{}'", name)),
+        Err(e) => workflow_template_error(e),
+    }
+}
+
+#[axum::debug_handler]
+async fn create_workflow_template(
+    auth_session: AuthSession,
+    Json(payload): Json<WorkflowTemplateCreate>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::workflow_templates::get(&payload.name).await {
+        Ok(Some(_)) => return json_error(StatusCode::CONFLICT, "Conflict", format!("A workflow template named '{}' already exists", payload.name)),
+        Ok(None) => {},
+        Err(e) => return workflow_template_error(e),
+    }
+
+    let content = payload.content.replace("\r\n", "\n");
+    match crate::workflow_templates::save(&payload.name, &content, &crate::policy::principal(&auth_session)).await {
+        Ok(template) => (StatusCode::CREATED, Json(template)).into_response(),
+        Err(e) => workflow_template_error(e),
+    }
+}
+
+// Save a new version of a template, applying it to Tinkerbell
+#[axum::debug_handler]
+async fn update_workflow_template(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+    Json(payload): Json<WorkflowTemplateUpdate>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::workflow_templates::get(&name).await {
+        Ok(Some(_)) => {},
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "Not Found", format!("No workflow template named '{}'", name)),
+        Err(e) => return workflow_template_error(e),
+    }
+
+    let content = payload.content.replace("\r\n", "\n");
+    match crate::workflow_templates::save(&name, &content, &crate::policy::principal(&auth_session)).await {
+        Ok(template) => (StatusCode::OK, Json(template)).into_response(),
+        Err(e) => workflow_template_error(e),
+    }
+}
+
+// Delete a stored template. Shipped templates go back to their built-in version.
+#[axum::debug_handler]
+async fn delete_workflow_template(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::workflow_templates::delete(&name, &crate::policy::principal(&auth_session)).await {
+        Ok(Some(template)) => (StatusCode::OK, Json(template)).into_response(),
+        Ok(None) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => workflow_template_error(e),
+    }
+}
+
+#[axum::debug_handler]
+async fn list_workflow_template_versions(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    if let Err(message) = crate::workflow_templates::validate_name(&name) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+    match db::get_workflow_template_versions(&name).await {
+        Ok(versions) => (StatusCode::OK, Json(versions)).into_response(),
+        Err(e) => workflow_template_error(e.into()),
+    }
+}
+
+#[axum::debug_handler]
+async fn get_workflow_template_version(
+    auth_session: AuthSession,
+    Path((name, version)): Path<(String, i64)>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    if let Err(message) = crate::workflow_templates::validate_name(&name) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+    match db::get_workflow_template_version(&name, version).await {
+        Ok(Some(version)) => (StatusCode::OK, Json(version)).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("Workflow template '{}' has no version {}", name, version)),
+        Err(e) => workflow_template_error(e.into()),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TemplateRenderQuery {
+    pub machine: Uuid,
+    /// A stored version to render instead of the one in use
+    pub version: Option<i64>,
+}
+
+// Dry run: what a template would produce for a machine, without applying anything
+#[axum::debug_handler]
+async fn render_workflow_template(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+    Query(query): Query<TemplateRenderQuery>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let machine = match db::get_machine_by_id(&query.machine).await {
+        Ok(Some(machine)) => machine,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine {} not found", query.machine)),
+        Err(e) => return workflow_template_error(e.into()),
+    };
+
+    match crate::workflow_templates::preview(&name, query.version, &machine).await {
+        Ok(preview) => (StatusCode::OK, Json(preview)).into_response(),
+        Err(e) => workflow_template_error(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PipelineUpdate {
+    pub stages: Vec<String>,
+}
+
+// Check a pipeline's shape, and that every stage is a workflow template that exists
+async fn check_pipeline(pipeline: &crate::pipelines::Pipeline) -> Result<(), Response> {
+    if let Err(message) = crate::pipelines::validate(pipeline) {
+        return Err(json_error(StatusCode::BAD_REQUEST, "Bad Request", message));
+    }
+    for stage in &pipeline.stages {
+        match crate::workflow_templates::get(stage).await {
+            Ok(Some(_)) => {},
+            Ok(None) => return Err(json_error(StatusCode::BAD_REQUEST, "Bad Request", format!("No workflow template named '{}'", stage))),
+            Err(e) => return Err(workflow_template_error(e)),
+        }
+    }
+    Ok(())
+}
+
+// Pipelines, by the profile name machines are tagged with
+#[axum::debug_handler]
+async fn list_pipelines(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_pipelines().await {
+        Ok(pipelines) => (StatusCode::OK, Json(pipelines)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn get_pipeline(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_pipeline(&name).await {
+        Ok(Some(pipeline)) => (StatusCode::OK, Json(pipeline)).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No pipeline named '{}'", name)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn create_pipeline(
+    auth_session: AuthSession,
+    Json(pipeline): Json<crate::pipelines::Pipeline>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_pipeline(&pipeline.name).await {
+        Ok(Some(_)) => return json_error(StatusCode::CONFLICT, "Conflict", format!("A pipeline named '{}' already exists", pipeline.name)),
+        Ok(None) => {},
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+    if let Err(response) = check_pipeline(&pipeline).await {
+        return response;
+    }
+
+    match db::save_pipeline(&pipeline).await {
+        Ok(()) => {
+            info!("{} created pipeline '{}': {}", crate::policy::principal(&auth_session), pipeline.name, pipeline.stages.join(" -> "));
+            (StatusCode::CREATED, Json(pipeline)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Replace a pipeline's stages. Runs already under way keep the stages they started with.
+#[axum::debug_handler]
+async fn update_pipeline(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+    Json(payload): Json<PipelineUpdate>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let pipeline = crate::pipelines::Pipeline { name, stages: payload.stages };
+    if let Err(response) = check_pipeline(&pipeline).await {
+        return response;
+    }
+
+    match db::save_pipeline(&pipeline).await {
+        Ok(()) => {
+            info!("{} updated pipeline '{}': {}", crate::policy::principal(&auth_session), pipeline.name, pipeline.stages.join(" -> "));
+            (StatusCode::OK, Json(pipeline)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn delete_pipeline(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::delete_pipeline(&name).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No pipeline named '{}'", name)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Where a machine is in its pipeline, and why it stopped if it failed
+#[axum::debug_handler]
+async fn get_machine_pipeline_run(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
+    }
+
+    match db::get_pipeline_run(&id).await {
+        Ok(Some(run)) => (StatusCode::OK, Json(run)).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine {} has no pipeline run", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Start a failed pipeline again from the stage that failed, skipping the ones that succeeded
+#[axum::debug_handler]
+async fn resume_machine_pipeline(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+    if let Some(freeze) = crate::freeze::current() {
+        return json_error(StatusCode::CONFLICT, "Conflict", crate::freeze::refusal(&freeze));
+    }
+
+    let machine = match db::get_machine_by_id(&id).await {
+        Ok(Some(machine)) => machine,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine {} not found", id)),
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    let run = match crate::pipelines::resume(&id).await {
+        Ok(Ok(run)) => run,
+        Ok(Err(message)) => return json_error(StatusCode::CONFLICT, "Conflict", message),
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+
+    let cause = StatusCause::Admin(crate::policy::principal(&auth_session));
+    match db::update_status(&id, MachineStatus::InstallingOS, &cause).await {
+        Ok(_) => {},
+        Err(e) if e.is::<InvalidTransition>() || e.is::<db::StatusConflict>() => return json_error(StatusCode::CONFLICT, "Conflict", e.to_string()),
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+    if let Err(e) = crate::tinkerbell::start_pipeline_stage(&machine, &run).await {
+        error!("Failed to resume pipeline for machine {}: {}", id, e);
+        return json_error(StatusCode::BAD_GATEWAY, "Bad Gateway", e.to_string());
+    }
+
+    let _ = state.event_manager.send(format!("machine_updated:{}", id));
+    (StatusCode::OK, Json(run)).into_response()
+}
+
+// The firmware repository: BIOS, BMC and NIC releases by vendor and model
+#[axum::debug_handler]
+async fn list_firmware_images(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_firmware_images().await {
+        Ok(images) => (StatusCode::OK, Json(images)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Add a firmware release. Give a URL to download it from, or upload the binary afterwards.
+#[axum::debug_handler]
+async fn add_firmware_image(
+    auth_session: AuthSession,
+    Json(image): Json<crate::firmware::NewFirmwareImage>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    if let Err(message) = crate::firmware::validate(&image) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+
+    match db::add_firmware_image(&image, &policy::principal(&auth_session)).await {
+        Ok(image) => {
+            info!("{} added {} {} {} firmware {}", policy::principal(&auth_session), image.vendor, image.model, image.component.as_str(), image.version);
+            (StatusCode::CREATED, Json(image)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn upload_firmware_binary(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+    body: Bytes,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_firmware_image(id).await {
+        Ok(Some(image)) if image.url.is_some() => {
+            return json_error(StatusCode::CONFLICT, "Conflict", format!("Firmware image {} is downloaded from {}", id, image.url.unwrap_or_default()));
+        },
+        Ok(Some(_)) => {},
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "Not Found", format!("No firmware image {}", id)),
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+    if body.is_empty() {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", "The firmware binary is empty".to_string());
+    }
+
+    if let Err(e) = crate::firmware::store_binary(id, &body).await {
+        error!("Failed to store firmware binary {}: {}", id, e);
+        return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Storage Error", e.to_string());
+    }
+    match db::get_firmware_image(id).await {
+        Ok(Some(image)) => (StatusCode::OK, Json(image)).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No firmware image {}", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn delete_firmware_image(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::delete_firmware_image(id).await {
+        Ok(true) => {
+            if let Err(e) = tokio::fs::remove_file(crate::firmware::binary_path(id).await).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Failed to remove firmware binary {}: {}", id, e);
+                }
+            }
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No firmware image {}", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// A machine's firmware versions as the flashing workflow last reported them
+#[axum::debug_handler]
+async fn get_machine_firmware(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
+    }
+
+    match db::get_machine_firmware(&id).await {
+        Ok(firmware) => (StatusCode::OK, Json(firmware)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StorageProfileAssignment {
+    /// The profile to attach, or null to detach
+    pub profile: Option<String>,
+}
+
+// Check the profile being attached exists, so a typo doesn't silently mean "no profile"
+async fn check_storage_profile_exists(profile: Option<&str>) -> Result<(), Response> {
+    let Some(name) = profile else { return Ok(()) };
+    match db::get_storage_profile(name).await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(json_error(StatusCode::BAD_REQUEST, "Bad Request", format!("No storage profile named '{}'", name))),
+        Err(e) => Err(json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())),
+    }
+}
+
+// RAID and disk layouts, attached to machines or workflow templates
+#[axum::debug_handler]
+async fn list_storage_profiles(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_storage_profiles().await {
+        Ok(profiles) => (StatusCode::OK, Json(profiles)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn get_storage_profile(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_storage_profile(&name).await {
+        Ok(Some(profile)) => (StatusCode::OK, Json(profile)).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No storage profile named '{}'", name)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn create_storage_profile(
+    auth_session: AuthSession,
+    Json(profile): Json<crate::storage_profiles::StorageProfile>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    if let Err(message) = crate::storage_profiles::validate(&profile) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+    match db::get_storage_profile(&profile.name).await {
+        Ok(Some(_)) => return json_error(StatusCode::CONFLICT, "Conflict", format!("A storage profile named '{}' already exists", profile.name)),
+        Ok(None) => {},
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+
+    match db::save_storage_profile(&profile).await {
+        Ok(()) => {
+            info!("{} created storage profile '{}'", policy::principal(&auth_session), profile.name);
+            (StatusCode::CREATED, Json(profile)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn update_storage_profile(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+    Json(mut profile): Json<crate::storage_profiles::StorageProfile>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    profile.name = name;
+
+    if let Err(message) = crate::storage_profiles::validate(&profile) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+
+    match db::save_storage_profile(&profile).await {
+        Ok(()) => {
+            info!("{} updated storage profile '{}'", policy::principal(&auth_session), profile.name);
+            (StatusCode::OK, Json(profile)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn delete_storage_profile(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::delete_storage_profile(&name).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No storage profile named '{}'", name)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// The storage profile attached to the machine itself, ahead of any on its template
+#[axum::debug_handler]
+async fn get_machine_storage_profile(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
+    }
+
+    match db::get_machine_storage_profile(&id).await {
+        Ok(Some(profile)) => (StatusCode::OK, Json(profile)).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine {} has no storage profile of its own", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn update_machine_storage_profile(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<StorageProfileAssignment>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+    if let Err(response) = check_storage_profile_exists(payload.profile.as_deref()).await {
+        return response;
+    }
+
+    match db::set_machine_storage_profile(&id, payload.profile.as_deref()).await {
+        Ok(()) => {
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            (StatusCode::OK, Json(payload.profile)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Give every machine installed with a template the same disk layout
+#[axum::debug_handler]
+async fn update_template_storage_profile(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+    Json(payload): Json<StorageProfileAssignment>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(response) = check_storage_profile_exists(payload.profile.as_deref()).await {
+        return response;
+    }
+
+    match db::set_template_storage_profile(&name, payload.profile.as_deref()).await {
+        Ok(()) => (StatusCode::OK, Json(payload.profile)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn get_template_health_checks(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_template_health_checks(&name).await {
+        Ok(checks) => (StatusCode::OK, Json(checks.unwrap_or_default())).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Set the checks a machine installed with this template must pass before it is Ready. An empty
+// list removes them.
+#[axum::debug_handler]
+async fn update_template_health_checks(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+    Json(checks): Json<crate::health_checks::TemplateChecks>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(message) = crate::health_checks::validate(&checks) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+
+    let saved = if checks.checks.is_empty() { None } else { Some(&checks) };
+    match db::set_template_health_checks(&name, saved).await {
+        Ok(()) => {
+            info!("{} set {} health checks for template {}", policy::principal(&auth_session), checks.checks.len(), name);
+            (StatusCode::OK, Json(checks)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MachineProfileAssignment {
+    /// The profile to put the machine in, or null to take it out of its profile
+    pub profile: Option<String>,
+}
+
+// Validate a machine profile and check the OS template it assigns exists
+async fn check_machine_profile(profile: &crate::machine_profiles::MachineProfile) -> Result<(), Response> {
+    if let Err(message) = crate::machine_profiles::validate(profile) {
+        return Err(json_error(StatusCode::BAD_REQUEST, "Bad Request", message));
+    }
+    if let Some(template) = &profile.os_template {
+        match crate::workflow_templates::get(template).await {
+            Ok(Some(_)) => {},
+            Ok(None) => return Err(json_error(StatusCode::BAD_REQUEST, "Bad Request", format!("No workflow template named '{}'", template))),
+            Err(e) => return Err(workflow_template_error(e)),
+        }
+    }
+    Ok(())
+}
+
+// Machine profiles, in the order they're matched
+#[axum::debug_handler]
+async fn list_machine_profiles(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_machine_profiles().await {
+        Ok(profiles) => (StatusCode::OK, Json(profiles)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn get_machine_profile(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_machine_profile(&name).await {
+        Ok(Some(profile)) => (StatusCode::OK, Json(profile)).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No machine profile named '{}'", name)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn create_machine_profile(
+    auth_session: AuthSession,
+    Json(profile): Json<crate::machine_profiles::MachineProfile>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    if let Err(response) = check_machine_profile(&profile).await {
+        return response;
+    }
+    match db::get_machine_profile(&profile.name).await {
+        Ok(Some(_)) => return json_error(StatusCode::CONFLICT, "Conflict", format!("A machine profile named '{}' already exists", profile.name)),
+        Ok(None) => {},
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+
+    match db::save_machine_profile(&profile).await {
+        Ok(()) => {
+            info!("{} created machine profile '{}'", policy::principal(&auth_session), profile.name);
+            (StatusCode::CREATED, Json(profile)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Changing a profile changes what machines adopted into it get from now on, e.g. their next install
+#[axum::debug_handler]
+async fn update_machine_profile(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+    Json(mut profile): Json<crate::machine_profiles::MachineProfile>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    profile.name = name;
+
+    if let Err(response) = check_machine_profile(&profile).await {
+        return response;
+    }
+
+    match db::save_machine_profile(&profile).await {
+        Ok(()) => {
+            info!("{} updated machine profile '{}'", policy::principal(&auth_session), profile.name);
+            (StatusCode::OK, Json(profile)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn delete_machine_profile(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::delete_machine_profile(&name).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No machine profile named '{}'", name)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Match machines awaiting an OS that aren't in a profile yet, e.g. ones imported or discovered
+// before the profile existed, and adopt them as if they had just registered
+#[axum::debug_handler]
+async fn apply_machine_profiles(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Query(query): Query<DryRunQuery>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let loaded = tokio::try_join!(db::get_all_machines(), db::get_machine_profiles(), db::get_machine_profile_assignments());
+    let (machines, profiles, assignments) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+
+    let mut matched = Vec::new();
+    for machine in machines.iter().filter(|m| m.status == MachineStatus::AwaitingAssignment && !assignments.contains_key(&m.id)) {
+        let facts = match crate::machine_profiles::facts_for(machine).await {
+            Ok(facts) => facts,
+            Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+        };
+        let Some(profile) = crate::machine_profiles::best_match(&profiles, &facts) else {
+            continue;
+        };
+        matched.push(json!({ "machine_id": machine.id, "profile": profile.name }));
+        if query.dry_run {
+            continue;
+        }
+
+        if let Err(e) = db::set_machine_profile_assignment(&machine.id, Some(&profile.name)).await {
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string());
+        }
+        info!("{} adopted machine {} into machine profile '{}'", policy::principal(&auth_session), machine.id, profile.name);
+        // Hostnames machines already have, e.g. from an import, are kept
+        if machine.hostname.is_none() {
+            if let Err(e) = crate::hostname_policy::apply_to_machine(&machine.id).await {
+                warn!("Failed to apply hostname policy to machine {}: {}", machine.id, e);
+            }
+        }
+        if profile.os_template.is_some() {
+            apply_default_os(&machine.id).await;
+        }
+        let _ = state.event_manager.send(format!("machine_updated:{}", machine.id));
+    }
+
+    (StatusCode::OK, Json(json!({ "dry_run": query.dry_run, "matched": matched }))).into_response()
+}
+
+// The machine profile a machine was adopted into
+#[axum::debug_handler]
+async fn get_machine_machine_profile(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::View).await {
+        return response;
+    }
+
+    match crate::machine_profiles::for_machine(&id).await {
+        Ok(Some(profile)) => (StatusCode::OK, Json(profile)).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine {} is not in a machine profile", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Put a machine in a profile by hand. Only its network config and pipeline follow from this;
+// the hostname and OS are left for the admin to set.
+#[axum::debug_handler]
+async fn update_machine_machine_profile(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<MachineProfileAssignment>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+    if let Some(name) = &payload.profile {
+        match db::get_machine_profile(name).await {
+            Ok(Some(_)) => {},
+            Ok(None) => return json_error(StatusCode::BAD_REQUEST, "Bad Request", format!("No machine profile named '{}'", name)),
+            Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+        }
+    }
+
+    match db::set_machine_profile_assignment(&id, payload.profile.as_deref()).await {
+        Ok(()) => {
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            (StatusCode::OK, Json(payload.profile)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// The signed-in user's saved views of the machine list
+#[axum::debug_handler]
+async fn list_saved_views(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_saved_views(&policy::principal(&auth_session)).await {
+        Ok(views) => (StatusCode::OK, Json(views)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn get_saved_view(auth_session: AuthSession, Path(id): Path<i64>) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_saved_view(&policy::principal(&auth_session), id).await {
+        Ok(Some(view)) => (StatusCode::OK, Json(view)).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No saved view {}", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Check and save a view for the signed-in user; names are unique per user
+async fn store_saved_view(auth_session: &AuthSession, mut view: crate::views::SavedView) -> Response {
+    view.owner = policy::principal(auth_session);
+    let (fields, existing) = match tokio::try_join!(db::get_custom_fields(), db::get_saved_views(&view.owner)) {
+        Ok(loaded) => loaded,
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    if let Err(message) = crate::views::validate(&view, &fields) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+    if existing.iter().any(|other| other.id != view.id && other.name == view.name.trim()) {
+        return json_error(StatusCode::CONFLICT, "Conflict", format!("You already have a view named '{}'", view.name.trim()));
+    }
+
+    let created = view.id == 0;
+    match db::save_view(&view).await {
+        Ok(Some(view)) => {
+            info!("{} saved view '{}'", view.owner, view.name);
+            let status = if created { StatusCode::CREATED } else { StatusCode::OK };
+            (status, Json(view)).into_response()
+        },
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No saved view {}", view.id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn add_saved_view(auth_session: AuthSession, Json(mut view): Json<crate::views::SavedView>) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    view.id = 0;
+    store_saved_view(&auth_session, view).await
+}
+
+#[axum::debug_handler]
+async fn update_saved_view(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+    Json(mut view): Json<crate::views::SavedView>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    view.id = id;
+    store_saved_view(&auth_session, view).await
+}
+
+#[axum::debug_handler]
+async fn delete_saved_view(auth_session: AuthSession, Path(id): Path<i64>) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::delete_saved_view(&policy::principal(&auth_session), id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No saved view {}", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// The signed-in user's UI preferences
+#[axum::debug_handler]
+async fn get_preferences(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let username = policy::principal(&auth_session);
+    match tokio::try_join!(crate::preferences::get(&username), db::get_saved_views(&username)) {
+        Ok((mut preferences, views)) => {
+            preferences.default_view = views.iter().find(|view| view.is_default).map(|view| view.id);
+            (StatusCode::OK, Json(preferences)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn update_preferences(
+    auth_session: AuthSession,
+    Json(preferences): Json<crate::preferences::UiPreferences>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(message) = crate::preferences::validate(&preferences) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+
+    let username = policy::principal(&auth_session);
+    if let Some(id) = preferences.default_view {
+        match db::get_saved_view(&username, id).await {
+            Ok(Some(_)) => {},
+            Ok(None) => return json_error(StatusCode::BAD_REQUEST, "Bad Request", format!("No saved view {}", id)),
+            Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+        }
+    }
+    match crate::preferences::save(&username, preferences.clone()).await {
+        Ok(()) => (StatusCode::OK, Json(preferences)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Weekly windows OS installs may start in
+#[axum::debug_handler]
+async fn list_maintenance_windows(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_maintenance_windows().await {
+        Ok(windows) => (StatusCode::OK, Json(windows)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn add_maintenance_window(
+    auth_session: AuthSession,
+    Json(window): Json<crate::maintenance::MaintenanceWindow>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(message) = crate::maintenance::validate(&window) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+
+    match db::add_maintenance_window(&window).await {
+        Ok(window) => {
+            info!("{} added maintenance window '{}'", policy::principal(&auth_session), window.name);
+            (StatusCode::CREATED, Json(window)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn update_maintenance_window(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+    Json(mut window): Json<crate::maintenance::MaintenanceWindow>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    window.id = id;
+    if let Err(message) = crate::maintenance::validate(&window) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+
+    match db::update_maintenance_window(&window).await {
+        Ok(true) => {
+            info!("{} updated maintenance window '{}'", policy::principal(&auth_session), window.name);
+            (StatusCode::OK, Json(window)).into_response()
+        },
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No maintenance window {}", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Deleting the last window lets held installs wait for a manual release; new ones start straight away
+#[axum::debug_handler]
+async fn delete_maintenance_window(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::delete_maintenance_window(id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No maintenance window {}", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn list_provisioning_holds(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::maintenance::holds().await {
+        Ok(holds) => (StatusCode::OK, Json(holds)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Start a held install now rather than in the next window
+#[axum::debug_handler]
+async fn release_provisioning_hold(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Operate).await {
+        return response;
+    }
+
+    match crate::maintenance::release(&id, StatusCause::Admin(policy::principal(&auth_session))).await {
+        Ok(true) => {
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine {} has no held install", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Drop a held install; the machine stays waiting for an OS
+#[axum::debug_handler]
+async fn cancel_provisioning_hold(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Operate).await {
+        return response;
+    }
+
+    match db::take_provisioning_hold(&id).await {
+        Ok(Some(hold)) => {
+            info!("{} cancelled the held install of {} on machine {}", policy::principal(&auth_session), hold.os_choice, id);
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine {} has no held install", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn get_install_limits(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_install_limits().await {
+        Ok(limits) => (StatusCode::OK, Json(limits)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Raising or removing a limit lets queued installs start on the next dispatch
+#[axum::debug_handler]
+async fn update_install_limits(
+    auth_session: AuthSession,
+    Json(limits): Json<crate::install_queue::InstallLimits>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(message) = crate::install_queue::validate(&limits) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+
+    match db::save_install_limits(&limits).await {
+        Ok(()) => {
+            info!("{} set install limits to {:?}", policy::principal(&auth_session), limits);
+            (StatusCode::OK, Json(limits)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Running and queued installs, and what each queued one is waiting on
+#[axum::debug_handler]
+async fn get_install_queue(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::install_queue::status().await {
+        Ok(status) => (StatusCode::OK, Json(status)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct QueuedInstallUpdate {
+    priority: i64,
+}
+
+// Move a queued install up or down the queue
+#[axum::debug_handler]
+async fn update_queued_install(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Json(update): Json<QueuedInstallUpdate>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::set_queued_install_priority(&id, update.priority).await {
+        Ok(true) => {
+            info!("{} set the install queue priority of machine {} to {}", policy::principal(&auth_session), id, update.priority);
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine {} is not in the install queue", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Drop a queued install; the machine stays waiting for an OS
+#[axum::debug_handler]
+async fn cancel_queued_install(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Operate).await {
+        return response;
+    }
+
+    match db::take_queued_install(&id).await {
+        Ok(Some(queued)) => {
+            info!("{} cancelled the queued install of {} on machine {}", policy::principal(&auth_session), queued.os_choice, id);
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("Machine {} is not in the install queue", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn list_rollouts(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_rollouts().await {
+        Ok(rollouts) => (StatusCode::OK, Json(rollouts)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Plan a rollout over the matching machines and start its canaries. With ?dry_run=true, only
+// show the batches it would run.
+#[axum::debug_handler]
+async fn create_rollout(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Query(query): Query<DryRunQuery>,
+    Json(spec): Json<crate::rollouts::RolloutSpec>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(message) = crate::rollouts::validate(&spec) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+
+    // Held until the rollout is saved, so two rollouts can't take the same machines
+    let _rollouts = crate::rollouts::ROLLOUTS.lock().await;
+    let principal = policy::principal(&auth_session);
+    let rollout = match crate::rollouts::plan(spec, &principal).await {
+        Ok(rollout) if rollout.members.is_empty() => {
+            return json_error(StatusCode::BAD_REQUEST, "Bad Request", "No machines match the rollout's rules".to_string());
+        },
+        Ok(rollout) => rollout,
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    if query.dry_run {
+        return (StatusCode::OK, Json(rollout)).into_response();
+    }
+
+    let mut rollout = match db::add_rollout(&rollout).await {
+        Ok(rollout) => rollout,
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    info!("{} started rollout {} ({}) of {} to {} machines", principal, rollout.id, rollout.spec.name, rollout.spec.os_template, rollout.members.len());
+    step_rollout(&mut rollout, &state).await;
+    (StatusCode::CREATED, Json(rollout)).into_response()
+}
+
+// Start whatever a rollout can start now rather than on the next tick
+async fn step_rollout(rollout: &mut crate::rollouts::Rollout, state: &AppState) {
+    if crate::freeze::is_frozen() {
+        return;
+    }
+    match crate::rollouts::step(rollout, &state.event_manager).await {
+        Ok(true) => if let Err(e) = db::save_rollout(rollout).await {
+            error!("Failed to save rollout {}: {}", rollout.id, e);
+        },
+        Ok(false) => {},
+        Err(e) => error!("Failed to step rollout {}: {}", rollout.id, e),
+    }
+}
+
+#[axum::debug_handler]
+async fn get_rollout(auth_session: AuthSession, Path(id): Path<i64>) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_rollout(id).await {
+        Ok(Some(rollout)) => (StatusCode::OK, Json(rollout)).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No rollout {}", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Load a rollout, change its state and save it, starting anything it can start now
+async fn change_rollout(
+    state: &AppState,
+    id: i64,
+    change: impl FnOnce(&mut crate::rollouts::Rollout) -> Result<(), String>,
+) -> Response {
+    let _rollouts = crate::rollouts::ROLLOUTS.lock().await;
+    let mut rollout = match db::get_rollout(id).await {
+        Ok(Some(rollout)) => rollout,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "Not Found", format!("No rollout {}", id)),
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    if let Err(message) = change(&mut rollout) {
+        return json_error(StatusCode::CONFLICT, "Conflict", message);
+    }
+    rollout.updated_at = Utc::now();
+    if let Err(e) = db::save_rollout(&rollout).await {
+        return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string());
+    }
+    step_rollout(&mut rollout, state).await;
+    (StatusCode::OK, Json(rollout)).into_response()
+}
+
+// Stop starting installs; those already started carry on
+#[axum::debug_handler]
+async fn pause_rollout(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let principal = policy::principal(&auth_session);
+    info!("{} paused rollout {}", principal, id);
+    change_rollout(&state, id, |rollout| rollout.pause(format!("Paused by {}", principal))).await
+}
+
+// Carry on after a pause, moving past a batch that failed
+#[axum::debug_handler]
+async fn resume_rollout(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    info!("{} resumed rollout {}", policy::principal(&auth_session), id);
+    change_rollout(&state, id, |rollout| rollout.resume()).await
+}
+
+// Stop the rollout for good; machines not yet started keep their current OS
+#[axum::debug_handler]
+async fn cancel_rollout(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    info!("{} cancelled rollout {}", policy::principal(&auth_session), id);
+    change_rollout(&state, id, |rollout| rollout.cancel()).await
+}
+
+#[axum::debug_handler]
+async fn list_reimage_schedules(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_reimage_schedules().await {
+        Ok(schedules) => (StatusCode::OK, Json(schedules)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn create_reimage_schedule(
+    auth_session: AuthSession,
+    Json(spec): Json<crate::reimage_schedules::ScheduleSpec>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(message) = crate::reimage_schedules::validate(&spec) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+
+    let now = Utc::now();
+    let schedule = crate::reimage_schedules::ReimageSchedule {
+        id: 0,
+        next_run_at: crate::reimage_schedules::next_run(&spec, now),
+        spec,
+        created_by: policy::principal(&auth_session),
+        created_at: now,
+        last_run_at: None,
+    };
+    match db::add_reimage_schedule(&schedule).await {
+        Ok(schedule) => {
+            info!("{} added reimage schedule {} ({}, '{}')", schedule.created_by, schedule.id, schedule.spec.name, schedule.spec.cron);
+            (StatusCode::CREATED, Json(schedule)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn get_reimage_schedule(auth_session: AuthSession, Path(id): Path<i64>) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_reimage_schedule(id).await {
+        Ok(Some(schedule)) => (StatusCode::OK, Json(schedule)).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No reimage schedule {}", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Change a schedule, working out its next run again
+#[axum::debug_handler]
+async fn update_reimage_schedule(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+    Json(spec): Json<crate::reimage_schedules::ScheduleSpec>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(message) = crate::reimage_schedules::validate(&spec) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+
+    let mut schedule = match db::get_reimage_schedule(id).await {
+        Ok(Some(schedule)) => schedule,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "Not Found", format!("No reimage schedule {}", id)),
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    schedule.next_run_at = crate::reimage_schedules::next_run(&spec, Utc::now());
+    schedule.spec = spec;
+    match db::save_reimage_schedule(&schedule).await {
+        Ok(_) => {
+            info!("{} updated reimage schedule {} ({})", policy::principal(&auth_session), id, schedule.spec.name);
+            (StatusCode::OK, Json(schedule)).into_response()
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Delete a schedule and its run reports. Reimages it started carry on.
+#[axum::debug_handler]
+async fn delete_reimage_schedule(auth_session: AuthSession, Path(id): Path<i64>) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::delete_reimage_schedule(id).await {
+        Ok(true) => {
+            info!("{} deleted reimage schedule {}", policy::principal(&auth_session), id);
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No reimage schedule {}", id)),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// Run a schedule now, without moving its next scheduled run
+#[axum::debug_handler]
+async fn run_reimage_schedule(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let schedule = match db::get_reimage_schedule(id).await {
+        Ok(Some(schedule)) => schedule,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "Not Found", format!("No reimage schedule {}", id)),
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    let principal = policy::principal(&auth_session);
+    info!("{} ran reimage schedule {} ({})", principal, id, schedule.spec.name);
+    match crate::reimage_schedules::start_run(&schedule, &principal, &state.event_manager).await {
+        Ok(run) => (StatusCode::CREATED, Json(run)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// A schedule's last 50 run reports, newest first
+#[axum::debug_handler]
+async fn list_reimage_runs(auth_session: AuthSession, Path(id): Path<i64>) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_reimage_runs(id, 50).await {
+        Ok(runs) => (StatusCode::OK, Json(runs)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct TemplateScopeUpdate {
+    project: String,
+}
+
+#[axum::debug_handler]
+async fn list_template_scopes(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_template_scopes().await {
+        Ok(scopes) => (StatusCode::OK, Json(scopes)).into_response(),
+        Err(e) => {
+            error!("Failed to list template scopes: {}", e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to list template scopes: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Restrict an OS template to the machines of one project
+#[axum::debug_handler]
+async fn update_template_scope(
+    auth_session: AuthSession,
+    Path(template): Path<String>,
+    Json(payload): Json<TemplateScopeUpdate>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    if let Err(message) = crate::projects::validate_name(&payload.project) {
+        let error_response = ErrorResponse {
+            error: "Invalid Request".to_string(),
+            message,
+        };
+        return (StatusCode::BAD_REQUEST, Json(error_response)).into_response();
+    }
+
+    let scope = crate::projects::TemplateScope { template, project: payload.project };
+    match db::save_template_scope(&scope).await {
+        Ok(()) => (StatusCode::OK, Json(scope)).into_response(),
+        Err(e) => {
+            error!("Failed to save template scope for '{}': {}", scope.template, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to save template scope: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn delete_template_scope(
+    auth_session: AuthSession,
+    Path(template): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::delete_template_scope(&template).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("OS template '{}' is not restricted to a project", template),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to delete template scope for '{}': {}", template, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to delete template scope: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Reset a template to the built-in version
+#[axum::debug_handler]
+async fn reset_ipxe_template(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    if let Err(e) = db::delete_ipxe_template(&name).await {
+        error!("Failed to reset iPXE template '{}': {}", name, e);
+        let error_response = ErrorResponse {
+            error: "Database Error".to_string(),
+            message: format!("Failed to reset iPXE template: {}", e),
+        };
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response();
+    }
+
+    match crate::ipxe_templates::get_template(&name).await {
+        Ok(template) => (StatusCode::OK, Json(template)).into_response(),
+        Err(e) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        }
+    }
+}
+
+// GRUB configs for Secure Boot machines that booted Dragonfly's signed shim+GRUB chain.
+// Serves the bootstrap `grub.cfg` and per-machine `<mac>.cfg` (or GRUB's own `grub.cfg-01-<mac>` lookup).
+pub async fn grub_config(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(file): Path<String>,
+) -> Response {
+    use crate::secure_boot;
+
+    if !secure_boot::chain_enabled() {
+        return (StatusCode::NOT_FOUND, "Secure Boot chain is disabled").into_response();
+    }
+    let grub_response = |config: String| (StatusCode::OK, [(axum::http::header::CONTENT_TYPE, "text/plain")], config).into_response();
+
+    if file == "grub.cfg" {
+        return grub_response(secure_boot::bootstrap_config());
+    }
+
+    let mac = match file.strip_prefix("grub.cfg-01-").or_else(|| file.strip_suffix(".cfg")) {
+        Some(mac) => mac.replace('-', ":").to_lowercase(),
+        None => return (StatusCode::NOT_FOUND, "Unknown GRUB config").into_response(),
+    };
+    if mac.split(':').count() != 6 {
+        warn!("Received invalid MAC format in GRUB config request: {}", file);
+        return (StatusCode::BAD_REQUEST, "Invalid MAC Address Format").into_response();
+    }
+
+    // Same allow/deny lists as iPXE requests
+    let client_ip = crate::access::client_ip(addr.ip(), &headers);
+    match db::get_app_settings().await {
+        Ok(settings) => {
+            if let Some(reason) = settings.boot_filter.check(&mac, Some(client_ip)) {
+                warn!("Refusing GRUB config request from MAC {} ({}): {}", mac, client_ip, reason);
+                return grub_response(secure_boot::local_boot_config("This machine is not managed by Dragonfly."));
+            }
+        },
+        Err(e) => {
+            error!("Failed to load boot filter for MAC {}: {}", mac, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load boot filter").into_response();
+        }
+    }
+
+    let machine = match crate::machine_cache::get_machine_by_mac(&mac).await {
+        Ok(machine) => machine,
+        Err(e) => {
+            error!("Database error while looking up MAC {}: {}", mac, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+
+    if let Some(machine) = &machine {
+        if machine.status.is_approval_gated() {
+            info!("MAC {} is {}, sending GRUB boot-to-local-disk config", mac, machine.status);
+            return grub_response(secure_boot::local_boot_config(&format!(
+                "This machine is {} in Dragonfly and will not be provisioned.", machine.status.to_string().to_lowercase()
+            )));
+        }
+        if matches!(machine.status, MachineStatus::Ready | MachineStatus::ExistingOS) {
+            return grub_response(secure_boot::local_boot_config("This machine already has an operating system."));
+        }
+
+        // Only a machine with Secure Boot on would come through the signed chain
+        let mut target = db::get_boot_target(&machine.id).await.ok().flatten().unwrap_or_default();
+        if !target.secure_boot {
+            target.secure_boot = true;
+            if let Err(e) = db::update_boot_target(&machine.id, &target).await {
+                warn!("Failed to record Secure Boot for MAC {}: {}", mac, e);
+            }
+        }
+    }
+
+    let key = secure_boot::image_key(machine.as_ref());
+    let image = match db::get_signed_boot_image(key).await {
+        Ok(image) => image,
+        Err(e) => {
+            error!("Failed to load signed boot image '{}': {}", key, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+
+    info!("Serving Secure Boot GRUB config for MAC {} (image '{}', found: {})", mac, key, image.is_some());
+    grub_response(secure_boot::render_config(&mac, machine.as_ref(), image.as_ref()))
+}
+
+#[axum::debug_handler]
+async fn list_signed_boot_images(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_signed_boot_images().await {
+        Ok(images) => (StatusCode::OK, Json(images)).into_response(),
+        Err(e) => {
+            error!("Failed to list signed boot images: {}", e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to list signed boot images: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn update_signed_boot_image(
+    auth_session: AuthSession,
+    Path(template): Path<String>,
+    Json(mut image): Json<crate::secure_boot::SignedBootImage>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    image.template = template;
+    if let Err(message) = crate::secure_boot::validate(&image) {
+        let error_response = ErrorResponse {
+            error: "Invalid Signed Image".to_string(),
+            message,
+        };
+        return (StatusCode::BAD_REQUEST, Json(error_response)).into_response();
+    }
+
+    match db::save_signed_boot_image(&image).await {
+        Ok(()) => (StatusCode::OK, Json(image)).into_response(),
+        Err(e) => {
+            error!("Failed to save signed boot image for '{}': {}", image.template, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to save signed boot image: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn delete_signed_boot_image(
+    auth_session: AuthSession,
+    Path(template): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::delete_signed_boot_image(&template).await {
+        Ok(true) => (StatusCode::OK, Json(json!({ "success": true, "message": format!("Signed boot image for '{}' deleted", template) }))).into_response(),
+        Ok(false) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("No signed boot image for '{}'", template),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to delete signed boot image for '{}': {}", template, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to delete signed boot image: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, serde::Serialize, Debug)]
+pub struct IpxeOverride {
+    pub script: Option<String>,
+}
+
+#[axum::debug_handler]
+async fn get_ipxe_override(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    // Check the caller's permissions on this machine
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+
+    match db::get_ipxe_override(&id).await {
+        Ok(script) => (StatusCode::OK, Json(IpxeOverride { script })).into_response(),
+        Err(e) => {
+            error!("Failed to get iPXE override for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to retrieve iPXE override: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// The override to store for a submitted script: line endings normalized, and None (no override)
+// for an empty script
+fn ipxe_override_script(script: Option<String>) -> Result<Option<String>, String> {
+    let script = script
+        .map(|s| s.replace("\r\n", "\n"))
+        .filter(|s| !s.trim().is_empty());
+    match script {
+        Some(script) if !script.trim_start().starts_with("#!ipxe") => Err("iPXE scripts must start with #!ipxe".to_string()),
+        script => Ok(script),
+    }
+}
+
+#[axum::debug_handler]
+async fn update_ipxe_override(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<IpxeOverride>,
+) -> Response {
+    // Check the caller's permissions on this machine
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+
+    let script = match ipxe_override_script(payload.script) {
+        Ok(script) => script,
+        Err(message) => {
+            let error_response = ErrorResponse {
+                error: "Invalid Script".to_string(),
+                message,
+            };
+            return (StatusCode::BAD_REQUEST, Json(error_response)).into_response();
+        }
+    };
+
+    match db::update_ipxe_override(&id, script.as_deref()).await {
+        Ok(true) => {
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            (StatusCode::OK, Json(IpxeOverride { script })).into_response()
+        },
+        Ok(false) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Machine with ID {} not found", id),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to update iPXE override for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to update iPXE override: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Query parameter accepted by destructive operations (`?dry_run=true`)
+#[derive(Deserialize, Debug, Default)]
+pub struct DryRunQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+pub struct DeleteMachineQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Also delete the machine's Hardware and Workflow from Tinkerbell
+    #[serde(default = "default_true")]
+    pub tinkerbell: bool,
+}
+
+// What a destructive operation would do, returned instead of executing it
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DryRunPlan {
+    pub dry_run: bool,
+    pub action: String,
+    pub affected_machines: Vec<Uuid>,
+    pub resources_to_delete: Vec<String>,
+    pub resources_to_create: Vec<String>,
+    pub notes: Vec<String>,
+}
+
+impl DryRunPlan {
+    fn new(action: &str, machine: &Machine) -> Self {
+        Self {
+            dry_run: true,
+            action: action.to_string(),
+            affected_machines: vec![machine.id],
+            resources_to_delete: Vec::new(),
+            resources_to_create: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+}
+
+impl IntoResponse for DryRunPlan {
+    fn into_response(self) -> Response {
+        info!("Dry run of '{}' for {:?}: nothing was changed", self.action, self.affected_machines);
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+// Plan for deleting a machine: the Tinkerbell resources and database row it removes
+fn plan_delete(machine: &Machine, tinkerbell: bool) -> DryRunPlan {
+    let namespace = crate::status::dragonfly_namespace();
+    let (hardware_name, workflow_name) = crate::tinkerbell::deletion_targets(&machine.mac_address.replace(":", "-"));
+    let mut plan = DryRunPlan::new("delete", machine);
+    if tinkerbell {
+        plan.resources_to_delete.push(format!("hardware/{}/{}", namespace, hardware_name));
+        plan.resources_to_delete.push(format!("workflow/{}/{}", namespace, workflow_name));
+    }
+    plan.resources_to_delete.push(format!("machine/{}", machine.id));
+    plan.resources_to_create.push(format!("archived_machine/{}", machine.id));
+    plan
+}
+
+// Plan for rejecting a machine: its Tinkerbell resources go, but the machine record is kept
+fn plan_reject(machine: &Machine) -> DryRunPlan {
+    let mut plan = plan_delete(machine, true);
+    plan.action = "reject".to_string();
+    plan.resources_to_delete.pop();
+    plan.resources_to_create.clear();
+    plan.notes.push(format!("Machine status will change from {} to {}", machine.status, MachineStatus::Rejected));
+    plan
+}
+
+// Plan for (re)imaging a machine with an OS: the workflow it creates and the disk it overwrites
+async fn plan_reimage(machine: &Machine, os_choice: &str) -> DryRunPlan {
+    let namespace = crate::status::dragonfly_namespace();
+    let workflow = format!("workflow/{}/{}", namespace, crate::tinkerbell::workflow_resource_name(&machine.mac_address));
+    let mut plan = DryRunPlan::new("reimage", machine);
+    if machine.os_choice.is_some() {
+        // create_workflow replaces any existing workflow for the machine
+        plan.resources_to_delete.push(workflow.clone());
+    }
+    plan.resources_to_create.push(workflow);
+    match machine.disks.first() {
+        Some(disk) => plan.notes.push(format!("Disk {} will be overwritten with {}", disk.device, os_choice)),
+        None => plan.notes.push("No disks reported; the first disk found at install time will be overwritten".to_string()),
+    }
+    if crate::tinkerbell::get_client().await.is_err() {
+        plan.notes.push("Kubernetes is unreachable; the OS choice would be saved but no workflow created".to_string());
+    }
+    if machine.status.is_approval_gated() {
+        plan.notes.push(format!("Machine is {}; it will not PXE boot until approved", machine.status));
+    }
+    if let Ok(reasons) = crate::conflicts::for_machine(&machine.id).await {
+        for reason in reasons {
+            plan.notes.push(format!("Blocked by an address conflict: {}", reason));
+        }
+    }
+    plan
+}
+
+#[axum::debug_handler]
+async fn delete_machine(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Query(query): Query<DeleteMachineQuery>,
+) -> Response {
+    // Check the caller's permissions on this machine
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Admin).await {
+        return response;
+    }
+
+    info!("Request to delete machine: {}", id);
+
+    // Get the machine to find its MAC address
+    match db::get_machine_by_id(&id).await {
+        Ok(Some(machine)) if query.dry_run => plan_delete(&machine, query.tinkerbell).into_response(),
+        Ok(Some(machine)) => {
+            // Delete from Tinkerbell unless the caller wants to keep its Hardware and Workflow
+            let tinkerbell_result = if query.tinkerbell {
+                let mac_address = machine.mac_address.replace(":", "-").to_lowercase();
+                match crate::tinkerbell::delete_hardware(&mac_address).await {
+                    Ok(_) => {
+                        info!("Successfully deleted machine from Tinkerbell: {}", mac_address);
+                        true
+                    },
+                    Err(e) => {
+                        warn!("Failed to delete machine from Tinkerbell: {}", e);
+                        false
+                    }
+                }
+            } else {
+                info!("Keeping Tinkerbell resources of machine {}", id);
+                true
+            };
+
+            // Move the record to the archive rather than losing its history
+            match db::archive_machine(&id, &policy::principal(&auth_session)).await {
+                Ok(true) => {
+                    let message = if !query.tinkerbell {
+                        "Machine archived; its Tinkerbell resources were kept."
+                    } else if tinkerbell_result {
+                        "Machine successfully deleted from Dragonfly and Tinkerbell."
+                    } else {
+                        "Machine deleted from Dragonfly but there was an issue removing it from Tinkerbell."
+                    };
+                    
+                    // Emit machine deleted event; webhooks find the archived record for IPAM and DNS cleanup
+                    let _ = state.event_manager.send(format!("machine_deleted:{}", id));
+                    
+                    (StatusCode::OK, Json(json!({ "success": true, "message": message }))).into_response()
+                },
+                Ok(false) => {
+                    (StatusCode::NOT_FOUND, Json(json!({ "error": "Machine not found in database" }))).into_response()
+                },
+                Err(e) => {
+                    error!("Failed to delete machine from database: {}", e);
+                    (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Database error: {}", e) }))).into_response()
+                }
+            }
+        },
+        Ok(None) => {
+            (StatusCode::NOT_FOUND, Json(json!({ "error": "Machine not found" }))).into_response()
+        },
+        Err(e) => {
+            error!("Error fetching machine for deletion: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Database error: {}", e) }))).into_response()
+        }
+    }
+}
+
+// Deleted machines, kept for history
+#[axum::debug_handler]
+async fn list_archived_machines(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_archived_machines().await {
+        Ok(machines) => (StatusCode::OK, Json(machines)).into_response(),
+        Err(e) => {
+            error!("Failed to list archived machines: {}", e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to list archived machines: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn get_archived_machine(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_archived_machine(&id).await {
+        Ok(Some(machine)) => (StatusCode::OK, Json(machine)).into_response(),
+        Ok(None) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: "No archived machine with that ID".to_string(),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to get archived machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to get archived machine: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Add this function to handle machine updates
+#[axum::debug_handler]
+async fn update_machine(
+    State(state): State<AppState>,
+    // Use AuthSession directly, not Option<AuthSession>
+    auth_session: AuthSession,
+    // Add ConnectInfo to get client IP
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<Uuid>,
+    Json(mut machine_payload): Json<Machine>,
+) -> Response {
+    let client_ip = addr.ip().to_string();
+    info!("Update request for machine {} from IP: {}", id, client_ip);
+
+    // Authorization Logic
+    // Check if an admin user is logged in
+    let is_admin = crate::auth::is_admin(&auth_session);
+
+    let authorized = if is_admin {
+        // Admin is always authorized
+        info!("Admin user authorized update for machine {}", id);
+        true
+    } else {
+        // Not an admin, check if it's the agent based on IP
+        info!("Request is not from an admin, checking IP for agent authorization...");
+        match db::get_machine_by_id(&id).await {
+            Ok(Some(stored_machine)) => {
+                if stored_machine.ip_address == client_ip {
+                    info!("Agent IP {} matches stored IP for machine {}. Authorizing update.", client_ip, id);
+                    true // IP matches, allow update
+                } else {
+                    warn!("Agent IP {} does NOT match stored IP {} for machine {}. Denying update.",
+                          client_ip, stored_machine.ip_address, id);
+                    false // IP mismatch
+                }
+            },
+            Ok(None) => {
+                warn!("Machine {} not found during IP authorization check.", id);
+                false // Machine not found
+                },
+                Err(e) => {
+                error!("Database error during IP authorization check for machine {}: {}", id, e);
+                false // Database error
+            }
+        }
+    };
+
+    if !authorized {
+        // Use 403 Forbidden for authorization failures
+        // (axum-login middleware handles 401 for missing authentication if configured)
+        return (StatusCode::FORBIDDEN, Json(json!({
+            "error": "Forbidden",
+            "message": "You are not authorized to update this machine."
+        }))).into_response();
+    }
+
+    // --- Proceed with Update (if authorized) ---
+    
+    // Ensure the ID from the path matches the payload ID
+    if machine_payload.id != id {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "error": "ID Mismatch",
+            "message": "The machine ID in the URL path does not match the ID in the request body."
+        }))).into_response();
+    }
+
+    info!("Updating machine {} with full payload (Authorized by admin: {})", id, is_admin);
+    
+    // Agents can't move a machine past the approval gate
+    if !is_admin {
+        if let Ok(Some(stored_machine)) = db::get_machine_by_id(&id).await {
+            if stored_machine.status.is_approval_gated() {
+                machine_payload.status = stored_machine.status;
+            }
+        }
+    }
+    
+    // Set the updated_at timestamp before saving
+    machine_payload.updated_at = Utc::now();
+
+    // Call the updated db::update_machine function
+    match db::update_machine(&machine_payload, &status_cause(&auth_session)).await {
+                Ok(true) => {
+            // Emit machine updated event
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            
+            // Return the updated machine object
+            (StatusCode::OK, Json(machine_payload)).into_response()
+                },
+                Ok(false) => {
+            // This case should ideally not happen if the ID check above passed
+            // but handle it just in case (e.g., race condition with deletion)
+            (StatusCode::NOT_FOUND, Json(json!({
+                "error": "Not Found",
+                "message": format!("Machine with ID {} not found during update attempt.", id)
+            }))).into_response()
+                },
+                Err(e) if e.is::<InvalidTransition>() || e.is::<db::StatusConflict>() => {
+            (StatusCode::CONFLICT, Json(json!({
+                "error": "Invalid Transition",
+                "message": e.to_string()
+            }))).into_response()
+                },
+                Err(e) => {
+            error!("Failed to update machine {}: {}", id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "error": "Database Error",
+                "message": e.to_string()
+            }))).into_response()
+        }
+    }
+}
+
+// Handler to get the OS assignment form
+async fn get_machine_os(Path(id): Path<Uuid>) -> Response {
+    // Order the choices by popularity so new operators see what the fleet uses
+    let popularity = get_template_popularity().await;
+    let options: String = popularity.iter().enumerate().map(|(i, t)| {
+        let hint = if t.usage_count > 0 {
+            format!(" ({} assigned)", t.usage_count)
+        } else if t.community_weight > 0 && i == 0 {
+            " (popular)".to_string()
+        } else {
+            String::new()
+        };
+        format!(
+            r#"<option value="{}"{}>{}{}</option>"#,
+            t.template,
+            if i == 0 { " selected" } else { "" },
+            t.name,
+            hint
+        )
+    }).collect::<Vec<_>>().join("\n                                ");
+
+    Html(format!(r#"
+        <div class="sm:flex sm:items-start">
+            <div class="mt-3 text-center sm:mt-0 sm:text-left w-full">
+                <h3 class="text-lg leading-6 font-medium text-gray-900">
+                    Assign Operating System
+                </h3>
+                <div class="mt-2">
+                    <form hx-post="/api/machines/{}/os" hx-swap="none" @submit="osModal = false">
+                        <div class="mt-4">
+                            <label for="os_choice" class="block text-sm font-medium text-gray-700">Operating System</label>
+                            <select
+                                id="os_choice"
+                                name="os_choice"
+                                class="mt-1 block w-full pl-3 pr-10 py-2 text-base border-gray-300 focus:outline-none focus:ring-indigo-500 focus:border-indigo-500 sm:text-sm rounded-md"
+                            >
+                                {}
+                            </select>
+                        </div>
+                        <div class="mt-5 sm:mt-4 sm:flex sm:flex-row-reverse">
+                            <button
+                                type="submit"
+                                class="inline-flex w-full justify-center rounded-md bg-indigo-600 px-3 py-2 text-sm font-semibold text-white shadow-sm hover:bg-indigo-500 sm:ml-3 sm:w-auto"
+                            >
+                                Assign
+                            </button>
+                            <button
+                                type="button"
+                                class="mt-3 inline-flex w-full justify-center rounded-md bg-white px-3 py-2 text-sm font-semibold text-gray-900 shadow-sm ring-1 ring-inset ring-gray-300 hover:bg-gray-50 sm:mt-0 sm:w-auto"
+                                @click="osModal = false"
+                            >
+                                Cancel
+                            </button>
+                        </div>
+                    </form>
+                </div>
+            </div>
+        </div>
+    "#, id, options)).into_response()
+}
+
+// Handler to get the status update form 
+pub async fn get_machine_status(Path(id): Path<Uuid>) -> impl IntoResponse {
+    let html = format!(r#"
+        <div class="sm:flex sm:items-start">
+            <div class="mt-3 text-center sm:mt-0 sm:text-left w-full">
+                <h3 class="text-lg leading-6 font-medium text-gray-900">
+                    Update Machine Status
+                </h3>
+                <div class="mt-2">
+                    <form hx-post="/machines/{}/status" hx-swap="none" @submit="statusModal = false">
+                        <div class="mb-4">
+                            <label for="status" class="block text-sm font-medium text-gray-700">Status</label>
+                            <select name="status" id="status" class="mt-1 block w-full pl-3 pr-10 py-2 text-base border-gray-300 focus:outline-none focus:ring-indigo-500 focus:border-indigo-500 sm:text-sm rounded-md">
+                                <option value="Ready">Ready</option>
+                                <option value="AwaitingAssignment">Awaiting OS Assignment</option>
+                                <option value="InstallingOS">Installing OS</option>
+                                <option value="Error">Error</option>
+                            </select>
+                        </div>
+                        <div class="mt-5 sm:mt-6">
+                            <button type="submit" class="inline-flex justify-center w-full rounded-md border border-transparent shadow-sm px-4 py-2 bg-indigo-600 text-base font-medium text-white hover:bg-indigo-700 focus:outline-none focus:ring-2 focus:ring-offset-2 focus:ring-indigo-500 sm:text-sm">
+                                Update Status
+                            </button>
+                        </div>
+                    </form>
+                </div>
+            </div>
+        </div>
+    "#, id);
+
+    Html(html)
+}
+
+// The event feed from /events over a WebSocket, with a machine snapshot on connect and
+// client-side subscription filters. The snapshot is the whole inventory, so it needs a login
+// even when require_login is off.
+#[axum::debug_handler]
+async fn event_socket(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    ws: WebSocketUpgrade,
+) -> Response {
+    if auth_session.user.is_none() {
+        return json_error(StatusCode::UNAUTHORIZED, "Unauthorized", "Sign in to open the event socket".to_string());
+    }
+    let event_manager = state.event_manager.clone();
+    ws.on_upgrade(move |socket| crate::event_socket::run_event_session(socket, event_manager))
+}
+
+// Run a GraphQL query over machines, their workflows and status events, and OS templates
+#[axum::debug_handler]
+async fn graphql(
+    auth_session: AuthSession,
+    Json(request): Json<async_graphql::Request>,
+) -> Response {
+    let viewer = crate::graphql::Viewer::new(&auth_session);
+    let response = crate::graphql::schema().execute(request.data(viewer)).await;
+    Json(response).into_response()
+}
+
+// GraphiQL, for exploring the schema from a browser
+async fn graphiql() -> Html<String> {
+    Html(async_graphql::http::GraphiQLSource::build().endpoint("/api/graphql").finish())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EventsQuery {
+    /// For clients that reconnect with a new EventSource, which doesn't send `Last-Event-ID`
+    pub last_event_id: Option<String>,
+}
+
+// Turn an event-manager message into an SSE event carrying its ID
+fn sse_event(events: &crate::event_manager::EventManager, event: &crate::event_manager::RecordedEvent) -> Event {
+    let (event_type, event_payload_str) = crate::event_socket::split_event(&event.message);
+    let sse_event = Event::default().id(events.event_id(event.id));
+
+    // Special handling for ip_download_progress to send raw JSON payload
+    if event_type == "ip_download_progress" {
+        return match event_payload_str {
+            // Directly use the JSON string as data for this specific event type
+            Some(payload_str) => sse_event.event(event_type).data(payload_str),
+            None => {
+                warn!("Received ip_download_progress event without payload: {}", event.message);
+                Event::default().comment("Warning: ip_download_progress event received without payload.")
+            }
+        };
+    }
+
+    // Other events (like machine_updated, machine_discovered, etc.) carry their type and ID as JSON
+    let data_payload = match event_payload_str {
+        Some(id_str) => json!({ "type": event_type, "id": id_str }),
+        // Ensure there's always a payload, even without ID
+        None => json!({ "type": event_type }),
+    };
+    match serde_json::to_string(&data_payload) {
+        Ok(json_string) => sse_event.event(event_type).data(json_string),
+        Err(e) => {
+            error!("Failed to serialize SSE event data to JSON: {}", e);
+            Event::default().comment("Internal error: failed to serialize event.")
+        }
+    }
+}
+
+// Live event feed. Clients resuming with `Last-Event-ID` first get the events they missed, or a
+// `resync` event when some are no longer held and they should reload instead.
+async fn machine_events(
+    State(state): State<AppState>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = std::result::Result<Event, Infallible>>> {
+    let last_event_id = headers.get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .or(query.last_event_id);
+    let replay = state.event_manager.subscribe_since(last_event_id.as_deref());
+
+    let mut replayed: Vec<std::result::Result<Event, Infallible>> = Vec::new();
+    if replay.gap {
+        replayed.push(Ok(Event::default().event("resync").data(json!({ "type": "resync" }).to_string())));
+    }
+    replayed.extend(replay.missed.iter().map(|event| Ok(sse_event(&state.event_manager, event))));
+
+    // A lagging client is disconnected; it reconnects with Last-Event-ID and catches up from the history
+    let events = state.event_manager.clone();
+    let live = stream::unfold(replay.subscription, move |mut subscription| {
+        let events = events.clone();
+        async move {
+            match subscription.recv_event().await {
+                Ok(event) => Some((Ok(sse_event(&events, &event)), subscription)),
+                Err(_) => None,
+            }
+        }
+    });
+
+    Sse::new(stream::iter(replayed).chain(live)).keep_alive(
+        KeepAlive::new()
+            .interval(Duration::from_secs(1))
+            .text("ping"),
+    )
+}
+
+async fn generate_ipxe_script(script_name: &str) -> Result<String, dragonfly_common::Error> {
+    info!("Generating IPXE script: {}", script_name);
+ 
+    match script_name {
+        "hookos.ipxe" => {
+            // Get Dragonfly base URL (required)
+            let base_url_str = env::var("DRAGONFLY_BASE_URL")
+                .map_err(|_| {
+                    error!("CRITICAL: DRAGONFLY_BASE_URL environment variable is not set. HookOS iPXE script requires this.");
+                    Error::Internal("Server is missing required DRAGONFLY_BASE_URL configuration.".to_string())
+                })?;
+
+            // --- Derive Tinkerbell defaults from DRAGONFLY_BASE_URL ---
+            let default_tinkerbell_host = Url::parse(&base_url_str)
+                .ok()
+                .and_then(|url| url.host_str().map(String::from))
+                .unwrap_or_else(|| {
+                    warn!("Could not parse DRAGONFLY_BASE_URL host, using fallback '127.0.0.1' for Tinkerbell defaults.");
+                    "127.0.0.1".to_string()
+                });
+            
+            const DEFAULT_GRPC_PORT: u16 = 42113;
+            let default_grpc_authority = format!("{}:{}", default_tinkerbell_host, DEFAULT_GRPC_PORT);
+            let default_syslog_host = default_tinkerbell_host.clone(); // Default syslog host is just the host part
+            // -----------------------------------------------------------
+
+            // Get Tinkerbell config, using derived values as defaults
+            let grpc_authority = env::var("TINKERBELL_GRPC_AUTHORITY")
+                .unwrap_or_else(|_| {
+                    info!("TINKERBELL_GRPC_AUTHORITY not set, deriving default: {}", default_grpc_authority);
+                    default_grpc_authority
+                });
+            let syslog_host = env::var("TINKERBELL_SYSLOG_HOST")
+                .unwrap_or_else(|_| {
+                     info!("TINKERBELL_SYSLOG_HOST not set, deriving default: {}", default_syslog_host);
+                     default_syslog_host
+                 });
+            let tinkerbell_tls = env::var("TINKERBELL_TLS")
+                .map(|s| s.parse().unwrap_or(false))
+                .unwrap_or(false);
+
+            // Format the HookOS iPXE script using Dragonfly URL for artifacts and Tinkerbell details for params
+            Ok(format!(r#"#!ipxe
+
+echo Loading HookOS via Dragonfly...
+
+set arch ${{buildarch}}
+# Dragonfly + Tinkerbell only supports 64 bit archectures.
+# The build architecture does not necessarily represent the architecture of the machine on which iPXE is running.
+# https://ipxe.org/cfg/buildarch
+
+iseq ${{arch}} i386 && set arch x86_64 ||
+iseq ${{arch}} arm32 && set arch aarch64 ||
+iseq ${{arch}} arm64 && set arch aarch64 ||
+set base-url {}
+set retries:int32 0
+set retry_delay:int32 0
+
+set worker_id ${{mac}}
+set grpc_authority {}
+set syslog_host {}
+set tinkerbell_tls {}
+
+echo worker_id=${{mac}}
+echo grpc_authority={}
+echo syslog_host={}
+echo tinkerbell_tls={}
+
+set idx:int32 0
+:retry_kernel
+kernel ${{base-url}}/ipxe/hookos/vmlinuz-${{arch}} \
+syslog_host=${{syslog_host}} grpc_authority=${{grpc_authority}} tinkerbell_tls=${{tinkerbell_tls}} worker_id=${{worker_id}} hw_addr=${{mac}} \
+console=tty1 console=tty2 console=ttyAMA0,115200 console=ttyAMA1,115200 console=ttyS0,115200 console=ttyS1,115200 tink_worker_image=quay.io/tinkerbell/tink-worker:v0.12.1 \
+intel_iommu=on iommu=pt initrd=initramfs-${{arch}} && goto download_initrd || iseq ${{idx}} ${{retries}} && goto kernel-error || inc idx && echo retry in ${{retry_delay}} seconds ; sleep ${{retry_delay}} ; goto retry_kernel
+
+:download_initrd
+set idx:int32 0
+:retry_initrd
+initrd ${{base-url}}/ipxe/hookos/initramfs-${{arch}} && goto boot || iseq ${{idx}} ${{retries}} && goto initrd-error || inc idx && echo retry in ${{retry_delay}} seconds ; sleep ${{retry_delay}} ; goto retry_initrd
+
+:boot
+set idx:int32 0
+:retry_boot
+boot || iseq ${{idx}} ${{retries}} && goto boot-error || inc idx && echo retry in ${{retry_delay}} seconds ; sleep ${{retry_delay}} ; goto retry_boot
+
+:kernel-error
+echo Failed to load kernel
+imgfree
+exit
+
+:initrd-error
+echo Failed to load initrd
+imgfree
+exit
+
+:boot-error
+echo Failed to boot
+imgfree
+exit
+"#, 
+            base_url_str, // Use Dragonfly base URL for artifacts
+            grpc_authority, // Use determined gRPC authority (env var or derived default)
+            syslog_host,    // Use determined syslog host (env var or derived default)
+            tinkerbell_tls, // Use determined TLS setting
+            grpc_authority, // for echo
+            syslog_host,    // for echo
+            tinkerbell_tls  // for echo
+            ))
+        },
+        "dragonfly-agent.ipxe" => {
+            // Get Dragonfly base URL for agent artifacts
+            let base_url = env::var("DRAGONFLY_BASE_URL")
+                .map_err(|_| {
+                    error!("CRITICAL: DRAGONFLY_BASE_URL environment variable is not set. Agent iPXE script requires this.");
+                    Error::Internal("Server is missing required DRAGONFLY_BASE_URL configuration.".to_string())
+                })?;
+                
+            // Format the Dragonfly Agent iPXE script, picking the Alpine kernel for the machine's architecture
+            Ok(format!(r#"#!ipxe
+set arch ${{buildarch}}
+iseq ${{arch}} i386 && set arch x86_64 ||
+iseq ${{arch}} arm32 && set arch aarch64 ||
+iseq ${{arch}} arm64 && set arch aarch64 ||
+kernel {}/ipxe/dragonfly-agent/${{arch}}/vmlinuz \
+  ip=dhcp \
+  alpine_repo=http://dl-cdn.alpinelinux.org/alpine/v3.21/main \
+  modules=loop,squashfs,sd-mod,usb-storage \
+  initrd=initramfs-lts \
+  modloop={}/ipxe/dragonfly-agent/${{arch}}/modloop \
+  apkovl={}/ipxe/dragonfly-agent/localhost.apkovl.tar.gz \
+  rw
+initrd {}/ipxe/dragonfly-agent/${{arch}}/initramfs-lts
+boot
+"#, 
+            base_url, // for kernel path
+            base_url, // for modloop path
+            base_url, // for apkovl path
+            base_url  // for initrd path
+            ))
+        },
+        _ => {
+            warn!("Cannot generate unknown IPXE script: {}", script_name); // Log the specific script name
+            Err(Error::NotFound) // Use the unit variant correctly
+        },
+    }
+}
+
+fn create_streaming_response(
+    stream: ReceiverStream<Result<Bytes, Error>>,
+    content_type: &str,
+    content_length: Option<u64>,
+    content_range: Option<String>
+) -> Response {
+    // Map the stream from Result<Bytes> to Result<Frame<Bytes>, BoxError>
+    let mapped_stream = stream.map(|result| {
+        match result {
+            Ok(bytes) => {
+                // Removed check for empty EOF marker
+                // Simply map non-empty bytes to a data frame
+                Ok(Frame::data(bytes))
+            },
+            Err(e) => Err(Box::new(e) as Box<dyn std::error::Error + Send + Sync>),
+        }
+    });
+    
+    // Create a stream body with explicit end signal
+    let body = StreamBody::new(mapped_stream);
+    
+    // Determine status code based on whether it's a partial response
+    let status_code = if content_range.is_some() {
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::OK
+    };
+    
+    // Start building the response
+    let mut builder = Response::builder()
+        .status(status_code)
+        .header(axum::http::header::CONTENT_TYPE, content_type)
+        // Always accept ranges
+        .header(axum::http::header::ACCEPT_RANGES, "bytes")
+        // Always set no compression
+        .header(axum::http::header::CONTENT_ENCODING, "identity");
+
+    if let Some(length) = content_length {
+        // If Content-Length is known, set it and DO NOT use chunked encoding.
+        // This applies to both 200 OK and 206 Partial Content.
+        builder = builder.header(axum::http::header::CONTENT_LENGTH, length.to_string());
+    } else {
+        // Only use chunked encoding if length is truly unknown (should typically only be for 200 OK).
+        // It's an error to have a 206 response without Content-Length.
+        if status_code == StatusCode::OK { 
+            builder = builder.header(axum::http::header::TRANSFER_ENCODING, "chunked");
+        } else {
+            // This case (206 without Content-Length) ideally shouldn't happen with our logic.
+            // Log a warning if it does.
+            warn!("Attempting to create 206 response without Content-Length!");
+        }
+    }
+    
+    // Include Content-Range if it's a partial response
+    if let Some(range_header_value) = content_range {
+        builder = builder.header(axum::http::header::CONTENT_RANGE, range_header_value);
+    }
+    
+    // Build the final response
+    builder.body(Body::new(body))
+        .unwrap_or_else(|_| {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::new(Empty::new()))
+                .unwrap()
+        })
+}
+
+
+async fn read_file_as_stream(
+    path: &StdPath,
+    range_header: Option<&HeaderValue>, // Add parameter for Range header
+    state: Option<&AppState>, // Add optional state for event emission
+    machine_id: Option<Uuid> // Add optional machine ID for tracking
+) -> Result<(ReceiverStream<Result<Bytes, Error>>, Option<u64>, Option<String>), Error> { // Return size and Content-Range
+    info!("[STREAM_READ] Beginning read_file_as_stream for path: {}, range: {:?}, machine_id: {:?}", 
+          path.display(), range_header.map(|h| h.to_str().unwrap_or("invalid")), machine_id);
+
+    let mut file = fs::File::open(path).await.map_err(|e| Error::Internal(format!("Failed to open file {}: {}", path.display(), e)))?; // Added mut back
+    let (tx, rx) = mpsc::channel::<Result<Bytes, Error>>(32);
+    let path_buf = path.to_path_buf();
+    
+    // Get total file size
+    let metadata = fs::metadata(path).await.map_err(|e| Error::Internal(format!("Failed to get metadata {}: {}", path.display(), e)))?;
+    let total_size = metadata.len();
+    
+    // Get file name for progress tracking
+    let file_name = path.file_name()
+                        .and_then(|name| name.to_str())
+                        .map(String::from);
+    
+    let (start, _end, response_length, content_range_header) = // Marked end as unused
+        if let Some(range_val) = range_header {
+            if let Ok(range_str) = range_val.to_str() {
+                if let Some((start, end)) = parse_range_header(range_str, total_size, file_name.as_deref(), state).await {
+                    let length = end - start + 1;
+                    let content_range = format!("bytes {}-{}/{}", start, end, total_size);
+                    // info!("Serving range request: {} for file {}", content_range, path.display()); // Commented out log
+                    (start, end, length, Some(content_range))
+                } else {
+                    warn!("Invalid Range header format: {}", range_str);
+                    // Invalid range, serve the whole file
+                    (0, total_size.saturating_sub(1), total_size, None)
+                }
+            } else {
+                warn!("Invalid Range header value (not UTF-8)");
+                // Invalid range, serve the whole file
+                (0, total_size.saturating_sub(1), total_size, None)
+            }
+        } else {
+            // No range header, serve the whole file
+            (0, total_size.saturating_sub(1), total_size, None)
+        };
+
+    let response_content_length = Some(response_length);
+    let content_range_header_clone = content_range_header.clone(); // Clone for the task
+    // Clone state and machine_id needed for the background task *before* spawning
+    // Ensures owned values are moved into the async block, avoiding lifetime issues.
+    let task_state_owned = state.cloned(); // Creates Option<AppState>
+    let task_machine_id_copied = machine_id; // Copies Option<Uuid>
+
+    tokio::spawn(async move {
+        // Handle Range requests differently: read the whole range at once
+        if content_range_header_clone.is_some() { // Use the clone
+            if start > 0 {
+                if let Err(e) = file.seek(std::io::SeekFrom::Start(start)).await {
+                    error!("Failed to seek file {}: {}", path_buf.display(), e);
+                    let _ = tx.send(Err(Error::Internal(format!("File seek error: {}", e)))).await;
+                    return;
+                }
+            }
+            
+            // Allocate buffer for the exact range size
+            let mut buffer = Vec::with_capacity(response_length as usize); // Use with_capacity
+            
+            // Create a reader limited to the exact range size
+            let mut limited_reader = file.take(response_length);
+            
+            // Read the exact range using the limited reader
+            match limited_reader.read_to_end(&mut buffer).await {
+                Ok(_) => {
+                    // Track progress for range requests too
+                    // For range requests, we use the start offset as an indicator of download progress
+                    if let (Some(state_ref), Some(machine_id_captured)) = (&task_state_owned, task_machine_id_copied) {
+                        if total_size > 0 {
+                            // Use start position + current range size as effective progress indicator
+                            let bytes_read = buffer.len() as u64;
+                            let effective_progress = start + bytes_read;
+                            
+                            info!("[RANGE_READ] Range request: start={}, bytes_read={}, total_size={}, effective_progress={}",
+                                  start, bytes_read, total_size, effective_progress);
+                                  
+                            // Clone state for progress tracking
+                            let owned_state = state_ref.clone();
+                            
+                            // Spawn progress tracking in a separate task
+                            tokio::spawn(async move {
+                                track_download_progress(Some(machine_id_captured), effective_progress, total_size, owned_state).await;
+                            });
+                        }
+                    }
+                
+                    // Send the complete range as a single chunk
+                    if tx.send(Ok(Bytes::from(buffer))).await.is_err() {
+                        warn!("Client stream receiver dropped for file {} while sending range", path_buf.display());
+                    }
+                    // Task finishes, tx is dropped, stream closes.
+                },
+                Err(e) => {
+                    error!("Failed to read exact range for file {}: {}", path_buf.display(), e);
+                    let _ = tx.send(Err(Error::Internal(format!("File read_exact error: {}", e)))).await;
+                }
+            }
+        } else {
+            // Original streaming logic for full file requests
+            let mut buffer = vec![0; 65536]; // 64KB buffer
+            let mut remaining = response_length; // For full file, response_length == total_size
+            let mut total_bytes_sent: u64 = 0;
+
+            while remaining > 0 {
+                let read_size = std::cmp::min(remaining as usize, buffer.len());
+                match file.read(&mut buffer[..read_size]).await {
+                    Ok(0) => {
+                        //info!("Reached EOF while serving file {} (remaining: {} bytes)", path_buf.display(), remaining);
+                        break; // EOF reached
+                    },
+                    Ok(n) => { // Handles n > 0
+                        let chunk = Bytes::copy_from_slice(&buffer[0..n]);
+                        remaining -= n as u64;
+                        total_bytes_sent += n as u64; // Add this line to update total bytes sent!
+
+                        // ADDED LOG: Log bytes read and total sent
+                        debug!(path = %path_buf.display(), bytes_read = n, total_bytes_sent = total_bytes_sent, total_size = total_size, "[STREAM_READ_LOOP] Read chunk");
+
+                        // Use the owned/copied state and machine_id captured by the 'move' closure
+                        // Match against the Option<&AppState> and Option<Uuid> directly
+                        if let (Some(state_ref), Some(machine_id_captured)) = (&task_state_owned, task_machine_id_copied) {
+                            if total_size > 0 { // Avoid division by zero
+                                debug!("[PROGRESS_DEBUG][CACHE_READ] Calling track_download_progress (machine_id: {}, sent: {}, total: {})", machine_id_captured, total_bytes_sent, total_size);
+                                // Clone the AppState here to get an owned value for the inner task.
+                                let owned_state = state_ref.clone(); // <-- Add this line
+                                // Spawn progress tracking in a separate task to avoid blocking the stream
+                                tokio::spawn(async move {
+                                    // Pass the already owned AppState.
+                                    track_download_progress(Some(machine_id_captured), total_bytes_sent, total_size, owned_state).await; // <-- Use owned_state here
+                                });
+                            } // else: Skipping progress track because total_size is 0 (logged elsewhere if needed)
+                        } // else: Skipping progress track because machine_id or state is missing
+
+                        if tx.send(Ok(chunk)).await.is_err() {
+                            warn!("Client stream receiver dropped for file {}", path_buf.display());
+                            break; // Exit loop if receiver is gone
+                        }
+                    },
+                    Err(e) => {
+                        let err = Error::Internal(format!("File read error for {}: {}", path_buf.display(), e));
+                        if tx.send(Err(err)).await.is_err() {
+                            warn!("Client stream receiver dropped while sending error for {}", path_buf.display());
+                        }
+                        break; // Exit loop on read error
+                    }
+                }
+            }
+        }
+        
+        // Task finishes, tx is dropped, stream closes.
+        debug!("Finished streaming task for: {}", path_buf.display());
+    });
+    
+    // Return the stream, the length of the *content being sent*, and the *original* Content-Range header string
+    Ok((tokio_stream::wrappers::ReceiverStream::new(rx), response_content_length, content_range_header))
+}
+
+// Serve iPXE artifacts (scripts and binaries)
+// Function to serve an iPXE artifact file from a configured directory
+pub async fn serve_ipxe_artifact(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(requested_path): Path<String>,
+    State(state): State<AppState>, // Add AppState to access event manager
+) -> Response {
+    // Define constants for directories and URLs
+    const ALLOWED_IPXE_SCRIPTS: &[&str] = &["hookos", "dragonfly-agent"]; // Define allowlist
+    const AGENT_APKOVL_PATH: &str = "/var/lib/dragonfly/ipxe-artifacts/dragonfly-agent/localhost.apkovl.tar.gz";
+    const AGENT_BINARY_URL: &str = "https://github.com/Zorlin/dragonfly/raw/refs/heads/main/dragonfly-agent-musl"; // TODO: Make configurable
+    
+    // --- Get Machine ID from Client IP --- 
+    let client_ip = Some(crate::access::client_ip(addr.ip(), &headers).to_string());
+    let machine_id = if let Some(ip) = &client_ip {
+        // ADDED LOG: Log the IP being looked up
+        info!("[PROGRESS_DEBUG] Looking up machine by IP: {}", ip);
+        match db::get_machine_by_ip(ip).await {
+            Ok(Some(machine)) => {
+                // ADDED LOG: Log successful lookup
+                info!("[PROGRESS_DEBUG] Found machine ID {} for IP {}", machine.id, ip);
+                Some(machine.id)
+            },
+            Ok(None) => {
+                // Changed to INFO for visibility
+                info!("[PROGRESS_DEBUG] No machine found for IP {} requesting artifact {}", ip, requested_path);
+                None
+            },
+            Err(e) => {
+                // Changed to INFO for visibility
+                info!("[PROGRESS_DEBUG] DB error looking up machine by IP {}: {}", ip, e);
+                None
+            }
+        }
+    } else {
+        // Changed to INFO for visibility
+        info!("[PROGRESS_DEBUG] Client IP not found in state for artifact request {}", requested_path);
+        None
+    };
+    // ----------------------------------
+
+    // Artifacts live wherever the configured storage backend keeps them
+    let storage = db::get_artifact_storage().await.unwrap_or_else(|e| {
+        warn!("Failed to load artifact storage settings, using local disk: {}", e);
+        ArtifactStorage::default()
+    });
+    let base_path = storage.local_dir();
+    
+    // Path sanitization - Allow '/' but prevent '..'
+    if requested_path.contains("..") || requested_path.contains('\\') {
+        warn!("Attempted iPXE artifact path traversal using '..' or '\': {}", requested_path);
+        return (StatusCode::BAD_REQUEST, "Invalid artifact path").into_response();
+    }
+    
+    // Project artifacts are only served to that project's machines; others are told they don't exist
+    if let Some(project) = crate::projects::artifact_project(&requested_path) {
+        let requester_project = match machine_id {
+            Some(id) => crate::projects::machine_project(&id).await.unwrap_or_else(|e| {
+                warn!("Failed to look up project of machine {}: {}", id, e);
+                None
+            }),
+            None => None,
+        };
+        if requester_project.as_deref() != Some(project) {
+            warn!("Refused project '{}' artifact {} to {:?} (machine {:?})", project, requested_path, client_ip, machine_id);
+            return (StatusCode::NOT_FOUND, "Artifact not found").into_response();
+        }
+    }
+    
+    let artifact_path = base_path.join(&requested_path);
+
+    // Images in object storage are served from the bucket; generated scripts and the agent overlay stay local
+    let object_storage = match &storage {
+        ArtifactStorage::S3(s3) if !requested_path.ends_with(".ipxe") && requested_path != "dragonfly-agent/localhost.apkovl.tar.gz" => Some(s3),
+        _ => None,
+    };
+    if let Some(s3) = object_storage {
+        let range = headers.get(axum::http::header::RANGE).and_then(|v| v.to_str().ok());
+        if let Some(response) = serve_from_object_storage(s3, &requested_path, range).await {
+            return response;
+        }
+    }
+
+    // --- Serve from Cache First ---
+    if artifact_path.exists() {
+        info!("[SERVE_ARTIFACT] Cached artifact exists at {}, will use read_file_as_stream", artifact_path.display());
+        // Determine content type AND if it's an IPXE script
+        let (content_type, is_ipxe) = if requested_path.ends_with(".ipxe") {
+            ("text/plain", true)
+        } else if requested_path.ends_with(".tar.gz") {
+            ("application/gzip", false) // Ensure this returns a tuple
+        } else {
+            ("application/octet-stream", false) // Ensure this returns a tuple
+        };
+
+        // Allowlist check for IPXE scripts from cache
+        if is_ipxe { // Check the boolean flag
+            let stem = StdPath::new(&requested_path).file_stem().and_then(|s| s.to_str());
+            if let Some(stem_str) = stem {
+                if !ALLOWED_IPXE_SCRIPTS.contains(&stem_str) {
+                    warn!("Attempt to serve non-allowlisted IPXE script stem from cache: {}", stem_str);
+                    return (StatusCode::NOT_FOUND, "iPXE Script Not Found").into_response();
+                }
+            } else {
+                 warn!("Could not extract stem from IPXE script path: {}", requested_path);
+                 return (StatusCode::BAD_REQUEST, "Invalid IPXE Script Path").into_response();
+            }
+        }
+        
+        // Serve allowed script or binary artifact from cache using streaming
+        // Pass the potentially found machine_id for progress tracking
+        match read_file_as_stream(&artifact_path, headers.get(axum::http::header::RANGE), Some(&state), machine_id).await {
+            Ok((stream, file_size, content_range)) => {
+                info!("Streaming cached artifact from disk: {}", requested_path);
+                return create_streaming_response(stream, content_type, file_size, content_range); // Pass content_range
+            },
+            Err(e) => {
+                error!("Failed to stream cached iPXE artifact: {}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Error reading iPXE artifact").into_response();
+            }
+        }
+    } else {
+        // --- File Not Found: Generate or Download --- 
+        info!("[SERVE_ARTIFACT] Artifact {} not found locally, will need to generate or download", requested_path);
+        
+        // FIRST check if it is the specific apkovl path that needs generation
+        // Compare against the RELATIVE path expected from the URL
+        if requested_path == "dragonfly-agent/localhost.apkovl.tar.gz" {
+            // --- Special Case: Generate apkovl on demand ---
+            // Use the full absolute path for generation logic
+            let generation_target_path = PathBuf::from(AGENT_APKOVL_PATH);
+            info!("Generating {} on demand...", generation_target_path.display());
+
+            let base_url = match env::var("DRAGONFLY_BASE_URL") {
+                Ok(url) => url,
+                Err(_) => {
+                    error!("Cannot generate apkovl: DRAGONFLY_BASE_URL environment variable is not set.");
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Server configuration error for apkovl generation").into_response();
+                }
+            };
+
+            match generate_agent_apkovl(&generation_target_path, &base_url, AGENT_BINARY_URL).await {
+                Ok(()) => {
+                    info!("Successfully generated {}, now serving...", generation_target_path.display());
+                    // Serve the newly generated file (no range needed here as it was just created)
+                    match read_file_as_stream(&generation_target_path, None, None, None).await { 
+                        Ok((stream, file_size, _)) => {
+                            return create_streaming_response(stream, "application/gzip", file_size, None); 
+                        },
+                        Err(e) => {
+                            error!("Failed to stream newly generated apkovl {}: {}", generation_target_path.display(), e);
+                            return (StatusCode::INTERNAL_SERVER_ERROR, "Error reading newly generated apkovl").into_response();
+                        }
+                    }
+                },
+                Err(e) => {
+                    error!("Failed to generate {}: {}", generation_target_path.display(), e);
+                    return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to generate {}: {}", generation_target_path.display(), e)).into_response();
+                }
+            }
+        } 
+        // NEXT check if it's a generic .ipxe script that needs generation
+        else if requested_path.ends_with(".ipxe") {
+            // --- Generate iPXE scripts on the fly ---
+            // Use the relative path for script generation lookup
+            match generate_ipxe_script(&requested_path).await {
+                Ok(script) => {
+                    info!("Generated {} script dynamically.", requested_path);
+                    // Cache in background using the full artifact_path
+                    let path_clone = artifact_path.clone(); 
+                    let script_clone = script.clone();
+                    let requested_path_clone = requested_path.clone(); // Clone for the task
+                    tokio::spawn(async move {
+                        // Ensure parent directory exists before writing
+                        if let Some(parent) = path_clone.parent() {
+                             if let Err(e) = fs::create_dir_all(parent).await {
+                                 warn!("Failed to create directory for caching {}: {}", requested_path_clone, e);
+                                 return; 
+                             }
+                         }
+                        if let Err(e) = fs::write(&path_clone, &script_clone).await {
+                             warn!("Failed to cache generated {} script: {}", requested_path_clone, e);
+                        }
+                    });
+                    
+                    // For iPXE scripts, let's build our own response
+                    let content_length = script.len() as u64;
+                    
+                    // Create a response that's optimized for iPXE
+                    return Response::builder()
+                        .status(StatusCode::OK)
+                        .header(axum::http::header::CONTENT_TYPE, "text/plain")
+                        .header(axum::http::header::CONTENT_LENGTH, content_length.to_string())
+                        .header(axum::http::header::CONTENT_ENCODING, "identity") // No compression
+                        .body(Body::from(script))
+                        .unwrap_or_else(|_| {
+                            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to build response").into_response()
+                        });
+                },
+                Err(Error::NotFound { .. }) => {
+                    warn!("IPXE script {} not found or could not be generated.", requested_path);
+                    // Fall through to final 404
+                },
+                Err(e) => {
+                    // Other error during generation (e.g., missing env var)
+                    error!("Failed to generate {} script: {}", requested_path, e);
+                    return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to generate script: {}", e)).into_response();
+                }
+            }
+            // If we fall through here, it means generate_ipxe_script returned NotFound
+        }
+        // FINALLY, assume it's a binary artifact to download/stream
+        else {
+            // --- Download/Stream Other Binary Artifacts ---
+            let remote_url = match requested_path.as_str() {
+                // Alpine Linux netboot artifacts for Dragonfly Agent (unprefixed paths are the old x86_64-only layout)
+                "dragonfly-agent/vmlinuz" | "dragonfly-agent/x86_64/vmlinuz" => "https://dl-cdn.alpinelinux.org/alpine/latest-stable/releases/x86_64/netboot/vmlinuz-lts",
+                "dragonfly-agent/initramfs-lts" | "dragonfly-agent/x86_64/initramfs-lts" => "https://dl-cdn.alpinelinux.org/alpine/latest-stable/releases/x86_64/netboot/initramfs-lts",
+                "dragonfly-agent/modloop" | "dragonfly-agent/x86_64/modloop" => "https://dl-cdn.alpinelinux.org/alpine/latest-stable/releases/x86_64/netboot/modloop-lts",
+                "dragonfly-agent/aarch64/vmlinuz" => "https://dl-cdn.alpinelinux.org/alpine/latest-stable/releases/aarch64/netboot/vmlinuz-lts",
+                "dragonfly-agent/aarch64/initramfs-lts" => "https://dl-cdn.alpinelinux.org/alpine/latest-stable/releases/aarch64/netboot/initramfs-lts",
+                "dragonfly-agent/aarch64/modloop" => "https://dl-cdn.alpinelinux.org/alpine/latest-stable/releases/aarch64/netboot/modloop-lts",
+                // iPXE bootloaders for UEFI HTTP boot and DHCP servers other than Smee
+                "bootloader/undionly.kpxe" => "https://boot.ipxe.org/undionly.kpxe",
+                "bootloader/ipxe.efi" => "https://boot.ipxe.org/ipxe.efi",
+                "bootloader/arm64/ipxe.efi" => "https://boot.ipxe.org/arm64-efi/ipxe.efi",
+                // Signed GRUB network images for the Secure Boot chain. Shim isn't published on its own,
+                // so shimx64.efi/shimaa64.efi must be placed in the artifact directory from a distro's shim-signed package.
+                "bootloader/secureboot/grubx64.efi" => "http://archive.ubuntu.com/ubuntu/dists/noble/main/uefi/grub2-amd64/current/grubnetx64.efi.signed",
+                "bootloader/secureboot/grubaa64.efi" => "http://ports.ubuntu.com/ubuntu-ports/dists/noble/main/uefi/grub2-arm64/current/grubnetaa64.efi.signed",
+                // Ubuntu 22.04
+                "ubuntu/jammy-server-cloudimg-amd64.img" => "https://cloud-images.ubuntu.com/jammy/current/jammy-server-cloudimg-amd64.img",
+                "ubuntu/jammy-server-cloudimg-arm64.img" => "https://cloud-images.ubuntu.com/jammy/current/jammy-server-cloudimg-arm64.img",
+                // Ubuntu 24.04
+                "ubuntu/noble-server-cloudimg-amd64.img" => "https://cloud-images.ubuntu.com/noble/current/noble-server-cloudimg-amd64.img",
+                "ubuntu/noble-server-cloudimg-arm64.img" => "https://cloud-images.ubuntu.com/noble/current/noble-server-cloudimg-arm64.img",
+                _ => {
+                    // If it wasn't an .ipxe script and not a known binary, it's unknown.
+                    warn!("Unknown artifact requested: {}", requested_path);
+                    return (StatusCode::NOT_FOUND, "Unknown iPXE artifact").into_response();
+                }
+            };
+            
+            // With object storage, copy the artifact into the bucket for next time and pass this
+            // download straight through rather than filling the local disk
+            if let Some(s3) = object_storage {
+                s3.mirror(remote_url, &requested_path);
+                return match reqwest::get(remote_url).await.and_then(|r| r.error_for_status()) {
+                    Ok(upstream) => proxy_response(upstream),
+                    Err(e) => {
+                        error!("Failed to stream artifact {}: {}", requested_path, e);
+                        (StatusCode::INTERNAL_SERVER_ERROR, format!("Error streaming artifact: {}", e)).into_response()
+                    }
+                };
+            }
+
+            // Use the efficient streaming download with caching for known artifacts
+            // Use artifact_path (full path) for caching
+            match stream_download_with_caching(
+                remote_url, 
+                &artifact_path, 
+                headers.get(axum::http::header::RANGE),
+                machine_id, // Pass the machine_id found via IP lookup
+                Some(&state)
+            ).await {
+                Ok((stream, content_length, content_range)) => {
+                    info!("Streaming artifact {} from remote source", requested_path);
+                    return create_streaming_response(stream, "application/octet-stream", content_length, content_range);
+                },
+                Err(e) => {
+                    error!("Failed to stream artifact {}: {}", requested_path, e);
+                    return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error streaming artifact: {}", e)).into_response();
+                }
+            }
+        }
+
+        // If code reaches here, it means an IPXE script was requested but generate_ipxe_script 
+        // returned NotFound, so return 404.
+        (StatusCode::NOT_FOUND, "Unknown or Ungeneratable IPXE Script").into_response()
+    }
+}
+
+// Serve an artifact held in object storage: large ones by redirecting to a signed URL, small ones by
+// proxying. None when the bucket doesn't have it (or can't be reached), so the caller falls back to upstream.
+async fn serve_from_object_storage(s3: &S3Storage, path: &str, range: Option<&str>) -> Option<Response> {
+    let size = match s3.object_size(path).await {
+        Ok(Some(size)) => size,
+        Ok(None) => return None,
+        Err(e) => {
+            warn!("Object storage lookup for {} failed, falling back: {}", path, e);
+            return None;
+        }
+    };
+
+    if size >= s3.redirect_min_bytes {
+        return match s3.presign("GET", path, Utc::now()) {
+            Ok(url) => {
+                info!("Redirecting artifact {} to object storage", path);
+                Some(Redirect::temporary(&url).into_response())
+            },
+            Err(e) => {
+                error!("Failed to sign object storage URL for {}: {}", path, e);
+                None
+            }
+        };
+    }
+    match s3.fetch(path, range).await {
+        Ok(response) => Some(proxy_response(response)),
+        Err(e) => {
+            warn!("Object storage fetch for {} failed, falling back: {}", path, e);
+            None
+        }
+    }
+}
+
+// Pass a remote response through to the client, keeping its status and size headers
+fn proxy_response(upstream: reqwest::Response) -> Response {
+    let mut builder = Response::builder().status(upstream.status().as_u16());
+    for name in [axum::http::header::CONTENT_TYPE, axum::http::header::CONTENT_LENGTH, axum::http::header::CONTENT_RANGE, axum::http::header::ACCEPT_RANGES] {
+        if let Some(value) = upstream.headers().get(name.as_str()) {
+            builder = builder.header(name, value.as_bytes());
+        }
+    }
+    builder
+        .body(Body::from_stream(upstream.bytes_stream()))
+        .unwrap_or_else(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to build response").into_response())
+}
+
+// Add this function after parse_range_header
+// Helper function to track and report image download progress
+async fn track_download_progress(
+    machine_id: Option<Uuid>, 
+    bytes_downloaded: u64, 
+    total_size: u64,
+    state: AppState // Changed from Option<&AppState> to AppState
+) {
+    info!(
+        machine_id = ?machine_id, 
+        bytes_downloaded = bytes_downloaded, 
+        total_size = total_size, 
+        "[PROGRESS_TRACK] CALLED track_download_progress with values: bytes_downloaded={}, total_size={}, machine_id={:?}",
+        bytes_downloaded, total_size, machine_id
+    );
+
+    debug!(
+        machine_id = ?machine_id, 
+        bytes_downloaded = bytes_downloaded, 
+        total_size = total_size, 
+        "[PROGRESS_DEBUG] Entering track_download_progress"
+    );
+
+    if total_size == 0 {
+        // Changed to INFO
+        info!("[PROGRESS_DEBUG] Exiting track_download_progress early: total_size is 0");
+        return; // Skip progress for zero-sized files
+    }
+    
+    let progress_float = (bytes_downloaded as f64 / total_size as f64) * 100.0;
+    let task_name = "stream image"; // TODO: Can we get the actual filename here?
+    
+    // If we have a machine ID, send task-specific event
+    if let Some(id) = machine_id {
+        debug!(machine_id = %id, progress = progress_float, task_name = task_name, "Updating DB progress");
+        // Update the machine's task progress in DB
+        if let Err(e) = db::update_installation_progress(
+            &id,
+            progress_float.min(100.0) as u8, // Convert to u8 for DB, clamped at 100
+            Some(task_name)
+        ).await {
+            warn!(machine_id = %id, error = %e, "Failed to update download progress in DB");
+        }
+        
+        // For real-time UI updates, emit a more detailed event with floating point precision
+        let task_progress_event = format!(
+            "task_progress:{}:{}:{:.3}:{}:{}",
+            id,                   // Machine ID
+            task_name,            // Task name 
+            progress_float,       // Floating point percentage (with 3 decimal precision)
+            bytes_downloaded,     // Current bytes
+            total_size            // Total bytes
+        );
+        
+        debug!(machine_id = %id, event = %task_progress_event, "Attempting to send task_progress event");
+        // Emit the detailed task progress event
+        if let Err(e) = state.event_manager.send(task_progress_event.clone()) { // Clone for logging
+            warn!(machine_id = %id, error = %e, "Failed to emit task_progress event: {}", task_progress_event);
+        }
+        
+        // Also emit standard machine updated event for compatibility
+        // debug!(machine_id = %id, "Sending generic machine_updated event");
+        // let _ = state.event_manager.send(format!("machine_updated:{}", id));
+    }
+    
+    // Also send IP-based progress event for any HTTP requests
+    let client_ip_guard = state.client_ip.lock().await;
+    if let Some(client_ip) = client_ip_guard.as_ref() {
+        // Find machine by IP if possible (for cases where we don't have machine_id)
+        let ip_machine_id = if machine_id.is_none() {
+            match db::get_machine_by_ip(client_ip).await {
+                Ok(Some(machine)) => Some(machine.id),
+                _ => None,
+            }
+        } else {
+            machine_id
+        };
+        
+        // Emit IP-based progress event
+        let ip_progress_event_payload = serde_json::json!({ 
+            "ip": client_ip,
+            "progress": progress_float, // Send float
+            "bytes_downloaded": bytes_downloaded,
+            "total_size": total_size,
+            "file_name": task_name, // Still uses hardcoded "Stream image"
+            "machine_id": ip_machine_id
+        });
+
+        // Construct the event string
+        let ip_progress_event_string = format!("ip_download_progress:{}", ip_progress_event_payload.to_string());
+
+        info!(client_ip = %client_ip, event_payload = %ip_progress_event_payload, "[PROGRESS_SEND] Attempting to send ip_download_progress event NOW"); // ADDED LOUD LOG
+        let send_result = state.event_manager.send(ip_progress_event_string.clone()); // Clone for logging
+        
+        if let Err(e) = send_result {
+            warn!(client_ip = %client_ip, error = %e, "[PROGRESS_SEND] Failed to emit IP-based progress event: {}", ip_progress_event_string);
+        } else {
+            info!(client_ip = %client_ip, event_payload = %ip_progress_event_payload, "[PROGRESS_SEND] Successfully sent ip_download_progress event"); // ADDED SUCCESS LOG
+        }
+    } // End of: if let Some(client_ip) = client_ip_guard.as_ref()
+    
+    debug!("Exiting track_download_progress");
+}
+
+// Modify stream_download_with_caching to track progress
+async fn stream_download_with_caching(
+    url: &str,
+    cache_path: &StdPath,
+    range_header: Option<&HeaderValue>, // Add parameter for Range header
+    machine_id: Option<Uuid>, // Add optional machine ID for tracking
+    state: Option<&AppState>, // Add optional state for event emission
+) -> Result<(ReceiverStream<Result<Bytes, Error>>, Option<u64>, Option<String>), Error> { // Return Content-Range
+    info!("[STREAM_DOWNLOAD] Beginning stream_download_with_caching for URL: {}, cache_path: {}, range: {:?}, machine_id: {:?}",
+          url, cache_path.display(), range_header.map(|h| h.to_str().unwrap_or("invalid")), machine_id);
+
+    // Create parent directory if needed
+    if let Some(parent) = cache_path.parent() {
+        fs::create_dir_all(parent).await.map_err(|e| Error::Internal(format!("Failed to create directory: {}", e)))?;
+    }
+
+    // Check if file is already cached
+    if cache_path.exists() {
+        // Even when serving from cache, track progress for range requests
+        if let (Some(machine_id), Some(state), Some(range_val)) = (machine_id, state, range_header) {
+            if let Ok(range_str) = range_val.to_str() {
+                let file_size = fs::metadata(cache_path).await
+                    .map(|m| m.len())
+                    .unwrap_or(0);
+                    
+                if let Some((start, end)) = parse_range_header(range_str, file_size, None, Some(state)).await {
+                    let bytes_downloaded = end - start + 1;
+                    
+                    // Use the start position as a progress indicator for range requests
+                    // This gives a rough approximation of download progress across multiple range requests
+                    let effective_progress = start + bytes_downloaded;
+                    
+                    info!("[RANGE_PROGRESS] Cached file with range: start={}, end={}, bytes={}, total={}, effective_progress={}",
+                          start, end, bytes_downloaded, file_size, effective_progress);
+                          
+                    // Track download progress with the effective bytes downloaded
+                    tokio::spawn(track_download_progress(Some(machine_id), effective_progress, file_size, state.clone()));
+                }
+            }
+        }
+        
+        // info!("Serving cached artifact from: {:?}", cache_path); // Commented out log
+        return read_file_as_stream(cache_path, range_header, state, machine_id).await; // Pass Range header
+    }
+    
+    info!("Downloading and caching artifact from: {}", url);
+    
+    // Start HTTP request with reqwest feature for streaming
+    let client = reqwest::Client::new();
+    let response = client.get(url).send().await.map_err(|e| Error::Internal(format!("HTTP request failed: {}", e)))?;
+    
+    if !response.status().is_success() {
+        return Err(Error::Internal(format!("HTTP error: {}", response.status())));
+    }
+    
+    // Get content length if available
+    let content_length = response.content_length();
+    if let Some(length) = content_length {
+        info!("[PROGRESS_DEBUG] Download size from Content-Length: {} bytes", length);
+    } else {
+        info!("[PROGRESS_DEBUG] No Content-Length header received from remote server.");
+    }
+    
+    let file = fs::File::create(cache_path).await.map_err(|e| Error::Internal(format!("Failed to create cache file: {}", e)))?;
+    let file = Arc::new(tokio::sync::Mutex::new(file));
+    let (tx, rx) = mpsc::channel::<Result<Bytes, Error>>(32);
+    
+    let url_clone = url.to_string();
+    let cache_path_clone = cache_path.to_path_buf();
+    
+    // For tracking download progress
+    let total_size = content_length.unwrap_or(0);
+    let mut total_bytes_downloaded: u64 = 0;
+    let tracking_machine_id = machine_id;
+    let app_state_clone = state.cloned();
+    
+    tokio::spawn(async move {
+        let mut client_disconnected = false;
+        let mut download_error = false;
+
+        // Get the stream. `bytes_stream` consumes the response object.
+        let mut stream = response.bytes_stream(); 
+
+        while let Some(chunk_result) = stream.next().await {
+            match chunk_result {
+                Ok(chunk) => {
+                    let chunk_clone = chunk.clone();
+                    let chunk_size = chunk.len() as u64;
+                    
+                    // Write chunk to cache file concurrently
+                    let file_clone = Arc::clone(&file);
+                    let write_handle = tokio::spawn(async move {
+                        let mut file = file_clone.lock().await;
+                        file.write_all(&chunk_clone).await
+                    });
+
+                    // Update progress tracking
+                    total_bytes_downloaded += chunk_size;
+                    
+                    // ADDED LOG: Log chunk size and total downloaded
+                    debug!(url = %url_clone, chunk_size = chunk_size, total_bytes_downloaded = total_bytes_downloaded, total_size = total_size, "[STREAM_DOWNLOAD_LOOP] Downloaded chunk");
+
+                    if let (Some(machine_id), Some(state)) = (tracking_machine_id, &app_state_clone) {
+                        if total_size > 0 {
+                            // ADDED LOG: Confirm call to track_download_progress
+                            debug!("[PROGRESS_DEBUG] Calling track_download_progress (machine_id: {}, downloaded: {}, total: {})", machine_id, total_bytes_downloaded, total_size);
+                            
+                            // ADDED LOG: Log before calling track_download_progress function
+                            debug!(url = %url_clone, machine_id = %machine_id, bytes_downloaded = total_bytes_downloaded, total_size = total_size, "[STREAM_DOWNLOAD_LOOP] PRE-PROGRESS CALL");
+                            
+                            track_download_progress(Some(machine_id), total_bytes_downloaded, total_size, state.clone()).await;
+                        }
+                    }
+                    
+                    // Attempt to send to client only if not already disconnected
+                    if !client_disconnected {
+                        if tx.send(Ok(chunk)).await.is_err() {
+                            warn!("Client stream receiver dropped for {}. Continuing download in background.", url_clone);
+                            client_disconnected = true;
+                            // DO NOT break here - let download continue for caching
+                        }
+                    }
+
+                    // Await the write operation regardless of client connection status
+                    match write_handle.await { // Await the JoinHandle itself
+                        Ok(Ok(())) => {
+                            // Write successful, continue loop
+                        },
+                        Ok(Err(e)) => {
+                            // Write operation failed
+                            warn!("Failed to write chunk to cache file {}: {}", cache_path_clone.display(), e);
+                            download_error = true;
+                            break; // Abort download if we can't write to cache
+                        },
+                        Err(e) => {
+                            // Task failed (e.g., panicked)
+                            warn!("Cache write task failed (join error) for {}: {}", cache_path_clone.display(), e);
+                            download_error = true;
+                            break; // Abort download if write task fails
+                        }
+                    }
+                },
+                Err(e) => { // e is reqwest::Error here
+                    error!("Download stream error for {}: {}", url_clone, e);
+                    // Send error to client if still connected
+                    if !client_disconnected {
+                        let err = Error::Internal(format!("Download stream error: {}", e));
+                        if tx.send(Err(err)).await.is_err() {
+                             warn!("Client stream receiver dropped while sending download error for {}", url_clone);
+                             // Client disconnected while we were trying to send an error
+                             client_disconnected = true;
+                        }
+                    }
+                    download_error = true;
+                    break; // Stop processing on download error
+                }
+            }
+            // If download_error is true, the inner match already broke, so we'll exit.
+        }
+        
+        // Explicitly drop the response stream to release network resources potentially sooner
+        drop(stream);
+
+        // Report final progress on successful download
+        if !download_error && total_size > 0 {
+            if let (Some(machine_id), Some(state)) = (tracking_machine_id, &app_state_clone) {
+                track_download_progress(Some(machine_id), total_size, total_size, state.clone()).await;
+            }
+        }
+
+        // Ensure file is flushed and closed first
+        if let Ok(mut file) = Arc::try_unwrap(file).map_err(|_| "Failed to unwrap Arc").and_then(|mutex| Ok(mutex.into_inner())) {
+            if let Err(e) = file.flush().await {
+                warn!("Failed to flush cache file {}: {}", cache_path_clone.display(), e);
+            }
+            // File is closed when it goes out of scope here
+        }
+        
+        // Only send EOF signal if the download completed without error AND the client is still connected
+        if !download_error && !client_disconnected {
+            info!("Download complete for {}, client still connected.", url_clone);
+            // Removed explicit EOF signal
+            // debug!("Sending EOF signal for {}", url_clone);
+            // let _ = tx.send(Ok(Bytes::new())).await;
+        } else if !download_error && client_disconnected {
+            info!("Download complete and cached for {} after client disconnected.", url_clone);
+        } else {
+            // An error occurred during download or caching
+            warn!("Download for {} did not complete successfully due to errors.", url_clone);
+            // Optionally remove the potentially incomplete cache file
+            // if let Err(e) = fs::remove_file(&cache_path_clone).await {
+            //     warn!("Failed to remove incomplete cache file {}: {}", cache_path_clone.display(), e);
+            // }
+        }
+    });
+    
+    // After download completes or if error, handle the stream
+    let (stream, content_length) = (tokio_stream::wrappers::ReceiverStream::new(rx), content_length);
+
+    // We cached the full file, but the *initial* request might have been a range request.
+    // If so, we need to read the *cached* file with range support now.
+    if range_header.is_some() {
+        info!("Download complete, now serving range request from cached file: {:?}", cache_path);
+        // Re-call read_file_as_stream with the range header on the now-cached file
+        read_file_as_stream(cache_path, range_header, state, machine_id).await // Pass machine_id here too
+    } else {
+        // No range requested initially, return the full stream we prepared during download
+        Ok((stream, content_length, None)) // No Content-Range for full file
+    }
+}
+
+// Helper to parse Range header. Returns (start, end)
+async fn parse_range_header(
+    range_str: &str,
+    total_size: u64,
+    _file_name: Option<&str>, // Marked unused, event logic removed
+    _state: Option<&AppState>, // Marked unused, event logic removed
+) -> Option<(u64, u64)> {
+    if !range_str.starts_with("bytes=") {
+        return None;
+    }
+    let range_val = &range_str[6..]; // Skip "bytes="
+    let parts: Vec<&str> = range_val.split('-').collect();
+    if parts.len() != 2 {
+        return None;
+    }
+
+    let start_str = parts[0].trim();
+    let end_str = parts[1].trim();
+
+    let start = if start_str.is_empty() {
+        // Suffix range: "-<length>"
+        if end_str.is_empty() { return None; } // Invalid: "-"
+        let suffix_len = end_str.parse::<u64>().ok()?;
+        if suffix_len >= total_size { 0 } else { total_size - suffix_len }
+    } else {
+        // Normal range: "start-" or "start-end"
+        start_str.parse::<u64>().ok()?
+    };
+
+    let end = if end_str.is_empty() {
+        // Range "start-" means start to end of file
+        total_size.saturating_sub(1)
+    } else {
+        // Range "start-end"
+        end_str.parse::<u64>().ok()?
+    };
+
+    // Validate range: start <= end < total_size
+    if start > end || end >= total_size {
+        warn!("Invalid range request: start={}, end={}, total_size={}", start, end, total_size);
+        return None;
+    }
+
+    // Optional: Emit progress event for the range being served
+    // if let Some(s) = state { // Check if state exists before trying to use it
+    //     let bytes_downloaded = end - start + 1;
+    //     let event_data = serde_json::json!({
+    //         "progress": 100.0, // A single range request is considered 100% of that range
+    //         "bytes_downloaded": bytes_downloaded,
+    //         "total_size": total_size,
+    //         "file_name": file_name.unwrap_or("unknown")
+    //     }).to_string();
+
+    //     // Prefer emitting IP-based progress if possible
+    //     let client_ip_guard = s.client_ip.lock().await;
+    //     if let Some(client_ip) = client_ip_guard.as_ref() {
+    //          let ip_progress_event = format!("ip_download_progress:{{ \"ip\": \"{}\", {} }}", client_ip, &event_data[1..]); // Construct JSON manually
+    //          // info!("Sending event: {}", ip_progress_event); // Commented out log
+    //          let _ = s.event_manager.send(ip_progress_event);
+    //     } else if let Some(f_name) = file_name {
+    //         // Fallback to file-based progress if IP is unavailable
+    //         let file_progress_event = format!("file_progress:{}:{}:{}", f_name, 100.0, event_data);
+    //         let _ = s.event_manager.send(file_progress_event);
+    //     }
+    // }
+
+    Some((start, end))
+}
+
+// Restore original function name and intended purpose (returning HTML partial)
+pub async fn get_workflow_progress(
+    State(app_state): State<AppState>, // Add AppState
+    Path(id): Path<Uuid>
+) -> Response { 
+    info!("Request for workflow progress HTML partial for machine {}", id);
+
+    let machine = match crate::demo::machine_by_id(&id).await {
+        Ok(Some(m)) => m,
+        Ok(None) => {
+            error!("Machine not found: {}", id);
+            return (StatusCode::NOT_FOUND, Html("<div>Machine not found</div>")).into_response();
+        },
+        Err(e) => {
+            error!("Error fetching machine {}: {}", id, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Html("<div>Database error</div>")).into_response();
+        }
+    };
+
+    if machine.status != MachineStatus::InstallingOS {
+        info!("Machine {} is not installing OS, status: {:?}", id, machine.status);
+        return (StatusCode::OK, Html("<div></div>")).into_response(); // Return empty div if not installing
+    }
+
+    match crate::tinkerbell::get_workflow_info(&machine).await {
+        Ok(Some(info)) => {
+            info!("Successfully got workflow info for machine {}: state={}, progress={}", id, info.state, info.progress);
+
+            // Create the context struct (as defined in ui.rs)
+            let context = ui::WorkflowProgressTemplate { 
+                machine_id: id,
+                workflow_info: info,
+            };
+            
+            // Render the partial using MiniJinja
+            ui::render_minijinja(&app_state, "partials/workflow_progress.html", context)
+        },
+        Ok(None) => {
+            info!("No workflow found for machine {}", id);
+            (StatusCode::OK, Html("<div></div>")).into_response()
+        },
+        Err(e) => {
+            error!("Error fetching workflow for machine {}: {}", id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Html("<div>Error fetching workflow</div>")).into_response()
+        }
+    }
+}
+
+// ... (rest of api.rs) ...
+
+// Stub for heartbeat
+pub async fn heartbeat() -> Response {
+    (StatusCode::OK, "OK").into_response()
+}
+
+// Add stubs for functions called from mode.rs
+pub async fn check_hookos_artifacts() -> bool {
+    // Check for the following four files
+    let files = vec![
+        "vmlinuz-latest-lts-x86_64",
+        "initramfs-latest-lts-x86_64",
+        "vmlinuz-latest-lts-aarch64",
+        "initramfs-latest-lts-aarch64",
+        "dtbs-latest-lts-aarch64.tar.gz",
+        "vmlinuz-x86_64",
+        "initramfs-x86_64",
+        "vmlinuz-aarch64",
+        "initramfs-aarch64",
+        "dtbs-aarch64.tar.gz",
+    ];
+
+    for file in files {
+        let path = FilePath::new("/var/lib/dragonfly/ipxe-artifacts/hookos").join(file);
+        if !path.exists() {
+            return false;
+        }
+    }
+
+    info!("All HookOS artifacts found");
+    true
+}
+
+pub async fn download_hookos_artifacts(version: &str) -> anyhow::Result<()> {
+    // Create directory structure if it doesn't exist
+    let hookos_dir = FilePath::new("/var/lib/dragonfly/ipxe-artifacts/hookos");
+    if !hookos_dir.exists() {
+        info!("Creating directory structure: {:?}", hookos_dir);
+        std::fs::create_dir_all(hookos_dir)?;
+    }
+    
+    // Download checksum file
+    let checksum_url = format!("https://github.com/tinkerbell/hook/releases/download/{}/checksum.txt", version);
+    let checksum_path = hookos_dir.join("checksum.txt");
+    let checksum_response = reqwest::get(checksum_url).await?;
+    let checksum_content = checksum_response.text().await?;
+    std::fs::write(checksum_path, checksum_content)?;
+
+    // Files to download
+    let files = vec![
+        "hook_x86_64.tar.gz",
+        "hook_aarch64.tar.gz",
+        "hook_latest-lts-x86_64.tar.gz",
+        "hook_latest-lts-aarch64.tar.gz",
+    ];
+
+    // Create a vector of download futures
+    let download_futures = files.iter().map(|file| {
+        let file = file.to_string();
+        let version = version.to_string();
+        let hookos_dir = hookos_dir.to_path_buf();
+        
+        // Return a future for each download
+        async move {
+            let url = format!("https://github.com/tinkerbell/hook/releases/download/{}/{}", version, file);
+            info!("Downloading {} in parallel", url);
+            let response = reqwest::get(&url).await?;
+            let content = response.bytes().await?;
+            let tarball_path = hookos_dir.join(&file);
+            std::fs::write(&tarball_path, content)?;
+            info!("Downloaded {} to {:?}", file, tarball_path);
+            Ok::<_, anyhow::Error>(tarball_path)
+        }
+    }).collect::<Vec<_>>();
+    
+    // Execute all downloads in parallel
+    let download_results = futures::future::try_join_all(download_futures).await?;
+    info!("All HookOS artifacts downloaded in parallel successfully");
+
+    // Create a vector of extraction futures
+    let extraction_futures = download_results.into_iter().map(|tarball_path| {
+        let hookos_dir = hookos_dir.to_path_buf();
+        
+        // Return a future for each extraction
+        async move {
+            let file_name = tarball_path.file_name().unwrap().to_string_lossy().to_string();
+            info!("Extracting {:?} in parallel", tarball_path);
+            
+            // Check if the file exists and has content before trying to extract
+            let metadata = match std::fs::metadata(&tarball_path) {
+                Ok(meta) => meta,
+                Err(e) => {
+                    warn!("Skipping extraction of {:?}: file not accessible: {}", tarball_path, e);
+                    return Ok::<_, anyhow::Error>(tarball_path);
+                }
+            };
+            
+            if metadata.len() == 0 {
+                warn!("Skipping extraction of {:?}: file is empty", tarball_path);
+                return Ok::<_, anyhow::Error>(tarball_path);
+            }
+            
+            // Open the file for reading
+            let tar_file = match File::open(&tarball_path) {
+                Ok(f) => f,
+                Err(e) => {
+                    warn!("Failed to open {:?} for extraction: {}", tarball_path, e);
+                    return Ok::<_, anyhow::Error>(tarball_path);
+                }
+            };
+            
+            // Create the archive and extract, handling any errors
+            // Check if the file is a .tar.gz file
+            if file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz") {
+                // Use GzDecoder for gzipped files
+                let gz = GzDecoder::new(tar_file);
+                let mut archive = Archive::new(gz);
+                match archive.unpack(&hookos_dir) {
+                    Ok(_) => info!("Successfully extracted gzipped archive {:?}", tarball_path),
+                    Err(e) => warn!("Failed to extract gzipped archive {:?}: {}", tarball_path, e),
+                }
+            } else {
+                // For non-gzipped files, use directly
+                let mut archive = Archive::new(tar_file);
+                match archive.unpack(&hookos_dir) {
+                    Ok(_) => info!("Successfully extracted archive {:?}", tarball_path),
+                    Err(e) => warn!("Failed to extract archive {:?}: {}", tarball_path, e),
+                }
+            }
+            
+            Ok::<_, anyhow::Error>(tarball_path)
+        }
+    }).collect::<Vec<_>>();
+    
+    // Execute all extractions in parallel
+    let extraction_results = futures::future::try_join_all(extraction_futures).await?;
+    info!("All HookOS artifacts extracted in parallel successfully");
+    
+    // Remove all tarballs in parallel
+    let cleanup_futures = extraction_results.into_iter().map(|tarball_path| {
+        async move {
+            // Remove the tarball after extraction
+            if let Err(e) = std::fs::remove_file(&tarball_path) {
+                warn!("Failed to remove tarball {:?}: {}", tarball_path, e);
+            } else {
+                info!("Removed tarball {:?}", tarball_path);
+            }
+            Ok::<(), anyhow::Error>(())
+        }
+    }).collect::<Vec<_>>();
+    
+    // Execute all cleanup operations in parallel
+    futures::future::try_join_all(cleanup_futures).await?;
+    
+    info!("HookOS artifacts downloaded, extracted, and cleaned up successfully to {:?}", hookos_dir);
+    Ok(())
+}
+
+// OS information struct
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct OsInfo {
+    pub name: String,
+    pub icon: String,
+}
+
+// Get OS icon for a specific OS
+pub fn get_os_icon(os: &str) -> String {
+    match os {
+        "ubuntu-2204" | "ubuntu-2404" => "<i class=\"fab fa-ubuntu text-orange-500 dark:text-orange-500 no-invert\"></i>",
+        "debian-12" => "<i class=\"fab fa-debian text-red-500\"></i>",
+        "proxmox" => "<i class=\"fas fa-server text-blue-500\"></i>",
+        "talos" => "<i class=\"fas fa-robot text-purple-500\"></i>",
+        "windows" => "<i class=\"fab fa-windows text-blue-400\"></i>",
+        "rocky" | "rocky-9" => "<i class=\"fas fa-mountain text-green-500\"></i>",
+        "fedora" => "<i class=\"fab fa-fedora text-blue-600\"></i>",
+        "alma" | "almalinux" => "<i class=\"fas fa-hat-cowboy text-amber-600\"></i>",
+        _ => "<i class=\"fas fa-square-question text-gray-500\"></i>", // Unknown OS
+    }.to_string()
+}
+
+// Make format_os_name public
+pub fn format_os_name(os: &str) -> String {
+    match os {
+        "ubuntu-2204" => "Ubuntu 22.04",
+        "ubuntu-2404" => "Ubuntu 24.04",
+        "debian-12" => "Debian 12",
+        "proxmox" => "Proxmox VE",
+        "talos" => "Talos",
+        _ => os, // Return original string if no match
+    }.to_string()
+}
+
+// Get both OS name and icon
+pub fn get_os_info(os: &str) -> OsInfo {
+    OsInfo {
+        name: format_os_name(os),
+        icon: get_os_icon(os),
+    }
+}
+
+// Anonymized community defaults baked into the crate (relative weights), used to
+// guide new operators before their own fleet has any history.
+// Set DRAGONFLY_COMMUNITY_DEFAULTS=false to ignore them.
+const COMMUNITY_TEMPLATE_WEIGHTS: &[(&str, u32)] = &[
+    ("ubuntu-2204", 40),
+    ("ubuntu-2404", 30),
+    ("debian-12", 15),
+    ("proxmox", 10),
+    ("talos", 5),
+];
+
+// Popularity information for an OS template
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TemplatePopularity {
+    pub template: String,
+    pub name: String,
+    pub usage_count: i64,
+    pub community_weight: u32,
+}
+
+// Rank the known OS templates by fleet usage, falling back to community defaults
+pub async fn get_template_popularity() -> Vec<TemplatePopularity> {
+    let use_community_defaults = env::var("DRAGONFLY_COMMUNITY_DEFAULTS")
+        .map(|v| v != "false" && v != "0")
+        .unwrap_or(true);
+    
+    let usage: HashMap<String, i64> = match db::get_template_usage().await {
+        Ok(usage) => usage.into_iter().collect(),
+        Err(e) => {
+            warn!("Failed to load template usage counters: {}", e);
+            HashMap::new()
+        }
+    };
+    rank_templates(&usage, use_community_defaults)
+}
+
+fn rank_templates(usage: &HashMap<String, i64>, use_community_defaults: bool) -> Vec<TemplatePopularity> {
+    let mut templates: Vec<TemplatePopularity> = COMMUNITY_TEMPLATE_WEIGHTS.iter()
+        .map(|(template, weight)| TemplatePopularity {
+            template: template.to_string(),
+            name: format_os_name(template),
+            usage_count: usage.get(*template).copied().unwrap_or(0),
+            community_weight: if use_community_defaults { *weight } else { 0 },
+        })
+        .collect();
+    
+    // Include any other templates the fleet has used
+    for (template, count) in usage {
+        if !templates.iter().any(|t| &t.template == template) {
+            templates.push(TemplatePopularity {
+                template: template.clone(),
+                name: format_os_name(template),
+                usage_count: *count,
+                community_weight: 0,
+            });
+        }
+    }
+    
+    templates.sort_by(|a, b| b.usage_count.cmp(&a.usage_count)
+        .then(b.community_weight.cmp(&a.community_weight))
+        .then(a.template.cmp(&b.template)));
+    templates
+}
+
+// Handler for OS template usage statistics (JSON, or an HTML hint for HTMX)
+async fn get_template_stats(headers: HeaderMap) -> Response {
+    let popularity = get_template_popularity().await;
+    
+    if headers.get("HX-Request").is_some() {
+        let total: i64 = popularity.iter().map(|t| t.usage_count).sum();
+        let html = match popularity.first() {
+            Some(top) if total > 0 => format!(
+                r#"<p class="text-sm text-gray-500 dark:text-gray-400 ml-36">Most used in your fleet: <span class="font-medium">{}</span> ({} of {} assignments)</p>"#,
+                top.name, top.usage_count, total
+            ),
+            Some(top) if top.community_weight > 0 => format!(
+                r#"<p class="text-sm text-gray-500 dark:text-gray-400 ml-36">No assignments yet. A common starting point is <span class="font-medium">{}</span>.</p>"#,
+                top.name
+            ),
+            _ => String::new(),
+        };
+        return Html(html).into_response();
+    }
+    
+    (StatusCode::OK, Json(json!({ "templates": popularity }))).into_response()
+}
+
+async fn update_installation_progress(
+    State(state): State<AppState>, // State is used for event manager
+    _auth_session: AuthSession, // Mark as unused - updates come from agent/tinkerbell
+    Path(id): Path<Uuid>,
+    Json(payload): Json<InstallationProgressUpdateRequest>,
+) -> Response {
+    // Remove admin check - allow agent/tinkerbell to post updates
+    /*
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    */
+
+    info!("Updating installation progress for machine {} to {}% (step: {:?})",
+          id, payload.progress, payload.step);
+
+    match db::update_installation_progress(&id, payload.progress, payload.step.as_deref()).await {
+        Ok(true) => {
+            // Emit machine updated event so the UI fetches new progress HTML
+            let _ = state.event_manager.send(format!("machine_updated:{}", id));
+            (StatusCode::OK, Json(json!({ "status": "progress_updated", "machine_id": id }))).into_response()
+        },
+        Ok(false) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Machine with ID {} not found", id),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to update installation progress for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Add new handler for getting machine tags
+#[axum::debug_handler]
+async fn api_get_machine_tags(
+    Path(id): Path<Uuid>,
+) -> Response {
+    match get_machine_tags(&id).await {
+        Ok(tags) => (StatusCode::OK, Json(tags)).into_response(),
+        Err(e) => {
+            error!("Failed to get tags for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to retrieve tags: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Add new handler for updating machine tags
+#[axum::debug_handler]
+async fn api_update_machine_tags(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Json(tags): Json<Vec<String>>,
+) -> Response {
+    // Check the caller's permissions on this machine
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Operate).await {
+        return response;
+    }
+
+    match db_update_machine_tags(&id, &tags).await {
+        Ok(true) => {
+            // Emit machine updated event
+            let _ = state.event_manager.send(format!("machine_updated:{}", id)); 
+            (StatusCode::OK, Json(json!({ "success": true, "message": "Tags updated" }))).into_response()
+        }
+                    Ok(false) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Machine with ID {} not found", id),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        }
+                Err(e) => {
+            error!("Failed to update tags for machine {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to update tags: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Prometheus scrape endpoint
+async fn get_metrics() -> Response {
+    match crate::observability::render_metrics().await {
+        Ok(body) => (StatusCode::OK, [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response(),
+        Err(e) => {
+            error!("Failed to render metrics: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to render metrics: {}", e)).into_response()
+        }
+    }
+}
+
+// Alert rules, scrape config and Grafana dashboard for monitoring this server, in one download
+async fn get_observability_bundle() -> Response {
+    let base_url = env::var("DRAGONFLY_BASE_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let bundle = crate::observability::prometheus_rules()
+        .and_then(|rules| Ok((rules, crate::observability::scrape_config(&base_url)?)));
+
+    match bundle {
+        Ok((rules, scrape)) => {
+            let metrics: Vec<_> = crate::observability::METRICS.iter()
+                .map(|m| json!({ "name": m.name, "type": m.kind, "help": m.help }))
+                .collect();
+            (StatusCode::OK, [(axum::http::header::CONTENT_DISPOSITION, "attachment; filename=\"dragonfly-observability.json\"")], Json(json!({
+                "metrics": metrics,
+                "files": {
+                    "prometheus/dragonfly-scrape.yml": scrape,
+                    "prometheus/dragonfly-alerts.yml": rules,
+                    "grafana/dashboards/dragonfly.json": crate::observability::grafana_dashboard(),
+                }
+            }))).into_response()
+        },
+        Err(e) => {
+            error!("Failed to build observability bundle: {}", e);
+            let error_response = ErrorResponse {
+                error: "Internal Error".to_string(),
+                message: format!("Failed to build observability bundle: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Aggregate counts for status pages and bots; like /metrics it exposes no per-machine detail
+async fn get_fleet_stats() -> Response {
+    match crate::observability::fleet_stats().await {
+        Ok(stats) => (StatusCode::OK, Json(stats)).into_response(),
+        Err(e) => {
+            error!("Failed to compute fleet stats: {}", e);
+            let error_response = ErrorResponse {
+                error: "Internal Error".to_string(),
+                message: format!("Failed to compute fleet stats: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Counts, current failures and installs in one small response for phone widgets and status boards.
+// Pollers send back the ETag in If-None-Match and get an empty 304 until something changes.
+// It names machines, so like GraphQL anyone but an admin only sees what an ACL lets them view.
+async fn get_fleet_summary(auth_session: AuthSession, headers: HeaderMap) -> Response {
+    let principal = (!crate::auth::is_admin(&auth_session)).then(|| policy::principal(&auth_session));
+    match crate::observability::fleet_summary(principal.as_deref()).await {
+        Ok(summary) => {
+            let etag = summary.etag();
+            let unchanged = headers.get(axum::http::header::IF_NONE_MATCH)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|tags| crate::resources::matches(tags, &etag));
+            let cache_headers = [
+                (axum::http::header::ETAG, etag),
+                (axum::http::header::CACHE_CONTROL, "no-cache".to_string()),
+            ];
+            if unchanged {
+                (StatusCode::NOT_MODIFIED, cache_headers).into_response()
+            } else {
+                (StatusCode::OK, cache_headers, Json(summary)).into_response()
+            }
+        }
+        Err(e) => {
+            error!("Failed to compute fleet summary: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Internal Error", format!("Failed to compute fleet summary: {}", e))
+        }
+    }
+}
+
+// Installs per day, median install time and failure rate by template, for the dashboard charts
+async fn get_provisioning_trends(Query(query): Query<HashMap<String, String>>) -> Response {
+    let days = query.get("days").and_then(|days| days.parse().ok()).unwrap_or(14);
+    match crate::observability::provisioning_trends(days).await {
+        Ok(trends) => (StatusCode::OK, Json(trends)).into_response(),
+        Err(e) => {
+            error!("Failed to compute provisioning trends: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Internal Error", format!("Failed to compute provisioning trends: {}", e))
+        }
+    }
+}
+
+// Pod status, versions and restarts of the Tinkerbell components; 503 while any of them is unhealthy
+async fn get_stack_health(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    let health = crate::status::stack_health().await;
+    let status = if health.healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(health)).into_response()
+}
+
+#[derive(Deserialize)]
+struct StackLogsQuery {
+    #[serde(default = "default_log_tail")]
+    tail: i64,
+}
+
+fn default_log_tail() -> i64 {
+    200
+}
+
+#[axum::debug_handler]
+async fn get_stack_logs(
+    auth_session: AuthSession,
+    Path(component): Path<String>,
+    Query(query): Query<StackLogsQuery>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if !crate::status::STACK_COMPONENTS.contains(&component.as_str()) {
+        let error_response = ErrorResponse {
+            error: "Not Found".to_string(),
+            message: format!("Unknown stack component '{}'", component),
+        };
+        return (StatusCode::NOT_FOUND, Json(error_response)).into_response();
+    }
+
+    match crate::status::component_logs(&component, query.tail.clamp(1, 5000)).await {
+        Ok(logs) => (StatusCode::OK, [(axum::http::header::CONTENT_TYPE, "text/plain; charset=utf-8")], logs).into_response(),
+        Err(e) => {
+            error!("Failed to read {} logs: {}", component, e);
+            let error_response = ErrorResponse {
+                error: "Kubernetes Error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::BAD_GATEWAY, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Slash commands from Slack or Mattermost. Replies always use status 200, since chat clients
+// show error statuses as a generic failure instead of the message.
+#[axum::debug_handler]
+async fn chatops_command(headers: HeaderMap, body: Bytes) -> Response {
+    let Some(command) = crate::chatops::SlashCommand::parse(&body) else {
+        let error_response = ErrorResponse {
+            error: "Bad Request".to_string(),
+            message: "Not a slash command request".to_string(),
+        };
+        return (StatusCode::BAD_REQUEST, Json(error_response)).into_response();
+    };
+    if let Err(reason) = crate::chatops::verify(&headers, &body, &command, Utc::now()) {
+        warn!("Rejected chat command from {} in {}: {}", command.user_name, command.channel_id, reason);
+        let error_response = ErrorResponse {
+            error: "Unauthorized".to_string(),
+            message: reason.to_string(),
+        };
+        return (StatusCode::UNAUTHORIZED, Json(error_response)).into_response();
+    }
+
+    info!("Chat command from {} in #{} ({}): {}", command.user_name, command.channel_name, command.channel_id, command.text);
+    (StatusCode::OK, Json(run_chat_command(&command).await)).into_response()
+}
+
+async fn run_chat_command(command: &crate::chatops::SlashCommand) -> serde_json::Value {
+    use crate::chatops::{self, Command};
+
+    // Resolve a machine and check the channel's grant on it
+    async fn authorized_machine(command: &chatops::SlashCommand, name: &str, required: Permission) -> Result<Machine, String> {
+        let machine = match chatops::find_machine(name).await {
+            Ok(Some(machine)) => machine,
+            Ok(None) => return Err(format!("No machine called `{}`", name)),
+            Err(e) => return Err(format!("Failed to look up machines: {}", e)),
+        };
+        match policy::check_principal(&command.principal(), &machine.id, required).await {
+            Ok(true) => Ok(machine),
+            Ok(false) => Err(format!("This channel needs {:?} permission on `{}`", required, name)),
+            Err(e) => Err(format!("Failed to evaluate permissions: {}", e)),
+        }
+    }
+
+    match chatops::parse_command(&command.text) {
+        Command::Help => chatops::ephemeral(chatops::HELP),
+        Command::Status => match chatops::fleet_summary().await {
+            Ok(text) => chatops::in_channel(text),
+            Err(e) => chatops::ephemeral(format!("Failed to compute fleet stats: {}", e)),
+        },
+        Command::Machine(name) => match authorized_machine(command, &name, Permission::View).await {
+            Ok(machine) => chatops::in_channel(chatops::describe_machine(&machine)),
+            Err(message) => chatops::ephemeral(message),
+        },
+        Command::Reimage { target, os } => {
+            let machine = match authorized_machine(command, &target, Permission::Operate).await {
+                Ok(machine) => machine,
+                Err(message) => return chatops::ephemeral(message),
+            };
+            let Some(os_choice) = os.or_else(|| machine.os_installed.clone()).or_else(|| machine.os_choice.clone()) else {
+                return chatops::ephemeral(format!("`{}` has no OS yet; say which one: `/dragonfly reimage {} <os>`", target, target));
+            };
+            let code = chatops::request_confirmation(command, &machine, &os_choice, Utc::now());
+            chatops::ephemeral(format!(
+                "This wipes *{}* and installs {}. To go ahead, run `/dragonfly confirm {}` in this channel within 5 minutes.",
+                machine.display_name(), os_choice, code
+            ))
+        },
+        Command::Confirm(code) => {
+            let Some(pending) = chatops::take_confirmation(command, &code, Utc::now()) else {
+                return chatops::ephemeral("That confirmation code is unknown or has expired");
+            };
+            // The grant may have been revoked while the confirmation was waiting
+            match policy::check_principal(&command.principal(), &pending.machine_id, Permission::Operate).await {
+                Ok(true) => {},
+                Ok(false) => return chatops::ephemeral(format!("This channel no longer has Operate permission on `{}`", pending.machine_name)),
+                Err(e) => return chatops::ephemeral(format!("Failed to evaluate permissions: {}", e)),
+            }
+            let cause = StatusCause::Admin(format!("{} via chat", command.user_name));
+            let response = assign_os_internal(pending.machine_id, pending.os_choice.clone(), cause).await;
+            if response.status().is_success() {
+                info!("Chat user {} reimaged machine {} with {}", command.user_name, pending.machine_id, pending.os_choice);
+                chatops::in_channel(format!("{} started reimaging *{}* with {}", command.user_name, pending.machine_name, pending.os_choice))
+            } else {
+                chatops::ephemeral(format!("Reimaging *{}* failed ({})", pending.machine_name, response.status()))
+            }
+        },
+    }
+}
+
+// The merged server configuration with the source of each value, for debugging odd deployments
+#[axum::debug_handler]
+async fn get_effective_config(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::config::effective().await {
+        Ok(config) => (StatusCode::OK, Json(config)).into_response(),
+        Err(e) => {
+            error!("Failed to build effective configuration: {}", e);
+            let error_response = ErrorResponse {
+                error: "Internal Error".to_string(),
+                message: format!("Failed to build effective configuration: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn list_acl_entries(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_acl_entries().await {
+        Ok(entries) => (StatusCode::OK, Json(entries)).into_response(),
+        Err(e) => {
+            error!("Failed to list ACL entries: {}", e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to list ACL entries: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn add_acl_entry(
+    auth_session: AuthSession,
+    Json(mut entry): Json<AclEntry>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    entry.principal = entry.principal.trim().to_string();
+    if entry.principal.is_empty() {
+        let error_response = ErrorResponse {
+            error: "Invalid ACL Entry".to_string(),
+            message: "principal must not be empty".to_string(),
+        };
+        return (StatusCode::BAD_REQUEST, Json(error_response)).into_response();
+    }
+
+    match db::add_acl_entry(&entry).await {
+        Ok(id) => {
+            entry.id = id;
+            (StatusCode::CREATED, Json(entry)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to add ACL entry: {}", e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to add ACL entry: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn delete_acl_entry(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::delete_acl_entry(id).await {
+        Ok(true) => (StatusCode::OK, Json(json!({ "success": true, "message": format!("ACL entry {} deleted", id) }))).into_response(),
+        Ok(false) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("ACL entry {} not found", id),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to delete ACL entry {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to delete ACL entry: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+fn validate_webhook(webhook: &mut WebhookEndpoint) -> Result<(), Response> {
+    webhook.name = webhook.name.trim().to_string();
+    let problem = if webhook.name.is_empty() {
+        Some("name must not be empty".to_string())
+    } else if !matches!(Url::parse(&webhook.url).map(|u| u.scheme().to_string()).as_deref(), Ok("http") | Ok("https")) {
+        Some(format!("'{}' is not an http(s) URL", webhook.url))
+    } else if let Some(Err(e)) = webhook.template.as_deref().map(webhooks::validate_template) {
+        Some(e.to_string())
+    } else {
+        None
+    };
+
+    match problem {
+        Some(message) => {
+            let error_response = ErrorResponse {
+                error: "Invalid Webhook".to_string(),
+                message,
+            };
+            Err((StatusCode::BAD_REQUEST, Json(error_response)).into_response())
+        },
+        None => Ok(()),
+    }
+}
+
+#[axum::debug_handler]
+async fn list_webhooks(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_webhooks().await {
+        Ok(webhooks) => (StatusCode::OK, Json(webhooks)).into_response(),
+        Err(e) => {
+            error!("Failed to list webhooks: {}", e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to list webhooks: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn add_webhook(
+    auth_session: AuthSession,
+    Json(mut webhook): Json<WebhookEndpoint>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(response) = validate_webhook(&mut webhook) {
+        return response;
+    }
+
+    match db::add_webhook(&webhook).await {
+        Ok(id) => {
+            webhook.id = id;
+            (StatusCode::CREATED, Json(webhook)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to add webhook: {}", e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to add webhook: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn update_webhook(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+    Json(mut webhook): Json<WebhookEndpoint>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(response) = validate_webhook(&mut webhook) {
+        return response;
+    }
+
+    match db::update_webhook(id, &webhook).await {
+        Ok(true) => {
+            webhook.id = id;
+            (StatusCode::OK, Json(webhook)).into_response()
+        },
+        Ok(false) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Webhook {} not found", id),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to update webhook {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to update webhook: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn delete_webhook(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::delete_webhook(id).await {
+        Ok(true) => (StatusCode::OK, Json(json!({ "success": true, "message": format!("Webhook {} deleted", id) }))).into_response(),
+        Ok(false) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Webhook {} not found", id),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to delete webhook {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to delete webhook: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Send a `webhook_test` event to one endpoint, so admins can check their payload template against the receiver
+#[axum::debug_handler]
+async fn test_webhook(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let webhook = match db::get_webhook(id).await {
+        Ok(Some(webhook)) => webhook,
+        Ok(None) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Webhook {} not found", id),
+            };
+            return (StatusCode::NOT_FOUND, Json(error_response)).into_response();
+        },
+        Err(e) => {
+            error!("Failed to load webhook {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to load webhook: {}", e),
+            };
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response();
+        }
+    };
+
+    // Use a real machine if there is one so templates that reference machine fields render meaningfully
+    let machine = db::get_all_machines().await.ok().and_then(|machines| machines.into_iter().next());
+    let subject = machine.as_ref().map(|m| m.id.to_string()).unwrap_or_default();
+    let payload = match webhooks::render_payload(&webhook, "webhook_test", &subject, machine.as_ref()) {
+        Ok(payload) => payload,
+        Err(e) => {
+            let error_response = ErrorResponse {
+                error: "Template Error".to_string(),
+                message: format!("Failed to render payload: {}", e),
+            };
+            return (StatusCode::UNPROCESSABLE_ENTITY, Json(error_response)).into_response();
+        }
+    };
+
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build().unwrap_or_default();
+    match webhooks::deliver(&client, &webhook, payload.clone()).await {
+        Ok(()) => (StatusCode::OK, Json(json!({ "success": true, "payload": payload }))).into_response(),
+        Err(e) => {
+            let error_response = ErrorResponse {
+                error: "Delivery Failed".to_string(),
+                message: format!("{} (payload: {})", e, payload),
+            };
+            (StatusCode::BAD_GATEWAY, Json(error_response)).into_response()
+        }
+    }
+}
+
+fn validate_notification_channel(channel: &mut NotificationChannel) -> Result<(), Response> {
+    channel.name = channel.name.trim().to_string();
+    notifications::validate(channel).map_err(|message| json_error(StatusCode::BAD_REQUEST, "Invalid Notification Channel", message))
+}
+
+fn notification_channel_not_found(id: i64) -> Response {
+    json_error(StatusCode::NOT_FOUND, "Not Found", format!("Notification channel {} not found", id))
+}
+
+#[axum::debug_handler]
+async fn list_notification_channels(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_notification_channels().await {
+        Ok(channels) => (StatusCode::OK, Json(channels)).into_response(),
+        Err(e) => {
+            error!("Failed to list notification channels: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", format!("Failed to list notification channels: {}", e))
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn add_notification_channel(
+    auth_session: AuthSession,
+    Json(mut channel): Json<NotificationChannel>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(response) = validate_notification_channel(&mut channel) {
+        return response;
+    }
+
+    match db::add_notification_channel(&channel).await {
+        Ok(id) => {
+            channel.id = id;
+            (StatusCode::CREATED, Json(channel)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to add notification channel: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", format!("Failed to add notification channel: {}", e))
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn update_notification_channel(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+    Json(mut channel): Json<NotificationChannel>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(response) = validate_notification_channel(&mut channel) {
+        return response;
+    }
+
+    match db::update_notification_channel(id, &channel).await {
+        Ok(true) => {
+            channel.id = id;
+            (StatusCode::OK, Json(channel)).into_response()
+        },
+        Ok(false) => notification_channel_not_found(id),
+        Err(e) => {
+            error!("Failed to update notification channel {}: {}", id, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", format!("Failed to update notification channel: {}", e))
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn delete_notification_channel(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::delete_notification_channel(id).await {
+        Ok(true) => (StatusCode::OK, Json(json!({ "success": true, "message": format!("Notification channel {} deleted", id) }))).into_response(),
+        Ok(false) => notification_channel_not_found(id),
+        Err(e) => {
+            error!("Failed to delete notification channel {}: {}", id, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", format!("Failed to delete notification channel: {}", e))
+        }
+    }
+}
+
+// Post a test message to one channel, so admins can check the webhook URL before relying on it
+#[axum::debug_handler]
+async fn test_notification_channel(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let channel = match db::get_notification_channel(id).await {
+        Ok(Some(channel)) => channel,
+        Ok(None) => return notification_channel_not_found(id),
+        Err(e) => {
+            error!("Failed to load notification channel {}: {}", id, e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", format!("Failed to load notification channel: {}", e));
+        }
+    };
+
+    let text = format!("👋 Test message from Dragonfly for channel '{}'", channel.name);
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build().unwrap_or_default();
+    match notifications::post(&client, &channel, &text).await {
+        Ok(()) => (StatusCode::OK, Json(json!({ "success": true }))).into_response(),
+        Err(e) => {
+            json_error(StatusCode::BAD_GATEWAY, "Delivery Failed", e.to_string())
+        }
+    }
+}
+
+// The SMTP password is write-only, like the S3 secret key: saving without one keeps the current password
+#[axum::debug_handler]
+async fn get_smtp_settings(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    // Read without decrypting, as the password is never shown
+    match db::get_smtp_settings().await {
+        Ok(smtp) => (StatusCode::OK, Json(smtp.map(|s| s.redacted()))).into_response(),
+        Err(e) => {
+            error!("Failed to load SMTP settings: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", format!("Failed to load SMTP settings: {}", e))
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn update_smtp_settings(
+    auth_session: AuthSession,
+    Json(mut smtp): Json<SmtpSettings>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if smtp.password.is_empty() {
+        match alerts::smtp_settings().await {
+            Ok(Some(current)) => smtp.password = current.password,
+            Ok(None) => {},
+            Err(e) => return smtp_error(e),
+        }
+    }
+    if let Err(e) = smtp.validate() {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid SMTP Settings", e);
+    }
+
+    match alerts::save_smtp_settings(&smtp).await {
+        Ok(()) => (StatusCode::OK, Json(smtp.redacted())).into_response(),
+        Err(e) => smtp_error(e),
+    }
+}
+
+// The password is sealed with the secrets key, so storing one needs the key
+fn smtp_error(e: VariableError) -> Response {
+    match e {
+        VariableError::Disabled => json_error(StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable",
+            "Set DRAGONFLY_SECRETS_KEY on the server to store an SMTP password".to_string()),
+        e => template_variable_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct SmtpTestRequest {
+    to: String,
+}
+
+// Send a test email, so admins can check the mail server settings before an alert depends on them
+#[axum::debug_handler]
+async fn test_smtp_settings(
+    auth_session: AuthSession,
+    Json(request): Json<SmtpTestRequest>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let smtp = match alerts::smtp_settings().await {
+        Ok(Some(smtp)) => smtp,
+        Ok(None) => return json_error(StatusCode::CONFLICT, "Not Configured", "Save the SMTP settings before sending a test email".to_string()),
+        Err(e) => return smtp_error(e),
+    };
+
+    let body = "This is a test email from Dragonfly. Alert emails will be sent through this server.".to_string();
+    match alerts::send_email(&smtp, &[request.to], "[Dragonfly] Test email", body).await {
+        Ok(()) => (StatusCode::OK, Json(json!({ "success": true }))).into_response(),
+        Err(e) => json_error(StatusCode::BAD_GATEWAY, "Delivery Failed", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn list_alert_rules(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_alert_rules().await {
+        Ok(rules) => (StatusCode::OK, Json(rules)).into_response(),
+        Err(e) => {
+            error!("Failed to list alert rules: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", format!("Failed to list alert rules: {}", e))
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn add_alert_rule(
+    auth_session: AuthSession,
+    Json(mut rule): Json<AlertRule>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    rule.name = rule.name.trim().to_string();
+    if let Err(e) = rule.validate() {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid Alert Rule", e);
+    }
+
+    match db::add_alert_rule(&rule).await {
+        Ok(id) => {
+            rule.id = id;
+            (StatusCode::CREATED, Json(rule)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to add alert rule: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", format!("Failed to add alert rule: {}", e))
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn update_alert_rule(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+    Json(mut rule): Json<AlertRule>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    rule.name = rule.name.trim().to_string();
+    if let Err(e) = rule.validate() {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid Alert Rule", e);
+    }
+
+    match db::update_alert_rule(id, &rule).await {
+        Ok(true) => {
+            rule.id = id;
+            (StatusCode::OK, Json(rule)).into_response()
+        },
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("Alert rule {} not found", id)),
+        Err(e) => {
+            error!("Failed to update alert rule {}: {}", id, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", format!("Failed to update alert rule: {}", e))
         }
-        // FINALLY, assume it's a binary artifact to download/stream
-        else {
-            // --- Download/Stream Other Binary Artifacts ---
-            let remote_url = match requested_path.as_str() {
-                // Alpine Linux netboot artifacts for Dragonfly Agent
-                "dragonfly-agent/vmlinuz" => "https://dl-cdn.alpinelinux.org/alpine/latest-stable/releases/x86_64/netboot/vmlinuz-lts",
-                "dragonfly-agent/initramfs-lts" => "https://dl-cdn.alpinelinux.org/alpine/latest-stable/releases/x86_64/netboot/initramfs-lts",
-                "dragonfly-agent/modloop" => "https://dl-cdn.alpinelinux.org/alpine/latest-stable/releases/x86_64/netboot/modloop-lts",
-                // Ubuntu 22.04
-                "ubuntu/jammy-server-cloudimg-amd64.img" => "https://cloud-images.ubuntu.com/jammy/current/jammy-server-cloudimg-amd64.img",
-                // Ubuntu 24.04
-                "ubuntu/noble-server-cloudimg-amd64.img" => "https://cloud-images.ubuntu.com/noble/current/noble-server-cloudimg-amd64.img",
-                _ => {
-                    // If it wasn't an .ipxe script and not a known binary, it's unknown.
-                    warn!("Unknown artifact requested: {}", requested_path);
-                    return (StatusCode::NOT_FOUND, "Unknown iPXE artifact").into_response();
-                }
+    }
+}
+
+#[axum::debug_handler]
+async fn delete_alert_rule(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::delete_alert_rule(id).await {
+        Ok(true) => (StatusCode::OK, Json(json!({ "success": true, "message": format!("Alert rule {} deleted", id) }))).into_response(),
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("Alert rule {} not found", id)),
+        Err(e) => {
+            error!("Failed to delete alert rule {}: {}", id, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", format!("Failed to delete alert rule: {}", e))
+        }
+    }
+}
+
+// 202 Accepted for an operation that carries on in the background, pointing at where to follow it
+fn operation_accepted(operation: &Operation) -> Response {
+    let location = format!("/api/v1/operations/{}", operation.id);
+    (StatusCode::ACCEPTED, [(axum::http::header::LOCATION, location)], Json(operation)).into_response()
+}
+
+#[axum::debug_handler]
+async fn list_operations(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    (StatusCode::OK, Json(operations::list())).into_response()
+}
+
+#[axum::debug_handler]
+async fn get_operation(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    match operations::get(&id) {
+        Some(operation) => (StatusCode::OK, Json(operation)).into_response(),
+        None => json_error(StatusCode::NOT_FOUND, "Not Found", format!("Operation {} not found", id)),
+    }
+}
+
+#[axum::debug_handler]
+async fn cancel_operation(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    match operations::cancel(&id) {
+        Ok(operation) => (StatusCode::ACCEPTED, Json(operation)).into_response(),
+        Err(e @ OperationError::NotFound) => json_error(StatusCode::NOT_FOUND, "Not Found", e.to_string()),
+        Err(e @ OperationError::Finished) => json_error(StatusCode::CONFLICT, "Conflict", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn get_discovery_policy(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_discovery_policy().await {
+        Ok(policy) => (StatusCode::OK, Json(policy)).into_response(),
+        Err(e) => {
+            error!("Failed to load discovery policy: {}", e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to load discovery policy: {}", e),
             };
-            
-            // Use the efficient streaming download with caching for known artifacts
-            // Use artifact_path (full path) for caching
-            match stream_download_with_caching(
-                remote_url, 
-                &artifact_path, 
-                headers.get(axum::http::header::RANGE),
-                machine_id, // Pass the machine_id found via IP lookup
-                Some(&state)
-            ).await {
-                Ok((stream, content_length, content_range)) => {
-                    info!("Streaming artifact {} from remote source", requested_path);
-                    return create_streaming_response(stream, "application/octet-stream", content_length, content_range);
-                },
-                Err(e) => {
-                    error!("Failed to stream artifact {}: {}", requested_path, e);
-                    return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error streaming artifact: {}", e)).into_response();
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn update_discovery_policy(
+    auth_session: AuthSession,
+    Json(policy): Json<DiscoveryPolicy>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(e) = policy.validate() {
+        let error_response = ErrorResponse {
+            error: "Invalid Discovery Policy".to_string(),
+            message: e.to_string(),
+        };
+        return (StatusCode::BAD_REQUEST, Json(error_response)).into_response();
+    }
+
+    match db::save_discovery_policy(&policy).await {
+        Ok(()) => (StatusCode::OK, Json(policy)).into_response(),
+        Err(e) => {
+            error!("Failed to save discovery policy: {}", e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to save discovery policy: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SimulateQuery {
+    pub scans: Option<i64>,
+}
+
+// Show what a proposed default OS, approval gate and auto-registration rules would do to the
+// fleet and to recently discovered devices, compared with the policy in force. Nothing is saved.
+#[axum::debug_handler]
+async fn simulate_assignment_policy(
+    auth_session: AuthSession,
+    Query(query): Query<SimulateQuery>,
+    Json(policy): Json<crate::assignment::AssignmentPolicy>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(message) = policy.validate() {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid Assignment Policy", message);
+    }
+
+    match crate::assignment::simulate(policy, query.scans.unwrap_or(crate::assignment::DEFAULT_SCANS)).await {
+        Ok(simulation) => (StatusCode::OK, Json(simulation)).into_response(),
+        Err(e) => {
+            error!("Failed to simulate assignment policy: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Simulation Failed", e.to_string())
+        }
+    }
+}
+
+// The S3 secret key is write-only: it is never returned, and saving without one keeps the current key
+#[axum::debug_handler]
+async fn get_artifact_storage(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_artifact_storage().await {
+        Ok(storage) => (StatusCode::OK, Json(storage.redacted())).into_response(),
+        Err(e) => {
+            error!("Failed to load artifact storage settings: {}", e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to load artifact storage settings: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn update_artifact_storage(
+    auth_session: AuthSession,
+    Json(mut storage): Json<ArtifactStorage>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let ArtifactStorage::S3(s3) = &mut storage {
+        if s3.secret_key.is_empty() {
+            if let Ok(ArtifactStorage::S3(current)) = db::get_artifact_storage().await {
+                s3.secret_key = current.secret_key;
+            }
+        }
+    }
+    if let Err(e) = storage.validate() {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid Artifact Storage", e);
+    }
+
+    match db::save_artifact_storage(&storage).await {
+        Ok(()) => (StatusCode::OK, Json(storage.redacted())).into_response(),
+        Err(e) => {
+            error!("Failed to save artifact storage settings: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", format!("Failed to save artifact storage settings: {}", e))
+        }
+    }
+}
+
+fn template_variable_error(e: VariableError) -> Response {
+    let status = match &e {
+        VariableError::Disabled => StatusCode::SERVICE_UNAVAILABLE,
+        VariableError::Invalid(_) => StatusCode::BAD_REQUEST,
+        VariableError::NotFound => StatusCode::NOT_FOUND,
+        VariableError::Database(_) => {
+            error!("Template variable operation failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    json_error(status, status.canonical_reason().unwrap_or("Error"), e.to_string())
+}
+
+// Secret values are always masked
+#[axum::debug_handler]
+async fn list_template_variables(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::template_vars::list().await {
+        Ok(variables) => (StatusCode::OK, Json(variables)).into_response(),
+        Err(e) => template_variable_error(e),
+    }
+}
+
+#[axum::debug_handler]
+async fn save_template_variable(
+    auth_session: AuthSession,
+    Json(variable): Json<TemplateVariable>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::template_vars::save(&variable).await {
+        Ok(()) => {
+            info!("Template variable '{}' saved by {}{}", variable.name, policy::principal(&auth_session), if variable.secret { " (secret)" } else { "" });
+            (StatusCode::OK, Json(variable.masked())).into_response()
+        },
+        Err(e) => template_variable_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct ReplaceQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+// Replace a value everywhere it is used, e.g. an old mirror URL. `?dry_run=true` previews the diff.
+#[axum::debug_handler]
+async fn search_and_replace(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Query(query): Query<ReplaceQuery>,
+    Json(request): Json<crate::replace::ReplaceRequest>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::replace::run(&request, query.dry_run, &policy::principal(&auth_session)).await {
+        Ok(report) => {
+            if !report.dry_run {
+                let machines: std::collections::BTreeSet<Uuid> = report.changes.iter().filter_map(|c| match &c.target {
+                    crate::replace::Target::MachineField { machine_id, .. } => Some(*machine_id),
+                    _ => None,
+                }).collect();
+                for id in machines {
+                    let _ = state.event_manager.send(format!("machine_updated:{}", id));
                 }
             }
+            (StatusCode::OK, Json(report)).into_response()
+        },
+        Err(e) => template_variable_error(e),
+    }
+}
+
+#[axum::debug_handler]
+async fn list_replace_audit(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_replace_audit().await {
+        Ok(entries) => (StatusCode::OK, Json(entries)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+#[axum::debug_handler]
+async fn delete_template_variable(
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::template_vars::delete(&name).await {
+        Ok(()) => {
+            info!("Template variable '{}' deleted by {}", name, policy::principal(&auth_session));
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Err(e) => template_variable_error(e),
+    }
+}
+
+async fn render_token_machine(token: &str) -> Result<Machine, Response> {
+    match crate::template_vars::machine_for_token(token).await {
+        Ok(Some(machine)) => Ok(machine),
+        Ok(None) => Err(json_error(StatusCode::UNAUTHORIZED, "Unauthorized", "Unknown or expired render token".to_string())),
+        Err(e) => Err(template_variable_error(e.into())),
+    }
+}
+
+// cloud-init NoCloud user-data for an installing machine, with secrets filled in. The token is
+// handed to the machine's workflow, so the URL itself is the credential.
+#[axum::debug_handler]
+async fn get_rendered_user_data(Path(token): Path<String>) -> Response {
+    let machine = match render_token_machine(&token).await {
+        Ok(machine) => machine,
+        Err(response) => return response,
+    };
+
+    match crate::template_vars::render_user_data(&machine).await {
+        Ok(Some(user_data)) => ([(axum::http::header::CONTENT_TYPE, "text/cloud-config")], user_data).into_response(),
+        Ok(None) => json_error(
+            StatusCode::NOT_FOUND,
+            "Not Found",
+            format!("No user-data template for {}", machine.os_choice.as_deref().unwrap_or("this OS")),
+        ),
+        Err(e) => template_variable_error(e),
+    }
+}
+
+#[axum::debug_handler]
+async fn get_rendered_meta_data(Path(token): Path<String>) -> Response {
+    let machine = match render_token_machine(&token).await {
+        Ok(machine) => machine,
+        Err(response) => return response,
+    };
+
+    let hostname = machine.hostname.clone().or_else(|| machine.memorable_name.clone()).unwrap_or_else(|| machine.id.to_string());
+    format!("instance-id: {}\nlocal-hostname: {}\n", machine.id, hostname).into_response()
+}
+
+// Shell script that builds the RAID array and volumes of an installing machine's storage profile
+#[axum::debug_handler]
+async fn get_rendered_storage_script(Path(token): Path<String>) -> Response {
+    let machine = match render_token_machine(&token).await {
+        Ok(machine) => machine,
+        Err(response) => return response,
+    };
+
+    match crate::storage_profiles::script_for(&machine).await {
+        Ok(Some(script)) => ([(axum::http::header::CONTENT_TYPE, "text/x-shellscript")], script).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No storage profile applies to machine {}", machine.id)),
+        Err(e) => json_error(StatusCode::CONFLICT, "Conflict", e.to_string()),
+    }
+}
+
+// The firmware workflow reports what a machine has installed and gets back what to flash
+#[axum::debug_handler]
+async fn plan_firmware_updates(
+    Path(token): Path<String>,
+    Json(inventory): Json<crate::firmware::FirmwareInventory>,
+) -> Response {
+    let machine = match render_token_machine(&token).await {
+        Ok(machine) => machine,
+        Err(response) => return response,
+    };
+
+    let base_url = env::var("DRAGONFLY_BASE_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let binary_url = |id: i64| format!("{}/api/render/{}/firmware/{}", base_url.trim_end_matches('/'), token, id);
+    match crate::firmware::plan_for_machine(&machine.id, &inventory, binary_url).await {
+        Ok(steps) => (StatusCode::OK, Json(steps)).into_response(),
+        Err(e) => {
+            error!("Failed to plan firmware updates for machine {}: {}", machine.id, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn report_firmware_results(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Json(results): Json<Vec<crate::firmware::FlashResult>>,
+) -> Response {
+    let machine = match render_token_machine(&token).await {
+        Ok(machine) => machine,
+        Err(response) => return response,
+    };
+
+    match crate::firmware::record_results(&machine.id, &results).await {
+        Ok(()) => {
+            let _ = state.event_manager.send(format!("machine_updated:{}", machine.id));
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Err(e) => {
+            error!("Failed to record firmware results for machine {}: {}", machine.id, e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
         }
+    }
+}
+
+// The same for the install workflow, which holds the machine's render token rather than an agent secret
+#[axum::debug_handler]
+async fn issue_rendered_disk_key(Path(token): Path<String>, body: axum::body::Bytes) -> Response {
+    let machine = match render_token_machine(&token).await {
+        Ok(machine) => machine,
+        Err(response) => return response,
+    };
+    issue_disk_key_response(&machine.id, &body).await
+}
+
+// An uploaded firmware binary, for the machine whose workflow holds the token. Only images for
+// the hardware that machine reported are handed out.
+#[axum::debug_handler]
+async fn download_firmware_binary(Path((token, id)): Path<(String, i64)>) -> Response {
+    let machine = match render_token_machine(&token).await {
+        Ok(machine) => machine,
+        Err(response) => return response,
+    };
+    let not_found = || json_error(StatusCode::NOT_FOUND, "Not Found", format!("No firmware image {} for this machine", id));
+    let image = match db::get_firmware_image(id).await {
+        Ok(Some(image)) => image,
+        Ok(None) => return not_found(),
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    match db::get_machine_firmware(&machine.id).await {
+        Ok(installed) if crate::firmware::applies_to(&image, &installed) => {},
+        Ok(_) => {
+            warn!("Machine {} asked for firmware image {}, which isn't for its hardware", machine.id, id);
+            return not_found();
+        },
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
 
-        // If code reaches here, it means an IPXE script was requested but generate_ipxe_script 
-        // returned NotFound, so return 404.
-        (StatusCode::NOT_FOUND, "Unknown or Ungeneratable IPXE Script").into_response()
+    match tokio::fs::read(crate::firmware::binary_path(id).await).await {
+        Ok(binary) => ([(axum::http::header::CONTENT_TYPE, "application/octet-stream")], binary).into_response(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            json_error(StatusCode::NOT_FOUND, "Not Found", format!("No binary uploaded for firmware image {}", id))
+        },
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Storage Error", e.to_string()),
     }
 }
 
-// Add this function after parse_range_header
-// Helper function to track and report image download progress
-async fn track_download_progress(
-    machine_id: Option<Uuid>, 
-    bytes_downloaded: u64, 
-    total_size: u64,
-    state: AppState // Changed from Option<&AppState> to AppState
-) {
-    info!(
-        machine_id = ?machine_id, 
-        bytes_downloaded = bytes_downloaded, 
-        total_size = total_size, 
-        "[PROGRESS_TRACK] CALLED track_download_progress with values: bytes_downloaded={}, total_size={}, machine_id={:?}",
-        bytes_downloaded, total_size, machine_id
-    );
+#[axum::debug_handler]
+async fn list_os_lifecycle(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
 
-    debug!(
-        machine_id = ?machine_id, 
-        bytes_downloaded = bytes_downloaded, 
-        total_size = total_size, 
-        "[PROGRESS_DEBUG] Entering track_download_progress"
-    );
+    match db::get_os_lifecycle().await {
+        Ok(entries) => (StatusCode::OK, Json(entries)).into_response(),
+        Err(e) => {
+            error!("Failed to list OS lifecycle dates: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
+        }
+    }
+}
 
-    if total_size == 0 {
-        // Changed to INFO
-        info!("[PROGRESS_DEBUG] Exiting track_download_progress early: total_size is 0");
-        return; // Skip progress for zero-sized files
+#[axum::debug_handler]
+async fn update_os_lifecycle(
+    auth_session: AuthSession,
+    Path(template): Path<String>,
+    Json(mut entry): Json<crate::os_lifecycle::OsLifecycle>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
     }
-    
-    let progress_float = (bytes_downloaded as f64 / total_size as f64) * 100.0;
-    let task_name = "stream image"; // TODO: Can we get the actual filename here?
-    
-    // If we have a machine ID, send task-specific event
-    if let Some(id) = machine_id {
-        debug!(machine_id = %id, progress = progress_float, task_name = task_name, "Updating DB progress");
-        // Update the machine's task progress in DB
-        if let Err(e) = db::update_installation_progress(
-            &id,
-            progress_float.min(100.0) as u8, // Convert to u8 for DB, clamped at 100
-            Some(task_name)
-        ).await {
-            warn!(machine_id = %id, error = %e, "Failed to update download progress in DB");
+
+    entry.template = template;
+    if let Err(message) = crate::os_lifecycle::validate(&entry) {
+        return json_error(StatusCode::BAD_REQUEST, "Bad Request", message);
+    }
+    match db::save_os_lifecycle(&entry).await {
+        Ok(()) => {
+            info!("End of life of {} set to {} by {}", entry.template, entry.eol_date, policy::principal(&auth_session));
+            (StatusCode::OK, Json(entry)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to save OS lifecycle date: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
         }
-        
-        // For real-time UI updates, emit a more detailed event with floating point precision
-        let task_progress_event = format!(
-            "task_progress:{}:{}:{:.3}:{}:{}",
-            id,                   // Machine ID
-            task_name,            // Task name 
-            progress_float,       // Floating point percentage (with 3 decimal precision)
-            bytes_downloaded,     // Current bytes
-            total_size            // Total bytes
-        );
-        
-        debug!(machine_id = %id, event = %task_progress_event, "Attempting to send task_progress event");
-        // Emit the detailed task progress event
-        if let Err(e) = state.event_manager.send(task_progress_event.clone()) { // Clone for logging
-            warn!(machine_id = %id, error = %e, "Failed to emit task_progress event: {}", task_progress_event);
+    }
+}
+
+#[axum::debug_handler]
+async fn delete_os_lifecycle(
+    auth_session: AuthSession,
+    Path(template): Path<String>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::delete_os_lifecycle(&template).await {
+        Ok(true) => {
+            info!("End of life of {} cleared by {}", template, policy::principal(&auth_session));
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No end-of-life date is set for {}", template)),
+        Err(e) => {
+            error!("Failed to delete OS lifecycle date: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
         }
-        
-        // Also emit standard machine updated event for compatibility
-        // debug!(machine_id = %id, "Sending generic machine_updated event");
-        // let _ = state.event_manager.send(format!("machine_updated:{}", id));
     }
-    
-    // Also send IP-based progress event for any HTTP requests
-    let client_ip_guard = state.client_ip.lock().await;
-    if let Some(client_ip) = client_ip_guard.as_ref() {
-        // Find machine by IP if possible (for cases where we don't have machine_id)
-        let ip_machine_id = if machine_id.is_none() {
-            match db::get_machine_by_ip(client_ip).await {
-                Ok(Some(machine)) => Some(machine.id),
-                _ => None,
-            }
-        } else {
-            machine_id
-        };
-        
-        // Emit IP-based progress event
-        let ip_progress_event_payload = serde_json::json!({ 
-            "ip": client_ip,
-            "progress": progress_float, // Send float
-            "bytes_downloaded": bytes_downloaded,
-            "total_size": total_size,
-            "file_name": task_name, // Still uses hardcoded "Stream image"
-            "machine_id": ip_machine_id
-        });
+}
 
-        // Construct the event string
-        let ip_progress_event_string = format!("ip_download_progress:{}", ip_progress_event_payload.to_string());
+// Machines running OS templates that are past, or close to, their end of life
+#[axum::debug_handler]
+async fn get_compliance(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
 
-        info!(client_ip = %client_ip, event_payload = %ip_progress_event_payload, "[PROGRESS_SEND] Attempting to send ip_download_progress event NOW"); // ADDED LOUD LOG
-        let send_result = state.event_manager.send(ip_progress_event_string.clone()); // Clone for logging
-        
-        if let Err(e) = send_result {
-            warn!(client_ip = %client_ip, error = %e, "[PROGRESS_SEND] Failed to emit IP-based progress event: {}", ip_progress_event_string);
-        } else {
-            info!(client_ip = %client_ip, event_payload = %ip_progress_event_payload, "[PROGRESS_SEND] Successfully sent ip_download_progress event"); // ADDED SUCCESS LOG
+    match crate::os_lifecycle::compliance().await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(e) => {
+            error!("Failed to build compliance report: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string())
         }
-    } // End of: if let Some(client_ip) = client_ip_guard.as_ref()
-    
-    debug!("Exiting track_download_progress");
+    }
 }
 
-// Modify stream_download_with_caching to track progress
-async fn stream_download_with_caching(
-    url: &str,
-    cache_path: &StdPath,
-    range_header: Option<&HeaderValue>, // Add parameter for Range header
-    machine_id: Option<Uuid>, // Add optional machine ID for tracking
-    state: Option<&AppState>, // Add optional state for event emission
-) -> Result<(ReceiverStream<Result<Bytes, Error>>, Option<u64>, Option<String>), Error> { // Return Content-Range
-    info!("[STREAM_DOWNLOAD] Beginning stream_download_with_caching for URL: {}, cache_path: {}, range: {:?}, machine_id: {:?}",
-          url, cache_path.display(), range_header.map(|h| h.to_str().unwrap_or("invalid")), machine_id);
+#[axum::debug_handler]
+async fn list_discovery_scans(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
 
-    // Create parent directory if needed
-    if let Some(parent) = cache_path.parent() {
-        fs::create_dir_all(parent).await.map_err(|e| Error::Internal(format!("Failed to create directory: {}", e)))?;
+    match db::get_discovery_reports(50).await {
+        Ok(reports) => (StatusCode::OK, Json(reports)).into_response(),
+        Err(e) => {
+            error!("Failed to list discovery scans: {}", e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to list discovery scans: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
     }
+}
 
-    // Check if file is already cached
-    if cache_path.exists() {
-        // Even when serving from cache, track progress for range requests
-        if let (Some(machine_id), Some(state), Some(range_val)) = (machine_id, state, range_header) {
-            if let Ok(range_str) = range_val.to_str() {
-                let file_size = fs::metadata(cache_path).await
-                    .map(|m| m.len())
-                    .unwrap_or(0);
-                    
-                if let Some((start, end)) = parse_range_header(range_str, file_size, None, Some(state)).await {
-                    let bytes_downloaded = end - start + 1;
-                    
-                    // Use the start position as a progress indicator for range requests
-                    // This gives a rough approximation of download progress across multiple range requests
-                    let effective_progress = start + bytes_downloaded;
-                    
-                    info!("[RANGE_PROGRESS] Cached file with range: start={}, end={}, bytes={}, total={}, effective_progress={}",
-                          start, end, bytes_downloaded, file_size, effective_progress);
-                          
-                    // Track download progress with the effective bytes downloaded
-                    tokio::spawn(track_download_progress(Some(machine_id), effective_progress, file_size, state.clone()));
-                }
-            }
+// Run a discovery scan now instead of waiting for the schedule
+#[axum::debug_handler]
+async fn run_discovery_scan(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let operation = operations::start("discovery_scan", "Scan the network for new devices".to_string(), &policy::principal(&auth_session), move |_| async move {
+        let report = discovery::run_scan(&state.event_manager).await?;
+        Ok(serde_json::to_value(report)?)
+    });
+    operation_accepted(&operation)
+}
+
+#[axum::debug_handler]
+async fn get_discovery_scan(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_discovery_report(id).await {
+        Ok(Some(report)) => (StatusCode::OK, Json(report)).into_response(),
+        Ok(None) => {
+            let error_response = ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Discovery scan {} not found", id),
+            };
+            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to load discovery scan {}: {}", id, e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to load discovery scan: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
         }
-        
-        // info!("Serving cached artifact from: {:?}", cache_path); // Commented out log
-        return read_file_as_stream(cache_path, range_header, state, machine_id).await; // Pass Range header
     }
-    
-    info!("Downloading and caching artifact from: {}", url);
-    
-    // Start HTTP request with reqwest feature for streaming
-    let client = reqwest::Client::new();
-    let response = client.get(url).send().await.map_err(|e| Error::Internal(format!("HTTP request failed: {}", e)))?;
-    
-    if !response.status().is_success() {
-        return Err(Error::Internal(format!("HTTP error: {}", response.status())));
+}
+
+// Every IP and MAC address currently claimed by more than one machine
+#[axum::debug_handler]
+async fn list_conflicts(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_all_machines().await {
+        Ok(machines) => (StatusCode::OK, Json(crate::conflicts::detect(&machines))).into_response(),
+        Err(e) => {
+            error!("Failed to load machines for conflict check: {}", e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to check for conflicts: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
+
+// Tinkerbell Hardware resources that no longer match Dragonfly, as of the last reconciliation
+#[axum::debug_handler]
+async fn get_hardware_drift(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    (StatusCode::OK, Json(crate::hardware_sync::last_report())).into_response()
+}
+
+// Reconcile Hardware resources now rather than waiting for the next pass
+#[axum::debug_handler]
+async fn reconcile_hardware(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::hardware_sync::reconcile(&state.event_manager).await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(e) => {
+            error!("Hardware reconciliation failed: {}", e);
+            json_error(StatusCode::SERVICE_UNAVAILABLE, "Reconciliation Failed", e.to_string())
+        }
     }
-    
-    // Get content length if available
-    let content_length = response.content_length();
-    if let Some(length) = content_length {
-        info!("[PROGRESS_DEBUG] Download size from Content-Length: {} bytes", length);
-    } else {
-        info!("[PROGRESS_DEBUG] No Content-Length header received from remote server.");
+}
+
+// Audit trail of break-glass credentials: who issued them, and when they were used and rotated
+#[axum::debug_handler]
+async fn list_break_glass_credentials(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
     }
-    
-    let file = fs::File::create(cache_path).await.map_err(|e| Error::Internal(format!("Failed to create cache file: {}", e)))?;
-    let file = Arc::new(tokio::sync::Mutex::new(file));
-    let (tx, rx) = mpsc::channel::<Result<Bytes, Error>>(32);
-    
-    let url_clone = url.to_string();
-    let cache_path_clone = cache_path.to_path_buf();
-    
-    // For tracking download progress
-    let total_size = content_length.unwrap_or(0);
-    let mut total_bytes_downloaded: u64 = 0;
-    let tracking_machine_id = machine_id;
-    let app_state_clone = state.cloned();
-    
-    tokio::spawn(async move {
-        let mut client_disconnected = false;
-        let mut download_error = false;
 
-        // Get the stream. `bytes_stream` consumes the response object.
-        let mut stream = response.bytes_stream(); 
+    match db::get_break_glass_records().await {
+        Ok(records) => (StatusCode::OK, Json(records)).into_response(),
+        Err(e) => {
+            error!("Failed to list break-glass credentials: {}", e);
+            let error_response = ErrorResponse {
+                error: "Database Error".to_string(),
+                message: format!("Failed to list break-glass credentials: {}", e),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
+        }
+    }
+}
 
-        while let Some(chunk_result) = stream.next().await {
-            match chunk_result {
-                Ok(chunk) => {
-                    let chunk_clone = chunk.clone();
-                    let chunk_size = chunk.len() as u64;
-                    
-                    // Write chunk to cache file concurrently
-                    let file_clone = Arc::clone(&file);
-                    let write_handle = tokio::spawn(async move {
-                        let mut file = file_clone.lock().await;
-                        file.write_all(&chunk_clone).await
-                    });
+// Whether provisioning is frozen. Like monitoring, reading it needs no admin rights.
+#[axum::debug_handler]
+async fn get_freeze() -> Response {
+    match crate::freeze::current() {
+        Some(record) => (StatusCode::OK, Json(json!({
+            "frozen": true,
+            "actor": record.actor,
+            "reason": record.reason,
+            "since": record.created_at,
+        }))).into_response(),
+        None => (StatusCode::OK, Json(json!({ "frozen": false }))).into_response(),
+    }
+}
 
-                    // Update progress tracking
-                    total_bytes_downloaded += chunk_size;
-                    
-                    // ADDED LOG: Log chunk size and total downloaded
-                    debug!(url = %url_clone, chunk_size = chunk_size, total_bytes_downloaded = total_bytes_downloaded, total_size = total_size, "[STREAM_DOWNLOAD_LOOP] Downloaded chunk");
+#[derive(Deserialize, Debug)]
+pub struct FreezeRequest {
+    pub frozen: bool,
+    pub reason: Option<String>,
+}
 
-                    if let (Some(machine_id), Some(state)) = (tracking_machine_id, &app_state_clone) {
-                        if total_size > 0 {
-                            // ADDED LOG: Confirm call to track_download_progress
-                            debug!("[PROGRESS_DEBUG] Calling track_download_progress (machine_id: {}, downloaded: {}, total: {})", machine_id, total_bytes_downloaded, total_size);
-                            
-                            // ADDED LOG: Log before calling track_download_progress function
-                            debug!(url = %url_clone, machine_id = %machine_id, bytes_downloaded = total_bytes_downloaded, total_size = total_size, "[STREAM_DOWNLOAD_LOOP] PRE-PROGRESS CALL");
-                            
-                            track_download_progress(Some(machine_id), total_bytes_downloaded, total_size, state.clone()).await;
-                        }
-                    }
-                    
-                    // Attempt to send to client only if not already disconnected
-                    if !client_disconnected {
-                        if tx.send(Ok(chunk)).await.is_err() {
-                            warn!("Client stream receiver dropped for {}. Continuing download in background.", url_clone);
-                            client_disconnected = true;
-                            // DO NOT break here - let download continue for caching
-                        }
-                    }
+// Freeze or unfreeze provisioning: while frozen no installs start and destructive calls are refused
+#[axum::debug_handler]
+async fn update_freeze(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Json(request): Json<FreezeRequest>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if request.frozen == crate::freeze::is_frozen() {
+        let message = if request.frozen { "Provisioning is already frozen" } else { "Provisioning is not frozen" };
+        return json_error(StatusCode::CONFLICT, "Conflict", message.to_string());
+    }
 
-                    // Await the write operation regardless of client connection status
-                    match write_handle.await { // Await the JoinHandle itself
-                        Ok(Ok(())) => {
-                            // Write successful, continue loop
-                        },
-                        Ok(Err(e)) => {
-                            // Write operation failed
-                            warn!("Failed to write chunk to cache file {}: {}", cache_path_clone.display(), e);
-                            download_error = true;
-                            break; // Abort download if we can't write to cache
-                        },
-                        Err(e) => {
-                            // Task failed (e.g., panicked)
-                            warn!("Cache write task failed (join error) for {}: {}", cache_path_clone.display(), e);
-                            download_error = true;
-                            break; // Abort download if write task fails
-                        }
-                    }
-                },
-                Err(e) => { // e is reqwest::Error here
-                    error!("Download stream error for {}: {}", url_clone, e);
-                    // Send error to client if still connected
-                    if !client_disconnected {
-                        let err = Error::Internal(format!("Download stream error: {}", e));
-                        if tx.send(Err(err)).await.is_err() {
-                             warn!("Client stream receiver dropped while sending download error for {}", url_clone);
-                             // Client disconnected while we were trying to send an error
-                             client_disconnected = true;
-                        }
-                    }
-                    download_error = true;
-                    break; // Stop processing on download error
-                }
-            }
-            // If download_error is true, the inner match already broke, so we'll exit.
+    let actor = policy::principal(&auth_session);
+    let reason = request.reason.as_deref().map(str::trim).filter(|r| !r.is_empty());
+    match crate::freeze::set(request.frozen, &actor, reason).await {
+        Ok(record) => {
+            let _ = state.event_manager.send(format!("freeze_changed:{}", if record.frozen { "frozen" } else { "unfrozen" }));
+            (StatusCode::OK, Json(record)).into_response()
+        },
+        Err(e) => {
+            error!("Failed to update provisioning freeze: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", format!("Failed to update provisioning freeze: {}", e))
         }
-        
-        // Explicitly drop the response stream to release network resources potentially sooner
-        drop(stream);
+    }
+}
 
-        // Report final progress on successful download
-        if !download_error && total_size > 0 {
-            if let (Some(machine_id), Some(state)) = (tracking_machine_id, &app_state_clone) {
-                track_download_progress(Some(machine_id), total_size, total_size, state.clone()).await;
-            }
-        }
+// Who froze and unfroze provisioning, newest first
+#[axum::debug_handler]
+async fn list_freeze_records(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
 
-        // Ensure file is flushed and closed first
-        if let Ok(mut file) = Arc::try_unwrap(file).map_err(|_| "Failed to unwrap Arc").and_then(|mutex| Ok(mutex.into_inner())) {
-            if let Err(e) = file.flush().await {
-                warn!("Failed to flush cache file {}: {}", cache_path_clone.display(), e);
-            }
-            // File is closed when it goes out of scope here
-        }
-        
-        // Only send EOF signal if the download completed without error AND the client is still connected
-        if !download_error && !client_disconnected {
-            info!("Download complete for {}, client still connected.", url_clone);
-            // Removed explicit EOF signal
-            // debug!("Sending EOF signal for {}", url_clone);
-            // let _ = tx.send(Ok(Bytes::new())).await;
-        } else if !download_error && client_disconnected {
-            info!("Download complete and cached for {} after client disconnected.", url_clone);
-        } else {
-            // An error occurred during download or caching
-            warn!("Download for {} did not complete successfully due to errors.", url_clone);
-            // Optionally remove the potentially incomplete cache file
-            // if let Err(e) = fs::remove_file(&cache_path_clone).await {
-            //     warn!("Failed to remove incomplete cache file {}: {}", cache_path_clone.display(), e);
-            // }
+    match db::get_freeze_records().await {
+        Ok(records) => (StatusCode::OK, Json(records)).into_response(),
+        Err(e) => {
+            error!("Failed to list freeze records: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", format!("Failed to list freeze records: {}", e))
         }
-    });
-    
-    // After download completes or if error, handle the stream
-    let (stream, content_length) = (tokio_stream::wrappers::ReceiverStream::new(rx), content_length);
-
-    // We cached the full file, but the *initial* request might have been a range request.
-    // If so, we need to read the *cached* file with range support now.
-    if range_header.is_some() {
-        info!("Download complete, now serving range request from cached file: {:?}", cache_path);
-        // Re-call read_file_as_stream with the range header on the now-cached file
-        read_file_as_stream(cache_path, range_header, state, machine_id).await // Pass machine_id here too
-    } else {
-        // No range requested initially, return the full stream we prepared during download
-        Ok((stream, content_length, None)) // No Content-Range for full file
     }
 }
 
-// Helper to parse Range header. Returns (start, end)
-async fn parse_range_header(
-    range_str: &str,
-    total_size: u64,
-    _file_name: Option<&str>, // Marked unused, event logic removed
-    _state: Option<&AppState>, // Marked unused, event logic removed
-) -> Option<(u64, u64)> {
-    if !range_str.starts_with("bytes=") {
-        return None;
+#[derive(Deserialize, Debug, Default)]
+struct VersionQuery {
+    /// Look up the latest release now instead of using the last check
+    #[serde(default)]
+    refresh: bool,
+}
+
+// The running version, the latest release and how the last upgrade went
+#[axum::debug_handler]
+async fn get_version(auth_session: AuthSession, Query(query): Query<VersionQuery>) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
     }
-    let range_val = &range_str[6..]; // Skip "bytes="
-    let parts: Vec<&str> = range_val.split('-').collect();
-    if parts.len() != 2 {
-        return None;
+
+    match crate::upgrade::version_info(query.refresh).await {
+        Ok(info) => (StatusCode::OK, Json(info)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
     }
+}
 
-    let start_str = parts[0].trim();
-    let end_str = parts[1].trim();
+#[derive(Deserialize, Debug, Default)]
+struct UpgradeRequest {
+    /// The release to upgrade to; the latest when not given
+    #[serde(default)]
+    version: Option<String>,
+    /// Allow reinstalling the running version or going back to an older one
+    #[serde(default)]
+    force: bool,
+}
 
-    let start = if start_str.is_empty() {
-        // Suffix range: "-<length>"
-        if end_str.is_empty() { return None; } // Invalid: "-"
-        let suffix_len = end_str.parse::<u64>().ok()?;
-        if suffix_len >= total_size { 0 } else { total_size - suffix_len }
-    } else {
-        // Normal range: "start-" or "start-end"
-        start_str.parse::<u64>().ok()?
+// Upgrade Dragonfly in place; progress is sent as install_status events
+#[axum::debug_handler]
+async fn start_upgrade(auth_session: AuthSession, Json(request): Json<UpgradeRequest>) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let version = match request.version {
+        Some(version) => version.trim().to_string(),
+        None => match crate::upgrade::latest_version(true).await {
+            Ok(version) => version,
+            Err(e) => return json_error(StatusCode::BAD_GATEWAY, "Release Check Failed", e),
+        },
     };
+    if !crate::upgrade::valid_release_tag(&version) {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid Version", format!("'{}' isn't a release version", version));
+    }
+    if !request.force && !crate::upgrade::is_newer(&version, crate::upgrade::VERSION) {
+        return json_error(
+            StatusCode::CONFLICT,
+            "Conflict",
+            format!("Dragonfly {} is running; set force to move to {}", crate::upgrade::VERSION, version),
+        );
+    }
+    if crate::upgrade::in_progress() {
+        return json_error(StatusCode::CONFLICT, "Conflict", "An upgrade is already running".to_string());
+    }
+    match crate::upgrade::start(version, &policy::principal(&auth_session)).await {
+        Ok(upgrade) => (StatusCode::ACCEPTED, Json(upgrade)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Upgrade Failed", e.to_string()),
+    }
+}
 
-    let end = if end_str.is_empty() {
-        // Range "start-" means start to end of file
-        total_size.saturating_sub(1)
-    } else {
-        // Range "start-end"
-        end_str.parse::<u64>().ok()?
+// The last 20 upgrades, newest first
+#[axum::debug_handler]
+async fn list_upgrades(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_upgrades(20).await {
+        Ok(upgrades) => (StatusCode::OK, Json(upgrades)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    }
+}
+
+// The live application settings, without credentials
+#[axum::debug_handler]
+async fn get_settings(State(state): State<AppState>, auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let settings = state.settings.lock().await;
+    (StatusCode::OK, Json(crate::settings::SettingsView::from(&*settings))).into_response()
+}
+
+// Change application settings; they take effect immediately
+#[axum::debug_handler]
+async fn update_settings(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Json(update): Json<crate::settings::SettingsUpdate>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let current = match db::get_app_settings().await {
+        Ok(settings) => settings,
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string()),
+    };
+    let settings = match update.apply_to(&current) {
+        Ok(settings) => settings,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, "Invalid Settings", e),
     };
+    if let Err(e) = db::save_app_settings(&settings).await {
+        return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Database Error", e.to_string());
+    }
+    info!("Settings updated by {}", policy::principal(&auth_session));
+    let view = crate::settings::SettingsView::from(&settings);
+    crate::settings::publish(&state.settings, settings).await;
+    (StatusCode::OK, Json(view)).into_response()
+}
+
+#[cfg(feature = "chaos")]
+#[axum::debug_handler]
+async fn get_chaos(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
 
-    // Validate range: start <= end < total_size
-    if start > end || end >= total_size {
-        warn!("Invalid range request: start={}, end={}, total_size={}", start, end, total_size);
-        return None;
+    (StatusCode::OK, Json(crate::chaos::status())).into_response()
+}
+
+// Replace the injected faults
+#[cfg(feature = "chaos")]
+#[axum::debug_handler]
+async fn update_chaos(auth_session: AuthSession, Json(faults): Json<crate::chaos::Faults>) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
     }
 
-    // Optional: Emit progress event for the range being served
-    // if let Some(s) = state { // Check if state exists before trying to use it
-    //     let bytes_downloaded = end - start + 1;
-    //     let event_data = serde_json::json!({
-    //         "progress": 100.0, // A single range request is considered 100% of that range
-    //         "bytes_downloaded": bytes_downloaded,
-    //         "total_size": total_size,
-    //         "file_name": file_name.unwrap_or("unknown")
-    //     }).to_string();
+    warn!("Fault injection changed by {}", policy::principal(&auth_session));
+    (StatusCode::OK, Json(crate::chaos::set(faults))).into_response()
+}
 
-    //     // Prefer emitting IP-based progress if possible
-    //     let client_ip_guard = s.client_ip.lock().await;
-    //     if let Some(client_ip) = client_ip_guard.as_ref() {
-    //          let ip_progress_event = format!("ip_download_progress:{{ \"ip\": \"{}\", {} }}", client_ip, &event_data[1..]); // Construct JSON manually
-    //          // info!("Sending event: {}", ip_progress_event); // Commented out log
-    //          let _ = s.event_manager.send(ip_progress_event);
-    //     } else if let Some(f_name) = file_name {
-    //         // Fallback to file-based progress if IP is unavailable
-    //         let file_progress_event = format!("file_progress:{}:{}:{}", f_name, 100.0, event_data);
-    //         let _ = s.event_manager.send(file_progress_event);
-    //     }
-    // }
+#[cfg(feature = "chaos")]
+#[axum::debug_handler]
+async fn clear_chaos(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
 
-    Some((start, end))
+    crate::chaos::clear();
+    StatusCode::NO_CONTENT.into_response()
 }
 
-// Restore original function name and intended purpose (returning HTML partial)
-pub async fn get_workflow_progress(
-    State(app_state): State<AppState>, // Add AppState
-    Path(id): Path<Uuid>
-) -> Response { 
-    info!("Request for workflow progress HTML partial for machine {}", id);
+#[derive(Deserialize)]
+struct StartRecordingRequest {
+    name: String,
+}
 
-    let machine = match db::get_machine_by_id(&id).await {
-        Ok(Some(m)) => m,
-        Ok(None) => {
-            error!("Machine not found: {}", id);
-            return (StatusCode::NOT_FOUND, Html("<div>Machine not found</div>")).into_response();
-        },
-        Err(e) => {
-            error!("Error fetching machine {}: {}", id, e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, Html("<div>Database error</div>")).into_response();
-        }
-    };
+// Saved scenarios, newest first
+#[axum::debug_handler]
+async fn list_scenarios(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
 
-    if machine.status != MachineStatus::InstallingOS {
-        info!("Machine {} is not installing OS, status: {:?}", id, machine.status);
-        return (StatusCode::OK, Html("<div></div>")).into_response(); // Return empty div if not installing
+    match crate::scenarios::list().await {
+        Ok(scenarios) => (StatusCode::OK, Json(scenarios)).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Scenario Error", e.to_string()),
     }
+}
 
-    match crate::tinkerbell::get_workflow_info(&machine).await {
-        Ok(Some(info)) => {
-            info!("Successfully got workflow info for machine {}: state={}, progress={}", id, info.state, info.progress);
+// Add a scenario recorded elsewhere, e.g. attached to a bug report
+#[axum::debug_handler]
+async fn upload_scenario(auth_session: AuthSession, Json(scenario): Json<crate::scenarios::Scenario>) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if let Err(e) = crate::scenarios::validate_name(&scenario.name) {
+        return json_error(StatusCode::BAD_REQUEST, "Invalid Scenario", e);
+    }
 
-            // Create the context struct (as defined in ui.rs)
-            let context = ui::WorkflowProgressTemplate { 
-                machine_id: id,
-                workflow_info: info,
-            };
-            
-            // Render the partial using MiniJinja
-            ui::render_minijinja(&app_state, "partials/workflow_progress.html", context)
-        },
-        Ok(None) => {
-            info!("No workflow found for machine {}", id);
-            (StatusCode::OK, Html("<div></div>")).into_response()
-        },
-        Err(e) => {
-            error!("Error fetching workflow for machine {}: {}", id, e);
-            (StatusCode::INTERNAL_SERVER_ERROR, Html("<div>Error fetching workflow</div>")).into_response()
-        }
+    match crate::scenarios::save(&scenario).await {
+        Ok(()) => (StatusCode::CREATED, Json(crate::scenarios::ScenarioSummary::from(&scenario))).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Scenario Error", e.to_string()),
     }
 }
 
-// ... (rest of api.rs) ...
+#[axum::debug_handler]
+async fn get_scenario(auth_session: AuthSession, Path(name): Path<String>) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
 
-// Stub for heartbeat
-pub async fn heartbeat() -> Response {
-    (StatusCode::OK, "OK").into_response()
+    match crate::scenarios::load(&name).await {
+        Ok(Some(scenario)) => (StatusCode::OK, Json(scenario)).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No scenario named '{}'", name)),
+        Err(e) => json_error(StatusCode::BAD_REQUEST, "Scenario Error", e.to_string()),
+    }
 }
 
-// Add stubs for functions called from mode.rs
-pub async fn check_hookos_artifacts() -> bool {
-    // Check for the following four files
-    let files = vec![
-        "vmlinuz-latest-lts-x86_64",
-        "initramfs-latest-lts-x86_64",
-        "vmlinuz-latest-lts-aarch64",
-        "initramfs-latest-lts-aarch64",
-        "dtbs-latest-lts-aarch64.tar.gz",
-        "vmlinuz-x86_64",
-        "initramfs-x86_64",
-        "vmlinuz-aarch64",
-        "initramfs-aarch64",
-        "dtbs-aarch64.tar.gz",
-    ];
-
-    for file in files {
-        let path = FilePath::new("/var/lib/dragonfly/ipxe-artifacts/hookos").join(file);
-        if !path.exists() {
-            return false;
-        }
+#[axum::debug_handler]
+async fn delete_scenario(auth_session: AuthSession, Path(name): Path<String>) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
     }
 
-    info!("All HookOS artifacts found");
-    true
+    match crate::scenarios::delete(&name).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "Not Found", format!("No scenario named '{}'", name)),
+        Err(e) => json_error(StatusCode::BAD_REQUEST, "Scenario Error", e.to_string()),
+    }
 }
 
-pub async fn download_hookos_artifacts(version: &str) -> anyhow::Result<()> {
-    // Create directory structure if it doesn't exist
-    let hookos_dir = FilePath::new("/var/lib/dragonfly/ipxe-artifacts/hookos");
-    if !hookos_dir.exists() {
-        info!("Creating directory structure: {:?}", hookos_dir);
-        std::fs::create_dir_all(hookos_dir)?;
+// The recording under way, if any
+#[axum::debug_handler]
+async fn get_scenario_recording(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
     }
-    
-    // Download checksum file
-    let checksum_url = format!("https://github.com/tinkerbell/hook/releases/download/{}/checksum.txt", version);
-    let checksum_path = hookos_dir.join("checksum.txt");
-    let checksum_response = reqwest::get(checksum_url).await?;
-    let checksum_content = checksum_response.text().await?;
-    std::fs::write(checksum_path, checksum_content)?;
-
-    // Files to download
-    let files = vec![
-        "hook_x86_64.tar.gz",
-        "hook_aarch64.tar.gz",
-        "hook_latest-lts-x86_64.tar.gz",
-        "hook_latest-lts-aarch64.tar.gz",
-    ];
 
-    // Create a vector of download futures
-    let download_futures = files.iter().map(|file| {
-        let file = file.to_string();
-        let version = version.to_string();
-        let hookos_dir = hookos_dir.to_path_buf();
-        
-        // Return a future for each download
-        async move {
-            let url = format!("https://github.com/tinkerbell/hook/releases/download/{}/{}", version, file);
-            info!("Downloading {} in parallel", url);
-            let response = reqwest::get(&url).await?;
-            let content = response.bytes().await?;
-            let tarball_path = hookos_dir.join(&file);
-            std::fs::write(&tarball_path, content)?;
-            info!("Downloaded {} to {:?}", file, tarball_path);
-            Ok::<_, anyhow::Error>(tarball_path)
-        }
-    }).collect::<Vec<_>>();
-    
-    // Execute all downloads in parallel
-    let download_results = futures::future::try_join_all(download_futures).await?;
-    info!("All HookOS artifacts downloaded in parallel successfully");
+    (StatusCode::OK, Json(crate::scenarios::recording())).into_response()
+}
 
-    // Create a vector of extraction futures
-    let extraction_futures = download_results.into_iter().map(|tarball_path| {
-        let hookos_dir = hookos_dir.to_path_buf();
-        
-        // Return a future for each extraction
-        async move {
-            let file_name = tarball_path.file_name().unwrap().to_string_lossy().to_string();
-            info!("Extracting {:?} in parallel", tarball_path);
-            
-            // Check if the file exists and has content before trying to extract
-            let metadata = match std::fs::metadata(&tarball_path) {
-                Ok(meta) => meta,
-                Err(e) => {
-                    warn!("Skipping extraction of {:?}: file not accessible: {}", tarball_path, e);
-                    return Ok::<_, anyhow::Error>(tarball_path);
-                }
-            };
-            
-            if metadata.len() == 0 {
-                warn!("Skipping extraction of {:?}: file is empty", tarball_path);
-                return Ok::<_, anyhow::Error>(tarball_path);
-            }
-            
-            // Open the file for reading
-            let tar_file = match File::open(&tarball_path) {
-                Ok(f) => f,
-                Err(e) => {
-                    warn!("Failed to open {:?} for extraction: {}", tarball_path, e);
-                    return Ok::<_, anyhow::Error>(tarball_path);
-                }
-            };
-            
-            // Create the archive and extract, handling any errors
-            // Check if the file is a .tar.gz file
-            if file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz") {
-                // Use GzDecoder for gzipped files
-                let gz = GzDecoder::new(tar_file);
-                let mut archive = Archive::new(gz);
-                match archive.unpack(&hookos_dir) {
-                    Ok(_) => info!("Successfully extracted gzipped archive {:?}", tarball_path),
-                    Err(e) => warn!("Failed to extract gzipped archive {:?}: {}", tarball_path, e),
-                }
-            } else {
-                // For non-gzipped files, use directly
-                let mut archive = Archive::new(tar_file);
-                match archive.unpack(&hookos_dir) {
-                    Ok(_) => info!("Successfully extracted archive {:?}", tarball_path),
-                    Err(e) => warn!("Failed to extract archive {:?}: {}", tarball_path, e),
-                }
-            }
-            
-            Ok::<_, anyhow::Error>(tarball_path)
-        }
-    }).collect::<Vec<_>>();
-    
-    // Execute all extractions in parallel
-    let extraction_results = futures::future::try_join_all(extraction_futures).await?;
-    info!("All HookOS artifacts extracted in parallel successfully");
-    
-    // Remove all tarballs in parallel
-    let cleanup_futures = extraction_results.into_iter().map(|tarball_path| {
-        async move {
-            // Remove the tarball after extraction
-            if let Err(e) = std::fs::remove_file(&tarball_path) {
-                warn!("Failed to remove tarball {:?}: {}", tarball_path, e);
-            } else {
-                info!("Removed tarball {:?}", tarball_path);
-            }
-            Ok::<(), anyhow::Error>(())
-        }
-    }).collect::<Vec<_>>();
-    
-    // Execute all cleanup operations in parallel
-    futures::future::try_join_all(cleanup_futures).await?;
-    
-    info!("HookOS artifacts downloaded, extracted, and cleaned up successfully to {:?}", hookos_dir);
-    Ok(())
+// Start recording events and API calls into a scenario
+#[axum::debug_handler]
+async fn start_scenario_recording(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Json(request): Json<StartRecordingRequest>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    let principal = policy::principal(&auth_session);
+    if let Err(e) = crate::scenarios::start_recording(&request.name, &principal, &state.event_manager) {
+        return json_error(StatusCode::CONFLICT, "Recording Error", e);
+    }
+    info!("Scenario recording {} started by {}", request.name, principal);
+    (StatusCode::ACCEPTED, Json(crate::scenarios::recording())).into_response()
 }
 
-// OS information struct
-#[derive(Debug, Clone, serde::Serialize)]
-pub struct OsInfo {
-    pub name: String,
-    pub icon: String,
+// Stop recording and save the scenario
+#[axum::debug_handler]
+async fn stop_scenario_recording(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match crate::scenarios::stop_recording().await {
+        Ok(summary) => (StatusCode::OK, Json(summary)).into_response(),
+        Err(e) => json_error(StatusCode::CONFLICT, "Recording Error", e.to_string()),
+    }
 }
 
-// Get OS icon for a specific OS
-pub fn get_os_icon(os: &str) -> String {
-    match os {
-        "ubuntu-2204" | "ubuntu-2404" => "<i class=\"fab fa-ubuntu text-orange-500 dark:text-orange-500 no-invert\"></i>",
-        "debian-12" => "<i class=\"fab fa-debian text-red-500\"></i>",
-        "proxmox" => "<i class=\"fas fa-server text-blue-500\"></i>",
-        "talos" => "<i class=\"fas fa-robot text-purple-500\"></i>",
-        "windows" => "<i class=\"fab fa-windows text-blue-400\"></i>",
-        "rocky" | "rocky-9" => "<i class=\"fas fa-mountain text-green-500\"></i>",
-        "fedora" => "<i class=\"fab fa-fedora text-blue-600\"></i>",
-        "alma" | "almalinux" => "<i class=\"fas fa-hat-cowboy text-amber-600\"></i>",
-        _ => "<i class=\"fas fa-square-question text-gray-500\"></i>", // Unknown OS
-    }.to_string()
+// Replay a saved scenario against this instance
+#[axum::debug_handler]
+async fn replay_scenario(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(options): Json<crate::scenarios::ReplayOptions>,
+) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+    if !crate::scenarios::replay_allowed() {
+        return json_error(
+            StatusCode::FORBIDDEN,
+            "Replay Disabled",
+            format!("Replays make real API calls; set {}=true on test instances to allow them", crate::scenarios::REPLAY_ENV_VAR),
+        );
+    }
+
+    let scenario = match crate::scenarios::load(&name).await {
+        Ok(Some(scenario)) => scenario,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "Not Found", format!("No scenario named '{}'", name)),
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, "Scenario Error", e.to_string()),
+    };
+    let management = match crate::network::Listeners::from_env() {
+        Ok(listeners) => listeners.management,
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Configuration Error", e),
+    };
+    let target = if management.ip().is_unspecified() {
+        std::net::SocketAddr::from(([127, 0, 0, 1], management.port()))
+    } else {
+        management
+    };
+    let base_url = format!("http://{}", target);
+    // Replayed calls run as the admin who started the replay
+    let cookie = headers.get(axum::http::header::COOKIE).and_then(|v| v.to_str().ok()).map(str::to_string);
+
+    match crate::scenarios::start_replay(scenario, options, base_url, cookie, state.event_manager.clone()) {
+        Ok(status) => {
+            info!("Scenario {} replay started by {}", name, policy::principal(&auth_session));
+            (StatusCode::ACCEPTED, Json(status)).into_response()
+        },
+        Err(e) => json_error(StatusCode::CONFLICT, "Replay Error", e),
+    }
 }
 
-// Make format_os_name public
-pub fn format_os_name(os: &str) -> String {
-    match os {
-        "ubuntu-2204" => "Ubuntu 22.04",
-        "ubuntu-2404" => "Ubuntu 24.04",
-        "debian-12" => "Debian 12",
-        "proxmox" => "Proxmox VE",
-        "talos" => "Talos",
-        _ => os, // Return original string if no match
-    }.to_string()
+// Progress of the current or last replay
+#[axum::debug_handler]
+async fn get_scenario_replay(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    (StatusCode::OK, Json(crate::scenarios::replay_status())).into_response()
 }
 
-// Get both OS name and icon
-pub fn get_os_info(os: &str) -> OsInfo {
-    OsInfo {
-        name: format_os_name(os),
-        icon: get_os_icon(os),
+#[axum::debug_handler]
+async fn cancel_scenario_replay(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    if !crate::scenarios::cancel_replay() {
+        return json_error(StatusCode::NOT_FOUND, "Not Found", "No replay is running".to_string());
     }
+    StatusCode::ACCEPTED.into_response()
 }
 
-async fn update_installation_progress(
-    State(state): State<AppState>, // State is used for event manager
-    _auth_session: AuthSession, // Mark as unused - updates come from agent/tinkerbell
-    Path(id): Path<Uuid>,
-    Json(payload): Json<InstallationProgressUpdateRequest>,
+#[derive(Deserialize)]
+struct CreateUserRequest {
+    username: String,
+    password: String,
+    #[serde(default)]
+    is_admin: bool,
+}
+
+/// Fields left out stay as they are
+#[derive(Deserialize)]
+struct UpdateUserRequest {
+    disabled: Option<bool>,
+    is_admin: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+struct ConfirmTotpRequest {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct DisableTotpRequest {
+    password: String,
+}
+
+fn user_error_response(e: UserError) -> Response {
+    let (status, error) = match &e {
+        UserError::Invalid(_) => (StatusCode::BAD_REQUEST, "Invalid Request"),
+        UserError::Exists(_) => (StatusCode::CONFLICT, "User Exists"),
+        UserError::NotFound => (StatusCode::NOT_FOUND, "Not Found"),
+        UserError::WrongPassword => (StatusCode::FORBIDDEN, "Wrong Password"),
+        UserError::Database(_) => {
+            error!("User account operation failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database Error")
+        }
+    };
+    let error_response = ErrorResponse {
+        error: error.to_string(),
+        message: e.to_string(),
+    };
+    (status, Json(error_response)).into_response()
+}
+
+#[axum::debug_handler]
+async fn list_users(auth_session: AuthSession) -> Response {
+    if let Err(response) = crate::auth::require_admin(&auth_session) {
+        return response;
+    }
+
+    match db::get_user_accounts().await {
+        Ok(users) => (StatusCode::OK, Json(users)).into_response(),
+        Err(e) => user_error_response(e.into()),
+    }
+}
+
+#[axum::debug_handler]
+async fn create_user(
+    auth_session: AuthSession,
+    Json(payload): Json<CreateUserRequest>,
 ) -> Response {
-    // Remove admin check - allow agent/tinkerbell to post updates
-    /*
     if let Err(response) = crate::auth::require_admin(&auth_session) {
         return response;
     }
-    */
 
-    info!("Updating installation progress for machine {} to {}% (step: {:?})",
-          id, payload.progress, payload.step);
+    match users::create(&payload.username, &payload.password, payload.is_admin, &policy::principal(&auth_session)).await {
+        Ok(user) => (StatusCode::CREATED, Json(user)).into_response(),
+        Err(e) => user_error_response(e),
+    }
+}
 
-    match db::update_installation_progress(&id, payload.progress, payload.step.as_deref()).await {
-        Ok(true) => {
-            // Emit machine updated event so the UI fetches new progress HTML
-            let _ = state.event_manager.send(format!("machine_updated:{}", id));
-            (StatusCode::OK, Json(json!({ "status": "progress_updated", "machine_id": id }))).into_response()
-        },
-        Ok(false) => {
-            let error_response = ErrorResponse {
-                error: "Not Found".to_string(),
-                message: format!("Machine with ID {} not found", id),
-            };
-            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
-        },
-        Err(e) => {
-            error!("Failed to update installation progress for machine {}: {}", id, e);
-            let error_response = ErrorResponse {
-                error: "Database Error".to_string(),
-                message: e.to_string(),
-            };
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
-        }
+#[axum::debug_handler]
+async fn update_user(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+    Json(payload): Json<UpdateUserRequest>,
+) -> Response {
+    let actor = match crate::auth::require_admin(&auth_session) {
+        Ok(actor) => actor,
+        Err(response) => return response,
+    };
+
+    let mut result = db::get_user_account(id).await
+        .map_err(UserError::from)
+        .and_then(|account| account.ok_or(UserError::NotFound));
+    if let Some(disabled) = payload.disabled {
+        result = users::set_disabled(actor, id, disabled).await;
+    }
+    if let (Some(is_admin), Ok(_)) = (payload.is_admin, &result) {
+        result = users::set_admin(actor, id, is_admin).await;
+    }
+    match result {
+        Ok(user) => (StatusCode::OK, Json(user)).into_response(),
+        Err(e) => user_error_response(e),
     }
 }
 
-// Add new handler for getting machine tags
 #[axum::debug_handler]
-async fn api_get_machine_tags(
-    Path(id): Path<Uuid>,
+async fn delete_user(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
 ) -> Response {
-    match get_machine_tags(&id).await {
-        Ok(tags) => (StatusCode::OK, Json(tags)).into_response(),
-        Err(e) => {
-            error!("Failed to get tags for machine {}: {}", id, e);
-            let error_response = ErrorResponse {
-                error: "Database Error".to_string(),
-                message: format!("Failed to retrieve tags: {}", e),
-            };
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
-        }
+    let actor = match crate::auth::require_admin(&auth_session) {
+        Ok(actor) => actor,
+        Err(response) => return response,
+    };
+
+    match users::delete(actor, id).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => user_error_response(e),
     }
 }
 
-// Add new handler for updating machine tags
+// Self-service password change for the signed-in user
 #[axum::debug_handler]
-async fn api_update_machine_tags(
-    State(state): State<AppState>,
+async fn change_own_password(
     auth_session: AuthSession,
-    Path(id): Path<Uuid>,
-    Json(tags): Json<Vec<String>>,
+    Json(payload): Json<ChangePasswordRequest>,
 ) -> Response {
-    // Check if user is authenticated as admin
-    if let Err(response) = crate::auth::require_admin(&auth_session) {
-        return response;
+    let Some(user) = auth_session.user.as_ref() else {
+        return crate::auth::require_admin(&auth_session).unwrap_err();
+    };
+    if crate::break_glass::is_break_glass(user) {
+        return user_error_response(UserError::Invalid("Break-glass sessions reset the admin password from the settings page".to_string()));
     }
 
-    match db_update_machine_tags(&id, &tags).await {
-        Ok(true) => {
-            // Emit machine updated event
-            let _ = state.event_manager.send(format!("machine_updated:{}", id)); 
-            (StatusCode::OK, Json(json!({ "success": true, "message": "Tags updated" }))).into_response()
-        }
-                    Ok(false) => {
-            let error_response = ErrorResponse {
-                error: "Not Found".to_string(),
-                message: format!("Machine with ID {} not found", id),
-            };
-            (StatusCode::NOT_FOUND, Json(error_response)).into_response()
-        }
-                Err(e) => {
-            error!("Failed to update tags for machine {}: {}", id, e);
-            let error_response = ErrorResponse {
-                error: "Database Error".to_string(),
-                message: format!("Failed to update tags: {}", e),
-            };
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response()
-        }
+    match users::change_password(user, &payload.current_password, &payload.new_password).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => user_error_response(e),
+    }
+}
+
+#[axum::debug_handler]
+async fn get_own_totp(auth_session: AuthSession) -> Response {
+    let Some(user) = auth_session.user.as_ref() else {
+        return crate::auth::require_admin(&auth_session).unwrap_err();
+    };
+
+    match crate::totp::status(user.id).await {
+        Ok(status) => (StatusCode::OK, Json(status)).into_response(),
+        Err(e) => user_error_response(e),
+    }
+}
+
+// Start two-factor enrollment; returns the secret and otpauth URI for the QR code
+#[axum::debug_handler]
+async fn begin_totp_enrollment(auth_session: AuthSession) -> Response {
+    let Some(user) = auth_session.user.as_ref() else {
+        return crate::auth::require_admin(&auth_session).unwrap_err();
+    };
+
+    match crate::totp::begin_enrollment(user).await {
+        Ok(enrollment) => (StatusCode::OK, Json(enrollment)).into_response(),
+        Err(e) => user_error_response(e),
+    }
+}
+
+#[axum::debug_handler]
+async fn confirm_totp_enrollment(
+    auth_session: AuthSession,
+    Json(payload): Json<ConfirmTotpRequest>,
+) -> Response {
+    let Some(user) = auth_session.user.as_ref() else {
+        return crate::auth::require_admin(&auth_session).unwrap_err();
+    };
+
+    match crate::totp::confirm_enrollment(user, &payload.code).await {
+        Ok(recovery_codes) => (StatusCode::OK, Json(json!({ "recovery_codes": recovery_codes }))).into_response(),
+        Err(e) => user_error_response(e),
+    }
+}
+
+#[axum::debug_handler]
+async fn disable_own_totp(
+    auth_session: AuthSession,
+    Json(payload): Json<DisableTotpRequest>,
+) -> Response {
+    let Some(user) = auth_session.user.as_ref() else {
+        return crate::auth::require_admin(&auth_session).unwrap_err();
+    };
+
+    match crate::totp::disable(user, &payload.password).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => user_error_response(e),
+    }
+}
+
+#[axum::debug_handler]
+async fn regenerate_recovery_codes(auth_session: AuthSession) -> Response {
+    let Some(user) = auth_session.user.as_ref() else {
+        return crate::auth::require_admin(&auth_session).unwrap_err();
+    };
+
+    match crate::totp::regenerate_recovery_codes(user).await {
+        Ok(recovery_codes) => (StatusCode::OK, Json(json!({ "recovery_codes": recovery_codes }))).into_response(),
+        Err(e) => user_error_response(e),
+    }
+}
+
+// For an operator who lost their authenticator device
+#[axum::debug_handler]
+async fn reset_user_totp(
+    auth_session: AuthSession,
+    Path(id): Path<i64>,
+) -> Response {
+    let actor = match crate::auth::require_admin(&auth_session) {
+        Ok(actor) => actor,
+        Err(response) => return response,
+    };
+
+    match crate::totp::reset(actor, id).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => user_error_response(e),
     }
 }
 
@@ -2820,12 +10040,9 @@ async fn api_delete_machine_tag(
     auth_session: AuthSession,
     Path((id, tag)): Path<(Uuid, String)>,
 ) -> Response {
-    // Check if user is authenticated as admin
-    if auth_session.user.is_none() {
-        return (StatusCode::UNAUTHORIZED, Json(json!({
-            "error": "Unauthorized",
-            "message": "Admin authentication required for this operation"
-        }))).into_response();
+    // Check the caller's permissions on this machine
+    if let Err(response) = policy::authorize(&auth_session, &id, Permission::Operate).await {
+        return response;
     }
 
     // Get current tags for the machine
@@ -2875,7 +10092,7 @@ async fn get_machine_status_and_progress_partial(
 ) -> Response { // Explicitly return Response
     info!("Request for status-and-progress partial for machine {}", id);
 
-    let machine = match db::get_machine_by_id(&id).await {
+    let machine = match crate::demo::machine_by_id(&id).await {
         Ok(Some(m)) => m,
         Ok(None) => return (StatusCode::NOT_FOUND, Html("<!-- Machine not found -->")).into_response(),
         Err(e) => {
@@ -2918,4 +10135,185 @@ async fn get_machine_status_and_progress_partial(
     ui::render_minijinja(&state, "partials/status_and_progress.html", context)
 }
 
-// Utility function to extract client IP
\ No newline at end of file
+// Utility function to extract client IP
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_skew() {
+        let server_time = Utc::now();
+        assert_eq!(clock_skew(server_time + chrono::Duration::seconds(90), server_time), 90);
+        assert_eq!(clock_skew(server_time - chrono::Duration::hours(1), server_time), -3600);
+
+        assert_eq!(parse_clock_skew_threshold(Some("120".to_string())), 120);
+        for unusable in [None, Some("0".to_string()), Some("-5".to_string()), Some("soon".to_string())] {
+            assert_eq!(parse_clock_skew_threshold(unusable), DEFAULT_CLOCK_SKEW_THRESHOLD_SECS);
+        }
+    }
+
+    #[test]
+    fn test_template_ranking() {
+        let ranked = |usage: &[(&str, i64)], community: bool| -> Vec<String> {
+            let usage: HashMap<String, i64> = usage.iter().map(|(template, count)| (template.to_string(), *count)).collect();
+            rank_templates(&usage, community).into_iter().map(|t| t.template).collect()
+        };
+
+        // A new fleet starts from the community defaults
+        assert_eq!(ranked(&[], true), ["ubuntu-2204", "ubuntu-2404", "debian-12", "proxmox", "talos"]);
+        // The fleet's own assignments come first, including templates the defaults don't know
+        assert_eq!(ranked(&[("talos", 3), ("rocky-9", 5)], true)[..3], ["rocky-9", "talos", "ubuntu-2204"]);
+        // Without the defaults, unused templates are alphabetical
+        assert_eq!(ranked(&[("proxmox", 1)], false), ["proxmox", "debian-12", "talos", "ubuntu-2204", "ubuntu-2404"]);
+    }
+
+    #[test]
+    fn test_approval_gate() {
+        assert!(held_at_approval_gate(&MachineStatus::PendingApproval, &MachineStatus::AwaitingAssignment));
+        assert!(held_at_approval_gate(&MachineStatus::Rejected, &MachineStatus::InstallingOS));
+        assert!(!held_at_approval_gate(&MachineStatus::PendingApproval, &MachineStatus::Rejected));
+        assert!(!held_at_approval_gate(&MachineStatus::Ready, &MachineStatus::InstallingOS));
+
+        let script = declined_boot_script(&MachineStatus::Rejected);
+        assert!(script.starts_with("#!ipxe\n"));
+        assert!(script.contains("This machine is rejected in Dragonfly"));
+        // Falls through to the local disk rather than chaining into an installer
+        assert!(script.ends_with("exit 1"));
+        assert!(!script.contains("chain"));
+    }
+
+    #[test]
+    fn test_dry_run_plans() {
+        let machine = crate::test_support::machine("BC:24:11:B9:54:89");
+        let namespace = crate::status::dragonfly_namespace();
+
+        let plan = plan_delete(&machine, true);
+        assert!(plan.dry_run);
+        assert_eq!(plan.affected_machines, [machine.id]);
+        assert_eq!(plan.resources_to_delete, [
+            format!("hardware/{}/machine-bc-24-11-b9-54-89", namespace),
+            format!("workflow/{}/os-install-bc-24-11-b9-54-89", namespace),
+            format!("machine/{}", machine.id),
+        ]);
+        assert_eq!(plan.resources_to_create, [format!("archived_machine/{}", machine.id)]);
+        assert_eq!(plan_delete(&machine, false).resources_to_delete, [format!("machine/{}", machine.id)]);
+
+        // Rejecting keeps the machine, so nothing is archived
+        let plan = plan_reject(&machine);
+        assert_eq!(plan.action, "reject");
+        assert_eq!(plan.resources_to_delete.len(), 2);
+        assert!(plan.resources_to_create.is_empty());
+        assert!(plan.notes[0].ends_with("to Rejected"));
+    }
+
+    #[test]
+    fn test_ipxe_override_script() {
+        assert_eq!(
+            ipxe_override_script(Some("#!ipxe\r\ndhcp\r\nsanboot --no-describe --drive 0x80\r\n".to_string())),
+            Ok(Some("#!ipxe\ndhcp\nsanboot --no-describe --drive 0x80\n".to_string()))
+        );
+        assert_eq!(ipxe_override_script(Some(" \r\n".to_string())), Ok(None));
+        assert_eq!(ipxe_override_script(None), Ok(None));
+        assert!(ipxe_override_script(Some("chain http://10.0.0.1/boot.ipxe".to_string())).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_deleted_machines_are_archived() {
+        let pool = crate::test_support::database().await;
+        let machine = Machine { hostname: Some("db-01".to_string()), ..crate::test_support::machine("bc:24:11:b9:54:89") };
+        sqlx::query("INSERT INTO machines (id, mac_address, ip_address, hostname, status, created_at, updated_at) VALUES (?, ?, ?, ?, 'Ready', 'now', 'now')")
+            .bind(machine.id.to_string())
+            .bind(&machine.mac_address)
+            .bind(&machine.ip_address)
+            .bind(&machine.hostname)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO machine_claims (machine_id, claim_key, claimed_by, claimed_at) VALUES (?, 'ci-42', 'ci', 'now')")
+            .bind(machine.id.to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(db::archive(&pool, &machine, &["storage".to_string()], "alice").await.unwrap());
+        let live: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM machines").fetch_one(&pool).await.unwrap();
+        let claimed: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM machine_claims").fetch_one(&pool).await.unwrap();
+        assert_eq!((live, claimed), (0, 0));
+
+        let archived = db::archived_machine(&pool, &machine.id).await.unwrap().unwrap();
+        assert_eq!(archived.machine.hostname.as_deref(), Some("db-01"));
+        assert_eq!(archived.tags, ["storage"]);
+        assert_eq!(archived.deleted_by, "alice");
+
+        // Deleting a machine that is already gone archives nothing new
+        assert!(!db::archive(&pool, &machine, &[], "bob").await.unwrap());
+        assert_eq!(db::archived_machine(&pool, &machine.id).await.unwrap().unwrap().deleted_by, "alice");
+    }
+
+    #[test]
+    fn test_assignment_refusal() {
+        assert_eq!(assignment_refusal(&[], Ok(()), Ok(())), None);
+
+        let conflicts = vec!["10.0.0.5 is also claimed by db-02".to_string()];
+        let (status, message) = assignment_refusal(&conflicts, Err("out of project".to_string()), Ok(())).unwrap();
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(message.contains("10.0.0.5 is also claimed by db-02"));
+
+        let refused = assignment_refusal(&[], Ok(()), Err("ubuntu-1804 is end of life".to_string()));
+        assert_eq!(refused, Some((StatusCode::FORBIDDEN, "ubuntu-1804 is end of life".to_string())));
+        let refused = assignment_refusal(&[], Err("out of project".to_string()), Err("end of life".to_string()));
+        assert_eq!(refused, Some((StatusCode::FORBIDDEN, "out of project".to_string())));
+    }
+
+    #[test]
+    fn test_machine_summary() {
+        assert_eq!(summary_template(None).unwrap().0, "embed/machine.md");
+        assert_eq!(summary_template(Some("html")).unwrap().0, "embed/machine.html");
+        assert!(summary_template(Some("pdf")).is_err());
+
+        let machine = Machine {
+            hostname: Some("db-01".to_string()),
+            memorable_name: Some("brave-otter".to_string()),
+            os_installed: Some("debian-12".to_string()),
+            bmc_credentials: Some(BmcCredentials {
+                address: "10.9.0.4".to_string(),
+                username: "root".to_string(),
+                password: Some("hunter2".to_string()),
+                bmc_type: BmcType::IPMI,
+            }),
+            ..crate::test_support::machine("bc:24:11:b9:54:89")
+        };
+        let id = machine.id;
+        let change = |n: i64, cause: StatusCause| db::StatusChange {
+            id: n,
+            from: Some(MachineStatus::Ready),
+            to: MachineStatus::Offline,
+            cause: Some(cause),
+            changed_at: chrono::Utc::now(),
+        };
+        let mut history: Vec<_> = (0..12).map(|n| change(n, StatusCause::Admin(format!("user{}", n)))).collect();
+        history.push(change(12, StatusCause::HeartbeatTimeout));
+        history.push(change(13, StatusCause::HeartbeatResumed));
+
+        let context = summary_context(machine, &history, vec!["rack-4".to_string()], Some("https://dragonfly.example/".to_string()));
+        assert_eq!(context["name"], "db-01");
+        assert_eq!(context["os"], "Debian 12");
+        assert_eq!(context["machine_url"], format!("https://dragonfly.example/machines/{}", id));
+        let causes: Vec<_> = context["history"].as_array().unwrap().iter().map(|change| change["cause"].as_str().unwrap()).collect();
+        assert_eq!(causes.len(), SUMMARY_HISTORY);
+        assert_eq!(causes[0], "Changed by user11");
+
+        let mut env = minijinja::Environment::new();
+        crate::ui::setup_minijinja_environment(&mut env).unwrap();
+        env.add_template("machine.md", include_str!("../templates/embed/machine.md")).unwrap();
+        env.add_template("machine.html", include_str!("../templates/embed/machine.html")).unwrap();
+        for template in ["machine.md", "machine.html"] {
+            let rendered = env.get_template(template).unwrap().render(&context).unwrap();
+            assert!(rendered.contains("db-01") && rendered.contains("rack-4") && rendered.contains("Changed by user11"));
+            for secret in ["hunter2", "10.9.0.4", "Heartbeat"] {
+                assert!(!rendered.contains(secret), "{} shows {}", template, secret);
+            }
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use dragonfly_common::models::MachineStatus;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use uuid::Uuid;
+
+use crate::auth::Settings;
+use crate::discovery::{DiscoveredDevice, DiscoveryPolicy};
+use crate::{conflicts, db, os_lifecycle};
+
+/// Discovery scans replayed by default, and at most
+pub const DEFAULT_SCANS: i64 = 20;
+pub const MAX_SCANS: i64 = 200;
+
+/// The settings that decide what happens to new machines without anyone picking an OS:
+/// which discovered devices get registered, whether they wait for approval, and the OS they receive
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct AssignmentPolicy {
+    #[serde(default)]
+    pub default_os: Option<String>,
+    #[serde(default)]
+    pub require_approval: bool,
+    /// Discovery rules (MAC, OUI or CIDR) registering new devices as machines
+    #[serde(default)]
+    pub auto_register: Vec<String>,
+}
+
+impl AssignmentPolicy {
+    pub fn current(settings: &Settings, discovery: &DiscoveryPolicy) -> Self {
+        AssignmentPolicy {
+            default_os: settings.default_os.clone(),
+            require_approval: settings.require_approval,
+            auto_register: discovery.auto_register.clone(),
+        }
+    }
+
+    fn discovery(&self) -> DiscoveryPolicy {
+        DiscoveryPolicy { auto_register: self.auto_register.clone(), ..DiscoveryPolicy::default() }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.default_os.as_deref().is_some_and(|os| os.trim().is_empty()) {
+            return Err("default_os must be a template name or null".to_string());
+        }
+        self.discovery().validate().map_err(|e| e.to_string())
+    }
+}
+
+/// What a policy does with one machine or discovered device
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    /// Receives the OS when an admin approves it
+    InstallOnApproval { os: String },
+    /// Would receive the OS on approval, but a check refuses it
+    Blocked { os: String, reason: String },
+    /// Registered without an OS, waiting for an admin to pick one
+    AwaitAssignment,
+    /// Registered, waiting for approval, and gets no OS after it
+    AwaitApproval,
+    /// A discovered device that isn't registered as a machine
+    NotRegistered,
+    /// Not something the policy acts on
+    Unaffected,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::InstallOnApproval { .. } => "install_on_approval",
+            Outcome::Blocked { .. } => "blocked",
+            Outcome::AwaitAssignment => "await_assignment",
+            Outcome::AwaitApproval => "await_approval",
+            Outcome::NotRegistered => "not_registered",
+            Outcome::Unaffected => "unaffected",
+        }
+    }
+
+    fn is_quiet(&self) -> bool {
+        matches!(self, Outcome::NotRegistered | Outcome::Unaffected)
+    }
+}
+
+/// A machine by its current status, or a device seen by discovery that isn't a machine
+#[derive(Debug, Clone, Copy)]
+pub enum Subject<'a> {
+    Machine(&'a MachineStatus),
+    Device(&'a DiscoveredDevice),
+}
+
+/// Apply a policy to one subject, the way registration and approval do. The default OS is given
+/// out when a machine is approved, so machines already waiting for an OS keep waiting. `blocked`
+/// says why the policy's OS can't be given to the subject, if something (an address conflict,
+/// an end-of-life template) stops it.
+pub fn decide(policy: &AssignmentPolicy, subject: Subject, blocked: Option<&str>) -> Outcome {
+    let status = match subject {
+        Subject::Machine(status) => status.clone(),
+        Subject::Device(device) if policy.discovery().should_auto_register(device) => match policy.require_approval {
+            true => MachineStatus::PendingApproval,
+            false => return Outcome::AwaitAssignment,
+        },
+        Subject::Device(_) => return Outcome::NotRegistered,
+    };
+    match (status, &policy.default_os) {
+        (MachineStatus::PendingApproval, Some(os)) => match blocked {
+            Some(reason) => Outcome::Blocked { os: os.clone(), reason: reason.to_string() },
+            None => Outcome::InstallOnApproval { os: os.clone() },
+        },
+        (MachineStatus::PendingApproval, None) => Outcome::AwaitApproval,
+        _ => Outcome::Unaffected,
+    }
+}
+
+/// One machine or device, under the policy in force and the proposed one
+#[derive(Debug, Clone, Serialize)]
+pub struct Simulated {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub machine_id: Option<Uuid>,
+    pub name: String,
+    pub mac_address: String,
+    pub ip_address: String,
+    /// When discovery first saw a device that isn't a machine
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_seen: Option<DateTime<Utc>>,
+    pub current: Outcome,
+    pub proposed: Outcome,
+    pub changed: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Simulation {
+    pub current_policy: AssignmentPolicy,
+    pub proposed_policy: AssignmentPolicy,
+    /// Discovery scans the devices were taken from
+    pub scans: usize,
+    /// Machines either policy acts on
+    pub fleet: Vec<Simulated>,
+    /// New devices from recent scans that aren't machines, which either policy would register
+    pub discovered: Vec<Simulated>,
+    /// How many machines and devices end up with each outcome under the proposed policy
+    pub summary: BTreeMap<&'static str, usize>,
+    /// How many would be treated differently from today
+    pub changed: usize,
+}
+
+/// Why each OS can't be assigned right now, if anything stops it
+async fn lifecycle_blocks(policies: [&AssignmentPolicy; 2]) -> Result<HashMap<String, String>> {
+    let mut blocks = HashMap::new();
+    for os in policies.iter().filter_map(|p| p.default_os.as_ref()) {
+        if let Err(reason) = os_lifecycle::check_assignment(os).await? {
+            blocks.insert(os.clone(), reason);
+        }
+    }
+    Ok(blocks)
+}
+
+/// Run the proposed policy and the one in force against the fleet and the new devices from the
+/// last `scans` discovery scans, without changing anything
+pub async fn simulate(proposed: AssignmentPolicy, scans: i64) -> Result<Simulation> {
+    let (settings, discovery, machines, reports) = tokio::try_join!(
+        db::get_app_settings(),
+        db::get_discovery_policy(),
+        db::get_all_machines(),
+        db::get_discovery_reports(scans.clamp(1, MAX_SCANS)),
+    )?;
+    let current = AssignmentPolicy::current(&settings, &discovery);
+    let os_blocks = lifecycle_blocks([&current, &proposed]).await?;
+    let address_conflicts = conflicts::reasons(&machines);
+
+    let blocked = |policy: &AssignmentPolicy, machine_id: Option<&Uuid>| -> Option<String> {
+        let os = policy.default_os.as_ref()?;
+        let conflict = machine_id.and_then(|id| address_conflicts.get(id)).filter(|r| !r.is_empty());
+        os_blocks.get(os).cloned().or_else(|| conflict.map(|r| r.join("; ")))
+    };
+    let compare = |subject: Subject, machine_id: Option<&Uuid>| {
+        let current = decide(&current, subject, blocked(&current, machine_id).as_deref());
+        let proposed = decide(&proposed, subject, blocked(&proposed, machine_id).as_deref());
+        (current, proposed)
+    };
+
+    let mut fleet = Vec::new();
+    for machine in &machines {
+        let (current, proposed) = compare(Subject::Machine(&machine.status), Some(&machine.id));
+        if current.is_quiet() && proposed.is_quiet() {
+            continue;
+        }
+        fleet.push(Simulated {
+            machine_id: Some(machine.id),
+            name: machine.display_name(),
+            mac_address: machine.mac_address.clone(),
+            ip_address: machine.ip_address.clone(),
+            first_seen: None,
+            changed: current != proposed,
+            current,
+            proposed,
+        });
+    }
+
+    // Reports come newest first; walk them oldest first so each device keeps its first sighting
+    let known: HashSet<String> = machines.iter().map(|m| m.mac_address.to_lowercase()).collect();
+    let mut seen = HashSet::new();
+    let mut discovered = Vec::new();
+    for report in reports.iter().rev() {
+        for device in &report.diff.new_devices {
+            if known.contains(&device.mac_address.to_lowercase()) || !seen.insert(device.mac_address.clone()) {
+                continue;
+            }
+            let (current, proposed) = compare(Subject::Device(device), None);
+            if current.is_quiet() && proposed.is_quiet() {
+                continue;
+            }
+            discovered.push(Simulated {
+                machine_id: None,
+                name: device.mac_address.clone(),
+                mac_address: device.mac_address.clone(),
+                ip_address: device.ip_address.clone(),
+                first_seen: Some(report.started_at),
+                changed: current != proposed,
+                current,
+                proposed,
+            });
+        }
+    }
+
+    let mut summary = BTreeMap::new();
+    for entry in fleet.iter().chain(&discovered) {
+        *summary.entry(entry.proposed.as_str()).or_insert(0) += 1;
+    }
+    let changed = fleet.iter().chain(&discovered).filter(|e| e.changed).count();
+    Ok(Simulation { current_policy: current, proposed_policy: proposed, scans: reports.len(), fleet, discovered, summary, changed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decide() {
+        let policy = AssignmentPolicy {
+            default_os: Some("ubuntu-2404".to_string()),
+            require_approval: true,
+            auto_register: vec!["10.0.5.0/24".to_string()],
+        };
+        let install = Outcome::InstallOnApproval { os: "ubuntu-2404".to_string() };
+        let device = |ip: &str| DiscoveredDevice { mac_address: "aa:bb:cc:00:00:01".to_string(), ip_address: ip.to_string() };
+
+        assert_eq!(decide(&policy, Subject::Machine(&MachineStatus::PendingApproval), None), install);
+        assert_eq!(decide(&policy, Subject::Machine(&MachineStatus::AwaitingAssignment), None), Outcome::Unaffected);
+        assert_eq!(decide(&policy, Subject::Device(&device("10.0.5.9")), None), install);
+        assert_eq!(decide(&policy, Subject::Device(&device("10.0.6.9")), None), Outcome::NotRegistered);
+        assert!(matches!(decide(&policy, Subject::Machine(&MachineStatus::PendingApproval), Some("conflict")), Outcome::Blocked { .. }));
+
+        let open = AssignmentPolicy { require_approval: false, ..policy };
+        assert_eq!(decide(&open, Subject::Device(&device("10.0.5.9")), None), Outcome::AwaitAssignment);
+    }
+}
@@ -0,0 +1,281 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use dragonfly_common::agent_protocol::{AttestationChallenge, TpmQuote};
+use dragonfly_common::models::TpmIdentity;
+use p256::pkcs8::{DecodePublicKey, EncodePublicKey};
+use rand::RngCore;
+use rsa::signature::Verifier;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::db;
+
+/// When set, machines that never reported a TPM can't enroll either
+pub const REQUIRE_TPM_ENV_VAR: &str = "DRAGONFLY_REQUIRE_TPM";
+
+const CHALLENGE_SECS: i64 = 300;
+/// Most challenges waiting for a quote at once, so asking for them can't exhaust memory
+const MAX_PENDING_CHALLENGES: usize = 4096;
+
+/// TPM_GENERATED_VALUE: only the TPM itself produces attestation structures starting with this
+const TPM_GENERATED: u32 = 0xff54_4347;
+/// TPM_ST_ATTEST_QUOTE
+const ATTEST_QUOTE: u16 = 0x8018;
+
+type Challenges = HashMap<Uuid, (Vec<u8>, DateTime<Utc>)>;
+
+lazy_static::lazy_static! {
+    static ref CHALLENGES: Mutex<Challenges> = Mutex::new(HashMap::new());
+}
+
+/// What the API shows about a machine's pinned TPM keys
+#[derive(Debug, Clone, Serialize)]
+pub struct TpmStatus {
+    pub ek_fingerprint: Option<String>,
+    pub ak_fingerprint: Option<String>,
+    pub recorded_at: DateTime<Utc>,
+}
+
+enum PublicKey {
+    Rsa(rsa::RsaPublicKey),
+    P256(p256::ecdsa::VerifyingKey),
+}
+
+pub fn required() -> bool {
+    env::var(REQUIRE_TPM_ENV_VAR).is_ok_and(|v| v == "true" || v == "1")
+}
+
+// TPMs use RSA 2048 or NIST P-256 keys; `tpm2_readpublic -f pem` writes them as SubjectPublicKeyInfo PEM
+fn parse_public_key(pem: &str) -> Result<PublicKey, String> {
+    if let Ok(key) = rsa::RsaPublicKey::from_public_key_pem(pem) {
+        return Ok(PublicKey::Rsa(key));
+    }
+    p256::ecdsa::VerifyingKey::from_public_key_pem(pem)
+        .map(PublicKey::P256)
+        .map_err(|_| "Not an RSA or P-256 public key in PEM format".to_string())
+}
+
+/// SHA-256 of the key's DER encoding, for comparing keys by eye
+pub fn fingerprint(pem: &str) -> Option<String> {
+    let der = match parse_public_key(pem).ok()? {
+        PublicKey::Rsa(key) => key.to_public_key_der().ok()?.into_vec(),
+        PublicKey::P256(key) => key.to_public_key_der().ok()?.into_vec(),
+    };
+    Some(hex::encode(Sha256::digest(der)))
+}
+
+fn read_u16(data: &[u8], at: &mut usize) -> Option<u16> {
+    let bytes = data.get(*at..*at + 2)?;
+    *at += 2;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_sized<'a>(data: &'a [u8], at: &mut usize) -> Option<&'a [u8]> {
+    let size = read_u16(data, at)? as usize;
+    let bytes = data.get(*at..*at + size)?;
+    *at += size;
+    Some(bytes)
+}
+
+/// The qualifying data of a TPMS_ATTEST quote, i.e. the nonce the TPM was asked to sign
+fn quote_nonce(attest: &[u8]) -> Result<&[u8], String> {
+    let magic = attest.get(..4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    if magic != Some(TPM_GENERATED) {
+        return Err("Quote was not generated by a TPM".to_string());
+    }
+    let mut at = 4;
+    if read_u16(attest, &mut at) != Some(ATTEST_QUOTE) {
+        return Err("Attestation is not a quote".to_string());
+    }
+    read_sized(attest, &mut at).ok_or("Truncated quote")?; // qualifiedSigner
+    read_sized(attest, &mut at).ok_or_else(|| "Truncated quote".to_string())
+}
+
+fn verify_signature(key: &PublicKey, message: &[u8], signature: &[u8]) -> bool {
+    match key {
+        PublicKey::Rsa(key) => rsa::pkcs1v15::Signature::try_from(signature)
+            .is_ok_and(|sig| rsa::pkcs1v15::VerifyingKey::<Sha256>::new(key.clone()).verify(message, &sig).is_ok()),
+        // tpm2_quote writes ECDSA signatures DER-encoded; accept raw r || s too
+        PublicKey::P256(key) => p256::ecdsa::Signature::from_der(signature)
+            .or_else(|_| p256::ecdsa::Signature::from_slice(signature))
+            .is_ok_and(|sig| key.verify(message, &sig).is_ok()),
+    }
+}
+
+/// Check a quote over `nonce` was signed by the attestation key
+fn verify_quote(ak_public: &str, nonce: &[u8], attest: &[u8], signature: &[u8]) -> Result<(), String> {
+    let key = parse_public_key(ak_public)?;
+    if !verify_signature(&key, attest, signature) {
+        return Err("Quote signature does not match the machine's attestation key".to_string());
+    }
+    if quote_nonce(attest)? != nonce {
+        return Err("Quote is not over the issued challenge".to_string());
+    }
+    Ok(())
+}
+
+/// Pin the TPM keys a machine reports at discovery. A known machine reporting different keys is
+/// refused; the error explains why.
+pub async fn record_identity(machine_id: &Uuid, identity: &TpmIdentity) -> Result<Result<(), String>> {
+    for pem in [&identity.ek_public, &identity.ak_public] {
+        if let Err(e) = parse_public_key(pem) {
+            return Ok(Err(e));
+        }
+    }
+    match db::get_tpm_identity(machine_id).await? {
+        Some((pinned, _)) if pinned == *identity => Ok(Ok(())),
+        Some(_) => {
+            warn!("Machine {} reported TPM keys that don't match the pinned ones", machine_id);
+            Ok(Err("TPM keys don't match the ones recorded for this machine".to_string()))
+        },
+        None => {
+            db::save_tpm_identity(machine_id, identity).await?;
+            info!("Pinned TPM keys for machine {}", machine_id);
+            Ok(Ok(()))
+        }
+    }
+}
+
+/// Issue a fresh nonce for the machine's TPM to quote, replacing any earlier one. None while too
+/// many other challenges are waiting to be answered.
+pub fn issue_challenge(machine_id: &Uuid, now: DateTime<Utc>) -> Option<AttestationChallenge> {
+    let mut nonce = vec![0u8; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let encoded = hex::encode(&nonce);
+    let mut challenges = CHALLENGES.lock().unwrap();
+    insert_challenge(&mut challenges, MAX_PENDING_CHALLENGES, machine_id, nonce, now)
+        .then_some(AttestationChallenge { nonce: encoded })
+}
+
+fn insert_challenge(challenges: &mut Challenges, cap: usize, machine_id: &Uuid, nonce: Vec<u8>, now: DateTime<Utc>) -> bool {
+    challenges.retain(|_, (_, expires_at)| *expires_at > now);
+    if challenges.len() >= cap && !challenges.contains_key(machine_id) {
+        return false;
+    }
+    challenges.insert(*machine_id, (nonce, now + Duration::seconds(CHALLENGE_SECS)));
+    true
+}
+
+/// Drop challenges that expired without being answered, returning how many there were
+pub fn prune_challenges(now: DateTime<Utc>) -> usize {
+    let mut challenges = CHALLENGES.lock().unwrap();
+    let before = challenges.len();
+    challenges.retain(|_, (_, expires_at)| *expires_at > now);
+    before - challenges.len()
+}
+
+fn take_challenge(machine_id: &Uuid, now: DateTime<Utc>) -> Option<Vec<u8>> {
+    let mut challenges = CHALLENGES.lock().unwrap();
+    challenges.retain(|_, (_, expires_at)| *expires_at > now);
+    challenges.remove(machine_id).map(|(nonce, _)| nonce)
+}
+
+/// Whether a registration that reported no TPM may go ahead. A machine whose keys were pinned
+/// can't drop its TPM, or anything copying its MAC address could simply leave the keys out.
+pub async fn check_missing_tpm(machine_id: Option<&Uuid>) -> Result<Result<(), String>> {
+    let pinned = match machine_id {
+        Some(id) => db::get_tpm_identity(id).await?.is_some(),
+        None => false,
+    };
+    Ok(missing_tpm(pinned, required()))
+}
+
+fn missing_tpm(pinned: bool, required: bool) -> Result<(), String> {
+    if pinned {
+        return Err("This machine has a TPM identity on record but reported no TPM".to_string());
+    }
+    if required {
+        return Err("The server requires a TPM and this machine reported none".to_string());
+    }
+    Ok(())
+}
+
+/// Whether an agent may enroll: machines with pinned TPM keys must quote the challenge with
+/// their attestation key. The error explains why not.
+pub async fn check_enrollment(machine_id: &Uuid, quote: Option<&TpmQuote>) -> Result<Result<(), String>> {
+    let Some((identity, _)) = db::get_tpm_identity(machine_id).await? else {
+        if required() {
+            return Ok(Err("This machine has no TPM identity on record and the server requires one".to_string()));
+        }
+        return Ok(Ok(()));
+    };
+    let Some(quote) = quote else {
+        return Ok(Err("This machine has a TPM identity on record; enroll with a TPM quote".to_string()));
+    };
+    let Some(nonce) = take_challenge(machine_id, Utc::now()) else {
+        return Ok(Err("No attestation challenge is pending; request one first".to_string()));
+    };
+    let (Ok(message), Ok(signature)) = (hex::decode(&quote.message), hex::decode(&quote.signature)) else {
+        return Ok(Err("Quote message and signature must be hex-encoded".to_string()));
+    };
+    Ok(verify_quote(&identity.ak_public, &nonce, &message, &signature))
+}
+
+pub async fn status(machine_id: &Uuid) -> Result<Option<TpmStatus>> {
+    Ok(db::get_tpm_identity(machine_id).await?.map(|(identity, recorded_at)| TpmStatus {
+        ek_fingerprint: fingerprint(&identity.ek_public),
+        ak_fingerprint: fingerprint(&identity.ak_public),
+        recorded_at,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::signature::Signer;
+    use p256::pkcs8::LineEnding;
+
+    fn quote(nonce: &[u8]) -> Vec<u8> {
+        let mut attest = Vec::new();
+        attest.extend_from_slice(&TPM_GENERATED.to_be_bytes());
+        attest.extend_from_slice(&ATTEST_QUOTE.to_be_bytes());
+        attest.extend_from_slice(&[0, 2, 0xaa, 0xbb]);
+        attest.extend_from_slice(&(nonce.len() as u16).to_be_bytes());
+        attest.extend_from_slice(nonce);
+        attest.extend_from_slice(&[0; 25]);
+        attest
+    }
+
+    #[test]
+    fn test_verify_quote() {
+        let signing_key = p256::ecdsa::SigningKey::random(&mut rand::rngs::OsRng);
+        let ak_public = signing_key.verifying_key().to_public_key_pem(LineEnding::LF).unwrap();
+        let nonce = [7u8; 32];
+        let attest = quote(&nonce);
+        let signature: p256::ecdsa::Signature = signing_key.sign(&attest);
+
+        assert!(verify_quote(&ak_public, &nonce, &attest, signature.to_der().as_bytes()).is_ok());
+        assert!(verify_quote(&ak_public, &[8u8; 32], &attest, &signature.to_bytes()).is_err());
+        let mut tampered = attest.clone();
+        tampered[40] ^= 1;
+        assert!(verify_quote(&ak_public, &nonce, &tampered, &signature.to_bytes()).is_err());
+        assert!(fingerprint(&ak_public).is_some());
+    }
+
+    #[test]
+    fn test_challenges_are_capped() {
+        let now = Utc::now();
+        let mut challenges = Challenges::new();
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        assert!(insert_challenge(&mut challenges, 2, &first, vec![1], now));
+        assert!(insert_challenge(&mut challenges, 2, &second, vec![2], now));
+        assert!(!insert_challenge(&mut challenges, 2, &third, vec![3], now));
+        // A machine asking again replaces its own challenge
+        assert!(insert_challenge(&mut challenges, 2, &first, vec![4], now));
+        // and expired ones make room
+        assert!(insert_challenge(&mut challenges, 2, &third, vec![3], now + Duration::seconds(CHALLENGE_SECS + 1)));
+        assert_eq!(challenges.len(), 1);
+    }
+
+    #[test]
+    fn test_missing_tpm() {
+        assert!(missing_tpm(false, false).is_ok());
+        assert!(missing_tpm(true, false).is_err());
+        assert!(missing_tpm(false, true).is_err());
+    }
+}
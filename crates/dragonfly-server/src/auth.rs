@@ -7,11 +7,12 @@ use axum::{
     middleware::Next,
     response::{IntoResponse, Redirect, Response, Html},
     routing::{get, post},
-    Extension, Form,
+    Extension, Form, Json,
     Router,
 };
 use axum_extra::extract::{cookie::Key, SignedCookieJar};
 use base64::engine::Engine;
+use serde_json::json;
 use oauth2::{
     basic::BasicClient,
     reqwest::async_http_client,
@@ -19,11 +20,12 @@ use oauth2::{
     PkceCodeVerifier, RedirectUrl, Scope, TokenResponse, TokenUrl,
 };
 use serde::{Deserialize, Serialize};
+use sqlx::Row;
 use std::env;
 use tower_http::trace::TraceLayer;
 use tracing::{debug, error, info, warn};
 use argon2::{
-    password_hash::{PasswordHash, PasswordVerifier as ArgonPasswordVerifier, SaltString},
+    password_hash::SaltString,
     Argon2, PasswordHasher,
 };
 use rand::rngs::OsRng;
@@ -43,6 +45,10 @@ use crate::{
     ui::AlertMessage,
     AppState,
 };
+use crate::hostname_policy::HostnamePolicy;
+use crate::rate_limit::{AccountFrom, RateLimitLayer};
+use crate::boot_filter::BootFilter;
+use crate::status_board::StatusBoardConfig;
 
 // Constants for the initial password file (not for loading, just for UX)
 const INITIAL_PASSWORD_FILE: &str = "initial_password.txt";
@@ -90,10 +96,30 @@ pub struct LoginForm {
     pub password: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct TotpForm {
+    pub code: String,
+}
+
+// Session key for a login that passed the password check and still needs its second factor
+const TOTP_PENDING_KEY: &str = "totp_pending";
+const TOTP_PENDING_SECS: i64 = 300;
+const TOTP_MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingLogin {
+    user: AdminUser,
+    started_at: chrono::DateTime<chrono::Utc>,
+    attempts: u32,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct AdminUser {
     pub id: i64,
     pub username: String,
+    /// May do anything; other users need an ACL grant for each machine
+    #[serde(default)]
+    pub is_admin: bool,
 }
 
 impl AuthUser for AdminUser {
@@ -153,6 +179,21 @@ pub struct Settings {
     pub oauth_auth_url: Option<String>,
     pub oauth_token_url: Option<String>,
     pub oauth_redirect_url: Option<String>,
+    pub hostname_policy: HostnamePolicy,
+    pub require_approval: bool,
+    pub boot_filter: BootFilter,
+    /// Give every machine a unique root password at install time
+    pub generate_root_passwords: bool,
+    /// Refuse to assign OS templates that are past their end-of-life date
+    pub block_eol_assignments: bool,
+    /// Theme for browsers that haven't chosen one; light when unset
+    pub default_theme: Option<String>,
+    /// Serve the iPXE endpoints only to signed-in users and the exempt subnets
+    pub require_boot_auth: bool,
+    /// Subnets, in CIDR notation, that may boot while the iPXE endpoints are protected
+    pub boot_auth_exempt_subnets: Vec<String>,
+    /// The public status board page
+    pub status_board: StatusBoardConfig,
 }
 
 impl Default for Settings {
@@ -168,6 +209,15 @@ impl Default for Settings {
             oauth_auth_url: None,
             oauth_token_url: None,
             oauth_redirect_url: None,
+            hostname_policy: HostnamePolicy::default(),
+            require_approval: false,
+            boot_filter: BootFilter::default(),
+            generate_root_passwords: false,
+            block_eol_assignments: false,
+            default_theme: None,
+            require_boot_auth: false,
+            boot_auth_exempt_subnets: Vec::new(),
+            status_board: StatusBoardConfig::default(),
         }
     }
 }
@@ -225,16 +275,21 @@ impl AuthnBackend for AdminBackend {
     ) -> Result<Option<Self::User>, Self::Error> {
         let username = creds.username.clone();
 
-        // Fetch the stored hash from the database
-        let stored_hash = match sqlx::query!(
-            "SELECT password_hash FROM admin_credentials WHERE username = ?",
-            creds.username
-        )
-        .fetch_optional(&self.db)
-        .await
+        // Fetch the account from the database
+        let row = match sqlx::query("SELECT id, password_hash, disabled, is_admin FROM users WHERE username = ?")
+            .bind(&creds.username)
+            .fetch_optional(&self.db)
+            .await
         {
-            Ok(Some(record)) => record.password_hash,
+            Ok(Some(row)) => row,
             Ok(None) => {
+                // Not an account; it may be a break-glass credential issued from the CLI
+                if let Some(password) = creds.password {
+                    return crate::break_glass::authenticate(&username, password).await.map_err(|e| {
+                        error!("Break-glass authentication error for user '{}': {}", username, e);
+                        MiniJinjaError::new(MiniJinjaErrorKind::InvalidOperation, format!("Break-glass authentication error: {}", e))
+                    });
+                }
                 info!("Authentication failed: User '{}' not found", creds.username);
                 return Ok(None);
             }
@@ -243,64 +298,54 @@ impl AuthnBackend for AdminBackend {
                 return Err(MiniJinjaError::new(MiniJinjaErrorKind::InvalidOperation, format!("Database error: {}", e)));
             }
         };
+        let user_id: i64 = row.get("id");
+        let stored_hash: String = row.get("password_hash");
 
-        // Convert password to owned bytes Option
-        let password_bytes = creds.password.map(|p| p.into_bytes());
-
-        // Verify the password using Argon2 within a blocking task
-        let is_valid = match password_bytes {
-            Some(bytes) => {
-                // Move stored_hash and bytes into the closure
-                match tokio::task::spawn_blocking(move || {
-                    match PasswordHash::new(&stored_hash) {
-                        Ok(parsed_hash) => {
-                            Argon2::default().verify_password(&bytes, &parsed_hash).is_ok()
-                        }
-                        Err(_) => false // Error parsing hash means invalid
-                    }
-                }).await {
-                    Ok(result) => result,
-                    Err(e) => {
-                        error!("Spawn blocking task failed during password verification for user '{}': {}", username, e);
-                        false // Treat join errors as verification failure
-                    }
-                }
-            }
+        let is_valid = match creds.password {
+            Some(password) => crate::users::verify_password(stored_hash, password).await,
             None => {
                 info!("Authentication failed for user '{}': No password provided", username);
                 false // No password provided
             }
         };
 
-        if is_valid {
-            info!("Authentication successful for user '{}'", username);
-            // Fetch the user ID from the database to create the AdminUser
-             match sqlx::query_as!(AdminUser, "SELECT id, username FROM admin_credentials WHERE username = ?", username)
-                .fetch_one(&self.db)
-                .await {
-                    Ok(user) => Ok(Some(user)),
-                    Err(e) => {
-                        error!("Database error fetching user details for '{}': {}", username, e);
-                         Err(MiniJinjaError::new(MiniJinjaErrorKind::InvalidOperation, format!("Database error fetching user: {}", e)))
-                    }
-                }
-        } else {
+        if !is_valid {
             info!("Authentication failed: Invalid password for user '{}'", username);
-            Ok(None)
+            return Ok(None);
         }
+        // Checked after the password so a disabled account doesn't reveal itself to guesses
+        if row.get::<bool, _>("disabled") {
+            warn!("Authentication refused for disabled user '{}'", username);
+            return Ok(None);
+        }
+
+        info!("Authentication successful for user '{}'", username);
+        if let Err(e) = sqlx::query("UPDATE users SET last_login_at = ? WHERE id = ?")
+            .bind(chrono::Utc::now().to_rfc3339())
+            .bind(user_id)
+            .execute(&self.db)
+            .await
+        {
+            warn!("Failed to record last login for user '{}': {}", username, e);
+        }
+        Ok(Some(AdminUser { id: user_id, username, is_admin: row.get("is_admin") }))
     }
 
     async fn get_user(&self, user_id: &UserId<Self>) -> Result<Option<Self::User>, Self::Error> {
-        // Fetch the user from the database based on the user_id
-        match sqlx::query_as!(
-            AdminUser,
-            "SELECT id, username FROM admin_credentials WHERE id = ?",
-            user_id
-        )
-        .fetch_optional(&self.db)
-        .await
+        if *user_id < 0 {
+            return crate::break_glass::get_user(*user_id).await.map_err(|e| {
+                error!("Database error fetching break-glass session '{}': {}", user_id, e);
+                MiniJinjaError::new(MiniJinjaErrorKind::InvalidOperation, format!("Database error fetching break-glass session: {}", e))
+            });
+        }
+
+        // Fetch the user from the database based on the user_id; disabled accounts lose their sessions
+        match sqlx::query("SELECT id, username, is_admin FROM users WHERE id = ? AND disabled = 0")
+            .bind(user_id)
+            .fetch_optional(&self.db)
+            .await
         {
-            Ok(user_opt) => Ok(user_opt),
+            Ok(row) => Ok(row.map(|row| AdminUser { id: row.get("id"), username: row.get("username"), is_admin: row.get("is_admin") })),
             Err(e) => {
                 error!("Database error fetching user by ID '{}': {}", user_id, e);
                  Err(MiniJinjaError::new(MiniJinjaErrorKind::InvalidOperation, format!("Database error fetching user by ID: {}", e)))
@@ -312,9 +357,15 @@ impl AuthnBackend for AdminBackend {
 pub type AuthSession = axum_login::AuthSession<AdminBackend>;
 
 pub fn auth_router() -> Router<crate::AppState> {
+    // Both login steps share one limiter, so guessing codes counts against the same address
+    let rate_limit = RateLimitLayer::new(AccountFrom::FormField("username"))
+        .redirect_when_locked("/login?error=too_many_attempts");
+
     Router::new()
         .route("/login", get(login_page))
-        .route("/login", post(login_handler))
+        .route("/login", post(login_handler).layer(rate_limit.clone()))
+        .route("/login/verify", get(login_verify_page))
+        .route("/login/verify", post(login_verify_handler).layer(rate_limit))
         .route("/logout", post(logout))
         .route("/login-test", get(login_test_handler))
 }
@@ -323,17 +374,32 @@ pub fn auth_router() -> Router<crate::AppState> {
 struct LoginTemplate {
     is_demo_mode: bool,
     error: Option<String>,
+    /// Asking for the authenticator code after the password was accepted
+    totp_step: bool,
 }
 
 async fn login_page(
     State(app_state): State<crate::AppState>,
     Query(params): Query<HashMap<String, String>>,
-) -> impl IntoResponse {
+) -> Response {
+    render_login(&app_state, params.get("error").cloned(), false)
+}
+
+async fn login_verify_page(
+    State(app_state): State<crate::AppState>,
+    auth_session: AuthSession,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    match auth_session.session.get::<PendingLogin>(TOTP_PENDING_KEY).await {
+        Ok(Some(_)) => render_login(&app_state, params.get("error").cloned(), true),
+        _ => Redirect::to("/login").into_response(),
+    }
+}
+
+fn render_login(app_state: &crate::AppState, error: Option<String>, totp_step: bool) -> Response {
     // Check if we're in demo mode
     let is_demo_mode = std::env::var("DRAGONFLY_DEMO_MODE").is_ok();
     
-    // Check for error parameter
-    let error = params.get("error").cloned();
     if let Some(err) = &error {
         info!("Login page loaded with error: {}", err);
     }
@@ -341,6 +407,7 @@ async fn login_page(
     let template = LoginTemplate {
         is_demo_mode,
         error,
+        totp_step,
     };
     
     // Get the environment based on the mode (static or reloading)
@@ -394,6 +461,7 @@ async fn login_handler(
         let demo_user = AdminUser {
             id: 1,
             username,
+            is_admin: true,
         };
         
         // Hard-set the user session
@@ -425,6 +493,23 @@ async fn login_handler(
     // Try to authenticate the user
     match auth_session.authenticate(credentials).await {
         Ok(Some(user)) => {
+            match crate::totp::is_required(user.id).await {
+                Ok(false) => {},
+                Ok(true) => {
+                    info!("Password accepted for user '{}'; waiting for their authenticator code", user.username);
+                    let pending = PendingLogin { user, started_at: chrono::Utc::now(), attempts: 0 };
+                    if let Err(e) = auth_session.session.insert(TOTP_PENDING_KEY, pending).await {
+                        error!("Failed to store pending two-factor login: {}", e);
+                        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                    }
+                    return Redirect::to("/login/verify").into_response();
+                },
+                Err(e) => {
+                    error!("Failed to check two-factor authentication for user '{}': {}", user.username, e);
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            }
+
             // Successfully authenticated, set up the session
             if let Err(e) = auth_session.login(&user).await {
                 error!("Failed to create session after successful auth: {}", e);
@@ -445,6 +530,55 @@ async fn login_handler(
     }
 }
 
+// Second login step for accounts with two-factor authentication
+async fn login_verify_handler(
+    mut auth_session: AuthSession,
+    Form(form): Form<TotpForm>,
+) -> Response {
+    let mut pending = match auth_session.session.get::<PendingLogin>(TOTP_PENDING_KEY).await {
+        Ok(Some(pending)) => pending,
+        Ok(None) => return Redirect::to("/login").into_response(),
+        Err(e) => {
+            error!("Failed to read pending two-factor login: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    if chrono::Utc::now() - pending.started_at > chrono::Duration::seconds(TOTP_PENDING_SECS) {
+        let _ = auth_session.session.remove::<PendingLogin>(TOTP_PENDING_KEY).await;
+        return Redirect::to("/login?error=totp_expired").into_response();
+    }
+
+    match crate::totp::verify_login(pending.user.id, &form.code).await {
+        Ok(true) => {
+            let _ = auth_session.session.remove::<PendingLogin>(TOTP_PENDING_KEY).await;
+            if let Err(e) = auth_session.login(&pending.user).await {
+                error!("Failed to create session after two-factor authentication: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+            info!("Login successful for user '{}' with two-factor authentication", pending.user.username);
+            Redirect::to("/").into_response()
+        }
+        Ok(false) => {
+            pending.attempts += 1;
+            warn!("Invalid authenticator code for user '{}' (attempt {})", pending.user.username, pending.attempts);
+            // Too many wrong codes sends the user back to the password step
+            if pending.attempts >= TOTP_MAX_ATTEMPTS {
+                let _ = auth_session.session.remove::<PendingLogin>(TOTP_PENDING_KEY).await;
+                return Redirect::to("/login?error=invalid_credentials").into_response();
+            }
+            if let Err(e) = auth_session.session.insert(TOTP_PENDING_KEY, pending).await {
+                error!("Failed to store pending two-factor login: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+            Redirect::to("/login/verify?error=invalid_code").into_response()
+        }
+        Err(e) => {
+            error!("Error during two-factor authentication: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
 async fn logout(mut auth_session: AuthSession) -> Response {
     match auth_session.logout().await {
         Ok(_) => Redirect::to("/login")
@@ -565,10 +699,23 @@ pub async fn save_settings(settings: &Settings) -> io::Result<()> {
     }
 }
 
-pub fn require_admin(auth_session: &AuthSession) -> Result<(), Response> {
-    match auth_session.user {
-        Some(_) => Ok(()),
-        None => Err(Redirect::to("/login").into_response()),
+/// Whether the session belongs to an administrator
+pub fn is_admin(auth_session: &AuthSession) -> bool {
+    auth_session.user.as_ref().is_some_and(|user| user.is_admin)
+}
+
+/// Gate for admin-only endpoints: 401 without a login, 403 for users who aren't administrators
+pub fn require_admin(auth_session: &AuthSession) -> Result<&AdminUser, Response> {
+    match &auth_session.user {
+        Some(user) if user.is_admin => Ok(user),
+        Some(_) => Err((StatusCode::FORBIDDEN, Json(json!({
+            "error": "Forbidden",
+            "message": "Administrator rights are required for this operation"
+        }))).into_response()),
+        None => Err((StatusCode::UNAUTHORIZED, Json(json!({
+            "error": "Unauthorized",
+            "message": "Authentication required for this operation"
+        }))).into_response()),
     }
 }
 
@@ -671,6 +818,7 @@ pub async fn oauth_callback(
     let user = AdminUser {
         id: 1, // Or generate a unique ID based on OAuth provider info
         username: "oauth_user".to_string(), // Use actual username from provider
+        is_admin: false,
     };
 
     // Log the user into the session (use the extracted auth_session)
@@ -723,17 +871,15 @@ mod tests {
         settings.admin_password_hash = hash_password("password".to_string()).await.unwrap();
 
         // Insert the test user credentials into the DB
-        sqlx::query!(
-            "INSERT OR IGNORE INTO admin_credentials (username, password_hash) VALUES (?, ?)",
-            settings.admin_username,
-            settings.admin_password_hash
-        )
+        sqlx::query("INSERT OR IGNORE INTO users (username, password_hash, created_at, updated_at) VALUES (?, ?, '', '')")
+            .bind(&settings.admin_username)
+            .bind(&settings.admin_password_hash)
         .execute(&pool)
         .await
         .expect("Failed to insert test admin credentials");
 
         // Fetch the ID of the inserted user (or assume 1 if IGNORE worked)
-        // let user_record = sqlx::query!("SELECT id FROM admin_credentials WHERE username = ?", settings.admin_username)
+        // let user_record = sqlx::query!("SELECT id FROM users WHERE username = ?", settings.admin_username)
         //    .fetch_one(&pool).await.expect("Failed to fetch test user ID");
         // let test_user_id = user_record.id;
 
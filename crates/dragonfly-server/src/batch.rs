@@ -0,0 +1,254 @@
+use dragonfly_common::models::Machine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::custom_fields::{self, FieldDefinition};
+use crate::db;
+use crate::event_manager::EventManager;
+
+pub const MAX_OPERATIONS: usize = 500;
+
+/// One change in a batch. Every operation only touches Dragonfly's database, so a batch can be
+/// committed or rolled back as a whole.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    SetHostname { machine_id: Uuid, hostname: String },
+    SetIpAddress { machine_id: Uuid, ip_address: String },
+    /// Custom field values; null clears a field
+    SetFields { machine_id: Uuid, values: HashMap<String, Value> },
+}
+
+impl Operation {
+    pub fn machine_id(&self) -> Uuid {
+        match self {
+            Operation::SetHostname { machine_id, .. }
+            | Operation::SetIpAddress { machine_id, .. }
+            | Operation::SetFields { machine_id, .. } => *machine_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchRequest {
+    /// Apply every operation or none. When false, each operation is applied on its own and the
+    /// rest still run when one fails.
+    #[serde(default = "default_atomic")]
+    pub atomic: bool,
+    pub operations: Vec<Operation>,
+}
+
+fn default_atomic() -> bool {
+    true
+}
+
+/// A validated operation, ready to be written
+#[derive(Debug, Clone)]
+pub enum Write {
+    Hostname(Uuid, String),
+    IpAddress(Uuid, String),
+    Fields(Uuid, Vec<(String, Option<Value>)>),
+}
+
+impl Write {
+    pub fn machine_id(&self) -> Uuid {
+        match self {
+            Write::Hostname(id, _) | Write::IpAddress(id, _) | Write::Fields(id, _) => *id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpStatus {
+    Applied,
+    Failed,
+    /// Valid, but not applied because another operation in the atomic batch failed
+    RolledBack,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OpResult {
+    pub index: usize,
+    pub status: OpStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchReport {
+    pub atomic: bool,
+    /// Whether anything was written
+    pub committed: bool,
+    pub results: Vec<OpResult>,
+}
+
+impl BatchReport {
+    pub fn all_applied(&self) -> bool {
+        self.results.iter().all(|r| r.status == OpStatus::Applied)
+    }
+
+    /// Machines with at least one applied change
+    pub fn changed_machines(&self, operations: &[Operation]) -> BTreeSet<Uuid> {
+        self.results.iter()
+            .filter(|r| r.status == OpStatus::Applied)
+            .map(|r| operations[r.index].machine_id())
+            .collect()
+    }
+}
+
+fn prepare(operation: &Operation, known: &BTreeSet<Uuid>, fields: &[FieldDefinition]) -> Result<Write, String> {
+    if !known.contains(&operation.machine_id()) {
+        return Err(format!("Machine {} not found", operation.machine_id()));
+    }
+    match operation {
+        Operation::SetHostname { machine_id, hostname } => match crate::import::valid_hostname(hostname) {
+            true => Ok(Write::Hostname(*machine_id, hostname.clone())),
+            false => Err(format!("'{}' is not a valid hostname", hostname)),
+        },
+        Operation::SetIpAddress { machine_id, ip_address } => match ip_address.parse::<IpAddr>() {
+            Ok(ip) => Ok(Write::IpAddress(*machine_id, ip.to_string())),
+            Err(_) => Err(format!("'{}' is not a valid IP address", ip_address)),
+        },
+        Operation::SetFields { machine_id, values } => {
+            let values = values.iter().map(|(name, value)| {
+                let field = fields.iter().find(|f| &f.name == name)
+                    .ok_or_else(|| format!("Unknown custom field '{}'", name))?;
+                match value {
+                    Value::Null => Ok((name.clone(), None)),
+                    value => Ok((name.clone(), Some(custom_fields::normalize_value(field, value)?))),
+                }
+            }).collect::<Result<Vec<_>, String>>()?;
+            Ok(Write::Fields(*machine_id, values))
+        },
+    }
+}
+
+/// Refuse IP writes that would leave two machines with the same address once the batch is in.
+/// Placeholders like `0.0.0.0` are shared by many machines, so they never conflict.
+fn check_ip_conflicts(machines: &[Machine], prepared: &mut [Result<Write, String>]) {
+    let mut addresses: HashMap<Uuid, Option<IpAddr>> = machines.iter()
+        .map(|m| (m.id, m.ip_address.parse::<IpAddr>().ok()))
+        .collect();
+    for write in prepared.iter().flatten() {
+        if let Write::IpAddress(id, ip) = write {
+            addresses.insert(*id, ip.parse().ok());
+        }
+    }
+    for result in prepared.iter_mut() {
+        let conflict = match &*result {
+            Ok(Write::IpAddress(id, ip)) => ip.parse::<IpAddr>().ok()
+                .filter(|ip| !ip.is_unspecified())
+                .and_then(|ip| addresses.iter().find(|(other, address)| *other != id && **address == Some(ip)))
+                .map(|(other, _)| format!("IP address {} belongs to machine {}", ip, other)),
+            _ => None,
+        };
+        if let Some(error) = conflict {
+            *result = Err(error);
+        }
+    }
+}
+
+/// Validate every operation, then write them: all in one transaction when the batch is atomic,
+/// otherwise one at a time. Changed machines are refreshed in Tinkerbell and announced.
+pub async fn run(request: &BatchRequest, actor: &str, events: &EventManager) -> anyhow::Result<BatchReport> {
+    let (machines, fields) = tokio::try_join!(db::get_all_machines(), db::get_custom_fields())?;
+    let known: BTreeSet<Uuid> = machines.iter().map(|m| m.id).collect();
+    let mut prepared: Vec<Result<Write, String>> = request.operations.iter().map(|op| prepare(op, &known, &fields)).collect();
+    check_ip_conflicts(&machines, &mut prepared);
+
+    let failed = |index: usize, error: String| OpResult { index, status: OpStatus::Failed, error: Some(error) };
+    let rolled_back = |index: usize| OpResult { index, status: OpStatus::RolledBack, error: None };
+    let applied = |index: usize| OpResult { index, status: OpStatus::Applied, error: None };
+
+    let report = if request.atomic {
+        let writes: Result<Vec<Write>, (usize, String)> = prepared.into_iter().enumerate()
+            .map(|(i, write)| write.map_err(|e| (i, e)))
+            .collect();
+        let outcome = match writes {
+            Ok(writes) => db::apply_batch(&writes).await.map_err(|(i, e)| (i, e.to_string())),
+            Err(invalid) => Err(invalid),
+        };
+        let results = (0..request.operations.len()).map(|i| match &outcome {
+            Ok(()) => applied(i),
+            Err((failed_index, error)) if *failed_index == i => failed(i, error.clone()),
+            Err(_) => rolled_back(i),
+        }).collect();
+        BatchReport { atomic: true, committed: outcome.is_ok(), results }
+    } else {
+        let mut results = Vec::new();
+        for (i, write) in prepared.into_iter().enumerate() {
+            results.push(match write {
+                Ok(write) => match db::apply_batch(std::slice::from_ref(&write)).await {
+                    Ok(()) => applied(i),
+                    Err((_, e)) => failed(i, e.to_string()),
+                },
+                Err(error) => failed(i, error),
+            });
+        }
+        let committed = results.iter().any(|r| r.status == OpStatus::Applied);
+        BatchReport { atomic: false, committed, results }
+    };
+
+    match report.all_applied() {
+        true => info!("{} applied a batch of {} operations", actor, request.operations.len()),
+        false => warn!("Batch of {} operations from {} did not fully apply (committed: {})", request.operations.len(), actor, report.committed),
+    }
+
+    // Tinkerbell's Hardware records carry the hostname and IP, so refresh them once the batch is in
+    for id in report.changed_machines(&request.operations) {
+        if let Ok(Some(machine)) = db::get_machine_by_id(&id).await {
+            if let Err(e) = crate::tinkerbell::register_machine(&machine).await {
+                warn!("Failed to update machine {} in Tinkerbell after batch (continuing anyway): {}", id, e);
+            }
+        }
+        let _ = events.send(format!("machine_updated:{}", id));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepare_validates_operations() {
+        let machine = Uuid::new_v4();
+        let known: BTreeSet<Uuid> = [machine].into_iter().collect();
+        let ops: Vec<Operation> = serde_json::from_str(&format!(r#"[
+            {{"op": "set_hostname", "machine_id": "{0}", "hostname": "web-01"}},
+            {{"op": "set_hostname", "machine_id": "{0}", "hostname": "-bad"}},
+            {{"op": "set_ip_address", "machine_id": "{0}", "ip_address": "10.0.0.300"}},
+            {{"op": "set_fields", "machine_id": "{0}", "values": {{"owner": "storage"}}}},
+            {{"op": "set_hostname", "machine_id": "{1}", "hostname": "web-02"}}
+        ]"#, machine, Uuid::nil())).unwrap();
+        let results: Vec<bool> = ops.iter().map(|op| prepare(op, &known, &[]).is_ok()).collect();
+        assert_eq!(results, vec![true, false, false, false, false]);
+    }
+
+    #[test]
+    fn test_ip_conflicts() {
+        let a = Machine { ip_address: "10.0.0.1".to_string(), ..crate::test_support::machine("aa:bb:cc:dd:ee:01") };
+        let b = Machine { ip_address: "10.0.0.2".to_string(), ..crate::test_support::machine("aa:bb:cc:dd:ee:02") };
+        let c = Machine { ip_address: "10.0.0.3".to_string(), ..crate::test_support::machine("aa:bb:cc:dd:ee:03") };
+        let machines = vec![a.clone(), b.clone(), c.clone()];
+
+        // Swapping two addresses within the batch is fine; taking one another machine ends up with is not
+        let mut prepared = vec![
+            Ok(Write::IpAddress(a.id, "10.0.0.2".to_string())),
+            Ok(Write::IpAddress(b.id, "10.0.0.1".to_string())),
+            Ok(Write::IpAddress(c.id, "10.0.0.2".to_string())),
+            Ok(Write::Hostname(c.id, "web-03".to_string())),
+        ];
+        check_ip_conflicts(&machines, &mut prepared);
+        assert_eq!(prepared.iter().map(Result::is_ok).collect::<Vec<_>>(), vec![false, true, false, true]);
+
+        let mut prepared = vec![Ok(Write::IpAddress(c.id, "10.0.0.1".to_string()))];
+        check_ip_conflicts(&machines, &mut prepared);
+        assert_eq!(prepared[0].as_ref().unwrap_err(), &format!("IP address 10.0.0.1 belongs to machine {}", a.id));
+    }
+}
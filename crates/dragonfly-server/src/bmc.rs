@@ -0,0 +1,416 @@
+//! Power and boot-device control through a machine's BMC.
+//!
+//! When the Tinkerbell stack includes Rufio, each request becomes a Rufio `Job` against a `Machine`
+//! resource that Dragonfly keeps in sync with the machine's BMC credentials, so the BMC is only
+//! ever reached from inside the cluster. Without Rufio the server runs `ipmitool` itself. Either
+//! way the job and how it ended are kept per machine.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use dragonfly_common::models::{BmcCredentials, BmcType, Machine};
+use k8s_openapi::api::core::v1::Secret;
+use kube::api::{Api, ListParams, Patch, PatchParams, PostParams};
+use kube::core::{ApiResource, DynamicObject, ObjectMeta, TypeMeta};
+use kube::Error as KubeError;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::db;
+
+const RUFIO_GROUP: &str = "bmc.tinkerbell.org";
+const RUFIO_VERSION: &str = "v1alpha1";
+/// How long a Rufio job may run before it's recorded as failed
+const JOB_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// When set, Rufio checks the BMC's TLS certificate. Most BMCs ship a self-signed one, so it
+/// doesn't by default.
+pub const VERIFY_TLS_ENV_VAR: &str = "DRAGONFLY_BMC_VERIFY_TLS";
+
+fn verify_tls() -> bool {
+    std::env::var(VERIFY_TLS_ENV_VAR).is_ok_and(|v| v == "true" || v == "1")
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerAction {
+    On,
+    Off,
+    Cycle,
+    Reset,
+    /// ACPI shutdown
+    Soft,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BootDevice {
+    Pxe,
+    Disk,
+    Bios,
+    Cdrom,
+}
+
+/// One step of a BMC job, e.g. `{"boot_device": "pxe"}` then `{"power": "cycle"}`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BmcTask {
+    Power(PowerAction),
+    /// Boot once from the device, in UEFI mode
+    BootDevice(BootDevice),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    Rufio,
+    Ipmitool,
+}
+
+impl Backend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Backend::Rufio => "rufio",
+            Backend::Ipmitool => "ipmitool",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "rufio" => Some(Backend::Rufio),
+            "ipmitool" => Some(Backend::Ipmitool),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Running => "running",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "running" => Some(JobState::Running),
+            "succeeded" => Some(JobState::Succeeded),
+            "failed" => Some(JobState::Failed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BmcJob {
+    pub id: i64,
+    pub machine_id: Uuid,
+    pub tasks: Vec<BmcTask>,
+    pub backend: Backend,
+    /// Name of the Rufio Job resource
+    pub job_name: Option<String>,
+    pub state: JobState,
+    pub message: Option<String>,
+    pub requested_by: String,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl std::fmt::Display for BmcTask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BmcTask::Power(action) => write!(f, "power {}", rufio_power(*action)),
+            BmcTask::BootDevice(device) => write!(f, "boot from {}", boot_device_name(*device)),
+        }
+    }
+}
+
+fn rufio_power(action: PowerAction) -> &'static str {
+    match action {
+        PowerAction::On => "on",
+        PowerAction::Off => "off",
+        PowerAction::Cycle => "cycle",
+        PowerAction::Reset => "reset",
+        PowerAction::Soft => "soft",
+    }
+}
+
+fn boot_device_name(device: BootDevice) -> &'static str {
+    match device {
+        BootDevice::Pxe => "pxe",
+        BootDevice::Disk => "disk",
+        BootDevice::Bios => "bios",
+        BootDevice::Cdrom => "cdrom",
+    }
+}
+
+/// Rufio's `spec.tasks` for a job
+fn rufio_tasks(tasks: &[BmcTask]) -> Value {
+    Value::Array(tasks.iter().map(|task| match task {
+        BmcTask::Power(action) => json!({ "powerAction": rufio_power(*action) }),
+        BmcTask::BootDevice(device) => json!({
+            "oneTimeBootDeviceAction": { "device": [boot_device_name(*device)], "efiBoot": true }
+        }),
+    }).collect())
+}
+
+/// The `ipmitool` arguments for a task
+fn ipmitool_args(task: &BmcTask) -> Vec<String> {
+    match task {
+        BmcTask::Power(action) => vec!["chassis".into(), "power".into(), rufio_power(*action).into()],
+        BmcTask::BootDevice(device) => vec!["chassis".into(), "bootdev".into(), boot_device_name(*device).into(), "options=efiboot".into()],
+    }
+}
+
+/// How a Rufio Job's conditions say it ended, if it has
+fn rufio_job_outcome(job: &Value) -> Option<(JobState, Option<String>)> {
+    let conditions = job.pointer("/status/conditions")?.as_array()?;
+    conditions.iter()
+        .filter(|c| c.get("status").and_then(Value::as_str) == Some("True"))
+        .find_map(|c| {
+            let state = match c.get("type").and_then(Value::as_str)? {
+                "Completed" => JobState::Succeeded,
+                "Failed" => JobState::Failed,
+                _ => return None,
+            };
+            Some((state, c.get("message").and_then(Value::as_str).map(str::to_string)))
+        })
+}
+
+fn rufio_resource(kind: &str, plural: &str) -> ApiResource {
+    ApiResource {
+        group: RUFIO_GROUP.to_string(),
+        version: RUFIO_VERSION.to_string(),
+        kind: kind.to_string(),
+        api_version: format!("{}/{}", RUFIO_GROUP, RUFIO_VERSION),
+        plural: plural.to_string(),
+    }
+}
+
+/// Whether Rufio's CRDs are installed in the cluster
+pub async fn rufio_available() -> bool {
+    let Ok(client) = crate::tinkerbell::get_client().await else {
+        return false;
+    };
+    let jobs: Api<DynamicObject> = Api::namespaced_with(client.clone(), &crate::status::dragonfly_namespace(), &rufio_resource("Job", "jobs"));
+    match jobs.list(&ListParams::default().limit(1)).await {
+        Ok(_) => true,
+        Err(KubeError::Api(ae)) if ae.code == 404 => false,
+        Err(e) => {
+            warn!("Failed to check for Rufio: {}", e);
+            false
+        }
+    }
+}
+
+fn resource_name(machine_id: &Uuid) -> String {
+    format!("dragonfly-{}", machine_id)
+}
+
+/// Create or update the Secret and Rufio Machine describing a machine's BMC
+async fn sync_rufio_machine(machine: &Machine, credentials: &BmcCredentials, host: &str) -> Result<String> {
+    let client = crate::tinkerbell::get_client().await?;
+    let namespace = crate::status::dragonfly_namespace();
+    let name = resource_name(&machine.id);
+
+    let secret = Secret {
+        metadata: ObjectMeta { name: Some(name.clone()), namespace: Some(namespace.clone()), ..Default::default() },
+        string_data: Some(BTreeMap::from([
+            ("username".to_string(), credentials.username.clone()),
+            ("password".to_string(), credentials.password.clone().unwrap_or_default()),
+        ])),
+        ..Default::default()
+    };
+    let secrets: Api<Secret> = Api::namespaced(client.clone(), &namespace);
+    match secrets.get(&name).await {
+        Ok(_) => { secrets.patch(&name, &PatchParams::default(), &Patch::Merge(&secret)).await?; },
+        Err(KubeError::Api(ae)) if ae.code == 404 => { secrets.create(&PostParams::default(), &secret).await?; },
+        Err(e) => return Err(anyhow!(crate::status::describe_kube_error(&e, "read BMC secret"))),
+    }
+
+    let port = match credentials.bmc_type {
+        BmcType::Redfish => 443,
+        _ => 623,
+    };
+    let rufio_machine = DynamicObject {
+        metadata: ObjectMeta { name: Some(name.clone()), namespace: Some(namespace.clone()), ..Default::default() },
+        types: Some(TypeMeta { api_version: format!("{}/{}", RUFIO_GROUP, RUFIO_VERSION), kind: "Machine".to_string() }),
+        data: json!({
+            "spec": {
+                "connection": {
+                    "host": host,
+                    "port": port,
+                    "authSecretRef": { "name": name, "namespace": namespace },
+                    "insecureTLS": !verify_tls(),
+                }
+            }
+        }),
+    };
+    let machines: Api<DynamicObject> = Api::namespaced_with(client.clone(), &namespace, &rufio_resource("Machine", "machines"));
+    match machines.get(&name).await {
+        Ok(_) => { machines.patch(&name, &PatchParams::default(), &Patch::Merge(&rufio_machine)).await?; },
+        Err(KubeError::Api(ae)) if ae.code == 404 => { machines.create(&PostParams::default(), &rufio_machine).await?; },
+        Err(e) => return Err(anyhow!(crate::status::describe_kube_error(&e, "read Rufio Machine"))),
+    }
+    Ok(name)
+}
+
+// Unique even for two jobs on one machine in the same second
+fn rufio_job_name(machine_name: &str) -> String {
+    let mut suffix = [0u8; 4];
+    rand::thread_rng().fill_bytes(&mut suffix);
+    format!("{}-{}-{}", machine_name, Utc::now().timestamp(), hex::encode(suffix))
+}
+
+async fn create_rufio_job(machine_name: &str, job_name: &str, tasks: &[BmcTask]) -> Result<()> {
+    let client = crate::tinkerbell::get_client().await?;
+    let namespace = crate::status::dragonfly_namespace();
+    let job = DynamicObject {
+        metadata: ObjectMeta { name: Some(job_name.to_string()), namespace: Some(namespace.clone()), ..Default::default() },
+        types: Some(TypeMeta { api_version: format!("{}/{}", RUFIO_GROUP, RUFIO_VERSION), kind: "Job".to_string() }),
+        data: json!({
+            "spec": {
+                "machineRef": { "name": machine_name, "namespace": namespace },
+                "tasks": rufio_tasks(tasks),
+            }
+        }),
+    };
+    let jobs: Api<DynamicObject> = Api::namespaced_with(client.clone(), &namespace, &rufio_resource("Job", "jobs"));
+    jobs.create(&PostParams::default(), &job).await
+        .map_err(|e| anyhow!(crate::status::describe_kube_error(&e, "create Rufio Job")))?;
+    Ok(())
+}
+
+/// Follow a Rufio Job until it completes, fails or times out
+async fn watch_rufio_job(job_id: i64, machine_id: Uuid, job_name: String) -> (JobState, Option<String>) {
+    let started = tokio::time::Instant::now();
+    while started.elapsed() < JOB_TIMEOUT {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let Ok(client) = crate::tinkerbell::get_client().await else {
+            continue;
+        };
+        let jobs: Api<DynamicObject> = Api::namespaced_with(client.clone(), &crate::status::dragonfly_namespace(), &rufio_resource("Job", "jobs"));
+        match jobs.get(&job_name).await {
+            Ok(job) => {
+                if let Some(outcome) = rufio_job_outcome(&job.data) {
+                    return outcome;
+                }
+            },
+            Err(KubeError::Api(ae)) if ae.code == 404 => return (JobState::Failed, Some("Rufio Job was deleted".to_string())),
+            Err(e) => warn!("Failed to read Rufio Job {} (BMC job {} of machine {}): {}", job_name, job_id, machine_id, e),
+        }
+    }
+    (JobState::Failed, Some(format!("Timed out after {} minutes waiting for Rufio", JOB_TIMEOUT.as_secs() / 60)))
+}
+
+async fn run_ipmitool(credentials: &BmcCredentials, host: &str, tasks: &[BmcTask]) -> (JobState, Option<String>) {
+    for task in tasks {
+        let args = ipmitool_args(task);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        match crate::console::ipmitool(credentials, host, &args).output().await {
+            Ok(output) if output.status.success() => {},
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+                return (JobState::Failed, Some(format!("{} failed: {}", task, stderr)));
+            },
+            Err(e) => return (JobState::Failed, Some(format!("Failed to run ipmitool: {}", e))),
+        }
+    }
+    (JobState::Succeeded, None)
+}
+
+fn publish(machine_id: &Uuid) {
+    if let Ok(event_manager) = crate::EVENT_MANAGER_REF.read() {
+        if let Some(event_manager) = event_manager.as_ref() {
+            event_manager.send(format!("bmc_job_updated:{}", machine_id));
+        }
+    }
+}
+
+/// Start a BMC job for a machine. It runs in the background; the returned job is still running,
+/// and its row is updated when it finishes.
+pub async fn start_job(machine: &Machine, tasks: Vec<BmcTask>, requested_by: &str) -> Result<BmcJob, String> {
+    if tasks.is_empty() {
+        return Err("Give at least one task".to_string());
+    }
+    let credentials = machine.bmc_credentials.clone()
+        .ok_or_else(|| "This machine has no BMC credentials".to_string())?;
+    if let BmcType::Other(name) = &credentials.bmc_type {
+        return Err(format!("{} BMCs can't be controlled by Dragonfly", name));
+    }
+    let host = crate::console::bmc_host(&credentials.address)
+        .ok_or_else(|| format!("Invalid BMC address '{}'", credentials.address))?;
+
+    let (backend, job_name) = if rufio_available().await {
+        let machine_name = sync_rufio_machine(machine, &credentials, &host).await.map_err(|e| e.to_string())?;
+        let job_name = rufio_job_name(&machine_name);
+        create_rufio_job(&machine_name, &job_name, &tasks).await.map_err(|e| e.to_string())?;
+        (Backend::Rufio, Some(job_name))
+    } else {
+        (Backend::Ipmitool, None)
+    };
+
+    let job = db::create_bmc_job(&machine.id, &tasks, backend, job_name.as_deref(), requested_by).await
+        .map_err(|e| e.to_string())?;
+    info!("BMC job {} for machine {} started through {} by {}", job.id, machine.id, backend.as_str(), requested_by);
+    publish(&machine.id);
+
+    let job_id = job.id;
+    let machine_id = machine.id;
+    tokio::spawn(async move {
+        let (state, message) = match job_name {
+            Some(job_name) => watch_rufio_job(job_id, machine_id, job_name).await,
+            None => run_ipmitool(&credentials, &host, &tasks).await,
+        };
+        if state == JobState::Failed {
+            warn!("BMC job {} for machine {} failed: {}", job_id, machine_id, message.as_deref().unwrap_or("no details"));
+        }
+        if let Err(e) = db::finish_bmc_job(job_id, state, message.as_deref()).await {
+            warn!("Failed to record the outcome of BMC job {}: {}", job_id, e);
+        }
+        publish(&machine_id);
+    });
+
+    Ok(job)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rufio_mapping() {
+        let tasks: Vec<BmcTask> = serde_json::from_value(json!([{ "boot_device": "pxe" }, { "power": "cycle" }])).unwrap();
+        assert_eq!(rufio_tasks(&tasks), json!([
+            { "oneTimeBootDeviceAction": { "device": ["pxe"], "efiBoot": true } },
+            { "powerAction": "cycle" },
+        ]));
+        assert_eq!(ipmitool_args(&tasks[0]), vec!["chassis", "bootdev", "pxe", "options=efiboot"]);
+
+        let job = json!({ "status": { "conditions": [
+            { "type": "Running", "status": "True" },
+            { "type": "Failed", "status": "True", "message": "BMC unreachable" },
+        ] } });
+        assert_eq!(rufio_job_outcome(&job), Some((JobState::Failed, Some("BMC unreachable".to_string()))));
+        assert_eq!(rufio_job_outcome(&json!({ "status": {} })), None);
+
+        let name = rufio_job_name("dragonfly-machine");
+        assert!(name.starts_with("dragonfly-machine-"));
+        assert_ne!(name, rufio_job_name("dragonfly-machine"));
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// CPU architecture a machine boots as
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BootArch {
+    #[default]
+    X86_64,
+    Aarch64,
+}
+
+impl BootArch {
+    /// Parse the names used by iPXE (`${buildarch}`), Rust (`std::env::consts::ARCH`) and distros.
+    /// 32-bit names map to their 64-bit counterparts: iPXE's build architecture doesn't
+    /// necessarily match the machine's, and we only provision 64-bit systems.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "x86_64" | "amd64" | "x64" | "i386" | "i686" | "x86" => Some(BootArch::X86_64),
+            "aarch64" | "arm64" | "arm32" | "arm" => Some(BootArch::Aarch64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BootArch::X86_64 => "x86_64",
+            BootArch::Aarch64 => "aarch64",
+        }
+    }
+
+    /// The name Debian and Ubuntu use, as in their cloud image and package names
+    pub fn debian_name(&self) -> &'static str {
+        match self {
+            BootArch::X86_64 => "amd64",
+            BootArch::Aarch64 => "arm64",
+        }
+    }
+}
+
+/// The `arch` workflow variable, so templates can pick images like `noble-server-cloudimg-{{.arch}}.img`.
+/// Machines whose architecture hasn't been detected are taken to be amd64.
+pub async fn workflow_vars(machine_id: &Uuid) -> Result<Vec<(String, String)>> {
+    let arch = crate::db::get_boot_target(machine_id).await?.map(|target| target.arch).unwrap_or_default();
+    Ok(vec![("arch".to_string(), arch.debian_name().to_string())])
+}
+
+/// Firmware interface a machine boots through
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Firmware {
+    Bios,
+    #[default]
+    Uefi,
+}
+
+/// Architecture and firmware of a machine, used to pick boot artifacts for it
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct BootTarget {
+    pub arch: BootArch,
+    pub firmware: Firmware,
+    /// The firmware fetched its bootloader over UEFI HTTP boot rather than TFTP
+    #[serde(default)]
+    pub http_boot: bool,
+    /// Secure Boot is enforced, so only signed bootloaders and kernels will run
+    #[serde(default)]
+    pub secure_boot: bool,
+}
+
+impl BootTarget {
+    /// From the DHCP client system architecture type (option 93, RFC 4578 and the IANA registry)
+    pub fn from_client_arch(code: u16) -> Option<Self> {
+        let (arch, firmware, http_boot) = match code {
+            0 => (BootArch::X86_64, Firmware::Bios, false),
+            6 | 7 | 9 => (BootArch::X86_64, Firmware::Uefi, false),
+            11 => (BootArch::Aarch64, Firmware::Uefi, false),
+            15 | 16 => (BootArch::X86_64, Firmware::Uefi, true),
+            19 => (BootArch::Aarch64, Firmware::Uefi, true),
+            _ => return None,
+        };
+        Some(Self { arch, firmware, http_boot, secure_boot: false })
+    }
+
+    /// From iPXE's `${buildarch}` and `${platform}` variables
+    pub fn from_ipxe(buildarch: &str, platform: Option<&str>) -> Option<Self> {
+        let arch = BootArch::parse(buildarch)?;
+        // ${platform} is "pcbios" or "efi"; without it assume UEFI, as all ARM and most current x86 hardware is
+        let firmware = if platform == Some("pcbios") { Firmware::Bios } else { Firmware::Uefi };
+        // Stock iPXE is unsigned, so if it is running Secure Boot is off
+        Some(Self { arch, firmware, http_boot: false, secure_boot: false })
+    }
+
+    /// From what the Dragonfly agent reports about the machine it runs on
+    pub fn from_agent(cpu_arch: Option<&str>, uefi: Option<bool>, secure_boot: Option<bool>) -> Option<Self> {
+        let arch = BootArch::parse(cpu_arch?)?;
+        let firmware = match uefi {
+            Some(false) => Firmware::Bios,
+            _ => Firmware::Uefi,
+        };
+        let secure_boot = firmware == Firmware::Uefi && secure_boot.unwrap_or(false);
+        Some(Self { arch, firmware, http_boot: false, secure_boot })
+    }
+
+    /// Path of the iPXE bootloader for this target, relative to `/ipxe/`
+    pub fn bootloader(&self) -> &'static str {
+        match (self.arch, self.firmware) {
+            (BootArch::X86_64, Firmware::Bios) => "bootloader/undionly.kpxe",
+            (BootArch::X86_64, Firmware::Uefi) => "bootloader/ipxe.efi",
+            (BootArch::Aarch64, _) => "bootloader/arm64/ipxe.efi",
+        }
+    }
+}
+
+/// Detection hints a client can pass when fetching its iPXE script,
+/// e.g. `/{mac}?arch=${buildarch}&platform=${platform}` or `?client_arch=11` from the DHCP server
+#[derive(Debug, Deserialize, Default)]
+pub struct BootHints {
+    pub arch: Option<String>,
+    pub platform: Option<String>,
+    pub client_arch: Option<String>,
+}
+
+impl BootHints {
+    pub fn detect(&self) -> Option<BootTarget> {
+        self.client_arch.as_deref()
+            .and_then(|code| code.trim().parse().ok())
+            .and_then(BootTarget::from_client_arch)
+            .or_else(|| BootTarget::from_ipxe(self.arch.as_deref()?, self.platform.as_deref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_arch_codes() {
+        let arm = BootTarget::from_client_arch(11).unwrap();
+        assert_eq!((arm.arch, arm.firmware), (BootArch::Aarch64, Firmware::Uefi));
+        assert_eq!(arm.bootloader(), "bootloader/arm64/ipxe.efi");
+        assert_eq!(arm.arch.debian_name(), "arm64");
+
+        assert_eq!(BootTarget::from_client_arch(0).unwrap().bootloader(), "bootloader/undionly.kpxe");
+        assert_eq!(BootTarget::from_client_arch(7).unwrap().bootloader(), "bootloader/ipxe.efi");
+        assert!(BootTarget::from_client_arch(16).unwrap().http_boot);
+        assert!(BootTarget::from_client_arch(10).is_none());
+    }
+
+    #[test]
+    fn test_hints_prefer_dhcp_over_ipxe() {
+        let hints = BootHints {
+            arch: Some("i386".to_string()),
+            platform: Some("pcbios".to_string()),
+            client_arch: Some("11".to_string()),
+        };
+        assert_eq!(hints.detect().unwrap().arch, BootArch::Aarch64);
+
+        let hints = BootHints { arch: Some("i386".to_string()), platform: Some("pcbios".to_string()), client_arch: None };
+        assert_eq!(hints.detect().unwrap(), BootTarget { arch: BootArch::X86_64, firmware: Firmware::Bios, http_boot: false, secure_boot: false });
+        assert!(BootHints::default().detect().is_none());
+    }
+
+    #[test]
+    fn test_agent_report() {
+        let target = BootTarget::from_agent(Some("aarch64"), Some(true), Some(true)).unwrap();
+        assert_eq!(target.arch, BootArch::Aarch64);
+        assert!(target.secure_boot);
+        let bios = BootTarget::from_agent(Some("x86_64"), Some(false), Some(true)).unwrap();
+        assert_eq!(bios.firmware, Firmware::Bios);
+        assert!(!bios.secure_boot);
+        assert!(BootTarget::from_agent(None, Some(true), None).is_none());
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// Allow/deny rules consulted before Dragonfly answers an iPXE request.
+/// Each rule is an exact MAC (`bc:24:11:b9:54:89`), an OUI prefix (`bc:24:11`)
+/// or a subnet in CIDR notation (`10.0.5.0/24`).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct BootFilter {
+    /// When non-empty, only matching machines are served
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Matching machines are never served, even if they are also allowed
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum BootRule {
+    Mac(String),
+    Oui(String),
+    Subnet(IpAddr, u8),
+}
+
+impl BootRule {
+    fn parse(rule: &str) -> Result<Self> {
+        let rule = rule.trim().to_lowercase().replace('-', ":");
+        if rule.contains('/') {
+            let (addr, prefix) = parse_subnet(&rule)?;
+            return Ok(BootRule::Subnet(addr, prefix));
+        }
+
+        let octets: Vec<&str> = rule.split(':').collect();
+        if !octets.iter().all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit())) {
+            return Err(anyhow!("Invalid MAC rule '{}'", rule));
+        }
+        match octets.len() {
+            6 => Ok(BootRule::Mac(rule)),
+            3 => Ok(BootRule::Oui(rule)),
+            _ => Err(anyhow!("MAC rule '{}' must be a full MAC address or a 3-octet OUI", rule)),
+        }
+    }
+
+    fn matches(&self, mac: &str, ip: Option<IpAddr>) -> bool {
+        match self {
+            BootRule::Mac(rule) => mac == rule,
+            BootRule::Oui(rule) => mac.starts_with(rule.as_str()),
+            BootRule::Subnet(net, prefix) => ip.is_some_and(|ip| in_subnet(ip, *net, *prefix)),
+        }
+    }
+}
+
+/// Parse a subnet in CIDR notation, e.g. `10.0.5.0/24`
+pub(crate) fn parse_subnet(cidr: &str) -> Result<(IpAddr, u8)> {
+    let (addr, prefix) = cidr.split_once('/').ok_or_else(|| anyhow!("'{}' is not in CIDR notation", cidr))?;
+    let addr: IpAddr = addr.parse().map_err(|_| anyhow!("Invalid subnet address in rule '{}'", cidr))?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix: u8 = prefix.parse().ok().filter(|p| *p <= max)
+        .ok_or_else(|| anyhow!("Invalid subnet prefix length in rule '{}'", cidr))?;
+    Ok((addr, prefix))
+}
+
+pub(crate) fn in_subnet(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+impl BootFilter {
+    /// Build a filter from newline/comma separated allow and deny lists, rejecting invalid rules
+    pub fn from_lists(allow: &str, deny: &str) -> Result<Self> {
+        let split = |list: &str| -> Result<Vec<String>> {
+            list.split(['\n', ','])
+                .map(str::trim)
+                .filter(|r| !r.is_empty())
+                .map(|r| BootRule::parse(r).map(|_| r.to_lowercase()))
+                .collect()
+        };
+        Ok(Self { allow: split(allow)?, deny: split(deny)? })
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.allow.is_empty() || !self.deny.is_empty()
+    }
+
+    /// Check whether a machine may be served an iPXE script.
+    /// Returns the reason when it must be refused.
+    pub fn check(&self, mac_address: &str, ip: Option<IpAddr>) -> Option<String> {
+        let mac = mac_address.to_lowercase().replace('-', ":");
+        let matching = |rules: &[String]| {
+            rules.iter()
+                .find(|r| BootRule::parse(r).is_ok_and(|rule| rule.matches(&mac, ip)))
+                .cloned()
+        };
+
+        if let Some(rule) = matching(&self.deny) {
+            return Some(format!("matched deny rule {}", rule));
+        }
+        if !self.allow.is_empty() && matching(&self.allow).is_none() {
+            return Some("not on the allow list".to_string());
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_parsing() {
+        assert_eq!(BootRule::parse("BC-24-11-B9-54-89").unwrap(), BootRule::Mac("bc:24:11:b9:54:89".to_string()));
+        assert_eq!(BootRule::parse("bc:24:11").unwrap(), BootRule::Oui("bc:24:11".to_string()));
+        assert!(matches!(BootRule::parse("10.0.5.0/24").unwrap(), BootRule::Subnet(_, 24)));
+        assert!(BootRule::parse("bc:24").is_err());
+        assert!(BootRule::parse("10.0.5.0/33").is_err());
+        assert!(BootRule::parse("not-a-mac").is_err());
+    }
+
+    #[test]
+    fn test_deny_wins_over_allow() {
+        let filter = BootFilter::from_lists("bc:24:11", "bc:24:11:b9:54:89").unwrap();
+        assert!(filter.check("BC:24:11:B9:54:89", None).is_some());
+        assert!(filter.check("bc:24:11:00:00:01", None).is_none());
+        assert!(filter.check("aa:bb:cc:dd:ee:ff", None).is_some());
+    }
+
+    #[test]
+    fn test_subnet_rules() {
+        let filter = BootFilter::from_lists("", "10.0.5.0/24").unwrap();
+        assert!(filter.check("aa:bb:cc:dd:ee:ff", "10.0.5.17".parse().ok()).is_some());
+        assert!(filter.check("aa:bb:cc:dd:ee:ff", "10.0.6.17".parse().ok()).is_none());
+        assert!(filter.check("aa:bb:cc:dd:ee:ff", None).is_none());
+        assert!(BootFilter::default().check("aa:bb:cc:dd:ee:ff", None).is_none());
+    }
+}
@@ -0,0 +1,164 @@
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::Request,
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::auth::{AdminUser, AuthSession, Credentials};
+use crate::db;
+use crate::ui::{AddAlert, AlertMessage};
+
+pub const DEFAULT_TTL_MINUTES: i64 = 15;
+pub const MAX_TTL_MINUTES: i64 = 60;
+
+/// Paths a break-glass session may use before the admin password has been rotated
+const ROTATION_PATHS: &[&str] = &["/settings", "/logout", "/login", "/static/", "/favicon.ico"];
+
+/// A break-glass credential as recorded for auditing. The password hash never leaves the database.
+#[derive(Debug, Clone, Serialize)]
+pub struct BreakGlassRecord {
+    pub id: i64,
+    pub username: String,
+    pub issued_by: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub rotated_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// A freshly issued credential, shown once on the console
+pub struct IssuedCredential {
+    pub username: String,
+    pub password: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Break-glass sessions use negative user IDs so they can't collide with user accounts
+pub fn is_break_glass(user: &AdminUser) -> bool {
+    user.id < 0
+}
+
+/// Issue a one-time local admin credential, revoking any earlier one that was never used
+pub async fn issue(ttl_minutes: i64, issued_by: &str) -> Result<IssuedCredential> {
+    if !(1..=MAX_TTL_MINUTES).contains(&ttl_minutes) {
+        return Err(anyhow!("Break-glass credentials must expire within 1 to {} minutes", MAX_TTL_MINUTES));
+    }
+
+    let mut rng = rand::thread_rng();
+    let suffix: String = (&mut rng).sample_iter(&Alphanumeric).take(6).map(char::from).collect();
+    let username = format!("breakglass-{}", suffix.to_lowercase());
+    let password: String = rng.sample_iter(&Alphanumeric).take(24).map(char::from).collect();
+    let credentials = Credentials::create(username.clone(), password.clone())?;
+    let expires_at = Utc::now() + Duration::minutes(ttl_minutes);
+
+    let revoked = db::revoke_unused_break_glass_credentials().await?;
+    if revoked > 0 {
+        warn!("Revoked {} unused break-glass credential(s) before issuing a new one", revoked);
+    }
+    db::add_break_glass_credential(&username, &credentials.password_hash, issued_by, expires_at).await?;
+    warn!("Break-glass credential '{}' issued by {} (expires {})", username, issued_by, expires_at.to_rfc3339());
+
+    Ok(IssuedCredential { username, password, expires_at })
+}
+
+/// Entry point for `dragonfly break-glass`, which runs outside the server process
+pub async fn issue_from_cli(ttl_minutes: i64, issued_by: &str) -> Result<IssuedCredential> {
+    db::init_db().await?;
+    issue(ttl_minutes, issued_by).await
+}
+
+/// Log in with a break-glass credential. Each credential works once, and only before it expires.
+pub async fn authenticate(username: &str, password: String) -> Result<Option<AdminUser>> {
+    let Some((id, password_hash)) = db::get_usable_break_glass_credential(username, Utc::now()).await? else {
+        return Ok(None);
+    };
+
+    if !crate::users::verify_password(password_hash, password).await {
+        warn!("Failed break-glass login attempt for '{}'", username);
+        return Ok(None);
+    }
+
+    // Claiming the credential is atomic, so two logins racing with the same password can't both win
+    if !db::mark_break_glass_credential_used(id).await? {
+        return Ok(None);
+    }
+    warn!("Break-glass credential '{}' used to log in; the admin password must now be rotated", username);
+    Ok(Some(AdminUser { id: -id, username: username.to_string(), is_admin: true }))
+}
+
+/// Session lookup for a break-glass user; the session ends at expiry or once the password is rotated
+pub async fn get_user(user_id: i64) -> Result<Option<AdminUser>> {
+    Ok(db::get_active_break_glass_session(-user_id, Utc::now()).await?
+        .map(|username| AdminUser { id: user_id, username, is_admin: true }))
+}
+
+/// Record that a break-glass session has set a new admin password, which ends the session
+pub async fn complete_rotation(user: &AdminUser) -> Result<()> {
+    db::mark_break_glass_credential_rotated(-user.id).await?;
+    info!("Admin password rotated by break-glass user '{}'; the break-glass session is closed", user.username);
+    Ok(())
+}
+
+/// Keep a break-glass session on the settings page until it has rotated the admin password
+pub async fn require_rotation(auth_session: AuthSession, request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    let must_rotate = auth_session.user.as_ref().is_some_and(is_break_glass)
+        && !ROTATION_PATHS.iter().any(|allowed| path.starts_with(allowed));
+    if !must_rotate {
+        return next.run(request).await;
+    }
+
+    if path.starts_with("/api/") {
+        (StatusCode::FORBIDDEN, Json(json!({
+            "error": "Password Rotation Required",
+            "message": "Break-glass sessions must set a new admin password at /settings before doing anything else"
+        }))).into_response()
+    } else {
+        Redirect::to("/settings")
+            .into_response()
+            .add_alert(AlertMessage::error("You signed in with a break-glass credential. Set a new admin password to continue."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_credential_works_once_until_rotation() {
+        let pool = crate::test_support::database().await;
+        let now = Utc::now();
+        for (username, expires_at) in [("breakglass-live", now + Duration::minutes(15)), ("breakglass-stale", now - Duration::minutes(1))] {
+            sqlx::query("INSERT INTO break_glass_credentials (username, password_hash, issued_by, created_at, expires_at) VALUES (?, 'hash', 'cli', ?, ?)")
+                .bind(username)
+                .bind(now.to_rfc3339())
+                .bind(expires_at.to_rfc3339())
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        assert_eq!(db::usable_break_glass_credential(&pool, "breakglass-stale", now).await.unwrap(), None);
+
+        let (id, _) = db::usable_break_glass_credential(&pool, "breakglass-live", now).await.unwrap().unwrap();
+        assert_eq!(db::active_break_glass_session(&pool, id, now).await.unwrap(), None);
+        assert!(db::claim_break_glass_credential(&pool, id).await.unwrap());
+        assert!(!db::claim_break_glass_credential(&pool, id).await.unwrap());
+        assert_eq!(db::usable_break_glass_credential(&pool, "breakglass-live", now).await.unwrap(), None);
+
+        assert_eq!(db::active_break_glass_session(&pool, id, now).await.unwrap().as_deref(), Some("breakglass-live"));
+        assert_eq!(db::active_break_glass_session(&pool, id, now + Duration::minutes(20)).await.unwrap(), None);
+        db::close_break_glass_session(&pool, id).await.unwrap();
+        assert_eq!(db::active_break_glass_session(&pool, id, now).await.unwrap(), None);
+
+        assert!(is_break_glass(&AdminUser { id: -id, username: "breakglass-live".to_string(), is_admin: true }));
+    }
+}
@@ -0,0 +1,140 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use dragonfly_common::models::Machine;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::db::ArchivedMachine;
+
+pub const DEFAULT_LIMIT: usize = 1000;
+pub const MAX_LIMIT: usize = 5000;
+
+/// Changes newer than this are held back for the next sync. Timestamps are stored to the second
+/// and written just before their transaction commits, so the newest second can still be filling in.
+pub const SETTLE_SECONDS: i64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MachineChange {
+    pub kind: ChangeKind,
+    pub machine_id: Uuid,
+    pub changed_at: DateTime<Utc>,
+    /// The machine as it is now; absent for deletions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub machine: Option<Machine>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeSet {
+    pub changes: Vec<MachineChange>,
+    /// Pass back as `since` to get the changes after these
+    pub cursor: String,
+    /// More changes are ready now; fetch again straight away instead of waiting for the next run
+    pub has_more: bool,
+}
+
+/// A position in the change feed: the time of the last change seen, and its machine to break ties
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor {
+    at: DateTime<Utc>,
+    machine_id: Uuid,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        format!("{}_{}", self.at.timestamp_nanos_opt().unwrap_or_default(), self.machine_id.simple())
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || "Invalid sync cursor; start again without 'since' to resync".to_string();
+        let (nanos, machine_id) = value.split_once('_').ok_or_else(invalid)?;
+        Ok(Cursor {
+            at: Utc.timestamp_nanos(nanos.parse().map_err(|_| invalid())?),
+            machine_id: Uuid::parse_str(machine_id).map_err(|_| invalid())?,
+        })
+    }
+
+    /// Where the database scan starts. Timestamps are stored as text, some as
+    /// `2026-10-16T12:00:00+00:00` and some as `2026-10-16 12:00:00+00:00`. The space sorts first,
+    /// so no change is left out; the few extra rows are dropped by `changes_since`.
+    pub fn lower_bound(&self) -> String {
+        self.at.format("%Y-%m-%d %H:%M:%S").to_string()
+    }
+}
+
+/// The changes after `since`, oldest first. Without a cursor every current machine is returned as
+/// created, which is how a sync job takes its first snapshot.
+pub fn changes_since(machines: Vec<Machine>, deleted: Vec<ArchivedMachine>, since: Option<Cursor>, now: DateTime<Utc>, limit: usize) -> ChangeSet {
+    let until = now - Duration::seconds(SETTLE_SECONDS);
+    let after = |cursor: Cursor| since.map_or(true, |since| cursor > since) && cursor.at <= until;
+
+    let mut changes: Vec<(Cursor, MachineChange)> = machines.into_iter()
+        .map(|machine| {
+            let kind = match since {
+                Some(since) if machine.created_at <= since.at => ChangeKind::Updated,
+                _ => ChangeKind::Created,
+            };
+            let cursor = Cursor { at: machine.updated_at, machine_id: machine.id };
+            (cursor, MachineChange { kind, machine_id: machine.id, changed_at: machine.updated_at, machine: Some(machine) })
+        })
+        .chain(deleted.into_iter().filter(|_| since.is_some()).map(|archived| {
+            let cursor = Cursor { at: archived.deleted_at, machine_id: archived.machine.id };
+            (cursor, MachineChange { kind: ChangeKind::Deleted, machine_id: archived.machine.id, changed_at: archived.deleted_at, machine: None })
+        }))
+        .filter(|(cursor, _)| after(*cursor))
+        .collect();
+    changes.sort_by_key(|(cursor, _)| *cursor);
+
+    let has_more = changes.len() > limit;
+    changes.truncate(limit);
+    // A finished feed moves the cursor up to the settle point, so quiet periods aren't rescanned
+    let settled = Cursor { at: until, machine_id: Uuid::max() };
+    let cursor = match (has_more, changes.last()) {
+        (true, Some((last, _))) => *last,
+        _ => since.map_or(settled, |since| since.max(settled)),
+    };
+
+    ChangeSet {
+        changes: changes.into_iter().map(|(_, change)| change).collect(),
+        cursor: cursor.encode(),
+        has_more,
+    }
+}
+
+/// Load the changes after `since` from the database, reading only rows from the cursor's second on
+pub async fn load(since: Option<Cursor>, limit: usize) -> anyhow::Result<ChangeSet> {
+    let bound = since.map(|since| since.lower_bound());
+    // A first sync has no deletions to report
+    let deleted = async {
+        match &bound {
+            Some(bound) => crate::db::get_archived_machines_since(bound).await,
+            None => Ok(Vec::new()),
+        }
+    };
+    let (machines, deleted) = tokio::try_join!(crate::db::get_machines_updated_since(bound.as_deref()), deleted)?;
+    Ok(changes_since(machines, deleted, since, Utc::now(), limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let cursor = Cursor { at: Utc.with_ymd_and_hms(2026, 10, 16, 12, 0, 0).unwrap(), machine_id: Uuid::new_v4() };
+        assert_eq!(Cursor::parse(&cursor.encode()).unwrap(), cursor);
+        assert!(Cursor::parse("yesterday").is_err());
+
+        let bound = cursor.lower_bound();
+        for stored in ["2026-10-16T12:00:00+00:00", "2026-10-16 12:00:00.250+00:00", "2026-10-16T12:00:01Z", "2026-10-17 00:00:00+00:00"] {
+            assert!(bound.as_str() <= stored, "{} is skipped", stored);
+        }
+        assert!(bound.as_str() > "2026-10-16 11:59:59+00:00");
+    }
+}
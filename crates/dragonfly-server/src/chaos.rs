@@ -0,0 +1,98 @@
+//! Fault injection for integration tests.
+//!
+//! Only built with the `chaos` feature. Tests set faults through `/api/admin/chaos` or [`set`]
+//! and check that the server recovers. Every fault is counted rather than random: the next N
+//! Tinkerbell calls fail and the next N events are dropped, so a test sees the same thing each run.
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::warn;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Faults {
+    /// Delay added to every database query
+    pub db_delay_ms: u64,
+    /// The next this many Tinkerbell (Kubernetes API) calls fail
+    pub tinkerbell_failures: u32,
+    /// The next this many events never reach subscribers
+    pub dropped_events: u32,
+    /// Only drop events starting with this, e.g. `machine_updated`
+    pub drop_event_prefix: Option<String>,
+}
+
+/// Faults injected since they were last set
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Injected {
+    pub db_delays: u64,
+    pub tinkerbell_failures: u64,
+    pub dropped_events: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChaosStatus {
+    /// What is still to be injected
+    pub faults: Faults,
+    pub injected: Injected,
+}
+
+static STATE: Lazy<Mutex<ChaosStatus>> = Lazy::new(|| Mutex::new(ChaosStatus::default()));
+
+pub fn status() -> ChaosStatus {
+    STATE.lock().unwrap().clone()
+}
+
+/// Replace the faults and reset the counts
+pub fn set(faults: Faults) -> ChaosStatus {
+    warn!("Injecting faults: {:?}", faults);
+    let mut state = STATE.lock().unwrap();
+    *state = ChaosStatus { faults, injected: Injected::default() };
+    state.clone()
+}
+
+pub fn clear() {
+    *STATE.lock().unwrap() = ChaosStatus::default();
+}
+
+/// Called before each database query
+pub async fn db_delay() {
+    let delay_ms = {
+        let mut state = STATE.lock().unwrap();
+        if state.faults.db_delay_ms > 0 {
+            state.injected.db_delays += 1;
+        }
+        state.faults.db_delay_ms
+    };
+    if delay_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+    }
+}
+
+/// Called before each Tinkerbell call; fails while failures are still owed
+pub fn tinkerbell_fault() -> Result<(), String> {
+    let mut state = STATE.lock().unwrap();
+    if state.faults.tinkerbell_failures == 0 {
+        return Ok(());
+    }
+    state.faults.tinkerbell_failures -= 1;
+    state.injected.tinkerbell_failures += 1;
+    Err("Tinkerbell API unavailable (injected fault)".to_string())
+}
+
+/// Whether to drop an event instead of publishing it
+pub fn drop_event(message: &str) -> bool {
+    let mut state = STATE.lock().unwrap();
+    if state.faults.dropped_events == 0 {
+        return false;
+    }
+    if let Some(prefix) = &state.faults.drop_event_prefix {
+        if !message.starts_with(prefix.as_str()) {
+            return false;
+        }
+    }
+    state.faults.dropped_events -= 1;
+    state.injected.dropped_events += 1;
+    true
+}
@@ -0,0 +1,231 @@
+use anyhow::Result;
+use axum::http::HeaderMap;
+use chrono::{DateTime, Duration, Utc};
+use dragonfly_common::models::Machine;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::db;
+use crate::observability::{self, FleetStats};
+
+pub const SLACK_SECRET_ENV_VAR: &str = "DRAGONFLY_SLACK_SIGNING_SECRET";
+pub const MATTERMOST_TOKEN_ENV_VAR: &str = "DRAGONFLY_MATTERMOST_TOKEN";
+
+/// Slack recommends refusing requests older than this, so captured ones can't be replayed
+const MAX_REQUEST_AGE_SECS: i64 = 300;
+const CONFIRM_SECS: i64 = 300;
+
+pub const HELP: &str = "Usage:\n\
+    `/dragonfly status` fleet summary\n\
+    `/dragonfly machine <name>` one machine's status\n\
+    `/dragonfly reimage <name> [os]` reinstall a machine, after confirmation\n\
+    `/dragonfly confirm <code>` confirm a reimage";
+
+lazy_static::lazy_static! {
+    static ref PENDING: Mutex<HashMap<String, PendingReimage>> = Mutex::new(HashMap::new());
+}
+
+/// The fields Slack and Mattermost both send with a slash command
+#[derive(Debug, Clone)]
+pub struct SlashCommand {
+    pub text: String,
+    pub channel_id: String,
+    pub channel_name: String,
+    pub user_name: String,
+    pub token: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help,
+    Status,
+    Machine(String),
+    Reimage { target: String, os: Option<String> },
+    Confirm(String),
+}
+
+/// A reimage waiting for the same user in the same channel to confirm it
+#[derive(Debug, Clone)]
+pub struct PendingReimage {
+    pub machine_id: Uuid,
+    pub machine_name: String,
+    pub os_choice: String,
+    channel_id: String,
+    user_name: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl SlashCommand {
+    pub fn parse(body: &[u8]) -> Option<Self> {
+        let fields: HashMap<String, String> = url::form_urlencoded::parse(body).into_owned().collect();
+        Some(Self {
+            text: fields.get("text").cloned().unwrap_or_default(),
+            channel_id: fields.get("channel_id")?.clone(),
+            channel_name: fields.get("channel_name").cloned().unwrap_or_default(),
+            user_name: fields.get("user_name")?.clone(),
+            token: fields.get("token").cloned(),
+        })
+    }
+
+    /// ACL principal for the channel; grant it access like any user to authorize commands there
+    pub fn principal(&self) -> String {
+        format!("chat:{}", self.channel_id)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn verify_slack(secret: &str, headers: &HeaderMap, body: &[u8], now: DateTime<Utc>) -> Result<(), &'static str> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let timestamp = header("x-slack-request-timestamp").ok_or("Missing request timestamp")?;
+    let signature = header("x-slack-signature").ok_or("Missing request signature")?;
+    let sent_at: i64 = timestamp.parse().map_err(|_| "Invalid request timestamp")?;
+    if (now.timestamp() - sent_at).abs() > MAX_REQUEST_AGE_SECS {
+        return Err("Request timestamp is too old");
+    }
+    let signature = signature.strip_prefix("v0=").and_then(|s| hex::decode(s).ok()).ok_or("Invalid request signature")?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("v0:{}:", timestamp).as_bytes());
+    mac.update(body);
+    mac.verify_slice(&signature).map_err(|_| "Request signature does not match")
+}
+
+/// Check a slash command came from the configured workspace: Slack signs the request,
+/// Mattermost sends the command's token in the body.
+pub fn verify(headers: &HeaderMap, body: &[u8], command: &SlashCommand, now: DateTime<Utc>) -> Result<(), &'static str> {
+    if headers.contains_key("x-slack-signature") {
+        let secret = env::var(SLACK_SECRET_ENV_VAR).map_err(|_| "Slack commands are not configured")?;
+        return verify_slack(&secret, headers, body, now);
+    }
+    let expected = env::var(MATTERMOST_TOKEN_ENV_VAR).map_err(|_| "Chat commands are not configured")?;
+    match &command.token {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => Err("Command token does not match"),
+    }
+}
+
+pub fn parse_command(text: &str) -> Command {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    match words.as_slice() {
+        ["status"] => Command::Status,
+        ["machine", name] => Command::Machine(name.to_string()),
+        ["reimage", target] => Command::Reimage { target: target.to_string(), os: None },
+        ["reimage", target, os] => Command::Reimage { target: target.to_string(), os: Some(os.to_string()) },
+        ["confirm", code] => Command::Confirm(code.to_string()),
+        _ => Command::Help,
+    }
+}
+
+/// Look a machine up the way people refer to them in chat: hostname, memorable name, MAC or ID
+pub async fn find_machine(name: &str) -> Result<Option<Machine>> {
+    let machines = db::get_all_machines().await?;
+    Ok(machines.into_iter().find(|m| {
+        m.hostname.as_deref().is_some_and(|h| h.eq_ignore_ascii_case(name))
+            || m.memorable_name.as_deref().is_some_and(|n| n.eq_ignore_ascii_case(name))
+            || m.mac_address.eq_ignore_ascii_case(name)
+            || m.id.to_string() == name
+    }))
+}
+
+pub fn describe_machine(machine: &Machine) -> String {
+    let mut text = format!("*{}* is {}", machine.display_name(), machine.status);
+    if machine.status == dragonfly_common::models::MachineStatus::InstallingOS {
+        text.push_str(&format!(" ({}%)", machine.installation_progress));
+    }
+    text.push_str(&format!("\nIP {} · MAC {}", machine.ip_address, machine.mac_address));
+    if let Some(os) = machine.os_installed.as_deref().or(machine.os_choice.as_deref()) {
+        text.push_str(&format!(" · OS {}", os));
+    }
+    text
+}
+
+pub fn describe_fleet(stats: &FleetStats) -> String {
+    let statuses: Vec<String> = stats.by_status.iter()
+        .filter(|(_, count)| **count > 0)
+        .map(|(status, count)| format!("{} {}", count, status.replace('_', " ")))
+        .collect();
+    let mut text = format!("*{} machines*: {}", stats.total, statuses.join(", "));
+    text.push_str(&format!("\n{} installing, {} waiting", stats.active_installs, stats.queue_depth));
+    let installs = &stats.installs_24h;
+    if let Some(rate) = installs.failure_rate {
+        text.push_str(&format!("\nLast 24h: {} installed, {} failed ({:.0}% failure rate)", installs.succeeded, installs.failed, rate * 100.0));
+    }
+    text
+}
+
+pub async fn fleet_summary() -> Result<String> {
+    Ok(describe_fleet(&observability::fleet_stats().await?))
+}
+
+/// Hold a reimage until it's confirmed, returning the code to confirm it with
+pub fn request_confirmation(command: &SlashCommand, machine: &Machine, os_choice: &str, now: DateTime<Utc>) -> String {
+    let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+    let mut pending = PENDING.lock().unwrap();
+    pending.retain(|_, p| p.expires_at > now);
+    pending.insert(code.clone(), PendingReimage {
+        machine_id: machine.id,
+        machine_name: machine.display_name(),
+        os_choice: os_choice.to_string(),
+        channel_id: command.channel_id.clone(),
+        user_name: command.user_name.clone(),
+        expires_at: now + Duration::seconds(CONFIRM_SECS),
+    });
+    code
+}
+
+/// Claim a pending reimage; only the user who asked for it, in the same channel, can confirm
+pub fn take_confirmation(command: &SlashCommand, code: &str, now: DateTime<Utc>) -> Option<PendingReimage> {
+    let mut pending = PENDING.lock().unwrap();
+    pending.retain(|_, p| p.expires_at > now);
+    let matches = pending.get(code)
+        .is_some_and(|p| p.channel_id == command.channel_id && p.user_name == command.user_name);
+    if matches { pending.remove(code) } else { None }
+}
+
+/// Reply visible only to the person who ran the command
+pub fn ephemeral(text: impl Into<String>) -> Value {
+    json!({ "response_type": "ephemeral", "text": text.into() })
+}
+
+/// Reply posted to the whole channel
+pub fn in_channel(text: impl Into<String>) -> Value {
+    json!({ "response_type": "in_channel", "text": text.into() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_slack_signature() {
+        // The example from Slack's "Verifying requests from Slack" guide
+        let body = b"token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J&team_domain=testteamnow&channel_id=G8PSS9T3V&channel_name=foobar&user_id=U2CERLKJA&user_name=roadrunner&command=%2Fwebhook-collect&text=&response_url=https%3A%2F%2Fhooks.slack.com%2Fcommands%2FT1DC2JH3J%2F397700885554%2F96rGlfmibIGlgcZRskXaIFfN&trigger_id=398738663015.47445629121.803a0bc887a14d10d2c447fce8b6703c";
+        let mut headers = HeaderMap::new();
+        headers.insert("x-slack-request-timestamp", "1531420618".parse().unwrap());
+        headers.insert("x-slack-signature", "v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503".parse().unwrap());
+        let now = Utc.timestamp_opt(1531420700, 0).unwrap();
+        let secret = "8f742231b10e8888abcd99yyyzzz85a5";
+
+        assert!(verify_slack(secret, &headers, body, now).is_ok());
+        assert!(verify_slack(secret, &headers, b"text=tampered", now).is_err());
+        assert!(verify_slack(secret, &headers, body, now + Duration::hours(1)).is_err());
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("status"), Command::Status);
+        assert_eq!(parse_command("machine web-01"), Command::Machine("web-01".to_string()));
+        assert_eq!(parse_command(" reimage web-01  ubuntu-2204"), Command::Reimage { target: "web-01".to_string(), os: Some("ubuntu-2204".to_string()) });
+        assert_eq!(parse_command("reimage"), Command::Help);
+    }
+}
@@ -0,0 +1,105 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use tracing::{debug, error, info};
+
+use crate::db;
+
+/// How often expired sessions and tokens are pruned
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+/// Break-glass credentials are kept this long after they expire or are revoked, for the audit trail
+pub const BREAK_GLASS_RETENTION_DAYS: i64 = 90;
+
+/// What gets cleaned, as the `kind` label on the removal counter
+pub const KINDS: &[&str] = &["sessions", "render_tokens", "break_glass_credentials", "attestation_challenges"];
+
+lazy_static::lazy_static! {
+    static ref REMOVED: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(KINDS.iter().map(|k| (*k, 0)).collect());
+}
+
+/// Rows removed since the server started, by kind
+pub fn removed_totals() -> BTreeMap<&'static str, u64> {
+    REMOVED.lock().unwrap().clone()
+}
+
+fn record(kind: &'static str, result: anyhow::Result<u64>) -> u64 {
+    match result {
+        Ok(count) => {
+            *REMOVED.lock().unwrap().entry(kind).or_default() += count;
+            count
+        },
+        Err(e) => {
+            error!("Failed to clean up expired {}: {}", kind.replace('_', " "), e);
+            0
+        }
+    }
+}
+
+/// Remove everything that has expired as of `now`, returning how much of each kind went
+pub async fn run_cleanup(now: DateTime<Utc>) -> BTreeMap<&'static str, u64> {
+    let retention_cutoff = now - Duration::days(BREAK_GLASS_RETENTION_DAYS);
+    let mut removed = BTreeMap::new();
+    removed.insert("sessions", record("sessions", db::delete_expired_sessions(now).await));
+    removed.insert("render_tokens", record("render_tokens", db::delete_expired_render_tokens(now).await));
+    removed.insert("break_glass_credentials",
+        record("break_glass_credentials", db::delete_stale_break_glass_credentials(retention_cutoff).await));
+    removed.insert("attestation_challenges",
+        record("attestation_challenges", Ok(crate::attestation::prune_challenges(now) as u64)));
+    removed
+}
+
+/// Periodically prune expired login sessions, render tokens, old break-glass credentials and
+/// attestation challenges that were never answered
+pub async fn start_cleanup_task(mut shutdown_rx: tokio::sync::watch::Receiver<()>) {
+    tokio::spawn(async move {
+        info!("Starting cleanup task (every {}s)", CLEANUP_INTERVAL.as_secs());
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let removed = run_cleanup(Utc::now()).await;
+                    if removed.values().any(|count| *count > 0) {
+                        info!("Cleaned up expired records: {:?}", removed);
+                    } else {
+                        debug!("Cleanup found nothing expired");
+                    }
+                },
+                _ = shutdown_rx.changed() => {
+                    info!("Shutdown signal received, stopping cleanup task");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_break_glass_records_are_kept_for_audit() {
+        let pool = crate::test_support::database().await;
+        let now = Utc::now();
+        let days_ago = |days: i64| (now - Duration::days(days)).to_rfc3339();
+        for (username, expires_at, revoked_at) in [
+            ("expired-long-ago", days_ago(100), None),
+            ("expired-recently", days_ago(10), None),
+            ("revoked-long-ago", days_ago(-1), Some(days_ago(100))),
+        ] {
+            sqlx::query("INSERT INTO break_glass_credentials (username, password_hash, issued_by, created_at, expires_at, revoked_at) VALUES (?, 'hash', 'cli', ?, ?, ?)")
+                .bind(username)
+                .bind(days_ago(200))
+                .bind(expires_at)
+                .bind(revoked_at)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let cutoff = now - Duration::days(BREAK_GLASS_RETENTION_DAYS);
+        assert_eq!(db::prune_break_glass_credentials(&pool, cutoff).await.unwrap(), 2);
+        let left: Vec<String> = sqlx::query_scalar("SELECT username FROM break_glass_credentials").fetch_all(&pool).await.unwrap();
+        assert_eq!(left, ["expired-recently"]);
+    }
+}
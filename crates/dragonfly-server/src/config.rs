@@ -0,0 +1,272 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::env;
+
+use crate::artifact_store::ArtifactStorage;
+use crate::auth::Settings;
+use crate::discovery::DiscoveryPolicy;
+use crate::status::{self, ClusterConfig, CLUSTER_CONFIG_FILE};
+use crate::{api, db, heartbeat, secure_boot};
+
+const REDACTED: &str = "<redacted>";
+
+/// Where a configuration value came from. Later layers win: defaults, then the cluster config file,
+/// then environment variables. Settings edited in the UI live in the database.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSource {
+    Default,
+    File,
+    Env,
+    Database,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigEntry {
+    pub key: String,
+    pub value: Value,
+    pub default: Value,
+    pub source: ConfigSource,
+    /// Environment variable that overrides this value, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env: Option<&'static str>,
+    pub secret: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigChange {
+    pub key: String,
+    pub default: Value,
+    pub value: Value,
+    pub source: ConfigSource,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EffectiveConfig {
+    pub generated_at: DateTime<Utc>,
+    pub entries: Vec<ConfigEntry>,
+    /// Entries whose value differs from the built-in default
+    pub diff: Vec<ConfigChange>,
+}
+
+impl ConfigEntry {
+    fn new(key: &str, value: Value, default: Value, source: ConfigSource) -> Self {
+        Self { key: key.to_string(), value, default, source, env: None, secret: false }
+    }
+
+    /// A value read through an environment variable; `value` is what the server actually uses
+    fn env(key: &str, var: &'static str, value: Value, default: Value) -> Self {
+        let source = if env::var(var).is_ok() { ConfigSource::Env } else { ConfigSource::Default };
+        Self { env: Some(var), ..Self::new(key, value, default, source) }
+    }
+
+    fn secret(mut self) -> Self {
+        self.secret = true;
+        self
+    }
+
+    fn redact(value: &Value) -> Value {
+        if value.is_null() { Value::Null } else { json!(REDACTED) }
+    }
+}
+
+impl EffectiveConfig {
+    /// Compare against defaults before redacting, so a changed secret still shows up in the diff
+    pub fn new(entries: Vec<ConfigEntry>) -> Self {
+        let diff = entries.iter()
+            .filter(|entry| entry.value != entry.default)
+            .map(|entry| ConfigChange {
+                key: entry.key.clone(),
+                default: if entry.secret { ConfigEntry::redact(&entry.default) } else { entry.default.clone() },
+                value: if entry.secret { ConfigEntry::redact(&entry.value) } else { entry.value.clone() },
+                source: entry.source,
+            })
+            .collect();
+        let entries = entries.into_iter()
+            .map(|entry| if entry.secret {
+                ConfigEntry { value: ConfigEntry::redact(&entry.value), default: ConfigEntry::redact(&entry.default), ..entry }
+            } else {
+                entry
+            })
+            .collect();
+        Self { generated_at: Utc::now(), entries, diff }
+    }
+}
+
+fn env_string(var: &str) -> Value {
+    env::var(var).ok().map_or(Value::Null, Value::String)
+}
+
+// Kubernetes settings come from the installer's cluster config file, overridden by environment variables
+fn kube_entries() -> Vec<ConfigEntry> {
+    let file = status::load_cluster_config();
+    let effective = status::kube_settings();
+    let defaults = ClusterConfig::default();
+    let source = |var: &str, in_file: bool| {
+        if env::var(var).is_ok() {
+            ConfigSource::Env
+        } else if in_file {
+            ConfigSource::File
+        } else {
+            ConfigSource::Default
+        }
+    };
+    let with_env = |entry: ConfigEntry, var: &'static str| ConfigEntry { env: Some(var), ..entry };
+
+    vec![
+        ConfigEntry::env("kube.cluster_config", "DRAGONFLY_CLUSTER_CONFIG",
+            json!(status::cluster_config_path()), json!(CLUSTER_CONFIG_FILE)),
+        with_env(ConfigEntry::new("kube.kubeconfig", json!(effective.kubeconfig), json!(defaults.kubeconfig),
+            source("DRAGONFLY_KUBECONFIG", file.as_ref().is_some_and(|f| f.kubeconfig.is_some()))), "DRAGONFLY_KUBECONFIG"),
+        with_env(ConfigEntry::new("kube.context", json!(effective.context), json!(defaults.context),
+            source("DRAGONFLY_KUBE_CONTEXT", file.as_ref().is_some_and(|f| f.context.is_some()))), "DRAGONFLY_KUBE_CONTEXT"),
+        with_env(ConfigEntry::new("kube.namespace", json!(effective.namespace), json!(defaults.namespace),
+            source("DRAGONFLY_NAMESPACE", file.is_some())), "DRAGONFLY_NAMESPACE"),
+        with_env(ConfigEntry::new("kube.timeout_secs", json!(effective.timeout_secs), json!(defaults.timeout_secs),
+            source("DRAGONFLY_KUBE_TIMEOUT", file.as_ref().is_some_and(|f| f.timeout_secs.is_some()))), "DRAGONFLY_KUBE_TIMEOUT"),
+        ConfigEntry::new("kube.existing_cluster", json!(effective.existing_cluster), json!(defaults.existing_cluster),
+            if file.is_some() { ConfigSource::File } else { ConfigSource::Default }),
+    ]
+}
+
+fn env_entries() -> Vec<ConfigEntry> {
+    let api_quota = crate::rate_limit::ApiQuota::from_env().ok();
+    let event_config = crate::event_manager::EventConfig::from_env().unwrap_or_default();
+    let pool_config = crate::db::PoolConfig::from_env().ok();
+    vec![
+        ConfigEntry::env("server.base_url", "DRAGONFLY_BASE_URL", env_string("DRAGONFLY_BASE_URL"), Value::Null),
+        ConfigEntry::env("server.listen_addr", crate::network::LISTEN_ADDR_ENV_VAR,
+            json!(env::var(crate::network::LISTEN_ADDR_ENV_VAR).unwrap_or_else(|_| crate::network::DEFAULT_LISTEN_ADDR.to_string())),
+            json!(crate::network::DEFAULT_LISTEN_ADDR)),
+        ConfigEntry::env("server.provisioning_addr", crate::network::PROVISIONING_ADDR_ENV_VAR,
+            env_string(crate::network::PROVISIONING_ADDR_ENV_VAR), Value::Null),
+        ConfigEntry::env("api.rate_limit_per_address", crate::rate_limit::API_RATE_LIMIT_ENV_VAR,
+            json!(api_quota.map_or(0, |q| q.per_address.unwrap_or(0))), json!(crate::rate_limit::DEFAULT_API_RATE_LIMIT)),
+        ConfigEntry::env("api.rate_limit_per_token", crate::rate_limit::API_TOKEN_RATE_LIMIT_ENV_VAR,
+            json!(api_quota.map_or(0, |q| q.per_token.unwrap_or(0))), json!(crate::rate_limit::DEFAULT_API_TOKEN_RATE_LIMIT)),
+        ConfigEntry::env("database.max_connections", crate::db::DB_MAX_CONNECTIONS_ENV_VAR,
+            json!(pool_config.map(|c| c.max_connections)), json!(crate::db::DEFAULT_DB_MAX_CONNECTIONS)),
+        ConfigEntry::env("database.acquire_timeout_secs", crate::db::DB_ACQUIRE_TIMEOUT_ENV_VAR,
+            json!(pool_config.map(|c| c.acquire_timeout_secs)), json!(crate::db::DEFAULT_DB_ACQUIRE_TIMEOUT_SECS)),
+        ConfigEntry::env("database.busy_timeout_secs", crate::db::DB_BUSY_TIMEOUT_ENV_VAR,
+            json!(pool_config.map(|c| c.busy_timeout_secs)), json!(crate::db::DEFAULT_DB_BUSY_TIMEOUT_SECS)),
+        ConfigEntry::env("database.slow_query_ms", crate::db::DB_SLOW_QUERY_ENV_VAR,
+            json!(pool_config.map(|c| c.slow_query_ms)), json!(crate::db::DEFAULT_DB_SLOW_QUERY_MS)),
+        ConfigEntry::env("machine_cache.ttl_secs", crate::machine_cache::CACHE_TTL_ENV_VAR,
+            json!(crate::machine_cache::ttl_from_env().ok().map(|ttl| ttl.map_or(0, |d| d.as_secs()))),
+            json!(crate::machine_cache::DEFAULT_CACHE_TTL_SECS)),
+        ConfigEntry::env("tinkerbell.drift_policy", crate::hardware_sync::DRIFT_POLICY_ENV_VAR,
+            json!(crate::hardware_sync::DriftPolicy::from_env().ok()), json!(crate::hardware_sync::DriftPolicy::default())),
+        ConfigEntry::env("events.queue_size", crate::event_manager::QUEUE_SIZE_ENV_VAR,
+            json!(event_config.queue_size), json!(crate::event_manager::DEFAULT_QUEUE_SIZE)),
+        ConfigEntry::env("events.drop_policy", crate::event_manager::DROP_POLICY_ENV_VAR,
+            json!(event_config.drop_policy), json!(crate::event_manager::DropPolicy::default())),
+        ConfigEntry::env("grpc.listen_addr", crate::grpc::GRPC_LISTEN_ENV_VAR,
+            env_string(crate::grpc::GRPC_LISTEN_ENV_VAR), Value::Null),
+        ConfigEntry::env("logging.format", crate::logging::LOG_FORMAT_ENV_VAR,
+            json!(env::var(crate::logging::LOG_FORMAT_ENV_VAR).unwrap_or_else(|_| "text".to_string())), json!("text")),
+        ConfigEntry::env("logging.syslog", crate::logging::SYSLOG_ENV_VAR, env_string(crate::logging::SYSLOG_ENV_VAR), Value::Null),
+        ConfigEntry::env("telemetry.otlp_endpoint", "OTEL_EXPORTER_OTLP_ENDPOINT", env_string("OTEL_EXPORTER_OTLP_ENDPOINT"), Value::Null),
+        ConfigEntry::env("server.demo_mode", "DRAGONFLY_DEMO_MODE", json!(env::var("DRAGONFLY_DEMO_MODE").is_ok()), json!(false)),
+        ConfigEntry::env("demo.fleet_size", crate::demo::FLEET_SIZE_ENV_VAR,
+            json!(crate::demo::fleet_size()), json!(crate::demo::DEFAULT_FLEET_SIZE)),
+        ConfigEntry::env("demo.tick_secs", crate::demo::TICK_ENV_VAR,
+            json!(crate::demo::tick_secs()), json!(crate::demo::DEFAULT_TICK_SECS)),
+        ConfigEntry::env("scenarios.dir", crate::scenarios::DIR_ENV_VAR,
+            json!(crate::scenarios::dir()), json!(crate::scenarios::DEFAULT_DIR)),
+        ConfigEntry::env("scenarios.replay", crate::scenarios::REPLAY_ENV_VAR,
+            json!(crate::scenarios::replay_allowed()), json!(false)),
+        ConfigEntry::env("heartbeat.offline_after_secs", "DRAGONFLY_OFFLINE_AFTER",
+            json!(heartbeat::offline_after_secs()), json!(heartbeat::DEFAULT_OFFLINE_AFTER_SECS)),
+        ConfigEntry::env("agent.clock_skew_threshold_secs", "DRAGONFLY_CLOCK_SKEW_THRESHOLD",
+            json!(api::clock_skew_threshold_secs()), json!(api::DEFAULT_CLOCK_SKEW_THRESHOLD_SECS)),
+        ConfigEntry::env("agent.enrollment_token", "DRAGONFLY_ENROLLMENT_TOKEN",
+            json!(crate::agent_commands::enrollment_token()), Value::Null).secret(),
+        ConfigEntry::env("agent.require_tpm", crate::attestation::REQUIRE_TPM_ENV_VAR,
+            json!(crate::attestation::required()), json!(false)),
+        ConfigEntry::env("escrow.key", crate::escrow::KEY_ENV_VAR,
+            env_string(crate::escrow::KEY_ENV_VAR), Value::Null).secret(),
+        ConfigEntry::env("chatops.slack_signing_secret", crate::chatops::SLACK_SECRET_ENV_VAR,
+            env_string(crate::chatops::SLACK_SECRET_ENV_VAR), Value::Null).secret(),
+        ConfigEntry::env("chatops.mattermost_token", crate::chatops::MATTERMOST_TOKEN_ENV_VAR,
+            env_string(crate::chatops::MATTERMOST_TOKEN_ENV_VAR), Value::Null).secret(),
+        ConfigEntry::env("provisioning.ntp_servers", "DRAGONFLY_NTP_SERVERS",
+            json!(env::var("DRAGONFLY_NTP_SERVERS").unwrap_or_else(|_| "pool.ntp.org".to_string())), json!("pool.ntp.org")),
+        ConfigEntry::env("provisioning.secure_boot_chain", "DRAGONFLY_SECURE_BOOT_CHAIN",
+            json!(secure_boot::chain_enabled()), json!(false)),
+        ConfigEntry::env("templates.community_defaults", "DRAGONFLY_COMMUNITY_DEFAULTS",
+            json!(env::var("DRAGONFLY_COMMUNITY_DEFAULTS").map(|v| v != "false" && v != "0").unwrap_or(true)), json!(true)),
+        // Unset Tinkerbell addresses are derived from the base URL when a machine boots
+        ConfigEntry::env("tinkerbell.grpc_authority", "TINKERBELL_GRPC_AUTHORITY", env_string("TINKERBELL_GRPC_AUTHORITY"), Value::Null),
+        ConfigEntry::env("tinkerbell.syslog_host", "TINKERBELL_SYSLOG_HOST", env_string("TINKERBELL_SYSLOG_HOST"), Value::Null),
+        ConfigEntry::env("tinkerbell.tls", "TINKERBELL_TLS",
+            json!(env::var("TINKERBELL_TLS").map(|s| s.parse().unwrap_or(false)).unwrap_or(false)), json!(false)),
+    ]
+}
+
+fn database_entries(settings: &Settings, discovery: &DiscoveryPolicy, storage: &ArtifactStorage) -> Vec<ConfigEntry> {
+    let defaults = Settings::default();
+    let database = |key: &str, value: Value, default: Value| ConfigEntry::new(key, value, default, ConfigSource::Database);
+    vec![
+        database("settings.require_login", json!(settings.require_login), json!(defaults.require_login)),
+        database("settings.require_approval", json!(settings.require_approval), json!(defaults.require_approval)),
+        database("settings.generate_root_passwords", json!(settings.generate_root_passwords), json!(defaults.generate_root_passwords)),
+        database("settings.block_eol_assignments", json!(settings.block_eol_assignments), json!(defaults.block_eol_assignments)),
+        database("settings.default_os", json!(settings.default_os), json!(defaults.default_os)),
+        database("settings.setup_completed", json!(settings.setup_completed), json!(defaults.setup_completed)),
+        database("settings.hostname_policy", json!(settings.hostname_policy), json!(defaults.hostname_policy)),
+        database("settings.boot_filter", json!(settings.boot_filter), json!(defaults.boot_filter)),
+        database("discovery.policy", json!(discovery), json!(DiscoveryPolicy::default())),
+        database("artifacts.storage", json!(storage.redacted()), json!(ArtifactStorage::default())),
+    ]
+}
+
+/// The configuration the server is running with, layer by layer, with secrets redacted
+pub async fn effective() -> Result<EffectiveConfig> {
+    let settings = db::get_app_settings().await?;
+    let discovery = db::get_discovery_policy().await?;
+    let storage = db::get_artifact_storage().await?;
+
+    let mut entries = env_entries();
+    entries.extend(kube_entries());
+    entries.extend(database_entries(&settings, &discovery, &storage));
+    Ok(EffectiveConfig::new(entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_and_redaction() {
+        let config = EffectiveConfig::new(vec![
+            ConfigEntry::new("a", json!(1), json!(1), ConfigSource::Default),
+            ConfigEntry::new("b", json!(2), json!(1), ConfigSource::Env),
+            ConfigEntry::new("token", json!("hunter2"), Value::Null, ConfigSource::Env).secret(),
+        ]);
+        assert_eq!(config.diff.iter().map(|c| c.key.as_str()).collect::<Vec<_>>(), vec!["b", "token"]);
+        assert_eq!(config.entries[2].value, json!(REDACTED));
+        assert_eq!(config.diff[1].value, json!(REDACTED));
+        assert_eq!(config.diff[1].default, Value::Null);
+    }
+
+    #[test]
+    fn test_database_pool_config() {
+        let vars = |pairs: &[(&str, &str)]| {
+            let map: std::collections::HashMap<String, String> = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            move |var: &str| map.get(var).cloned()
+        };
+        let config = db::PoolConfig::from_vars(vars(&[])).unwrap();
+        assert_eq!(config.max_connections, db::DEFAULT_DB_MAX_CONNECTIONS);
+        assert_eq!(config.slow_query_ms, db::DEFAULT_DB_SLOW_QUERY_MS);
+
+        let config = db::PoolConfig::from_vars(vars(&[(db::DB_MAX_CONNECTIONS_ENV_VAR, " 4 "), (db::DB_SLOW_QUERY_ENV_VAR, "0")])).unwrap();
+        assert_eq!((config.max_connections, config.slow_query_ms), (4, 0));
+        for (var, value) in [(db::DB_MAX_CONNECTIONS_ENV_VAR, "0"), (db::DB_ACQUIRE_TIMEOUT_ENV_VAR, "0"), (db::DB_BUSY_TIMEOUT_ENV_VAR, "five")] {
+            let error = db::PoolConfig::from_vars(vars(&[(var, value)])).unwrap_err();
+            assert!(error.contains(var), "{}", error);
+        }
+    }
+}
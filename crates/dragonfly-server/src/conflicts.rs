@@ -0,0 +1,156 @@
+use anyhow::Result;
+use dragonfly_common::models::Machine;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::db;
+use crate::event_manager::EventManager;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    Ip,
+    Mac,
+}
+
+/// Two or more machines claiming the same address
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Conflict {
+    pub kind: ConflictKind,
+    pub value: String,
+    pub machines: Vec<Uuid>,
+}
+
+/// Only real addresses count; placeholders like `0.0.0.0` or `Unknown` are shared by many machines
+fn normalize_ip(ip: &str) -> Option<String> {
+    ip.trim().parse::<IpAddr>().ok()
+        .filter(|ip| !ip.is_unspecified())
+        .map(|ip| ip.to_string())
+}
+
+/// Find every IP and MAC address claimed by more than one machine
+pub fn detect(machines: &[Machine]) -> Vec<Conflict> {
+    let mut claims: BTreeMap<(ConflictKind, String), Vec<Uuid>> = BTreeMap::new();
+    for machine in machines {
+        // Rows from before MACs were normalized may still be spelled `AA-BB-..`
+        claims.entry((ConflictKind::Mac, db::normalize_mac(&machine.mac_address))).or_default().push(machine.id);
+        if let Some(ip) = normalize_ip(&machine.ip_address) {
+            claims.entry((ConflictKind::Ip, ip)).or_default().push(machine.id);
+        }
+    }
+    claims.into_iter()
+        .filter(|(_, ids)| ids.len() > 1)
+        .map(|((kind, value), machines)| Conflict { kind, value, machines })
+        .collect()
+}
+
+/// Human-readable conflict reasons for each conflicted machine
+pub fn reasons(machines: &[Machine]) -> HashMap<Uuid, Vec<String>> {
+    let names: HashMap<Uuid, String> = machines.iter()
+        .map(|m| (m.id, m.display_name()))
+        .collect();
+
+    let mut reasons: HashMap<Uuid, Vec<String>> = HashMap::new();
+    for conflict in detect(machines) {
+        let what = match conflict.kind {
+            ConflictKind::Ip => "IP",
+            ConflictKind::Mac => "MAC",
+        };
+        for id in &conflict.machines {
+            let others: Vec<&str> = conflict.machines.iter()
+                .filter(|other| *other != id)
+                .filter_map(|other| names.get(other).map(String::as_str))
+                .collect();
+            reasons.entry(*id).or_default()
+                .push(format!("{} {} is also claimed by {}", what, conflict.value, others.join(", ")));
+        }
+    }
+    reasons
+}
+
+/// Conflicts that currently block provisioning a machine
+pub async fn for_machine(id: &Uuid) -> Result<Vec<String>> {
+    let machines = db::get_all_machines().await?;
+    Ok(reasons(&machines).remove(id).unwrap_or_default())
+}
+
+/// Periodically re-check for conflicts and announce machines entering or leaving conflict
+pub async fn start_conflict_monitor(
+    event_manager: Arc<EventManager>,
+    mut shutdown_rx: tokio::sync::watch::Receiver<()>,
+) {
+    tokio::spawn(async move {
+        info!("Starting IP/MAC conflict monitor");
+        let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+        let mut conflicted: HashSet<Uuid> = HashSet::new();
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    let machines = match db::get_all_machines().await {
+                        Ok(machines) => machines,
+                        Err(e) => {
+                            error!("Failed to load machines for conflict check: {}", e);
+                            continue;
+                        }
+                    };
+                    let current = reasons(&machines);
+                    for (id, why) in &current {
+                        if !conflicted.contains(id) {
+                            warn!("Machine {} has an address conflict: {}", id, why.join("; "));
+                            let _ = event_manager.send(format!("machine_conflict:{}", id));
+                            let _ = event_manager.send(format!("machine_updated:{}", id));
+                        }
+                    }
+                    for id in conflicted.iter().filter(|id| !current.contains_key(id)) {
+                        info!("Address conflict for machine {} is resolved", id);
+                        let _ = event_manager.send(format!("machine_updated:{}", id));
+                    }
+                    conflicted = current.into_keys().collect();
+                },
+                _ = shutdown_rx.changed() => {
+                    info!("Shutdown signal received, stopping conflict monitor.");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dragonfly_common::models::MachineStatus;
+
+    fn machine(mac: &str, ip: &str, hostname: &str) -> Machine {
+        Machine {
+            ip_address: ip.to_string(),
+            hostname: Some(hostname.to_string()),
+            status: MachineStatus::AwaitingAssignment,
+            ..crate::test_support::machine(mac)
+        }
+    }
+
+    #[test]
+    fn test_detect_conflicts() {
+        let a = machine("aa:bb:cc:dd:ee:01", "10.0.0.5", "node-a");
+        let b = machine("aa:bb:cc:dd:ee:02", "10.0.0.5", "node-b");
+        let c = machine("AA-BB-CC-DD-EE-01", "0.0.0.0", "node-c");
+        let d = machine("aa:bb:cc:dd:ee:03", "0.0.0.0", "node-d");
+        let conflicts = detect(&[a.clone(), b.clone(), c.clone(), d.clone()]);
+        assert_eq!(conflicts.len(), 2);
+        assert!(conflicts.contains(&Conflict { kind: ConflictKind::Ip, value: "10.0.0.5".to_string(), machines: vec![a.id, b.id] }));
+        assert!(conflicts.contains(&Conflict { kind: ConflictKind::Mac, value: "aa:bb:cc:dd:ee:01".to_string(), machines: vec![a.id, c.id] }));
+
+        let why = reasons(&[a.clone(), b.clone(), c, d.clone()]);
+        assert_eq!(why[&b.id], vec!["IP 10.0.0.5 is also claimed by node-a".to_string()]);
+        assert_eq!(why[&a.id].len(), 2);
+        assert!(!why.contains_key(&d.id));
+    }
+}
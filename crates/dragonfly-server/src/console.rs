@@ -0,0 +1,102 @@
+use axum::extract::ws::{Message, WebSocket};
+use dragonfly_common::models::{BmcCredentials, BmcType};
+use std::process::Stdio;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Host part of a BMC address, which may be stored as a bare host or as a Redfish URL
+pub fn bmc_host(address: &str) -> Option<String> {
+    let address = address.trim();
+    if address.contains("://") {
+        return url::Url::parse(address).ok()?.host_str().map(str::to_string);
+    }
+    Some(address.to_string()).filter(|a| !a.is_empty())
+}
+
+/// `ipmitool` invocation against a machine's BMC. The password goes through
+/// IPMI_PASSWORD (`-E`) so it never shows up in the process list.
+pub(crate) fn ipmitool(credentials: &BmcCredentials, host: &str, args: &[&str]) -> Command {
+    let mut command = Command::new("ipmitool");
+    command
+        .args(["-I", "lanplus", "-H", host, "-U", &credentials.username, "-E"])
+        .args(args)
+        .env("IPMI_PASSWORD", credentials.password.as_deref().unwrap_or(""))
+        .kill_on_drop(true);
+    command
+}
+
+/// Check that a machine's BMC can serve a serial console, returning the host to connect to.
+/// Redfish has no streaming console of its own; the BMCs that implement it (iDRAC, iLO, XCC, OpenBMC)
+/// expose the same serial port over IPMI SOL, so both types are proxied through `ipmitool`.
+pub fn console_host(credentials: &BmcCredentials) -> Result<String, String> {
+    match credentials.bmc_type {
+        BmcType::IPMI | BmcType::Redfish => {},
+        BmcType::Other(ref name) => return Err(format!("{} BMCs do not support a serial console", name)),
+    }
+    bmc_host(&credentials.address).ok_or_else(|| format!("Invalid BMC address '{}'", credentials.address))
+}
+
+/// Proxy a browser terminal to the machine's serial console until either side hangs up
+pub async fn run_sol_session(mut socket: WebSocket, machine_id: Uuid, credentials: BmcCredentials, host: String) {
+    // A console left open elsewhere would make activation fail, so take it over
+    let _ = ipmitool(&credentials, &host, &["sol", "deactivate"]).output().await;
+
+    let mut child = match ipmitool(&credentials, &host, &["sol", "activate"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Failed to start ipmitool for machine {}: {}", machine_id, e);
+            let _ = socket.send(Message::Text(format!("\r\n[dragonfly] Failed to start ipmitool: {}\r\n", e).into())).await;
+            return;
+        }
+    };
+    let (Some(mut stdin), Some(mut stdout), Some(mut stderr)) = (child.stdin.take(), child.stdout.take(), child.stderr.take()) else {
+        return;
+    };
+
+    info!("Serial console session opened for machine {} via {}", machine_id, host);
+    let mut out = [0u8; 4096];
+    let mut err = [0u8; 1024];
+    let mut stderr_open = true;
+    loop {
+        tokio::select! {
+            read = stdout.read(&mut out) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => if socket.send(Message::Binary(out[..n].to_vec().into())).await.is_err() { break },
+            },
+            read = stderr.read(&mut err), if stderr_open => match read {
+                Ok(0) | Err(_) => stderr_open = false,
+                Ok(n) => if socket.send(Message::Binary(err[..n].to_vec().into())).await.is_err() { break },
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => if stdin.write_all(text.as_bytes()).await.is_err() { break },
+                Some(Ok(Message::Binary(data))) => if stdin.write_all(&data).await.is_err() { break },
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                Some(Ok(_)) => {},
+            },
+        }
+    }
+
+    let _ = child.kill().await;
+    let _ = ipmitool(&credentials, &host, &["sol", "deactivate"]).output().await;
+    let _ = socket.send(Message::Text("\r\n[dragonfly] Console session closed\r\n".into())).await;
+    info!("Serial console session closed for machine {}", machine_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bmc_host() {
+        assert_eq!(bmc_host("10.0.0.5").as_deref(), Some("10.0.0.5"));
+        assert_eq!(bmc_host("https://idrac.example.com/redfish/v1").as_deref(), Some("idrac.example.com"));
+        assert_eq!(bmc_host("  "), None);
+    }
+}
@@ -0,0 +1,175 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Query parameters of the form `field.<name>=<filter>` filter machine lists by custom field
+pub const FILTER_PREFIX: &str = "field.";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    Text,
+    Number,
+    /// `YYYY-MM-DD`
+    Date,
+    Boolean,
+}
+
+impl FieldType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FieldType::Text => "text",
+            FieldType::Number => "number",
+            FieldType::Date => "date",
+            FieldType::Boolean => "boolean",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "text" => Some(FieldType::Text),
+            "number" => Some(FieldType::Number),
+            "date" => Some(FieldType::Date),
+            "boolean" => Some(FieldType::Boolean),
+            _ => None,
+        }
+    }
+}
+
+/// An admin-defined machine attribute such as owner, cost center or warranty expiry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldDefinition {
+    pub name: String,
+    pub label: String,
+    pub field_type: FieldType,
+    /// Show the field as a column on the machine list, not just on the details page
+    #[serde(default)]
+    pub show_in_list: bool,
+}
+
+pub fn validate_definition(field: &FieldDefinition) -> Result<(), String> {
+    let name = &field.name;
+    if name.is_empty() || name.len() > 64 || !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+        return Err("Field names must be 1 to 64 lowercase letters, digits or '_'".to_string());
+    }
+    if field.label.trim().is_empty() {
+        return Err("Give the field a label".to_string());
+    }
+    Ok(())
+}
+
+/// Check a value against the field's type, accepting numbers and booleans sent as strings
+pub fn normalize_value(field: &FieldDefinition, value: &Value) -> Result<Value, String> {
+    let invalid = || format!("'{}' must be a {}", field.name, field.field_type.as_str());
+    match (field.field_type, value) {
+        (FieldType::Text, Value::String(text)) => Ok(Value::String(text.trim().to_string())),
+        (FieldType::Number, Value::Number(_)) => Ok(value.clone()),
+        (FieldType::Number, Value::String(text)) => text.trim().parse::<f64>().ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number)
+            .ok_or_else(invalid),
+        (FieldType::Date, Value::String(text)) => NaiveDate::parse_from_str(text.trim(), "%Y-%m-%d")
+            .map(|date| Value::String(date.to_string()))
+            .map_err(|_| format!("'{}' must be a date (YYYY-MM-DD)", field.name)),
+        (FieldType::Boolean, Value::Bool(_)) => Ok(value.clone()),
+        (FieldType::Boolean, Value::String(text)) => match text.trim() {
+            "true" | "yes" | "1" => Ok(Value::Bool(true)),
+            "false" | "no" | "0" => Ok(Value::Bool(false)),
+            _ => Err(invalid()),
+        },
+        _ => Err(invalid()),
+    }
+}
+
+/// Whether a machine's value matches a filter. Numbers and dates take `<`, `<=`, `>` and `>=`
+/// prefixes; text matches case-insensitively; `~text` matches a substring. An empty filter
+/// matches machines without the field set.
+pub fn matches(field: &FieldDefinition, value: Option<&Value>, filter: &str) -> bool {
+    if filter.is_empty() {
+        return value.is_none();
+    }
+    let Some(value) = value else {
+        return false;
+    };
+    match field.field_type {
+        FieldType::Text => {
+            let text = value.as_str().unwrap_or_default().to_lowercase();
+            match filter.strip_prefix('~') {
+                Some(needle) => text.contains(&needle.to_lowercase()),
+                None => text == filter.to_lowercase(),
+            }
+        },
+        FieldType::Boolean => normalize_value(field, &Value::String(filter.to_string())).is_ok_and(|wanted| &wanted == value),
+        FieldType::Number | FieldType::Date => {
+            let (op, operand) = ["<=", ">=", "<", ">"].iter()
+                .find_map(|op| filter.strip_prefix(op).map(|rest| (*op, rest)))
+                .unwrap_or(("=", filter));
+            let ordering = match field.field_type {
+                FieldType::Number => match (value.as_f64(), operand.trim().parse::<f64>()) {
+                    (Some(have), Ok(want)) => have.partial_cmp(&want),
+                    _ => None,
+                },
+                _ => match (value.as_str().map(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d")), NaiveDate::parse_from_str(operand.trim(), "%Y-%m-%d")) {
+                    (Some(Ok(have)), Ok(want)) => Some(have.cmp(&want)),
+                    _ => None,
+                },
+            };
+            let Some(ordering) = ordering else {
+                return false;
+            };
+            match op {
+                "<=" => ordering.is_le(),
+                ">=" => ordering.is_ge(),
+                "<" => ordering.is_lt(),
+                ">" => ordering.is_gt(),
+                _ => ordering.is_eq(),
+            }
+        },
+    }
+}
+
+/// Pick the `field.<name>` filters out of a query string. Unknown fields are an error, so a typo
+/// doesn't silently return every machine.
+pub fn parse_filters<'a>(query: &'a HashMap<String, String>, fields: &'a [FieldDefinition]) -> Result<Vec<(&'a FieldDefinition, &'a str)>, String> {
+    let mut filters = Vec::new();
+    for (key, filter) in query {
+        let Some(name) = key.strip_prefix(FILTER_PREFIX) else {
+            continue;
+        };
+        let field = fields.iter().find(|f| f.name == name)
+            .ok_or_else(|| format!("Unknown custom field '{}'", name))?;
+        filters.push((field, filter.as_str()));
+    }
+    Ok(filters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn field(name: &str, field_type: FieldType) -> FieldDefinition {
+        FieldDefinition { name: name.to_string(), label: name.to_string(), field_type, show_in_list: false }
+    }
+
+    #[test]
+    fn test_values_and_filters() {
+        let warranty = field("warranty_expiry", FieldType::Date);
+        let date = normalize_value(&warranty, &json!("2026-03-01")).unwrap();
+        assert!(normalize_value(&warranty, &json!("next year")).is_err());
+        assert!(matches(&warranty, Some(&date), "<2026-06-01"));
+        assert!(!matches(&warranty, Some(&date), ">=2026-06-01"));
+
+        let rack_u = field("rack_u", FieldType::Number);
+        let u = normalize_value(&rack_u, &json!("12")).unwrap();
+        assert!(matches(&rack_u, Some(&u), "12"));
+        assert!(matches(&rack_u, Some(&u), ">10"));
+
+        let owner = field("owner", FieldType::Text);
+        assert!(matches(&owner, Some(&json!("Alice")), "alice"));
+        assert!(matches(&owner, Some(&json!("Alice")), "~lic"));
+        assert!(matches(&owner, None, ""));
+        assert!(validate_definition(&field("Cost Center", FieldType::Text)).is_err());
+    }
+}
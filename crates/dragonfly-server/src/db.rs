@@ -1,26 +1,205 @@
 use anyhow::{anyhow, Result};
 use chrono::Utc;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
 use sqlx::{Pool, Sqlite, SqlitePool, Row};
 use tokio::sync::OnceCell;
-use tracing::{error, info};
+use tracing::{error, info, instrument, warn};
 use uuid::Uuid;
 use std::fs::{File, OpenOptions};
 use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use serde_json;
 
-use dragonfly_common::models::{Machine, MachineStatus, RegisterRequest};
+use dragonfly_common::models::{Machine, MachineStatus, PciDevice, RegisterRequest, TpmIdentity};
+use dragonfly_common::state_machine::{StatusCause, Transition};
 // Make re-exports public and correct the imported names
 pub use dragonfly_common::models::{OsAssignmentRequest, RegisterResponse, ErrorResponse}; // Removed UpdateTagsRequest, corrected others
 use crate::auth::{Credentials, Settings};
 use crate::tinkerbell::WorkflowInfo;
+use crate::policy::AclEntry;
+use crate::boot_arch::BootTarget;
+use crate::webhooks::WebhookEndpoint;
+use crate::notifications::{ChannelKind, NotificationChannel};
+use crate::alerts::{AlertRule, SmtpSettings};
+use crate::replace::{Change, Edit, ReplaceAudit};
+use crate::freeze::FreezeRecord;
+use crate::discovery::{DiscoveryPolicy, DiscoveryReport};
+use crate::break_glass::BreakGlassRecord;
+use crate::users::UserAccount;
+use crate::totp::TotpState;
+use crate::projects::TemplateScope;
+use crate::escrow::{DiskKey, DiskKeyAccess, Scheme};
+use crate::custom_fields::{FieldDefinition, FieldType};
+use crate::topology::{Placement, Rack, Site};
+use crate::bmc::{Backend, BmcJob, BmcTask, JobState};
+use crate::template_vars::TemplateVariable;
+use crate::root_password::RootPasswordInfo;
+use crate::os_lifecycle::OsLifecycle;
+use crate::resources::Claim;
+use crate::batch::Write;
+use crate::artifact_store::ArtifactStorage;
+use crate::vnc::VncTarget;
+use crate::views::SavedView;
+use crate::preferences::UiPreferences;
+use crate::secure_boot::SignedBootImage;
+use crate::workflow_templates::TemplateVersion;
+use crate::pipelines::{Pipeline, PipelineRun, RunState};
+use crate::firmware::{Component, FirmwareImage, MachineFirmware, NewFirmwareImage};
+use crate::storage_profiles::StorageProfile;
+use crate::machine_profiles::MachineProfile;
+use crate::maintenance::{MaintenanceWindow, ProvisioningHold};
+use crate::install_queue::{InstallLimits, QueuedInstall};
+use crate::rollouts::Rollout;
+use crate::health_checks::{HealthReport, TemplateChecks};
+use crate::reimage_schedules::{ReimageRun, ReimageSchedule};
+use crate::upgrade::Upgrade;
+use dragonfly_common::agent_protocol::{AgentCommand, CommandKind, CommandStatus};
 
 // Global database pool
 static DB_POOL: OnceCell<Pool<Sqlite>> = OnceCell::const_new();
 
-// Initialize the database connection pool
+/// Connections in the database pool
+pub const DB_MAX_CONNECTIONS_ENV_VAR: &str = "DRAGONFLY_DB_MAX_CONNECTIONS";
+/// Seconds a query waits for a free connection before failing
+pub const DB_ACQUIRE_TIMEOUT_ENV_VAR: &str = "DRAGONFLY_DB_ACQUIRE_TIMEOUT";
+/// Seconds a connection waits for another's write to finish before failing with "database is locked"
+pub const DB_BUSY_TIMEOUT_ENV_VAR: &str = "DRAGONFLY_DB_BUSY_TIMEOUT";
+/// Queries slower than this many milliseconds are logged; 0 turns the log off
+pub const DB_SLOW_QUERY_ENV_VAR: &str = "DRAGONFLY_DB_SLOW_QUERY_MS";
+pub const DEFAULT_DB_MAX_CONNECTIONS: u32 = 10;
+pub const DEFAULT_DB_ACQUIRE_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_DB_BUSY_TIMEOUT_SECS: u64 = 5;
+pub const DEFAULT_DB_SLOW_QUERY_MS: u64 = 250;
+
+/// Database pool settings, read from the environment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    pub max_connections: u32,
+    pub acquire_timeout_secs: u64,
+    pub busy_timeout_secs: u64,
+    pub slow_query_ms: u64,
+}
+
+impl PoolConfig {
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_vars(|var| env::var(var).ok())
+    }
+
+    /// The pool settings from `var`, which looks up an environment variable
+    pub(crate) fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let number = |name: &str, default: u64| -> Result<u64, String> {
+            match var(name) {
+                Some(value) => value.trim().parse::<u64>()
+                    .map_err(|_| format!("{} must be a whole number, got '{}'", name, value)),
+                None => Ok(default),
+            }
+        };
+        let max_connections = u32::try_from(number(DB_MAX_CONNECTIONS_ENV_VAR, DEFAULT_DB_MAX_CONNECTIONS as u64)?).ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| format!("{} must be at least 1", DB_MAX_CONNECTIONS_ENV_VAR))?;
+        let acquire_timeout_secs = number(DB_ACQUIRE_TIMEOUT_ENV_VAR, DEFAULT_DB_ACQUIRE_TIMEOUT_SECS)?;
+        if acquire_timeout_secs == 0 {
+            return Err(format!("{} must be at least 1 second", DB_ACQUIRE_TIMEOUT_ENV_VAR));
+        }
+        Ok(PoolConfig {
+            max_connections,
+            acquire_timeout_secs,
+            busy_timeout_secs: number(DB_BUSY_TIMEOUT_ENV_VAR, DEFAULT_DB_BUSY_TIMEOUT_SECS)?,
+            slow_query_ms: number(DB_SLOW_QUERY_ENV_VAR, DEFAULT_DB_SLOW_QUERY_MS)?,
+        })
+    }
+}
+
+// ---- START QUERY TIMING FUNCTIONS ----
+
+/// Upper bounds, in seconds, of the query duration histogram buckets
+pub const QUERY_DURATION_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 1.0, 5.0];
+
+static SLOW_QUERY_MS: AtomicU64 = AtomicU64::new(DEFAULT_DB_SLOW_QUERY_MS);
+
+/// How long one query has taken since the server started
+#[derive(Debug, Clone, Default)]
+pub struct QueryDurations {
+    /// Queries that finished within each of `QUERY_DURATION_BUCKETS`
+    pub buckets: [u64; QUERY_DURATION_BUCKETS.len()],
+    pub count: u64,
+    pub sum_secs: f64,
+}
+
+impl QueryDurations {
+    /// Count one query that took `secs`; buckets are cumulative, as Prometheus expects
+    pub(crate) fn record(&mut self, secs: f64) {
+        for (bucket, le) in self.buckets.iter_mut().zip(QUERY_DURATION_BUCKETS) {
+            if secs <= le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum_secs += secs;
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref QUERY_DURATIONS: Mutex<BTreeMap<&'static str, QueryDurations>> = Mutex::new(BTreeMap::new());
+}
+
+/// Durations of the timed queries, by query
+pub fn query_durations() -> BTreeMap<&'static str, QueryDurations> {
+    QUERY_DURATIONS.lock().unwrap().clone()
+}
+
+/// Times a query from creation until dropped, so early returns and errors are counted too
+struct QueryTimer {
+    query: &'static str,
+    started: Instant,
+}
+
+impl QueryTimer {
+    fn start(query: &'static str) -> Self {
+        QueryTimer { query, started: Instant::now() }
+    }
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        QUERY_DURATIONS.lock().unwrap().entry(self.query).or_default().record(elapsed.as_secs_f64());
+        let slow_ms = SLOW_QUERY_MS.load(Ordering::Relaxed);
+        if slow_ms > 0 && elapsed >= Duration::from_millis(slow_ms) {
+            warn!("Slow database query {} took {}ms", self.query, elapsed.as_millis());
+        }
+    }
+}
+
+// ---- END QUERY TIMING FUNCTIONS ----
+
+/// The database file, relative to the server's working directory
+pub const DB_PATH: &str = "sqlite.db";
+
+/// Open a pool on the database with the configured limits, without touching the schema
+pub async fn open_pool() -> Result<SqlitePool> {
+    let pool_config = PoolConfig::from_env().map_err(|e| anyhow!(e))?;
+    SLOW_QUERY_MS.store(pool_config.slow_query_ms, Ordering::Relaxed);
+    let options = SqliteConnectOptions::from_str(&format!("sqlite:{}", DB_PATH))?
+        .busy_timeout(Duration::from_secs(pool_config.busy_timeout_secs));
+    let pool = SqlitePoolOptions::new()
+        .max_connections(pool_config.max_connections)
+        .acquire_timeout(Duration::from_secs(pool_config.acquire_timeout_secs))
+        .connect_with(options)
+        .await?;
+    info!("Database pool: {} connections, {}s acquire timeout", pool_config.max_connections, pool_config.acquire_timeout_secs);
+    Ok(pool)
+}
+
+// Initialize the database connection pool and bring the schema up to date
 pub async fn init_db() -> Result<SqlitePool> {
     // Create or open the SQLite database file
-    let db_path = "sqlite.db";
+    let db_path = DB_PATH;
     
     // Check if the database file exists and create it if not
     if !Path::new(db_path).exists() {
@@ -42,51 +221,10 @@ pub async fn init_db() -> Result<SqlitePool> {
     }
     
     info!("Attempting to open database at: {}", db_path);
-    let pool = SqlitePool::connect(&format!("sqlite:{}", db_path)).await?;
-    
-    // Create tables if they don't exist
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS machines (
-            id TEXT PRIMARY KEY,
-            mac_address TEXT UNIQUE NOT NULL,
-            ip_address TEXT NOT NULL,
-            hostname TEXT,
-            os_choice TEXT,
-            os_installed TEXT,
-            status TEXT NOT NULL,
-            disks TEXT, -- JSON array of disk info
-            nameservers TEXT, -- JSON array of nameservers
-            bmc_credentials TEXT, -- JSON object of BMC credentials
-            created_at TEXT NOT NULL,
-            updated_at TEXT NOT NULL,
-            -- Add new hardware columns
-            cpu_model TEXT,
-            cpu_cores INTEGER,
-            total_ram_bytes INTEGER
-        )
-        "#,
-    )
-    .execute(&pool)
-    .await?;
-    
-    // Create admin_credentials table
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS admin_credentials (
-            id INTEGER PRIMARY KEY,
-            username TEXT NOT NULL,
-            password_hash TEXT NOT NULL,
-            created_at TEXT NOT NULL,
-            updated_at TEXT NOT NULL
-        )
-        "#,
-    )
-    .execute(&pool)
-    .await?;
+    let pool = open_pool().await?;
     
-    // Run database migrations
-    migrate_db(&pool).await?;
+    // Create the schema, or bring it up to date
+    crate::migrations::run(&pool).await?;
     
     // Store the pool globally
     if let Err(_) = DB_POOL.set(pool.clone()) {
@@ -99,14 +237,25 @@ pub async fn init_db() -> Result<SqlitePool> {
 
 // Get a reference to the database pool
 async fn get_pool() -> Result<&'static Pool<Sqlite>> {
+    #[cfg(feature = "chaos")]
+    crate::chaos::db_delay().await;
     DB_POOL.get().ok_or_else(|| anyhow!("Database pool not initialized"))
 }
 
+/// The form MACs are stored in: lower-case and colon-separated, so the UNIQUE index sees `AA-BB-..`
+/// and `aa:bb:..` as the same NIC
+pub fn normalize_mac(mac_address: &str) -> String {
+    mac_address.trim().to_lowercase().replace('-', ":")
+}
+
 // Register a new machine
+#[instrument(skip_all, fields(db.system = "sqlite", otel.kind = "client"))]
 pub async fn register_machine(req: &RegisterRequest) -> Result<Uuid> {
+    let _timer = QueryTimer::start("register_machine");
     let pool = get_pool().await?;
     let now = Utc::now();
     let now_str = now.to_rfc3339();
+    let mac_address = normalize_mac(&req.mac_address);
     
     // First check if a machine with this MAC address already exists
     let existing_machine = sqlx::query(
@@ -114,7 +263,7 @@ pub async fn register_machine(req: &RegisterRequest) -> Result<Uuid> {
         SELECT id FROM machines WHERE mac_address = ?
         "#,
     )
-    .bind(&req.mac_address)
+    .bind(&mac_address)
     .fetch_optional(pool)
     .await?;
     
@@ -131,7 +280,7 @@ pub async fn register_machine(req: &RegisterRequest) -> Result<Uuid> {
         sqlx::query(
             r#"
             UPDATE machines 
-            SET ip_address = ?, hostname = ?, disks = ?, nameservers = ?, 
+            SET ip_address = ?, hostname = COALESCE(?, hostname), disks = ?, nameservers = ?, 
                 cpu_model = ?, cpu_cores = ?, total_ram_bytes = ?, 
                 updated_at = ?
             WHERE id = ?
@@ -160,8 +309,12 @@ pub async fn register_machine(req: &RegisterRequest) -> Result<Uuid> {
     let disks_json = serde_json::to_string(&req.disks)?;
     let nameservers_json = serde_json::to_string(&req.nameservers)?;
     
-    // Always start with AwaitingAssignment status
-    let status_json = serde_json::to_string(&MachineStatus::AwaitingAssignment)?;
+    // Start with AwaitingAssignment status, or hold at the approval gate if required
+    let initial_status = match get_app_settings().await {
+        Ok(settings) if settings.require_approval => MachineStatus::PendingApproval,
+        _ => MachineStatus::AwaitingAssignment,
+    };
+    let status_json = serde_json::to_string(&initial_status)?;
     
     // Insert the new machine including hardware info
     let result = sqlx::query(
@@ -171,7 +324,7 @@ pub async fn register_machine(req: &RegisterRequest) -> Result<Uuid> {
         "#,
     )
     .bind(machine_id.to_string())
-    .bind(&req.mac_address)
+    .bind(&mac_address)
     .bind(&req.ip_address)
     .bind(&req.hostname)
     .bind(status_json)
@@ -188,6 +341,7 @@ pub async fn register_machine(req: &RegisterRequest) -> Result<Uuid> {
     match result {
         Ok(_) => {
             info!("Machine registered with ID: {}", machine_id);
+            record_transition(&machine_id, None, &initial_status, &StatusCause::Registration).await?;
             Ok(machine_id)
         }
         Err(e) => {
@@ -197,22 +351,20 @@ pub async fn register_machine(req: &RegisterRequest) -> Result<Uuid> {
     }
 }
 
+// Columns `map_row_to_machine_with_hardware` reads, for queries that list machines
+const MACHINE_COLUMNS: &str = "id, mac_address, ip_address, hostname, os_choice, os_installed, status, \
+    disks, nameservers, created_at, updated_at, bmc_credentials, installation_progress, installation_step, \
+    cpu_model, cpu_cores, total_ram_bytes, clock_skew_seconds";
+
 // Get all machines
+#[instrument(skip_all, fields(db.system = "sqlite", otel.kind = "client"))]
 pub async fn get_all_machines() -> Result<Vec<Machine>> {
+    let _timer = QueryTimer::start("get_all_machines");
     let pool = get_pool().await?;
     
-    let rows = sqlx::query(
-        r#"
-        SELECT id, mac_address, ip_address, hostname, os_choice, os_installed, status, 
-               disks, nameservers, created_at, updated_at, bmc_credentials, 
-               installation_progress, installation_step, 
-               -- Add new hardware columns
-               cpu_model, cpu_cores, total_ram_bytes 
-        FROM machines
-        "#,
-    )
-    .fetch_all(pool)
-    .await?;
+    let rows = sqlx::query(&format!("SELECT {} FROM machines", MACHINE_COLUMNS))
+        .fetch_all(pool)
+        .await?;
     
     let mut machines = Vec::new();
     for row in rows {
@@ -223,8 +375,47 @@ pub async fn get_all_machines() -> Result<Vec<Machine>> {
     Ok(machines)
 }
 
+// Machines updated at or after `since`, a `changes::Cursor::lower_bound`, or all of them
+#[instrument(skip_all, fields(db.system = "sqlite", otel.kind = "client"))]
+pub async fn get_machines_updated_since(since: Option<&str>) -> Result<Vec<Machine>> {
+    let Some(since) = since else {
+        return get_all_machines().await;
+    };
+    let _timer = QueryTimer::start("get_machines_updated_since");
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query(&format!("SELECT {} FROM machines WHERE updated_at >= ?", MACHINE_COLUMNS))
+        .bind(since)
+        .fetch_all(pool)
+        .await?;
+    
+    rows.into_iter().map(map_row_to_machine_with_hardware).collect()
+}
+
+// Machines matching a search query, see `search::Search`
+#[instrument(skip_all, fields(db.system = "sqlite", otel.kind = "client"))]
+pub async fn search_machines(search: &crate::search::Search) -> Result<Vec<Machine>> {
+    let _timer = QueryTimer::start("search_machines");
+    find_machines(get_pool().await?, search).await
+}
+
+pub(crate) async fn find_machines(pool: &Pool<Sqlite>, search: &crate::search::Search) -> Result<Vec<Machine>> {
+    let filter = search.to_filter();
+    
+    let sql = format!("SELECT {} FROM machines WHERE {}", MACHINE_COLUMNS, filter.sql);
+    let mut query = sqlx::query(&sql);
+    for value in &filter.binds {
+        query = query.bind(value.as_str());
+    }
+    let rows = query.fetch_all(pool).await?;
+    
+    rows.into_iter().map(map_row_to_machine_with_hardware).collect()
+}
+
 // Get machine by ID
+#[instrument(skip_all, fields(db.system = "sqlite", otel.kind = "client"))]
 pub async fn get_machine_by_id(id: &Uuid) -> Result<Option<Machine>> {
+    let _timer = QueryTimer::start("get_machine_by_id");
     let pool = get_pool().await?;
     
     let result = sqlx::query(
@@ -233,7 +424,8 @@ pub async fn get_machine_by_id(id: &Uuid) -> Result<Option<Machine>> {
                disks, nameservers, created_at, updated_at, bmc_credentials, 
                installation_progress, installation_step, 
                -- Add new hardware columns
-               cpu_model, cpu_cores, total_ram_bytes 
+               cpu_model, cpu_cores, total_ram_bytes, 
+               clock_skew_seconds 
         FROM machines 
         WHERE id = ?
         "#,
@@ -251,7 +443,9 @@ pub async fn get_machine_by_id(id: &Uuid) -> Result<Option<Machine>> {
 }
 
 // Get machine by MAC address
+#[instrument(skip_all, fields(db.system = "sqlite", otel.kind = "client"))]
 pub async fn get_machine_by_mac(mac_address: &str) -> Result<Option<Machine>> {
+    let _timer = QueryTimer::start("get_machine_by_mac");
     let pool = get_pool().await?;
     
     let result = sqlx::query(
@@ -260,12 +454,13 @@ pub async fn get_machine_by_mac(mac_address: &str) -> Result<Option<Machine>> {
                disks, nameservers, created_at, updated_at, bmc_credentials, 
                installation_progress, installation_step, 
                -- Add new hardware columns
-               cpu_model, cpu_cores, total_ram_bytes 
+               cpu_model, cpu_cores, total_ram_bytes, 
+               clock_skew_seconds 
         FROM machines 
         WHERE mac_address = ?
         "#,
     )
-    .bind(mac_address)
+    .bind(normalize_mac(mac_address))
     .fetch_optional(pool)
     .await?;
     
@@ -278,7 +473,9 @@ pub async fn get_machine_by_mac(mac_address: &str) -> Result<Option<Machine>> {
 }
 
 // Get machine by IP address
+#[instrument(skip_all, fields(db.system = "sqlite", otel.kind = "client"))]
 pub async fn get_machine_by_ip(ip_address: &str) -> Result<Option<Machine>> {
+    let _timer = QueryTimer::start("get_machine_by_ip");
     let pool = get_pool().await?;
     
     let result = sqlx::query(
@@ -287,7 +484,8 @@ pub async fn get_machine_by_ip(ip_address: &str) -> Result<Option<Machine>> {
                disks, nameservers, created_at, updated_at, bmc_credentials, 
                installation_progress, installation_step, 
                -- Add new hardware columns
-               cpu_model, cpu_cores, total_ram_bytes 
+               cpu_model, cpu_cores, total_ram_bytes, 
+               clock_skew_seconds 
         FROM machines 
         WHERE ip_address = ?
         "#,
@@ -305,65 +503,89 @@ pub async fn get_machine_by_ip(ip_address: &str) -> Result<Option<Machine>> {
 }
 
 // Assign OS to a machine
-pub async fn assign_os(id: &Uuid, os_choice: &str) -> Result<bool> {
+#[instrument(skip_all, fields(db.system = "sqlite", otel.kind = "client"))]
+pub async fn assign_os(id: &Uuid, os_choice: &str, cause: &StatusCause) -> Result<bool> {
+    let _timer = QueryTimer::start("assign_os");
     let pool = get_pool().await?;
     let now = Utc::now();
     let now_str = now.to_rfc3339();
     
+    let Some(checked) = check_transition(id, MachineStatus::InstallingOS).await? else {
+        info!("No machine found with ID {} to assign OS", id);
+        return Ok(false);
+    };
+    
+    let mut tx = pool.begin().await?;
+    // The machine goes quiet while it reboots into the installer, so the heartbeats of the old
+    // OS must not get it marked Offline, nor a later heartbeat restore its status from before
     let result = sqlx::query(
         r#"
         UPDATE machines 
-        SET os_choice = ?, status = ?, updated_at = ? 
-        WHERE id = ?
+        SET os_choice = ?, status = ?, updated_at = ?, last_heartbeat = NULL, status_before_offline = NULL
+        WHERE id = ? AND status = ?
         "#,
     )
     .bind(os_choice)
     .bind(serde_json::to_string(&MachineStatus::InstallingOS)?)
     .bind(&now_str)
     .bind(id.to_string())
-    .execute(pool)
+    .bind(&checked.stored)
+    .execute(&mut *tx)
     .await?;
-    
-    let success = result.rows_affected() > 0;
-    if success {
-        info!("OS assigned to machine {}: {}", id, os_choice);
-    } else {
-        info!("No machine found with ID {} to assign OS", id);
+    if result.rows_affected() == 0 {
+        return Err(StatusConflict(*id).into());
     }
+    let recorded = insert_transition(&mut *tx, id, Some(&checked.transition.from), &checked.transition.to, cause).await?;
+    tx.commit().await?;
     
-    Ok(success)
+    info!("OS assigned to machine {}: {}", id, os_choice);
+    if recorded {
+        notify_status_changed(id);
+    }
+    Ok(true)
 }
 
 // Update machine status
-pub async fn update_status(id: &Uuid, status: MachineStatus) -> Result<bool> {
+#[instrument(skip_all, fields(db.system = "sqlite", otel.kind = "client"))]
+pub async fn update_status(id: &Uuid, status: MachineStatus, cause: &StatusCause) -> Result<bool> {
+    let _timer = QueryTimer::start("update_status");
     let pool = get_pool().await?;
     let now = Utc::now();
     let now_str = now.to_rfc3339();
     
+    let Some(checked) = check_transition(id, status.clone()).await? else {
+        info!("No machine found with ID {} to update status", id);
+        return Ok(false);
+    };
+    
     // Store the serialized enum value directly
     let status_json = serde_json::to_string(&status)?;
     
+    let mut tx = pool.begin().await?;
     let result = sqlx::query(
         r#"
         UPDATE machines 
-        SET status = ?, updated_at = ? 
-        WHERE id = ?
+        SET status = ?, status_before_offline = NULL, updated_at = ? 
+        WHERE id = ? AND status = ?
         "#,
     )
     .bind(status_json)
     .bind(&now_str)
     .bind(id.to_string())
-    .execute(pool)
+    .bind(&checked.stored)
+    .execute(&mut *tx)
     .await?;
-    
-    let success = result.rows_affected() > 0;
-    if success {
-        info!("Status updated for machine {}: {:?}", id, status);
-    } else {
-        info!("No machine found with ID {} to update status", id);
+    if result.rows_affected() == 0 {
+        return Err(StatusConflict(*id).into());
     }
+    let recorded = insert_transition(&mut *tx, id, Some(&checked.transition.from), &checked.transition.to, cause).await?;
+    tx.commit().await?;
     
-    Ok(success)
+    info!("Status updated for machine {}: {:?}", id, status);
+    if recorded {
+        notify_status_changed(id);
+    }
+    Ok(true)
 }
 
 // Update machine hostname
@@ -490,6 +712,7 @@ pub async fn update_mac_address(id: &Uuid, mac_address: &str) -> Result<bool> {
     let pool = get_pool().await?;
     let now = Utc::now();
     let now_str = now.to_rfc3339();
+    let mac_address = normalize_mac(mac_address);
     
     // First check if a machine with this MAC address already exists
     let existing_machine = sqlx::query(
@@ -497,7 +720,7 @@ pub async fn update_mac_address(id: &Uuid, mac_address: &str) -> Result<bool> {
         SELECT id FROM machines WHERE mac_address = ?
         "#,
     )
-    .bind(mac_address)
+    .bind(&mac_address)
     .fetch_optional(pool)
     .await?;
     
@@ -516,7 +739,7 @@ pub async fn update_mac_address(id: &Uuid, mac_address: &str) -> Result<bool> {
         WHERE id = ?
         "#,
     )
-    .bind(mac_address)
+    .bind(&mac_address)
     .bind(&now_str)
     .bind(id.to_string())
     .execute(pool)
@@ -573,6 +796,8 @@ fn parse_status(status_str: &str) -> MachineStatus {
         "InstallingOS" => MachineStatus::InstallingOS,
         "Ready" => MachineStatus::Ready,
         "Offline" => MachineStatus::Offline,
+        "PendingApproval" => MachineStatus::PendingApproval,
+        "Rejected" => MachineStatus::Rejected,
         s if s.starts_with("Error: ") => {
             let message = s.trim_start_matches("Error: ").to_string();
             MachineStatus::Error(message)
@@ -594,400 +819,222 @@ fn parse_datetime(datetime_str: &str) -> chrono::DateTime<Utc> {
         .unwrap_or(dt)
 }
 
-// Apply database migrations
-async fn migrate_db(pool: &Pool<Sqlite>) -> Result<()> {
-    // Check if os_installed column exists
-    let result = sqlx::query(
+
+/// One entry in a machine's status history
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct StatusChange {
+    pub id: i64,
+    /// None for the status the machine was registered with
+    pub from: Option<MachineStatus>,
+    pub to: MachineStatus,
+    /// None for changes recorded before causes were
+    pub cause: Option<StatusCause>,
+    pub changed_at: chrono::DateTime<Utc>,
+}
+
+/// A deleted machine, kept for history
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ArchivedMachine {
+    pub id: i64,
+    pub machine: Machine,
+    pub tags: Vec<String>,
+    pub deleted_by: String,
+    pub deleted_at: chrono::DateTime<Utc>,
+}
+
+// Move a machine into archived_machines, deleting the live record in the same transaction
+pub async fn archive_machine(id: &Uuid, deleted_by: &str) -> Result<bool> {
+    let Some(machine) = get_machine_by_id(id).await? else {
+        return Ok(false);
+    };
+    let tags = get_machine_tags(id).await?;
+    archive(get_pool().await?, &machine, &tags, deleted_by).await
+}
+
+pub(crate) async fn archive(pool: &Pool<Sqlite>, machine: &Machine, tags: &[String], deleted_by: &str) -> Result<bool> {
+    let id = &machine.id;
+    let mut tx = pool.begin().await?;
+    sqlx::query(
         r#"
-        SELECT COUNT(*) AS count FROM pragma_table_info('machines') WHERE name = 'os_installed'
+        INSERT INTO archived_machines (machine_id, record, tags, deleted_by, deleted_at)
+        VALUES (?, ?, ?, ?, ?)
         "#,
     )
-    .fetch_one(pool)
+    .bind(id.to_string())
+    .bind(serde_json::to_string(machine)?)
+    .bind(serde_json::to_string(tags)?)
+    .bind(deleted_by)
+    .bind(Utc::now().to_rfc3339())
+    .execute(&mut *tx)
     .await?;
     
-    let column_exists: i64 = result.get(0);
+    sqlx::query("DELETE FROM machine_tpm WHERE machine_id = ?")
+        .bind(id.to_string())
+        .execute(&mut *tx)
+        .await?;
     
-    // Add os_installed column if it doesn't exist
-    if column_exists == 0 {
-        info!("Adding os_installed column to machines table");
-        sqlx::query(
-            r#"
-            ALTER TABLE machines ADD COLUMN os_installed TEXT
-            "#,
-        )
-        .execute(pool)
+    sqlx::query("DELETE FROM machine_field_values WHERE machine_id = ?")
+        .bind(id.to_string())
+        .execute(&mut *tx)
         .await?;
-        
-        // If we have ExistingOS machines, update their os_installed field
-        let existing_os_machines = sqlx::query(
-            r#"
-            SELECT id, status FROM machines WHERE status LIKE 'ExistingOS:%' OR status = 'Existing OS'
-            "#,
-        )
-        .fetch_all(pool)
+    
+    sqlx::query("DELETE FROM machine_placements WHERE machine_id = ?")
+        .bind(id.to_string())
+        .execute(&mut *tx)
         .await?;
-        
-        let now = Utc::now();
-        let now_str = now.to_rfc3339();
-        
-        for row in existing_os_machines {
-            let id: String = row.get(0);
-            let status_str: String = row.get(1);
-            let os = if status_str.starts_with("ExistingOS: ") {
-                status_str.trim_start_matches("ExistingOS: ").to_string()
-            } else {
-                "Unknown".to_string() // Fallback for "Existing OS" format
-            };
-            
-            info!("Setting os_installed for machine {} to {}", id, os);
-            sqlx::query(
-                r#"
-                UPDATE machines 
-                SET os_installed = ?, updated_at = ?, status = ? 
-                WHERE id = ?
-                "#,
-            )
-            .bind(os)
-            .bind(&now_str)
-            .bind("Existing OS") // Update to the new format
-            .bind(id)
-            .execute(pool)
-            .await?;
-        }
-    }
     
-    // Check if bmc_credentials column exists
-    let result = sqlx::query(
-        r#"
-        SELECT COUNT(*) AS count FROM pragma_table_info('machines') WHERE name = 'bmc_credentials'
-        "#,
-    )
-    .fetch_one(pool)
-    .await?;
+    sqlx::query("DELETE FROM machine_claims WHERE machine_id = ?")
+        .bind(id.to_string())
+        .execute(&mut *tx)
+        .await?;
     
-    let column_exists: i64 = result.get(0);
+    sqlx::query("DELETE FROM bmc_jobs WHERE machine_id = ?")
+        .bind(id.to_string())
+        .execute(&mut *tx)
+        .await?;
     
-    // Add bmc_credentials column if it doesn't exist
-    if column_exists == 0 {
-        info!("Adding bmc_credentials column to machines table");
-        sqlx::query(
-            r#"
-            ALTER TABLE machines ADD COLUMN bmc_credentials TEXT
-            "#,
-        )
-        .execute(pool)
+    sqlx::query("DELETE FROM render_tokens WHERE machine_id = ?")
+        .bind(id.to_string())
+        .execute(&mut *tx)
+        .await?;
+    
+    sqlx::query("DELETE FROM root_passwords WHERE machine_id = ?")
+        .bind(id.to_string())
+        .execute(&mut *tx)
+        .await?;
+    
+    let result = sqlx::query("DELETE FROM machines WHERE id = ?")
+        .bind(id.to_string())
+        .execute(&mut *tx)
         .await?;
+    if result.rows_affected() == 0 {
+        // Deleted in the meantime; dropping the transaction discards the archive record
+        return Ok(false);
     }
+    tx.commit().await?;
     
-    // Check if installation_progress column exists
-    let result = sqlx::query(
-        r#"
-        SELECT COUNT(*) AS count FROM pragma_table_info('machines') WHERE name = 'installation_progress'
-        "#,
-    )
-    .fetch_one(pool)
-    .await?;
+    info!("Machine {} archived and deleted by {}", id, deleted_by);
+    Ok(true)
+}
+
+fn archived_machine_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<ArchivedMachine> {
+    Ok(ArchivedMachine {
+        id: row.get("id"),
+        machine: serde_json::from_str(&row.get::<String, _>("record"))?,
+        tags: serde_json::from_str(&row.get::<String, _>("tags"))?,
+        deleted_by: row.get("deleted_by"),
+        deleted_at: parse_datetime(&row.get::<String, _>("deleted_at")),
+    })
+}
+
+// List archived machines, most recently deleted first
+pub async fn get_archived_machines() -> Result<Vec<ArchivedMachine>> {
+    let pool = get_pool().await?;
     
-    let column_exists: i64 = result.get(0);
+    let rows = sqlx::query("SELECT * FROM archived_machines ORDER BY id DESC")
+        .fetch_all(pool)
+        .await?;
     
-    // Add installation_progress column if it doesn't exist
-    if column_exists == 0 {
-        info!("Adding installation_progress column to machines table");
-        sqlx::query(
-            r#"
-            ALTER TABLE machines ADD COLUMN installation_progress INTEGER DEFAULT 0
-            "#,
-        )
-        .execute(pool)
+    rows.iter().map(archived_machine_from_row).collect()
+}
+
+// Machines deleted at or after `since`, a `changes::Cursor::lower_bound`
+pub async fn get_archived_machines_since(since: &str) -> Result<Vec<ArchivedMachine>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM archived_machines WHERE deleted_at >= ?")
+        .bind(since)
+        .fetch_all(pool)
         .await?;
-    }
     
-    // Check if installation_step column exists
-    let result = sqlx::query(
+    rows.iter().map(archived_machine_from_row).collect()
+}
+
+// Get the latest archived record of a machine
+pub async fn get_archived_machine(machine_id: &Uuid) -> Result<Option<ArchivedMachine>> {
+    archived_machine(get_pool().await?, machine_id).await
+}
+
+pub(crate) async fn archived_machine(pool: &Pool<Sqlite>, machine_id: &Uuid) -> Result<Option<ArchivedMachine>> {
+    let row = sqlx::query("SELECT * FROM archived_machines WHERE machine_id = ? ORDER BY id DESC LIMIT 1")
+        .bind(machine_id.to_string())
+        .fetch_optional(pool)
+        .await?;
+    
+    row.as_ref().map(archived_machine_from_row).transpose()
+}
+
+// Get the bootstrap admin credentials (the first account created) from database
+pub async fn get_admin_credentials() -> Result<Option<Credentials>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query(
         r#"
-        SELECT COUNT(*) AS count FROM pragma_table_info('machines') WHERE name = 'installation_step'
+        SELECT username, password_hash FROM users ORDER BY id ASC LIMIT 1
         "#,
     )
-    .fetch_one(pool)
+    .fetch_optional(pool)
     .await?;
     
-    let column_exists: i64 = result.get(0);
-    
-    // Add installation_step column if it doesn't exist
-    if column_exists == 0 {
-        info!("Adding installation_step column to machines table");
-        sqlx::query(
-            r#"
-            ALTER TABLE machines ADD COLUMN installation_step TEXT
-            "#,
-        )
-        .execute(pool)
-        .await?;
+    if let Some(row) = row {
+        let username: String = row.get(0);
+        let password_hash: String = row.get(1);
+        
+        Ok(Some(Credentials {
+            username,
+            password: None,
+            password_hash,
+        }))
+    } else {
+        Ok(None)
     }
+}
+
+// Save admin credentials to database, creating the account if it doesn't exist.
+// Saving also re-enables the account and makes it an administrator, so resetting the admin
+// password always restores access.
+pub async fn save_admin_credentials(credentials: &Credentials) -> Result<()> {
+    // Make sure the database pool is initialized
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
     
-    // Check if last_deployment_duration column exists
     let result = sqlx::query(
         r#"
-        SELECT COUNT(*) AS count FROM pragma_table_info('machines') WHERE name = 'last_deployment_duration'
+        INSERT INTO users (username, password_hash, disabled, is_admin, created_at, updated_at)
+        VALUES (?, ?, 0, 1, ?, ?)
+        ON CONFLICT(username) DO UPDATE SET
+            password_hash = excluded.password_hash,
+            disabled = 0,
+            is_admin = 1,
+            updated_at = excluded.updated_at
         "#,
     )
-    .fetch_one(pool)
+    .bind(&credentials.username)
+    .bind(&credentials.password_hash)
+    .bind(&now_str)
+    .bind(&now_str)
+    .execute(pool)
     .await?;
     
-    let duration_column_exists: i64 = result.get(0);
-    
-    // Add last_deployment_duration column if it doesn't exist
-    if duration_column_exists == 0 {
-        info!("Adding last_deployment_duration column to machines table");
-        sqlx::query(
-            r#"
-            ALTER TABLE machines ADD COLUMN last_deployment_duration INTEGER
-            "#,
-        )
-        .execute(pool)
-        .await?;
-    }
-    
-    // Check if default_os column exists in app_settings table
-    let result = sqlx::query(
-        r#"
-        SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name='app_settings'
-        "#,
-    )
-    .fetch_one(pool)
-    .await?;
-    
-    let table_exists: i64 = result.get(0);
-    
-    if table_exists > 0 {
-        // Table exists, check for the column
-        let result = sqlx::query(
-            r#"
-            SELECT COUNT(*) AS count FROM pragma_table_info('app_settings') WHERE name = 'default_os'
-            "#,
-        )
-        .fetch_one(pool)
-        .await?;
-        
-        let column_exists: i64 = result.get(0);
-        
-        // Add default_os column if it doesn't exist
-        if column_exists == 0 {
-            info!("Adding default_os column to app_settings table");
-            sqlx::query(
-                r#"
-                ALTER TABLE app_settings ADD COLUMN default_os TEXT
-                "#,
-            )
-            .execute(pool)
-            .await?;
-        }
-    }
-    
-    // Check if setup_completed column exists in app_settings table
-    let result = sqlx::query(
-        r#"
-        SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name='app_settings'
-        "#,
-    )
-    .fetch_one(pool)
-    .await?;
-    
-    let table_exists: i64 = result.get(0);
-    
-    if table_exists > 0 {
-        // Check if setup_completed column exists
-        let result = sqlx::query(
-            r#"
-            SELECT COUNT(*) AS count FROM pragma_table_info('app_settings') WHERE name = 'setup_completed'
-            "#,
-        )
-        .fetch_one(pool)
-        .await?;
-        
-        let column_exists: i64 = result.get(0);
-        
-        // Add setup_completed column if it doesn't exist
-        if column_exists == 0 {
-            info!("Adding setup_completed column to app_settings table");
-            sqlx::query(
-                r#"
-                ALTER TABLE app_settings ADD COLUMN setup_completed BOOLEAN NOT NULL DEFAULT 0
-                "#,
-            )
-            .execute(pool)
-            .await?;
-        }
-    }
-    
-    // Add cpu_model column if it doesn't exist
-    let result = sqlx::query("SELECT COUNT(*) FROM pragma_table_info('machines') WHERE name = 'cpu_model'").fetch_one(pool).await?;
-    let column_exists: i64 = result.get(0);
-    if column_exists == 0 {
-        info!("Adding cpu_model column to machines table");
-        sqlx::query("ALTER TABLE machines ADD COLUMN cpu_model TEXT").execute(pool).await?;
-    }
-
-    // Add cpu_cores column if it doesn't exist
-    let result = sqlx::query("SELECT COUNT(*) FROM pragma_table_info('machines') WHERE name = 'cpu_cores'").fetch_one(pool).await?;
-    let column_exists: i64 = result.get(0);
-    if column_exists == 0 {
-        info!("Adding cpu_cores column to machines table");
-        sqlx::query("ALTER TABLE machines ADD COLUMN cpu_cores INTEGER").execute(pool).await?;
-    }
-
-    // Add total_ram_bytes column if it doesn't exist
-    let result = sqlx::query("SELECT COUNT(*) FROM pragma_table_info('machines') WHERE name = 'total_ram_bytes'").fetch_one(pool).await?;
-    let column_exists: i64 = result.get(0);
-    if column_exists == 0 {
-        info!("Adding total_ram_bytes column to machines table");
-        sqlx::query("ALTER TABLE machines ADD COLUMN total_ram_bytes INTEGER").execute(pool).await?;
-    }
+    if result.rows_affected() == 0 {
+        error!("Failed to save admin credentials for user: {}", credentials.username);
+        return Err(anyhow!("Failed to save admin credentials"));
+    }
     
+    info!("Saved admin credentials for user: {}", credentials.username);
     Ok(())
 }
 
-// Delete a machine by ID
-pub async fn delete_machine(id: &Uuid) -> Result<bool> {
-    let pool = get_pool().await?;
-    
-    let result = sqlx::query(
-        r#"
-        DELETE FROM machines 
-        WHERE id = ?
-        "#,
-    )
-    .bind(id.to_string())
-    .execute(pool)
-    .await?;
-    
-    let success = result.rows_affected() > 0;
-    if success {
-        info!("Machine deleted from database: {}", id);
-    } else {
-        info!("No machine found with ID {} to delete", id);
-    }
-    
-    Ok(success)
-}
-
-// Get admin credentials from database
-pub async fn get_admin_credentials() -> Result<Option<Credentials>> {
-    let pool = get_pool().await?;
-    
-    let row = sqlx::query(
-        r#"
-        SELECT username, password_hash FROM admin_credentials ORDER BY id DESC LIMIT 1
-        "#,
-    )
-    .fetch_optional(pool)
-    .await?;
-    
-    if let Some(row) = row {
-        let username: String = row.get(0);
-        let password_hash: String = row.get(1);
-        
-        Ok(Some(Credentials {
-            username,
-            password: None,
-            password_hash,
-        }))
-    } else {
-        Ok(None)
-    }
-}
-
-// Save admin credentials to database
-pub async fn save_admin_credentials(credentials: &Credentials) -> Result<()> {
-    // Make sure the database pool is initialized
-    let pool = get_pool().await?;
-    let now = Utc::now();
-    let now_str = now.to_rfc3339();
-    
-    // Use a transaction to ensure atomicity
-    let mut tx = pool.begin().await?;
-    
-    // Check if credentials already exist
-    let existing = sqlx::query("SELECT COUNT(*) FROM admin_credentials")
-        .fetch_one(&mut *tx)
-        .await?;
-    
-    let count: i64 = existing.get(0);
-    
-    if count > 0 {
-        // Update existing credentials
-        sqlx::query(
-            r#"
-            UPDATE admin_credentials 
-            SET username = ?, password_hash = ?, updated_at = ?
-            WHERE id = (SELECT id FROM admin_credentials ORDER BY id DESC LIMIT 1)
-            "#,
-        )
-        .bind(&credentials.username)
-        .bind(&credentials.password_hash)
-        .bind(&now_str)
-        .execute(&mut *tx)
-        .await?;
-        
-        info!("Updated existing admin credentials for user: {}", credentials.username);
-    } else {
-        // Insert new credentials
-        sqlx::query(
-            r#"
-            INSERT INTO admin_credentials (username, password_hash, created_at, updated_at)
-            VALUES (?, ?, ?, ?)
-            "#,
-        )
-        .bind(&credentials.username)
-        .bind(&credentials.password_hash)
-        .bind(&now_str)
-        .bind(&now_str)
-        .execute(&mut *tx)
-        .await?;
-        
-        info!("Created new admin credentials for user: {}", credentials.username);
-    }
-    
-    // Commit the transaction
-    tx.commit().await?;
-    
-    // Verify the save worked by retrieving the credentials again
-    match get_admin_credentials().await {
-        Ok(Some(_)) => {
-            info!("Successfully verified admin credentials were saved");
-            Ok(())
-        },
-        _ => {
-            error!("Failed to verify admin credentials were saved - this is a critical error!");
-            Err(anyhow!("Failed to verify admin credentials were saved"))
-        }
-    }
-}
-
 // Get application settings from database
 pub async fn get_app_settings() -> Result<Settings> {
+    let _timer = QueryTimer::start("get_app_settings");
     let pool = get_pool().await?;
     
-    // First, make sure the settings table exists
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS app_settings (
-            id INTEGER PRIMARY KEY CHECK (id = 1), -- Only one settings record allowed
-            require_login BOOLEAN NOT NULL,
-            default_os TEXT,
-            setup_completed BOOLEAN NOT NULL DEFAULT 0,
-            created_at TEXT NOT NULL,
-            updated_at TEXT NOT NULL
-        )
-        "#,
-    )
-    .execute(pool)
-    .await?;
-    
     // Try to get settings
     let row = sqlx::query(
         r#"
-        SELECT require_login, default_os, setup_completed FROM app_settings WHERE id = 1
+        SELECT require_login, default_os, setup_completed, hostname_policy, require_approval, boot_filter, generate_root_passwords, block_eol_assignments, default_theme, require_boot_auth, boot_auth_exempt_subnets, status_board FROM app_settings WHERE id = 1
         "#,
     )
     .fetch_optional(pool)
@@ -1001,6 +1048,23 @@ pub async fn get_app_settings() -> Result<Settings> {
         settings.require_login = row.get::<bool, _>("require_login");
         settings.default_os = row.get::<Option<String>, _>("default_os");
         settings.setup_completed = row.get::<bool, _>("setup_completed");
+        settings.hostname_policy = row.get::<Option<String>, _>("hostname_policy")
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+        settings.require_approval = row.get::<bool, _>("require_approval");
+        settings.boot_filter = row.get::<Option<String>, _>("boot_filter")
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+        settings.generate_root_passwords = row.get::<bool, _>("generate_root_passwords");
+        settings.block_eol_assignments = row.get::<bool, _>("block_eol_assignments");
+        settings.default_theme = row.get::<Option<String>, _>("default_theme");
+        settings.require_boot_auth = row.get::<bool, _>("require_boot_auth");
+        settings.boot_auth_exempt_subnets = row.get::<Option<String>, _>("boot_auth_exempt_subnets")
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+        settings.status_board = row.get::<Option<String>, _>("status_board")
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
         
         // Load admin credentials separately to populate those fields in the default settings struct
         // Note: This might introduce a small inconsistency if DB ops fail between here and AppState creation,
@@ -1043,18 +1107,36 @@ pub async fn save_app_settings(settings: &Settings) -> Result<()> {
     // Update existing settings or insert if they don't exist (upsert pattern)
     sqlx::query(
         r#"
-        INSERT INTO app_settings (id, require_login, default_os, setup_completed, created_at, updated_at)
-        VALUES (1, ?, ?, ?, ?, ?)
+        INSERT INTO app_settings (id, require_login, default_os, setup_completed, hostname_policy, require_approval, boot_filter, generate_root_passwords, block_eol_assignments, default_theme, require_boot_auth, boot_auth_exempt_subnets, status_board, created_at, updated_at)
+        VALUES (1, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         ON CONFLICT (id) DO UPDATE SET
         require_login = excluded.require_login,
         default_os = excluded.default_os,
         setup_completed = excluded.setup_completed,
+        hostname_policy = excluded.hostname_policy,
+        require_approval = excluded.require_approval,
+        boot_filter = excluded.boot_filter,
+        generate_root_passwords = excluded.generate_root_passwords,
+        block_eol_assignments = excluded.block_eol_assignments,
+        default_theme = excluded.default_theme,
+        require_boot_auth = excluded.require_boot_auth,
+        boot_auth_exempt_subnets = excluded.boot_auth_exempt_subnets,
+        status_board = excluded.status_board,
         updated_at = excluded.updated_at
         "#,
     )
     .bind(settings.require_login)
     .bind(&settings.default_os)
     .bind(settings.setup_completed)
+    .bind(serde_json::to_string(&settings.hostname_policy)?)
+    .bind(settings.require_approval)
+    .bind(serde_json::to_string(&settings.boot_filter)?)
+    .bind(settings.generate_root_passwords)
+    .bind(settings.block_eol_assignments)
+    .bind(&settings.default_theme)
+    .bind(settings.require_boot_auth)
+    .bind(serde_json::to_string(&settings.boot_auth_exempt_subnets)?)
+    .bind(serde_json::to_string(&settings.status_board)?)
     .bind(&now_str)
     .bind(&now_str)
     .execute(pool)
@@ -1114,9 +1196,15 @@ pub async fn update_installation_progress(id: &Uuid, progress: u8, step: Option<
 }
 
 // Update machine in the database
-pub async fn update_machine(machine: &Machine) -> Result<bool> {
+#[instrument(skip_all, fields(db.system = "sqlite", otel.kind = "client"))]
+pub async fn update_machine(machine: &Machine, cause: &StatusCause) -> Result<bool> {
+    let _timer = QueryTimer::start("update_machine");
     let pool = get_pool().await?;
     
+    let Some(checked) = check_transition(&machine.id, machine.status.clone()).await? else {
+        return Ok(false);
+    };
+    
     // Serialize the status enum to JSON for storage
     let status_json = serde_json::to_string(&machine.status)?;
     let nameservers_json = serde_json::to_string(&machine.nameservers)?;
@@ -1142,10 +1230,11 @@ pub async fn update_machine(machine: &Machine) -> Result<bool> {
             cpu_model = $10,
             cpu_cores = $11,
             total_ram_bytes = $12
-        WHERE id = $13
+        WHERE id = $13 AND status = $14
     ";
     
     // Execute the update query with explicit type annotation for SqlitePool
+    let mut tx = pool.begin().await?;
     let result = sqlx::query::<sqlx::Sqlite>(query)
         .bind(machine.hostname.as_deref())
         .bind(&machine.ip_address)
@@ -1160,16 +1249,25 @@ pub async fn update_machine(machine: &Machine) -> Result<bool> {
         .bind(machine.cpu_model.as_deref())
         .bind(machine.cpu_cores.map(|c| c as i64)) // Map Option<u32> to Option<i64>
         .bind(machine.total_ram_bytes.map(|r| r as i64)) // Map Option<u64> to Option<i64>
-        // Bind ID last
+        // Bind ID and the status the transition was checked against last
         .bind(machine.id)
-        .execute(pool)
+        .bind(&checked.stored)
+        .execute(&mut *tx)
         .await;
         
     match result {
         Ok(result) => {
             let rows_affected = result.rows_affected();
             info!("Database update for machine {} affected {} rows", machine.id, rows_affected);
-            Ok(rows_affected > 0)
+            if rows_affected == 0 {
+                return Err(StatusConflict(machine.id).into());
+            }
+            let recorded = insert_transition(&mut *tx, &machine.id, Some(&checked.transition.from), &checked.transition.to, cause).await?;
+            tx.commit().await?;
+            if recorded {
+                notify_status_changed(&machine.id);
+            }
+            Ok(true)
         },
         Err(e) => {
             error!("Failed to update machine in database: {}", e);
@@ -1178,60 +1276,247 @@ pub async fn update_machine(machine: &Machine) -> Result<bool> {
     }
 }
 
-// Add a new type for template timing data
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub struct TemplateTiming {
-    pub template_name: String,
-    pub action_name: String,
-    pub durations: Vec<u64>,
+// Record the clock skew last reported by a machine's agent
+pub async fn update_clock_skew(id: &Uuid, skew_seconds: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    let result = sqlx::query(
+        r#"
+        UPDATE machines 
+        SET clock_skew_seconds = ?, updated_at = ? 
+        WHERE id = ?
+        "#,
+    )
+    .bind(skew_seconds)
+    .bind(&now_str)
+    .bind(id.to_string())
+    .execute(pool)
+    .await?;
+    
+    let success = result.rows_affected() > 0;
+    if success {
+        info!("Clock skew for machine {} recorded as {}s", id, skew_seconds);
+    } else {
+        info!("No machine found with ID {} to record clock skew", id);
+    }
+    
+    Ok(success)
 }
 
-// Save template timing data to database
-pub async fn save_template_timing(template_name: &str, action_name: &str, durations: &[u64]) -> Result<bool> {
-    const MAX_TIMING_HISTORY: usize = 50; // Keep only the last 50 runs of timing data
+// Get the detected architecture and firmware of a machine, if known
+pub async fn get_boot_target(id: &Uuid) -> Result<Option<BootTarget>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT boot_target FROM machines WHERE id = ?")
+        .bind(id.to_string())
+        .fetch_optional(pool)
+        .await?;
     
+    Ok(row
+        .and_then(|row| row.get::<Option<String>, _>("boot_target"))
+        .and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+// Record the detected architecture and firmware of a machine
+pub async fn update_boot_target(id: &Uuid, target: &BootTarget) -> Result<bool> {
     let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
     
-    info!("Saving timing data for template {}, action {}", template_name, action_name);
+    let result = sqlx::query(
+        r#"
+        UPDATE machines 
+        SET boot_target = ?, updated_at = ? 
+        WHERE id = ?
+        "#,
+    )
+    .bind(serde_json::to_string(target)?)
+    .bind(&now_str)
+    .bind(id.to_string())
+    .execute(pool)
+    .await?;
     
-    // Limit the durations to the most recent MAX_TIMING_HISTORY entries
-    let limited_durations = if durations.len() > MAX_TIMING_HISTORY {
-        &durations[durations.len() - MAX_TIMING_HISTORY..]
-    } else {
-        durations
-    };
+    let success = result.rows_affected() > 0;
+    if success {
+        info!("Boot target for machine {} recorded as {:?}", id, target);
+    }
     
-    // Convert durations to JSON
-    let durations_json = serde_json::to_string(limited_durations)?;
+    Ok(success)
+}
+
+// Get where a machine's VNC console is served, if it was configured
+pub async fn get_vnc_target(id: &Uuid) -> Result<Option<VncTarget>> {
+    let pool = get_pool().await?;
     
-    // Create a plain SQL query to insert or update timing data
-    let query = "
-        INSERT INTO template_timings (template_name, action_name, durations)
-        VALUES ($1, $2, $3)
-        ON CONFLICT (template_name, action_name) 
-        DO UPDATE SET durations = $3
-    ";
+    let row = sqlx::query("SELECT vnc_target FROM machines WHERE id = ?")
+        .bind(id.to_string())
+        .fetch_optional(pool)
+        .await?;
     
-    // Execute the query
-    let result = sqlx::query::<sqlx::Sqlite>(query)
-        .bind(template_name)
-        .bind(action_name)
-        .bind(durations_json)
+    Ok(row
+        .and_then(|row| row.get::<Option<String>, _>("vnc_target"))
+        .and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+// Set where a machine's VNC console is served
+pub async fn update_vnc_target(id: &Uuid, target: &VncTarget) -> Result<bool> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    let result = sqlx::query("UPDATE machines SET vnc_target = ?, updated_at = ? WHERE id = ?")
+        .bind(serde_json::to_string(target)?)
+        .bind(&now_str)
+        .bind(id.to_string())
         .execute(pool)
         .await?;
     
     Ok(result.rows_affected() > 0)
 }
 
-// Load all template timing data from database
-pub async fn load_template_timings() -> Result<Vec<TemplateTiming>> {
+// Get the hand-written iPXE script attached to a machine, if any
+pub async fn get_ipxe_override(id: &Uuid) -> Result<Option<String>> {
     let pool = get_pool().await?;
     
-    info!("Loading all template timing data");
+    let row = sqlx::query("SELECT ipxe_override FROM machines WHERE id = ?")
+        .bind(id.to_string())
+        .fetch_optional(pool)
+        .await?;
     
-    // Create a plain SQL query to select all timing data
-    let query = "
-        SELECT template_name, action_name, durations FROM template_timings
+    Ok(row.and_then(|row| row.get::<Option<String>, _>("ipxe_override")))
+}
+
+// Attach (or with `None`, remove) a hand-written iPXE script for a machine
+pub async fn update_ipxe_override(id: &Uuid, script: Option<&str>) -> Result<bool> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    let result = sqlx::query(
+        r#"
+        UPDATE machines 
+        SET ipxe_override = ?, updated_at = ? 
+        WHERE id = ?
+        "#,
+    )
+    .bind(script)
+    .bind(&now_str)
+    .bind(id.to_string())
+    .execute(pool)
+    .await?;
+    
+    let success = result.rows_affected() > 0;
+    if success {
+        info!("iPXE override for machine {} {}", id, if script.is_some() { "updated" } else { "removed" });
+    } else {
+        info!("No machine found with ID {} to update iPXE override", id);
+    }
+    
+    Ok(success)
+}
+
+// Increment the usage counter for an OS template
+pub async fn record_template_usage(template_name: &str) -> Result<()> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    sqlx::query(
+        r#"
+        INSERT INTO template_usage (template_name, usage_count, last_used_at)
+        VALUES (?, 1, ?)
+        ON CONFLICT (template_name) DO UPDATE SET
+        usage_count = usage_count + 1,
+        last_used_at = excluded.last_used_at
+        "#,
+    )
+    .bind(template_name)
+    .bind(&now_str)
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+// Get usage counters for all OS templates, most used first
+pub async fn get_template_usage() -> Result<Vec<(String, i64)>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query(
+        r#"
+        SELECT template_name, usage_count FROM template_usage
+        ORDER BY usage_count DESC, template_name ASC
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    
+    let mut usage = Vec::with_capacity(rows.len());
+    for row in rows {
+        let template_name: String = row.get(0);
+        let usage_count: i64 = row.get(1);
+        usage.push((template_name, usage_count));
+    }
+    
+    Ok(usage)
+}
+
+// Add a new type for template timing data
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct TemplateTiming {
+    pub template_name: String,
+    pub action_name: String,
+    pub durations: Vec<u64>,
+}
+
+// Save template timing data to database
+pub async fn save_template_timing(template_name: &str, action_name: &str, durations: &[u64]) -> Result<bool> {
+    const MAX_TIMING_HISTORY: usize = 50; // Keep only the last 50 runs of timing data
+    
+    let pool = get_pool().await?;
+    
+    info!("Saving timing data for template {}, action {}", template_name, action_name);
+    
+    // Limit the durations to the most recent MAX_TIMING_HISTORY entries
+    let limited_durations = if durations.len() > MAX_TIMING_HISTORY {
+        &durations[durations.len() - MAX_TIMING_HISTORY..]
+    } else {
+        durations
+    };
+    
+    // Convert durations to JSON
+    let durations_json = serde_json::to_string(limited_durations)?;
+    
+    // Create a plain SQL query to insert or update timing data
+    let query = "
+        INSERT INTO template_timings (template_name, action_name, durations)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (template_name, action_name) 
+        DO UPDATE SET durations = $3
+    ";
+    
+    // Execute the query
+    let result = sqlx::query::<sqlx::Sqlite>(query)
+        .bind(template_name)
+        .bind(action_name)
+        .bind(durations_json)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Load all template timing data from database
+pub async fn load_template_timings() -> Result<Vec<TemplateTiming>> {
+    let pool = get_pool().await?;
+    
+    info!("Loading all template timing data");
+    
+    // Create a plain SQL query to select all timing data
+    let query = "
+        SELECT template_name, action_name, durations FROM template_timings
     ";
     
     // Execute the query
@@ -1259,28 +1544,6 @@ pub async fn load_template_timings() -> Result<Vec<TemplateTiming>> {
     Ok(timings)
 }
 
-// Initialize database schema for template timing data
-pub async fn init_timing_tables() -> Result<()> {
-    let pool = get_pool().await?;
-    
-    info!("Initializing template timing tables");
-    
-    // Create table for template timings if it doesn't exist
-    let create_table_query = "
-        CREATE TABLE IF NOT EXISTS template_timings (
-            template_name TEXT NOT NULL,
-            action_name TEXT NOT NULL,
-            durations TEXT NOT NULL,
-            PRIMARY KEY (template_name, action_name)
-        )
-    ";
-    
-    sqlx::query::<sqlx::Sqlite>(create_table_query)
-        .execute(pool)
-        .await?;
-    
-    Ok(())
-}
 
 // Get statistics about the template timing database
 pub async fn get_timing_database_stats() -> Result<(usize, usize, usize)> {
@@ -1322,7 +1585,9 @@ pub async fn get_timing_database_stats() -> Result<(usize, usize, usize)> {
     Ok((template_count as usize, action_count as usize, total_entries))
 }
 
+#[instrument(skip_all, fields(db.system = "sqlite", otel.kind = "client"))]
 pub async fn store_completed_workflow(machine_id: &Uuid, workflow_info: &WorkflowInfo) -> Result<()> {
+    let _timer = QueryTimer::start("store_completed_workflow");
     let pool = get_pool().await?;
     
     // Store workflow info as JSON
@@ -1368,7 +1633,9 @@ pub async fn get_completed_workflow(machine_id: &Uuid) -> Result<Option<(Workflo
 }
 
 // Get all machines with a specific status
+#[instrument(skip_all, fields(db.system = "sqlite", otel.kind = "client"))]
 pub async fn get_machines_by_status(status: dragonfly_common::models::MachineStatus) -> Result<Vec<dragonfly_common::models::Machine>> {
+    let _timer = QueryTimer::start("get_machines_by_status");
     let pool = get_pool().await?;
     
     // Convert the status to a JSON string for comparison
@@ -1408,6 +1675,7 @@ fn map_row_to_machine_with_hardware(row: sqlx::sqlite::SqliteRow) -> Result<Mach
     let cpu_cores: Option<u32> = cpu_cores_i64.map(|c| c as u32);
     let total_ram_bytes_i64: Option<i64> = row.try_get("total_ram_bytes")?;
     let total_ram_bytes: Option<u64> = total_ram_bytes_i64.map(|r| r as u64);
+    let clock_skew_seconds: Option<i64> = row.try_get("clock_skew_seconds").unwrap_or(None);
     
     // Generate memorable name from MAC address
     let memorable_name = dragonfly_common::mac_to_words::mac_to_words_safe(&mac_address);
@@ -1476,111 +1744,4448 @@ fn map_row_to_machine_with_hardware(row: sqlx::sqlite::SqliteRow) -> Result<Mach
         cpu_model,
         cpu_cores,
         total_ram_bytes,
+        clock_skew_seconds,
     })
 }
 
-// ---- START TAGS FUNCTIONS ----
+// ---- START HEARTBEAT FUNCTIONS ----
 
-// STUB: Get machine tags
-pub async fn get_machine_tags(id: &Uuid) -> Result<Vec<String>> {
-    info!("STUB: Called get_machine_tags for machine {}", id);
-    // TODO: Implement database logic to fetch tags for the given machine ID.
-    // This will likely require schema changes (e.g., a separate machine_tags table or a tags column in machines).
-    Ok(vec!["stub_tag".to_string()]) // Return dummy data for now
+// Record a heartbeat. If the machine had been marked Offline, its previous status is
+// restored and returned.
+#[instrument(skip_all, fields(db.system = "sqlite", otel.kind = "client"))]
+pub async fn record_heartbeat(id: &Uuid) -> Result<Option<MachineStatus>> {
+    let _timer = QueryTimer::start("record_heartbeat");
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    let row = sqlx::query("SELECT status_before_offline FROM machines WHERE id = ?")
+        .bind(id.to_string())
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| anyhow!("Machine {} not found", id))?;
+    let previous: Option<String> = row.get("status_before_offline");
+    
+    sqlx::query(
+        r#"
+        UPDATE machines 
+        SET last_heartbeat = ?,
+            status = COALESCE(status_before_offline, status),
+            status_before_offline = NULL
+        WHERE id = ?
+        "#,
+    )
+    .bind(&now_str)
+    .bind(id.to_string())
+    .execute(pool)
+    .await?;
+    
+    let restored = previous.map(|status| parse_status(&status));
+    if let Some(status) = &restored {
+        info!("Machine {} is back online, restored status {:?}", id, status);
+        record_transition(id, Some(&MachineStatus::Offline), status, &StatusCause::HeartbeatResumed).await?;
+    }
+    Ok(restored)
 }
 
-// STUB: Update machine tags
-pub async fn update_machine_tags(id: &Uuid, tags: &[String]) -> Result<bool> {
-    info!("STUB: Called update_machine_tags for machine {} with tags: {:?}", id, tags);
-    // TODO: Implement database logic to update tags for the given machine ID.
-    // This will likely involve deleting existing tags and inserting the new ones.
-    // Requires schema changes.
-    Ok(true) // Assume success for now
+// List machines whose last heartbeat is older than the cutoff and that aren't already Offline.
+// Machines that have never sent a heartbeat (e.g. older agents) are left alone.
+pub async fn get_silent_machines(cutoff: chrono::DateTime<Utc>) -> Result<Vec<(Uuid, MachineStatus)>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query(
+        r#"
+        SELECT id, status FROM machines 
+        WHERE last_heartbeat IS NOT NULL AND last_heartbeat < ? AND status_before_offline IS NULL
+        "#,
+    )
+    .bind(cutoff.to_rfc3339())
+    .fetch_all(pool)
+    .await?;
+    
+    rows.iter()
+        .map(|row| Ok((Uuid::parse_str(&row.get::<String, _>("id"))?, parse_status(&row.get::<String, _>("status")))))
+        .collect()
 }
 
-// ---- END TAGS FUNCTIONS ----
-
-// Update setup completion status
-pub async fn mark_setup_completed(completed: bool) -> Result<()> {
+// Mark a machine Offline, remembering its status so the next heartbeat can restore it
+pub async fn mark_offline(id: &Uuid) -> Result<bool> {
     let pool = get_pool().await?;
     let now = Utc::now();
     let now_str = now.to_rfc3339();
     
-    // First make sure the settings table exists
-    sqlx::query(
+    let Some(checked) = check_transition(id, MachineStatus::Offline).await? else {
+        return Ok(false);
+    };
+    
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query(
         r#"
-        CREATE TABLE IF NOT EXISTS app_settings (
-            id INTEGER PRIMARY KEY CHECK (id = 1),
-            require_login BOOLEAN NOT NULL DEFAULT 0,
-            default_os TEXT,
-            setup_completed BOOLEAN NOT NULL DEFAULT 0,
-            created_at TEXT NOT NULL,
-            updated_at TEXT NOT NULL
-        )
+        UPDATE machines 
+        SET status_before_offline = status, status = ?, updated_at = ? 
+        WHERE id = ? AND status = ? AND status_before_offline IS NULL
         "#,
     )
-    .execute(pool)
+    .bind(serde_json::to_string(&MachineStatus::Offline)?)
+    .bind(&now_str)
+    .bind(id.to_string())
+    .bind(&checked.stored)
+    .execute(&mut *tx)
     .await?;
     
-    // Check if settings record exists
-    let result = sqlx::query("SELECT COUNT(*) FROM app_settings WHERE id = 1")
-        .fetch_one(pool)
+    // A heartbeat or another change got there first; the caller expects this now and then
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+    let recorded = insert_transition(&mut *tx, id, Some(&checked.transition.from), &checked.transition.to, &StatusCause::HeartbeatTimeout).await?;
+    tx.commit().await?;
+    if recorded {
+        notify_status_changed(id);
+    }
+    Ok(true)
+}
+
+// ---- END HEARTBEAT FUNCTIONS ----
+
+// ---- START AGENT COMMAND FUNCTIONS ----
+
+// Get the secret a machine's agent signs its requests with, if it has enrolled
+pub async fn get_agent_secret(id: &Uuid) -> Result<Option<String>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT agent_secret FROM machines WHERE id = ?")
+        .bind(id.to_string())
+        .fetch_optional(pool)
         .await?;
     
-    let count: i64 = result.get(0);
+    Ok(row.and_then(|row| row.get::<Option<String>, _>("agent_secret")))
+}
+
+// Store a newly issued agent secret. Returns false if the machine doesn't exist or is already enrolled.
+pub async fn set_agent_secret(id: &Uuid, secret: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
     
-    if count > 0 {
-        // Update existing record
-        sqlx::query(
-            r#"
-            UPDATE app_settings 
-            SET setup_completed = ?, updated_at = ?
-            WHERE id = 1
-            "#,
-        )
-        .bind(completed)
+    let result = sqlx::query("UPDATE machines SET agent_secret = ?, updated_at = ? WHERE id = ? AND agent_secret IS NULL")
+        .bind(secret)
         .bind(&now_str)
+        .bind(id.to_string())
         .execute(pool)
         .await?;
-    } else {
-        // Create a new record with default values and the specified setup_completed
-        sqlx::query(
-            r#"
-            INSERT INTO app_settings (id, require_login, default_os, setup_completed, created_at, updated_at)
-            VALUES (1, 0, NULL, ?, ?, ?)
-            "#,
-        )
-        .bind(completed)
-        .bind(&now_str)
+    
+    let success = result.rows_affected() > 0;
+    if success {
+        info!("Agent for machine {} enrolled", id);
+    }
+    Ok(success)
+}
+
+// Forget a machine's agent secret so its agent can enroll again
+pub async fn clear_agent_secret(id: &Uuid) -> Result<bool> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    
+    let result = sqlx::query("UPDATE machines SET agent_secret = NULL, updated_at = ? WHERE id = ? AND agent_secret IS NOT NULL")
         .bind(&now_str)
+        .bind(id.to_string())
         .execute(pool)
         .await?;
-    }
     
-    info!("Setup completion status set to: {}", completed);
-    Ok(())
+    Ok(result.rows_affected() > 0)
 }
 
-// Check if setup has been completed
-pub async fn is_setup_completed() -> Result<bool> {
+fn agent_command_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<AgentCommand> {
+    let completed_at: Option<String> = row.get("completed_at");
+    Ok(AgentCommand {
+        id: row.get("id"),
+        machine_id: Uuid::parse_str(&row.get::<String, _>("machine_id"))?,
+        kind: serde_json::from_str(&row.get::<String, _>("kind"))?,
+        status: serde_json::from_str(&row.get::<String, _>("status"))?,
+        requested_by: row.get("requested_by"),
+        exit_code: row.get("exit_code"),
+        output: row.get("output"),
+        created_at: parse_datetime(&row.get::<String, _>("created_at")),
+        completed_at: completed_at.map(|value| parse_datetime(&value)),
+    })
+}
+
+// Queue a command for a machine's agent, returning its ID
+pub async fn add_agent_command(machine_id: &Uuid, kind: &CommandKind, requested_by: Option<&str>) -> Result<i64> {
     let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
     
-    // First make sure the settings table exists
-    sqlx::query(
+    let result = sqlx::query(
         r#"
-        CREATE TABLE IF NOT EXISTS app_settings (
-            id INTEGER PRIMARY KEY CHECK (id = 1),
-            require_login BOOLEAN NOT NULL DEFAULT 0,
-            default_os TEXT,
-            setup_completed BOOLEAN NOT NULL DEFAULT 0,
-            created_at TEXT NOT NULL,
-            updated_at TEXT NOT NULL
-        )
+        INSERT INTO agent_commands (machine_id, kind, status, requested_by, created_at)
+        VALUES (?, ?, ?, ?, ?)
         "#,
     )
+    .bind(machine_id.to_string())
+    .bind(serde_json::to_string(kind)?)
+    .bind(serde_json::to_string(&CommandStatus::Pending)?)
+    .bind(requested_by)
+    .bind(&now_str)
     .execute(pool)
     .await?;
     
+    let id = result.last_insert_rowid();
+    info!("Queued command {} for machine {}: {:?}", id, machine_id, kind);
+    Ok(id)
+}
+
+// Get a single command
+pub async fn get_agent_command(id: i64) -> Result<Option<AgentCommand>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT * FROM agent_commands WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    
+    row.as_ref().map(agent_command_from_row).transpose()
+}
+
+// List the most recent commands for a machine, newest first
+pub async fn get_agent_commands(machine_id: &Uuid) -> Result<Vec<AgentCommand>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM agent_commands WHERE machine_id = ? ORDER BY id DESC LIMIT 50")
+        .bind(machine_id.to_string())
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(agent_command_from_row).collect()
+}
+
+// Hand the oldest pending command to the agent, marking it running
+pub async fn claim_next_agent_command(machine_id: &Uuid) -> Result<Option<AgentCommand>> {
+    let pool = get_pool().await?;
+    let pending = serde_json::to_string(&CommandStatus::Pending)?;
+    
+    let row = sqlx::query(
+        r#"
+        UPDATE agent_commands SET status = ?
+        WHERE id = (SELECT id FROM agent_commands WHERE machine_id = ? AND status = ? ORDER BY id LIMIT 1)
+        RETURNING *
+        "#,
+    )
+    .bind(serde_json::to_string(&CommandStatus::Running)?)
+    .bind(machine_id.to_string())
+    .bind(&pending)
+    .fetch_optional(pool)
+    .await?;
+    
+    row.as_ref().map(agent_command_from_row).transpose()
+}
+
+// Record the outcome of a running command
+pub async fn complete_agent_command(machine_id: &Uuid, id: i64, exit_code: i32, output: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    let status = if exit_code == 0 { CommandStatus::Succeeded } else { CommandStatus::Failed };
+    
+    let result = sqlx::query(
+        r#"
+        UPDATE agent_commands
+        SET status = ?, exit_code = ?, output = ?, completed_at = ?
+        WHERE id = ? AND machine_id = ? AND status = ?
+        "#,
+    )
+    .bind(serde_json::to_string(&status)?)
+    .bind(exit_code)
+    .bind(output)
+    .bind(&now_str)
+    .bind(id)
+    .bind(machine_id.to_string())
+    .bind(serde_json::to_string(&CommandStatus::Running)?)
+    .execute(pool)
+    .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// ---- END AGENT COMMAND FUNCTIONS ----
+
+// ---- START IPXE TEMPLATE FUNCTIONS ----
+
+// Get an admin-edited iPXE template by name
+pub async fn get_ipxe_template(name: &str) -> Result<Option<String>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT content FROM ipxe_templates WHERE name = ?")
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.map(|row| row.get("content")))
+}
+
+// Store an admin-edited iPXE template
+pub async fn save_ipxe_template(name: &str, content: &str) -> Result<()> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    sqlx::query(
+        r#"
+        INSERT INTO ipxe_templates (name, content, updated_at)
+        VALUES (?, ?, ?)
+        ON CONFLICT (name) DO UPDATE SET
+        content = excluded.content,
+        updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(name)
+    .bind(content)
+    .bind(&now_str)
+    .execute(pool)
+    .await?;
+    
+    info!("Saved iPXE template '{}'", name);
+    Ok(())
+}
+
+// Remove an admin-edited iPXE template, restoring the built-in one
+pub async fn delete_ipxe_template(name: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM ipxe_templates WHERE name = ?")
+        .bind(name)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// List every admin-edited iPXE template with its content
+pub async fn get_ipxe_templates() -> Result<Vec<(String, String)>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT name, content FROM ipxe_templates ORDER BY name")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().map(|row| (row.get("name"), row.get("content"))).collect())
+}
+
+// List the names of project-specific iPXE templates
+pub async fn get_scoped_ipxe_template_names() -> Result<Vec<String>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT name FROM ipxe_templates WHERE name LIKE '%@%' ORDER BY name")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().map(|row| row.get("name")).collect())
+}
+
+// ---- END IPXE TEMPLATE FUNCTIONS ----
+
+// ---- START SECURE BOOT FUNCTIONS ----
+
+// Get the signed boot image registered for a template
+pub async fn get_signed_boot_image(template: &str) -> Result<Option<SignedBootImage>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT image FROM secure_boot_images WHERE template = ?")
+        .bind(template)
+        .fetch_optional(pool)
+        .await?;
+    
+    match row {
+        Some(row) => Ok(Some(serde_json::from_str(&row.get::<String, _>("image"))?)),
+        None => Ok(None),
+    }
+}
+
+// List all registered signed boot images
+pub async fn get_signed_boot_images() -> Result<Vec<SignedBootImage>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT image FROM secure_boot_images ORDER BY template")
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter()
+        .map(|row| Ok(serde_json::from_str(&row.get::<String, _>("image"))?))
+        .collect()
+}
+
+// Register or replace the signed boot image for a template
+pub async fn save_signed_boot_image(image: &SignedBootImage) -> Result<()> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    sqlx::query(
+        r#"
+        INSERT INTO secure_boot_images (template, image, updated_at)
+        VALUES (?, ?, ?)
+        ON CONFLICT (template) DO UPDATE SET
+        image = excluded.image,
+        updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(&image.template)
+    .bind(serde_json::to_string(image)?)
+    .bind(&now_str)
+    .execute(pool)
+    .await?;
+    
+    info!("Saved signed boot image for template '{}'", image.template);
+    Ok(())
+}
+
+// Remove the signed boot image for a template
+pub async fn delete_signed_boot_image(template: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM secure_boot_images WHERE template = ?")
+        .bind(template)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// ---- END SECURE BOOT FUNCTIONS ----
+
+// ---- START ACL FUNCTIONS ----
+
+fn acl_entry_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<AclEntry> {
+    Ok(AclEntry {
+        id: row.get("id"),
+        principal: row.get("principal"),
+        scope: serde_json::from_str(&row.get::<String, _>("scope"))?,
+        permission: serde_json::from_str(&row.get::<String, _>("permission"))?,
+    })
+}
+
+// List all ACL entries
+pub async fn get_acl_entries() -> Result<Vec<AclEntry>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT id, principal, scope, permission FROM acl_entries ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(acl_entry_from_row).collect()
+}
+
+// List the ACL entries granted to a single principal
+pub async fn get_acl_entries_for_principal(principal: &str) -> Result<Vec<AclEntry>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT id, principal, scope, permission FROM acl_entries WHERE principal = ?")
+        .bind(principal)
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(acl_entry_from_row).collect()
+}
+
+// Add an ACL entry, returning its ID
+pub async fn add_acl_entry(entry: &AclEntry) -> Result<i64> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    let result = sqlx::query(
+        r#"
+        INSERT INTO acl_entries (principal, scope, permission, created_at)
+        VALUES (?, ?, ?, ?)
+        "#,
+    )
+    .bind(&entry.principal)
+    .bind(serde_json::to_string(&entry.scope)?)
+    .bind(serde_json::to_string(&entry.permission)?)
+    .bind(&now_str)
+    .execute(pool)
+    .await?;
+    
+    let id = result.last_insert_rowid();
+    info!("Added ACL entry {}: {} has {:?} on {:?}", id, entry.principal, entry.permission, entry.scope);
+    Ok(id)
+}
+
+// Delete an ACL entry by ID
+pub async fn delete_acl_entry(id: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM acl_entries WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// ---- END ACL FUNCTIONS ----
+
+// ---- START WEBHOOK FUNCTIONS ----
+
+fn webhook_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<WebhookEndpoint> {
+    Ok(WebhookEndpoint {
+        id: row.get("id"),
+        name: row.get("name"),
+        url: row.get("url"),
+        events: serde_json::from_str(&row.get::<String, _>("events"))?,
+        template: row.get("template"),
+        content_type: row.get("content_type"),
+        enabled: row.get("enabled"),
+    })
+}
+
+// List all webhook endpoints
+pub async fn get_webhooks() -> Result<Vec<WebhookEndpoint>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT id, name, url, events, template, content_type, enabled FROM webhooks ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(webhook_from_row).collect()
+}
+
+// Get a single webhook endpoint by ID
+pub async fn get_webhook(id: i64) -> Result<Option<WebhookEndpoint>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT id, name, url, events, template, content_type, enabled FROM webhooks WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    
+    row.as_ref().map(webhook_from_row).transpose()
+}
+
+// Add a webhook endpoint, returning its ID
+pub async fn add_webhook(webhook: &WebhookEndpoint) -> Result<i64> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    let result = sqlx::query(
+        r#"
+        INSERT INTO webhooks (name, url, events, template, content_type, enabled, created_at, updated_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(&webhook.name)
+    .bind(&webhook.url)
+    .bind(serde_json::to_string(&webhook.events)?)
+    .bind(&webhook.template)
+    .bind(&webhook.content_type)
+    .bind(webhook.enabled)
+    .bind(&now_str)
+    .bind(&now_str)
+    .execute(pool)
+    .await?;
+    
+    let id = result.last_insert_rowid();
+    info!("Added webhook {} ({}) -> {}", id, webhook.name, webhook.url);
+    Ok(id)
+}
+
+// Replace a webhook endpoint's configuration
+pub async fn update_webhook(id: i64, webhook: &WebhookEndpoint) -> Result<bool> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    let result = sqlx::query(
+        r#"
+        UPDATE webhooks
+        SET name = ?, url = ?, events = ?, template = ?, content_type = ?, enabled = ?, updated_at = ?
+        WHERE id = ?
+        "#,
+    )
+    .bind(&webhook.name)
+    .bind(&webhook.url)
+    .bind(serde_json::to_string(&webhook.events)?)
+    .bind(&webhook.template)
+    .bind(&webhook.content_type)
+    .bind(webhook.enabled)
+    .bind(&now_str)
+    .bind(id)
+    .execute(pool)
+    .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Delete a webhook endpoint by ID
+pub async fn delete_webhook(id: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM webhooks WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// ---- END WEBHOOK FUNCTIONS ----
+
+// ---- START NOTIFICATION CHANNEL FUNCTIONS ----
+
+fn notification_channel_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<NotificationChannel> {
+    let kind: String = row.get("kind");
+    Ok(NotificationChannel {
+        id: row.get("id"),
+        name: row.get("name"),
+        kind: ChannelKind::parse(&kind).ok_or_else(|| anyhow!("Unknown notification channel kind '{}'", kind))?,
+        webhook_url: row.get("webhook_url"),
+        events: serde_json::from_str(&row.get::<String, _>("events"))?,
+        enabled: row.get("enabled"),
+    })
+}
+
+// List all chat notification channels
+pub async fn get_notification_channels() -> Result<Vec<NotificationChannel>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT id, name, kind, webhook_url, events, enabled FROM notification_channels ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(notification_channel_from_row).collect()
+}
+
+// Get a single notification channel by ID
+pub async fn get_notification_channel(id: i64) -> Result<Option<NotificationChannel>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT id, name, kind, webhook_url, events, enabled FROM notification_channels WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    
+    row.as_ref().map(notification_channel_from_row).transpose()
+}
+
+// Add a notification channel, returning its ID
+pub async fn add_notification_channel(channel: &NotificationChannel) -> Result<i64> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    
+    let result = sqlx::query(
+        r#"
+        INSERT INTO notification_channels (name, kind, webhook_url, events, enabled, created_at, updated_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(&channel.name)
+    .bind(channel.kind.as_str())
+    .bind(&channel.webhook_url)
+    .bind(serde_json::to_string(&channel.events)?)
+    .bind(channel.enabled)
+    .bind(&now_str)
+    .bind(&now_str)
+    .execute(pool)
+    .await?;
+    
+    let id = result.last_insert_rowid();
+    info!("Added notification channel {} ({})", id, channel.name);
+    Ok(id)
+}
+
+// Replace a notification channel's configuration
+pub async fn update_notification_channel(id: i64, channel: &NotificationChannel) -> Result<bool> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    
+    let result = sqlx::query(
+        r#"
+        UPDATE notification_channels
+        SET name = ?, kind = ?, webhook_url = ?, events = ?, enabled = ?, updated_at = ?
+        WHERE id = ?
+        "#,
+    )
+    .bind(&channel.name)
+    .bind(channel.kind.as_str())
+    .bind(&channel.webhook_url)
+    .bind(serde_json::to_string(&channel.events)?)
+    .bind(channel.enabled)
+    .bind(&now_str)
+    .bind(id)
+    .execute(pool)
+    .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Delete a notification channel by ID
+pub async fn delete_notification_channel(id: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM notification_channels WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// ---- END NOTIFICATION CHANNEL FUNCTIONS ----
+
+// ---- START ALERT FUNCTIONS ----
+
+// Get the SMTP settings, if a mail server has been configured
+pub async fn get_smtp_settings() -> Result<Option<SmtpSettings>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT config FROM smtp_settings WHERE id = 1")
+        .fetch_optional(pool)
+        .await?;
+    
+    row.map(|row| Ok(serde_json::from_str(&row.get::<String, _>("config"))?)).transpose()
+}
+
+// Save the SMTP settings
+pub async fn save_smtp_settings(smtp: &SmtpSettings) -> Result<()> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    
+    sqlx::query(
+        r#"
+        INSERT INTO smtp_settings (id, config, updated_at)
+        VALUES (1, ?, ?)
+        ON CONFLICT (id) DO UPDATE SET
+        config = excluded.config,
+        updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(serde_json::to_string(smtp)?)
+    .bind(&now_str)
+    .execute(pool)
+    .await?;
+    
+    info!("Saved SMTP settings for {}", smtp.host);
+    Ok(())
+}
+
+fn alert_rule_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<AlertRule> {
+    let rule: AlertRule = serde_json::from_str(&row.get::<String, _>("config"))?;
+    Ok(AlertRule {
+        id: row.get("id"),
+        enabled: row.get("enabled"),
+        ..rule
+    })
+}
+
+// List all alert rules
+pub async fn get_alert_rules() -> Result<Vec<AlertRule>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT id, config, enabled FROM alert_rules ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(alert_rule_from_row).collect()
+}
+
+// Add an alert rule, returning its ID
+pub async fn add_alert_rule(rule: &AlertRule) -> Result<i64> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    
+    let result = sqlx::query(
+        r#"
+        INSERT INTO alert_rules (name, config, enabled, created_at, updated_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(&rule.name)
+    .bind(serde_json::to_string(rule)?)
+    .bind(rule.enabled)
+    .bind(&now_str)
+    .bind(&now_str)
+    .execute(pool)
+    .await?;
+    
+    let id = result.last_insert_rowid();
+    info!("Added alert rule {} ({})", id, rule.name);
+    Ok(id)
+}
+
+// Replace an alert rule
+pub async fn update_alert_rule(id: i64, rule: &AlertRule) -> Result<bool> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    
+    let result = sqlx::query("UPDATE alert_rules SET name = ?, config = ?, enabled = ?, updated_at = ? WHERE id = ?")
+        .bind(&rule.name)
+        .bind(serde_json::to_string(rule)?)
+        .bind(rule.enabled)
+        .bind(&now_str)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Delete an alert rule by ID
+pub async fn delete_alert_rule(id: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM alert_rules WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Rule/machine pairs already alerted on
+pub async fn get_alert_firings() -> Result<HashSet<(i64, Uuid)>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT rule_id, machine_id FROM alert_firings")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().filter_map(|row| {
+        let machine_id = Uuid::parse_str(&row.get::<String, _>("machine_id")).ok()?;
+        Some((row.get::<i64, _>("rule_id"), machine_id))
+    }).collect())
+}
+
+// Remember that a rule alerted on these machines
+pub async fn record_alert_firings(rule_id: i64, machine_ids: &[Uuid]) -> Result<()> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    
+    for machine_id in machine_ids {
+        sqlx::query("INSERT OR IGNORE INTO alert_firings (rule_id, machine_id, fired_at) VALUES (?, ?, ?)")
+            .bind(rule_id)
+            .bind(machine_id.to_string())
+            .bind(&now_str)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+// Forget an alert, so the machine is alerted on again the next time it matches the rule
+pub async fn delete_alert_firing(rule_id: i64, machine_id: &Uuid) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query("DELETE FROM alert_firings WHERE rule_id = ? AND machine_id = ?")
+        .bind(rule_id)
+        .bind(machine_id.to_string())
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// When each machine entered its current status, from its latest status change
+pub async fn get_status_entered_times() -> Result<HashMap<Uuid, chrono::DateTime<Utc>>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT machine_id, MAX(changed_at) AS changed_at FROM status_history GROUP BY machine_id")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().filter_map(|row| {
+        let id = Uuid::parse_str(&row.get::<String, _>("machine_id")).ok()?;
+        Some((id, parse_datetime(&row.get::<String, _>("changed_at"))))
+    }).collect())
+}
+
+// ---- END ALERT FUNCTIONS ----
+
+// ---- START DISCOVERY FUNCTIONS ----
+
+// Get the discovery policy, or the default (no schedule, no rules) if none was saved
+pub async fn get_discovery_policy() -> Result<DiscoveryPolicy> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT policy FROM discovery_policy WHERE id = 1")
+        .fetch_optional(pool)
+        .await?;
+    
+    match row {
+        Some(row) => Ok(serde_json::from_str(&row.get::<String, _>("policy"))?),
+        None => Ok(DiscoveryPolicy::default()),
+    }
+}
+
+// Save the discovery policy
+pub async fn save_discovery_policy(policy: &DiscoveryPolicy) -> Result<()> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    sqlx::query(
+        r#"
+        INSERT INTO discovery_policy (id, policy, updated_at)
+        VALUES (1, ?, ?)
+        ON CONFLICT (id) DO UPDATE SET
+        policy = excluded.policy,
+        updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(serde_json::to_string(policy)?)
+    .bind(&now_str)
+    .execute(pool)
+    .await?;
+    
+    info!("Saved discovery policy");
+    Ok(())
+}
+
+// Store a discovery report, returning its ID
+pub async fn add_discovery_report(report: &DiscoveryReport) -> Result<i64> {
+    let pool = get_pool().await?;
+    
+    let id = sqlx::query("INSERT INTO discovery_scans (started_at, report) VALUES (?, ?)")
+        .bind(report.started_at.to_rfc3339())
+        .bind(serde_json::to_string(report)?)
+        .execute(pool)
+        .await?
+        .last_insert_rowid();
+    
+    Ok(id)
+}
+
+fn discovery_report_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<DiscoveryReport> {
+    let mut report: DiscoveryReport = serde_json::from_str(&row.get::<String, _>("report"))?;
+    report.id = row.get("id");
+    Ok(report)
+}
+
+// Get one discovery report
+pub async fn get_discovery_report(id: i64) -> Result<Option<DiscoveryReport>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT id, report FROM discovery_scans WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    
+    row.as_ref().map(discovery_report_from_row).transpose()
+}
+
+// Get the most recent discovery report
+pub async fn get_latest_discovery_report() -> Result<Option<DiscoveryReport>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT id, report FROM discovery_scans ORDER BY id DESC LIMIT 1")
+        .fetch_optional(pool)
+        .await?;
+    
+    row.as_ref().map(discovery_report_from_row).transpose()
+}
+
+// List recent discovery reports, newest first
+pub async fn get_discovery_reports(limit: i64) -> Result<Vec<DiscoveryReport>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT id, report FROM discovery_scans ORDER BY id DESC LIMIT ?")
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(discovery_report_from_row).collect()
+}
+
+// ---- END DISCOVERY FUNCTIONS ----
+
+// ---- START BREAK-GLASS FUNCTIONS ----
+
+// Record a newly issued break-glass credential
+pub async fn add_break_glass_credential(username: &str, password_hash: &str, issued_by: &str, expires_at: chrono::DateTime<Utc>) -> Result<i64> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    let id = sqlx::query(
+        r#"
+        INSERT INTO break_glass_credentials (username, password_hash, issued_by, created_at, expires_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(username)
+    .bind(password_hash)
+    .bind(issued_by)
+    .bind(&now_str)
+    .bind(expires_at.to_rfc3339())
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    
+    Ok(id)
+}
+
+// Revoke every break-glass credential that has not been used yet
+pub async fn revoke_unused_break_glass_credentials() -> Result<u64> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    let result = sqlx::query(
+        "UPDATE break_glass_credentials SET revoked_at = ? WHERE used_at IS NULL AND revoked_at IS NULL AND expires_at > ?",
+    )
+    .bind(&now_str)
+    .bind(&now_str)
+    .execute(pool)
+    .await?;
+    
+    Ok(result.rows_affected())
+}
+
+// Get the ID and hash of a break-glass credential that can still be used to log in
+pub async fn get_usable_break_glass_credential(username: &str, now: chrono::DateTime<Utc>) -> Result<Option<(i64, String)>> {
+    usable_break_glass_credential(get_pool().await?, username, now).await
+}
+
+pub(crate) async fn usable_break_glass_credential(pool: &Pool<Sqlite>, username: &str, now: chrono::DateTime<Utc>) -> Result<Option<(i64, String)>> {
+    let row = sqlx::query(
+        r#"
+        SELECT id, password_hash FROM break_glass_credentials
+        WHERE username = ? AND used_at IS NULL AND revoked_at IS NULL AND expires_at > ?
+        "#,
+    )
+    .bind(username)
+    .bind(now.to_rfc3339())
+    .fetch_optional(pool)
+    .await?;
+    
+    Ok(row.map(|row| (row.get("id"), row.get("password_hash"))))
+}
+
+// Claim a break-glass credential for a login; false if it was already used
+pub async fn mark_break_glass_credential_used(id: i64) -> Result<bool> {
+    claim_break_glass_credential(get_pool().await?, id).await
+}
+
+pub(crate) async fn claim_break_glass_credential(pool: &Pool<Sqlite>, id: i64) -> Result<bool> {
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    let result = sqlx::query("UPDATE break_glass_credentials SET used_at = ? WHERE id = ? AND used_at IS NULL")
+        .bind(&now_str)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Username of a break-glass session that is still allowed to act
+pub async fn get_active_break_glass_session(id: i64, now: chrono::DateTime<Utc>) -> Result<Option<String>> {
+    active_break_glass_session(get_pool().await?, id, now).await
+}
+
+pub(crate) async fn active_break_glass_session(pool: &Pool<Sqlite>, id: i64, now: chrono::DateTime<Utc>) -> Result<Option<String>> {
+    let row = sqlx::query(
+        r#"
+        SELECT username FROM break_glass_credentials
+        WHERE id = ? AND used_at IS NOT NULL AND rotated_at IS NULL AND revoked_at IS NULL AND expires_at > ?
+        "#,
+    )
+    .bind(id)
+    .bind(now.to_rfc3339())
+    .fetch_optional(pool)
+    .await?;
+    
+    Ok(row.map(|row| row.get("username")))
+}
+
+// Record that a break-glass session rotated the admin password
+pub async fn mark_break_glass_credential_rotated(id: i64) -> Result<()> {
+    close_break_glass_session(get_pool().await?, id).await
+}
+
+pub(crate) async fn close_break_glass_session(pool: &Pool<Sqlite>, id: i64) -> Result<()> {
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    sqlx::query("UPDATE break_glass_credentials SET rotated_at = ? WHERE id = ?")
+        .bind(&now_str)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(())
+}
+
+// List break-glass credentials for auditing, newest first
+pub async fn get_break_glass_records() -> Result<Vec<BreakGlassRecord>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query(
+        r#"
+        SELECT id, username, issued_by, created_at, expires_at, used_at, rotated_at, revoked_at
+        FROM break_glass_credentials ORDER BY id DESC
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    
+    let optional = |row: &sqlx::sqlite::SqliteRow, column: &str| {
+        row.get::<Option<String>, _>(column).map(|value| parse_datetime(&value))
+    };
+    Ok(rows.iter().map(|row| BreakGlassRecord {
+        id: row.get("id"),
+        username: row.get("username"),
+        issued_by: row.get("issued_by"),
+        created_at: parse_datetime(&row.get::<String, _>("created_at")),
+        expires_at: parse_datetime(&row.get::<String, _>("expires_at")),
+        used_at: optional(row, "used_at"),
+        rotated_at: optional(row, "rotated_at"),
+        revoked_at: optional(row, "revoked_at"),
+    }).collect())
+}
+
+// ---- END BREAK-GLASS FUNCTIONS ----
+
+// ---- START USERS FUNCTIONS ----
+
+fn user_account_from_row(row: &sqlx::sqlite::SqliteRow) -> UserAccount {
+    UserAccount {
+        id: row.get("id"),
+        username: row.get("username"),
+        disabled: row.get("disabled"),
+        is_admin: row.get("is_admin"),
+        created_at: parse_datetime(&row.get::<String, _>("created_at")),
+        updated_at: parse_datetime(&row.get::<String, _>("updated_at")),
+        last_login_at: row.get::<Option<String>, _>("last_login_at").map(|value| parse_datetime(&value)),
+        totp_enabled: row.get("totp_enabled"),
+    }
+}
+
+// List all user accounts
+pub async fn get_user_accounts() -> Result<Vec<UserAccount>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query(
+        "SELECT id, username, disabled, is_admin, created_at, updated_at, last_login_at, totp_enabled FROM users ORDER BY id",
+    )
+    .fetch_all(pool)
+    .await?;
+    
+    Ok(rows.iter().map(user_account_from_row).collect())
+}
+
+// Get a user account by ID
+pub async fn get_user_account(id: i64) -> Result<Option<UserAccount>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query(
+        "SELECT id, username, disabled, is_admin, created_at, updated_at, last_login_at, totp_enabled FROM users WHERE id = ?",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    
+    Ok(row.as_ref().map(user_account_from_row))
+}
+
+// Get a user's password hash, for re-checking the current password
+pub async fn get_user_password_hash(id: i64) -> Result<Option<String>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT password_hash FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.map(|row| row.get(0)))
+}
+
+// Create a user account; returns None if the username is taken
+pub async fn add_user_account(username: &str, password_hash: &str, is_admin: bool) -> Result<Option<UserAccount>> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    
+    let result = sqlx::query(
+        r#"
+        INSERT INTO users (username, password_hash, disabled, is_admin, created_at, updated_at)
+        VALUES (?, ?, 0, ?, ?, ?)
+        ON CONFLICT(username) DO NOTHING
+        "#,
+    )
+    .bind(username)
+    .bind(password_hash)
+    .bind(is_admin)
+    .bind(&now_str)
+    .bind(&now_str)
+    .execute(pool)
+    .await?;
+    
+    if result.rows_affected() == 0 {
+        return Ok(None);
+    }
+    get_user_account(result.last_insert_rowid()).await
+}
+
+// Replace a user's password hash
+pub async fn update_user_password(id: i64, password_hash: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    
+    let result = sqlx::query("UPDATE users SET password_hash = ?, updated_at = ? WHERE id = ?")
+        .bind(password_hash)
+        .bind(&now_str)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Disable or re-enable a user account
+pub async fn set_user_disabled(id: i64, disabled: bool) -> Result<bool> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    
+    let result = sqlx::query("UPDATE users SET disabled = ?, updated_at = ? WHERE id = ?")
+        .bind(disabled)
+        .bind(&now_str)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Grant or revoke administrator rights
+pub async fn set_user_admin(id: i64, is_admin: bool) -> Result<bool> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    
+    let result = sqlx::query("UPDATE users SET is_admin = ?, updated_at = ? WHERE id = ?")
+        .bind(is_admin)
+        .bind(&now_str)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Delete a user account
+pub async fn delete_user_account(id: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM users WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Count accounts that can still sign in
+pub async fn count_enabled_users() -> Result<i64> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT COUNT(*) FROM users WHERE disabled = 0")
+        .fetch_one(pool)
+        .await?;
+    
+    Ok(row.get(0))
+}
+
+// Count administrators that can still sign in
+pub async fn count_enabled_admins() -> Result<i64> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT COUNT(*) FROM users WHERE disabled = 0 AND is_admin = 1")
+        .fetch_one(pool)
+        .await?;
+    
+    Ok(row.get(0))
+}
+
+// Get a user's two-factor state, or None if they have never enrolled
+pub async fn get_user_totp(id: i64) -> Result<Option<TotpState>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query(
+        "SELECT totp_secret, totp_enabled, totp_last_step FROM users WHERE id = ? AND totp_secret IS NOT NULL",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let recovery_codes = sqlx::query("SELECT code_hash FROM totp_recovery_codes WHERE user_id = ?")
+        .bind(id)
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| row.get("code_hash"))
+        .collect();
+    Ok(Some(TotpState {
+        secret: row.get("totp_secret"),
+        enabled: row.get("totp_enabled"),
+        recovery_codes,
+        last_step: row.get("totp_last_step"),
+    }))
+}
+
+// Store a new, not yet confirmed, two-factor secret
+pub async fn set_user_totp_secret(id: i64, secret: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    let mut tx = pool.begin().await?;
+    
+    sqlx::query("DELETE FROM totp_recovery_codes WHERE user_id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    
+    let result = sqlx::query(
+        r#"
+        UPDATE users SET totp_secret = ?, totp_enabled = 0, totp_last_step = NULL
+        WHERE id = ?
+        "#,
+    )
+    .bind(secret)
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Turn on two-factor authentication once enrollment is confirmed
+pub async fn enable_user_totp(id: i64, recovery_codes: &[String], step: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    let mut tx = pool.begin().await?;
+    
+    let result = sqlx::query(
+        r#"
+        UPDATE users SET totp_enabled = 1, totp_last_step = ?, updated_at = ?
+        WHERE id = ? AND totp_secret IS NOT NULL
+        "#,
+    )
+    .bind(step)
+    .bind(&now_str)
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+    replace_recovery_codes(&mut tx, id, recovery_codes).await?;
+    tx.commit().await?;
+    
+    Ok(true)
+}
+
+async fn replace_recovery_codes(tx: &mut sqlx::Transaction<'_, Sqlite>, id: i64, recovery_codes: &[String]) -> Result<()> {
+    sqlx::query("DELETE FROM totp_recovery_codes WHERE user_id = ?")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    for code_hash in recovery_codes {
+        sqlx::query("INSERT OR IGNORE INTO totp_recovery_codes (user_id, code_hash) VALUES (?, ?)")
+            .bind(id)
+            .bind(code_hash)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
+}
+
+// Replace a user's remaining recovery code hashes
+pub async fn set_user_recovery_codes(id: i64, recovery_codes: &[String]) -> Result<bool> {
+    let pool = get_pool().await?;
+    let mut tx = pool.begin().await?;
+    
+    let exists = sqlx::query("SELECT 1 FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .is_some();
+    if !exists {
+        return Ok(false);
+    }
+    replace_recovery_codes(&mut tx, id, recovery_codes).await?;
+    tx.commit().await?;
+    
+    Ok(true)
+}
+
+// Spend a recovery code of a user with two-factor on, if it is still unused. Returns how many are
+// left, or None if it wasn't one; the delete is the check, so a code can only be spent once.
+pub async fn use_recovery_code(id: i64, code_hash: &str) -> Result<Option<i64>> {
+    spend_recovery_code(get_pool().await?, id, code_hash).await
+}
+
+pub(crate) async fn spend_recovery_code(pool: &Pool<Sqlite>, id: i64, code_hash: &str) -> Result<Option<i64>> {
+    let used = sqlx::query(
+        r#"
+        DELETE FROM totp_recovery_codes
+        WHERE user_id = ? AND code_hash = ? AND user_id IN (SELECT id FROM users WHERE totp_enabled = 1)
+        RETURNING code_hash
+        "#,
+    )
+    .bind(id)
+    .bind(code_hash)
+    .fetch_optional(pool)
+    .await?;
+    if used.is_none() {
+        return Ok(None);
+    }
+    
+    let row = sqlx::query("SELECT COUNT(*) FROM totp_recovery_codes WHERE user_id = ?")
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+    Ok(Some(row.get(0)))
+}
+
+// Remember the last accepted code's time step; false if a later one was already used
+pub async fn record_totp_step(id: i64, step: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query(
+        "UPDATE users SET totp_last_step = ? WHERE id = ? AND (totp_last_step IS NULL OR totp_last_step < ?)",
+    )
+    .bind(step)
+    .bind(id)
+    .bind(step)
+    .execute(pool)
+    .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Remove a user's two-factor secret and recovery codes
+pub async fn clear_user_totp(id: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    let mut tx = pool.begin().await?;
+    
+    sqlx::query("DELETE FROM totp_recovery_codes WHERE user_id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    
+    let result = sqlx::query(
+        r#"
+        UPDATE users SET totp_secret = NULL, totp_enabled = 0, totp_last_step = NULL, updated_at = ?
+        WHERE id = ?
+        "#,
+    )
+    .bind(&now_str)
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// ---- END USERS FUNCTIONS ----
+
+// ---- START ARTIFACT STORAGE FUNCTIONS ----
+
+// Get the artifact storage backend, defaulting to local disk
+pub async fn get_artifact_storage() -> Result<ArtifactStorage> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT config FROM artifact_storage WHERE id = 1")
+        .fetch_optional(pool)
+        .await?;
+    
+    match row {
+        Some(row) => Ok(serde_json::from_str(&row.get::<String, _>("config"))?),
+        None => Ok(ArtifactStorage::default()),
+    }
+}
+
+// Save the artifact storage backend
+pub async fn save_artifact_storage(storage: &ArtifactStorage) -> Result<()> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    
+    sqlx::query(
+        r#"
+        INSERT INTO artifact_storage (id, config, updated_at)
+        VALUES (1, ?, ?)
+        ON CONFLICT (id) DO UPDATE SET
+        config = excluded.config,
+        updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(serde_json::to_string(storage)?)
+    .bind(&now_str)
+    .execute(pool)
+    .await?;
+    
+    info!("Saved artifact storage settings");
+    Ok(())
+}
+
+// ---- END ARTIFACT STORAGE FUNCTIONS ----
+
+// ---- START INSTALL OUTCOME FUNCTIONS ----
+
+/// How an OS installation ended
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstallOutcome {
+    pub template_name: Option<String>,
+    pub succeeded: bool,
+    /// From the machine entering InstallingOS; unknown for outcomes recorded by older versions
+    pub duration_secs: Option<i64>,
+    pub finished_at: chrono::DateTime<Utc>,
+}
+
+// Record the end of a machine's OS installation, dropping outcomes too old to matter for
+// statistics. The machine is as it was while installing.
+#[instrument(skip_all, fields(db.system = "sqlite", otel.kind = "client"))]
+pub async fn record_install_outcome(machine: &Machine, succeeded: bool) -> Result<()> {
+    let _timer = QueryTimer::start("record_install_outcome");
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    
+    sqlx::query("INSERT INTO install_outcomes (machine_id, succeeded, finished_at, template_name, duration_secs) VALUES (?, ?, ?, ?, ?)")
+        .bind(machine.id.to_string())
+        .bind(succeeded)
+        .bind(now.to_rfc3339())
+        .bind(&machine.os_choice)
+        .bind(now.signed_duration_since(machine.updated_at).num_seconds())
+        .execute(pool)
+        .await?;
+    
+    sqlx::query("DELETE FROM install_outcomes WHERE finished_at < ?")
+        .bind((now - chrono::Duration::days(30)).to_rfc3339())
+        .execute(pool)
+        .await?;
+    
+    Ok(())
+}
+
+// Count installations that succeeded and failed since a point in time
+pub async fn count_install_outcomes(since: chrono::DateTime<Utc>) -> Result<(i64, i64)> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query(
+        r#"
+        SELECT COALESCE(SUM(succeeded), 0) AS succeeded, COALESCE(SUM(1 - succeeded), 0) AS failed
+        FROM install_outcomes WHERE finished_at >= ?
+        "#,
+    )
+    .bind(since.to_rfc3339())
+    .fetch_one(pool)
+    .await?;
+    
+    Ok((row.get("succeeded"), row.get("failed")))
+}
+
+// Installations that finished since a point in time, oldest first
+pub async fn get_install_outcomes(since: chrono::DateTime<Utc>) -> Result<Vec<InstallOutcome>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query(
+        "SELECT template_name, succeeded, duration_secs, finished_at FROM install_outcomes WHERE finished_at >= ? ORDER BY finished_at",
+    )
+    .bind(since.to_rfc3339())
+    .fetch_all(pool)
+    .await?;
+    
+    Ok(rows.iter()
+        .map(|row| InstallOutcome {
+            template_name: row.get("template_name"),
+            succeeded: row.get("succeeded"),
+            duration_secs: row.get("duration_secs"),
+            finished_at: parse_datetime(&row.get::<String, _>("finished_at")),
+        })
+        .collect())
+}
+
+// ---- END INSTALL OUTCOME FUNCTIONS ----
+
+// ---- START STATUS HISTORY FUNCTIONS ----
+
+/// The machine's status changed between checking a transition and writing it
+#[derive(Debug, thiserror::Error)]
+#[error("Machine {0} changed status while it was being updated; try again")]
+pub struct StatusConflict(pub Uuid);
+
+/// A transition that was allowed from the status as it was stored, which the write must still find
+struct CheckedTransition {
+    transition: Transition,
+    stored: String,
+}
+
+// Check a status change is allowed from the machine's current status. Returns None if the machine
+// doesn't exist, and an InvalidTransition error if the change isn't allowed.
+async fn check_transition(id: &Uuid, next: MachineStatus) -> Result<Option<CheckedTransition>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT status FROM machines WHERE id = ?")
+        .bind(id.to_string())
+        .fetch_optional(pool)
+        .await?;
+    
+    let Some(row) = row else { return Ok(None) };
+    let stored: String = row.get("status");
+    let transition = parse_status(&stored).transition_to(next)?;
+    Ok(Some(CheckedTransition { transition, stored }))
+}
+
+// Add a status change, and what caused it, to the machine's history and tell listeners about it
+async fn record_transition(id: &Uuid, from: Option<&MachineStatus>, to: &MachineStatus, cause: &StatusCause) -> Result<()> {
+    let mut conn = get_pool().await?.acquire().await?;
+    if insert_transition(&mut *conn, id, from, to, cause).await? {
+        notify_status_changed(id);
+    }
+    Ok(())
+}
+
+fn notify_status_changed(id: &Uuid) {
+    if let Ok(event_manager) = crate::EVENT_MANAGER_REF.read() {
+        if let Some(event_manager) = event_manager.as_ref() {
+            let _ = event_manager.send(format!("machine_status_changed:{}", id));
+        }
+    }
+}
+
+// False when there was nothing worth keeping. Takes a connection so a status update can keep its
+// history in the same transaction.
+pub(crate) async fn insert_transition(conn: &mut sqlx::SqliteConnection, id: &Uuid, from: Option<&MachineStatus>, to: &MachineStatus, cause: &StatusCause) -> Result<bool> {
+    // Pipeline stages don't change the status but are still worth keeping
+    if from == Some(to) && !matches!(cause, StatusCause::PipelineStage(_)) {
+        return Ok(false);
+    }
+    
+    sqlx::query("INSERT INTO status_history (machine_id, from_status, to_status, cause, detail, changed_at) VALUES (?, ?, ?, ?, ?, ?)")
+        .bind(id.to_string())
+        .bind(from.map(serde_json::to_string).transpose()?)
+        .bind(serde_json::to_string(to)?)
+        .bind(cause.kind())
+        .bind(cause.detail())
+        .bind(Utc::now().to_rfc3339())
+        .execute(conn)
+        .await?;
+    
+    Ok(true)
+}
+
+// A machine's status changes, oldest first
+pub async fn get_status_history(id: &Uuid) -> Result<Vec<StatusChange>> {
+    status_history(get_pool().await?, id).await
+}
+
+pub(crate) async fn status_history(pool: &Pool<Sqlite>, id: &Uuid) -> Result<Vec<StatusChange>> {
+    let rows = sqlx::query("SELECT id, from_status, to_status, cause, detail, changed_at FROM status_history WHERE machine_id = ? ORDER BY id")
+        .bind(id.to_string())
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().map(status_change_from_row).collect())
+}
+
+fn status_change_from_row(row: &sqlx::sqlite::SqliteRow) -> StatusChange {
+    StatusChange {
+        id: row.get("id"),
+        from: row.get::<Option<String>, _>("from_status").map(|status| parse_status(&status)),
+        to: parse_status(&row.get::<String, _>("to_status")),
+        cause: row.get::<Option<String>, _>("cause")
+            .and_then(|kind| StatusCause::from_parts(&kind, row.get("detail"))),
+        changed_at: parse_datetime(&row.get::<String, _>("changed_at")),
+    }
+}
+
+// Status changes across the fleet, newest first, optionally only those after `since`
+pub async fn get_recent_status_changes(since: Option<chrono::DateTime<Utc>>, limit: i64) -> Result<Vec<(Uuid, StatusChange)>> {
+    let pool = get_pool().await?;
+    let since = since.map(|since| since.to_rfc3339());
+    
+    let rows = sqlx::query("SELECT id, machine_id, from_status, to_status, cause, detail, changed_at FROM status_history WHERE (? IS NULL OR changed_at > ?) ORDER BY id DESC LIMIT ?")
+        .bind(&since)
+        .bind(&since)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().filter_map(|row| {
+        let machine_id = Uuid::parse_str(&row.get::<String, _>("machine_id")).ok()?;
+        Some((machine_id, status_change_from_row(row)))
+    }).collect())
+}
+
+// ---- END STATUS HISTORY FUNCTIONS ----
+
+// ---- START TPM FUNCTIONS ----
+
+// Get the TPM keys pinned for a machine, with when they were first seen
+pub async fn get_tpm_identity(machine_id: &Uuid) -> Result<Option<(TpmIdentity, chrono::DateTime<Utc>)>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT ek_public, ak_public, recorded_at FROM machine_tpm WHERE machine_id = ?")
+        .bind(machine_id.to_string())
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.map(|row| (
+        TpmIdentity { ek_public: row.get("ek_public"), ak_public: row.get("ak_public") },
+        parse_datetime(&row.get::<String, _>("recorded_at")),
+    )))
+}
+
+// Pin a machine's TPM keys; keys already pinned are never replaced
+pub async fn save_tpm_identity(machine_id: &Uuid, identity: &TpmIdentity) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query(
+        r#"
+        INSERT OR IGNORE INTO machine_tpm (machine_id, ek_public, ak_public, recorded_at)
+        VALUES (?, ?, ?, ?)
+        "#,
+    )
+    .bind(machine_id.to_string())
+    .bind(&identity.ek_public)
+    .bind(&identity.ak_public)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Forget a machine's TPM keys, e.g. after its motherboard was replaced
+pub async fn clear_tpm_identity(machine_id: &Uuid) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM machine_tpm WHERE machine_id = ?")
+        .bind(machine_id.to_string())
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// ---- END TPM FUNCTIONS ----
+
+// ---- START DISK KEY FUNCTIONS ----
+
+// Store a sealed recovery key, returning its ID
+pub async fn save_disk_key(machine_id: &Uuid, scheme: Scheme, sealed_key: &str) -> Result<i64> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("INSERT INTO disk_keys (machine_id, scheme, sealed_key, created_at) VALUES (?, ?, ?, ?)")
+        .bind(machine_id.to_string())
+        .bind(scheme.as_str())
+        .bind(sealed_key)
+        .bind(Utc::now().to_rfc3339())
+        .execute(pool)
+        .await?;
+    
+    Ok(result.last_insert_rowid())
+}
+
+// List a machine's escrowed keys, newest first, without the keys themselves
+pub async fn get_disk_keys(machine_id: &Uuid) -> Result<Vec<DiskKey>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT id, machine_id, scheme, created_at FROM disk_keys WHERE machine_id = ? ORDER BY id DESC")
+        .bind(machine_id.to_string())
+        .fetch_all(pool)
+        .await?;
+    
+    let mut keys = Vec::new();
+    for row in rows {
+        let scheme: String = row.get("scheme");
+        keys.push(DiskKey {
+            id: row.get("id"),
+            machine_id: Uuid::parse_str(&row.get::<String, _>("machine_id"))?,
+            scheme: Scheme::parse(&scheme).ok_or_else(|| anyhow!("Unknown disk key scheme '{}'", scheme))?,
+            created_at: parse_datetime(&row.get::<String, _>("created_at")),
+        });
+    }
+    Ok(keys)
+}
+
+pub async fn get_sealed_disk_key(machine_id: &Uuid, key_id: i64) -> Result<Option<String>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT sealed_key FROM disk_keys WHERE id = ? AND machine_id = ?")
+        .bind(key_id)
+        .bind(machine_id.to_string())
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.map(|row| row.get("sealed_key")))
+}
+
+pub async fn record_disk_key_access(key_id: i64, machine_id: &Uuid, revealed_by: &str, reason: &str) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query("INSERT INTO disk_key_access (key_id, machine_id, revealed_by, reason, revealed_at) VALUES (?, ?, ?, ?, ?)")
+        .bind(key_id)
+        .bind(machine_id.to_string())
+        .bind(revealed_by)
+        .bind(reason)
+        .bind(Utc::now().to_rfc3339())
+        .execute(pool)
+        .await?;
+    
+    Ok(())
+}
+
+// Audit trail of revealed keys, newest first
+pub async fn get_disk_key_accesses() -> Result<Vec<DiskKeyAccess>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT id, key_id, machine_id, revealed_by, reason, revealed_at FROM disk_key_access ORDER BY id DESC")
+        .fetch_all(pool)
+        .await?;
+    
+    let mut accesses = Vec::new();
+    for row in rows {
+        accesses.push(DiskKeyAccess {
+            id: row.get("id"),
+            key_id: row.get("key_id"),
+            machine_id: Uuid::parse_str(&row.get::<String, _>("machine_id"))?,
+            revealed_by: row.get("revealed_by"),
+            reason: row.get("reason"),
+            revealed_at: parse_datetime(&row.get::<String, _>("revealed_at")),
+        });
+    }
+    Ok(accesses)
+}
+
+// ---- END DISK KEY FUNCTIONS ----
+
+// ---- START CUSTOM FIELD FUNCTIONS ----
+
+// List custom field definitions in the order they were created
+pub async fn get_custom_fields() -> Result<Vec<FieldDefinition>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT name, label, field_type, show_in_list FROM custom_fields ORDER BY created_at, name")
+        .fetch_all(pool)
+        .await?;
+    
+    let mut fields = Vec::new();
+    for row in rows {
+        let field_type: String = row.get("field_type");
+        fields.push(FieldDefinition {
+            name: row.get("name"),
+            label: row.get("label"),
+            field_type: FieldType::parse(&field_type).ok_or_else(|| anyhow!("Unknown custom field type '{}'", field_type))?,
+            show_in_list: row.get::<i64, _>("show_in_list") != 0,
+        });
+    }
+    Ok(fields)
+}
+
+// Create a custom field, or update its label and list visibility. A field's type can't change
+// once machines may have values for it.
+pub async fn save_custom_field(field: &FieldDefinition) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query(
+        r#"
+        INSERT INTO custom_fields (name, label, field_type, show_in_list, created_at)
+        VALUES (?, ?, ?, ?, ?)
+        ON CONFLICT (name) DO UPDATE SET
+        label = excluded.label,
+        show_in_list = excluded.show_in_list
+        WHERE custom_fields.field_type = excluded.field_type
+        "#,
+    )
+    .bind(&field.name)
+    .bind(&field.label)
+    .bind(field.field_type.as_str())
+    .bind(field.show_in_list as i64)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Delete a custom field along with every machine's value for it
+pub async fn delete_custom_field(name: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    let mut tx = pool.begin().await?;
+    
+    sqlx::query("DELETE FROM machine_field_values WHERE field = ?")
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+    
+    let result = sqlx::query("DELETE FROM custom_fields WHERE name = ?")
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Get one machine's custom field values
+pub async fn get_machine_field_values(machine_id: &Uuid) -> Result<HashMap<String, serde_json::Value>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT field, value FROM machine_field_values WHERE machine_id = ?")
+        .bind(machine_id.to_string())
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter()
+        .map(|row| Ok((row.get("field"), serde_json::from_str(&row.get::<String, _>("value"))?)))
+        .collect()
+}
+
+// Get every machine's custom field values, by machine
+pub async fn get_all_field_values() -> Result<HashMap<Uuid, HashMap<String, serde_json::Value>>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT machine_id, field, value FROM machine_field_values")
+        .fetch_all(pool)
+        .await?;
+    
+    let mut values: HashMap<Uuid, HashMap<String, serde_json::Value>> = HashMap::new();
+    for row in rows {
+        let machine_id = Uuid::parse_str(&row.get::<String, _>("machine_id"))?;
+        let value = serde_json::from_str(&row.get::<String, _>("value"))?;
+        values.entry(machine_id).or_default().insert(row.get("field"), value);
+    }
+    Ok(values)
+}
+
+// Set a machine's custom field values; None clears a field
+pub async fn set_machine_field_values(machine_id: &Uuid, values: &[(String, Option<serde_json::Value>)]) -> Result<()> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    let mut tx = pool.begin().await?;
+    
+    for (field, value) in values {
+        match value {
+            Some(value) => {
+                sqlx::query(
+                    r#"
+                    INSERT INTO machine_field_values (machine_id, field, value, updated_at)
+                    VALUES (?, ?, ?, ?)
+                    ON CONFLICT (machine_id, field) DO UPDATE SET
+                    value = excluded.value,
+                    updated_at = excluded.updated_at
+                    "#,
+                )
+                .bind(machine_id.to_string())
+                .bind(field)
+                .bind(serde_json::to_string(value)?)
+                .bind(&now_str)
+                .execute(&mut *tx)
+                .await?;
+            },
+            None => {
+                sqlx::query("DELETE FROM machine_field_values WHERE machine_id = ? AND field = ?")
+                    .bind(machine_id.to_string())
+                    .bind(field)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+// ---- END CUSTOM FIELD FUNCTIONS ----
+
+// ---- START TOPOLOGY FUNCTIONS ----
+
+pub async fn get_sites() -> Result<Vec<Site>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT name, description FROM sites ORDER BY name")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().map(|row| Site { name: row.get("name"), description: row.get("description") }).collect())
+}
+
+pub async fn save_site(site: &Site) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO sites (name, description) VALUES (?, ?)
+        ON CONFLICT (name) DO UPDATE SET description = excluded.description
+        "#,
+    )
+    .bind(&site.name)
+    .bind(&site.description)
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+// Delete a site; callers check it has no racks left
+pub async fn delete_site(name: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM sites WHERE name = ?")
+        .bind(name)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_racks() -> Result<Vec<Rack>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT name, site, height_u FROM racks ORDER BY site, name")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().map(|row| Rack {
+        name: row.get("name"),
+        site: row.get("site"),
+        height_u: row.get::<i64, _>("height_u") as u32,
+    }).collect())
+}
+
+pub async fn get_rack(name: &str) -> Result<Option<Rack>> {
+    Ok(get_racks().await?.into_iter().find(|rack| rack.name == name))
+}
+
+pub async fn save_rack(rack: &Rack) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO racks (name, site, height_u) VALUES (?, ?, ?)
+        ON CONFLICT (name) DO UPDATE SET site = excluded.site, height_u = excluded.height_u
+        "#,
+    )
+    .bind(&rack.name)
+    .bind(&rack.site)
+    .bind(rack.height_u as i64)
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+// Delete a rack; callers check no machines are placed in it
+pub async fn delete_rack(name: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM racks WHERE name = ?")
+        .bind(name)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_placements() -> Result<Vec<Placement>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT machine_id, rack, position_u, height_u FROM machine_placements")
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter()
+        .map(|row| Ok(Placement {
+            machine_id: Uuid::parse_str(&row.get::<String, _>("machine_id"))?,
+            rack: row.get("rack"),
+            position_u: row.get::<i64, _>("position_u") as u32,
+            height_u: row.get::<i64, _>("height_u") as u32,
+        }))
+        .collect()
+}
+
+pub async fn set_placement(placement: &Placement) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO machine_placements (machine_id, rack, position_u, height_u) VALUES (?, ?, ?, ?)
+        ON CONFLICT (machine_id) DO UPDATE SET
+        rack = excluded.rack,
+        position_u = excluded.position_u,
+        height_u = excluded.height_u
+        "#,
+    )
+    .bind(placement.machine_id.to_string())
+    .bind(&placement.rack)
+    .bind(placement.position_u as i64)
+    .bind(placement.height_u as i64)
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+pub async fn clear_placement(machine_id: &Uuid) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM machine_placements WHERE machine_id = ?")
+        .bind(machine_id.to_string())
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// ---- END TOPOLOGY FUNCTIONS ----
+
+// ---- START BMC JOB FUNCTIONS ----
+
+fn bmc_job_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<BmcJob> {
+    let state: String = row.get("state");
+    Ok(BmcJob {
+        id: row.get("id"),
+        machine_id: Uuid::parse_str(&row.get::<String, _>("machine_id"))?,
+        tasks: serde_json::from_str(&row.get::<String, _>("tasks"))?,
+        backend: Backend::parse(&row.get::<String, _>("backend")).ok_or_else(|| anyhow!("Unknown BMC backend"))?,
+        job_name: row.get("job_name"),
+        state: JobState::parse(&state).ok_or_else(|| anyhow!("Unknown BMC job state '{}'", state))?,
+        message: row.get("message"),
+        requested_by: row.get("requested_by"),
+        created_at: parse_datetime(&row.get::<String, _>("created_at")),
+        finished_at: row.get::<Option<String>, _>("finished_at").map(|t| parse_datetime(&t)),
+    })
+}
+
+// Record a BMC job that has just started
+pub async fn create_bmc_job(machine_id: &Uuid, tasks: &[BmcTask], backend: Backend, job_name: Option<&str>, requested_by: &str) -> Result<BmcJob> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query(
+        r#"
+        INSERT INTO bmc_jobs (machine_id, tasks, backend, job_name, state, requested_by, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        RETURNING *
+        "#,
+    )
+    .bind(machine_id.to_string())
+    .bind(serde_json::to_string(tasks)?)
+    .bind(backend.as_str())
+    .bind(job_name)
+    .bind(JobState::Running.as_str())
+    .bind(requested_by)
+    .bind(Utc::now().to_rfc3339())
+    .fetch_one(pool)
+    .await?;
+    
+    bmc_job_from_row(&row)
+}
+
+pub async fn finish_bmc_job(id: i64, state: JobState, message: Option<&str>) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query("UPDATE bmc_jobs SET state = ?, message = ?, finished_at = ? WHERE id = ?")
+        .bind(state.as_str())
+        .bind(message)
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(())
+}
+
+// A machine's most recent BMC jobs, newest first
+pub async fn get_bmc_jobs(machine_id: &Uuid, limit: i64) -> Result<Vec<BmcJob>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM bmc_jobs WHERE machine_id = ? ORDER BY id DESC LIMIT ?")
+        .bind(machine_id.to_string())
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(bmc_job_from_row).collect()
+}
+
+// ---- END BMC JOB FUNCTIONS ----
+
+// ---- START TEMPLATE VARIABLE FUNCTIONS ----
+
+// Values are returned as stored; secret values are still sealed
+pub async fn get_template_variables() -> Result<Vec<TemplateVariable>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT name, value, secret FROM template_variables ORDER BY name")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().map(|row| TemplateVariable {
+        name: row.get("name"),
+        value: row.get("value"),
+        secret: row.get("secret"),
+    }).collect())
+}
+
+// A variable's stored value and whether it is secret
+pub async fn get_template_variable(name: &str) -> Result<Option<(String, bool)>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT value, secret FROM template_variables WHERE name = ?")
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.map(|row| (row.get("value"), row.get("secret"))))
+}
+
+pub async fn save_template_variable(name: &str, value: &str, secret: bool) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO template_variables (name, value, secret, updated_at) VALUES (?, ?, ?, ?)
+        ON CONFLICT(name) DO UPDATE SET value = excluded.value, secret = excluded.secret, updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(name)
+    .bind(value)
+    .bind(secret)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+pub async fn delete_template_variable(name: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM template_variables WHERE name = ?")
+        .bind(name)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Replace a machine's render token
+pub async fn save_render_token(machine_id: &Uuid, token_hash: &str, expires_at: chrono::DateTime<Utc>) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO render_tokens (machine_id, token_hash, expires_at) VALUES (?, ?, ?)
+        ON CONFLICT(machine_id) DO UPDATE SET token_hash = excluded.token_hash, expires_at = excluded.expires_at
+        "#,
+    )
+    .bind(machine_id.to_string())
+    .bind(token_hash)
+    .bind(expires_at.to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+// The machine holding a render token that hasn't expired
+pub async fn get_render_token_machine(token_hash: &str, now: chrono::DateTime<Utc>) -> Result<Option<Uuid>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT machine_id FROM render_tokens WHERE token_hash = ? AND expires_at > ?")
+        .bind(token_hash)
+        .bind(now.to_rfc3339())
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.and_then(|row| Uuid::parse_str(&row.get::<String, _>("machine_id")).ok()))
+}
+
+// ---- END TEMPLATE VARIABLE FUNCTIONS ----
+
+// ---- START ROOT PASSWORD FUNCTIONS ----
+
+// Make a new password the machine's only one
+pub async fn replace_root_password(machine_id: &Uuid, sealed_password: &str) -> Result<()> {
+    let pool = get_pool().await?;
+    let mut tx = pool.begin().await?;
+    
+    sqlx::query("DELETE FROM root_passwords WHERE machine_id = ?")
+        .bind(machine_id.to_string())
+        .execute(&mut *tx)
+        .await?;
+    
+    sqlx::query("INSERT INTO root_passwords (machine_id, sealed_password, active, created_at) VALUES (?, ?, 1, ?)")
+        .bind(machine_id.to_string())
+        .bind(sealed_password)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&mut *tx)
+        .await?;
+    
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn get_sealed_root_password(machine_id: &Uuid) -> Result<Option<String>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT sealed_password FROM root_passwords WHERE machine_id = ? AND active = 1")
+        .bind(machine_id.to_string())
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.map(|row| row.get("sealed_password")))
+}
+
+pub async fn get_root_password_info(machine_id: &Uuid) -> Result<Option<RootPasswordInfo>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query(
+        r#"
+        SELECT created_at, revealed_by, revealed_at,
+               EXISTS (SELECT 1 FROM root_passwords p WHERE p.machine_id = r.machine_id AND p.active = 0) AS rotation_pending
+        FROM root_passwords r WHERE machine_id = ? AND active = 1
+        "#,
+    )
+    .bind(machine_id.to_string())
+    .fetch_optional(pool)
+    .await?;
+    
+    Ok(row.map(|row| RootPasswordInfo {
+        machine_id: *machine_id,
+        created_at: parse_datetime(&row.get::<String, _>("created_at")),
+        revealed_by: row.get("revealed_by"),
+        revealed_at: row.get::<Option<String>, _>("revealed_at").map(|t| parse_datetime(&t)),
+        rotation_pending: row.get("rotation_pending"),
+    }))
+}
+
+// Record who saw the active password, unless someone already has
+pub async fn mark_root_password_revealed(machine_id: &Uuid, revealed_by: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("UPDATE root_passwords SET revealed_by = ?, revealed_at = ? WHERE machine_id = ? AND active = 1 AND revealed_by IS NULL")
+        .bind(revealed_by)
+        .bind(Utc::now().to_rfc3339())
+        .bind(machine_id.to_string())
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Store the password a rotation command is setting, replacing any earlier pending one
+pub async fn save_pending_root_password(machine_id: &Uuid, command_id: i64, sealed_password: &str) -> Result<()> {
+    let pool = get_pool().await?;
+    let mut tx = pool.begin().await?;
+    
+    sqlx::query("DELETE FROM root_passwords WHERE machine_id = ? AND active = 0")
+        .bind(machine_id.to_string())
+        .execute(&mut *tx)
+        .await?;
+    
+    sqlx::query("INSERT INTO root_passwords (machine_id, sealed_password, active, command_id, created_at) VALUES (?, ?, 0, ?, ?)")
+        .bind(machine_id.to_string())
+        .bind(sealed_password)
+        .bind(command_id)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&mut *tx)
+        .await?;
+    
+    tx.commit().await?;
+    Ok(())
+}
+
+// Promote a rotation's pending password on success, or drop it on failure
+pub async fn finish_root_password_rotation(machine_id: &Uuid, command_id: i64, succeeded: bool) -> Result<()> {
+    let pool = get_pool().await?;
+    let mut tx = pool.begin().await?;
+    
+    let pending = sqlx::query("SELECT id FROM root_passwords WHERE machine_id = ? AND command_id = ? AND active = 0")
+        .bind(machine_id.to_string())
+        .bind(command_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    let Some(pending) = pending else {
+        return Ok(());
+    };
+    let pending_id: i64 = pending.get("id");
+    
+    if succeeded {
+        sqlx::query("DELETE FROM root_passwords WHERE machine_id = ? AND id != ?")
+            .bind(machine_id.to_string())
+            .bind(pending_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE root_passwords SET active = 1, created_at = ? WHERE id = ?")
+            .bind(Utc::now().to_rfc3339())
+            .bind(pending_id)
+            .execute(&mut *tx)
+            .await?;
+    } else {
+        sqlx::query("DELETE FROM root_passwords WHERE id = ?")
+            .bind(pending_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    
+    tx.commit().await?;
+    Ok(())
+}
+
+// ---- END ROOT PASSWORD FUNCTIONS ----
+
+// ---- START OS LIFECYCLE FUNCTIONS ----
+
+pub async fn get_os_lifecycle() -> Result<Vec<OsLifecycle>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT template, eol_date FROM os_lifecycle ORDER BY template")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().filter_map(|row| {
+        let eol_date: String = row.get("eol_date");
+        Some(OsLifecycle {
+            template: row.get("template"),
+            eol_date: chrono::NaiveDate::parse_from_str(&eol_date, "%Y-%m-%d").ok()?,
+        })
+    }).collect())
+}
+
+pub async fn save_os_lifecycle(entry: &OsLifecycle) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO os_lifecycle (template, eol_date, updated_at) VALUES (?, ?, ?)
+        ON CONFLICT(template) DO UPDATE SET eol_date = excluded.eol_date, updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(&entry.template)
+    .bind(entry.eol_date.format("%Y-%m-%d").to_string())
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+pub async fn delete_os_lifecycle(template: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM os_lifecycle WHERE template = ?")
+        .bind(template)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// ---- END OS LIFECYCLE FUNCTIONS ----
+
+// ---- START MACHINE CLAIM FUNCTIONS ----
+
+pub async fn get_machine_claim(machine_id: &Uuid) -> Result<Option<Claim>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT claim_key, claimed_by, claimed_at FROM machine_claims WHERE machine_id = ?")
+        .bind(machine_id.to_string())
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.map(|row| Claim {
+        key: row.get("claim_key"),
+        claimed_by: row.get("claimed_by"),
+        claimed_at: parse_datetime(&row.get::<String, _>("claimed_at")),
+    }))
+}
+
+// The machine held by a claim key
+pub async fn get_claimed_machine(claim_key: &str) -> Result<Option<Uuid>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT machine_id FROM machine_claims WHERE claim_key = ?")
+        .bind(claim_key)
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.and_then(|row| Uuid::parse_str(&row.get::<String, _>("machine_id")).ok()))
+}
+
+pub async fn get_claimed_machine_ids() -> Result<Vec<Uuid>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT machine_id FROM machine_claims")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().filter_map(|row| Uuid::parse_str(&row.get::<String, _>("machine_id")).ok()).collect())
+}
+
+pub async fn save_machine_claim(machine_id: &Uuid, claim: &Claim) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query("INSERT INTO machine_claims (machine_id, claim_key, claimed_by, claimed_at) VALUES (?, ?, ?, ?)")
+        .bind(machine_id.to_string())
+        .bind(&claim.key)
+        .bind(&claim.claimed_by)
+        .bind(claim.claimed_at.to_rfc3339())
+        .execute(pool)
+        .await?;
+    
+    Ok(())
+}
+
+pub async fn delete_machine_claim(machine_id: &Uuid) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM machine_claims WHERE machine_id = ?")
+        .bind(machine_id.to_string())
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// ---- END MACHINE CLAIM FUNCTIONS ----
+
+// ---- START BATCH FUNCTIONS ----
+
+// Apply writes in one transaction. On failure nothing is kept, and the error names the write that failed.
+pub async fn apply_batch(writes: &[Write]) -> std::result::Result<(), (usize, anyhow::Error)> {
+    let pool = get_pool().await.map_err(|e| (0, e))?;
+    let mut tx = pool.begin().await.map_err(|e| (0, e.into()))?;
+    let now_str = Utc::now().to_rfc3339();
+    
+    for (index, write) in writes.iter().enumerate() {
+        apply_batch_write(&mut tx, write, &now_str).await.map_err(|e| (index, e))?;
+    }
+    tx.commit().await.map_err(|e| (writes.len().saturating_sub(1), e.into()))?;
+    Ok(())
+}
+
+async fn apply_batch_write(tx: &mut sqlx::Transaction<'_, Sqlite>, write: &Write, now_str: &str) -> Result<()> {
+    let machine_id = write.machine_id().to_string();
+    let (column, value) = match write {
+        Write::Hostname(_, hostname) => ("hostname", hostname),
+        Write::IpAddress(_, ip_address) => ("ip_address", ip_address),
+        Write::Fields(_, values) => {
+            for (field, value) in values {
+                match value {
+                    Some(value) => {
+                        sqlx::query(
+                            r#"
+                            INSERT INTO machine_field_values (machine_id, field, value, updated_at)
+                            VALUES (?, ?, ?, ?)
+                            ON CONFLICT (machine_id, field) DO UPDATE SET
+                            value = excluded.value,
+                            updated_at = excluded.updated_at
+                            "#,
+                        )
+                        .bind(&machine_id)
+                        .bind(field)
+                        .bind(serde_json::to_string(value)?)
+                        .bind(now_str)
+                        .execute(&mut **tx)
+                        .await?;
+                    },
+                    None => {
+                        sqlx::query("DELETE FROM machine_field_values WHERE machine_id = ? AND field = ?")
+                            .bind(&machine_id)
+                            .bind(field)
+                            .execute(&mut **tx)
+                            .await?;
+                    }
+                }
+            }
+            return Ok(());
+        },
+    };
+    
+    // The column comes from the match above, never from the request
+    let result = sqlx::query(&format!("UPDATE machines SET {} = ?, updated_at = ? WHERE id = ?", column))
+        .bind(value)
+        .bind(now_str)
+        .bind(&machine_id)
+        .execute(&mut **tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(anyhow!("Machine {} not found", machine_id));
+    }
+    Ok(())
+}
+
+// ---- END BATCH FUNCTIONS ----
+
+// ---- START SEARCH AND REPLACE FUNCTIONS ----
+
+// Write every edit of a search and replace and its audit entry in one transaction, returning the entry's ID
+pub async fn apply_replacements(edits: &[Edit], actor: &str, find: &str, replacement: &str, changes: &[Change]) -> Result<i64> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    let mut tx = pool.begin().await?;
+    
+    for edit in edits {
+        match edit {
+            Edit::TemplateVariable { name, value, secret } => {
+                sqlx::query("UPDATE template_variables SET value = ?, secret = ?, updated_at = ? WHERE name = ?")
+                    .bind(value)
+                    .bind(secret)
+                    .bind(&now_str)
+                    .bind(name)
+                    .execute(&mut *tx)
+                    .await?;
+            },
+            Edit::IpxeTemplate { name, content } => {
+                sqlx::query("UPDATE ipxe_templates SET content = ?, updated_at = ? WHERE name = ?")
+                    .bind(content)
+                    .bind(&now_str)
+                    .bind(name)
+                    .execute(&mut *tx)
+                    .await?;
+            },
+            Edit::MachineField { machine_id, field, value } => {
+                sqlx::query("UPDATE machine_field_values SET value = ?, updated_at = ? WHERE machine_id = ? AND field = ?")
+                    .bind(serde_json::to_string(value)?)
+                    .bind(&now_str)
+                    .bind(machine_id.to_string())
+                    .bind(field)
+                    .execute(&mut *tx)
+                    .await?;
+            },
+        }
+    }
+    
+    let result = sqlx::query("INSERT INTO replace_audit (actor, find, replacement, changes, created_at) VALUES (?, ?, ?, ?, ?)")
+        .bind(actor)
+        .bind(find)
+        .bind(replacement)
+        .bind(serde_json::to_string(changes)?)
+        .bind(&now_str)
+        .execute(&mut *tx)
+        .await?;
+    
+    tx.commit().await?;
+    Ok(result.last_insert_rowid())
+}
+
+// The search and replace audit log, newest first
+pub async fn get_replace_audit() -> Result<Vec<ReplaceAudit>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT id, actor, find, replacement, changes, created_at FROM replace_audit ORDER BY id DESC")
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(|row| Ok(ReplaceAudit {
+        id: row.get("id"),
+        actor: row.get("actor"),
+        find: row.get("find"),
+        replace: row.get("replacement"),
+        changes: serde_json::from_str(&row.get::<String, _>("changes"))?,
+        created_at: parse_datetime(&row.get::<String, _>("created_at")),
+    })).collect()
+}
+
+// ---- END SEARCH AND REPLACE FUNCTIONS ----
+
+// ---- START PROJECT FUNCTIONS ----
+
+// Get the project an OS template is restricted to, if any
+pub async fn get_template_scope(template: &str) -> Result<Option<String>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT project FROM template_scopes WHERE template = ?")
+        .bind(template)
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.map(|row| row.get("project")))
+}
+
+// List all OS template restrictions
+pub async fn get_template_scopes() -> Result<Vec<TemplateScope>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT template, project FROM template_scopes ORDER BY template")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().map(|row| TemplateScope {
+        template: row.get("template"),
+        project: row.get("project"),
+    }).collect())
+}
+
+// Restrict an OS template to a project
+pub async fn save_template_scope(scope: &TemplateScope) -> Result<()> {
+    let pool = get_pool().await?;
+    let now_str = Utc::now().to_rfc3339();
+    
+    sqlx::query(
+        r#"
+        INSERT INTO template_scopes (template, project, updated_at)
+        VALUES (?, ?, ?)
+        ON CONFLICT (template) DO UPDATE SET
+        project = excluded.project,
+        updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(&scope.template)
+    .bind(&scope.project)
+    .bind(&now_str)
+    .execute(pool)
+    .await?;
+    
+    info!("OS template '{}' restricted to project '{}'", scope.template, scope.project);
+    Ok(())
+}
+
+// Make an OS template available to every machine again
+pub async fn delete_template_scope(template: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM template_scopes WHERE template = ?")
+        .bind(template)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// ---- END PROJECT FUNCTIONS ----
+
+// ---- START TAGS FUNCTIONS ----
+
+// STUB: Get machine tags
+pub async fn get_machine_tags(id: &Uuid) -> Result<Vec<String>> {
+    info!("STUB: Called get_machine_tags for machine {}", id);
+    // TODO: Implement database logic to fetch tags for the given machine ID.
+    // This will likely require schema changes (e.g., a separate machine_tags table or a tags column in machines).
+    Ok(vec!["stub_tag".to_string()]) // Return dummy data for now
+}
+
+// STUB: Update machine tags
+pub async fn update_machine_tags(id: &Uuid, tags: &[String]) -> Result<bool> {
+    info!("STUB: Called update_machine_tags for machine {} with tags: {:?}", id, tags);
+    // TODO: Implement database logic to update tags for the given machine ID.
+    // This will likely involve deleting existing tags and inserting the new ones.
+    // Requires schema changes.
+    Ok(true) // Assume success for now
+}
+
+// ---- END TAGS FUNCTIONS ----
+
+// ---- START FREEZE FUNCTIONS ----
+
+fn freeze_record_from_row(row: &sqlx::sqlite::SqliteRow) -> FreezeRecord {
+    FreezeRecord {
+        id: row.get("id"),
+        frozen: row.get("frozen"),
+        actor: row.get("actor"),
+        reason: row.get("reason"),
+        created_at: parse_datetime(&row.get::<String, _>("created_at")),
+    }
+}
+
+// Record a freeze or unfreeze
+pub async fn add_freeze_record(frozen: bool, actor: &str, reason: Option<&str>) -> Result<FreezeRecord> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    
+    let result = sqlx::query("INSERT INTO freeze_audit (frozen, actor, reason, created_at) VALUES (?, ?, ?, ?)")
+        .bind(frozen)
+        .bind(actor)
+        .bind(reason)
+        .bind(now.to_rfc3339())
+        .execute(pool)
+        .await?;
+    
+    Ok(FreezeRecord {
+        id: result.last_insert_rowid(),
+        frozen,
+        actor: actor.to_string(),
+        reason: reason.map(str::to_string),
+        created_at: now,
+    })
+}
+
+// The most recent freeze or unfreeze, if provisioning was ever frozen
+pub async fn get_latest_freeze_record() -> Result<Option<FreezeRecord>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT id, frozen, actor, reason, created_at FROM freeze_audit ORDER BY id DESC LIMIT 1")
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.as_ref().map(freeze_record_from_row))
+}
+
+// Every freeze and unfreeze, newest first
+pub async fn get_freeze_records() -> Result<Vec<FreezeRecord>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT id, frozen, actor, reason, created_at FROM freeze_audit ORDER BY id DESC")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().map(freeze_record_from_row).collect())
+}
+
+// ---- END FREEZE FUNCTIONS ----
+
+// ---- START WORKFLOW TEMPLATE FUNCTIONS ----
+
+fn template_version_from_row(row: &sqlx::sqlite::SqliteRow) -> TemplateVersion {
+    TemplateVersion {
+        name: row.get("name"),
+        version: row.get("version"),
+        content: row.get("content"),
+        created_by: row.get("created_by"),
+        created_at: parse_datetime(&row.get::<String, _>("created_at")),
+    }
+}
+
+// Every stored version of a template, newest first
+pub async fn get_workflow_template_versions(name: &str) -> Result<Vec<TemplateVersion>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query(
+        "SELECT name, version, content, created_by, created_at FROM workflow_template_versions WHERE name = ? ORDER BY version DESC",
+    )
+    .bind(name)
+    .fetch_all(pool)
+    .await?;
+    
+    Ok(rows.iter().map(template_version_from_row).collect())
+}
+
+pub async fn get_workflow_template_version(name: &str, version: i64) -> Result<Option<TemplateVersion>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query(
+        "SELECT name, version, content, created_by, created_at FROM workflow_template_versions WHERE name = ? AND version = ?",
+    )
+    .bind(name)
+    .bind(version)
+    .fetch_optional(pool)
+    .await?;
+    
+    Ok(row.as_ref().map(template_version_from_row))
+}
+
+// The newest version of every stored template
+pub async fn get_latest_workflow_templates() -> Result<Vec<TemplateVersion>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query(
+        r#"
+        SELECT name, version, content, created_by, created_at FROM workflow_template_versions v
+        WHERE version = (SELECT MAX(version) FROM workflow_template_versions WHERE name = v.name)
+        ORDER BY name
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    
+    Ok(rows.iter().map(template_version_from_row).collect())
+}
+
+// Store a template as the next version after its newest one
+pub async fn add_workflow_template_version(name: &str, content: &str, created_by: &str) -> Result<TemplateVersion> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let mut tx = pool.begin().await?;
+    
+    let version: i64 = sqlx::query("SELECT COALESCE(MAX(version), 0) + 1 FROM workflow_template_versions WHERE name = ?")
+        .bind(name)
+        .fetch_one(&mut *tx)
+        .await?
+        .get(0);
+    sqlx::query("INSERT INTO workflow_template_versions (name, version, content, created_by, created_at) VALUES (?, ?, ?, ?, ?)")
+        .bind(name)
+        .bind(version)
+        .bind(content)
+        .bind(created_by)
+        .bind(now.to_rfc3339())
+        .execute(&mut *tx)
+        .await?;
+    
+    tx.commit().await?;
+    Ok(TemplateVersion {
+        name: name.to_string(),
+        version,
+        content: content.to_string(),
+        created_by: created_by.to_string(),
+        created_at: now,
+    })
+}
+
+// Delete a template and all its versions
+pub async fn delete_workflow_template(name: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM workflow_template_versions WHERE name = ?")
+        .bind(name)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// ---- END WORKFLOW TEMPLATE FUNCTIONS ----
+
+// ---- START PIPELINE FUNCTIONS ----
+
+fn pipeline_from_row(row: &sqlx::sqlite::SqliteRow) -> Pipeline {
+    Pipeline {
+        name: row.get("name"),
+        stages: serde_json::from_str(&row.get::<String, _>("stages")).unwrap_or_default(),
+    }
+}
+
+pub async fn get_pipelines() -> Result<Vec<Pipeline>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT name, stages FROM pipelines ORDER BY name")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().map(pipeline_from_row).collect())
+}
+
+pub async fn get_pipeline(name: &str) -> Result<Option<Pipeline>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT name, stages FROM pipelines WHERE name = ?")
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.as_ref().map(pipeline_from_row))
+}
+
+pub async fn save_pipeline(pipeline: &Pipeline) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO pipelines (name, stages, updated_at) VALUES (?, ?, ?)
+        ON CONFLICT(name) DO UPDATE SET stages = excluded.stages, updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(&pipeline.name)
+    .bind(serde_json::to_string(&pipeline.stages)?)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+pub async fn delete_pipeline(name: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM pipelines WHERE name = ?")
+        .bind(name)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_pipeline_run(machine_id: &Uuid) -> Result<Option<PipelineRun>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query(
+        "SELECT pipeline, stages, current_stage, state, error, started_at, updated_at FROM pipeline_runs WHERE machine_id = ?",
+    )
+    .bind(machine_id.to_string())
+    .fetch_optional(pool)
+    .await?;
+    
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let stages: Vec<String> = serde_json::from_str(&row.get::<String, _>("stages"))?;
+    let current_stage = row.get::<i64, _>("current_stage") as usize;
+    let Some(state) = RunState::parse(&row.get::<String, _>("state")) else {
+        return Err(anyhow!("Unknown pipeline run state for machine {}", machine_id));
+    };
+    if current_stage >= stages.len() {
+        return Err(anyhow!("Pipeline run for machine {} is past its last stage", machine_id));
+    }
+    Ok(Some(PipelineRun {
+        machine_id: *machine_id,
+        pipeline: row.get("pipeline"),
+        stages,
+        current_stage,
+        state,
+        error: row.get("error"),
+        started_at: parse_datetime(&row.get::<String, _>("started_at")),
+        updated_at: parse_datetime(&row.get::<String, _>("updated_at")),
+    }))
+}
+
+// Store a machine's run, replacing any earlier one
+pub async fn save_pipeline_run(run: &PipelineRun) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO pipeline_runs (machine_id, pipeline, stages, current_stage, state, error, started_at, updated_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT(machine_id) DO UPDATE SET
+            pipeline = excluded.pipeline, stages = excluded.stages, current_stage = excluded.current_stage,
+            state = excluded.state, error = excluded.error, started_at = excluded.started_at, updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(run.machine_id.to_string())
+    .bind(&run.pipeline)
+    .bind(serde_json::to_string(&run.stages)?)
+    .bind(run.current_stage as i64)
+    .bind(run.state.as_str())
+    .bind(&run.error)
+    .bind(run.started_at.to_rfc3339())
+    .bind(run.updated_at.to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+// Move a running pipeline on from `from_stage`, unless something else already has
+pub async fn advance_pipeline_run(machine_id: &Uuid, from_stage: usize, to_stage: usize, state: RunState) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query(
+        "UPDATE pipeline_runs SET current_stage = ?, state = ?, updated_at = ? WHERE machine_id = ? AND current_stage = ? AND state = 'running'",
+    )
+    .bind(to_stage as i64)
+    .bind(state.as_str())
+    .bind(Utc::now().to_rfc3339())
+    .bind(machine_id.to_string())
+    .bind(from_stage as i64)
+    .execute(pool)
+    .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn delete_pipeline_run(machine_id: &Uuid) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM pipeline_runs WHERE machine_id = ?")
+        .bind(machine_id.to_string())
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// ---- END PIPELINE FUNCTIONS ----
+
+// ---- START FIRMWARE FUNCTIONS ----
+
+fn firmware_image_from_row(row: &sqlx::sqlite::SqliteRow) -> Option<FirmwareImage> {
+    Some(FirmwareImage {
+        id: row.get("id"),
+        vendor: row.get("vendor"),
+        model: row.get("model"),
+        component: Component::parse(&row.get::<String, _>("component"))?,
+        version: row.get("version"),
+        url: row.get("url"),
+        sha256: row.get("sha256"),
+        size_bytes: row.get("size_bytes"),
+        created_by: row.get("created_by"),
+        created_at: parse_datetime(&row.get::<String, _>("created_at")),
+    })
+}
+
+pub async fn get_firmware_images() -> Result<Vec<FirmwareImage>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM firmware_images ORDER BY vendor, model, component, created_at DESC")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().filter_map(firmware_image_from_row).collect())
+}
+
+pub async fn get_firmware_image(id: i64) -> Result<Option<FirmwareImage>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT * FROM firmware_images WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.as_ref().and_then(firmware_image_from_row))
+}
+
+pub async fn add_firmware_image(image: &NewFirmwareImage, created_by: &str) -> Result<FirmwareImage> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    
+    let result = sqlx::query(
+        r#"
+        INSERT INTO firmware_images (vendor, model, component, version, url, sha256, created_by, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(image.vendor.trim())
+    .bind(image.model.trim())
+    .bind(image.component.as_str())
+    .bind(image.version.trim())
+    .bind(&image.url)
+    .bind(image.sha256.as_ref().map(|sha| sha.to_lowercase()))
+    .bind(created_by)
+    .bind(now.to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    get_firmware_image(result.last_insert_rowid()).await?
+        .ok_or_else(|| anyhow!("Firmware image disappeared after being added"))
+}
+
+// Record an uploaded binary's checksum and size
+pub async fn set_firmware_binary(id: i64, sha256: &str, size_bytes: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("UPDATE firmware_images SET sha256 = ?, size_bytes = ? WHERE id = ?")
+        .bind(sha256)
+        .bind(size_bytes)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn delete_firmware_image(id: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM firmware_images WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_machine_firmware(machine_id: &Uuid) -> Result<Vec<MachineFirmware>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM machine_firmware WHERE machine_id = ? ORDER BY component, device")
+        .bind(machine_id.to_string())
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().filter_map(|row| Some(MachineFirmware {
+        machine_id: *machine_id,
+        component: Component::parse(&row.get::<String, _>("component"))?,
+        device: row.get("device"),
+        vendor: row.get("vendor"),
+        model: row.get("model"),
+        version: row.get("version"),
+        previous_version: row.get("previous_version"),
+        message: row.get("message"),
+        updated_at: parse_datetime(&row.get::<String, _>("updated_at")),
+    })).collect())
+}
+
+// Store a reported firmware version. A version that changed without a flash result to say so
+// (e.g. someone flashed it by hand) still keeps the one it replaced.
+pub async fn record_machine_firmware(firmware: &MachineFirmware) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO machine_firmware (machine_id, component, device, vendor, model, version, previous_version, message, updated_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT(machine_id, component, device) DO UPDATE SET
+            vendor = CASE WHEN excluded.vendor = '' THEN machine_firmware.vendor ELSE excluded.vendor END,
+            model = CASE WHEN excluded.model = '' THEN machine_firmware.model ELSE excluded.model END,
+            previous_version = CASE
+                WHEN excluded.previous_version IS NOT NULL THEN excluded.previous_version
+                WHEN machine_firmware.version != excluded.version THEN machine_firmware.version
+                ELSE machine_firmware.previous_version
+            END,
+            version = excluded.version,
+            message = excluded.message,
+            updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(firmware.machine_id.to_string())
+    .bind(firmware.component.as_str())
+    .bind(&firmware.device)
+    .bind(&firmware.vendor)
+    .bind(&firmware.model)
+    .bind(&firmware.version)
+    .bind(&firmware.previous_version)
+    .bind(&firmware.message)
+    .bind(firmware.updated_at.to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+// ---- END FIRMWARE FUNCTIONS ----
+
+// ---- START STORAGE PROFILE FUNCTIONS ----
+
+pub async fn get_storage_profiles() -> Result<Vec<StorageProfile>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT profile FROM storage_profiles ORDER BY name")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().filter_map(|row| serde_json::from_str(&row.get::<String, _>("profile")).ok()).collect())
+}
+
+pub async fn get_storage_profile(name: &str) -> Result<Option<StorageProfile>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT profile FROM storage_profiles WHERE name = ?")
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+    
+    match row {
+        Some(row) => Ok(Some(serde_json::from_str(&row.get::<String, _>("profile"))?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn save_storage_profile(profile: &StorageProfile) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO storage_profiles (name, profile, updated_at) VALUES (?, ?, ?)
+        ON CONFLICT(name) DO UPDATE SET profile = excluded.profile, updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(&profile.name)
+    .bind(serde_json::to_string(profile)?)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+// Delete a profile; machines and templates it was attached to go back to having none
+pub async fn delete_storage_profile(name: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM storage_profiles WHERE name = ?")
+        .bind(name)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_machine_storage_profile(machine_id: &Uuid) -> Result<Option<StorageProfile>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query(
+        "SELECT p.profile FROM machine_storage_profiles m JOIN storage_profiles p ON p.name = m.profile WHERE m.machine_id = ?",
+    )
+    .bind(machine_id.to_string())
+    .fetch_optional(pool)
+    .await?;
+    
+    match row {
+        Some(row) => Ok(Some(serde_json::from_str(&row.get::<String, _>("profile"))?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn get_template_storage_profile(template: &str) -> Result<Option<StorageProfile>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query(
+        "SELECT p.profile FROM template_storage_profiles t JOIN storage_profiles p ON p.name = t.profile WHERE t.template = ?",
+    )
+    .bind(template)
+    .fetch_optional(pool)
+    .await?;
+    
+    match row {
+        Some(row) => Ok(Some(serde_json::from_str(&row.get::<String, _>("profile"))?)),
+        None => Ok(None),
+    }
+}
+
+// Attach a profile to a machine, or detach whatever it had with None
+pub async fn set_machine_storage_profile(machine_id: &Uuid, profile: Option<&str>) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    match profile {
+        Some(profile) => {
+            sqlx::query(
+                "INSERT INTO machine_storage_profiles (machine_id, profile) VALUES (?, ?) ON CONFLICT(machine_id) DO UPDATE SET profile = excluded.profile",
+            )
+            .bind(machine_id.to_string())
+            .bind(profile)
+            .execute(pool)
+            .await?;
+        },
+        None => {
+            sqlx::query("DELETE FROM machine_storage_profiles WHERE machine_id = ?")
+                .bind(machine_id.to_string())
+                .execute(pool)
+                .await?;
+        }
+    }
+    
+    Ok(())
+}
+
+// Attach a profile to a workflow template, or detach whatever it had with None
+pub async fn set_template_storage_profile(template: &str, profile: Option<&str>) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    match profile {
+        Some(profile) => {
+            sqlx::query(
+                "INSERT INTO template_storage_profiles (template, profile) VALUES (?, ?) ON CONFLICT(template) DO UPDATE SET profile = excluded.profile",
+            )
+            .bind(template)
+            .bind(profile)
+            .execute(pool)
+            .await?;
+        },
+        None => {
+            sqlx::query("DELETE FROM template_storage_profiles WHERE template = ?")
+                .bind(template)
+                .execute(pool)
+                .await?;
+        }
+    }
+    
+    Ok(())
+}
+
+// ---- END STORAGE PROFILE FUNCTIONS ----
+
+// ---- START PCI DEVICE FUNCTIONS ----
+
+fn pci_device_from_row(row: &sqlx::sqlite::SqliteRow) -> PciDevice {
+    PciDevice {
+        address: row.get("address"),
+        class: row.get("class"),
+        vendor_id: row.get("vendor_id"),
+        device_id: row.get("device_id"),
+        vendor_name: row.get("vendor_name"),
+        device_name: row.get("device_name"),
+        driver: row.get("driver"),
+        sriov_total_vfs: row.get::<Option<i64>, _>("sriov_total_vfs").map(|vfs| vfs as u32),
+    }
+}
+
+pub async fn get_pci_devices(machine_id: &Uuid) -> Result<Vec<PciDevice>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM machine_pci_devices WHERE machine_id = ? ORDER BY address")
+        .bind(machine_id.to_string())
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().map(pci_device_from_row).collect())
+}
+
+pub async fn get_all_pci_devices() -> Result<HashMap<Uuid, Vec<PciDevice>>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM machine_pci_devices ORDER BY machine_id, address")
+        .fetch_all(pool)
+        .await?;
+    
+    let mut devices: HashMap<Uuid, Vec<PciDevice>> = HashMap::new();
+    for row in rows {
+        let machine_id = Uuid::parse_str(&row.get::<String, _>("machine_id"))?;
+        devices.entry(machine_id).or_default().push(pci_device_from_row(&row));
+    }
+    Ok(devices)
+}
+
+// Replace a machine's PCI inventory with what its agent just reported
+pub async fn set_pci_devices(machine_id: &Uuid, devices: &[PciDevice]) -> Result<()> {
+    let pool = get_pool().await?;
+    let mut tx = pool.begin().await?;
+    
+    sqlx::query("DELETE FROM machine_pci_devices WHERE machine_id = ?")
+        .bind(machine_id.to_string())
+        .execute(&mut *tx)
+        .await?;
+    
+    for device in devices {
+        sqlx::query(
+            r#"
+            INSERT INTO machine_pci_devices (machine_id, address, class, vendor_id, device_id, vendor_name, device_name, driver, sriov_total_vfs)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(machine_id.to_string())
+        .bind(&device.address)
+        .bind(&device.class)
+        .bind(&device.vendor_id)
+        .bind(&device.device_id)
+        .bind(&device.vendor_name)
+        .bind(&device.device_name)
+        .bind(&device.driver)
+        .bind(device.sriov_total_vfs.map(i64::from))
+        .execute(&mut *tx)
+        .await?;
+    }
+    
+    tx.commit().await?;
+    Ok(())
+}
+
+// ---- END PCI DEVICE FUNCTIONS ----
+
+// ---- START MACHINE PROFILE FUNCTIONS ----
+
+// Profiles in the order they're matched: highest priority first
+pub async fn get_machine_profiles() -> Result<Vec<MachineProfile>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT profile FROM machine_profiles ORDER BY priority DESC, name")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().filter_map(|row| serde_json::from_str(&row.get::<String, _>("profile")).ok()).collect())
+}
+
+pub async fn get_machine_profile(name: &str) -> Result<Option<MachineProfile>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT profile FROM machine_profiles WHERE name = ?")
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+    
+    match row {
+        Some(row) => Ok(Some(serde_json::from_str(&row.get::<String, _>("profile"))?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn save_machine_profile(profile: &MachineProfile) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO machine_profiles (name, priority, profile, updated_at) VALUES (?, ?, ?, ?)
+        ON CONFLICT(name) DO UPDATE SET priority = excluded.priority, profile = excluded.profile, updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(&profile.name)
+    .bind(profile.priority)
+    .bind(serde_json::to_string(profile)?)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+// Delete a profile; machines adopted into it keep what they were given but no longer have a profile
+pub async fn delete_machine_profile(name: &str) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM machine_profiles WHERE name = ?")
+        .bind(name)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_assigned_machine_profile(machine_id: &Uuid) -> Result<Option<MachineProfile>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query(
+        "SELECT p.profile FROM machine_profile_assignments a JOIN machine_profiles p ON p.name = a.profile WHERE a.machine_id = ?",
+    )
+    .bind(machine_id.to_string())
+    .fetch_optional(pool)
+    .await?;
+    
+    match row {
+        Some(row) => Ok(Some(serde_json::from_str(&row.get::<String, _>("profile"))?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn get_machine_profile_assignments() -> Result<HashMap<Uuid, String>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT machine_id, profile FROM machine_profile_assignments")
+        .fetch_all(pool)
+        .await?;
+    
+    let mut assignments = HashMap::new();
+    for row in rows {
+        assignments.insert(Uuid::parse_str(&row.get::<String, _>("machine_id"))?, row.get("profile"));
+    }
+    Ok(assignments)
+}
+
+// Put a machine in a profile, or take it out of its profile with None
+pub async fn set_machine_profile_assignment(machine_id: &Uuid, profile: Option<&str>) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    match profile {
+        Some(profile) => {
+            sqlx::query(
+                r#"
+                INSERT INTO machine_profile_assignments (machine_id, profile, assigned_at) VALUES (?, ?, ?)
+                ON CONFLICT(machine_id) DO UPDATE SET profile = excluded.profile, assigned_at = excluded.assigned_at
+                "#,
+            )
+            .bind(machine_id.to_string())
+            .bind(profile)
+            .bind(Utc::now().to_rfc3339())
+            .execute(pool)
+            .await?;
+        }
+        None => {
+            sqlx::query("DELETE FROM machine_profile_assignments WHERE machine_id = ?")
+                .bind(machine_id.to_string())
+                .execute(pool)
+                .await?;
+        }
+    }
+    
+    Ok(())
+}
+
+// The system vendor and model a machine's agent last reported
+pub async fn get_machine_system_info(machine_id: &Uuid) -> Result<(Option<String>, Option<String>)> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT vendor, model FROM machine_system_info WHERE machine_id = ?")
+        .bind(machine_id.to_string())
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.map(|row| (row.get("vendor"), row.get("model"))).unwrap_or_default())
+}
+
+pub async fn set_machine_system_info(machine_id: &Uuid, vendor: Option<&str>, model: Option<&str>, serial: Option<&str>) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO machine_system_info (machine_id, vendor, model, serial, updated_at) VALUES (?, ?, ?, ?, ?)
+        ON CONFLICT(machine_id) DO UPDATE SET vendor = excluded.vendor, model = excluded.model, serial = excluded.serial, updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(machine_id.to_string())
+    .bind(vendor)
+    .bind(model)
+    .bind(serial)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+// ---- END MACHINE PROFILE FUNCTIONS ----
+
+// ---- START SAVED VIEW FUNCTIONS ----
+
+fn saved_view_from_row(row: &sqlx::sqlite::SqliteRow) -> SavedView {
+    SavedView {
+        id: row.get("id"),
+        owner: row.get("owner"),
+        name: row.get("name"),
+        query: row.get("query"),
+        sort: row.get("sort"),
+        columns: row.get::<Option<String>, _>("columns").and_then(|columns| serde_json::from_str(&columns).ok()),
+        pinned: row.get("pinned"),
+        is_default: row.get("is_default"),
+    }
+}
+
+// A user's saved views, by name
+pub async fn get_saved_views(owner: &str) -> Result<Vec<SavedView>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM saved_views WHERE owner = ? ORDER BY name COLLATE NOCASE")
+        .bind(owner)
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().map(saved_view_from_row).collect())
+}
+
+pub async fn get_saved_view(owner: &str, id: i64) -> Result<Option<SavedView>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT * FROM saved_views WHERE owner = ? AND id = ?")
+        .bind(owner)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row.as_ref().map(saved_view_from_row))
+}
+
+// Save a new view, or update the owner's view with `view.id`. Making a view the default takes
+// that from the owner's other views. Returns None when there is no such view to update.
+pub async fn save_view(view: &SavedView) -> Result<Option<SavedView>> {
+    let pool = get_pool().await?;
+    let mut tx = pool.begin().await?;
+    
+    if view.is_default {
+        sqlx::query("UPDATE saved_views SET is_default = 0 WHERE owner = ? AND id != ?")
+            .bind(&view.owner)
+            .bind(view.id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    let columns = view.columns.as_ref().map(serde_json::to_string).transpose()?;
+    let id = if view.id == 0 {
+        sqlx::query(
+            "INSERT INTO saved_views (owner, name, query, sort, columns, pinned, is_default, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&view.owner)
+        .bind(view.name.trim())
+        .bind(view.query.trim())
+        .bind(&view.sort)
+        .bind(columns)
+        .bind(view.pinned)
+        .bind(view.is_default)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid()
+    } else {
+        let result = sqlx::query(
+            "UPDATE saved_views SET name = ?, query = ?, sort = ?, columns = ?, pinned = ?, is_default = ? WHERE owner = ? AND id = ?",
+        )
+        .bind(view.name.trim())
+        .bind(view.query.trim())
+        .bind(&view.sort)
+        .bind(columns)
+        .bind(view.pinned)
+        .bind(view.is_default)
+        .bind(&view.owner)
+        .bind(view.id)
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+        view.id
+    };
+    
+    tx.commit().await?;
+    Ok(Some(SavedView {
+        id,
+        name: view.name.trim().to_string(),
+        query: view.query.trim().to_string(),
+        ..view.clone()
+    }))
+}
+
+pub async fn delete_saved_view(owner: &str, id: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM saved_views WHERE owner = ? AND id = ?")
+        .bind(owner)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Make one of the owner's views their default, or none of them
+pub async fn set_default_view(owner: &str, id: Option<i64>) -> Result<()> {
+    let pool = get_pool().await?;
+    let mut tx = pool.begin().await?;
+    
+    sqlx::query("UPDATE saved_views SET is_default = 0 WHERE owner = ?")
+        .bind(owner)
+        .execute(&mut *tx)
+        .await?;
+    if let Some(id) = id {
+        sqlx::query("UPDATE saved_views SET is_default = 1 WHERE owner = ? AND id = ?")
+            .bind(owner)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    
+    tx.commit().await?;
+    Ok(())
+}
+
+// ---- END SAVED VIEW FUNCTIONS ----
+
+// ---- START USER PREFERENCE FUNCTIONS ----
+
+pub async fn get_user_preferences(username: &str) -> Result<Option<UiPreferences>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT preferences FROM user_preferences WHERE username = ?")
+        .bind(username)
+        .fetch_optional(pool)
+        .await?;
+    
+    row.map(|row| Ok(serde_json::from_str(&row.get::<String, _>("preferences"))?)).transpose()
+}
+
+pub async fn save_user_preferences(username: &str, preferences: &UiPreferences) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO user_preferences (username, preferences, updated_at) VALUES (?, ?, ?)
+        ON CONFLICT(username) DO UPDATE SET
+        preferences = excluded.preferences,
+        updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(username)
+    .bind(serde_json::to_string(preferences)?)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    info!("Saved UI preferences for {}", username);
+    Ok(())
+}
+
+// ---- END USER PREFERENCE FUNCTIONS ----
+
+// ---- START MAINTENANCE WINDOW FUNCTIONS ----
+
+fn maintenance_window_from_row(row: &sqlx::sqlite::SqliteRow) -> MaintenanceWindow {
+    MaintenanceWindow {
+        id: row.get("id"),
+        name: row.get("name"),
+        days: serde_json::from_str(&row.get::<String, _>("days")).unwrap_or_default(),
+        start: row.get("start_time"),
+        end: row.get("end_time"),
+        enabled: row.get("enabled"),
+    }
+}
+
+pub async fn get_maintenance_windows() -> Result<Vec<MaintenanceWindow>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM maintenance_windows ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(rows.iter().map(maintenance_window_from_row).collect())
+}
+
+pub async fn add_maintenance_window(window: &MaintenanceWindow) -> Result<MaintenanceWindow> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query(
+        "INSERT INTO maintenance_windows (name, days, start_time, end_time, enabled, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(window.name.trim())
+    .bind(serde_json::to_string(&window.days)?)
+    .bind(window.start.trim())
+    .bind(window.end.trim())
+    .bind(window.enabled)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(MaintenanceWindow { id: result.last_insert_rowid(), ..window.clone() })
+}
+
+pub async fn update_maintenance_window(window: &MaintenanceWindow) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query(
+        "UPDATE maintenance_windows SET name = ?, days = ?, start_time = ?, end_time = ?, enabled = ? WHERE id = ?",
+    )
+    .bind(window.name.trim())
+    .bind(serde_json::to_string(&window.days)?)
+    .bind(window.start.trim())
+    .bind(window.end.trim())
+    .bind(window.enabled)
+    .bind(window.id)
+    .execute(pool)
+    .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn delete_maintenance_window(id: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM maintenance_windows WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+fn provisioning_hold_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<ProvisioningHold> {
+    Ok(ProvisioningHold {
+        machine_id: Uuid::parse_str(&row.get::<String, _>("machine_id"))?,
+        os_choice: row.get("os_choice"),
+        requested_by: row.get("requested_by"),
+        queued_at: parse_datetime(&row.get::<String, _>("queued_at")),
+        next_window: None,
+    })
+}
+
+pub async fn get_provisioning_holds() -> Result<Vec<ProvisioningHold>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM provisioning_holds ORDER BY queued_at")
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(provisioning_hold_from_row).collect()
+}
+
+// Hold an assignment; a machine assigned again while held keeps only the latest assignment
+pub async fn save_provisioning_hold(hold: &ProvisioningHold) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO provisioning_holds (machine_id, os_choice, requested_by, queued_at) VALUES (?, ?, ?, ?)
+        ON CONFLICT(machine_id) DO UPDATE SET
+            os_choice = excluded.os_choice,
+            requested_by = excluded.requested_by,
+            queued_at = excluded.queued_at
+        "#,
+    )
+    .bind(hold.machine_id.to_string())
+    .bind(&hold.os_choice)
+    .bind(&hold.requested_by)
+    .bind(hold.queued_at.to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+// Remove a machine's hold, returning it. Only one caller gets a given hold.
+pub async fn take_provisioning_hold(machine_id: &Uuid) -> Result<Option<ProvisioningHold>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("DELETE FROM provisioning_holds WHERE machine_id = ? RETURNING *")
+        .bind(machine_id.to_string())
+        .fetch_optional(pool)
+        .await?;
+    
+    row.as_ref().map(provisioning_hold_from_row).transpose()
+}
+
+// ---- END MAINTENANCE WINDOW FUNCTIONS ----
+
+// ---- START INSTALL QUEUE FUNCTIONS ----
+
+pub async fn get_install_limits() -> Result<InstallLimits> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT limits FROM install_limits WHERE id = 1")
+        .fetch_optional(pool)
+        .await?;
+    
+    Ok(row
+        .and_then(|row| serde_json::from_str(&row.get::<String, _>("limits")).ok())
+        .unwrap_or_default())
+}
+
+pub async fn save_install_limits(limits: &InstallLimits) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO install_limits (id, limits, updated_at) VALUES (1, ?, ?)
+        ON CONFLICT(id) DO UPDATE SET limits = excluded.limits, updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(serde_json::to_string(limits)?)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+fn queued_install_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<QueuedInstall> {
+    Ok(QueuedInstall {
+        machine_id: Uuid::parse_str(&row.get::<String, _>("machine_id"))?,
+        os_choice: row.get("os_choice"),
+        requested_by: row.get("requested_by"),
+        priority: row.get("priority"),
+        queued_at: parse_datetime(&row.get::<String, _>("queued_at")),
+        waiting_for: None,
+    })
+}
+
+// Queued installs in the order they will start
+pub async fn get_queued_installs() -> Result<Vec<QueuedInstall>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM install_queue ORDER BY priority DESC, queued_at")
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(queued_install_from_row).collect()
+}
+
+// Queue an install; a machine queued again keeps its place and priority but installs the latest assignment
+pub async fn save_queued_install(queued: &QueuedInstall) -> Result<QueuedInstall> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query(
+        r#"
+        INSERT INTO install_queue (machine_id, os_choice, requested_by, priority, queued_at) VALUES (?, ?, ?, ?, ?)
+        ON CONFLICT(machine_id) DO UPDATE SET
+            os_choice = excluded.os_choice,
+            requested_by = excluded.requested_by
+        RETURNING *
+        "#,
+    )
+    .bind(queued.machine_id.to_string())
+    .bind(&queued.os_choice)
+    .bind(&queued.requested_by)
+    .bind(queued.priority)
+    .bind(queued.queued_at.to_rfc3339())
+    .fetch_one(pool)
+    .await?;
+    
+    queued_install_from_row(&row)
+}
+
+pub async fn set_queued_install_priority(machine_id: &Uuid, priority: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("UPDATE install_queue SET priority = ? WHERE machine_id = ?")
+        .bind(priority)
+        .bind(machine_id.to_string())
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// Remove a machine from the queue, returning its entry. Only one caller gets a given entry.
+pub async fn take_queued_install(machine_id: &Uuid) -> Result<Option<QueuedInstall>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("DELETE FROM install_queue WHERE machine_id = ? RETURNING *")
+        .bind(machine_id.to_string())
+        .fetch_optional(pool)
+        .await?;
+    
+    row.as_ref().map(queued_install_from_row).transpose()
+}
+
+// ---- END INSTALL QUEUE FUNCTIONS ----
+
+// ---- START ROLLOUT FUNCTIONS ----
+
+fn rollout_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<Rollout> {
+    Ok(Rollout {
+        id: row.get("id"),
+        spec: serde_json::from_str(&row.get::<String, _>("spec"))?,
+        state: serde_json::from_str(&row.get::<String, _>("state"))?,
+        batch: row.get::<i64, _>("batch") as usize,
+        members: serde_json::from_str(&row.get::<String, _>("members"))?,
+        created_by: row.get("created_by"),
+        created_at: parse_datetime(&row.get::<String, _>("created_at")),
+        updated_at: parse_datetime(&row.get::<String, _>("updated_at")),
+    })
+}
+
+// Rollouts, newest first
+pub async fn get_rollouts() -> Result<Vec<Rollout>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM rollouts ORDER BY id DESC")
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(rollout_from_row).collect()
+}
+
+pub async fn get_rollout(id: i64) -> Result<Option<Rollout>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT * FROM rollouts WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    
+    row.as_ref().map(rollout_from_row).transpose()
+}
+
+pub async fn add_rollout(rollout: &Rollout) -> Result<Rollout> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query(
+        "INSERT INTO rollouts (spec, state, batch, members, created_by, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(serde_json::to_string(&rollout.spec)?)
+    .bind(serde_json::to_string(&rollout.state)?)
+    .bind(rollout.batch as i64)
+    .bind(serde_json::to_string(&rollout.members)?)
+    .bind(&rollout.created_by)
+    .bind(rollout.created_at.to_rfc3339())
+    .bind(rollout.updated_at.to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(Rollout { id: result.last_insert_rowid(), ..rollout.clone() })
+}
+
+// Record a rollout's progress
+pub async fn save_rollout(rollout: &Rollout) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query("UPDATE rollouts SET state = ?, batch = ?, members = ?, updated_at = ? WHERE id = ?")
+        .bind(serde_json::to_string(&rollout.state)?)
+        .bind(rollout.batch as i64)
+        .bind(serde_json::to_string(&rollout.members)?)
+        .bind(rollout.updated_at.to_rfc3339())
+        .bind(rollout.id)
+        .execute(pool)
+        .await?;
+    
+    Ok(())
+}
+
+// ---- END ROLLOUT FUNCTIONS ----
+
+// ---- START HEALTH CHECK FUNCTIONS ----
+
+pub async fn get_template_health_checks(template: &str) -> Result<Option<TemplateChecks>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT checks FROM template_health_checks WHERE template = ?")
+        .bind(template)
+        .fetch_optional(pool)
+        .await?;
+    
+    row.map(|row| serde_json::from_str(&row.get::<String, _>("checks")).map_err(Into::into)).transpose()
+}
+
+// Set a template's checks, or remove them with None
+pub async fn set_template_health_checks(template: &str, checks: Option<&TemplateChecks>) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    match checks {
+        Some(checks) => {
+            sqlx::query(
+                r#"
+                INSERT INTO template_health_checks (template, checks, updated_at) VALUES (?, ?, ?)
+                ON CONFLICT(template) DO UPDATE SET checks = excluded.checks, updated_at = excluded.updated_at
+                "#,
+            )
+            .bind(template)
+            .bind(serde_json::to_string(checks)?)
+            .bind(Utc::now().to_rfc3339())
+            .execute(pool)
+            .await?;
+        },
+        None => {
+            sqlx::query("DELETE FROM template_health_checks WHERE template = ?")
+                .bind(template)
+                .execute(pool)
+                .await?;
+        },
+    }
+    
+    Ok(())
+}
+
+pub async fn get_health_report(machine_id: &Uuid) -> Result<Option<HealthReport>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT report FROM machine_health_reports WHERE machine_id = ?")
+        .bind(machine_id.to_string())
+        .fetch_optional(pool)
+        .await?;
+    
+    row.map(|row| serde_json::from_str(&row.get::<String, _>("report")).map_err(Into::into)).transpose()
+}
+
+pub async fn save_health_report(machine_id: &Uuid, report: &HealthReport) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query(
+        r#"
+        INSERT INTO machine_health_reports (machine_id, report, updated_at) VALUES (?, ?, ?)
+        ON CONFLICT(machine_id) DO UPDATE SET report = excluded.report, updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(machine_id.to_string())
+    .bind(serde_json::to_string(report)?)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    
+    Ok(())
+}
+
+pub async fn delete_health_report(machine_id: &Uuid) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM machine_health_reports WHERE machine_id = ?")
+        .bind(machine_id.to_string())
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+// ---- END HEALTH CHECK FUNCTIONS ----
+
+// ---- START REIMAGE SCHEDULE FUNCTIONS ----
+
+fn reimage_schedule_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<ReimageSchedule> {
+    Ok(ReimageSchedule {
+        id: row.get("id"),
+        spec: serde_json::from_str(&row.get::<String, _>("spec"))?,
+        created_by: row.get("created_by"),
+        created_at: parse_datetime(&row.get::<String, _>("created_at")),
+        last_run_at: row.get::<Option<String>, _>("last_run_at").map(|at| parse_datetime(&at)),
+        next_run_at: row.get::<Option<String>, _>("next_run_at").map(|at| parse_datetime(&at)),
+    })
+}
+
+pub async fn get_reimage_schedules() -> Result<Vec<ReimageSchedule>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM reimage_schedules ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(reimage_schedule_from_row).collect()
+}
+
+pub async fn get_reimage_schedule(id: i64) -> Result<Option<ReimageSchedule>> {
+    let pool = get_pool().await?;
+    
+    let row = sqlx::query("SELECT * FROM reimage_schedules WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    
+    row.as_ref().map(reimage_schedule_from_row).transpose()
+}
+
+pub async fn add_reimage_schedule(schedule: &ReimageSchedule) -> Result<ReimageSchedule> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query(
+        "INSERT INTO reimage_schedules (spec, created_by, created_at, last_run_at, next_run_at) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(serde_json::to_string(&schedule.spec)?)
+    .bind(&schedule.created_by)
+    .bind(schedule.created_at.to_rfc3339())
+    .bind(schedule.last_run_at.map(|at| at.to_rfc3339()))
+    .bind(schedule.next_run_at.map(|at| at.to_rfc3339()))
+    .execute(pool)
+    .await?;
+    
+    Ok(ReimageSchedule { id: result.last_insert_rowid(), ..schedule.clone() })
+}
+
+// Save a schedule's spec and when it last ran and runs next
+pub async fn save_reimage_schedule(schedule: &ReimageSchedule) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("UPDATE reimage_schedules SET spec = ?, last_run_at = ?, next_run_at = ? WHERE id = ?")
+        .bind(serde_json::to_string(&schedule.spec)?)
+        .bind(schedule.last_run_at.map(|at| at.to_rfc3339()))
+        .bind(schedule.next_run_at.map(|at| at.to_rfc3339()))
+        .bind(schedule.id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn delete_reimage_schedule(id: i64) -> Result<bool> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM reimage_schedules WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+fn reimage_run_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<ReimageRun> {
+    Ok(ReimageRun {
+        id: row.get("id"),
+        schedule_id: row.get("schedule_id"),
+        schedule_name: row.get("schedule_name"),
+        requested_by: row.get("requested_by"),
+        started_at: parse_datetime(&row.get::<String, _>("started_at")),
+        finished_at: row.get::<Option<String>, _>("finished_at").map(|at| parse_datetime(&at)),
+        machines: serde_json::from_str(&row.get::<String, _>("machines"))?,
+        skipped: serde_json::from_str(&row.get::<String, _>("skipped"))?,
+        note: row.get("note"),
+    })
+}
+
+// A schedule's most recent runs, newest first
+pub async fn get_reimage_runs(schedule_id: i64, limit: i64) -> Result<Vec<ReimageRun>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM reimage_runs WHERE schedule_id = ? ORDER BY id DESC LIMIT ?")
+        .bind(schedule_id)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(reimage_run_from_row).collect()
+}
+
+// Runs with reimages still under way
+pub async fn get_unfinished_reimage_runs() -> Result<Vec<ReimageRun>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM reimage_runs WHERE finished_at IS NULL ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(reimage_run_from_row).collect()
+}
+
+pub async fn add_reimage_run(run: &ReimageRun) -> Result<ReimageRun> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query(
+        r#"
+        INSERT INTO reimage_runs (schedule_id, schedule_name, requested_by, started_at, finished_at, machines, skipped, note)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(run.schedule_id)
+    .bind(&run.schedule_name)
+    .bind(&run.requested_by)
+    .bind(run.started_at.to_rfc3339())
+    .bind(run.finished_at.map(|at| at.to_rfc3339()))
+    .bind(serde_json::to_string(&run.machines)?)
+    .bind(serde_json::to_string(&run.skipped)?)
+    .bind(&run.note)
+    .execute(pool)
+    .await?;
+    
+    Ok(ReimageRun { id: result.last_insert_rowid(), ..run.clone() })
+}
+
+// Record how a run's reimages are going
+pub async fn save_reimage_run(run: &ReimageRun) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query("UPDATE reimage_runs SET finished_at = ?, machines = ? WHERE id = ?")
+        .bind(run.finished_at.map(|at| at.to_rfc3339()))
+        .bind(serde_json::to_string(&run.machines)?)
+        .bind(run.id)
+        .execute(pool)
+        .await?;
+    
+    Ok(())
+}
+
+// ---- END REIMAGE SCHEDULE FUNCTIONS ----
+
+// ---- START UPGRADE FUNCTIONS ----
+
+pub async fn add_upgrade(upgrade: &Upgrade) -> Result<Upgrade> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query(
+        r#"
+        INSERT INTO upgrades (from_version, to_version, requested_by, state, message, backup, started_at, finished_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(&upgrade.from_version)
+    .bind(&upgrade.to_version)
+    .bind(&upgrade.requested_by)
+    .bind(serde_json::to_string(&upgrade.state)?)
+    .bind(&upgrade.message)
+    .bind(&upgrade.backup)
+    .bind(upgrade.started_at.to_rfc3339())
+    .bind(upgrade.finished_at.map(|at| at.to_rfc3339()))
+    .execute(pool)
+    .await?;
+    
+    Ok(Upgrade { id: result.last_insert_rowid(), ..upgrade.clone() })
+}
+
+pub async fn save_upgrade(upgrade: &Upgrade) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query("UPDATE upgrades SET state = ?, message = ?, backup = ?, finished_at = ? WHERE id = ?")
+        .bind(serde_json::to_string(&upgrade.state)?)
+        .bind(&upgrade.message)
+        .bind(&upgrade.backup)
+        .bind(upgrade.finished_at.map(|at| at.to_rfc3339()))
+        .bind(upgrade.id)
+        .execute(pool)
+        .await?;
+    
+    Ok(())
+}
+
+// The most recent upgrades, newest first
+pub async fn get_upgrades(limit: i64) -> Result<Vec<Upgrade>> {
+    let pool = get_pool().await?;
+    
+    let rows = sqlx::query("SELECT * FROM upgrades ORDER BY id DESC LIMIT ?")
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    
+    rows.iter().map(|row| Ok(Upgrade {
+        id: row.get("id"),
+        from_version: row.get("from_version"),
+        to_version: row.get("to_version"),
+        requested_by: row.get("requested_by"),
+        state: serde_json::from_str(&row.get::<String, _>("state"))?,
+        message: row.get("message"),
+        backup: row.get("backup"),
+        started_at: parse_datetime(&row.get::<String, _>("started_at")),
+        finished_at: row.get::<Option<String>, _>("finished_at").map(|at| parse_datetime(&at)),
+    })).collect()
+}
+
+// Write a consistent copy of the database to `path`, e.g. before an upgrade migrates it
+pub async fn backup_database(path: &str) -> Result<()> {
+    let pool = get_pool().await?;
+    
+    sqlx::query("VACUUM INTO ?")
+        .bind(path)
+        .execute(pool)
+        .await?;
+    
+    Ok(())
+}
+
+// ---- END UPGRADE FUNCTIONS ----
+
+// ---- START CLEANUP FUNCTIONS ----
+
+// Delete login sessions past their expiry from the tower-sessions store
+pub async fn delete_expired_sessions(now: chrono::DateTime<Utc>) -> Result<u64> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM tower_sessions WHERE expiry_date < ?")
+        .bind(now.timestamp())
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected())
+}
+
+// Delete render tokens that can no longer be used
+pub async fn delete_expired_render_tokens(now: chrono::DateTime<Utc>) -> Result<u64> {
+    let pool = get_pool().await?;
+    
+    let result = sqlx::query("DELETE FROM render_tokens WHERE expires_at <= ?")
+        .bind(now.to_rfc3339())
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected())
+}
+
+// Delete break-glass credentials that expired or were revoked before the cutoff
+pub async fn delete_stale_break_glass_credentials(cutoff: chrono::DateTime<Utc>) -> Result<u64> {
+    prune_break_glass_credentials(get_pool().await?, cutoff).await
+}
+
+pub(crate) async fn prune_break_glass_credentials(pool: &Pool<Sqlite>, cutoff: chrono::DateTime<Utc>) -> Result<u64> {
+    let cutoff_str = cutoff.to_rfc3339();
+    
+    let result = sqlx::query("DELETE FROM break_glass_credentials WHERE expires_at < ? OR revoked_at < ?")
+        .bind(&cutoff_str)
+        .bind(&cutoff_str)
+        .execute(pool)
+        .await?;
+    
+    Ok(result.rows_affected())
+}
+
+// ---- END CLEANUP FUNCTIONS ----
+
+// Update setup completion status
+pub async fn mark_setup_completed(completed: bool) -> Result<()> {
+    let pool = get_pool().await?;
+    let now = Utc::now();
+    let now_str = now.to_rfc3339();
+    
+    // Check if settings record exists
+    let result = sqlx::query("SELECT COUNT(*) FROM app_settings WHERE id = 1")
+        .fetch_one(pool)
+        .await?;
+    
+    let count: i64 = result.get(0);
+    
+    if count > 0 {
+        // Update existing record
+        sqlx::query(
+            r#"
+            UPDATE app_settings 
+            SET setup_completed = ?, updated_at = ?
+            WHERE id = 1
+            "#,
+        )
+        .bind(completed)
+        .bind(&now_str)
+        .execute(pool)
+        .await?;
+    } else {
+        // Create a new record with default values and the specified setup_completed
+        sqlx::query(
+            r#"
+            INSERT INTO app_settings (id, require_login, default_os, setup_completed, created_at, updated_at)
+            VALUES (1, 0, NULL, ?, ?, ?)
+            "#,
+        )
+        .bind(completed)
+        .bind(&now_str)
+        .bind(&now_str)
+        .execute(pool)
+        .await?;
+    }
+    
+    info!("Setup completion status set to: {}", completed);
+    Ok(())
+}
+
+// Check if setup has been completed
+pub async fn is_setup_completed() -> Result<bool> {
+    let pool = get_pool().await?;
+    
     // Try to get the setup_completed value
     let result = sqlx::query("SELECT setup_completed FROM app_settings WHERE id = 1")
         .fetch_optional(pool)
@@ -0,0 +1,503 @@
+//! Demo mode's simulated fleet.
+//!
+//! In demo mode nothing touches hardware or the machines table. Instead a fleet is fabricated in
+//! memory: racks of control planes, workers, storage and GPU nodes in every status a real fleet
+//! passes through. A simulation then moves it along. Installs make progress and finish or fail,
+//! machines are discovered, approved, assigned, reimaged, go offline and come back. Every change
+//! is published as the same event a real one would be, so the UI updates exactly as it would live.
+
+use anyhow::Result;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use dragonfly_common::models::{DiskInfo, Machine, MachineStatus};
+use once_cell::sync::Lazy;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::api::format_os_name;
+use crate::db;
+use crate::event_manager::EventManager;
+use crate::tinkerbell::{TaskInfo, WorkflowInfo};
+
+pub const FLEET_SIZE_ENV_VAR: &str = "DRAGONFLY_DEMO_FLEET_SIZE";
+pub const TICK_ENV_VAR: &str = "DRAGONFLY_DEMO_TICK_SECS";
+pub const DEFAULT_FLEET_SIZE: usize = 120;
+pub const DEFAULT_TICK_SECS: u64 = 3;
+
+/// The fleet is generated from a fixed seed, so machine IDs and names survive restarts
+const SEED: u64 = 0x00d2_a60f;
+
+/// (hostname prefix, weight, CPU, cores, RAM in GiB, disk in GB, OS template)
+const ROLES: &[(&str, u32, &str, u32, u64, u64, &str)] = &[
+    ("topaz-control", 3, "AMD EPYC 7313P", 16, 64, 500, "ubuntu-2404"),
+    ("topaz-worker", 30, "AMD EPYC 7543", 64, 256, 2000, "ubuntu-2404"),
+    ("cubefs-master", 3, "Intel Xeon Silver 4314", 32, 128, 500, "debian-12"),
+    ("cubefs-datanode", 20, "Intel Xeon Silver 4314", 32, 128, 8000, "debian-12"),
+    ("gpu", 10, "AMD EPYC 9454", 96, 768, 4000, "ubuntu-2204"),
+    ("pve", 12, "Intel Xeon Gold 6338", 64, 512, 4000, "proxmox"),
+    ("talos", 12, "AMD EPYC 7313P", 16, 64, 500, "talos"),
+    ("edge", 10, "Intel Core i5-1235U", 10, 32, 250, "ubuntu-2204"),
+];
+
+/// Statuses a new fleet starts in, by weight
+const STATUSES: &[(u32, Initial)] = &[
+    (62, Initial::Ready),
+    (8, Initial::ExistingOs),
+    (8, Initial::Awaiting),
+    (8, Initial::Installing),
+    (6, Initial::Offline),
+    (4, Initial::PendingApproval),
+    (3, Initial::Error),
+    (1, Initial::Rejected),
+];
+
+#[derive(Clone, Copy)]
+enum Initial {
+    Ready,
+    ExistingOs,
+    Awaiting,
+    Installing,
+    Offline,
+    PendingApproval,
+    Error,
+    Rejected,
+}
+
+const FOREIGN_OSES: &[&str] = &["Windows Server 2022", "Rocky Linux 9", "VMware ESXi 8.0", "FreeBSD 14"];
+const FAILURES: &[&str] = &[
+    "Image checksum mismatch",
+    "Disk /dev/nvme0n1 not found",
+    "Timed out waiting for DHCP",
+    "Workflow action stream-image exited 1",
+];
+
+/// Install actions and the progress at which each is finished
+const ACTIONS: &[(&str, u8)] = &[
+    ("Streaming OS image", 45),
+    ("Growing partitions", 60),
+    ("Writing network config", 75),
+    ("Installing bootloader", 90),
+    ("Rebooting into new OS", 100),
+];
+
+/// Seconds each action takes in the simulation, used for workflow estimates
+const ACTION_SECS: u64 = 12;
+
+#[derive(Default)]
+struct Fleet {
+    machines: Vec<Machine>,
+    /// When each installing machine started
+    installs: HashMap<Uuid, DateTime<Utc>>,
+    /// Installs that finished, for the dashboard trend charts
+    outcomes: Vec<db::InstallOutcome>,
+    /// Machines generated so far, for naming the ones discovered later
+    generated: usize,
+    size: usize,
+}
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static FLEET: Lazy<RwLock<Fleet>> = Lazy::new(|| RwLock::new(Fleet::default()));
+
+pub fn fleet_size() -> usize {
+    env::var(FLEET_SIZE_ENV_VAR).ok()
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(DEFAULT_FLEET_SIZE)
+}
+
+pub fn tick_secs() -> u64 {
+    env::var(TICK_ENV_VAR).ok()
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_TICK_SECS)
+}
+
+fn weighted<'a, T>(rng: &mut StdRng, choices: &'a [(u32, T)]) -> &'a T {
+    &choices.choose_weighted(rng, |(weight, _)| *weight).expect("weights are positive").1
+}
+
+fn new_machine(index: usize, rng: &mut StdRng, counters: &mut HashMap<&'static str, usize>, now: DateTime<Utc>) -> Machine {
+    let role = ROLES.choose_weighted(rng, |role| role.1).expect("weights are positive");
+    let (prefix, _, cpu, cores, ram_gib, disk_gb, _) = *role;
+    let number = counters.entry(prefix).or_insert(0);
+    *number += 1;
+    let hostname = format!("{}{:02}", prefix, number);
+    let mac_address = format!("52:54:00:ab:{:02x}:{:02x}", (index >> 8) & 0xff, index & 0xff);
+    let created_at = now - ChronoDuration::days(90) + ChronoDuration::minutes(index as i64 * 17);
+    let disk = DiskInfo {
+        device: if disk_gb > 2000 { "/dev/sda".to_string() } else { "/dev/nvme0n1".to_string() },
+        size_bytes: disk_gb * 1_000_000_000,
+        model: Some(if disk_gb > 2000 { "Seagate Exos X18".to_string() } else { "Samsung PM9A3".to_string() }),
+        calculated_size: Some(format!("{} GB", disk_gb)),
+    };
+    Machine {
+        // UUID v5 keeps details pages linkable across restarts
+        id: Uuid::new_v5(&Uuid::NAMESPACE_DNS, hostname.as_bytes()),
+        memorable_name: Some(dragonfly_common::mac_to_words::mac_to_words_safe(&mac_address)),
+        mac_address,
+        ip_address: format!("10.42.{}.{}", index / 250, index % 250 + 5),
+        hostname: Some(hostname),
+        os_choice: None,
+        os_installed: None,
+        status: MachineStatus::AwaitingAssignment,
+        disks: vec![disk],
+        nameservers: vec!["10.42.0.2".to_string(), "1.1.1.1".to_string()],
+        created_at,
+        updated_at: created_at,
+        bmc_credentials: None,
+        installation_progress: 0,
+        installation_step: None,
+        last_deployment_duration: None,
+        cpu_model: Some(cpu.to_string()),
+        cpu_cores: Some(cores),
+        total_ram_bytes: Some(ram_gib * 1_073_741_824),
+        clock_skew_seconds: Some(rng.gen_range(-2..=2)),
+    }
+}
+
+/// The OS template a machine's role runs
+fn role_os(machine: &Machine) -> &'static str {
+    let hostname = machine.hostname.as_deref().unwrap_or_default();
+    ROLES.iter()
+        .find(|role| hostname.starts_with(role.0))
+        .map_or("ubuntu-2204", |role| role.6)
+}
+
+fn step_name(progress: u8) -> &'static str {
+    ACTIONS.iter().find(|(_, done)| progress < *done).map_or(ACTIONS[ACTIONS.len() - 1].0, |(name, _)| name)
+}
+
+impl Fleet {
+    fn generate(size: usize, now: DateTime<Utc>) -> Self {
+        let mut rng = StdRng::seed_from_u64(SEED);
+        // A separate stream for install history, so it doesn't change the fleet itself
+        let mut history = StdRng::seed_from_u64(SEED.wrapping_add(1));
+        let mut counters = HashMap::new();
+        let mut fleet = Fleet { size, ..Default::default() };
+        for index in 0..size {
+            let mut machine = new_machine(index, &mut rng, &mut counters, now);
+            let os = role_os(&machine);
+            match weighted(&mut rng, STATUSES) {
+                Initial::Ready => {
+                    machine.status = MachineStatus::Ready;
+                    machine.os_choice = Some(os.to_string());
+                    machine.os_installed = Some(format_os_name(os));
+                    machine.last_deployment_duration = Some(rng.gen_range(300..1500));
+                },
+                Initial::ExistingOs => {
+                    machine.status = MachineStatus::ExistingOS;
+                    machine.os_installed = FOREIGN_OSES.choose(&mut rng).map(|os| os.to_string());
+                },
+                Initial::Awaiting => {},
+                Initial::Installing => {
+                    machine.os_choice = Some(os.to_string());
+                    machine.status = MachineStatus::InstallingOS;
+                    machine.installation_progress = rng.gen_range(0..90);
+                    machine.installation_step = Some(step_name(machine.installation_progress).to_string());
+                    let elapsed = machine.installation_progress as i64 * ACTION_SECS as i64 * ACTIONS.len() as i64 / 100;
+                    fleet.installs.insert(machine.id, now - ChronoDuration::seconds(elapsed));
+                },
+                Initial::Offline => {
+                    machine.status = MachineStatus::Offline;
+                    machine.os_choice = Some(os.to_string());
+                    machine.os_installed = Some(format_os_name(os));
+                },
+                Initial::PendingApproval => machine.status = MachineStatus::PendingApproval,
+                Initial::Error => {
+                    machine.os_choice = Some(os.to_string());
+                    machine.status = MachineStatus::Error(FAILURES.choose(&mut rng).unwrap_or(&FAILURES[0]).to_string());
+                },
+                Initial::Rejected => machine.status = MachineStatus::Rejected,
+            }
+            machine.updated_at = now - ChronoDuration::minutes(rng.gen_range(1..600));
+            if matches!(machine.status, MachineStatus::Ready | MachineStatus::Error(_)) {
+                let succeeded = machine.status == MachineStatus::Ready;
+                fleet.outcomes.push(db::InstallOutcome {
+                    template_name: machine.os_choice.clone(),
+                    succeeded,
+                    duration_secs: Some(machine.last_deployment_duration.unwrap_or_else(|| history.gen_range(60..600))),
+                    finished_at: now - ChronoDuration::minutes(history.gen_range(1..30 * 24 * 60)),
+                });
+            }
+            fleet.machines.push(machine);
+        }
+        fleet.generated = size;
+        fleet
+    }
+
+    fn start_install(&mut self, index: usize, now: DateTime<Utc>) -> Uuid {
+        let machine = &mut self.machines[index];
+        machine.os_choice = Some(role_os(machine).to_string());
+        machine.status = MachineStatus::InstallingOS;
+        machine.installation_progress = 0;
+        machine.installation_step = Some(step_name(0).to_string());
+        machine.updated_at = now;
+        self.installs.insert(machine.id, now);
+        machine.id
+    }
+
+    fn pick(&self, rng: &mut impl Rng, wanted: impl Fn(&MachineStatus) -> bool) -> Option<usize> {
+        let candidates: Vec<usize> = self.machines.iter().enumerate()
+            .filter(|(_, machine)| wanted(&machine.status))
+            .map(|(index, _)| index)
+            .collect();
+        candidates.choose(rng).copied()
+    }
+
+    /// Move the fleet on by one tick, returning the events a real fleet would have sent
+    fn step(&mut self, rng: &mut impl Rng, now: DateTime<Utc>) -> Vec<String> {
+        let mut events = Vec::new();
+
+        for machine in self.machines.iter_mut().filter(|m| m.status == MachineStatus::InstallingOS) {
+            let id = machine.id;
+            machine.updated_at = now;
+            if rng.gen_bool(0.01) {
+                machine.status = MachineStatus::Error(FAILURES.choose(rng).unwrap_or(&FAILURES[0]).to_string());
+                machine.installation_step = None;
+                let started = self.installs.remove(&id).unwrap_or(now);
+                self.outcomes.push(db::InstallOutcome {
+                    template_name: machine.os_choice.clone(),
+                    succeeded: false,
+                    duration_secs: Some((now - started).num_seconds()),
+                    finished_at: now,
+                });
+                events.push(format!("install_failed:{}", id));
+            } else {
+                machine.installation_progress = machine.installation_progress.saturating_add(rng.gen_range(2..=8)).min(100);
+                machine.installation_step = Some(step_name(machine.installation_progress).to_string());
+                if machine.installation_progress >= 100 {
+                    let started = self.installs.remove(&id).unwrap_or(now);
+                    machine.status = MachineStatus::Ready;
+                    machine.os_installed = machine.os_choice.as_deref().map(format_os_name);
+                    machine.installation_progress = 0;
+                    machine.installation_step = None;
+                    machine.last_deployment_duration = Some((now - started).num_seconds());
+                    self.outcomes.push(db::InstallOutcome {
+                        template_name: machine.os_choice.clone(),
+                        succeeded: true,
+                        duration_secs: machine.last_deployment_duration,
+                        finished_at: now,
+                    });
+                    events.push(format!("install_completed:{}", id));
+                }
+            }
+            events.push(format!("machine_updated:{}", id));
+        }
+
+        // Kept as long as the real install history is
+        self.outcomes.retain(|outcome| now - outcome.finished_at < ChronoDuration::days(30));
+
+        // Admins assign OSes, approve new machines and reimage old ones
+        if rng.gen_bool(0.3) {
+            if let Some(index) = self.pick(rng, |s| *s == MachineStatus::AwaitingAssignment) {
+                events.push(format!("machine_updated:{}", self.start_install(index, now)));
+            }
+        }
+        if rng.gen_bool(0.08) {
+            if let Some(index) = self.pick(rng, |s| *s == MachineStatus::Ready) {
+                events.push(format!("machine_updated:{}", self.start_install(index, now)));
+            }
+        }
+        if rng.gen_bool(0.1) {
+            if let Some(index) = self.pick(rng, |s| *s == MachineStatus::PendingApproval) {
+                self.machines[index].status = MachineStatus::AwaitingAssignment;
+                self.machines[index].updated_at = now;
+                events.push(format!("machine_updated:{}", self.machines[index].id));
+            }
+        }
+        if rng.gen_bool(0.05) {
+            if let Some(index) = self.pick(rng, |s| matches!(s, MachineStatus::Error(_))) {
+                self.machines[index].status = MachineStatus::AwaitingAssignment;
+                self.machines[index].updated_at = now;
+                events.push(format!("machine_updated:{}", self.machines[index].id));
+            }
+        }
+
+        // Machines drop off the network and come back
+        if rng.gen_bool(0.04) {
+            if let Some(index) = self.pick(rng, |s| *s == MachineStatus::Ready) {
+                self.machines[index].status = MachineStatus::Offline;
+                self.machines[index].updated_at = now;
+                events.push(format!("machine_offline:{}", self.machines[index].id));
+                events.push(format!("machine_updated:{}", self.machines[index].id));
+            }
+        }
+        if rng.gen_bool(0.1) {
+            if let Some(index) = self.pick(rng, |s| *s == MachineStatus::Offline) {
+                self.machines[index].status = MachineStatus::Ready;
+                self.machines[index].updated_at = now;
+                events.push(format!("machine_updated:{}", self.machines[index].id));
+            }
+        }
+
+        // New hardware turns up, until the fleet is a tenth bigger than it started
+        if rng.gen_bool(0.05) && self.machines.len() < self.size + self.size / 10 {
+            let mut counters = HashMap::new();
+            for machine in &self.machines {
+                if let Some(role) = ROLES.iter().find(|role| machine.hostname.as_deref().is_some_and(|h| h.starts_with(role.0))) {
+                    *counters.entry(role.0).or_insert(0) += 1;
+                }
+            }
+            let mut seeded = StdRng::seed_from_u64(SEED.wrapping_add(self.generated as u64));
+            let mut machine = new_machine(self.generated, &mut seeded, &mut counters, now);
+            machine.status = MachineStatus::PendingApproval;
+            machine.created_at = now;
+            machine.updated_at = now;
+            self.generated += 1;
+            events.push(format!("machine_discovered:{}", machine.id));
+            self.machines.push(machine);
+        }
+
+        events
+    }
+}
+
+/// Whether the simulated fleet stands in for the machines table
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// The simulated fleet as it is now
+pub fn machines() -> Vec<Machine> {
+    FLEET.read().map(|fleet| fleet.machines.clone()).unwrap_or_default()
+}
+
+/// Installs the simulated fleet has finished in the last 30 days
+pub fn install_outcomes() -> Vec<db::InstallOutcome> {
+    FLEET.read().map(|fleet| fleet.outcomes.clone()).unwrap_or_default()
+}
+
+/// A machine from the simulated fleet in demo mode, and from the database otherwise
+pub async fn machine_by_id(id: &Uuid) -> Result<Option<Machine>> {
+    if !is_active() {
+        return crate::db::get_machine_by_id(id).await;
+    }
+    Ok(FLEET.read().ok().and_then(|fleet| fleet.machines.iter().find(|m| m.id == *id).cloned()))
+}
+
+/// The install workflow of a simulated machine, shaped like Tinkerbell's
+pub fn workflow_info(machine: &Machine) -> Option<WorkflowInfo> {
+    if machine.status != MachineStatus::InstallingOS {
+        return None;
+    }
+    let started = FLEET.read().ok()?.installs.get(&machine.id).copied().unwrap_or_else(Utc::now);
+    let progress = machine.installation_progress;
+    let mut previous = 0;
+    let tasks = ACTIONS.iter().enumerate()
+        .map(|(index, (name, done))| {
+            let start = previous;
+            previous = *done;
+            let task_progress = (progress.clamp(start, *done) - start) as u64 * 100 / (done - start) as u64;
+            let status = match task_progress {
+                100 => "STATE_SUCCESS",
+                0 if progress < start => "STATE_PENDING",
+                _ => "STATE_RUNNING",
+            };
+            let duration = ACTION_SECS * task_progress / 100;
+            TaskInfo {
+                name: name.to_string(),
+                status: status.to_string(),
+                started_at: (started + ChronoDuration::seconds((index as u64 * ACTION_SECS) as i64)).to_rfc3339(),
+                duration,
+                reported_duration: duration,
+                estimated_duration: ACTION_SECS,
+                progress: task_progress as u8,
+            }
+        })
+        .collect();
+    let remaining = ACTION_SECS * ACTIONS.len() as u64 * (100 - progress.min(100)) as u64 / 100;
+    Some(WorkflowInfo {
+        state: "STATE_RUNNING".to_string(),
+        current_action: machine.installation_step.clone(),
+        progress,
+        tasks,
+        estimated_completion: Some(format!("About {} seconds remaining", remaining)),
+        template_name: machine.os_choice.clone().unwrap_or_default(),
+    })
+}
+
+/// Fabricate the demo fleet and keep it moving until shutdown
+pub async fn start_simulation(event_manager: Arc<EventManager>, mut shutdown_rx: tokio::sync::watch::Receiver<()>) {
+    let size = fleet_size();
+    if let Ok(mut fleet) = FLEET.write() {
+        *fleet = Fleet::generate(size, Utc::now());
+    }
+    ACTIVE.store(true, Ordering::Relaxed);
+
+    tokio::spawn(async move {
+        info!("Simulating a demo fleet of {} machines, every {}s", size, tick_secs());
+        let mut ticker = tokio::time::interval(Duration::from_secs(tick_secs()));
+        let mut rng = StdRng::from_entropy();
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    let events = match FLEET.write() {
+                        Ok(mut fleet) => fleet.step(&mut rng, Utc::now()),
+                        Err(_) => continue,
+                    };
+                    for event in events {
+                        let _ = event_manager.send(event);
+                    }
+                },
+                _ = shutdown_rx.changed() => {
+                    info!("Shutdown signal received, stopping demo simulation.");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fleet_generation_and_simulation() {
+        let now = Utc::now();
+        let fleet = Fleet::generate(150, now);
+        assert_eq!(fleet.machines.len(), 150);
+        // The same seed gives the same fleet
+        let again = Fleet::generate(150, now);
+        assert!(fleet.machines.iter().zip(&again.machines).all(|(a, b)| a.id == b.id && a.status == b.status));
+        let ids: std::collections::HashSet<_> = fleet.machines.iter().map(|m| m.id).collect();
+        assert_eq!(ids.len(), 150);
+        for status in [MachineStatus::Ready, MachineStatus::InstallingOS, MachineStatus::AwaitingAssignment] {
+            assert!(fleet.machines.iter().any(|m| m.status == status), "no {:?} machines", status);
+        }
+
+        let mut fleet = fleet;
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut events = Vec::new();
+        for tick in 0..100 {
+            events.extend(fleet.step(&mut rng, now + ChronoDuration::seconds(tick * 3)));
+        }
+        assert!(events.iter().any(|e| e.starts_with("install_completed:")));
+        assert!(events.iter().any(|e| e.starts_with("machine_updated:")));
+        assert!(fleet.outcomes.iter().any(|o| o.finished_at > now && o.succeeded));
+        assert!(fleet.machines.len() <= 165);
+        assert!(fleet.machines.iter()
+            .filter(|m| m.status == MachineStatus::InstallingOS)
+            .all(|m| m.installation_progress < 100 && fleet.installs.contains_key(&m.id)));
+    }
+
+    #[test]
+    fn test_workflow_info_tracks_progress() {
+        assert_eq!(step_name(0), "Streaming OS image");
+        assert_eq!(step_name(80), "Installing bootloader");
+        let mut machine = Fleet::generate(1, Utc::now()).machines.remove(0);
+        machine.status = MachineStatus::InstallingOS;
+        machine.installation_progress = 50;
+        let info = workflow_info(&machine).unwrap();
+        let statuses: Vec<&str> = info.tasks.iter().map(|t| t.status.as_str()).collect();
+        assert_eq!(statuses, vec!["STATE_SUCCESS", "STATE_RUNNING", "STATE_PENDING", "STATE_PENDING", "STATE_PENDING"]);
+        assert_eq!(info.tasks[1].progress, 33);
+    }
+}
@@ -0,0 +1,308 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use dragonfly_common::models::RegisterRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::boot_filter::BootFilter;
+use crate::db;
+use crate::event_manager::EventManager;
+
+/// Kernel neighbour table; lists every device the server has exchanged traffic with on its local segments
+const ARP_TABLE: &str = "/proc/net/arp";
+
+/// How often the scheduler wakes to see whether a scan is due
+const SCHEDULER_TICK: Duration = Duration::from_secs(60);
+
+/// When discovery scans run and what happens to devices they find.
+/// Rules use the boot filter syntax: an exact MAC, a 3-octet OUI or a CIDR subnet.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct DiscoveryPolicy {
+    /// Minutes between scheduled scans; 0 disables the schedule
+    #[serde(default)]
+    pub interval_minutes: u64,
+    /// New devices matching these rules are registered as machines automatically
+    #[serde(default)]
+    pub auto_register: Vec<String>,
+    /// New devices matching these rules are expected, so they don't raise a notification
+    #[serde(default)]
+    pub expected: Vec<String>,
+}
+
+impl DiscoveryPolicy {
+    pub fn validate(&self) -> Result<()> {
+        BootFilter::from_lists(&self.auto_register.join("\n"), &self.expected.join("\n"))?;
+        Ok(())
+    }
+
+    fn matches(rules: &[String], device: &DiscoveredDevice) -> bool {
+        // An allow list with no matching rule is exactly "not on the list"
+        !rules.is_empty()
+            && BootFilter { allow: rules.to_vec(), deny: Vec::new() }
+                .check(&device.mac_address, device.ip_address.parse().ok())
+                .is_none()
+    }
+
+    pub fn should_auto_register(&self, device: &DiscoveredDevice) -> bool {
+        Self::matches(&self.auto_register, device)
+    }
+
+    pub fn is_expected(&self, device: &DiscoveredDevice) -> bool {
+        Self::matches(&self.expected, device)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DiscoveredDevice {
+    pub mac_address: String,
+    pub ip_address: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct IpChange {
+    pub mac_address: String,
+    pub old_ip: String,
+    pub new_ip: String,
+}
+
+/// What changed on the network since the previous scan
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct DiscoveryDiff {
+    pub new_devices: Vec<DiscoveredDevice>,
+    pub disappeared: Vec<DiscoveredDevice>,
+    pub ip_changes: Vec<IpChange>,
+}
+
+/// A completed scan and its diff against the one before it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoveryReport {
+    pub id: i64,
+    pub started_at: DateTime<Utc>,
+    pub devices: Vec<DiscoveredDevice>,
+    #[serde(flatten)]
+    pub diff: DiscoveryDiff,
+    /// New devices that are neither known machines nor covered by the `expected` rules
+    pub unexpected: Vec<DiscoveredDevice>,
+    /// Machines registered by this scan under the `auto_register` rules
+    pub auto_registered: Vec<String>,
+}
+
+/// Parse `/proc/net/arp`, keeping only complete entries with a real MAC address
+pub fn parse_arp_table(table: &str) -> Vec<DiscoveredDevice> {
+    let mut devices: Vec<DiscoveredDevice> = table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (ip, flags, mac) = (fields.first()?, fields.get(2)?, fields.get(3)?);
+            let complete = u32::from_str_radix(flags.trim_start_matches("0x"), 16).ok()? & 0x2 != 0;
+            if !complete || *mac == "00:00:00:00:00:00" || ip.parse::<IpAddr>().is_err() {
+                return None;
+            }
+            Some(DiscoveredDevice { mac_address: mac.to_lowercase(), ip_address: ip.to_string() })
+        })
+        .collect();
+    devices.sort();
+    devices.dedup_by(|a, b| a.mac_address == b.mac_address);
+    devices
+}
+
+/// Compare two scans by MAC address
+pub fn diff(previous: &[DiscoveredDevice], current: &[DiscoveredDevice]) -> DiscoveryDiff {
+    let before: BTreeMap<&str, &str> = previous.iter().map(|d| (d.mac_address.as_str(), d.ip_address.as_str())).collect();
+    let after: BTreeMap<&str, &str> = current.iter().map(|d| (d.mac_address.as_str(), d.ip_address.as_str())).collect();
+
+    let mut result = DiscoveryDiff::default();
+    for device in current {
+        match before.get(device.mac_address.as_str()) {
+            None => result.new_devices.push(device.clone()),
+            Some(old_ip) if *old_ip != device.ip_address => result.ip_changes.push(IpChange {
+                mac_address: device.mac_address.clone(),
+                old_ip: old_ip.to_string(),
+                new_ip: device.ip_address.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    result.disappeared = previous.iter()
+        .filter(|d| !after.contains_key(d.mac_address.as_str()))
+        .cloned()
+        .collect();
+    result
+}
+
+async fn auto_register(device: &DiscoveredDevice) -> Result<uuid::Uuid> {
+    let request = RegisterRequest {
+        mac_address: device.mac_address.clone(),
+        ip_address: device.ip_address.clone(),
+        hostname: None,
+        disks: Vec::new(),
+        nameservers: Vec::new(),
+        cpu_model: None,
+        cpu_cores: None,
+        total_ram_bytes: None,
+        agent_time: None,
+        cpu_arch: None,
+        uefi: None,
+        secure_boot: None,
+        tpm: None,
+        pci_devices: Vec::new(),
+        system_vendor: None,
+        system_model: None,
+        system_serial: None,
+    };
+    let machine_id = db::register_machine(&request).await?;
+    // Discovered machines can only match profiles on their subnet, having no agent to report more
+    if let Err(e) = crate::machine_profiles::adopt(&machine_id).await {
+        warn!("Failed to match discovered machine {} against machine profiles: {}", machine_id, e);
+    }
+    if let Err(e) = crate::hostname_policy::apply_to_machine(&machine_id).await {
+        warn!("Failed to apply hostname policy to discovered machine {}: {}", machine_id, e);
+    }
+    if let Ok(Some(machine)) = db::get_machine_by_id(&machine_id).await {
+        if !machine.status.is_approval_gated() {
+            if let Err(e) = crate::tinkerbell::register_machine(&machine).await {
+                warn!("Failed to register discovered machine with Tinkerbell (continuing anyway): {}", e);
+            }
+        }
+    }
+    Ok(machine_id)
+}
+
+/// Scan the network, diff against the last scan, apply the policy and store the report
+pub async fn run_scan(event_manager: &EventManager) -> Result<DiscoveryReport> {
+    let started_at = Utc::now();
+    let policy = db::get_discovery_policy().await?;
+    let devices = parse_arp_table(&tokio::fs::read_to_string(ARP_TABLE).await?);
+    let previous = db::get_latest_discovery_report().await?.map(|r| r.devices).unwrap_or_default();
+    let diff = diff(&previous, &devices);
+
+    let known: HashSet<String> = db::get_all_machines().await?
+        .into_iter()
+        .map(|m| m.mac_address.to_lowercase())
+        .collect();
+
+    let mut unexpected = Vec::new();
+    let mut auto_registered = Vec::new();
+    for device in diff.new_devices.iter().filter(|d| !known.contains(&d.mac_address)) {
+        if policy.should_auto_register(device) {
+            match auto_register(device).await {
+                Ok(machine_id) => {
+                    info!("Auto-registered discovered device {} ({}) as machine {}", device.mac_address, device.ip_address, machine_id);
+                    let _ = event_manager.send(format!("machine_discovered:{}", machine_id));
+                    auto_registered.push(machine_id.to_string());
+                    continue;
+                },
+                Err(e) => error!("Failed to auto-register discovered device {}: {}", device.mac_address, e),
+            }
+        }
+        if !policy.is_expected(device) {
+            unexpected.push(device.clone());
+        }
+    }
+
+    let mut report = DiscoveryReport { id: 0, started_at, devices, diff, unexpected, auto_registered };
+    report.id = db::add_discovery_report(&report).await?;
+
+    info!(
+        "Discovery scan {} found {} devices: {} new, {} gone, {} changed IP, {} unexpected",
+        report.id, report.devices.len(), report.diff.new_devices.len(), report.diff.disappeared.len(),
+        report.diff.ip_changes.len(), report.unexpected.len()
+    );
+    for device in &report.unexpected {
+        warn!("Unexpected new device on the network: {} ({})", device.mac_address, device.ip_address);
+        let _ = event_manager.send(format!("discovery_unexpected_device:{}", device.mac_address));
+    }
+    let _ = event_manager.send(format!("discovery_completed:{}", report.id));
+    Ok(report)
+}
+
+/// Run discovery scans on the schedule set in the discovery policy
+pub async fn start_discovery_scheduler(
+    event_manager: Arc<EventManager>,
+    mut shutdown_rx: tokio::sync::watch::Receiver<()>,
+) {
+    tokio::spawn(async move {
+        info!("Starting discovery scan scheduler");
+        let mut ticker = tokio::time::interval(SCHEDULER_TICK);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    // Re-read the policy every tick so schedule changes apply without a restart
+                    let interval = match db::get_discovery_policy().await {
+                        Ok(policy) if policy.interval_minutes > 0 => policy.interval_minutes,
+                        Ok(_) => continue,
+                        Err(e) => {
+                            error!("Failed to load discovery policy: {}", e);
+                            continue;
+                        }
+                    };
+                    let last = db::get_latest_discovery_report().await.ok().flatten().map(|r| r.started_at);
+                    if last.is_some_and(|last| Utc::now() - last < chrono::Duration::minutes(interval as i64)) {
+                        continue;
+                    }
+                    if let Err(e) = run_scan(&event_manager).await {
+                        error!("Scheduled discovery scan failed: {}", e);
+                    }
+                },
+                _ = shutdown_rx.changed() => {
+                    info!("Shutdown signal received, stopping discovery scan scheduler.");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(mac: &str, ip: &str) -> DiscoveredDevice {
+        DiscoveredDevice { mac_address: mac.to_string(), ip_address: ip.to_string() }
+    }
+
+    #[test]
+    fn test_parse_arp_table() {
+        let table = "IP address       HW type     Flags       HW address            Mask     Device\n\
+                     10.0.0.5         0x1         0x2         BC:24:11:B9:54:89     *        eth0\n\
+                     10.0.0.6         0x1         0x0         00:00:00:00:00:00     *        eth0\n\
+                     10.0.0.7         0x1         0x2         bc:24:11:00:00:01     *        eth0\n";
+        assert_eq!(parse_arp_table(table), vec![
+            device("bc:24:11:00:00:01", "10.0.0.7"),
+            device("bc:24:11:b9:54:89", "10.0.0.5"),
+        ]);
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = vec![device("aa:aa:aa:aa:aa:01", "10.0.0.1"), device("aa:aa:aa:aa:aa:02", "10.0.0.2")];
+        let after = vec![device("aa:aa:aa:aa:aa:02", "10.0.0.9"), device("aa:aa:aa:aa:aa:03", "10.0.0.3")];
+        let result = diff(&before, &after);
+        assert_eq!(result.new_devices, vec![device("aa:aa:aa:aa:aa:03", "10.0.0.3")]);
+        assert_eq!(result.disappeared, vec![device("aa:aa:aa:aa:aa:01", "10.0.0.1")]);
+        assert_eq!(result.ip_changes, vec![IpChange {
+            mac_address: "aa:aa:aa:aa:aa:02".to_string(),
+            old_ip: "10.0.0.2".to_string(),
+            new_ip: "10.0.0.9".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn test_policy_rules() {
+        let policy = DiscoveryPolicy {
+            interval_minutes: 30,
+            auto_register: vec!["10.0.5.0/24".to_string()],
+            expected: vec!["bc:24:11".to_string()],
+        };
+        assert!(policy.validate().is_ok());
+        assert!(policy.should_auto_register(&device("aa:aa:aa:aa:aa:01", "10.0.5.20")));
+        assert!(!policy.should_auto_register(&device("aa:aa:aa:aa:aa:01", "10.0.6.20")));
+        assert!(policy.is_expected(&device("bc:24:11:00:00:01", "10.0.6.20")));
+        assert!(!DiscoveryPolicy::default().is_expected(&device("bc:24:11:00:00:01", "10.0.6.20")));
+    }
+}
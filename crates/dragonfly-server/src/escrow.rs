@@ -0,0 +1,174 @@
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chrono::{DateTime, Utc};
+use dragonfly_common::models::MachineStatus;
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::env;
+use thiserror::Error;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::db;
+
+/// 32-byte key, hex-encoded, that escrowed disk keys are encrypted with. Escrow is disabled when unset.
+/// Kept out of the database so a copy of it alone doesn't give the keys away.
+pub const KEY_ENV_VAR: &str = "DRAGONFLY_ESCROW_KEY";
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scheme {
+    Luks,
+    Bitlocker,
+}
+
+impl Scheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Luks => "luks",
+            Scheme::Bitlocker => "bitlocker",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "luks" => Some(Scheme::Luks),
+            "bitlocker" => Some(Scheme::Bitlocker),
+            _ => None,
+        }
+    }
+}
+
+/// An escrowed recovery key, without the key itself
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskKey {
+    pub id: i64,
+    pub machine_id: Uuid,
+    pub scheme: Scheme,
+    pub created_at: DateTime<Utc>,
+}
+
+/// One admin looking at one recovery key
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskKeyAccess {
+    pub id: i64,
+    pub key_id: i64,
+    pub machine_id: Uuid,
+    pub revealed_by: String,
+    pub reason: String,
+    pub revealed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Error)]
+pub enum EscrowError {
+    #[error("Set DRAGONFLY_ESCROW_KEY on the server to enable disk key escrow")]
+    Disabled,
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Recovery key not found")]
+    NotFound,
+    #[error(transparent)]
+    Database(#[from] anyhow::Error),
+}
+
+fn master_key() -> Result<Key, EscrowError> {
+    let value = env::var(KEY_ENV_VAR).map_err(|_| EscrowError::Disabled)?;
+    match hex::decode(value.trim()) {
+        Ok(bytes) if bytes.len() == 32 => Ok(*Key::from_slice(&bytes)),
+        _ => Err(EscrowError::Invalid(format!("{} must be 64 hex characters", KEY_ENV_VAR))),
+    }
+}
+
+/// A LUKS passphrase, or a BitLocker recovery password: eight groups of six digits, each a multiple of 11
+fn generate(scheme: Scheme) -> String {
+    let mut rng = rand::thread_rng();
+    match scheme {
+        Scheme::Luks => {
+            let key: String = (&mut rng).sample_iter(&Alphanumeric).take(40).map(char::from).collect();
+            key.as_bytes().chunks(8).map(|c| String::from_utf8_lossy(c).into_owned()).collect::<Vec<_>>().join("-")
+        },
+        Scheme::Bitlocker => (0..8)
+            .map(|_| format!("{:06}", rng.gen::<u16>() as u32 * 11))
+            .collect::<Vec<_>>()
+            .join("-"),
+    }
+}
+
+// The machine ID is bound in as associated data, so a sealed key can't be moved to another machine's row
+fn seal(key: &Key, machine_id: &Uuid, secret: &str) -> Result<String, EscrowError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let sealed = ChaCha20Poly1305::new(key)
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: secret.as_bytes(), aad: machine_id.as_bytes() })
+        .map_err(|_| EscrowError::Invalid("Failed to encrypt recovery key".to_string()))?;
+    Ok(hex::encode([nonce.as_slice(), &sealed].concat()))
+}
+
+fn open(key: &Key, machine_id: &Uuid, sealed: &str) -> Result<String, EscrowError> {
+    let failed = || EscrowError::Invalid("Recovery key can't be decrypted; was DRAGONFLY_ESCROW_KEY changed?".to_string());
+    let bytes = hex::decode(sealed).map_err(|_| failed())?;
+    if bytes.len() < NONCE_LEN {
+        return Err(failed());
+    }
+    let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+    let secret = ChaCha20Poly1305::new(key)
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad: machine_id.as_bytes() })
+        .map_err(|_| failed())?;
+    String::from_utf8(secret).map_err(|_| failed())
+}
+
+/// Generate and escrow a recovery key for a machine that is installing its OS, returning it to the installer.
+/// Each call makes a new key; the machine never gets an earlier one back.
+pub async fn issue(machine_id: &Uuid, scheme: Scheme) -> Result<String, EscrowError> {
+    let key = master_key()?;
+    let machine = db::get_machine_by_id(machine_id).await?.ok_or(EscrowError::NotFound)?;
+    if machine.status != MachineStatus::InstallingOS {
+        warn!("Refused to issue a disk key to machine {} while it is {}", machine_id, machine.status);
+        return Err(EscrowError::Invalid("Recovery keys are only issued while the machine is installing its OS".to_string()));
+    }
+
+    let secret = generate(scheme);
+    let id = db::save_disk_key(machine_id, scheme, &seal(&key, machine_id, &secret)?).await?;
+    info!("Escrowed {} recovery key {} for machine {}", scheme.as_str(), id, machine_id);
+    Ok(secret)
+}
+
+/// Decrypt an escrowed key for an admin, recording who looked and why
+pub async fn reveal(actor: &str, machine_id: &Uuid, key_id: i64, reason: &str) -> Result<String, EscrowError> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err(EscrowError::Invalid("Give a reason for revealing the recovery key".to_string()));
+    }
+    let key = master_key()?;
+    let sealed = db::get_sealed_disk_key(machine_id, key_id).await?.ok_or(EscrowError::NotFound)?;
+    let secret = open(&key, machine_id, &sealed)?;
+
+    db::record_disk_key_access(key_id, machine_id, actor, reason).await?;
+    warn!("Recovery key {} of machine {} revealed to '{}': {}", key_id, machine_id, actor, reason);
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_round_trip() {
+        let key = Key::from([7u8; 32]);
+        let machine = Uuid::new_v4();
+        let sealed = seal(&key, &machine, "hunter2").unwrap();
+        assert_eq!(open(&key, &machine, &sealed).unwrap(), "hunter2");
+        assert!(open(&key, &Uuid::new_v4(), &sealed).is_err());
+        assert!(open(&Key::from([8u8; 32]), &machine, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_bitlocker_recovery_password() {
+        let password = generate(Scheme::Bitlocker);
+        let groups: Vec<&str> = password.split('-').collect();
+        assert_eq!(groups.len(), 8);
+        assert!(groups.iter().all(|g| g.len() == 6 && g.parse::<u32>().unwrap() % 11 == 0));
+    }
+}
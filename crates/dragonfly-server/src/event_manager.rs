@@ -1,6 +1,21 @@
-use tokio::sync::broadcast;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::env;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::Notify;
 use tracing::{info, warn};
 
+/// Recent events kept so reconnecting SSE clients can catch up with `Last-Event-ID`
+pub const HISTORY_SIZE: usize = 1000;
+
+/// Events each subscriber may have waiting before the drop policy applies
+pub const QUEUE_SIZE_ENV_VAR: &str = "DRAGONFLY_EVENT_QUEUE_SIZE";
+/// What happens to a subscriber that falls behind: `drop-oldest`, `drop-newest` or `disconnect`
+pub const DROP_POLICY_ENV_VAR: &str = "DRAGONFLY_EVENT_DROP_POLICY";
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+
 // Event types that can be published
 #[derive(Debug, Clone)]
 pub enum Event {
@@ -9,48 +24,378 @@ pub enum Event {
     MachineDeleted(String),
 }
 
+/// How a full subscriber queue makes room. Other subscribers are never held up either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DropPolicy {
+    /// Discard the subscriber's oldest queued events; it hears about the newest state
+    #[default]
+    DropOldest,
+    /// Discard new events until the subscriber catches up
+    DropNewest,
+    /// Close the subscription. SSE clients reconnect with `Last-Event-ID` and replay what they missed.
+    Disconnect,
+}
+
+impl DropPolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim() {
+            "drop-oldest" => Ok(DropPolicy::DropOldest),
+            "drop-newest" => Ok(DropPolicy::DropNewest),
+            "disconnect" => Ok(DropPolicy::Disconnect),
+            other => Err(format!("{} must be drop-oldest, drop-newest or disconnect, got '{}'", DROP_POLICY_ENV_VAR, other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EventConfig {
+    pub queue_size: usize,
+    pub drop_policy: DropPolicy,
+}
+
+impl Default for EventConfig {
+    fn default() -> Self {
+        Self { queue_size: DEFAULT_QUEUE_SIZE, drop_policy: DropPolicy::default() }
+    }
+}
+
+impl EventConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let queue_size = match env::var(QUEUE_SIZE_ENV_VAR) {
+            Ok(value) => value.trim().parse::<usize>().ok().filter(|size| *size > 0)
+                .ok_or_else(|| format!("{} must be a positive number of events, got '{}'", QUEUE_SIZE_ENV_VAR, value))?,
+            Err(_) => DEFAULT_QUEUE_SIZE,
+        };
+        let drop_policy = match env::var(DROP_POLICY_ENV_VAR) {
+            Ok(value) => DropPolicy::parse(&value)?,
+            Err(_) => DropPolicy::default(),
+        };
+        Ok(Self { queue_size, drop_policy })
+    }
+}
+
+/// An event with its ID, which increases by one with every event sent. Clients see it as
+/// `<epoch>-<id>` (see [`EventManager::event_id`]).
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedEvent {
+    pub id: u64,
+    pub message: String,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum RecvError {
+    /// This many events were dropped because the subscriber fell behind
+    #[error("subscriber lagged by {0} events")]
+    Lagged(u64),
+    /// The subscription was closed, by the drop policy or because the event manager went away
+    #[error("subscription closed")]
+    Closed,
+}
+
+#[derive(Debug, Error)]
+#[error("no subscribers for event: {0}")]
+pub struct SendError(pub String);
+
+enum Slot {
+    Event(RecordedEvent),
+    /// Marks where events were dropped
+    Gap(u64),
+}
+
+/// A subscriber's queued events and gap markers
+#[derive(Default)]
+struct Slots {
+    queue: VecDeque<Slot>,
+    /// Events in the queue, kept so a send doesn't have to count them; gap markers don't count
+    events: usize,
+}
+
+impl Slots {
+    fn push_event(&mut self, event: RecordedEvent) {
+        self.queue.push_back(Slot::Event(event));
+        self.events += 1;
+    }
+
+    fn pop_front(&mut self) -> Option<Slot> {
+        let slot = self.queue.pop_front();
+        if matches!(slot, Some(Slot::Event(_))) {
+            self.events -= 1;
+        }
+        slot
+    }
+}
+
+/// One subscriber's bounded queue
+struct Queue {
+    slots: Mutex<Slots>,
+    notify: Notify,
+    closed: AtomicBool,
+    policy: DropPolicy,
+}
+
+impl Queue {
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.notify.notify_one();
+    }
+}
+
+/// Why an event couldn't be queued for a subscriber
+enum Overflow {
+    Dropped,
+    Disconnected,
+}
+
+/// Add an event to a queue holding at most `capacity` events (gap markers don't count)
+fn enqueue(slots: &mut Slots, event: RecordedEvent, capacity: usize, policy: DropPolicy) -> Result<(), Overflow> {
+    if slots.events < capacity {
+        slots.push_event(event);
+        return Ok(());
+    }
+    match policy {
+        DropPolicy::DropOldest => {
+            // Drop the oldest event and fold it into a gap at the front
+            let mut dropped = 0;
+            while let Some(slot) = slots.pop_front() {
+                match slot {
+                    Slot::Gap(n) => dropped += n,
+                    Slot::Event(_) => {
+                        dropped += 1;
+                        break;
+                    },
+                }
+            }
+            if let Some(Slot::Gap(n)) = slots.queue.front_mut() {
+                *n += dropped;
+            } else {
+                slots.queue.push_front(Slot::Gap(dropped));
+            }
+            slots.push_event(event);
+            Err(Overflow::Dropped)
+        },
+        DropPolicy::DropNewest => {
+            match slots.queue.back_mut() {
+                Some(Slot::Gap(n)) => *n += 1,
+                _ => slots.queue.push_back(Slot::Gap(1)),
+            }
+            Err(Overflow::Dropped)
+        },
+        DropPolicy::Disconnect => Err(Overflow::Disconnected),
+    }
+}
+
+/// Counters for the metrics endpoint
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EventStats {
+    pub subscribers: usize,
+    /// Events waiting in the fullest subscriber queue
+    pub max_queue_depth: usize,
+    pub events_sent: u64,
+    pub events_dropped: u64,
+    pub subscribers_disconnected: u64,
+}
+
+#[derive(Default)]
+struct History {
+    events: VecDeque<RecordedEvent>,
+    last_id: u64,
+}
+
+struct Inner {
+    config: EventConfig,
+    /// Differs on every start, so an ID from before a restart can't pass for one of ours
+    epoch: String,
+    // Held while sending, so IDs follow the order subscribers receive events in
+    history: Mutex<History>,
+    subscribers: Mutex<Vec<Arc<Queue>>>,
+    events_sent: AtomicU64,
+    events_dropped: AtomicU64,
+    subscribers_disconnected: AtomicU64,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for queue in self.subscribers.get_mut().unwrap().iter() {
+            queue.close();
+        }
+    }
+}
+
+/// What a client resuming after `last_id` missed
+pub struct Replay {
+    /// The missed events still in the history, oldest first
+    pub missed: Vec<RecordedEvent>,
+    /// Some events are gone (too old, or from before a restart), so the client should reload its state
+    pub gap: bool,
+    pub subscription: Subscription,
+}
+
+/// A live subscription with its own bounded queue, so a slow subscriber only ever loses its own events
+pub struct Subscription {
+    queue: Arc<Queue>,
+}
+
+impl Subscription {
+    pub async fn recv(&mut self) -> Result<String, RecvError> {
+        self.recv_event().await.map(|event| event.message)
+    }
+
+    /// The next event with its ID
+    pub async fn recv_event(&mut self) -> Result<RecordedEvent, RecvError> {
+        loop {
+            // Waits for a permit left by a send that raced with the check below
+            let notified = self.queue.notify.notified();
+            if let Some(slot) = self.queue.slots.lock().unwrap().pop_front() {
+                return match slot {
+                    Slot::Event(event) => Ok(event),
+                    Slot::Gap(skipped) => Err(RecvError::Lagged(skipped)),
+                };
+            }
+            if self.queue.closed.load(Ordering::Acquire) {
+                return Err(RecvError::Closed);
+            }
+            notified.await;
+        }
+    }
+}
+
 // Event manager for publishing SSE events
+#[derive(Clone)]
 pub struct EventManager {
-    tx: broadcast::Sender<String>,
+    inner: Arc<Inner>,
 }
 
 impl EventManager {
     pub fn new() -> Self {
-        let (tx, _) = broadcast::channel(100);
-        Self { tx }
+        Self::with_config(EventConfig::default())
+    }
+
+    pub fn with_config(config: EventConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                config,
+                epoch: uuid::Uuid::new_v4().simple().to_string()[..8].to_string(),
+                history: Mutex::new(History::default()),
+                subscribers: Mutex::new(Vec::new()),
+                events_sent: AtomicU64::new(0),
+                events_dropped: AtomicU64::new(0),
+                subscribers_disconnected: AtomicU64::new(0),
+            }),
+        }
     }
 
     // Create a new subscription to events
-    pub fn subscribe(&self) -> broadcast::Receiver<String> {
-        self.tx.subscribe()
-    }
-
-    // Publish an event, returning Result to handle errors
-    pub fn send(&self, message: String) -> Result<usize, broadcast::error::SendError<String>> {
-        let receivers = self.tx.receiver_count();
-        
-        // Only attempt to send if we have receivers to avoid log spam
-        if receivers > 0 {
-            match self.tx.send(message.clone()) {
-                Ok(n) => {
-                    info!("Event sent to {} receivers: {}", n, message);
-                    Ok(n)
+    pub fn subscribe(&self) -> Subscription {
+        self.subscribe_with_policy(self.inner.config.drop_policy)
+    }
+
+    /// Subscribe with a drop policy other than the configured one
+    pub fn subscribe_with_policy(&self, policy: DropPolicy) -> Subscription {
+        let queue = Arc::new(Queue {
+            slots: Mutex::new(Slots::default()),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+            policy,
+        });
+        self.inner.subscribers.lock().unwrap().push(queue.clone());
+        Subscription { queue }
+    }
+
+    /// The ID clients see for an event: its number, prefixed with this run's epoch
+    pub fn event_id(&self, id: u64) -> String {
+        format!("{}-{}", self.inner.epoch, id)
+    }
+
+    /// Subscribe, first collecting the events sent after the client's `Last-Event-ID`. Without one
+    /// nothing is replayed; one from another run (or garbled) is a gap.
+    pub fn subscribe_since(&self, last_event_id: Option<&str>) -> Replay {
+        let history = self.inner.history.lock().unwrap();
+        let subscription = self.subscribe();
+        let Some(last_event_id) = last_event_id else {
+            return Replay { missed: Vec::new(), gap: false, subscription };
+        };
+        let last_id = last_event_id.trim().split_once('-')
+            .filter(|(epoch, _)| *epoch == self.inner.epoch)
+            .and_then(|(_, id)| id.parse::<u64>().ok());
+        let Some(last_id) = last_id else {
+            return Replay { missed: Vec::new(), gap: true, subscription };
+        };
+        let oldest = history.events.front().map_or(history.last_id + 1, |e| e.id);
+        Replay {
+            missed: history.events.iter().filter(|e| e.id > last_id).cloned().collect(),
+            gap: last_id + 1 < oldest || last_id > history.last_id,
+            subscription,
+        }
+    }
+
+    // Publish an event to every subscriber's queue. Never waits on a subscriber.
+    pub fn send(&self, message: String) -> Result<usize, SendError> {
+        #[cfg(feature = "chaos")]
+        if crate::chaos::drop_event(&message) {
+            return Ok(0);
+        }
+        let mut history = self.inner.history.lock().unwrap();
+        history.last_id += 1;
+        let event = RecordedEvent { id: history.last_id, message };
+        if history.events.len() == HISTORY_SIZE {
+            history.events.pop_front();
+        }
+        history.events.push_back(event.clone());
+        self.inner.events_sent.fetch_add(1, Ordering::Relaxed);
+
+        let mut subscribers = self.inner.subscribers.lock().unwrap();
+        // Subscriptions that were dropped or closed only hold their queue here
+        subscribers.retain(|queue| Arc::strong_count(queue) > 1 && !queue.closed.load(Ordering::Acquire));
+        if subscribers.is_empty() {
+            // Create a more descriptive error when there are no receivers
+            warn!("No receivers for event: {}", event.message);
+            return Err(SendError(event.message));
+        }
+
+        let mut delivered = 0;
+        for queue in subscribers.iter() {
+            let result = enqueue(&mut queue.slots.lock().unwrap(), event.clone(), self.inner.config.queue_size, queue.policy);
+            match result {
+                Ok(()) => delivered += 1,
+                Err(Overflow::Dropped) => {
+                    self.inner.events_dropped.fetch_add(1, Ordering::Relaxed);
+                },
+                Err(Overflow::Disconnected) => {
+                    self.inner.subscribers_disconnected.fetch_add(1, Ordering::Relaxed);
+                    warn!("Disconnecting an event subscriber that fell {} events behind", self.inner.config.queue_size);
+                    queue.close();
+                    continue;
                 },
-                Err(e) => {
-                    warn!("Failed to send event: {}", e);
-                    Err(e)
-                }
             }
-        } else {
-            // Create a more descriptive error when there are no receivers
-            warn!("No receivers for event: {}", message);
-            Err(broadcast::error::SendError(message))
+            queue.notify.notify_one();
         }
+        info!("Event sent to {} receivers: {}", delivered, event.message);
+        Ok(delivered)
     }
-    
+
     // Get the current receiver count
     pub fn receiver_count(&self) -> usize {
-        self.tx.receiver_count()
+        self.inner.subscribers.lock().unwrap().iter()
+            .filter(|queue| Arc::strong_count(queue) > 1 && !queue.closed.load(Ordering::Acquire))
+            .count()
+    }
+
+    pub fn stats(&self) -> EventStats {
+        let subscribers = self.inner.subscribers.lock().unwrap();
+        let live: Vec<_> = subscribers.iter()
+            .filter(|queue| Arc::strong_count(queue) > 1 && !queue.closed.load(Ordering::Acquire))
+            .collect();
+        EventStats {
+            subscribers: live.len(),
+            max_queue_depth: live.iter()
+                .map(|queue| queue.slots.lock().unwrap().events)
+                .max()
+                .unwrap_or(0),
+            events_sent: self.inner.events_sent.load(Ordering::Relaxed),
+            events_dropped: self.inner.events_dropped.load(Ordering::Relaxed),
+            subscribers_disconnected: self.inner.subscribers_disconnected.load(Ordering::Relaxed),
+        }
     }
 }
 
@@ -60,11 +405,107 @@ impl Default for EventManager {
     }
 }
 
-// Make EventManager safe to clone by wrapping in Arc
-impl Clone for EventManager {
-    fn clone(&self) -> Self {
-        Self {
-            tx: self.tx.clone(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn manager(queue_size: usize, drop_policy: DropPolicy) -> EventManager {
+        EventManager::with_config(EventConfig { queue_size, drop_policy })
+    }
+
+    #[tokio::test]
+    async fn test_replay_after_last_event_id() {
+        let events = EventManager::new();
+        for i in 1..=3 {
+            let _ = events.send(format!("machine_updated:{}", i));
+        }
+        let mut replay = events.subscribe_since(Some(&events.event_id(1)));
+        assert!(!replay.gap);
+        assert_eq!(replay.missed.iter().map(|e| e.id).collect::<Vec<_>>(), vec![2, 3]);
+
+        let _ = events.send("machine_deleted:4".to_string());
+        let next = replay.subscription.recv_event().await.unwrap();
+        assert_eq!((next.id, next.message.as_str()), (4, "machine_deleted:4"));
+
+        // IDs from before a restart, or older than the history, can't be replayed
+        assert!(events.subscribe_since(Some(&events.event_id(99))).gap);
+        let restarted = EventManager::new();
+        let _ = restarted.send("machine_updated:1".to_string());
+        let before_restart = restarted.subscribe_since(Some(&events.event_id(0)));
+        assert!(before_restart.gap && before_restart.missed.is_empty());
+        assert!(events.subscribe_since(Some("2")).gap);
+        for i in 0..HISTORY_SIZE {
+            let _ = events.send(format!("machine_updated:{}", i));
         }
+        assert!(events.subscribe_since(Some(&events.event_id(1))).gap);
+        assert!(!events.subscribe_since(None).gap);
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_slow_subscriber_drops_oldest_without_holding_up_others() {
+        let events = manager(3, DropPolicy::DropOldest);
+        let mut slow = events.subscribe();
+        let mut fast = events.subscribe();
+
+        for i in 1..=5 {
+            assert!(events.send(format!("machine_updated:{}", i)).is_ok());
+            assert_eq!(fast.recv().await.unwrap(), format!("machine_updated:{}", i));
+        }
+
+        assert_eq!(events.stats().max_queue_depth, 3);
+        assert_eq!(slow.recv().await, Err(RecvError::Lagged(2)));
+        for i in 3..=5 {
+            assert_eq!(slow.recv().await.unwrap(), format!("machine_updated:{}", i));
+        }
+        assert_eq!(events.stats().events_dropped, 2);
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_drops_newest() {
+        let events = manager(2, DropPolicy::DropNewest);
+        let mut slow = events.subscribe();
+        for i in 1..=5 {
+            let _ = events.send(format!("machine_updated:{}", i));
+        }
+        assert_eq!(slow.recv().await.unwrap(), "machine_updated:1");
+        assert_eq!(slow.recv().await.unwrap(), "machine_updated:2");
+        assert_eq!(slow.recv().await, Err(RecvError::Lagged(3)));
+
+        let _ = events.send("machine_updated:6".to_string());
+        assert_eq!(slow.recv().await.unwrap(), "machine_updated:6");
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_is_disconnected() {
+        let events = manager(2, DropPolicy::Disconnect);
+        let mut slow = events.subscribe();
+        let mut lossless = events.subscribe_with_policy(DropPolicy::DropOldest);
+        for i in 1..=3 {
+            let _ = events.send(format!("machine_updated:{}", i));
+        }
+        // What was queued before the overflow is still delivered
+        assert_eq!(slow.recv().await.unwrap(), "machine_updated:1");
+        assert_eq!(slow.recv().await.unwrap(), "machine_updated:2");
+        assert_eq!(slow.recv().await, Err(RecvError::Closed));
+        assert_eq!(events.stats().subscribers_disconnected, 1);
+        assert_eq!(events.receiver_count(), 1);
+        assert_eq!(lossless.recv().await, Err(RecvError::Lagged(1)));
+    }
+
+    #[tokio::test]
+    async fn test_waiting_subscriber_wakes_on_send() {
+        let events = EventManager::new();
+        let mut subscription = events.subscribe();
+        let sender = events.clone();
+        let waiter = tokio::spawn(async move { subscription.recv().await });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let _ = sender.send("machine_discovered:1".to_string());
+        assert_eq!(waiter.await.unwrap().unwrap(), "machine_discovered:1");
+
+        let mut closed = events.subscribe();
+        drop(events);
+        drop(sender);
+        assert_eq!(closed.recv().await, Err(RecvError::Closed));
+    }
+}
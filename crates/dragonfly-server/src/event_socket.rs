@@ -0,0 +1,206 @@
+use axum::extract::ws::{Message, WebSocket};
+use dragonfly_common::models::Machine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::db;
+use crate::event_manager::{EventManager, RecvError};
+
+/// Keeps idle connections open through proxies that drop silent sockets
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Which events a client wants. Empty lists mean everything.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Filter {
+    /// Event names such as `machine_updated`; a trailing `*` matches a prefix, e.g. `machine_*`
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Only events about these machines. Events that aren't about a machine are left out.
+    #[serde(default)]
+    pub machines: Vec<Uuid>,
+}
+
+impl Filter {
+    pub fn matches(&self, event: &str, subject: Option<&str>) -> bool {
+        let event_wanted = self.events.is_empty() || self.events.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => event.starts_with(prefix),
+            None => pattern == event,
+        });
+        let machine_wanted = self.machines.is_empty()
+            || subject.and_then(|s| Uuid::parse_str(s).ok()).is_some_and(|id| self.machines.contains(&id));
+        event_wanted && machine_wanted
+    }
+}
+
+/// Messages a client can send
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Request {
+    /// Replace the filter
+    Subscribe(Filter),
+    /// Send the current state of the machines the filter covers
+    Snapshot,
+}
+
+/// Messages sent to the client, as JSON text frames
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame {
+    Snapshot { machines: Vec<Machine> },
+    /// The same events as `/api/events`. `id` is the subject (usually a machine ID); events that
+    /// carry a JSON payload, such as `ip_download_progress`, have it in `data` instead.
+    Event {
+        event: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<Value>,
+    },
+    Subscribed { filter: Filter },
+    /// The connection fell behind and missed events; ask for a snapshot to catch up
+    Lagged { skipped: u64 },
+    Error { message: String },
+}
+
+/// Split an event-manager message (`event` or `event:subject`) into the event and its subject
+pub fn split_event(message: &str) -> (&str, Option<&str>) {
+    match message.split_once(':') {
+        Some((event, subject)) => (event, Some(subject)),
+        None => (message, None),
+    }
+}
+
+impl Frame {
+    pub fn from_event(message: &str) -> Frame {
+        let (event, subject) = split_event(message);
+        let data = subject.filter(|_| event == "ip_download_progress").and_then(|s| serde_json::from_str(s).ok());
+        Frame::Event {
+            event: event.to_string(),
+            id: if data.is_some() { None } else { subject.map(str::to_string) },
+            data,
+        }
+    }
+}
+
+async fn send(socket: &mut WebSocket, frame: &Frame) -> bool {
+    match serde_json::to_string(frame) {
+        Ok(text) => socket.send(Message::Text(text.into())).await.is_ok(),
+        Err(e) => {
+            warn!("Failed to serialize WebSocket frame: {}", e);
+            true
+        }
+    }
+}
+
+/// The machines a snapshot covers, without their BMC credentials
+fn snapshot_machines(machines: Vec<Machine>, filter: &Filter) -> Vec<Machine> {
+    machines.into_iter()
+        .filter(|m| filter.machines.is_empty() || filter.machines.contains(&m.id))
+        .map(|m| Machine { bmc_credentials: None, ..m })
+        .collect()
+}
+
+async fn snapshot(filter: &Filter) -> Frame {
+    match db::get_all_machines().await {
+        Ok(machines) => Frame::Snapshot { machines: snapshot_machines(machines, filter) },
+        Err(e) => Frame::Error { message: format!("Failed to load machines: {}", e) },
+    }
+}
+
+/// Serve one `/api/ws` connection: a snapshot of every machine, then events as they happen,
+/// narrowed by whatever filter the client subscribes with
+pub async fn run_event_session(mut socket: WebSocket, event_manager: Arc<EventManager>) {
+    let mut rx = event_manager.subscribe();
+    let mut filter = Filter::default();
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    ping.tick().await;
+
+    if !send(&mut socket, &snapshot(&filter).await).await {
+        return;
+    }
+
+    loop {
+        let frame = tokio::select! {
+            message = rx.recv() => match message {
+                Ok(message) => {
+                    let (event, subject) = split_event(&message);
+                    if !filter.matches(event, subject) {
+                        continue;
+                    }
+                    Frame::from_event(&message)
+                },
+                Err(RecvError::Lagged(skipped)) => Frame::Lagged { skipped },
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str::<Request>(&text) {
+                    Ok(Request::Subscribe(new_filter)) => {
+                        filter = new_filter;
+                        Frame::Subscribed { filter: filter.clone() }
+                    },
+                    Ok(Request::Snapshot) => snapshot(&filter).await,
+                    Err(e) => Frame::Error { message: format!("Unrecognised request: {}", e) },
+                },
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                // Pings are answered for us; pongs and binary frames need nothing
+                Some(Ok(_)) => continue,
+            },
+            _ = ping.tick() => {
+                if socket.send(Message::Ping(Vec::new().into())).await.is_err() {
+                    break;
+                }
+                continue;
+            },
+        };
+        if !send(&mut socket, &frame).await {
+            break;
+        }
+    }
+    debug!("Event WebSocket closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dragonfly_common::models::{BmcCredentials, BmcType};
+
+    #[test]
+    fn test_filter_and_frames() {
+        let machine = Uuid::new_v4();
+        let filter = Filter { events: vec!["machine_*".to_string()], machines: vec![machine] };
+        assert!(filter.matches("machine_updated", Some(&machine.to_string())));
+        assert!(!filter.matches("machine_updated", Some(&Uuid::new_v4().to_string())));
+        assert!(!filter.matches("discovery_completed", Some("3")));
+        assert!(Filter::default().matches("discovery_completed", Some("3")));
+
+        let request: Request = serde_json::from_str(r#"{"action": "subscribe", "events": ["install_failed"]}"#).unwrap();
+        assert!(matches!(request, Request::Subscribe(Filter { ref events, .. }) if events == &["install_failed"]));
+
+        let frame = serde_json::to_value(Frame::from_event(&format!("machine_updated:{}", machine))).unwrap();
+        assert_eq!(frame["type"], "event");
+        assert_eq!(frame["id"], machine.to_string());
+        let progress = serde_json::to_value(Frame::from_event(r#"ip_download_progress:{"percent":50}"#)).unwrap();
+        assert_eq!(progress["data"]["percent"], 50);
+    }
+
+    #[test]
+    fn test_snapshot_leaves_out_bmc_credentials() {
+        let mut with_bmc = crate::test_support::machine("aa:bb:cc:dd:ee:01");
+        with_bmc.bmc_credentials = Some(BmcCredentials {
+            address: "10.0.9.5".to_string(),
+            username: "root".to_string(),
+            password: Some("calvin".to_string()),
+            bmc_type: BmcType::IPMI,
+        });
+        let other = crate::test_support::machine("aa:bb:cc:dd:ee:02");
+
+        let machines = snapshot_machines(vec![with_bmc.clone(), other], &Filter { machines: vec![with_bmc.id], ..Default::default() });
+        assert_eq!(machines.len(), 1);
+        assert!(machines[0].bmc_credentials.is_none());
+        assert!(!serde_json::to_string(&machines).unwrap().contains("calvin"));
+    }
+}
@@ -0,0 +1,160 @@
+use dragonfly_common::models::Machine;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::custom_fields::{FieldDefinition, FILTER_PREFIX};
+use crate::topology::{Placement, Rack};
+
+/// Every built-in column, in the order they are exported. Custom fields are exported as
+/// `field.<name>` columns after these.
+pub const COLUMNS: &[&str] = &[
+    "id", "hostname", "memorable_name", "mac_address", "ip_address", "status", "os_choice",
+    "os_installed", "cpu_model", "cpu_cores", "total_ram_bytes", "disk_count", "disk_bytes",
+    "site", "rack", "position_u", "created_at", "updated_at",
+];
+
+/// Exported when no columns are asked for
+pub const DEFAULT_COLUMNS: &[&str] = &[
+    "id", "hostname", "mac_address", "ip_address", "status", "os_installed", "cpu_cores",
+    "total_ram_bytes", "site", "rack",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+impl Format {
+    pub fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value.unwrap_or("csv") {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            other => Err(format!("Unknown export format '{}'; use csv or json", other)),
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Json => "json",
+        }
+    }
+}
+
+/// The columns named in a comma-separated list, or the defaults when there is none
+pub fn parse_columns(spec: Option<&str>, fields: &[FieldDefinition]) -> Result<Vec<String>, String> {
+    let Some(spec) = spec.filter(|s| !s.trim().is_empty()) else {
+        return Ok(DEFAULT_COLUMNS.iter().map(|c| c.to_string()).collect());
+    };
+    spec.split(',').map(str::trim).filter(|c| !c.is_empty()).map(|column| {
+        let known = match column.strip_prefix(FILTER_PREFIX) {
+            Some(name) => fields.iter().any(|f| f.name == name),
+            None => COLUMNS.contains(&column),
+        };
+        match known {
+            true => Ok(column.to_string()),
+            false => Err(format!("Unknown export column '{}'", column)),
+        }
+    }).collect()
+}
+
+/// What the export needs besides the machines themselves
+#[derive(Debug, Default)]
+pub struct Inventory {
+    pub field_values: HashMap<Uuid, HashMap<String, Value>>,
+    pub placements: Vec<Placement>,
+    pub racks: Vec<Rack>,
+}
+
+impl Inventory {
+    fn value(&self, column: &str, machine: &Machine) -> Value {
+        let placement = || self.placements.iter().find(|p| p.machine_id == machine.id);
+        let text = |value: &Option<String>| value.clone().map_or(Value::Null, Value::String);
+        match column {
+            "id" => Value::String(machine.id.to_string()),
+            "hostname" => text(&machine.hostname),
+            "memorable_name" => text(&machine.memorable_name),
+            "mac_address" => Value::String(machine.mac_address.clone()),
+            "ip_address" => Value::String(machine.ip_address.clone()),
+            "status" => Value::String(machine.status.to_string()),
+            "os_choice" => text(&machine.os_choice),
+            "os_installed" => text(&machine.os_installed),
+            "cpu_model" => text(&machine.cpu_model),
+            "cpu_cores" => machine.cpu_cores.map_or(Value::Null, Value::from),
+            "total_ram_bytes" => machine.total_ram_bytes.map_or(Value::Null, Value::from),
+            "disk_count" => Value::from(machine.disks.len()),
+            "disk_bytes" => Value::from(machine.disks.iter().map(|d| d.size_bytes).sum::<u64>()),
+            "site" => placement()
+                .and_then(|p| self.racks.iter().find(|r| r.name == p.rack))
+                .map_or(Value::Null, |r| Value::String(r.site.clone())),
+            "rack" => placement().map_or(Value::Null, |p| Value::String(p.rack.clone())),
+            "position_u" => placement().map_or(Value::Null, |p| Value::from(p.position_u)),
+            "created_at" => Value::String(machine.created_at.to_rfc3339()),
+            "updated_at" => Value::String(machine.updated_at.to_rfc3339()),
+            column => column.strip_prefix(FILTER_PREFIX)
+                .and_then(|name| self.field_values.get(&machine.id)?.get(name).cloned())
+                .unwrap_or(Value::Null),
+        }
+    }
+
+    /// One object per machine, keyed by column
+    pub fn to_json(&self, machines: &[Machine], columns: &[String]) -> Vec<Map<String, Value>> {
+        machines.iter()
+            .map(|machine| columns.iter().map(|c| (c.clone(), self.value(c, machine))).collect())
+            .collect()
+    }
+
+    /// A header row followed by one row per machine
+    pub fn to_csv(&self, machines: &[Machine], columns: &[String]) -> String {
+        let mut out = columns.iter().map(|c| csv_escape(c)).collect::<Vec<_>>().join(",");
+        out.push_str("\r\n");
+        for machine in machines {
+            let row: Vec<String> = columns.iter().map(|c| match self.value(c, machine) {
+                Value::Null => String::new(),
+                Value::String(s) => csv_escape(&s),
+                other => csv_escape(&other.to_string()),
+            }).collect();
+            out.push_str(&row.join(","));
+            out.push_str("\r\n");
+        }
+        out
+    }
+}
+
+// Quotes values that need it, and defuses values a spreadsheet would run as a formula, since
+// hostnames and labels can come from the machines themselves
+fn csv_escape(value: &str) -> String {
+    let value = match value.starts_with(['=', '+', '-', '@']) && value.parse::<f64>().is_err() {
+        true => format!("'{}", value),
+        false => value.to_string(),
+    };
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_columns_and_csv_escaping() {
+        let fields = vec![FieldDefinition {
+            name: "owner".to_string(),
+            label: "Owner".to_string(),
+            field_type: crate::custom_fields::FieldType::Text,
+            show_in_list: false,
+        }];
+        assert_eq!(parse_columns(Some("id, field.owner"), &fields).unwrap(), vec!["id", "field.owner"]);
+        assert!(parse_columns(Some("field.cost_center"), &fields).is_err());
+        assert_eq!(parse_columns(None, &fields).unwrap().len(), DEFAULT_COLUMNS.len());
+
+        assert_eq!(csv_escape("web-01"), "web-01");
+        assert_eq!(csv_escape("rack 4, row \"b\""), "\"rack 4, row \"\"b\"\"\"");
+        assert_eq!(csv_escape("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_escape("-5"), "-5");
+    }
+}
@@ -0,0 +1,370 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::path::PathBuf;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::db;
+
+/// Workflow template that flashes firmware, usually run as the first stage of a pipeline
+pub const WORKFLOW_TEMPLATE: &str = "firmware-update";
+
+/// Largest firmware binary that can be uploaded
+pub const MAX_BINARY_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Component {
+    Bios,
+    Bmc,
+    Nic,
+}
+
+impl Component {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Component::Bios => "bios",
+            Component::Bmc => "bmc",
+            Component::Nic => "nic",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "bios" => Some(Component::Bios),
+            "bmc" => Some(Component::Bmc),
+            "nic" => Some(Component::Nic),
+            _ => None,
+        }
+    }
+}
+
+/// A firmware release in the repository, for one component of one vendor's model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FirmwareImage {
+    pub id: i64,
+    pub vendor: String,
+    pub model: String,
+    pub component: Component,
+    pub version: String,
+    /// Where the binary is downloaded from, unless it was uploaded to Dragonfly
+    pub url: Option<String>,
+    pub sha256: Option<String>,
+    pub size_bytes: Option<i64>,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewFirmwareImage {
+    pub vendor: String,
+    pub model: String,
+    pub component: Component,
+    pub version: String,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+/// What the flashing tool found on a machine before touching anything
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FirmwareInventory {
+    pub vendor: String,
+    pub model: String,
+    pub components: Vec<InstalledFirmware>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstalledFirmware {
+    pub component: Component,
+    /// Which one, for components a machine has several of, e.g. a NIC's PCI address
+    #[serde(default)]
+    pub device: String,
+    pub version: String,
+}
+
+/// One image the flashing tool should apply
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct FlashStep {
+    pub image_id: i64,
+    pub component: Component,
+    pub device: String,
+    pub from_version: String,
+    pub version: String,
+    pub url: String,
+    pub sha256: Option<String>,
+}
+
+/// How flashing one component went
+#[derive(Debug, Clone, Deserialize)]
+pub struct FlashResult {
+    pub component: Component,
+    #[serde(default)]
+    pub device: String,
+    pub image_id: Option<i64>,
+    pub version_before: String,
+    pub version_after: String,
+    pub success: bool,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+/// A machine's firmware as last reported, with the version it replaced if it was flashed
+#[derive(Debug, Clone, Serialize)]
+pub struct MachineFirmware {
+    pub machine_id: Uuid,
+    pub component: Component,
+    pub device: String,
+    pub vendor: String,
+    pub model: String,
+    pub version: String,
+    pub previous_version: Option<String>,
+    pub message: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+pub fn validate(image: &NewFirmwareImage) -> Result<(), String> {
+    if image.vendor.trim().is_empty() || image.model.trim().is_empty() || image.version.trim().is_empty() {
+        return Err("Firmware needs a vendor, model and version".to_string());
+    }
+    if let Some(url) = &image.url {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err("The firmware URL must be http:// or https://".to_string());
+        }
+    }
+    if let Some(sha256) = &image.sha256 {
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err("sha256 must be 64 hex characters".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Order firmware versions such as "1.9.0" and "1.10.2" or "A05" and "A12": runs of digits
+/// compare as numbers, anything else as text, and separators are ignored
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn parts(version: &str) -> Vec<&str> {
+        let mut parts = Vec::new();
+        for chunk in version.split(|c: char| !c.is_ascii_alphanumeric()).filter(|chunk| !chunk.is_empty()) {
+            // Only ASCII is left, so byte offsets are character boundaries
+            let bytes = chunk.as_bytes();
+            let mut start = 0;
+            for i in 1..bytes.len() {
+                if bytes[i - 1].is_ascii_digit() != bytes[i].is_ascii_digit() {
+                    parts.push(&chunk[start..i]);
+                    start = i;
+                }
+            }
+            parts.push(&chunk[start..]);
+        }
+        parts
+    }
+
+    let (a, b) = (parts(a.trim()), parts(b.trim()));
+    for (x, y) in a.iter().zip(&b) {
+        let order = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            _ => x.to_ascii_lowercase().cmp(&y.to_ascii_lowercase()),
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// Whether an image is for hardware the machine reported, so its binary may be handed out
+pub fn applies_to(image: &FirmwareImage, installed: &[MachineFirmware]) -> bool {
+    installed.iter().any(|firmware| firmware.component == image.component
+        && firmware.vendor.eq_ignore_ascii_case(&image.vendor)
+        && firmware.model.eq_ignore_ascii_case(&image.model))
+}
+
+/// The images a machine should be flashed with: for each installed component, the highest
+/// version for its vendor and model, if that is newer than the version installed. Images with
+/// no binary yet are skipped. `binary_url` gives the download URL for uploaded images.
+pub fn plan(images: &[FirmwareImage], inventory: &FirmwareInventory, binary_url: impl Fn(i64) -> String) -> Vec<FlashStep> {
+    let mut steps = Vec::new();
+    for installed in &inventory.components {
+        let newest = images.iter()
+            .filter(|image| image.component == installed.component
+                && image.vendor.eq_ignore_ascii_case(inventory.vendor.trim())
+                && image.model.eq_ignore_ascii_case(inventory.model.trim())
+                && (image.url.is_some() || image.size_bytes.is_some()))
+            .max_by(|a, b| compare_versions(&a.version, &b.version).then(a.id.cmp(&b.id)));
+        let Some(image) = newest else { continue };
+        // Never a downgrade, even if the newest image in the repository is older than what's installed
+        if compare_versions(&image.version, &installed.version) != Ordering::Greater {
+            continue;
+        }
+        steps.push(FlashStep {
+            image_id: image.id,
+            component: image.component,
+            device: installed.device.clone(),
+            from_version: installed.version.clone(),
+            version: image.version.clone(),
+            url: image.url.clone().unwrap_or_else(|| binary_url(image.id)),
+            sha256: image.sha256.clone(),
+        });
+    }
+    steps
+}
+
+/// Where uploaded binaries are kept, alongside the boot artifacts
+pub async fn binary_path(id: i64) -> PathBuf {
+    let storage = db::get_artifact_storage().await.unwrap_or_default();
+    storage.local_dir().join("firmware").join(format!("{}.bin", id))
+}
+
+/// Store an uploaded binary for an image, recording its checksum and size
+pub async fn store_binary(id: i64, body: &[u8]) -> Result<()> {
+    let path = binary_path(id).await;
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    tokio::fs::write(&path, body).await?;
+    let sha256 = hex::encode(Sha256::digest(body));
+    db::set_firmware_binary(id, &sha256, body.len() as i64).await?;
+    info!("Stored {} byte firmware binary for image {} ({})", body.len(), id, sha256);
+    Ok(())
+}
+
+/// Record what a machine has installed and work out what to flash
+pub async fn plan_for_machine(machine_id: &Uuid, inventory: &FirmwareInventory, binary_url: impl Fn(i64) -> String) -> Result<Vec<FlashStep>> {
+    for installed in &inventory.components {
+        db::record_machine_firmware(&MachineFirmware {
+            machine_id: *machine_id,
+            component: installed.component,
+            device: installed.device.clone(),
+            vendor: inventory.vendor.trim().to_string(),
+            model: inventory.model.trim().to_string(),
+            version: installed.version.trim().to_string(),
+            previous_version: None,
+            message: None,
+            updated_at: Utc::now(),
+        }).await?;
+    }
+    let images = db::get_firmware_images().await?;
+    let steps = plan(&images, inventory, binary_url);
+    info!("Machine {} ({} {}) has {} firmware update(s) to apply", machine_id, inventory.vendor, inventory.model, steps.len());
+    Ok(steps)
+}
+
+/// Record the outcome of flashing, keeping the version each component had before
+pub async fn record_results(machine_id: &Uuid, results: &[FlashResult]) -> Result<()> {
+    let current = db::get_machine_firmware(machine_id).await?;
+    for result in results {
+        let known = current.iter().find(|f| f.component == result.component && f.device == result.device);
+        let message = match (&result.message, result.success) {
+            (Some(message), _) => Some(message.clone()),
+            (None, false) => Some("Flashing failed".to_string()),
+            (None, true) => None,
+        };
+        db::record_machine_firmware(&MachineFirmware {
+            machine_id: *machine_id,
+            component: result.component,
+            device: result.device.clone(),
+            vendor: known.map(|f| f.vendor.clone()).unwrap_or_default(),
+            model: known.map(|f| f.model.clone()).unwrap_or_default(),
+            version: result.version_after.clone(),
+            previous_version: (result.version_after != result.version_before).then(|| result.version_before.clone()),
+            message,
+            updated_at: Utc::now(),
+        }).await?;
+        info!(
+            "Machine {} {} firmware{}: {} -> {} ({})",
+            machine_id,
+            result.component.as_str(),
+            if result.device.is_empty() { String::new() } else { format!(" {}", result.device) },
+            result.version_before,
+            result.version_after,
+            if result.success { "flashed" } else { "failed" }
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(id: i64, component: Component, version: &str, age_days: i64) -> FirmwareImage {
+        FirmwareImage {
+            id,
+            vendor: "Dell Inc.".to_string(),
+            model: "PowerEdge R650".to_string(),
+            component,
+            version: version.to_string(),
+            url: Some(format!("https://downloads.example.com/{}.bin", id)),
+            sha256: None,
+            size_bytes: None,
+            created_by: "admin".to_string(),
+            created_at: Utc::now() - chrono::Duration::days(age_days),
+        }
+    }
+
+    #[test]
+    fn test_binaries_only_go_to_matching_hardware() {
+        let installed = vec![MachineFirmware {
+            machine_id: Uuid::new_v4(),
+            component: Component::Bios,
+            device: String::new(),
+            vendor: "DELL INC.".to_string(),
+            model: "PowerEdge R650".to_string(),
+            version: "1.8.2".to_string(),
+            previous_version: None,
+            message: None,
+            updated_at: Utc::now(),
+        }];
+        assert!(applies_to(&image(1, Component::Bios, "1.9.0", 0), &installed));
+        assert!(!applies_to(&image(2, Component::Bmc, "7.00.00", 0), &installed));
+        assert!(!applies_to(&FirmwareImage { model: "PowerEdge R750".to_string(), ..image(3, Component::Bios, "1.9.0", 0) }, &installed));
+        assert!(!applies_to(&image(1, Component::Bios, "1.9.0", 0), &[]));
+    }
+
+    #[test]
+    fn test_plan_picks_newest_image_per_component() {
+        let images = vec![
+            image(1, Component::Bios, "1.8.2", 30),
+            image(2, Component::Bios, "1.9.0", 1),
+            image(3, Component::Bmc, "7.00.00", 1),
+        ];
+        let inventory = FirmwareInventory {
+            vendor: "dell inc.".to_string(),
+            model: "PowerEdge R650".to_string(),
+            components: vec![
+                InstalledFirmware { component: Component::Bios, device: String::new(), version: "1.8.2".to_string() },
+                InstalledFirmware { component: Component::Bmc, device: String::new(), version: "7.00.00".to_string() },
+                InstalledFirmware { component: Component::Nic, device: "0000:3b:00.0".to_string(), version: "22.5".to_string() },
+            ],
+        };
+        let steps = plan(&images, &inventory, |id| format!("/firmware/{}", id));
+        assert_eq!(steps.len(), 1);
+        assert_eq!((steps[0].image_id, steps[0].from_version.as_str()), (2, "1.8.2"));
+
+        // The highest version wins, however recently the others were added
+        let images = vec![image(4, Component::Bios, "1.10.0", 10), image(5, Component::Bios, "1.9.1", 0)];
+        assert_eq!(plan(&images, &inventory, |id| id.to_string())[0].version, "1.10.0");
+        let newer_installed = FirmwareInventory {
+            components: vec![InstalledFirmware { component: Component::Bios, device: String::new(), version: "1.11.0".to_string() }],
+            ..inventory
+        };
+        assert!(plan(&images, &newer_installed, |id| id.to_string()).is_empty());
+        assert_eq!(compare_versions("A05", "a12"), Ordering::Less);
+        assert_eq!(compare_versions("7.00.00", "7.0.0"), Ordering::Equal);
+        assert_eq!(compare_versions("2.1", "2.1.1"), Ordering::Less);
+
+        assert!(validate(&NewFirmwareImage {
+            vendor: "Dell Inc.".to_string(),
+            model: "PowerEdge R650".to_string(),
+            component: Component::Bios,
+            version: "1.9.0".to_string(),
+            url: Some("ftp://example.com/bios.bin".to_string()),
+            sha256: None,
+        }).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::Result;
+use axum::{
+    extract::Request,
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::db::{self, ErrorResponse};
+
+/// A freeze or unfreeze, kept for audit
+#[derive(Debug, Clone, Serialize)]
+pub struct FreezeRecord {
+    pub id: i64,
+    pub frozen: bool,
+    pub actor: String,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+lazy_static::lazy_static! {
+    /// The freeze in force, if any. Checked on every API request, so it is kept in memory.
+    static ref CURRENT: RwLock<Option<FreezeRecord>> = RwLock::new(None);
+}
+
+/// API calls refused while frozen: anything that starts an install or destroys a machine's state.
+/// `*` matches one path segment.
+const BLOCKED: &[(Method, &str)] = &[
+    (Method::POST, "/machines/*/os"),
+    (Method::POST, "/machines/*/reimage"),
+    (Method::POST, "/reimage-schedules/*/run"),
+    (Method::POST, "/racks/*/assign-os"),
+    (Method::POST, "/provisioning-holds/*/release"),
+    (Method::DELETE, "/machines/*"),
+    (Method::DELETE, "/v1/machines/*"),
+    (Method::PATCH, "/v1/machines/*"),
+    (Method::POST, "/v1/batch"),
+    (Method::POST, "/machines/*/bmc/jobs"),
+    (Method::POST, "/machines/*/commands"),
+    (Method::POST, "/search-replace"),
+    (Method::PUT, "/machines/*/ipxe"),
+    (Method::POST, "/admin/upgrade"),
+];
+
+/// Load the freeze state recorded in the database
+pub async fn load() -> Result<()> {
+    let latest = db::get_latest_freeze_record().await?.filter(|record| record.frozen);
+    if let Some(record) = &latest {
+        warn!("Provisioning is frozen (by {} at {})", record.actor, record.created_at.to_rfc3339());
+    }
+    *CURRENT.write().unwrap() = latest;
+    Ok(())
+}
+
+/// The freeze in force, if provisioning is frozen
+pub fn current() -> Option<FreezeRecord> {
+    CURRENT.read().unwrap().clone()
+}
+
+pub fn is_frozen() -> bool {
+    CURRENT.read().unwrap().is_some()
+}
+
+/// Freeze or unfreeze provisioning, recording who did it
+pub async fn set(frozen: bool, actor: &str, reason: Option<&str>) -> Result<FreezeRecord> {
+    let record = db::add_freeze_record(frozen, actor, reason).await?;
+    if frozen {
+        warn!("Provisioning frozen by {}: {}", actor, reason.unwrap_or("no reason given"));
+    } else {
+        info!("Provisioning unfrozen by {}", actor);
+    }
+    *CURRENT.write().unwrap() = frozen.then(|| record.clone());
+    Ok(record)
+}
+
+/// Explain why an action was refused
+pub fn refusal(record: &FreezeRecord) -> String {
+    match &record.reason {
+        Some(reason) => format!("Provisioning is frozen by {}: {}", record.actor, reason),
+        None => format!("Provisioning is frozen by {}", record.actor),
+    }
+}
+
+fn path_matches(pattern: &str, path: &str) -> bool {
+    let mut pattern = pattern.split('/');
+    let mut path = path.trim_end_matches('/').split('/');
+    loop {
+        match (pattern.next(), path.next()) {
+            (None, None) => return true,
+            (Some("*"), Some(segment)) if !segment.is_empty() => {},
+            (Some(expected), Some(segment)) if expected == segment => {},
+            _ => return false,
+        }
+    }
+}
+
+/// Whether an API call is refused while frozen. The path may include the `/api` prefix.
+pub fn blocks(method: &Method, path: &str) -> bool {
+    let path = path.strip_prefix("/api").unwrap_or(path);
+    BLOCKED.iter().any(|(blocked, pattern)| blocked == method && path_matches(pattern, path))
+}
+
+/// 423 Locked while provisioning is frozen, for handlers that act like a blocked API call
+pub fn locked() -> Option<Response> {
+    let record = current()?;
+    let error_response = ErrorResponse {
+        error: "Provisioning Frozen".to_string(),
+        message: refusal(&record),
+    };
+    Some((StatusCode::LOCKED, Json(error_response)).into_response())
+}
+
+/// Middleware refusing blocked API calls with 423 Locked while provisioning is frozen
+pub async fn freeze_guard(request: Request, next: Next) -> Response {
+    if blocks(request.method(), request.uri().path()) {
+        if let Some(response) = locked() {
+            warn!("Refused {} {} during provisioning freeze", request.method(), request.uri().path());
+            return response;
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocked_calls() {
+        assert!(blocks(&Method::POST, "/api/machines/0e1c4f5a-0000-0000-0000-000000000000/os"));
+        assert!(blocks(&Method::DELETE, "/machines/0e1c4f5a-0000-0000-0000-000000000000"));
+        assert!(blocks(&Method::POST, "/racks/r1/assign-os/"));
+        assert!(!blocks(&Method::GET, "/api/machines/0e1c4f5a-0000-0000-0000-000000000000/os"));
+        assert!(!blocks(&Method::DELETE, "/api/machines/0e1c4f5a-0000-0000-0000-000000000000/tags/web"));
+        assert!(!blocks(&Method::POST, "/api/machines/0e1c4f5a-0000-0000-0000-000000000000/heartbeat"));
+        assert!(!blocks(&Method::POST, "/api/machines"));
+    }
+}
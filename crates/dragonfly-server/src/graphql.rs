@@ -0,0 +1,434 @@
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Enum, InputObject, Object, Result, Schema, SimpleObject};
+use chrono::{DateTime, NaiveDate, Utc};
+use dragonfly_common::models::{DiskInfo, Machine, MachineStatus};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use uuid::Uuid;
+
+use crate::api::{format_os_name, get_template_popularity};
+use crate::auth::AuthSession;
+use crate::db::{self, StatusChange};
+use crate::policy::{self, Permission};
+use crate::tinkerbell::{self, TaskInfo, WorkflowInfo};
+
+/// Queries nested deeper than this, or costing more, are refused before they run
+const MAX_DEPTH: usize = 8;
+const MAX_COMPLEXITY: usize = 1000;
+/// Most status events one field returns; 50 when the query doesn't say
+const MAX_EVENTS: i32 = 500;
+
+pub type DragonflySchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+static SCHEMA: OnceLock<DragonflySchema> = OnceLock::new();
+
+pub fn schema() -> &'static DragonflySchema {
+    SCHEMA.get_or_init(|| {
+        Schema::build(Query, EmptyMutation, EmptySubscription)
+            .limit_depth(MAX_DEPTH)
+            .limit_complexity(MAX_COMPLEXITY)
+            .finish()
+    })
+}
+
+/// Who is asking. Administrators see every machine; anyone else sees the machines an ACL lets them view.
+pub struct Viewer {
+    principal: String,
+    is_admin: bool,
+}
+
+impl Viewer {
+    pub fn new(auth_session: &AuthSession) -> Self {
+        Viewer { principal: policy::principal(auth_session), is_admin: crate::auth::is_admin(auth_session) }
+    }
+
+    async fn can_view(&self, machine_id: &Uuid) -> anyhow::Result<bool> {
+        if self.is_admin {
+            return Ok(true);
+        }
+        policy::check_principal(&self.principal, machine_id, Permission::View).await
+    }
+}
+
+async fn visible_machines(ctx: &Context<'_>) -> Result<Vec<Machine>> {
+    let viewer = ctx.data::<Viewer>()?;
+    let mut visible = Vec::new();
+    for machine in db::get_all_machines().await? {
+        if viewer.can_view(&machine.id).await? {
+            visible.push(machine);
+        }
+    }
+    Ok(visible)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum MachineState {
+    ExistingOs,
+    AwaitingAssignment,
+    InstallingOs,
+    Ready,
+    Offline,
+    PendingApproval,
+    Rejected,
+    Error,
+}
+
+impl From<&MachineStatus> for MachineState {
+    fn from(status: &MachineStatus) -> Self {
+        match status {
+            MachineStatus::ExistingOS => MachineState::ExistingOs,
+            MachineStatus::AwaitingAssignment => MachineState::AwaitingAssignment,
+            MachineStatus::InstallingOS => MachineState::InstallingOs,
+            MachineStatus::Ready => MachineState::Ready,
+            MachineStatus::Offline => MachineState::Offline,
+            MachineStatus::PendingApproval => MachineState::PendingApproval,
+            MachineStatus::Rejected => MachineState::Rejected,
+            MachineStatus::Error(_) => MachineState::Error,
+        }
+    }
+}
+
+/// Machines to return; every condition given must hold
+#[derive(Debug, Default, InputObject)]
+pub struct MachineFilter {
+    pub ids: Option<Vec<Uuid>>,
+    /// Any of these states
+    pub status: Option<Vec<MachineState>>,
+    /// Assigned or installed OS template, e.g. `ubuntu-2404`
+    pub os: Option<String>,
+    /// Case-insensitive match on hostname, memorable name, MAC or IP address
+    pub search: Option<String>,
+    pub tag: Option<String>,
+}
+
+impl MachineFilter {
+    /// Everything but the tag, which needs a lookup
+    pub fn matches(&self, machine: &Machine) -> bool {
+        let search = self.search.as_ref().map(|s| s.to_lowercase());
+        self.ids.as_ref().is_none_or(|ids| ids.contains(&machine.id))
+            && self.status.as_ref().is_none_or(|states| states.contains(&MachineState::from(&machine.status)))
+            && self.os.as_ref().is_none_or(|os| machine.os_choice.as_ref() == Some(os) || machine.os_installed.as_ref() == Some(os))
+            && search.is_none_or(|search| {
+                [machine.hostname.as_deref(), machine.memorable_name.as_deref(), Some(&machine.mac_address), Some(&machine.ip_address)]
+                    .into_iter()
+                    .flatten()
+                    .any(|value| value.to_lowercase().contains(&search))
+            })
+    }
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct Disk {
+    pub device: String,
+    pub size_bytes: u64,
+    pub model: Option<String>,
+}
+
+impl From<&DiskInfo> for Disk {
+    fn from(disk: &DiskInfo) -> Self {
+        Disk { device: disk.device.clone(), size_bytes: disk.size_bytes, model: disk.model.clone() }
+    }
+}
+
+/// A status change, from the machine's status history
+#[derive(Debug, Clone, SimpleObject)]
+pub struct StatusEvent {
+    pub id: i64,
+    pub machine_id: Uuid,
+    pub from: Option<MachineState>,
+    pub to: MachineState,
+    /// What caused it, e.g. `admin`, `workflow` or `heartbeat_timeout`
+    pub cause: Option<String>,
+    pub description: Option<String>,
+    pub changed_at: DateTime<Utc>,
+}
+
+impl StatusEvent {
+    fn new(machine_id: Uuid, change: StatusChange) -> Self {
+        StatusEvent {
+            id: change.id,
+            machine_id,
+            from: change.from.as_ref().map(MachineState::from),
+            to: MachineState::from(&change.to),
+            cause: change.cause.as_ref().map(|cause| cause.kind().to_string()),
+            description: change.cause.as_ref().map(|cause| cause.to_string()),
+            changed_at: change.changed_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct WorkflowTask {
+    pub name: String,
+    pub status: String,
+    pub started_at: String,
+    /// Seconds
+    pub duration: u64,
+    pub estimated_duration: u64,
+    pub progress: i32,
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct Workflow {
+    pub state: String,
+    pub current_action: Option<String>,
+    pub progress: i32,
+    pub estimated_completion: Option<String>,
+    pub template: String,
+    pub tasks: Vec<WorkflowTask>,
+}
+
+impl From<WorkflowInfo> for Workflow {
+    fn from(info: WorkflowInfo) -> Self {
+        Workflow {
+            state: info.state,
+            current_action: info.current_action,
+            progress: info.progress.into(),
+            estimated_completion: info.estimated_completion,
+            template: info.template_name,
+            tasks: info.tasks.into_iter().map(|task: TaskInfo| WorkflowTask {
+                name: task.name,
+                status: task.status,
+                started_at: task.started_at,
+                duration: task.duration,
+                estimated_duration: task.estimated_duration,
+                progress: task.progress.into(),
+            }).collect(),
+        }
+    }
+}
+
+pub struct MachineNode(Machine);
+
+#[Object(name = "Machine")]
+impl MachineNode {
+    async fn id(&self) -> Uuid {
+        self.0.id
+    }
+
+    async fn mac_address(&self) -> &str {
+        &self.0.mac_address
+    }
+
+    async fn ip_address(&self) -> &str {
+        &self.0.ip_address
+    }
+
+    async fn hostname(&self) -> Option<&str> {
+        self.0.hostname.as_deref()
+    }
+
+    async fn memorable_name(&self) -> Option<&str> {
+        self.0.memorable_name.as_deref()
+    }
+
+    async fn status(&self) -> MachineState {
+        MachineState::from(&self.0.status)
+    }
+
+    /// The error, when the status is ERROR
+    async fn status_message(&self) -> Option<&str> {
+        match &self.0.status {
+            MachineStatus::Error(message) => Some(message),
+            _ => None,
+        }
+    }
+
+    async fn os_choice(&self) -> Option<&str> {
+        self.0.os_choice.as_deref()
+    }
+
+    async fn os_installed(&self) -> Option<&str> {
+        self.0.os_installed.as_deref()
+    }
+
+    async fn installation_progress(&self) -> i32 {
+        self.0.installation_progress.into()
+    }
+
+    async fn installation_step(&self) -> Option<&str> {
+        self.0.installation_step.as_deref()
+    }
+
+    /// Seconds the last deployment took
+    async fn last_deployment_duration(&self) -> Option<i64> {
+        self.0.last_deployment_duration
+    }
+
+    async fn cpu_model(&self) -> Option<&str> {
+        self.0.cpu_model.as_deref()
+    }
+
+    async fn cpu_cores(&self) -> Option<u32> {
+        self.0.cpu_cores
+    }
+
+    async fn total_ram_bytes(&self) -> Option<u64> {
+        self.0.total_ram_bytes
+    }
+
+    async fn disks(&self) -> Vec<Disk> {
+        self.0.disks.iter().map(Disk::from).collect()
+    }
+
+    async fn nameservers(&self) -> &[String] {
+        &self.0.nameservers
+    }
+
+    async fn created_at(&self) -> DateTime<Utc> {
+        self.0.created_at
+    }
+
+    async fn updated_at(&self) -> DateTime<Utc> {
+        self.0.updated_at
+    }
+
+    async fn tags(&self) -> Result<Vec<String>> {
+        Ok(db::get_machine_tags(&self.0.id).await?)
+    }
+
+    /// Status changes, newest first
+    async fn events(&self, #[graphql(default = 50)] limit: i32) -> Result<Vec<StatusEvent>> {
+        let history = db::get_status_history(&self.0.id).await?;
+        Ok(history.into_iter().rev()
+            .take(limit.clamp(0, MAX_EVENTS) as usize)
+            .map(|change| StatusEvent::new(self.0.id, change))
+            .collect())
+    }
+
+    /// The running or most recently completed provisioning workflow
+    async fn workflow(&self) -> Result<Option<Workflow>> {
+        Ok(tinkerbell::get_workflow_info(&self.0).await?.map(Workflow::from))
+    }
+
+    /// The OS template assigned to the machine, or else the one it has installed
+    async fn template(&self) -> Result<Option<TemplateNode>> {
+        match self.0.os_choice.as_ref().or(self.0.os_installed.as_ref()) {
+            Some(name) => TemplateNode::load(name).await.map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+pub struct TemplateNode {
+    name: String,
+    usage_count: i64,
+    eol_date: Option<NaiveDate>,
+}
+
+impl TemplateNode {
+    async fn load_all() -> Result<Vec<TemplateNode>> {
+        let eol: HashMap<String, NaiveDate> = db::get_os_lifecycle().await?
+            .into_iter()
+            .map(|entry| (entry.template, entry.eol_date))
+            .collect();
+        Ok(get_template_popularity().await.into_iter().map(|template| TemplateNode {
+            eol_date: eol.get(&template.template).copied(),
+            name: template.template,
+            usage_count: template.usage_count,
+        }).collect())
+    }
+
+    async fn load(name: &str) -> Result<TemplateNode> {
+        let found = Self::load_all().await?.into_iter().find(|template| template.name == name);
+        Ok(found.unwrap_or_else(|| TemplateNode { name: name.to_string(), usage_count: 0, eol_date: None }))
+    }
+}
+
+#[Object(name = "Template")]
+impl TemplateNode {
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn display_name(&self) -> String {
+        format_os_name(&self.name)
+    }
+
+    /// How many times the template has been assigned
+    async fn usage_count(&self) -> i64 {
+        self.usage_count
+    }
+
+    async fn eol_date(&self) -> Option<NaiveDate> {
+        self.eol_date
+    }
+
+    /// Machines assigned or running the template
+    async fn machines(&self, ctx: &Context<'_>) -> Result<Vec<MachineNode>> {
+        let filter = MachineFilter { os: Some(self.name.clone()), ..MachineFilter::default() };
+        Ok(visible_machines(ctx).await?.into_iter().filter(|m| filter.matches(m)).map(MachineNode).collect())
+    }
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    async fn machines(&self, ctx: &Context<'_>, filter: Option<MachineFilter>, limit: Option<i32>, #[graphql(default = 0)] offset: i32) -> Result<Vec<MachineNode>> {
+        let filter = filter.unwrap_or_default();
+        let mut machines = Vec::new();
+        for machine in visible_machines(ctx).await? {
+            if !filter.matches(&machine) {
+                continue;
+            }
+            if let Some(tag) = &filter.tag {
+                if !db::get_machine_tags(&machine.id).await?.contains(tag) {
+                    continue;
+                }
+            }
+            machines.push(MachineNode(machine));
+        }
+        let limit = limit.map_or(usize::MAX, |limit| limit.max(0) as usize);
+        Ok(machines.into_iter().skip(offset.max(0) as usize).take(limit).collect())
+    }
+
+    async fn machine(&self, ctx: &Context<'_>, id: Uuid) -> Result<Option<MachineNode>> {
+        if !ctx.data::<Viewer>()?.can_view(&id).await? {
+            return Ok(None);
+        }
+        Ok(db::get_machine_by_id(&id).await?.map(MachineNode))
+    }
+
+    /// Status changes across the fleet, newest first
+    async fn events(&self, ctx: &Context<'_>, since: Option<DateTime<Utc>>, #[graphql(default = 50)] limit: i32) -> Result<Vec<StatusEvent>> {
+        let viewer = ctx.data::<Viewer>()?;
+        let mut events = Vec::new();
+        for (machine_id, change) in db::get_recent_status_changes(since, limit.clamp(0, MAX_EVENTS).into()).await? {
+            if viewer.can_view(&machine_id).await? {
+                events.push(StatusEvent::new(machine_id, change));
+            }
+        }
+        Ok(events)
+    }
+
+    /// OS templates, most used first
+    async fn templates(&self) -> Result<Vec<TemplateNode>> {
+        TemplateNode::load_all().await
+    }
+
+    async fn template(&self, name: String) -> Result<TemplateNode> {
+        TemplateNode::load(&name).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_machine_filter() {
+        let machine = Machine {
+            hostname: Some("Worker-01".to_string()),
+            os_choice: Some("ubuntu-2404".to_string()),
+            status: MachineStatus::InstallingOS,
+            ..crate::test_support::machine("aa:bb:cc:dd:ee:ff")
+        };
+        let filter = |f: MachineFilter| f.matches(&machine);
+
+        assert!(filter(MachineFilter::default()));
+        assert!(filter(MachineFilter { search: Some("worker".to_string()), os: Some("ubuntu-2404".to_string()), ..Default::default() }));
+        assert!(filter(MachineFilter { status: Some(vec![MachineState::Ready, MachineState::InstallingOs]), ..Default::default() }));
+        assert!(!filter(MachineFilter { status: Some(vec![MachineState::Ready]), ..Default::default() }));
+        assert!(!filter(MachineFilter { ids: Some(vec![Uuid::new_v4()]), ..Default::default() }));
+    }
+}
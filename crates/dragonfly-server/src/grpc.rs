@@ -0,0 +1,475 @@
+use chrono::{DateTime, Utc};
+use dragonfly_common::models::{DiskInfo, Machine, MachineStatus, RegisterRequest, TpmIdentity};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::api::{register_agent, RegistrationError};
+use crate::changes::{self, ChangeKind, Cursor};
+use crate::db;
+use crate::event_manager::{EventManager, RecvError};
+use crate::event_socket::{split_event, Filter};
+
+pub mod proto {
+    tonic::include_proto!("dragonfly.v1");
+}
+
+use proto::agent_server::{Agent, AgentServer};
+use proto::events_server::{Events, EventsServer};
+use proto::peer_server::{Peer, PeerServer};
+
+/// Address to serve gRPC on; unset leaves it off
+pub const GRPC_LISTEN_ENV_VAR: &str = "DRAGONFLY_GRPC_LISTEN";
+/// Server certificate and key, PEM
+pub const GRPC_TLS_CERT_ENV_VAR: &str = "DRAGONFLY_GRPC_TLS_CERT";
+pub const GRPC_TLS_KEY_ENV_VAR: &str = "DRAGONFLY_GRPC_TLS_KEY";
+/// CA that agent and peer client certificates must be signed by, PEM
+pub const GRPC_CLIENT_CA_ENV_VAR: &str = "DRAGONFLY_GRPC_CLIENT_CA";
+/// Comma-separated SHA-256 fingerprints of the client certificates peer servers present.
+/// Only these may call `Peer`, and they can't act as agents.
+pub const GRPC_PEER_FINGERPRINTS_ENV_VAR: &str = "DRAGONFLY_GRPC_PEER_FINGERPRINTS";
+
+/// Messages a slow stream can fall behind by before it is dropped
+const STREAM_BUFFER: usize = 64;
+/// How long a peer sync waits for a machine event before checking for changes anyway
+const SYNC_POLL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrpcConfig {
+    pub listen: SocketAddr,
+    pub cert: String,
+    pub key: String,
+    pub client_ca: String,
+    /// Lowercase hex, without separators
+    pub peer_fingerprints: Vec<String>,
+}
+
+/// What a client may do. Agent and peer certificates come from the same CA, so peers are told
+/// apart by the fingerprint of their certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Agent,
+    Peer,
+}
+
+fn fingerprint(der: &[u8]) -> String {
+    hex::encode(Sha256::digest(der))
+}
+
+/// Fingerprints as `openssl x509 -fingerprint -sha256` prints them, or plain hex
+fn parse_fingerprints(value: &str) -> Result<Vec<String>, String> {
+    value.split(',')
+        .map(|fingerprint| fingerprint.trim().replace(':', "").to_lowercase())
+        .filter(|fingerprint| !fingerprint.is_empty())
+        .map(|fingerprint| match fingerprint.len() == 64 && fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+            true => Ok(fingerprint),
+            false => Err(format!("{} must list SHA-256 certificate fingerprints, got '{}'", GRPC_PEER_FINGERPRINTS_ENV_VAR, fingerprint)),
+        })
+        .collect()
+}
+
+fn role_of(peer_fingerprints: &[String], client_cert: Option<&[u8]>) -> Role {
+    match client_cert {
+        Some(der) if peer_fingerprints.contains(&fingerprint(der)) => Role::Peer,
+        _ => Role::Agent,
+    }
+}
+
+fn client_role<T>(peer_fingerprints: &[String], request: &Request<T>) -> Role {
+    let certs = request.peer_certs();
+    role_of(peer_fingerprints, certs.as_ref().and_then(|certs| certs.first()).map(|cert| cert.as_ref()))
+}
+
+impl GrpcConfig {
+    /// None when gRPC isn't configured. Clients must present a certificate, so the TLS files are
+    /// required whenever a listen address is set.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let listen = match env::var(GRPC_LISTEN_ENV_VAR) {
+            Ok(value) if !value.trim().is_empty() => value.trim().parse()
+                .map_err(|_| format!("{} must be an address and port such as 0.0.0.0:50051, got '{}'", GRPC_LISTEN_ENV_VAR, value))?,
+            _ => return Ok(None),
+        };
+        let required = |var: &str| match env::var(var) {
+            Ok(value) if !value.trim().is_empty() => Ok(value.trim().to_string()),
+            _ => Err(format!("{} is required when {} is set; the gRPC API only accepts mutual TLS", var, GRPC_LISTEN_ENV_VAR)),
+        };
+        Ok(Some(GrpcConfig {
+            listen,
+            cert: required(GRPC_TLS_CERT_ENV_VAR)?,
+            key: required(GRPC_TLS_KEY_ENV_VAR)?,
+            client_ca: required(GRPC_CLIENT_CA_ENV_VAR)?,
+            peer_fingerprints: parse_fingerprints(&env::var(GRPC_PEER_FINGERPRINTS_ENV_VAR).unwrap_or_default())?,
+        }))
+    }
+
+    async fn tls(&self) -> Result<ServerTlsConfig, String> {
+        let read = |path: String| async move {
+            tokio::fs::read(&path).await.map_err(|e| format!("Failed to read {}: {}", path, e))
+        };
+        let identity = Identity::from_pem(read(self.cert.clone()).await?, read(self.key.clone()).await?);
+        let client_ca = Certificate::from_pem(read(self.client_ca.clone()).await?);
+        Ok(ServerTlsConfig::new().identity(identity).client_ca_root(client_ca))
+    }
+}
+
+fn parse_machine_id(value: &str) -> Result<Uuid, Status> {
+    Uuid::parse_str(value).map_err(|_| Status::invalid_argument(format!("Invalid machine ID '{}'", value)))
+}
+
+impl From<proto::Disk> for DiskInfo {
+    fn from(disk: proto::Disk) -> Self {
+        DiskInfo { device: disk.device, size_bytes: disk.size_bytes, model: disk.model, calculated_size: None }
+    }
+}
+
+impl From<&DiskInfo> for proto::Disk {
+    fn from(disk: &DiskInfo) -> Self {
+        proto::Disk { device: disk.device.clone(), size_bytes: disk.size_bytes, model: disk.model.clone() }
+    }
+}
+
+impl TryFrom<proto::RegisterRequest> for RegisterRequest {
+    type Error = Status;
+
+    fn try_from(request: proto::RegisterRequest) -> Result<Self, Status> {
+        let agent_time = request.agent_time
+            .map(|t| DateTime::parse_from_rfc3339(&t).map(|t| t.with_timezone(&Utc)))
+            .transpose()
+            .map_err(|_| Status::invalid_argument("agent_time must be an RFC3339 timestamp"))?;
+        Ok(RegisterRequest {
+            mac_address: request.mac_address,
+            ip_address: request.ip_address,
+            hostname: request.hostname,
+            disks: request.disks.into_iter().map(DiskInfo::from).collect(),
+            nameservers: request.nameservers,
+            cpu_model: request.cpu_model,
+            cpu_cores: request.cpu_cores,
+            total_ram_bytes: request.total_ram_bytes,
+            agent_time,
+            cpu_arch: request.cpu_arch,
+            uefi: request.uefi,
+            secure_boot: request.secure_boot,
+            tpm: request.tpm.map(|tpm| TpmIdentity { ek_public: tpm.ek_public, ak_public: tpm.ak_public }),
+            pci_devices: Vec::new(),
+            system_vendor: None,
+            system_model: None,
+            system_serial: None,
+        })
+    }
+}
+
+/// BMC credentials never leave the server
+impl From<&Machine> for proto::Machine {
+    fn from(machine: &Machine) -> Self {
+        let (status, status_message) = match &machine.status {
+            MachineStatus::Error(message) => ("Error".to_string(), Some(message.clone())),
+            status => (format!("{:?}", status), None),
+        };
+        proto::Machine {
+            id: machine.id.to_string(),
+            mac_address: machine.mac_address.clone(),
+            ip_address: machine.ip_address.clone(),
+            hostname: machine.hostname.clone(),
+            memorable_name: machine.memorable_name.clone(),
+            status,
+            status_message,
+            os_choice: machine.os_choice.clone(),
+            os_installed: machine.os_installed.clone(),
+            disks: machine.disks.iter().map(proto::Disk::from).collect(),
+            nameservers: machine.nameservers.clone(),
+            cpu_model: machine.cpu_model.clone(),
+            cpu_cores: machine.cpu_cores,
+            total_ram_bytes: machine.total_ram_bytes,
+            installation_progress: machine.installation_progress.into(),
+            created_at: machine.created_at.to_rfc3339(),
+            updated_at: machine.updated_at.to_rfc3339(),
+        }
+    }
+}
+
+impl From<changes::MachineChange> for proto::MachineChange {
+    fn from(change: changes::MachineChange) -> Self {
+        let kind = match change.kind {
+            ChangeKind::Created => proto::machine_change::Kind::Created,
+            ChangeKind::Updated => proto::machine_change::Kind::Updated,
+            ChangeKind::Deleted => proto::machine_change::Kind::Deleted,
+        };
+        proto::MachineChange {
+            kind: kind.into(),
+            machine_id: change.machine_id.to_string(),
+            changed_at: change.changed_at.to_rfc3339(),
+            machine: change.machine.as_ref().map(proto::Machine::from),
+        }
+    }
+}
+
+pub struct AgentService {
+    event_manager: Arc<EventManager>,
+    peer_fingerprints: Arc<[String]>,
+}
+
+impl AgentService {
+    fn check_role<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        match client_role(&self.peer_fingerprints, request) {
+            Role::Agent => Ok(()),
+            Role::Peer => Err(Status::permission_denied("Peer certificates can't act as agents")),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Agent for AgentService {
+    async fn register(&self, request: Request<proto::RegisterRequest>) -> Result<Response<proto::RegisterResponse>, Status> {
+        self.check_role(&request)?;
+        let payload = RegisterRequest::try_from(request.into_inner())?;
+        match register_agent(&payload, &self.event_manager).await {
+            Ok(response) => Ok(Response::new(proto::RegisterResponse {
+                machine_id: response.machine_id.to_string(),
+                next_step: response.next_step,
+            })),
+            Err(RegistrationError::AttestationFailed(message)) => Err(Status::permission_denied(message)),
+            Err(RegistrationError::Database(e)) => {
+                error!("Failed to register machine over gRPC: {}", e);
+                Err(Status::internal(e.to_string()))
+            },
+        }
+    }
+
+    async fn heartbeat(&self, request: Request<proto::HeartbeatRequest>) -> Result<Response<proto::HeartbeatResponse>, Status> {
+        self.check_role(&request)?;
+        let id = parse_machine_id(&request.into_inner().machine_id)?;
+        match db::get_machine_by_id(&id).await {
+            Ok(Some(_)) => {},
+            Ok(None) => return Err(Status::not_found(format!("Machine with ID {} not found", id))),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
+        match db::record_heartbeat(&id).await {
+            Ok(restored) => {
+                if restored.is_some() {
+                    let _ = self.event_manager.send(format!("machine_online:{}", id));
+                    let _ = self.event_manager.send(format!("machine_updated:{}", id));
+                }
+                Ok(Response::new(proto::HeartbeatResponse { offline_after_seconds: crate::heartbeat::offline_after_secs() }))
+            },
+            Err(e) => {
+                error!("Failed to record heartbeat for machine {}: {}", id, e);
+                Err(Status::internal(e.to_string()))
+            },
+        }
+    }
+}
+
+pub struct EventsService {
+    event_manager: Arc<EventManager>,
+}
+
+fn event_message(message: &str) -> proto::Event {
+    let (event, subject) = split_event(message);
+    let is_payload = event == "ip_download_progress";
+    proto::Event {
+        event: event.to_string(),
+        subject: subject.filter(|_| !is_payload).map(str::to_string),
+        data: subject.filter(|_| is_payload).map(str::to_string),
+    }
+}
+
+#[tonic::async_trait]
+impl Events for EventsService {
+    type SubscribeStream = ReceiverStream<Result<proto::Event, Status>>;
+
+    async fn subscribe(&self, request: Request<proto::SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        let request = request.into_inner();
+        let filter = Filter {
+            events: request.events,
+            machines: request.machines.iter().map(|id| parse_machine_id(id)).collect::<Result<_, _>>()?,
+        };
+        let mut rx = self.event_manager.subscribe();
+        let (tx, stream) = mpsc::channel(STREAM_BUFFER);
+
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    message = rx.recv() => match message {
+                        Ok(message) => {
+                            let (event, subject) = split_event(&message);
+                            if !filter.matches(event, subject) {
+                                continue;
+                            }
+                            event_message(&message)
+                        },
+                        // Tell the client, so it can refetch what it missed
+                        Err(RecvError::Lagged(skipped)) => proto::Event {
+                            event: "lagged".to_string(),
+                            subject: None,
+                            data: Some(serde_json::json!({ "skipped": skipped }).to_string()),
+                        },
+                        Err(RecvError::Closed) => break,
+                    },
+                    _ = tx.closed() => break,
+                };
+                if tx.send(Ok(event)).await.is_err() {
+                    break;
+                }
+            }
+            debug!("gRPC event stream closed");
+        });
+
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+}
+
+pub struct PeerService {
+    event_manager: Arc<EventManager>,
+    peer_fingerprints: Arc<[String]>,
+}
+
+async fn load_changes(since: Option<Cursor>) -> Result<changes::ChangeSet, Status> {
+    changes::load(since, changes::DEFAULT_LIMIT).await
+        .map_err(|e| Status::internal(format!("Failed to load machine changes: {}", e)))
+}
+
+#[tonic::async_trait]
+impl Peer for PeerService {
+    type SyncMachinesStream = ReceiverStream<Result<proto::ChangeBatch, Status>>;
+
+    async fn sync_machines(&self, request: Request<proto::SyncRequest>) -> Result<Response<Self::SyncMachinesStream>, Status> {
+        // The full machine list, so agents may not ask for it
+        if client_role(&self.peer_fingerprints, &request) != Role::Peer {
+            return Err(Status::permission_denied(format!("Only certificates listed in {} can sync machines", GRPC_PEER_FINGERPRINTS_ENV_VAR)));
+        }
+        let since = request.into_inner().since;
+        let mut cursor = match since.trim() {
+            "" => None,
+            since => Some(Cursor::parse(since).map_err(Status::invalid_argument)?),
+        };
+        // Check the first batch loads before committing to a stream
+        let first = load_changes(cursor).await?;
+        let mut rx = self.event_manager.subscribe();
+        let (tx, stream) = mpsc::channel(STREAM_BUFFER);
+
+        tokio::spawn(async move {
+            let mut pending = Some(first);
+            let mut first_batch = true;
+            loop {
+                let batch = match pending.take() {
+                    Some(batch) => Ok(batch),
+                    None => load_changes(cursor).await,
+                };
+                let batch = match batch {
+                    Ok(batch) => batch,
+                    Err(status) => {
+                        let _ = tx.send(Err(status)).await;
+                        break;
+                    },
+                };
+                let has_more = batch.has_more;
+                cursor = Cursor::parse(&batch.cursor).ok().or(cursor);
+                // The first batch is always sent so the peer learns its cursor, even with nothing to sync
+                if first_batch || !batch.changes.is_empty() {
+                    let message = proto::ChangeBatch {
+                        changes: batch.changes.into_iter().map(proto::MachineChange::from).collect(),
+                        cursor: batch.cursor,
+                    };
+                    if tx.send(Ok(message)).await.is_err() {
+                        break;
+                    }
+                }
+                first_batch = false;
+                if has_more {
+                    continue;
+                }
+
+                // Wait for something to change, then for it to settle into the change feed
+                let poll = tokio::time::sleep(SYNC_POLL);
+                tokio::pin!(poll);
+                loop {
+                    tokio::select! {
+                        message = rx.recv() => match message {
+                            Ok(message) if message.starts_with("machine_") => break,
+                            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                            Err(RecvError::Closed) => return,
+                        },
+                        _ = &mut poll => break,
+                        _ = tx.closed() => return,
+                    }
+                }
+                tokio::time::sleep(Duration::from_secs(changes::SETTLE_SECONDS as u64 + 1)).await;
+            }
+            debug!("gRPC peer sync stream closed");
+        });
+
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+}
+
+/// Serve the agent, event and peer services on their own port until shutdown
+pub async fn start_grpc_server(config: GrpcConfig, event_manager: Arc<EventManager>, mut shutdown_rx: watch::Receiver<()>) -> Result<(), String> {
+    let tls = config.tls().await?;
+    let peer_fingerprints: Arc<[String]> = config.peer_fingerprints.clone().into();
+    let router = Server::builder()
+        .tls_config(tls)
+        .map_err(|e| format!("Invalid gRPC TLS configuration: {}", e))?
+        .add_service(AgentServer::new(AgentService { event_manager: event_manager.clone(), peer_fingerprints: peer_fingerprints.clone() }))
+        .add_service(EventsServer::new(EventsService { event_manager: event_manager.clone() }))
+        .add_service(PeerServer::new(PeerService { event_manager, peer_fingerprints }));
+
+    info!("gRPC API listening on {} (mutual TLS)", config.listen);
+    tokio::spawn(async move {
+        let shutdown = async move {
+            let _ = shutdown_rx.changed().await;
+        };
+        if let Err(e) = router.serve_with_shutdown(config.listen, shutdown).await {
+            warn!("gRPC server stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_request_and_events() {
+        let request = proto::RegisterRequest {
+            mac_address: "52:54:00:12:34:56".to_string(),
+            ip_address: "10.0.0.5".to_string(),
+            disks: vec![proto::Disk { device: "/dev/sda".to_string(), size_bytes: 1 << 30, model: None }],
+            agent_time: Some("2024-05-01T12:00:00Z".to_string()),
+            ..Default::default()
+        };
+        let payload = RegisterRequest::try_from(request.clone()).unwrap();
+        assert_eq!(payload.disks[0].device, "/dev/sda");
+        assert_eq!(payload.agent_time.unwrap().to_rfc3339(), "2024-05-01T12:00:00+00:00");
+        let bad_time = proto::RegisterRequest { agent_time: Some("yesterday".to_string()), ..request };
+        assert!(RegisterRequest::try_from(bad_time).is_err());
+
+        let event = event_message("machine_updated:abc");
+        assert_eq!((event.subject.as_deref(), event.data.as_deref()), (Some("abc"), None));
+        let progress = event_message(r#"ip_download_progress:{"percent":50}"#);
+        assert_eq!(progress.data.as_deref(), Some(r#"{"percent":50}"#));
+    }
+    #[test]
+    fn test_peer_and_agent_roles() {
+        let peer_cert = b"peer certificate";
+        let listed = fingerprint(peer_cert).to_uppercase();
+        let colons = listed.as_bytes().chunks(2).map(|pair| std::str::from_utf8(pair).unwrap()).collect::<Vec<_>>().join(":");
+        let peers = parse_fingerprints(&format!(" {} ,", colons)).unwrap();
+        assert_eq!(peers, vec![fingerprint(peer_cert)]);
+        assert!(parse_fingerprints("abc").is_err());
+        assert!(parse_fingerprints("").unwrap().is_empty());
+
+        assert_eq!(role_of(&peers, Some(peer_cert)), Role::Peer);
+        assert_eq!(role_of(&peers, Some(b"agent certificate")), Role::Agent);
+        assert_eq!(role_of(&peers, None), Role::Agent);
+        assert_eq!(role_of(&[], Some(peer_cert)), Role::Agent);
+    }
+}
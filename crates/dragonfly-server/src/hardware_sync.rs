@@ -0,0 +1,297 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use dragonfly_common::models::Machine;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::{Arc, RwLock};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::db;
+use crate::event_manager::EventManager;
+use crate::tinkerbell;
+
+/// What to do when a Hardware resource no longer matches Dragonfly: `restore`, `adopt` or `report`
+pub const DRIFT_POLICY_ENV_VAR: &str = "DRAGONFLY_HARDWARE_DRIFT_POLICY";
+
+const RECONCILE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+/// Machines changed this recently may still be on their way into Tinkerbell
+const GRACE_SECS: i64 = 120;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftPolicy {
+    /// Re-apply Dragonfly's Hardware resource, recreating it if it was deleted
+    #[default]
+    Restore,
+    /// Take changed IP addresses into Dragonfly's database; report everything else
+    Adopt,
+    /// Only flag drifted machines
+    Report,
+}
+
+impl DriftPolicy {
+    pub fn from_env() -> Result<Self, String> {
+        match env::var(DRIFT_POLICY_ENV_VAR).as_deref().map(str::trim) {
+            Err(_) | Ok("") | Ok("restore") => Ok(DriftPolicy::Restore),
+            Ok("adopt") => Ok(DriftPolicy::Adopt),
+            Ok("report") => Ok(DriftPolicy::Report),
+            Ok(other) => Err(format!("{} must be restore, adopt or report, got '{}'", DRIFT_POLICY_ENV_VAR, other)),
+        }
+    }
+}
+
+/// One way a Hardware resource differs from what Dragonfly registered
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Drift {
+    Missing,
+    Mac { expected: String, found: String },
+    Ip { expected: String, found: String },
+    NetbootDisabled,
+}
+
+impl Drift {
+    pub fn describe(&self) -> String {
+        match self {
+            Drift::Missing => "Hardware resource was deleted in Kubernetes".to_string(),
+            Drift::Mac { expected, found } => format!("Hardware MAC is {} instead of {}", found, expected),
+            Drift::Ip { expected, found } => format!("Hardware IP is {} instead of {}", found, expected),
+            Drift::NetbootDisabled => "PXE or workflows were disabled on the Hardware resource".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MachineDrift {
+    pub machine_id: Uuid,
+    pub mac_address: String,
+    pub drift: Vec<Drift>,
+    /// What the policy did about it, if anything
+    pub action: Option<String>,
+    /// Whether the machine still differs from its Hardware resource
+    pub unresolved: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DriftReport {
+    pub checked_at: Option<DateTime<Utc>>,
+    pub policy: DriftPolicy,
+    pub machines: Vec<MachineDrift>,
+    /// Dragonfly-named Hardware resources for MACs Dragonfly doesn't know
+    pub orphans: Vec<String>,
+}
+
+lazy_static::lazy_static! {
+    static ref LAST_REPORT: RwLock<DriftReport> = RwLock::new(DriftReport::default());
+}
+
+fn dhcp(hardware: &Value) -> Option<&Value> {
+    hardware.pointer("/spec/interfaces/0/dhcp")
+}
+
+/// Compare a machine with the data of its Hardware resource, if it has one
+pub fn detect(machine: &Machine, hardware: Option<&Value>) -> Vec<Drift> {
+    let Some(hardware) = hardware else {
+        return vec![Drift::Missing];
+    };
+    let mut drift = Vec::new();
+    let found_mac = dhcp(hardware).and_then(|d| d.get("mac")).and_then(Value::as_str).unwrap_or("");
+    if db::normalize_mac(found_mac) != db::normalize_mac(&machine.mac_address) {
+        drift.push(Drift::Mac { expected: machine.mac_address.clone(), found: found_mac.to_string() });
+    }
+    // Placeholders like "Unknown" aren't a claim about where the machine is
+    if machine.ip_address.parse::<std::net::IpAddr>().is_ok() {
+        let found_ip = dhcp(hardware).and_then(|d| d.pointer("/ip/address")).and_then(Value::as_str).unwrap_or("");
+        if found_ip.trim() != machine.ip_address.trim() {
+            drift.push(Drift::Ip { expected: machine.ip_address.clone(), found: found_ip.to_string() });
+        }
+    }
+    let netboot = hardware.pointer("/spec/interfaces/0/netboot");
+    let allowed = |field: &str| netboot.and_then(|n| n.get(field)).and_then(Value::as_bool).unwrap_or(false);
+    if !allowed("allowPXE") || !allowed("allowWorkflow") {
+        drift.push(Drift::NetbootDisabled);
+    }
+    drift
+}
+
+/// The last report's unresolved drift, described per machine, for the UI
+pub fn reasons() -> HashMap<Uuid, Vec<String>> {
+    LAST_REPORT.read().unwrap().machines.iter()
+        .filter(|m| m.unresolved)
+        .map(|m| (m.machine_id, m.drift.iter().map(Drift::describe).collect()))
+        .collect()
+}
+
+pub fn last_report() -> DriftReport {
+    LAST_REPORT.read().unwrap().clone()
+}
+
+/// Apply the policy to one drifted machine, returning what was done and whether drift remains
+async fn resolve(policy: DriftPolicy, machine: &Machine, drift: &[Drift]) -> (Option<String>, bool) {
+    match policy {
+        DriftPolicy::Restore => match tinkerbell::register_machine(machine).await {
+            Ok(()) => (Some("restored Hardware resource".to_string()), false),
+            Err(e) => (Some(format!("failed to restore Hardware resource: {}", e)), true),
+        },
+        DriftPolicy::Adopt => {
+            let Some(found) = drift.iter().find_map(|d| match d {
+                Drift::Ip { found, .. } if found.parse::<std::net::IpAddr>().is_ok() => Some(found.clone()),
+                _ => None,
+            }) else {
+                return (None, true);
+            };
+            let unresolved = drift.iter().any(|d| !matches!(d, Drift::Ip { .. }));
+            match db::update_ip_address(&machine.id, &found).await {
+                Ok(_) => (Some(format!("adopted IP {}", found)), unresolved),
+                Err(e) => (Some(format!("failed to adopt IP {}: {}", found, e)), true),
+            }
+        },
+        DriftPolicy::Report => (None, true),
+    }
+}
+
+/// Compare every machine with its Hardware resource and apply the drift policy
+pub async fn reconcile(event_manager: &EventManager) -> Result<DriftReport> {
+    let policy = DriftPolicy::from_env().map_err(|e| anyhow!(e))?;
+    let hardware = tinkerbell::list_hardware().await?;
+    let machines = db::get_all_machines().await?;
+
+    let mut by_mac: HashMap<String, (String, Value)> = hardware.into_iter()
+        .filter_map(|h| {
+            let name = h.metadata.name.clone()?;
+            let mac = dhcp(&h.data).and_then(|d| d.get("mac")).and_then(Value::as_str).map(db::normalize_mac)?;
+            Some((mac, (name, h.data)))
+        })
+        .collect();
+
+    let previously: HashSet<Uuid> = LAST_REPORT.read().unwrap().machines.iter()
+        .filter(|m| m.unresolved).map(|m| m.machine_id).collect();
+    let recent = Utc::now() - Duration::seconds(GRACE_SECS);
+    let mut report = DriftReport { checked_at: Some(Utc::now()), policy, ..Default::default() };
+    let mut known_names = HashSet::new();
+    for machine in &machines {
+        let name = tinkerbell::hardware_resource_name(&machine.mac_address);
+        known_names.insert(name.clone());
+        // Found by MAC, or by name if the MAC itself was edited
+        let found = by_mac.remove(&db::normalize_mac(&machine.mac_address)).map(|(_, data)| data)
+            .or_else(|| {
+                let key = by_mac.iter().find(|(_, (n, _))| *n == name).map(|(mac, _)| mac.clone())?;
+                by_mac.remove(&key).map(|(_, data)| data)
+            });
+        // Machines awaiting approval are deliberately kept out of Tinkerbell
+        if machine.status.is_approval_gated() || machine.updated_at > recent {
+            continue;
+        }
+        let drift = detect(machine, found.as_ref());
+        if drift.is_empty() {
+            continue;
+        }
+        let descriptions: Vec<String> = drift.iter().map(Drift::describe).collect();
+        warn!("Machine {} has drifted from its Hardware resource: {}", machine.id, descriptions.join("; "));
+        let (action, unresolved) = resolve(policy, machine, &drift).await;
+        if let Some(action) = &action {
+            info!("Hardware drift for machine {}: {}", machine.id, action);
+        }
+        // Sent as soon as the machine changes, e.g. when its IP was adopted
+        if action.is_some() || !previously.contains(&machine.id) {
+            let _ = event_manager.send(format!("machine_updated:{}", machine.id));
+        }
+        report.machines.push(MachineDrift {
+            machine_id: machine.id,
+            mac_address: machine.mac_address.clone(),
+            drift,
+            action,
+            unresolved,
+        });
+    }
+    report.orphans = by_mac.into_values()
+        .map(|(name, _)| name)
+        .filter(|name| name.starts_with("machine-") && !known_names.contains(name))
+        .collect();
+    report.orphans.sort();
+
+    // Announce machines entering or leaving drift, like the conflict monitor does
+    let flagged: HashSet<Uuid> = report.machines.iter().filter(|m| m.unresolved).map(|m| m.machine_id).collect();
+    for id in flagged.difference(&previously) {
+        let _ = event_manager.send(format!("hardware_drift:{}", id));
+    }
+    for id in previously.difference(&flagged) {
+        if !report.machines.iter().any(|m| m.machine_id == *id) {
+            let _ = event_manager.send(format!("machine_updated:{}", id));
+        }
+    }
+
+    *LAST_REPORT.write().unwrap() = report.clone();
+    Ok(report)
+}
+
+/// Periodically reconcile Tinkerbell Hardware resources with Dragonfly's machines
+pub async fn start_reconcile_task(
+    event_manager: Arc<EventManager>,
+    mut shutdown_rx: tokio::sync::watch::Receiver<()>,
+) {
+    let policy = match DriftPolicy::from_env() {
+        Ok(policy) => policy,
+        Err(e) => {
+            error!("Hardware reconciliation disabled: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        info!("Starting Hardware reconciliation (every {}s, policy {:?})", RECONCILE_INTERVAL.as_secs(), policy);
+        let mut ticker = tokio::time::interval(RECONCILE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    if tinkerbell::get_client().await.is_err() {
+                        debug!("Skipping Hardware reconciliation, Kubernetes is not reachable");
+                        continue;
+                    }
+                    match reconcile(&event_manager).await {
+                        Ok(report) => debug!("Hardware reconciliation found {} drifted machines and {} orphans",
+                            report.machines.len(), report.orphans.len()),
+                        Err(e) => error!("Hardware reconciliation failed: {}", e),
+                    }
+                },
+                _ = shutdown_rx.changed() => {
+                    info!("Shutdown signal received, stopping Hardware reconciliation.");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn machine() -> Machine {
+        crate::test_support::machine("AA:BB:CC:DD:EE:01")
+    }
+
+    fn hardware(mac: &str, ip: &str, allow_pxe: bool) -> Value {
+        json!({ "spec": { "interfaces": [{
+            "dhcp": { "mac": mac, "ip": { "address": ip } },
+            "netboot": { "allowPXE": allow_pxe, "allowWorkflow": true },
+        }] } })
+    }
+
+    #[test]
+    fn test_detect_drift() {
+        let m = machine();
+        assert!(detect(&m, Some(&hardware("aa:bb:cc:dd:ee:01", "10.0.0.5", true))).is_empty());
+        assert_eq!(detect(&m, None), vec![Drift::Missing]);
+        assert_eq!(detect(&m, Some(&hardware("aa:bb:cc:dd:ee:01", "10.0.0.9", false))), vec![
+            Drift::Ip { expected: "10.0.0.5".to_string(), found: "10.0.0.9".to_string() },
+            Drift::NetbootDisabled,
+        ]);
+
+        let unknown_ip = Machine { ip_address: "Unknown".to_string(), ..m };
+        assert!(detect(&unknown_ip, Some(&hardware("aa:bb:cc:dd:ee:01", "10.0.0.9", true))).is_empty());
+    }
+}
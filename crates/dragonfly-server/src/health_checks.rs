@@ -0,0 +1,262 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use dragonfly_common::agent_protocol::{CommandKind, CommandStatus};
+use dragonfly_common::models::{Machine, MachineStatus};
+use dragonfly_common::state_machine::StatusCause;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::db;
+
+/// How often a check that hasn't passed yet is tried again, e.g. while the machine reboots
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Check output kept in status history; the full output is in the machine's health report
+const HISTORY_OUTPUT_CHARS: usize = 500;
+
+/// Machines whose checks are running, so a workflow polled again doesn't start them twice
+static RUNNING: Lazy<Mutex<HashSet<Uuid>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Something that has to be true of a freshly installed machine before it is Ready
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HealthCheck {
+    /// The installed OS accepts TCP connections on the SSH port
+    SshReachable {
+        #[serde(default = "default_ssh_port")]
+        port: u16,
+    },
+    /// `cloud-init status --wait` succeeds, run by the machine's agent
+    CloudInitFinished,
+    /// A script run by the machine's agent exits 0
+    Script {
+        script: String,
+    },
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+/// The checks run after a template's workflow completes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateChecks {
+    pub checks: Vec<HealthCheck>,
+    /// How long all the checks together may take to pass
+    #[serde(default = "default_timeout_minutes")]
+    pub timeout_minutes: u32,
+}
+
+fn default_timeout_minutes() -> u32 {
+    30
+}
+
+impl Default for TemplateChecks {
+    fn default() -> Self {
+        Self { checks: Vec::new(), timeout_minutes: default_timeout_minutes() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckResult {
+    pub check: String,
+    pub passed: bool,
+    pub output: String,
+}
+
+/// The outcome of a machine's latest health checks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub template: String,
+    /// None while the checks are running
+    pub passed: Option<bool>,
+    pub results: Vec<CheckResult>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl HealthCheck {
+    pub fn describe(&self) -> String {
+        match self {
+            HealthCheck::SshReachable { port } => format!("SSH reachable on port {}", port),
+            HealthCheck::CloudInitFinished => "cloud-init finished".to_string(),
+            HealthCheck::Script { script } => {
+                let first_line = script.lines().next().unwrap_or_default();
+                format!("script `{}`", first_line)
+            },
+        }
+    }
+}
+
+pub fn validate(checks: &TemplateChecks) -> Result<(), String> {
+    if checks.timeout_minutes == 0 {
+        return Err("The checks need at least a minute to run".to_string());
+    }
+    for check in &checks.checks {
+        match check {
+            HealthCheck::SshReachable { port: 0 } => return Err("Port 0 can't be checked".to_string()),
+            HealthCheck::Script { script } if script.trim().is_empty() => {
+                return Err("A script check needs a script".to_string());
+            },
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+/// The checks for a template, if it has any
+pub async fn for_template(template: Option<&str>) -> Result<Option<TemplateChecks>> {
+    let Some(template) = template else {
+        return Ok(None);
+    };
+    Ok(db::get_template_health_checks(template).await?.filter(|checks| !checks.checks.is_empty()))
+}
+
+/// Wait for a command queued for the machine's agent, until the deadline
+async fn run_on_agent(machine_id: &Uuid, script: &str, deadline: tokio::time::Instant) -> Result<(bool, String)> {
+    let timeout_secs = deadline.saturating_duration_since(tokio::time::Instant::now()).as_secs();
+    let kind = CommandKind::RunScript { script: script.to_string(), timeout_secs: Some(timeout_secs) };
+    let id = db::add_agent_command(machine_id, &kind, Some("health checks")).await?;
+    loop {
+        if let Some(command) = db::get_agent_command(id).await? {
+            if matches!(command.status, CommandStatus::Succeeded | CommandStatus::Failed) {
+                return Ok((command.exit_code == Some(0), command.output.unwrap_or_default()));
+            }
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Ok((false, "The machine's agent didn't run the check in time; is it enrolled?".to_string()));
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+async fn run_check(machine: &Machine, check: &HealthCheck, deadline: tokio::time::Instant) -> Result<(bool, String)> {
+    match check {
+        HealthCheck::SshReachable { port } => {
+            let Ok(ip) = machine.ip_address.parse::<IpAddr>() else {
+                return Ok((false, format!("The machine has no usable IP address ({})", machine.ip_address)));
+            };
+            loop {
+                let attempt = tokio::time::timeout(RETRY_INTERVAL, tokio::net::TcpStream::connect((ip, *port))).await;
+                match attempt {
+                    Ok(Ok(_)) => return Ok((true, format!("{}:{} accepted a connection", ip, port))),
+                    Ok(Err(e)) if tokio::time::Instant::now() >= deadline => return Ok((false, format!("{}:{}: {}", ip, port, e))),
+                    Err(_) if tokio::time::Instant::now() >= deadline => return Ok((false, format!("{}:{}: timed out", ip, port))),
+                    _ => tokio::time::sleep(RETRY_INTERVAL).await,
+                }
+            }
+        },
+        HealthCheck::CloudInitFinished => run_on_agent(&machine.id, "cloud-init status --wait --long", deadline).await,
+        HealthCheck::Script { script } => run_on_agent(&machine.id, script, deadline).await,
+    }
+}
+
+/// Run the checks in order, stopping at the first that fails
+async fn run(machine: &Machine, template: &str, checks: &TemplateChecks) -> Result<HealthReport> {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(checks.timeout_minutes as u64 * 60);
+    let mut report = HealthReport {
+        template: template.to_string(),
+        passed: None,
+        results: Vec::new(),
+        started_at: Utc::now(),
+        finished_at: None,
+    };
+    db::save_health_report(&machine.id, &report).await?;
+    for check in &checks.checks {
+        let (passed, output) = run_check(machine, check, deadline).await?;
+        report.results.push(CheckResult { check: check.describe(), passed, output });
+        db::save_health_report(&machine.id, &report).await?;
+        if !passed {
+            break;
+        }
+    }
+    report.passed = Some(report.results.iter().all(|result| result.passed));
+    report.finished_at = Some(Utc::now());
+    db::save_health_report(&machine.id, &report).await?;
+    Ok(report)
+}
+
+/// Mark the machine Ready if its checks passed, or Error with the failing check's output
+async fn finish(machine: &Machine, report: &HealthReport) -> Result<()> {
+    // Reassigned, reimaged or deleted while the checks ran
+    let current = db::get_machine_by_id(&machine.id).await?;
+    let still_installing = current.is_some_and(|current| current.status == MachineStatus::InstallingOS && current.os_choice == machine.os_choice);
+    // A reimage to the same OS clears the report these checks were writing
+    let same_report = db::get_health_report(&machine.id).await?.is_some_and(|saved| saved.started_at == report.started_at);
+    if !still_installing || !same_report {
+        info!("Machine {} moved on while its health checks ran; ignoring the result", machine.id);
+        return Ok(());
+    }
+    if report.passed == Some(true) {
+        info!("Health checks passed for machine {}", machine.id);
+        let cause = StatusCause::Workflow(format!("workflow completed and {} health checks passed", report.results.len()));
+        return crate::tinkerbell::mark_ready(machine, cause).await;
+    }
+    let Some(failed) = report.results.iter().find(|result| !result.passed) else {
+        return Ok(());
+    };
+    warn!("Health check '{}' failed for machine {}: {}", failed.check, machine.id, failed.output);
+    let output: String = failed.output.chars().take(HISTORY_OUTPUT_CHARS).collect();
+    let cause = StatusCause::Workflow(format!("health check '{}' failed: {}", failed.check, output));
+    db::update_status(&machine.id, MachineStatus::Error(format!("Health check failed: {}", failed.check)), &cause).await?;
+    if let Err(e) = db::record_install_outcome(machine, false).await {
+        warn!("Failed to record installation outcome: {}", e);
+    }
+    if let Ok(event_manager) = crate::EVENT_MANAGER_REF.read() {
+        if let Some(event_manager) = event_manager.as_ref() {
+            let _ = event_manager.send(format!("install_failed:{}", machine.id));
+        }
+    }
+    Ok(())
+}
+
+/// Run a freshly installed machine's health checks in the background. The machine stays
+/// InstallingOS until they finish.
+pub fn start(machine: Machine, checks: TemplateChecks) {
+    if !RUNNING.lock().unwrap().insert(machine.id) {
+        return;
+    }
+    tokio::spawn(async move {
+        let template = machine.os_choice.clone().unwrap_or_default();
+        info!("Running {} health checks for machine {} ({})", checks.checks.len(), machine.id, template);
+        if let Err(e) = db::update_installation_progress(&machine.id, 100, Some("Running health checks")).await {
+            warn!("Failed to update installation step for machine {}: {}", machine.id, e);
+        }
+        let outcome = match run(&machine, &template, &checks).await {
+            Ok(report) => finish(&machine, &report).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = outcome {
+            // The checks run again the next time the completed workflow is seen
+            error!("Failed to run health checks for machine {}: {}", machine.id, e);
+        }
+        RUNNING.lock().unwrap().remove(&machine.id);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checks_parse() {
+        let checks: TemplateChecks = serde_json::from_str(r#"{
+            "checks": [
+                {"type": "ssh_reachable"},
+                {"type": "cloud_init_finished"},
+                {"type": "script", "script": "systemctl is-active kubelet\n"}
+            ]
+        }"#).unwrap();
+        assert_eq!(checks.timeout_minutes, 30);
+        assert_eq!(checks.checks[0], HealthCheck::SshReachable { port: 22 });
+        assert_eq!(checks.checks[2].describe(), "script `systemctl is-active kubelet`");
+        assert!(validate(&checks).is_ok());
+        assert!(validate(&TemplateChecks { checks: vec![HealthCheck::Script { script: " ".to_string() }], timeout_minutes: 5 }).is_err());
+    }
+}
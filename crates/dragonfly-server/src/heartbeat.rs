@@ -0,0 +1,87 @@
+use chrono::Utc;
+use dragonfly_common::models::MachineStatus;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+use crate::db;
+use crate::event_manager::EventManager;
+
+pub const DEFAULT_OFFLINE_AFTER_SECS: i64 = 300;
+
+/// How long a machine may go without a heartbeat before it is marked Offline.
+/// Configurable with DRAGONFLY_OFFLINE_AFTER (seconds).
+pub fn offline_after_secs() -> i64 {
+    env::var("DRAGONFLY_OFFLINE_AFTER")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_OFFLINE_AFTER_SECS)
+}
+
+/// Whether a silent machine in this status should be marked Offline. Installing machines run
+/// HookOS rather than the agent, and rejected machines never run it, so silence is expected.
+pub fn should_go_offline(status: &MachineStatus) -> bool {
+    !matches!(status, MachineStatus::Offline | MachineStatus::InstallingOS | MachineStatus::Rejected)
+}
+
+async fn check_for_silent_machines(event_manager: &EventManager) {
+    let cutoff = Utc::now() - chrono::Duration::seconds(offline_after_secs());
+    let silent = match db::get_silent_machines(cutoff).await {
+        Ok(silent) => silent,
+        Err(e) => {
+            error!("Failed to check machine heartbeats: {}", e);
+            return;
+        }
+    };
+
+    for (id, status) in silent.into_iter().filter(|(_, status)| should_go_offline(status)) {
+        match db::mark_offline(&id).await {
+            Ok(true) => {
+                warn!("Machine {} has not sent a heartbeat for {}s, marking it Offline (was {:?})", id, offline_after_secs(), status);
+                let _ = event_manager.send(format!("machine_offline:{}", id));
+                let _ = event_manager.send(format!("machine_updated:{}", id));
+            },
+            Ok(false) => debug!("Machine {} changed before it could be marked Offline", id),
+            Err(e) => error!("Failed to mark machine {} Offline: {}", id, e),
+        }
+    }
+}
+
+/// Periodically mark machines that have stopped sending heartbeats as Offline
+pub async fn start_offline_detection_task(
+    event_manager: Arc<EventManager>,
+    mut shutdown_rx: tokio::sync::watch::Receiver<()>,
+) {
+    // Check a few times per window so machines go Offline close to the configured time
+    let interval = Duration::from_secs((offline_after_secs() as u64 / 4).clamp(5, 60));
+    tokio::spawn(async move {
+        info!("Starting offline detection task (offline after {}s of silence)", offline_after_secs());
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => check_for_silent_machines(&event_manager).await,
+                _ = shutdown_rx.changed() => {
+                    info!("Shutdown signal received, stopping offline detection task.");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_which_statuses_go_offline() {
+        assert!(should_go_offline(&MachineStatus::Ready));
+        assert!(should_go_offline(&MachineStatus::AwaitingAssignment));
+        assert!(should_go_offline(&MachineStatus::Error("disk".to_string())));
+        assert!(!should_go_offline(&MachineStatus::Offline));
+        assert!(!should_go_offline(&MachineStatus::InstallingOS));
+        assert!(!should_go_offline(&MachineStatus::Rejected));
+    }
+}
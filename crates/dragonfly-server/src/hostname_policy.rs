@@ -0,0 +1,153 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::db;
+
+/// How hostnames are generated for newly adopted machines
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HostnamePolicy {
+    /// Keep whatever hostname the agent reported
+    #[default]
+    None,
+    /// Prefix plus the lowest free zero-padded sequence number, e.g. node-001
+    Sequence {
+        prefix: String,
+        start: u32,
+        width: usize,
+    },
+    /// Prefix plus the last three octets of the MAC address, e.g. node-b95489
+    MacDerived {
+        prefix: String,
+    },
+    /// Look the MAC address up in a CSV of `mac,hostname` rows (local path or http(s) URL)
+    Csv {
+        source: String,
+    },
+}
+
+impl HostnamePolicy {
+    pub fn is_active(&self) -> bool {
+        !matches!(self, HostnamePolicy::None)
+    }
+}
+
+/// Generate a hostname for a machine according to the given policy.
+/// Returns `None` when the policy doesn't produce a name for this machine.
+pub async fn generate_hostname(policy: &HostnamePolicy, mac_address: &str) -> Result<Option<String>> {
+    match policy {
+        HostnamePolicy::None => Ok(None),
+        HostnamePolicy::Sequence { prefix, start, width } => {
+            let machines = db::get_all_machines().await?;
+            let taken: HashSet<String> = machines.into_iter()
+                .filter_map(|m| m.hostname)
+                .collect();
+            Ok(Some(next_sequence_hostname(prefix, *start, *width, &taken)))
+        }
+        HostnamePolicy::MacDerived { prefix } => Ok(Some(mac_derived_hostname(prefix, mac_address))),
+        HostnamePolicy::Csv { source } => {
+            let content = load_csv(source).await?;
+            Ok(lookup_csv_hostname(&content, mac_address))
+        }
+    }
+}
+
+/// Apply the configured hostname policy to a newly adopted machine. A machine profile's
+/// policy, when the machine matched one that has its own, wins over the global one.
+pub async fn apply_to_machine(id: &Uuid) -> Result<Option<String>> {
+    let policy = match crate::machine_profiles::for_machine(id).await?.and_then(|p| p.hostname_policy) {
+        Some(policy) => policy,
+        None => db::get_app_settings().await?.hostname_policy,
+    };
+    if !policy.is_active() {
+        return Ok(None);
+    }
+
+    let machine = db::get_machine_by_id(id).await?
+        .ok_or_else(|| anyhow!("Machine {} not found", id))?;
+
+    match generate_hostname(&policy, &machine.mac_address).await? {
+        Some(hostname) => {
+            db::update_hostname(id, &hostname).await?;
+            info!("Hostname policy assigned hostname {} to machine {}", hostname, id);
+            Ok(Some(hostname))
+        }
+        None => {
+            warn!("Hostname policy produced no hostname for machine {} ({})", id, machine.mac_address);
+            Ok(None)
+        }
+    }
+}
+
+fn next_sequence_hostname(prefix: &str, start: u32, width: usize, taken: &HashSet<String>) -> String {
+    let mut n = start;
+    loop {
+        let candidate = format!("{}{:0width$}", prefix, n, width = width);
+        if !taken.contains(&candidate) {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+fn mac_derived_hostname(prefix: &str, mac_address: &str) -> String {
+    let octets: Vec<&str> = mac_address.split(':').collect();
+    let suffix = octets[octets.len().saturating_sub(3)..].concat().to_lowercase();
+    format!("{}{}", prefix, suffix)
+}
+
+async fn load_csv(source: &str) -> Result<String> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        let response = reqwest::get(source).await
+            .map_err(|e| anyhow!("Failed to fetch hostname CSV from {}: {}", source, e))?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to fetch hostname CSV from {}: status {}", source, response.status()));
+        }
+        Ok(response.text().await?)
+    } else {
+        tokio::fs::read_to_string(source).await
+            .map_err(|e| anyhow!("Failed to read hostname CSV {}: {}", source, e))
+    }
+}
+
+fn lookup_csv_hostname(content: &str, mac_address: &str) -> Option<String> {
+    let mac = mac_address.to_lowercase();
+    content.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split(',').map(|f| f.trim().trim_matches('"'));
+            Some((fields.next()?, fields.next()?))
+        })
+        .find(|(row_mac, _)| row_mac.to_lowercase() == mac)
+        .map(|(_, hostname)| hostname.to_string())
+        .filter(|hostname| !hostname.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_skips_taken_names() {
+        let taken: HashSet<String> = ["node-001", "node-002"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(next_sequence_hostname("node-", 1, 3, &taken), "node-003");
+        assert_eq!(next_sequence_hostname("rack", 10, 0, &HashSet::new()), "rack10");
+    }
+
+    #[test]
+    fn test_mac_derived_hostname() {
+        assert_eq!(mac_derived_hostname("node-", "BC:24:11:B9:54:89"), "node-b95489");
+    }
+
+    #[test]
+    fn test_csv_lookup() {
+        let csv = "mac,hostname\n# comment\nbc:24:11:b9:54:89, blackberry\n\"aa:bb:cc:dd:ee:ff\",\"apple\"\n";
+        assert_eq!(lookup_csv_hostname(csv, "BC:24:11:B9:54:89"), Some("blackberry".to_string()));
+        assert_eq!(lookup_csv_hostname(csv, "aa:bb:cc:dd:ee:ff"), Some("apple".to_string()));
+        assert_eq!(lookup_csv_hostname(csv, "00:00:00:00:00:00"), None);
+    }
+}
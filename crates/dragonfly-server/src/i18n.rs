@@ -0,0 +1,167 @@
+//! Translations of the web UI.
+//!
+//! Templates wrap their text in `t()`: `{{ t("Machines") }}`, or with placeholders,
+//! `{{ t("Signed in as {name}", name=user_email) }}`. The English text is the message ID, as
+//! with gettext, and each other language has a catalog in `locales/` mapping it to a
+//! translation. Text a catalog doesn't have is shown in English.
+//!
+//! The language is the signed-in user's choice if they made one, or else the best match for the
+//! browser's `Accept-Language` header.
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+
+/// Languages the UI is available in, with their own name for themselves
+pub const LANGUAGES: &[(&str, &str)] = &[("en", "English"), ("de", "Deutsch"), ("es", "Español")];
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+static CATALOGS: Lazy<HashMap<&'static str, HashMap<String, String>>> = Lazy::new(|| {
+    [("de", include_str!("../locales/de.json")), ("es", include_str!("../locales/es.json"))]
+        .into_iter()
+        .map(|(language, catalog)| {
+            let catalog = serde_json::from_str(catalog)
+                .unwrap_or_else(|e| panic!("Invalid {} translation catalog: {}", language, e));
+            (language, catalog)
+        })
+        .collect()
+});
+
+tokio::task_local! {
+    static CURRENT: &'static str;
+}
+
+/// The language of the page being rendered
+pub fn current() -> &'static str {
+    CURRENT.try_with(|language| *language).unwrap_or(DEFAULT_LANGUAGE)
+}
+
+pub fn is_supported(language: &str) -> bool {
+    LANGUAGES.iter().any(|(code, _)| *code == language)
+}
+
+fn supported(tag: &str) -> Option<&'static str> {
+    // `de-AT` is served German, `es-419` Spanish
+    let primary = tag.split(['-', '_']).next()?.to_ascii_lowercase();
+    LANGUAGES.iter().map(|(code, _)| *code).find(|code| *code == primary)
+}
+
+/// Pick the language for a page: the user's preference if we have it, else the supported
+/// language the browser ranks highest, else English
+pub fn negotiate(preferred: Option<&str>, accept_language: Option<&str>) -> &'static str {
+    if let Some(language) = preferred.and_then(supported) {
+        return language;
+    }
+    let mut ranked: Vec<(f32, &'static str)> = accept_language.unwrap_or_default()
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let language = supported(parts.next()?.trim())?;
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (quality > 0.0).then_some((quality, language))
+        })
+        .collect();
+    // Stable, so of equally ranked languages the first listed wins
+    ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+    ranked.first().map(|(_, language)| *language).unwrap_or(DEFAULT_LANGUAGE)
+}
+
+/// `text` in `language`, with each `{name}` replaced by its value
+pub fn translate(language: &str, text: &str, values: &[(&str, String)]) -> String {
+    let mut translated = CATALOGS.get(language)
+        .and_then(|catalog| catalog.get(text))
+        .map(String::as_str)
+        .unwrap_or(text)
+        .to_string();
+    for (name, value) in values {
+        translated = translated.replace(&format!("{{{}}}", name), value);
+    }
+    translated
+}
+
+/// Layer choosing the language pages are rendered in
+pub async fn negotiate_language(request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if path.starts_with("/api/") || path.starts_with("/static/") {
+        return next.run(request).await;
+    }
+    let preferred = crate::preferences::current().and_then(|preferences| preferences.language);
+    let accept_language = request.headers().get(header::ACCEPT_LANGUAGE).and_then(|value| value.to_str().ok());
+    let language = negotiate(preferred.as_deref(), accept_language);
+
+    let mut response = CURRENT.scope(language, next.run(request)).await;
+    response.headers_mut().insert(header::CONTENT_LANGUAGE, HeaderValue::from_static(language));
+    response.headers_mut().append(header::VARY, HeaderValue::from_static("accept-language"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(None, None), "en");
+        assert_eq!(negotiate(None, Some("de-AT,de;q=0.9,en;q=0.8")), "de");
+        assert_eq!(negotiate(None, Some("fr-FR, es;q=0.7, en;q=0.5")), "es");
+        assert_eq!(negotiate(None, Some("en;q=0.3, de;q=0.9")), "de");
+        assert_eq!(negotiate(None, Some("fr, ja")), "en");
+        assert_eq!(negotiate(None, Some("de;q=0, es")), "es");
+        assert_eq!(negotiate(Some("es"), Some("de")), "es");
+        assert_eq!(negotiate(Some("klingon"), Some("de")), "de");
+    }
+
+    #[test]
+    fn test_catalogs() {
+        // Every language translates the same messages, and keeps their placeholders
+        let messages: Vec<&String> = {
+            let mut keys: Vec<&String> = CATALOGS["de"].keys().collect();
+            keys.sort();
+            keys
+        };
+        for (language, catalog) in CATALOGS.iter() {
+            let mut keys: Vec<&String> = catalog.keys().collect();
+            keys.sort();
+            assert_eq!(keys, messages, "{} translates different messages", language);
+            for (text, translation) in catalog {
+                assert!(!translation.is_empty(), "{} has no translation for '{}'", language, text);
+                for placeholder in text.split('{').skip(1).filter_map(|rest| rest.split_once('}')).map(|(name, _)| name) {
+                    assert!(translation.contains(&format!("{{{}}}", placeholder)), "{} drops {{{}}} from '{}'", language, placeholder, text);
+                }
+            }
+        }
+
+        // ...and every message the templates ask for
+        let mut directories = vec![std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("templates")];
+        while let Some(directory) = directories.pop() {
+            for entry in std::fs::read_dir(directory).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    directories.push(path);
+                    continue;
+                }
+                let source = std::fs::read_to_string(&path).unwrap();
+                for (start, _) in source.match_indices("t(\"") {
+                    if source[..start].ends_with(|c: char| c.is_alphanumeric() || c == '_') {
+                        continue;
+                    }
+                    let text = source[start + 3..].split('"').next().unwrap();
+                    assert!(CATALOGS["de"].contains_key(text), "{} asks for '{}', which isn't translated", path.display(), text);
+                }
+            }
+        }
+
+        let values = [("name", "admin".to_string())];
+        assert_eq!(translate("de", "Signed in as {name}", &values), "Angemeldet als admin");
+        assert_eq!(translate("en", "Signed in as {name}", &values), "Signed in as admin");
+        assert_eq!(translate("es", "Not in any catalog", &[]), "Not in any catalog");
+    }
+}
@@ -0,0 +1,323 @@
+use anyhow::Result;
+use dragonfly_common::models::{Machine, MachineStatus, RegisterRequest};
+use dragonfly_common::state_machine::StatusCause;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::custom_fields;
+use crate::db;
+
+/// One machine in an import manifest. Labels are values for admin-defined custom fields.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    #[serde(alias = "mac")]
+    pub mac_address: String,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default, alias = "ip")]
+    pub ip_address: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Line of the CSV manifest the entry was read from
+    #[serde(skip)]
+    pub line: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RowError {
+    /// Line of a CSV manifest, or 1-based position in a JSON one
+    pub row: usize,
+    pub mac_address: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportedMachine {
+    pub mac_address: String,
+    /// Not known for machines a dry run would create
+    pub machine_id: Option<Uuid>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub created: Vec<ImportedMachine>,
+    pub updated: Vec<ImportedMachine>,
+    /// MACs of machines that already exist and were left alone
+    pub skipped: Vec<String>,
+    pub errors: Vec<RowError>,
+}
+
+/// Split one CSV line into fields, honouring double quotes and `""` escapes
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields.into_iter().map(|f| f.trim().to_string()).collect()
+}
+
+/// Parse a CSV manifest. The header names the columns: `mac_address` (or `mac`), `hostname` and
+/// `ip_address` (or `ip`); every other column is a label.
+pub fn parse_csv(text: &str) -> Result<Vec<ManifestEntry>, String> {
+    let mut lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+    let header = csv_fields(lines.next().ok_or("The manifest is empty")?.1);
+    let column = |names: &[&str]| header.iter().position(|h| names.contains(&h.to_lowercase().as_str()));
+    let mac = column(&["mac_address", "mac"]).ok_or("The CSV header needs a mac_address column")?;
+    let hostname = column(&["hostname"]);
+    let ip = column(&["ip_address", "ip"]);
+
+    Ok(lines.map(|(index, line)| {
+        let fields = csv_fields(line);
+        let get = |i: Option<usize>| i.and_then(|i| fields.get(i)).filter(|v| !v.is_empty()).cloned();
+        ManifestEntry {
+            mac_address: get(Some(mac)).unwrap_or_default(),
+            hostname: get(hostname),
+            ip_address: get(ip),
+            labels: header.iter().enumerate()
+                .filter(|(i, _)| *i != mac && Some(*i) != hostname && Some(*i) != ip)
+                .filter_map(|(i, name)| get(Some(i)).map(|value| (name.clone(), value)))
+                .collect(),
+            line: Some(index + 1),
+        }
+    }).collect())
+}
+
+pub fn parse_json(text: &str) -> Result<Vec<ManifestEntry>, String> {
+    serde_json::from_str(text).map_err(|e| format!("Invalid JSON manifest: {}", e))
+}
+
+/// Lower-case, colon-separated form of a MAC address
+pub fn normalize_mac(mac: &str) -> Option<String> {
+    let mac = mac.trim().to_lowercase().replace('-', ":");
+    let parts: Vec<&str> = mac.split(':').collect();
+    (parts.len() == 6 && parts.iter().all(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()))).then_some(mac)
+}
+
+pub(crate) fn valid_hostname(hostname: &str) -> bool {
+    hostname.len() <= 253 && hostname.split('.').all(|label| {
+        !label.is_empty() && label.len() <= 63
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !label.starts_with('-') && !label.ends_with('-')
+    })
+}
+
+/// Check each entry on its own and against the rest of the manifest, normalizing MACs and IPs.
+/// Returns the entries that passed, with their row numbers, and the errors of those that didn't.
+pub fn validate(entries: Vec<ManifestEntry>) -> (Vec<(usize, ManifestEntry)>, Vec<RowError>) {
+    let mut valid = Vec::new();
+    let mut errors = Vec::new();
+    let mut seen_macs = HashSet::new();
+    let mut seen_ips = HashSet::new();
+    let mut seen_hostnames = HashSet::new();
+
+    for (index, mut entry) in entries.into_iter().enumerate() {
+        let row = entry.line.unwrap_or(index + 1);
+        let mut fail = |entry: &ManifestEntry, message: String| errors.push(RowError { row, mac_address: entry.mac_address.clone(), message });
+
+        let Some(mac) = normalize_mac(&entry.mac_address) else {
+            fail(&entry, format!("'{}' is not a MAC address", entry.mac_address));
+            continue;
+        };
+        entry.mac_address = mac;
+        if let Some(ip) = &entry.ip_address {
+            match ip.parse::<IpAddr>() {
+                Ok(parsed) => entry.ip_address = Some(parsed.to_string()),
+                Err(_) => {
+                    fail(&entry, format!("'{}' is not an IP address", ip));
+                    continue;
+                }
+            }
+        }
+        if let Some(hostname) = &entry.hostname {
+            if !valid_hostname(hostname) {
+                fail(&entry, format!("'{}' is not a valid hostname", hostname));
+                continue;
+            }
+        }
+
+        if !seen_macs.insert(entry.mac_address.clone()) {
+            fail(&entry, "MAC address appears earlier in the manifest".to_string());
+        } else if entry.ip_address.as_ref().is_some_and(|ip| !seen_ips.insert(ip.clone())) {
+            fail(&entry, "IP address appears earlier in the manifest".to_string());
+        } else if entry.hostname.as_ref().is_some_and(|h| !seen_hostnames.insert(h.to_lowercase())) {
+            fail(&entry, "Hostname appears earlier in the manifest".to_string());
+        } else {
+            valid.push((row, entry));
+        }
+    }
+    (valid, errors)
+}
+
+/// Turn an entry's labels into custom field values, failing on labels that aren't defined fields
+fn label_values(entry: &ManifestEntry, fields: &[custom_fields::FieldDefinition]) -> Result<Vec<(String, Option<serde_json::Value>)>, String> {
+    entry.labels.iter().map(|(name, value)| {
+        let field = fields.iter().find(|f| &f.name == name)
+            .ok_or_else(|| format!("'{}' is not a custom field; define it before importing", name))?;
+        let value = custom_fields::normalize_value(field, &serde_json::Value::String(value.clone()))?;
+        Ok((name.clone(), Some(value)))
+    }).collect()
+}
+
+async fn import_entry(entry: &ManifestEntry, existing: Option<&Machine>, labels: &[(String, Option<serde_json::Value>)], actor: &str) -> Result<Uuid> {
+    let machine_id = match existing {
+        Some(machine) => {
+            if let Some(hostname) = &entry.hostname {
+                db::update_hostname(&machine.id, hostname).await?;
+            }
+            if let Some(ip) = &entry.ip_address {
+                db::update_ip_address(&machine.id, ip).await?;
+            }
+            machine.id
+        },
+        None => {
+            let request = RegisterRequest {
+                mac_address: entry.mac_address.clone(),
+                // Left empty until the machine's first DHCP lease or agent check-in
+                ip_address: entry.ip_address.clone().unwrap_or_default(),
+                hostname: entry.hostname.clone(),
+                disks: Vec::new(),
+                nameservers: Vec::new(),
+                cpu_model: None,
+                cpu_cores: None,
+                total_ram_bytes: None,
+                agent_time: None,
+                cpu_arch: None,
+                uefi: None,
+                secure_boot: None,
+                tpm: None,
+                pci_devices: Vec::new(),
+                system_vendor: None,
+                system_model: None,
+                system_serial: None,
+            };
+            let machine_id = db::register_machine(&request).await?;
+            if entry.hostname.is_none() {
+                if let Err(e) = crate::hostname_policy::apply_to_machine(&machine_id).await {
+                    warn!("Failed to apply hostname policy to imported machine {}: {}", machine_id, e);
+                }
+            }
+            // An admin listing a machine in a manifest has already approved it
+            if let Some(machine) = db::get_machine_by_id(&machine_id).await? {
+                if machine.status == MachineStatus::PendingApproval {
+                    db::update_status(&machine_id, MachineStatus::AwaitingAssignment, &StatusCause::Admin(format!("{} via import", actor))).await?;
+                }
+            }
+            machine_id
+        },
+    };
+
+    if !labels.is_empty() {
+        db::set_machine_field_values(&machine_id, labels).await?;
+    }
+    // Tinkerbell needs the Hardware record before the machine's first DHCP request
+    if let Some(machine) = db::get_machine_by_id(&machine_id).await? {
+        if let Err(e) = crate::tinkerbell::register_machine(&machine).await {
+            warn!("Failed to register imported machine {} with Tinkerbell (continuing anyway): {}", machine_id, e);
+        }
+    }
+    Ok(machine_id)
+}
+
+/// Create the machines in a manifest. Existing machines, matched by MAC, are skipped unless
+/// `update` is set. With `dry_run` nothing is written, but the report says what would happen.
+pub async fn import(entries: Vec<ManifestEntry>, update: bool, dry_run: bool, actor: &str) -> Result<ImportReport> {
+    let (valid, errors) = validate(entries);
+    let mut report = ImportReport { dry_run, errors, ..Default::default() };
+
+    let machines = db::get_all_machines().await?;
+    let by_mac: HashMap<String, &Machine> = machines.iter()
+        .filter_map(|m| normalize_mac(&m.mac_address).map(|mac| (mac, m)))
+        .collect();
+    let fields = db::get_custom_fields().await?;
+
+    for (row, entry) in valid {
+        let existing = by_mac.get(&entry.mac_address).copied();
+        let mut fail = |message: String| report.errors.push(RowError { row, mac_address: entry.mac_address.clone(), message });
+
+        if existing.is_some() && !update {
+            report.skipped.push(entry.mac_address.clone());
+            continue;
+        }
+        // An address another machine already has would be a conflict the moment this one boots
+        if let Some(ip) = &entry.ip_address {
+            if let Some(other) = machines.iter().find(|m| &m.ip_address == ip && Some(m.id) != existing.map(|e| e.id)) {
+                fail(format!("IP address {} belongs to machine {}", ip, other.id));
+                continue;
+            }
+        }
+        let labels = match label_values(&entry, &fields) {
+            Ok(labels) => labels,
+            Err(message) => {
+                fail(message);
+                continue;
+            }
+        };
+        let machine_id = if dry_run {
+            existing.map(|m| m.id)
+        } else {
+            match import_entry(&entry, existing, &labels, actor).await {
+                Ok(id) => Some(id),
+                Err(e) => {
+                    fail(e.to_string());
+                    continue;
+                }
+            }
+        };
+        let imported = ImportedMachine { mac_address: entry.mac_address.clone(), machine_id };
+        match existing {
+            Some(_) => report.updated.push(imported),
+            None => report.created.push(imported),
+        }
+    }
+
+    info!(
+        "{} imported machines{}: {} created, {} updated, {} skipped, {} errors",
+        actor, if dry_run { " (dry run)" } else { "" },
+        report.created.len(), report.updated.len(), report.skipped.len(), report.errors.len()
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_validate_csv() {
+        let manifest = "mac_address,hostname,ip_address,owner\n\
+                        AA-BB-CC-DD-EE-01,web-01,10.0.0.11,\"Storage, team\"\n\
+                        aa:bb:cc:dd:ee:02,,,\n\
+                        \n\
+                        aa:bb:cc:dd:ee:01,web-03,10.0.0.13,\n\
+                        not-a-mac,web-04,,\n";
+        let entries = parse_csv(manifest).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].labels.get("owner").map(String::as_str), Some("Storage, team"));
+        assert_eq!(entries[1].hostname, None);
+        assert!(entries[1].labels.is_empty());
+
+        let (valid, errors) = validate(entries);
+        assert_eq!(valid.len(), 2);
+        assert_eq!(valid[0].1.mac_address, "aa:bb:cc:dd:ee:01");
+        assert_eq!(errors.iter().map(|e| e.row).collect::<Vec<_>>(), vec![5, 6]);
+        let (_, errors) = validate(parse_json(r#"[{"mac": "aa:bb:cc:dd:ee:01"}, {"mac": "nope"}]"#).unwrap());
+        assert_eq!(errors[0].row, 2);
+        assert!(parse_csv("hostname\nweb-01\n").is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use dragonfly_common::models::MachineStatus;
+use dragonfly_common::state_machine::StatusCause;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::boot_filter::{in_subnet, parse_subnet};
+use crate::db;
+use crate::event_manager::EventManager;
+
+/// How often queued installs are checked for a free slot
+const DISPATCH_TICK: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// Held from counting running installs until the admitted one is marked as installing, so two
+/// assignments can't both take the last slot
+pub(crate) static ADMISSION: Mutex<()> = Mutex::const_new(());
+
+/// Caps on installs running at once, e.g. to protect the image mirror. No caps means no queue.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct InstallLimits {
+    /// Installs running at once across the whole fleet
+    #[serde(default)]
+    pub global: Option<u32>,
+    #[serde(default)]
+    pub subnets: Vec<SubnetLimit>,
+}
+
+/// A cap on installs running at once for machines in one subnet
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SubnetLimit {
+    /// e.g. `10.0.5.0/24`
+    pub subnet: String,
+    pub max: u32,
+}
+
+/// An OS assignment waiting for an install slot
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuedInstall {
+    pub machine_id: Uuid,
+    pub os_choice: String,
+    /// Who or what made the assignment, as it would appear in status history
+    pub requested_by: String,
+    /// Higher priorities start first; equal priorities start in the order they were queued
+    pub priority: i64,
+    pub queued_at: DateTime<Utc>,
+    /// The limit it is waiting on, as of the last check
+    pub waiting_for: Option<String>,
+}
+
+/// The queue as the UI shows it
+#[derive(Debug, Serialize)]
+pub struct QueueStatus {
+    pub limits: InstallLimits,
+    pub running: usize,
+    pub queued: Vec<QueuedInstall>,
+}
+
+impl InstallLimits {
+    pub fn is_empty(&self) -> bool {
+        self.global.is_none() && self.subnets.is_empty()
+    }
+}
+
+pub fn validate(limits: &InstallLimits) -> Result<(), String> {
+    if limits.global == Some(0) || limits.subnets.iter().any(|limit| limit.max == 0) {
+        return Err("A limit of 0 would never let an install start".to_string());
+    }
+    for limit in &limits.subnets {
+        parse_subnet(&limit.subnet).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Which limit keeps an install on a machine at `ip` from starting while `running` (the addresses
+/// of machines already installing) are under way, if any
+pub fn blocked_by(limits: &InstallLimits, running: &[Option<IpAddr>], ip: Option<IpAddr>) -> Option<String> {
+    if let Some(max) = limits.global {
+        if running.len() >= max as usize {
+            return Some(format!("the limit of {} installs at once", max));
+        }
+    }
+    let ip = ip?;
+    limits.subnets.iter()
+        .filter_map(|limit| parse_subnet(&limit.subnet).ok().map(|(net, prefix)| (limit, net, prefix)))
+        .filter(|(_, net, prefix)| in_subnet(ip, *net, *prefix))
+        .find(|(limit, net, prefix)| {
+            let in_use = running.iter().flatten().filter(|running| in_subnet(**running, *net, *prefix)).count();
+            in_use >= limit.max as usize
+        })
+        .map(|(limit, _, _)| format!("the limit of {} installs at once in {}", limit.max, limit.subnet))
+}
+
+async fn running_installs() -> Result<Vec<Option<IpAddr>>> {
+    Ok(db::get_machines_by_status(MachineStatus::InstallingOS).await?.iter()
+        .map(|machine| machine.ip_address.parse().ok())
+        .collect())
+}
+
+async fn machine_ip(machine_id: &Uuid) -> Result<Option<IpAddr>> {
+    Ok(db::get_machine_by_id(machine_id).await?.and_then(|machine| machine.ip_address.parse().ok()))
+}
+
+/// Queue an OS assignment when a limit is reached. Returns the queued install, or None when it
+/// may start now. Call with `ADMISSION` held and keep holding it until the install has started.
+pub async fn admit(machine_id: &Uuid, os_choice: &str, cause: &StatusCause) -> Result<Option<QueuedInstall>> {
+    let limits = db::get_install_limits().await?;
+    if limits.is_empty() {
+        return Ok(None);
+    }
+    let Some(reason) = blocked_by(&limits, &running_installs().await?, machine_ip(machine_id).await?) else {
+        return Ok(None);
+    };
+    let queued = QueuedInstall {
+        machine_id: *machine_id,
+        os_choice: os_choice.to_string(),
+        requested_by: cause.to_string(),
+        priority: 0,
+        queued_at: Utc::now(),
+        waiting_for: None,
+    };
+    // A machine assigned again while queued keeps its place and priority
+    let queued = db::save_queued_install(&queued).await?;
+    info!("Queued {} for machine {}: {} is reached", os_choice, machine_id, reason);
+    Ok(Some(QueuedInstall { waiting_for: Some(reason), ..queued }))
+}
+
+/// The limits, how many installs are running and what is waiting, in the order it will start
+pub async fn status() -> Result<QueueStatus> {
+    let limits = db::get_install_limits().await?;
+    let running = running_installs().await?;
+    let mut queued = db::get_queued_installs().await?;
+    for entry in &mut queued {
+        entry.waiting_for = blocked_by(&limits, &running, machine_ip(&entry.machine_id).await?);
+    }
+    Ok(QueueStatus { limits, running: running.len(), queued })
+}
+
+/// Start queued installs for which a slot has freed up, highest priority first. A queued machine
+/// held back by its subnet's limit doesn't hold up machines elsewhere.
+pub async fn dispatch(event_manager: &EventManager) -> Result<()> {
+    let _admission = ADMISSION.lock().await;
+    let limits = db::get_install_limits().await?;
+    let mut running = running_installs().await?;
+    for entry in db::get_queued_installs().await? {
+        let ip = match db::get_machine_by_id(&entry.machine_id).await? {
+            Some(machine) if machine.status != MachineStatus::InstallingOS => machine.ip_address.parse().ok(),
+            Some(_) => {
+                warn!("Dropping queued install on machine {}: it is already installing", entry.machine_id);
+                db::take_queued_install(&entry.machine_id).await?;
+                continue;
+            },
+            None => continue,
+        };
+        if blocked_by(&limits, &running, ip).is_some() {
+            continue;
+        }
+        // Cancelled since the queue was read
+        let Some(entry) = db::take_queued_install(&entry.machine_id).await? else {
+            continue;
+        };
+        info!("Starting queued install of {} on machine {}", entry.os_choice, entry.machine_id);
+        let cause = StatusCause::Automatic(format!("Started from the install queue ({})", entry.requested_by));
+        let response = crate::api::install_os_now(entry.machine_id, entry.os_choice, cause).await;
+        // An install that never started holds no slot
+        if response.status().is_success() {
+            running.push(ip);
+        } else {
+            warn!("Queued install on machine {} failed to start: status {}", entry.machine_id, response.status());
+        }
+        let _ = event_manager.send(format!("machine_updated:{}", entry.machine_id));
+    }
+    Ok(())
+}
+
+/// Start queued installs as slots free up
+pub async fn start_dispatch_task(
+    event_manager: Arc<EventManager>,
+    mut shutdown_rx: tokio::sync::watch::Receiver<()>,
+) {
+    tokio::spawn(async move {
+        info!("Starting install queue dispatch task");
+        let mut ticker = tokio::time::interval(DISPATCH_TICK);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    // Queued installs wait out a provisioning freeze
+                    if crate::freeze::is_frozen() {
+                        continue;
+                    }
+                    if let Err(e) = dispatch(&event_manager).await {
+                        error!("Failed to start queued installs: {}", e);
+                    }
+                },
+                _ = shutdown_rx.changed() => {
+                    info!("Shutdown signal received, stopping install queue dispatch task.");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocked_by() {
+        let limits = InstallLimits {
+            global: Some(3),
+            subnets: vec![SubnetLimit { subnet: "10.0.5.0/24".to_string(), max: 1 }],
+        };
+        let ip = |s: &str| s.parse::<IpAddr>().ok();
+        let running = vec![ip("10.0.5.10"), None];
+        assert!(blocked_by(&limits, &running, ip("10.0.5.11")).unwrap().contains("10.0.5.0/24"));
+        assert_eq!(blocked_by(&limits, &running, ip("10.0.6.11")), None);
+        assert_eq!(blocked_by(&limits, &running, None), None);
+
+        let full = vec![None, None, None];
+        assert!(blocked_by(&limits, &full, ip("10.0.6.11")).is_some());
+
+        assert!(validate(&InstallLimits { global: Some(0), subnets: vec![] }).is_err());
+        assert!(validate(&InstallLimits { global: None, subnets: vec![SubnetLimit { subnet: "10.0.5".to_string(), max: 2 }] }).is_err());
+    }
+}
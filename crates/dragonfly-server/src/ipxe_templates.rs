@@ -0,0 +1,143 @@
+use anyhow::{anyhow, Result};
+use dragonfly_common::models::Machine;
+use minijinja::{context, Environment};
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::boot_arch::BootTarget;
+use crate::{db, projects};
+
+/// Script served to machines Dragonfly already knows about
+pub const MACHINE_TEMPLATE: &str = "machine";
+/// Script served to machines Dragonfly has never seen
+pub const DISCOVERY_TEMPLATE: &str = "discovery";
+
+pub const TEMPLATE_NAMES: &[&str] = &[MACHINE_TEMPLATE, DISCOVERY_TEMPLATE];
+
+const DEFAULT_MACHINE_TEMPLATE: &str = "#!ipxe\nchain {{ base_url }}/ipxe/hookos.ipxe";
+const DEFAULT_DISCOVERY_TEMPLATE: &str = "#!ipxe\nchain {{ base_url }}/ipxe/dragonfly-agent.ipxe";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IpxeTemplate {
+    pub name: String,
+    pub content: String,
+    /// Whether an admin has replaced the built-in template
+    pub custom: bool,
+}
+
+/// The built-in template for a name, used until an admin stores their own
+pub fn default_template(name: &str) -> Option<&'static str> {
+    match name {
+        MACHINE_TEMPLATE => Some(DEFAULT_MACHINE_TEMPLATE),
+        DISCOVERY_TEMPLATE => Some(DEFAULT_DISCOVERY_TEMPLATE),
+        _ => None,
+    }
+}
+
+/// Load a template, preferring a project's own version, then the admin's global one, then the
+/// built-in one. Project versions are named `<template>@<project>`.
+pub async fn get_template(name: &str) -> Result<IpxeTemplate> {
+    let (base, project) = projects::split_template_name(name);
+    let default = default_template(base).ok_or_else(|| anyhow!("Unknown iPXE template '{}'", base))?;
+    if project.is_some() {
+        if let Some(content) = db::get_ipxe_template(name).await? {
+            return Ok(IpxeTemplate { name: name.to_string(), content, custom: true });
+        }
+    }
+    Ok(match db::get_ipxe_template(base).await? {
+        Some(content) => IpxeTemplate { name: base.to_string(), content, custom: true },
+        None => IpxeTemplate { name: base.to_string(), content: default.to_string(), custom: false },
+    })
+}
+
+/// Check a template name can be stored: a built-in name, or the machine template for a project
+pub fn validate_name(name: &str) -> Result<(), String> {
+    match projects::split_template_name(name) {
+        (base, None) if default_template(base).is_some() => Ok(()),
+        (MACHINE_TEMPLATE, Some(project)) => projects::validate_name(project),
+        (_, Some(_)) => Err("Only the machine template can have per-project versions".to_string()),
+        _ => Err(format!("Unknown iPXE template '{}'", name)),
+    }
+}
+
+/// Check that a template compiles before it is stored
+pub fn validate(content: &str) -> Result<()> {
+    if !content.trim_start().starts_with("#!ipxe") {
+        return Err(anyhow!("iPXE templates must start with #!ipxe"));
+    }
+    let env = Environment::new();
+    env.template_from_str(content)
+        .map_err(|e| anyhow!("Template syntax error: {}", e))?;
+    Ok(())
+}
+
+fn render(content: &str, base_url: &str, mac: &str, machine: Option<&Machine>, tags: &[String], target: Option<&BootTarget>) -> Result<String> {
+    let env = Environment::new();
+    let ctx = context! {
+        base_url => base_url,
+        mac => mac,
+        machine => machine,
+        tags => tags,
+        arch => target.map(|t| t.arch.as_str()),
+        firmware => target.map(|t| t.firmware),
+        bootloader => target.map(|t| format!("{}/ipxe/{}", base_url, t.bootloader())),
+    };
+    Ok(env.render_str(content, ctx)?)
+}
+
+/// Render the boot script for a MAC address. `machine` is `None` for undiscovered machines.
+/// A broken custom template falls back to the built-in one so machines can still boot.
+pub async fn render_boot_script(base_url: &str, mac: &str, machine: Option<&Machine>, target: Option<&BootTarget>) -> Result<String> {
+    let name = if machine.is_some() { MACHINE_TEMPLATE } else { DISCOVERY_TEMPLATE };
+    let tags = match machine {
+        Some(machine) => db::get_machine_tags(&machine.id).await.unwrap_or_default(),
+        None => Vec::new(),
+    };
+    let template = match projects::project_of(&tags) {
+        Some(project) => get_template(&projects::scoped_template_name(name, project)).await?,
+        None => get_template(name).await?,
+    };
+
+    match render(&template.content, base_url, mac, machine, &tags, target) {
+        Ok(script) => Ok(script),
+        Err(e) if template.custom => {
+            error!("Custom iPXE template '{}' failed to render for {}, using built-in template: {}", name, mac, e);
+            let default = default_template(name).unwrap_or_default();
+            render(default, base_url, mac, machine, &tags, target)
+        },
+        Err(e) => {
+            warn!("Built-in iPXE template '{}' failed to render for {}: {}", name, mac, e);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_templates_render() {
+        let script = render(DEFAULT_DISCOVERY_TEMPLATE, "http://10.0.0.1:3000", "aa:bb:cc:dd:ee:ff", None, &[], None).unwrap();
+        assert_eq!(script, "#!ipxe\nchain http://10.0.0.1:3000/ipxe/dragonfly-agent.ipxe");
+    }
+
+    #[test]
+    fn test_ipxe_variables_are_left_alone() {
+        let template = "#!ipxe\necho ${mac} {{ mac }}{% if 'gpu' in tags %} gpu{% endif %}";
+        let tags = vec!["gpu".to_string()];
+        let script = render(template, "http://x", "aa:bb:cc:dd:ee:ff", None, &tags, None).unwrap();
+        assert_eq!(script, "#!ipxe\necho ${mac} aa:bb:cc:dd:ee:ff gpu");
+
+        let target = BootTarget::from_client_arch(11);
+        let script = render("#!ipxe\n{{ arch }} {{ bootloader }}", "http://x", "aa:bb:cc:dd:ee:ff", None, &[], target.as_ref()).unwrap();
+        assert_eq!(script, "#!ipxe\naarch64 http://x/ipxe/bootloader/arm64/ipxe.efi");
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(DEFAULT_MACHINE_TEMPLATE).is_ok());
+        assert!(validate("chain http://x").is_err());
+        assert!(validate("#!ipxe\n{% if %}").is_err());
+    }
+}
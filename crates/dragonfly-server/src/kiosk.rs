@@ -0,0 +1,75 @@
+//! Kiosk mode for NOC displays.
+//!
+//! `/kiosk` is a full-screen page that cycles through the fleet at a glance, the installs in
+//! progress and the machines in error, in type big enough to read across the room. It draws on
+//! `/api/summary` and fetches it again whenever the event stream says a machine changed, so the
+//! screen stays current without anyone touching it.
+//!
+//! `?interval=30` sets the seconds per view and `?views=installs,failures` which views are shown,
+//! in that order.
+
+use axum::{
+    extract::{Query, State},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+
+/// The views the kiosk can show, in the order it shows them by default
+pub const VIEWS: &[&str] = &["dashboard", "installs", "failures"];
+pub const DEFAULT_INTERVAL_SECS: u32 = 20;
+const INTERVAL_RANGE: std::ops::RangeInclusive<u32> = 5..=600;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct KioskQuery {
+    pub interval: Option<u32>,
+    /// Comma-separated, from [`VIEWS`]
+    pub views: Option<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct KioskTemplate {
+    pub views: Vec<&'static str>,
+    pub interval_secs: u32,
+}
+
+impl KioskTemplate {
+    fn new(query: &KioskQuery) -> Self {
+        let mut views: Vec<&'static str> = Vec::new();
+        for name in query.views.as_deref().unwrap_or_default().split(',').map(str::trim) {
+            if let Some(view) = VIEWS.iter().copied().find(|view| *view == name) {
+                if !views.contains(&view) {
+                    views.push(view);
+                }
+            }
+        }
+        // Nothing asked for, or nothing we know, shows everything
+        if views.is_empty() {
+            views = VIEWS.to_vec();
+        }
+        let interval_secs = query.interval
+            .unwrap_or(DEFAULT_INTERVAL_SECS)
+            .clamp(*INTERVAL_RANGE.start(), *INTERVAL_RANGE.end());
+        Self { views, interval_secs }
+    }
+}
+
+pub async fn page(State(app_state): State<AppState>, Query(query): Query<KioskQuery>) -> Response {
+    crate::ui::render_minijinja(&app_state, "kiosk.html", KioskTemplate::new(&query))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kiosk_query() {
+        let kiosk = KioskTemplate::new(&KioskQuery { interval: Some(1), views: Some("failures, installs,failures,weather".to_string()) });
+        assert_eq!(kiosk, KioskTemplate { views: vec!["failures", "installs"], interval_secs: 5 });
+
+        let kiosk = KioskTemplate::new(&KioskQuery::default());
+        assert_eq!(kiosk, KioskTemplate { views: VIEWS.to_vec(), interval_secs: DEFAULT_INTERVAL_SECS });
+        assert_eq!(KioskTemplate::new(&KioskQuery { interval: Some(86400), views: Some("weather".to_string()) }).views, VIEWS);
+    }
+}
@@ -46,6 +46,84 @@ pub mod tinkerbell;
 pub mod event_manager;
 pub mod os_templates;
 pub mod mode;
+pub mod hostname_policy;
+pub mod boot_filter;
+pub mod policy;
+pub mod ipxe_templates;
+pub mod observability;
+pub mod boot_arch;
+pub mod webhooks;
+pub mod notifications;
+pub mod alerts;
+pub mod operations;
+pub mod replace;
+pub mod logging;
+pub mod telemetry;
+pub mod assignment;
+pub mod event_socket;
+pub mod grpc;
+pub mod cleanup;
+pub mod freeze;
+pub mod machine_cache;
+pub mod hardware_sync;
+pub mod workflow_templates;
+pub mod pipelines;
+pub mod firmware;
+pub mod storage_profiles;
+pub mod pci_devices;
+pub mod machine_profiles;
+pub mod maintenance;
+pub mod install_queue;
+pub mod rollouts;
+pub mod health_checks;
+pub mod reimage_schedules;
+pub mod upgrade;
+pub mod migrations;
+pub mod settings;
+pub mod access;
+pub mod demo;
+pub mod search;
+pub mod views;
+pub mod preferences;
+pub mod i18n;
+pub mod no_js;
+pub mod status_board;
+pub mod kiosk;
+pub mod scenarios;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod graphql;
+pub mod secure_boot;
+pub mod heartbeat;
+pub mod agent_commands;
+pub mod discovery;
+pub mod conflicts;
+pub mod console;
+pub mod break_glass;
+pub mod vnc;
+pub mod users;
+pub mod config;
+pub mod artifact_store;
+pub mod totp;
+pub mod chatops;
+pub mod rate_limit;
+pub mod projects;
+pub mod attestation;
+pub mod escrow;
+pub mod network;
+pub mod custom_fields;
+pub mod topology;
+pub mod bmc;
+pub mod import;
+pub mod template_vars;
+pub mod root_password;
+pub mod export;
+pub mod os_lifecycle;
+pub mod changes;
+pub mod resources;
+pub mod batch;
+#[cfg(test)]
+mod test_support;
 
 // Expose status module for integration tests
 pub mod status;
@@ -67,7 +145,7 @@ pub static INSTALL_STATE_REF: Lazy<RwLock<Option<Arc<Mutex<InstallationState>>>>
 
 // Stub function to check installation status (Replace with real check later)
 // Checks environment variable DRAGONFLY_FORCE_INSTALLED=true for testing
-// Also checks for /var/lib/dragonfly and dragonfly StatefulSet status
+// Also checks for /var/lib/dragonfly, or a cluster config from an existing-cluster install
 pub async fn is_dragonfly_installed() -> bool {
     // 1. Check environment variable override first
     if std::env::var("DRAGONFLY_FORCE_INSTALLED").map_or(false, |val| val.to_lowercase() == "true") {
@@ -89,6 +167,11 @@ pub async fn is_dragonfly_installed() -> bool {
     };
 
     if !dir_exists {
+        // 3. Installs into an existing cluster don't create the directory locally
+        if status::load_cluster_config().map_or(false, |c| c.existing_cluster) {
+            info!("Installation check: Detected installed state (existing cluster config at {:?}).", status::cluster_config_path());
+            return true;
+        }
         debug!("Installation check: Directory '{}' not found.", dir_path);
         return false;
     }
@@ -112,6 +195,7 @@ pub enum InstallationState {
     DetectingNetwork,
     InstallingK3s,
     WaitingK3s,
+    ConnectingCluster,
     DeployingTinkerbell,
     DeployingDragonfly,
     Ready,
@@ -129,6 +213,8 @@ impl InstallationState {
             InstallationState::InstallingK3s => "Dragonfly is installing k3s.",
             // Phase 3
             InstallationState::WaitingK3s => "Dragonfly is waiting for k3s to be ready.",
+            // Phase 2-3 (existing cluster)
+            InstallationState::ConnectingCluster => "Dragonfly is connecting to your Kubernetes cluster.",
             // Phase 4
             InstallationState::DeployingTinkerbell => "Dragonfly is deploying Tinkerbell.",
             // Phase 5
@@ -149,6 +235,8 @@ impl InstallationState {
             InstallationState::InstallingK3s => "rocket-sparks",
             // Phase 3 (Waiting K3s) -> Glowing
             InstallationState::WaitingK3s => "rocket-glowing",
+            // Phase 2-3 (Connecting to existing cluster) -> Glowing
+            InstallationState::ConnectingCluster => "rocket-glowing",
             // Phase 4 (Deploying Tinkerbell) -> Smoke
             InstallationState::DeployingTinkerbell => "rocket-smoke",
             // Phase 5 (Deploying Dragonfly) -> Flicker
@@ -202,6 +290,10 @@ pub async fn run() -> anyhow::Result<()> {
     let is_installation_server = std::env::var("DRAGONFLY_INSTALL_SERVER_MODE").is_ok();
     let is_explicit_demo_mode = std::env::var("DRAGONFLY_DEMO_MODE").is_ok();
     let setup_mode = std::env::var("DRAGONFLY_SETUP_MODE").is_ok();
+    // Safe mode serves the UI and API only, so state can be inspected and repaired without side effects
+    let is_safe_mode = std::env::var("DRAGONFLY_SAFE_MODE").is_ok();
+    #[cfg(feature = "chaos")]
+    warn!("Built with fault injection (chaos feature); this build is for integration tests only");
 
     // Determine installation status
     let is_installed = is_dragonfly_installed().await;
@@ -221,7 +313,8 @@ pub async fn run() -> anyhow::Result<()> {
     
     // --- Create and Store Event Manager EARLY --- 
     // Create event manager (needed even if installing for SSE updates)
-    let event_manager = Arc::new(EventManager::new());
+    let event_config = event_manager::EventConfig::from_env().map_err(|e| anyhow::anyhow!(e))?;
+    let event_manager = Arc::new(EventManager::with_config(event_config));
     // Store the event manager in the global static ASAP
     match EVENT_MANAGER_REF.write() { 
         Ok(mut global_ref) => { 
@@ -256,16 +349,26 @@ pub async fn run() -> anyhow::Result<()> {
     } else if is_installed {
         info!("Dragonfly installed - starting server in normal mode");
     }
+    if is_safe_mode {
+        warn!("Starting server in SAFE MODE - background tasks are disabled");
+    }
 
     // Initialize the database 
     let db_pool = init_db().await?; // DB init is essential
 
-    // Initialize timing database tables
-    db::init_timing_tables().await?; // Essential
-
     // Load historical timing data
     tinkerbell::load_historical_timings().await?; // Essential
 
+    // A provisioning freeze survives restarts
+    freeze::load().await?;
+
+    // The database is migrated, so an upgrade that restarted the server has finished
+    if !is_installation_server {
+        if let Err(e) = upgrade::finish_pending().await {
+            warn!("Failed to record how the last upgrade ended: {}", e);
+        }
+    }
+
     // --- Start OS Templates Initialization --- 
     // Get current deployment mode from database
     let current_mode = mode::get_current_mode().await?;
@@ -279,8 +382,9 @@ pub async fn run() -> anyhow::Result<()> {
     }
     
     let is_flight_mode = matches!(current_mode, Some(mode::DeploymentMode::Flight));
+    let tasks = BackgroundTasks::new(is_safe_mode, is_installation_server, is_demo_mode, is_flight_mode);
     
-    if is_flight_mode && !is_installation_server {
+    if tasks.flight {
         info!("Starting OS templates initialization for Flight mode...");
         let event_manager_clone = event_manager.clone(); // Clone for the task
         tokio::spawn(async move { 
@@ -299,16 +403,52 @@ pub async fn run() -> anyhow::Result<()> {
     let (shutdown_tx, shutdown_rx) = watch::channel(());
 
     // Start the timing cleanup task
-    tinkerbell::start_timing_cleanup_task(shutdown_rx.clone()).await; // Essential
+    if tasks.timing_cleanup {
+        tinkerbell::start_timing_cleanup_task(shutdown_rx.clone()).await;
+    }
     
     // Event Manager already created and stored above
 
-    // Start the workflow polling task - only in Flight mode
-    if is_flight_mode && !is_installation_server {
-        info!("Starting workflow polling task with interval of 1s for Flight mode");
-        tinkerbell::start_workflow_polling_task(event_manager.clone(), shutdown_rx.clone()).await;
+    // Forward events to configured webhook endpoints and chat channels, email alerts for stuck machines,
+    // mark machines that stop checking in as Offline,
+    // run scheduled discovery scans, watch for IP/MAC conflicts, prune expired sessions and tokens,
+    // start held and queued installs when they may run, move rollouts along, run reimage schedules
+    // and keep the machine cache in step with events
+    if tasks.fleet {
+        webhooks::start_webhook_dispatcher(event_manager.clone(), shutdown_rx.clone()).await;
+        alerts::start_alert_monitor(shutdown_rx.clone()).await;
+        heartbeat::start_offline_detection_task(event_manager.clone(), shutdown_rx.clone()).await;
+        discovery::start_discovery_scheduler(event_manager.clone(), shutdown_rx.clone()).await;
+        conflicts::start_conflict_monitor(event_manager.clone(), shutdown_rx.clone()).await;
+        cleanup::start_cleanup_task(shutdown_rx.clone()).await;
+        maintenance::start_release_task(event_manager.clone(), shutdown_rx.clone()).await;
+        install_queue::start_dispatch_task(event_manager.clone(), shutdown_rx.clone()).await;
+        rollouts::start_rollout_task(event_manager.clone(), shutdown_rx.clone()).await;
+        reimage_schedules::start_schedule_task(event_manager.clone(), shutdown_rx.clone()).await;
+        machine_cache::start_invalidation_task(event_manager.clone(), shutdown_rx.clone()).await;
+        hardware_sync::start_reconcile_task(event_manager.clone(), shutdown_rx.clone()).await;
+    }
+
+    // Demo mode shows a simulated fleet that changes like a real one
+    if tasks.demo {
+        demo::start_simulation(event_manager.clone(), shutdown_rx.clone()).await;
+    }
+
+    // Typed, streaming API for agents and peers, on its own port with mutual TLS
+    if !is_installation_server {
+        match grpc::GrpcConfig::from_env().map_err(|e| anyhow::anyhow!(e))? {
+            Some(config) => grpc::start_grpc_server(config, event_manager.clone(), shutdown_rx.clone()).await
+                .map_err(|e| anyhow::anyhow!(e))?,
+            None => debug!("gRPC API disabled ({} not set)", grpc::GRPC_LISTEN_ENV_VAR),
+        }
+    }
+
+    // Watch install workflows - only in Flight mode
+    if tasks.flight {
+        info!("Starting workflow watch task for Flight mode");
+        tinkerbell::start_workflow_watch_task(event_manager.clone(), shutdown_rx.clone()).await;
     } else {
-        debug!("Skipping workflow polling task (not in Flight mode)");
+        debug!("Skipping workflow watch task (not in Flight mode)");
     }
 
     // Load or generate admin credentials
@@ -432,7 +572,8 @@ pub async fn run() -> anyhow::Result<()> {
     };
     // --- End MiniJinja Setup --- 
 
-    // Create application state
+    // Create application state; settings::publish keeps these settings live from here on
+    settings::init(settings.clone());
     let app_state = AppState {
         settings: Arc::new(Mutex::new(settings.clone())), // Clone settings here
         event_manager: event_manager.clone(), // Use the one created earlier
@@ -468,14 +609,21 @@ pub async fn run() -> anyhow::Result<()> {
     let auth_layer = AuthManagerLayerBuilder::new(backend, session_layer)
         .build();
 
+    // Per-address and per-credential quotas on the API, so scripts can't crowd out booting machines
+    let api_quota = rate_limit::ApiQuota::from_env().map_err(|e| anyhow::anyhow!(e))?;
+
     // --- Build Router --- 
     let app = Router::new()
         .merge(auth_router())
         .merge(ui::ui_router())
         .route("/favicon.ico", get(handle_favicon))
         .route("/{mac}", get(api::ipxe_script))
+        .route("/grub/{file}", get(api::grub_config))
         .route("/ipxe/{*path}", get(api::serve_ipxe_artifact))
-        .nest("/api", api::api_router())
+        .nest("/api", api::api_router()
+            .layer(axum::middleware::from_fn(freeze::freeze_guard))
+            .layer(rate_limit::ApiQuotaLayer::new(api_quota))
+            .layer(axum::middleware::from_fn(scenarios::record_requests)))
         .nest_service("/static", {
             let preferred_path = "/opt/dragonfly/static";
             let fallback_path = "crates/dragonfly-server/static";
@@ -486,6 +634,16 @@ pub async fn run() -> anyhow::Result<()> {
             };
             ServeDir::new(static_path)
         })
+        // Break-glass sessions may only rotate the admin password
+        .layer(axum::middleware::from_fn(break_glass::require_rotation))
+        // Anonymous visitors and boot clients, when the settings require login
+        .layer(axum::middleware::from_fn(access::require_login))
+        // No-JS mode, and the outcome of the form that led to the page
+        .layer(axum::middleware::from_fn(no_js::detect))
+        // The language pages are shown in, which may be one of the preferences below
+        .layer(axum::middleware::from_fn(i18n::negotiate_language))
+        // Signed-in users' theme, palette and other UI preferences
+        .layer(axum::middleware::from_fn(preferences::apply))
         .layer(CookieManagerLayer::new())
         .layer(auth_layer)
         .layer(Extension(db_pool.clone()))
@@ -502,13 +660,15 @@ pub async fn run() -> anyhow::Result<()> {
                         .map(MatchedPath::as_str)
                         .unwrap_or(request.uri().path());
                     
-                    tracing::debug_span!(
+                    // Info level so it reaches the OpenTelemetry exporter. Headers are left out, as they carry session cookies.
+                    tracing::info_span!(
                         "http-request",
                         method = %request.method(),
                         uri = %request.uri(),
                         matched_path = matched_path, // Log matched path
                         version = ?request.version(),
-                        headers = ?request.headers(),
+                        otel.name = format!("{} {}", request.method(), matched_path),
+                        otel.kind = "server",
                     )
                 })
                 .on_request(DefaultOnRequest::new().level(Level::INFO))
@@ -522,7 +682,7 @@ pub async fn run() -> anyhow::Result<()> {
 
     // Handoff listener setup 
     if let Some(mode) = &current_mode {
-        if *mode == mode::DeploymentMode::Flight {
+        if *mode == mode::DeploymentMode::Flight && tasks.handoff {
             if !is_installation_server { info!("Running in Flight mode - starting handoff listener"); }
             tokio::spawn(async move {
                 if let Err(e) = mode::start_handoff_listener(shutdown_rx.clone()).await {
@@ -533,8 +693,9 @@ pub async fn run() -> anyhow::Result<()> {
     }
 
     // --- Start Server --- 
-    let server_port = 3000;
-    let addr = SocketAddr::from(([0, 0, 0, 0], server_port));
+    let listeners = network::Listeners::from_env().map_err(|e| anyhow::anyhow!(e))?;
+    let addr = listeners.management;
+    let server_port = addr.port();
     let mut listenfd = ListenFd::from_env();
     let socket_activation = std::env::var("LISTEN_FDS").is_ok();
     if socket_activation && !is_installation_server { // Conditional Log
@@ -574,6 +735,23 @@ pub async fn run() -> anyhow::Result<()> {
         info!("Dragonfly server listening on http://{}", listener.local_addr().context("Failed to get local address")?);
     }
 
+    // Dual-homed servers answer booting machines on the provisioning network only
+    if let Some(provisioning_addr) = listeners.provisioning {
+        let provisioning_listener = tokio::net::TcpListener::bind(provisioning_addr).await
+            .with_context(|| format!("Failed to bind provisioning address {}", provisioning_addr))?;
+        info!("Serving provisioning endpoints on http://{}", provisioning_addr);
+        let provisioning_app = app.clone().layer(axum::middleware::from_fn(network::provisioning_only));
+        let mut provisioning_shutdown = shutdown_tx.subscribe();
+        tokio::spawn(async move {
+            let result = axum::serve(provisioning_listener, provisioning_app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(async move { let _ = provisioning_shutdown.changed().await; })
+                .await;
+            if let Err(e) = result {
+                error!("Provisioning listener failed: {}", e);
+            }
+        });
+    }
+
     // --- Shutdown Signal Handling --- 
     let shutdown_signal = async move {
         // Set up a simple future for Ctrl+C
@@ -623,10 +801,41 @@ pub async fn run() -> anyhow::Result<()> {
         .context("Server error")?;
 
     if !is_installation_server { info!("Shutdown complete"); } // Cond Log
+    telemetry::shutdown();
 
     Ok(())
 }
 
+/// Which background tasks `run` starts. Safe mode starts none of them.
+#[derive(Debug, PartialEq)]
+struct BackgroundTasks {
+    /// Pruning old install timings
+    timing_cleanup: bool,
+    /// Webhooks, alerts, heartbeats, discovery and the other loops that act on the fleet
+    fleet: bool,
+    /// The simulated fleet of demo mode
+    demo: bool,
+    /// OS template setup and the workflow watch of Flight mode
+    flight: bool,
+    /// The Flight mode handoff listener, which the installation server runs too
+    handoff: bool,
+}
+
+impl BackgroundTasks {
+    fn new(is_safe_mode: bool, is_installation_server: bool, is_demo_mode: bool, is_flight_mode: bool) -> Self {
+        if is_safe_mode {
+            return Self { timing_cleanup: false, fleet: false, demo: false, flight: false, handoff: false };
+        }
+        Self {
+            timing_cleanup: true,
+            fleet: !is_installation_server,
+            demo: is_demo_mode,
+            flight: is_flight_mode && !is_installation_server,
+            handoff: is_flight_mode,
+        }
+    }
+}
+
 async fn handle_favicon() -> impl IntoResponse {
     let path = if std::path::Path::new("/opt/dragonfly/static/favicon/favicon.ico").exists() {
         "/opt/dragonfly/static/favicon/favicon.ico"
@@ -643,4 +852,25 @@ async fn handle_favicon() -> impl IntoResponse {
 pub use db::database_exists;
 
 // Bring the middleware function into scope
-use crate::api::track_client_ip;
\ No newline at end of file
+use crate::api::track_client_ip;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safe_mode_starts_no_background_tasks() {
+        let normal = BackgroundTasks::new(false, false, false, true);
+        assert_eq!(normal, BackgroundTasks { timing_cleanup: true, fleet: true, demo: false, flight: true, handoff: true });
+
+        for (installation_server, demo, flight) in [(false, false, true), (true, false, true), (false, true, false)] {
+            assert_eq!(
+                BackgroundTasks::new(true, installation_server, demo, flight),
+                BackgroundTasks { timing_cleanup: false, fleet: false, demo: false, flight: false, handoff: false }
+            );
+        }
+
+        let installer = BackgroundTasks::new(false, true, false, true);
+        assert!(!installer.fleet && !installer.flight && installer.handoff);
+    }
+}
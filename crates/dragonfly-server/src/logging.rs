@@ -0,0 +1,269 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::env;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::time::Duration;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::{fmt, prelude::*, registry, EnvFilter};
+
+/// `text` (default) for people reading a terminal, `json` for one JSON object per line on stdout
+pub const LOG_FORMAT_ENV_VAR: &str = "DRAGONFLY_LOG_FORMAT";
+/// Also forward every log line to a syslog server, e.g. `udp://logs.example.com:514` or `tcp://10.0.0.5:601`
+pub const SYSLOG_ENV_VAR: &str = "DRAGONFLY_SYSLOG";
+
+const APP_NAME: &str = "dragonfly";
+/// RFC5424 facility 3, system daemons
+const FACILITY_DAEMON: u8 = 3;
+/// Lines waiting for the syslog server; more are dropped rather than slowing the server down
+const SYSLOG_QUEUE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyslogTransport {
+    Udp,
+    /// Octet-counted framing (RFC6587), reconnecting when the connection drops
+    Tcp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyslogTarget {
+    pub transport: SyslogTransport,
+    /// `host:port`, resolved when connecting so DNS changes are picked up
+    pub address: String,
+}
+
+impl SyslogTarget {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let url = url::Url::parse(value.trim())
+            .map_err(|_| format!("{} must look like udp://host:514 or tcp://host:601, got '{}'", SYSLOG_ENV_VAR, value))?;
+        let (transport, default_port) = match url.scheme() {
+            "udp" => (SyslogTransport::Udp, 514),
+            "tcp" => (SyslogTransport::Tcp, 601),
+            other => return Err(format!("{} must use udp:// or tcp://, got '{}://'", SYSLOG_ENV_VAR, other)),
+        };
+        let host = url.host_str().filter(|h| !h.is_empty())
+            .ok_or_else(|| format!("{} has no host: '{}'", SYSLOG_ENV_VAR, value))?;
+        Ok(SyslogTarget { transport, address: format!("{}:{}", host, url.port().unwrap_or(default_port)) })
+    }
+}
+
+/// Where log lines go, read from the environment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    pub syslog: Option<SyslogTarget>,
+}
+
+impl LogConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let format = match env::var(LOG_FORMAT_ENV_VAR) {
+            Ok(value) if !value.trim().is_empty() => LogFormat::parse(value.trim().to_lowercase().as_str())
+                .ok_or_else(|| format!("{} must be 'text' or 'json', got '{}'", LOG_FORMAT_ENV_VAR, value))?,
+            _ => LogFormat::Text,
+        };
+        let syslog = match env::var(SYSLOG_ENV_VAR) {
+            Ok(value) if !value.trim().is_empty() => Some(SyslogTarget::parse(&value)?),
+            _ => None,
+        };
+        Ok(LogConfig { format, syslog })
+    }
+}
+
+/// Install the global logger: human-readable lines on stderr or JSON lines on stdout, plus syslog
+/// forwarding and OpenTelemetry span export when configured
+pub fn init(filter: EnvFilter) -> Result<LogConfig, String> {
+    let config = LogConfig::from_env()?;
+    let text = (config.format == LogFormat::Text).then(|| fmt::layer().with_writer(std::io::stderr));
+    let json = (config.format == LogFormat::Json).then(|| fmt::layer().json().flatten_event(true).with_writer(std::io::stdout));
+    let syslog = config.syslog.clone().map(SyslogLayer::start);
+    registry().with(filter).with(text).with(json).with(syslog).with(crate::telemetry::layer()?).init();
+    Ok(config)
+}
+
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// RFC5424 header fields are printable ASCII without spaces, or `-` when empty
+fn header_field(value: &str, max: usize) -> String {
+    let field: String = value.chars().filter(|c| c.is_ascii_graphic()).take(max).collect();
+    if field.is_empty() { "-".to_string() } else { field }
+}
+
+/// One RFC5424 syslog message, without structured data. The event's target is used as the MSGID.
+pub fn format_rfc5424(level: &Level, timestamp: DateTime<Utc>, hostname: &str, pid: u32, target: &str, message: &str) -> String {
+    format!(
+        "<{}>1 {} {} {} {} {} - {}",
+        FACILITY_DAEMON * 8 + severity(level),
+        timestamp.to_rfc3339_opts(SecondsFormat::Micros, true),
+        header_field(hostname, 255),
+        APP_NAME,
+        pid,
+        header_field(target, 32),
+        message,
+    )
+}
+
+/// Collects an event's message followed by its other fields as `key=value`
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Formats events as RFC5424 and hands them to a background thread that sends them on
+pub struct SyslogLayer {
+    tx: SyncSender<String>,
+    hostname: String,
+    pid: u32,
+}
+
+impl SyslogLayer {
+    pub fn start(target: SyslogTarget) -> Self {
+        let (tx, rx) = mpsc::sync_channel(SYSLOG_QUEUE);
+        std::thread::Builder::new()
+            .name("syslog-forwarder".to_string())
+            .spawn(move || forward(target, rx))
+            .expect("Failed to start the syslog forwarder thread");
+        SyslogLayer {
+            tx,
+            hostname: sysinfo::System::host_name().unwrap_or_default(),
+            pid: std::process::id(),
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for SyslogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let metadata = event.metadata();
+        let line = format_rfc5424(metadata.level(), Utc::now(), &self.hostname, self.pid, metadata.target(), &(visitor.message + &visitor.fields));
+        // Dropped when the queue is full: a slow syslog server must not slow the server down
+        let _ = self.tx.try_send(line);
+    }
+}
+
+fn resolve(address: &str) -> Option<SocketAddr> {
+    address.to_socket_addrs().ok()?.next()
+}
+
+// Failures are reported on stderr once per outage; logging them would only queue more lines behind them
+fn forward(target: SyslogTarget, rx: Receiver<String>) {
+    let mut failing = false;
+    let mut report = |result: Result<(), String>| match result {
+        Ok(()) => failing = false,
+        Err(e) if !failing => {
+            eprintln!("Syslog forwarding to {} failed, dropping log lines until it recovers: {}", target.address, e);
+            failing = true;
+        },
+        Err(_) => {},
+    };
+
+    match target.transport {
+        SyslogTransport::Udp => {
+            let mut socket: Option<(UdpSocket, SocketAddr)> = None;
+            for line in rx {
+                if socket.is_none() {
+                    socket = resolve(&target.address).and_then(|addr| {
+                        let bind: SocketAddr = if addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+                        UdpSocket::bind(bind).ok().map(|s| (s, addr))
+                    });
+                }
+                let result = match &socket {
+                    Some((socket, addr)) => socket.send_to(line.as_bytes(), addr).map(|_| ()).map_err(|e| e.to_string()),
+                    None => Err("address could not be resolved".to_string()),
+                };
+                if result.is_err() {
+                    socket = None;
+                }
+                report(result);
+            }
+        },
+        SyslogTransport::Tcp => {
+            let mut stream: Option<TcpStream> = None;
+            for line in rx {
+                if stream.is_none() {
+                    stream = resolve(&target.address)
+                        .and_then(|addr| TcpStream::connect_timeout(&addr, Duration::from_secs(5)).ok());
+                }
+                let result = match stream.as_mut() {
+                    Some(stream) => stream.write_all(format!("{} {}", line.len(), line).as_bytes()).map_err(|e| e.to_string()),
+                    None => Err("could not connect".to_string()),
+                };
+                if result.is_err() {
+                    stream = None;
+                }
+                report(result);
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_syslog_target_and_format() {
+        assert_eq!(SyslogTarget::parse("udp://logs.example.com").unwrap(),
+            SyslogTarget { transport: SyslogTransport::Udp, address: "logs.example.com:514".to_string() });
+        assert_eq!(SyslogTarget::parse("tcp://[::1]:6514").unwrap().address, "[::1]:6514");
+        assert!(SyslogTarget::parse("http://logs.example.com").is_err());
+
+        let timestamp = DateTime::parse_from_rfc3339("2025-01-02T03:04:05Z").unwrap().with_timezone(&Utc);
+        assert_eq!(
+            format_rfc5424(&Level::WARN, timestamp, "pxe 01", 42, "dragonfly_server::tinkerbell", "Workflow failed"),
+            "<28>1 2025-01-02T03:04:05.000000Z pxe01 dragonfly 42 dragonfly_server::tinkerbell - Workflow failed"
+        );
+    }
+}
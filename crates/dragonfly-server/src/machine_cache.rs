@@ -0,0 +1,225 @@
+use anyhow::Result;
+use dragonfly_common::models::Machine;
+use std::collections::HashMap;
+use std::env;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::db;
+use crate::event_manager::{DropPolicy, EventManager, RecvError};
+use crate::event_socket::split_event;
+
+/// Seconds a cached machine is trusted without an event about it; 0 turns the cache off
+pub const CACHE_TTL_ENV_VAR: &str = "DRAGONFLY_MACHINE_CACHE_TTL";
+/// Long enough to absorb a mass boot, short enough to pick up a write that sent no event
+pub const DEFAULT_CACHE_TTL_SECS: u64 = 30;
+
+/// How long cached machines are kept, read from the environment
+pub fn ttl_from_env() -> Result<Option<Duration>, String> {
+    match env::var(CACHE_TTL_ENV_VAR) {
+        Ok(value) => value.trim().parse::<u64>()
+            .map(|secs| (secs > 0).then(|| Duration::from_secs(secs)))
+            .map_err(|_| format!("{} must be a whole number of seconds, got '{}'", CACHE_TTL_ENV_VAR, value)),
+        Err(_) => Ok(Some(Duration::from_secs(DEFAULT_CACHE_TTL_SECS))),
+    }
+}
+
+struct Entry<T> {
+    value: T,
+    cached_at: Instant,
+}
+
+#[derive(Default)]
+struct Cache {
+    ttl: Duration,
+    by_id: HashMap<Uuid, Entry<Machine>>,
+    /// Keyed by lowercase MAC, as lookups ignore case
+    by_mac: HashMap<String, Entry<Machine>>,
+    all: Option<Entry<Vec<Machine>>>,
+    /// Bumped by every invalidation, so a read that raced with a write doesn't cache what it read
+    generation: u64,
+}
+
+impl Cache {
+    fn fresh<'a, T>(&self, entry: Option<&'a Entry<T>>, now: Instant) -> Option<&'a T> {
+        entry.filter(|e| now.duration_since(e.cached_at) < self.ttl).map(|e| &e.value)
+    }
+
+    fn invalidate(&mut self, id: &Uuid) {
+        self.generation += 1;
+        self.by_id.remove(id);
+        self.by_mac.retain(|_, e| e.value.id != *id);
+        self.all = None;
+    }
+
+    fn clear(&mut self) {
+        self.generation += 1;
+        self.by_id.clear();
+        self.by_mac.clear();
+        self.all = None;
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref CACHE: Mutex<Cache> = Mutex::new(Cache::default());
+}
+
+/// Only set while the invalidation task is listening for events; lookups go to the database otherwise
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Cache hits and misses since the server started
+pub fn lookup_totals() -> (u64, u64) {
+    (HITS.load(Ordering::Relaxed), MISSES.load(Ordering::Relaxed))
+}
+
+/// Look something up in the cache, or load it from the database and cache it
+async fn cached<T: Clone, F>(
+    lookup: impl Fn(&Cache, Instant) -> Option<T>,
+    load: F,
+    store: impl FnOnce(&mut Cache, T, Instant),
+) -> Result<T>
+where
+    F: std::future::Future<Output = Result<T>>,
+{
+    if !ENABLED.load(Ordering::Acquire) {
+        return load.await;
+    }
+    let generation = {
+        let cache = CACHE.lock().unwrap();
+        if let Some(value) = lookup(&cache, Instant::now()) {
+            HITS.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+        cache.generation
+    };
+    MISSES.fetch_add(1, Ordering::Relaxed);
+    let value = load.await?;
+    let mut cache = CACHE.lock().unwrap();
+    if cache.generation == generation {
+        store(&mut cache, value.clone(), Instant::now());
+    }
+    Ok(value)
+}
+
+/// The machine with this MAC address. Unknown MACs are not cached, so new machines are seen at once.
+pub async fn get_machine_by_mac(mac_address: &str) -> Result<Option<Machine>> {
+    let key = mac_address.to_lowercase();
+    cached(
+        |cache, now| cache.fresh(cache.by_mac.get(&key), now).cloned().map(Some),
+        db::get_machine_by_mac(mac_address),
+        |cache, machine, now| if let Some(machine) = machine {
+            cache.by_mac.insert(key.clone(), Entry { value: machine, cached_at: now });
+        },
+    ).await
+}
+
+pub async fn get_machine_by_id(id: &Uuid) -> Result<Option<Machine>> {
+    cached(
+        |cache, now| cache.fresh(cache.by_id.get(id), now).cloned().map(Some),
+        db::get_machine_by_id(id),
+        |cache, machine, now| if let Some(machine) = machine {
+            cache.by_id.insert(*id, Entry { value: machine, cached_at: now });
+        },
+    ).await
+}
+
+pub async fn get_all_machines() -> Result<Vec<Machine>> {
+    cached(
+        |cache, now| cache.fresh(cache.all.as_ref(), now).cloned(),
+        db::get_all_machines(),
+        |cache, machines, now| cache.all = Some(Entry { value: machines, cached_at: now }),
+    ).await
+}
+
+/// Forget a machine, e.g. after writing it outside the usual event-sending paths
+pub fn invalidate(id: &Uuid) {
+    CACHE.lock().unwrap().invalidate(id);
+}
+
+fn handle_event(message: &str) {
+    let (_, subject) = split_event(message);
+    if let Some(id) = subject.and_then(|s| Uuid::parse_str(s).ok()) {
+        invalidate(&id);
+    }
+}
+
+/// Serve machine lookups from memory, dropping a machine whenever an event names it
+pub async fn start_invalidation_task(
+    event_manager: Arc<EventManager>,
+    mut shutdown_rx: tokio::sync::watch::Receiver<()>,
+) {
+    let ttl = match ttl_from_env() {
+        Ok(Some(ttl)) => ttl,
+        Ok(None) => {
+            info!("Machine cache disabled ({} is 0)", CACHE_TTL_ENV_VAR);
+            return;
+        },
+        Err(e) => {
+            warn!("Machine cache disabled: {}", e);
+            return;
+        }
+    };
+    // Dropping old events is fine here: a lag clears the whole cache
+    let mut rx = event_manager.subscribe_with_policy(DropPolicy::DropOldest);
+    {
+        let mut cache = CACHE.lock().unwrap();
+        cache.ttl = ttl;
+        cache.clear();
+    }
+    ENABLED.store(true, Ordering::Release);
+    tokio::spawn(async move {
+        info!("Machine cache enabled ({}s TTL)", ttl.as_secs());
+        loop {
+            tokio::select! {
+                message = rx.recv() => match message {
+                    Ok(message) => handle_event(&message),
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("Machine cache missed {} events, clearing it", skipped);
+                        CACHE.lock().unwrap().clear();
+                    },
+                    Err(RecvError::Closed) => break,
+                },
+                _ = shutdown_rx.changed() => {
+                    info!("Shutdown signal received, stopping machine cache");
+                    break;
+                }
+            }
+        }
+        ENABLED.store(false, Ordering::Release);
+        CACHE.lock().unwrap().clear();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::machine;
+
+    #[test]
+    fn test_invalidation_and_expiry() {
+        let now = Instant::now();
+        let mut cache = Cache { ttl: Duration::from_secs(30), ..Default::default() };
+        let a = machine("aa:bb:cc:dd:ee:01");
+        let b = machine("aa:bb:cc:dd:ee:02");
+        for m in [&a, &b] {
+            cache.by_mac.insert(m.mac_address.clone(), Entry { value: m.clone(), cached_at: now });
+            cache.by_id.insert(m.id, Entry { value: m.clone(), cached_at: now });
+        }
+        cache.all = Some(Entry { value: vec![a.clone(), b.clone()], cached_at: now });
+
+        assert!(cache.fresh(cache.by_mac.get("aa:bb:cc:dd:ee:01"), now).is_some());
+        assert!(cache.fresh(cache.by_mac.get("aa:bb:cc:dd:ee:01"), now + Duration::from_secs(31)).is_none());
+
+        let generation = cache.generation;
+        cache.invalidate(&a.id);
+        assert!(cache.generation > generation);
+        assert!(!cache.by_id.contains_key(&a.id) && !cache.by_mac.contains_key("aa:bb:cc:dd:ee:01"));
+        assert!(cache.by_id.contains_key(&b.id) && cache.by_mac.contains_key("aa:bb:cc:dd:ee:02"));
+        assert!(cache.all.is_none());
+    }
+}
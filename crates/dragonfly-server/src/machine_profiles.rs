@@ -0,0 +1,245 @@
+use anyhow::Result;
+use dragonfly_common::models::Machine;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::boot_filter::{in_subnet, parse_subnet};
+use crate::db;
+use crate::hostname_policy::HostnamePolicy;
+
+/// Which machines a profile applies to. Every rule that is set must match, so a profile
+/// with no rules at all is a catch-all.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct MatchRules {
+    /// Matched case-insensitively against the system vendor, e.g. "dell"
+    #[serde(default)]
+    pub vendor: Option<String>,
+    /// Matched case-insensitively against the system model, e.g. "r650"
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Custom field values the machine must have, e.g. `{"role": "storage"}`
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// The machine's IP address must be in this subnet, e.g. `10.0.5.0/24`
+    #[serde(default)]
+    pub subnet: Option<String>,
+}
+
+/// Network settings for the installed OS, passed to workflows as `network_*` variables
+/// and to user-data as `{{ network }}`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct NetworkConfig {
+    #[serde(default)]
+    pub vlan: Option<u16>,
+    #[serde(default)]
+    pub mtu: Option<u32>,
+    #[serde(default)]
+    pub gateway: Option<String>,
+    #[serde(default)]
+    pub nameservers: Vec<String>,
+    #[serde(default)]
+    pub domain: Option<String>,
+}
+
+/// A class of machines, and what a newly discovered machine of that class is given
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MachineProfile {
+    pub name: String,
+    /// When several profiles match, the highest priority wins, then the first by name
+    #[serde(default)]
+    pub priority: i64,
+    #[serde(default)]
+    pub rules: MatchRules,
+    /// Assigned, and installed, as soon as the machine is adopted
+    #[serde(default)]
+    pub os_template: Option<String>,
+    /// Used instead of the global hostname policy
+    #[serde(default)]
+    pub hostname_policy: Option<HostnamePolicy>,
+    #[serde(default)]
+    pub network: Option<NetworkConfig>,
+}
+
+/// What profiles are matched against
+#[derive(Debug, Clone, Default)]
+pub struct MachineFacts {
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub labels: HashMap<String, String>,
+    pub ip: Option<IpAddr>,
+}
+
+impl MatchRules {
+    pub fn matches(&self, facts: &MachineFacts) -> bool {
+        let contains = |rule: &Option<String>, value: &Option<String>| match rule {
+            Some(rule) => value.as_ref().is_some_and(|value| value.to_lowercase().contains(&rule.to_lowercase())),
+            None => true,
+        };
+        let in_rule_subnet = match &self.subnet {
+            Some(subnet) => match (parse_subnet(subnet), facts.ip) {
+                (Ok((net, prefix)), Some(ip)) => in_subnet(ip, net, prefix),
+                _ => false,
+            },
+            None => true,
+        };
+        contains(&self.vendor, &facts.vendor)
+            && contains(&self.model, &facts.model)
+            && in_rule_subnet
+            && self.labels.iter().all(|(name, value)| facts.labels.get(name) == Some(value))
+    }
+}
+
+impl NetworkConfig {
+    pub fn workflow_vars(&self) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        if let Some(vlan) = self.vlan {
+            vars.push(("network_vlan".to_string(), vlan.to_string()));
+        }
+        if let Some(mtu) = self.mtu {
+            vars.push(("network_mtu".to_string(), mtu.to_string()));
+        }
+        if let Some(gateway) = &self.gateway {
+            vars.push(("network_gateway".to_string(), gateway.clone()));
+        }
+        if !self.nameservers.is_empty() {
+            vars.push(("network_nameservers".to_string(), self.nameservers.join(" ")));
+        }
+        if let Some(domain) = &self.domain {
+            vars.push(("network_domain".to_string(), domain.clone()));
+        }
+        vars
+    }
+}
+
+pub fn validate(profile: &MachineProfile) -> Result<(), String> {
+    crate::workflow_templates::validate_name(&profile.name)?;
+    // POST /machine-profiles/apply would shadow a profile of that name
+    if profile.name == "apply" {
+        return Err("'apply' can't be used as a profile name".to_string());
+    }
+    if profile.os_template.is_none() && profile.hostname_policy.is_none() && profile.network.is_none() {
+        return Err("A profile needs an OS template, hostname policy or network config to assign".to_string());
+    }
+    if let Some(template) = &profile.os_template {
+        crate::workflow_templates::validate_name(template)?;
+    }
+    if let Some(subnet) = &profile.rules.subnet {
+        parse_subnet(subnet).map_err(|e| e.to_string())?;
+    }
+    if let Some(network) = &profile.network {
+        if network.vlan.is_some_and(|vlan| !(1..=4094).contains(&vlan)) {
+            return Err("VLAN IDs run from 1 to 4094".to_string());
+        }
+        if network.mtu.is_some_and(|mtu| !(576..=9216).contains(&mtu)) {
+            return Err("The MTU must be between 576 and 9216".to_string());
+        }
+        if let Some(address) = network.gateway.iter().chain(&network.nameservers).find(|a| a.parse::<IpAddr>().is_err()) {
+            return Err(format!("'{}' is not an IP address", address));
+        }
+    }
+    Ok(())
+}
+
+/// The profile a machine with these facts gets, if any matches
+pub fn best_match<'a>(profiles: &'a [MachineProfile], facts: &MachineFacts) -> Option<&'a MachineProfile> {
+    profiles.iter()
+        .filter(|profile| profile.rules.matches(facts))
+        .min_by_key(|profile| (Reverse(profile.priority), profile.name.as_str()))
+}
+
+pub async fn facts_for(machine: &Machine) -> Result<MachineFacts> {
+    let (vendor, model) = db::get_machine_system_info(&machine.id).await?;
+    let labels = db::get_machine_field_values(&machine.id).await?.into_iter()
+        .map(|(name, value)| match value {
+            serde_json::Value::String(value) => (name, value),
+            value => (name, value.to_string()),
+        })
+        .collect();
+    Ok(MachineFacts { vendor, model, labels, ip: machine.ip_address.parse().ok() })
+}
+
+/// The profile a machine was adopted into
+pub async fn for_machine(machine_id: &Uuid) -> Result<Option<MachineProfile>> {
+    db::get_assigned_machine_profile(machine_id).await
+}
+
+/// Match a machine against the profiles and record the one it gets. The hostname policy and
+/// OS are picked up from the assignment by the usual adoption steps that follow.
+pub async fn adopt(machine_id: &Uuid) -> Result<Option<MachineProfile>> {
+    let Some(machine) = db::get_machine_by_id(machine_id).await? else {
+        return Ok(None);
+    };
+    let profiles = db::get_machine_profiles().await?;
+    let facts = facts_for(&machine).await?;
+    let Some(profile) = best_match(&profiles, &facts) else {
+        return Ok(None);
+    };
+    db::set_machine_profile_assignment(machine_id, Some(&profile.name)).await?;
+    info!("Machine {} matched machine profile '{}'", machine_id, profile.name);
+    Ok(Some(profile.clone()))
+}
+
+/// The network config of a machine's profile, if it has one
+pub async fn network_for(machine_id: &Uuid) -> Result<Option<NetworkConfig>> {
+    Ok(for_machine(machine_id).await?.and_then(|profile| profile.network))
+}
+
+/// `network_*` workflow variables from the machine's profile
+pub async fn workflow_vars(machine_id: &Uuid) -> Result<Vec<(String, String)>> {
+    Ok(network_for(machine_id).await?.map(|network| network.workflow_vars()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(name: &str, priority: i64, rules: MatchRules) -> MachineProfile {
+        MachineProfile {
+            name: name.to_string(),
+            priority,
+            rules,
+            os_template: Some("ubuntu-2404".to_string()),
+            hostname_policy: None,
+            network: None,
+        }
+    }
+
+    #[test]
+    fn test_best_match() {
+        let profiles = vec![
+            profile("catch-all", -10, MatchRules::default()),
+            profile("dell-compute", 0, MatchRules {
+                vendor: Some("dell".to_string()),
+                subnet: Some("10.0.5.0/24".to_string()),
+                ..Default::default()
+            }),
+            profile("dell-storage", 5, MatchRules {
+                vendor: Some("Dell".to_string()),
+                labels: BTreeMap::from([("role".to_string(), "storage".to_string())]),
+                ..Default::default()
+            }),
+        ];
+        let mut facts = MachineFacts {
+            vendor: Some("Dell Inc.".to_string()),
+            model: Some("PowerEdge R650".to_string()),
+            labels: HashMap::new(),
+            ip: "10.0.5.17".parse().ok(),
+        };
+        assert_eq!(best_match(&profiles, &facts).map(|p| p.name.as_str()), Some("dell-compute"));
+        facts.labels.insert("role".to_string(), "storage".to_string());
+        assert_eq!(best_match(&profiles, &facts).map(|p| p.name.as_str()), Some("dell-storage"));
+        let elsewhere = MachineFacts { vendor: None, ip: "10.0.6.17".parse().ok(), ..Default::default() };
+        assert_eq!(best_match(&profiles, &elsewhere).map(|p| p.name.as_str()), Some("catch-all"));
+
+        assert!(validate(&profile("apply", 0, MatchRules::default())).is_err());
+        let bad_network = MachineProfile {
+            network: Some(NetworkConfig { gateway: Some("10.0.5.1".to_string()), nameservers: vec!["dns".to_string()], ..Default::default() }),
+            ..profile("edge", 0, MatchRules::default())
+        };
+        assert!(validate(&bad_network).is_err());
+    }
+}
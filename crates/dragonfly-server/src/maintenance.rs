@@ -0,0 +1,220 @@
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc, Weekday};
+use dragonfly_common::models::MachineStatus;
+use dragonfly_common::state_machine::StatusCause;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::db;
+use crate::event_manager::EventManager;
+
+/// How often held assignments are checked against the windows
+const RELEASE_TICK: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// A weekly window in which OS installs may start, in UTC. A window whose end is before its
+/// start runs past midnight into the next day, e.g. Sat 22:00–02:00.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MaintenanceWindow {
+    #[serde(default)]
+    pub id: i64,
+    pub name: String,
+    /// e.g. `["Sat", "Sun"]`
+    pub days: Vec<Weekday>,
+    /// `HH:MM`
+    pub start: String,
+    pub end: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// An OS assignment waiting for a maintenance window
+#[derive(Debug, Clone, Serialize)]
+pub struct ProvisioningHold {
+    pub machine_id: Uuid,
+    pub os_choice: String,
+    /// Who or what made the assignment, as it would appear in status history
+    pub requested_by: String,
+    pub queued_at: DateTime<Utc>,
+    /// When the next window opens, if any is enabled
+    pub next_window: Option<DateTime<Utc>>,
+}
+
+fn parse_time(value: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(value.trim(), "%H:%M").ok()
+}
+
+impl MaintenanceWindow {
+    fn times(&self) -> Option<(NaiveTime, NaiveTime)> {
+        Some((parse_time(&self.start)?, parse_time(&self.end)?))
+    }
+
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let Some((start, end)) = self.times() else {
+            return false;
+        };
+        let (day, time) = (now.weekday(), now.time());
+        if start < end {
+            self.days.contains(&day) && time >= start && time < end
+        } else {
+            // Runs past midnight: either the evening of a listed day or the morning after one
+            (self.days.contains(&day) && time >= start) || (self.days.contains(&day.pred()) && time < end)
+        }
+    }
+}
+
+pub fn validate(window: &MaintenanceWindow) -> Result<(), String> {
+    if window.name.trim().is_empty() {
+        return Err("A maintenance window needs a name".to_string());
+    }
+    if window.days.is_empty() {
+        return Err("A maintenance window needs at least one day".to_string());
+    }
+    match window.times() {
+        Some((start, end)) if start == end => Err("A maintenance window can't start and end at the same time".to_string()),
+        Some(_) => Ok(()),
+        None => Err("Window times must be HH:MM, e.g. 02:00".to_string()),
+    }
+}
+
+/// The enabled window open at `now`, if any
+pub fn open_window(windows: &[MaintenanceWindow], now: DateTime<Utc>) -> Option<&MaintenanceWindow> {
+    windows.iter().find(|window| window.enabled && window.contains(now))
+}
+
+/// When the next enabled window opens after `now`
+pub fn next_opening(windows: &[MaintenanceWindow], now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    (0..=7)
+        .flat_map(|days| {
+            let date = now.date_naive() + Duration::days(days);
+            windows.iter()
+                .filter(move |window| window.enabled && window.days.contains(&date.weekday()))
+                .filter_map(move |window| window.times().map(|(start, _)| date.and_time(start).and_utc()))
+        })
+        .filter(|opening| *opening > now)
+        .min()
+}
+
+/// Hold an OS assignment for the next window when windows are enabled and none is open.
+/// Returns the hold, or None when the install may start now.
+pub async fn hold(machine_id: &Uuid, os_choice: &str, cause: &StatusCause) -> Result<Option<ProvisioningHold>> {
+    let windows = db::get_maintenance_windows().await?;
+    let now = Utc::now();
+    if !windows.iter().any(|window| window.enabled) || open_window(&windows, now).is_some() {
+        return Ok(None);
+    }
+    let hold = ProvisioningHold {
+        machine_id: *machine_id,
+        os_choice: os_choice.to_string(),
+        requested_by: cause.to_string(),
+        queued_at: now,
+        next_window: next_opening(&windows, now),
+    };
+    db::save_provisioning_hold(&hold).await?;
+    info!("Holding {} for machine {} until the next maintenance window", os_choice, machine_id);
+    Ok(Some(hold))
+}
+
+/// Held assignments, with when each will be released
+pub async fn holds() -> Result<Vec<ProvisioningHold>> {
+    let windows = db::get_maintenance_windows().await?;
+    let next_window = next_opening(&windows, Utc::now());
+    Ok(db::get_provisioning_holds().await?.into_iter()
+        .map(|hold| ProvisioningHold { next_window, ..hold })
+        .collect())
+}
+
+/// Start a held install now. Machines that started installing something else in the meantime
+/// just lose the hold; reimages of installed machines go ahead.
+pub async fn release(machine_id: &Uuid, cause: StatusCause) -> Result<bool> {
+    let Some(hold) = db::take_provisioning_hold(machine_id).await? else {
+        return Ok(false);
+    };
+    match db::get_machine_by_id(machine_id).await? {
+        Some(machine) if machine.status != MachineStatus::InstallingOS => {
+            info!("Releasing held install of {} on machine {} ({})", hold.os_choice, machine_id, cause);
+            let response = crate::api::start_os_install(*machine_id, hold.os_choice, cause).await;
+            if !response.status().is_success() {
+                warn!("Held install on machine {} failed to start: status {}", machine_id, response.status());
+            }
+        }
+        Some(_) => warn!("Dropping held install on machine {}: it is already installing", machine_id),
+        None => {}
+    }
+    Ok(true)
+}
+
+/// Release held assignments whenever a maintenance window is open
+pub async fn start_release_task(
+    event_manager: Arc<EventManager>,
+    mut shutdown_rx: tokio::sync::watch::Receiver<()>,
+) {
+    tokio::spawn(async move {
+        info!("Starting maintenance window release task");
+        let mut ticker = tokio::time::interval(RELEASE_TICK);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    // Held installs wait out a provisioning freeze, even in a window
+                    if crate::freeze::is_frozen() {
+                        continue;
+                    }
+                    let loaded = tokio::try_join!(db::get_maintenance_windows(), db::get_provisioning_holds());
+                    let (windows, holds) = match loaded {
+                        Ok(loaded) => loaded,
+                        Err(e) => {
+                            error!("Failed to load maintenance windows: {}", e);
+                            continue;
+                        }
+                    };
+                    let Some(window) = open_window(&windows, Utc::now()) else {
+                        continue;
+                    };
+                    for hold in holds {
+                        let cause = StatusCause::Automatic(format!("Released in maintenance window {} ({})", window.name, hold.requested_by));
+                        match release(&hold.machine_id, cause).await {
+                            Ok(_) => { let _ = event_manager.send(format!("machine_updated:{}", hold.machine_id)); },
+                            Err(e) => error!("Failed to release held install on machine {}: {}", hold.machine_id, e),
+                        }
+                    }
+                },
+                _ = shutdown_rx.changed() => {
+                    info!("Shutdown signal received, stopping maintenance window release task.");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn window(days: &[Weekday], start: &str, end: &str) -> MaintenanceWindow {
+        MaintenanceWindow { id: 1, name: "weekend".to_string(), days: days.to_vec(), start: start.to_string(), end: end.to_string(), enabled: true }
+    }
+
+    #[test]
+    fn test_windows() {
+        // 2026-10-17 is a Saturday
+        let saturday = |h, m| Utc.with_ymd_and_hms(2026, 10, 17, h, m, 0).unwrap();
+        let early = window(&[Weekday::Sat], "02:00", "06:00");
+        assert!(early.contains(saturday(2, 0)));
+        assert!(!early.contains(saturday(6, 0)));
+
+        let overnight = window(&[Weekday::Fri], "22:00", "02:00");
+        assert!(overnight.contains(saturday(1, 30)));
+        assert!(!overnight.contains(saturday(22, 30)));
+
+        let windows = vec![early, overnight];
+        assert_eq!(next_opening(&windows, saturday(12, 0)), Some(Utc.with_ymd_and_hms(2026, 10, 23, 22, 0, 0).unwrap()));
+        assert!(validate(&window(&[Weekday::Sat], "2am", "06:00")).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+//! Versioned database migrations.
+//!
+//! Each schema change is a file in `migrations/` named `<version>_<description>.sql`. They are
+//! built into the server and applied in order at startup. sqlx records every applied migration
+//! with a checksum in `_sqlx_migrations`, and refuses to start if a released migration was edited
+//! or the database was migrated by a newer Dragonfly. Change the schema by adding a file; never
+//! edit one that has shipped.
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::migrate::Migrator;
+use sqlx::{Pool, Row, Sqlite};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use tracing::info;
+
+use crate::db;
+
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Columns servers used to add at startup before there were migrations, as (table, column,
+/// definition). A database from then gets the ones it lacks before it adopts the baseline.
+const LEGACY_COLUMNS: &[(&str, &str, &str)] = &[
+    ("machines", "os_installed", "TEXT"),
+    ("machines", "bmc_credentials", "TEXT"),
+    ("machines", "installation_progress", "INTEGER DEFAULT 0"),
+    ("machines", "installation_step", "TEXT"),
+    ("machines", "last_deployment_duration", "INTEGER"),
+    ("machines", "cpu_model", "TEXT"),
+    ("machines", "cpu_cores", "INTEGER"),
+    ("machines", "total_ram_bytes", "INTEGER"),
+    ("app_settings", "default_os", "TEXT"),
+    ("app_settings", "setup_completed", "BOOLEAN NOT NULL DEFAULT 0"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    /// Applied, but the file has changed since
+    Modified,
+    /// Started and never finished
+    Failed,
+    /// Applied by a newer Dragonfly; this one doesn't know it
+    Unknown,
+}
+
+impl fmt::Display for MigrationState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            MigrationState::Applied => "applied",
+            MigrationState::Pending => "pending",
+            MigrationState::Modified => "modified since it was applied",
+            MigrationState::Failed => "failed",
+            MigrationState::Unknown => "applied by a newer version",
+        };
+        f.write_str(state)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+}
+
+async fn table_exists(pool: &Pool<Sqlite>, table: &str) -> Result<bool> {
+    let row = sqlx::query("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+        .bind(table)
+        .fetch_one(pool)
+        .await?;
+    Ok(row.get::<i64, _>(0) > 0)
+}
+
+async fn column_exists(pool: &Pool<Sqlite>, table: &str, column: &str) -> Result<bool> {
+    let row = sqlx::query("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?")
+        .bind(table)
+        .bind(column)
+        .fetch_one(pool)
+        .await?;
+    Ok(row.get::<i64, _>(0) > 0)
+}
+
+/// Whether the database was created before there were migrations
+async fn is_legacy(pool: &Pool<Sqlite>) -> Result<bool> {
+    Ok(table_exists(pool, "machines").await? && !table_exists(pool, "_sqlx_migrations").await?)
+}
+
+/// Add the columns the database would have gained at startup, so it matches the baseline
+async fn upgrade_legacy_schema(pool: &Pool<Sqlite>) -> Result<()> {
+    for (table, column, definition) in LEGACY_COLUMNS {
+        if !table_exists(pool, table).await? || column_exists(pool, table, column).await? {
+            continue;
+        }
+        info!("Adding {} column to {} table", column, table);
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+            .execute(pool)
+            .await?;
+        // Machines with an existing OS used to carry it in their status
+        if (*table, *column) == ("machines", "os_installed") {
+            sqlx::query(
+                r#"
+                UPDATE machines
+                SET os_installed = CASE WHEN status LIKE 'ExistingOS: %' THEN substr(status, 13) ELSE 'Unknown' END,
+                    status = 'Existing OS', updated_at = ?
+                WHERE status LIKE 'ExistingOS:%' OR status = 'Existing OS'
+                "#,
+            )
+            .bind(Utc::now().to_rfc3339())
+            .execute(pool)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Every migration this server knows or the database records, oldest first
+pub async fn status(pool: &Pool<Sqlite>) -> Result<Vec<MigrationStatus>> {
+    let mut applied: HashMap<i64, (String, bool, Vec<u8>)> = HashMap::new();
+    if table_exists(pool, "_sqlx_migrations").await? {
+        let rows = sqlx::query("SELECT version, description, success, checksum FROM _sqlx_migrations")
+            .fetch_all(pool)
+            .await?;
+        for row in rows {
+            applied.insert(row.get("version"), (row.get("description"), row.get("success"), row.get("checksum")));
+        }
+    }
+    let mut statuses: Vec<MigrationStatus> = MIGRATOR.iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .map(|migration| {
+            let state = match applied.remove(&migration.version) {
+                None => MigrationState::Pending,
+                Some((_, false, _)) => MigrationState::Failed,
+                Some((_, true, checksum)) if checksum != *migration.checksum => MigrationState::Modified,
+                Some(_) => MigrationState::Applied,
+            };
+            MigrationStatus { version: migration.version, description: migration.description.to_string(), state }
+        })
+        .collect();
+    statuses.extend(applied.into_iter().map(|(version, (description, _, _))| MigrationStatus {
+        version,
+        description,
+        state: MigrationState::Unknown,
+    }));
+    statuses.sort_by_key(|status| status.version);
+    Ok(statuses)
+}
+
+/// Apply the migrations the database hasn't had yet
+pub async fn run(pool: &Pool<Sqlite>) -> Result<()> {
+    if is_legacy(pool).await? {
+        info!("Database predates versioned migrations; bringing it up to the baseline schema");
+        upgrade_legacy_schema(pool).await?;
+    }
+    for pending in status(pool).await?.iter().filter(|status| status.state == MigrationState::Pending) {
+        info!("Applying migration {} ({})", pending.version, pending.description);
+    }
+    MIGRATOR.run(pool).await.map_err(|e| anyhow!("Failed to migrate the database: {}", e))
+}
+
+/// The state of the database's migrations, without applying any, for `--check-migrations`
+pub async fn check() -> Result<Vec<MigrationStatus>> {
+    if !Path::new(db::DB_PATH).exists() {
+        return Err(anyhow!("No database at {}", db::DB_PATH));
+    }
+    let pool = db::open_pool().await?;
+    status(&pool).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn test_legacy_database_adopts_baseline() {
+        let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+        // A database from before migrations, before os_installed and two-factor login
+        for statement in [
+            "CREATE TABLE machines (id TEXT PRIMARY KEY, mac_address TEXT UNIQUE NOT NULL, ip_address TEXT NOT NULL, hostname TEXT, os_choice TEXT, status TEXT NOT NULL, disks TEXT, nameservers TEXT, created_at TEXT NOT NULL, updated_at TEXT NOT NULL)",
+            "CREATE TABLE admin_credentials (id INTEGER PRIMARY KEY, username TEXT NOT NULL, password_hash TEXT NOT NULL, created_at TEXT NOT NULL, updated_at TEXT NOT NULL)",
+            "INSERT INTO machines VALUES ('m1', '00:11:22:33:44:55', '10.0.0.5', NULL, NULL, 'ExistingOS: Debian 12', NULL, NULL, 'now', 'now')",
+            "INSERT INTO machines VALUES ('m2', 'AA-BB-CC-DD-EE-FF', '10.0.0.6', NULL, NULL, 'Ready', NULL, NULL, 'now', 'now')",
+            "INSERT INTO machines VALUES ('m3', 'aa:bb:cc:dd:ee:ff', '10.0.0.7', NULL, NULL, 'Ready', NULL, NULL, 'now', 'now')",
+            "INSERT INTO machines VALUES ('m4', 'BC:24:11:B9:54:89', '10.0.0.8', NULL, NULL, 'Ready', NULL, NULL, 'now', 'now')",
+            "INSERT INTO admin_credentials VALUES (1, 'admin', 'hash', 'now', 'now')",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+        assert!(status(&pool).await.unwrap().iter().all(|status| status.state == MigrationState::Pending));
+
+        run(&pool).await.unwrap();
+        let machine = sqlx::query("SELECT os_installed, status, vnc_target FROM machines WHERE id = 'm1'").fetch_one(&pool).await.unwrap();
+        assert_eq!(machine.get::<String, _>("os_installed"), "Debian 12");
+        assert_eq!(machine.get::<String, _>("status"), "Existing OS");
+        // MACs are normalized unless that would collide with a machine already holding the normal form
+        let macs: Vec<String> = sqlx::query("SELECT mac_address FROM machines ORDER BY id").fetch_all(&pool).await.unwrap()
+            .iter().map(|row| row.get(0)).collect();
+        assert_eq!(macs, ["00:11:22:33:44:55", "AA-BB-CC-DD-EE-FF", "aa:bb:cc:dd:ee:ff", "bc:24:11:b9:54:89"]);
+        assert_eq!(crate::db::normalize_mac(" AA-BB-CC-DD-EE-FF"), "aa:bb:cc:dd:ee:ff");
+        let user = sqlx::query("SELECT username, totp_enabled FROM users").fetch_one(&pool).await.unwrap();
+        assert_eq!(user.get::<String, _>("username"), "admin");
+        assert!(status(&pool).await.unwrap().iter().all(|status| status.state == MigrationState::Applied));
+
+        // Running again at the next start changes nothing
+        run(&pool).await.unwrap();
+    }
+}
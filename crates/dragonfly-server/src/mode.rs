@@ -1137,9 +1137,10 @@ pub async fn enter_flight_mode() -> Result<()> {
     }
 
     // --- Check current Helm values for Smee DHCP --- 
+    let namespace = crate::status::dragonfly_namespace();
     info!("Checking current Helm values for tink-stack...");
     let helm_get_values_output = Command::new("helm")
-        .args(["get", "values", "tink-stack", "-n", "tink", "-o", "yaml"])
+        .args(["get", "values", "tink-stack", "-n", namespace.as_str(), "-o", "yaml"])
         .output();
         
     let needs_upgrade = match helm_get_values_output {
@@ -1191,7 +1192,7 @@ pub async fn enter_flight_mode() -> Result<()> {
         // Check if the Tinkerbell stack release actually exists (redundant but safe)
     let release_exists = {
         let release_check = Command::new("helm")
-            .args(["list", "-n", "tink", "--filter", "tink-stack", "--short"])
+            .args(["list", "-n", namespace.as_str(), "--filter", "tink-stack", "--short"])
                 .output()
                 .with_context(|| "Failed to check deployment status after upgrade")?;
             
@@ -1227,7 +1228,7 @@ pub async fn enter_flight_mode() -> Result<()> {
     let helm_args = [
         "upgrade", "tink-stack",
             upgrade_chart_path.to_str().ok_or_else(|| anyhow!("Chart path is not valid UTF-8"))?,
-        "--namespace", "tink",
+        "--namespace", namespace.as_str(),
         "--wait",
         "--timeout", "10m",
         "--reuse-values",
@@ -1246,24 +1247,24 @@ pub async fn enter_flight_mode() -> Result<()> {
                 
                 // Try to get more diagnostic information
                 let helm_list = Command::new("helm")
-                    .args(["list", "-n", "tink", "-a"])
+                    .args(["list", "-n", namespace.as_str(), "-a"])
                     .output();
                 
                 if let Ok(list_output) = helm_list {
                     if list_output.status.success() {
                         let list_stdout = String::from_utf8_lossy(&list_output.stdout);
-                        error!("Current Helm releases in tink namespace:\n{}", list_stdout);
+                        error!("Current Helm releases in {} namespace:\n{}", namespace, list_stdout);
                     }
                 }
                 
                 let pod_list = Command::new("kubectl")
-                    .args(["get", "pods", "-n", "tink", "-o", "wide"])
+                    .args(["get", "pods", "-n", namespace.as_str(), "-o", "wide"])
                     .output();
                 
                 if let Ok(pod_output) = pod_list {
                     if pod_output.status.success() {
                         let pod_stdout = String::from_utf8_lossy(&pod_output.stdout);
-                        error!("Current pods in tink namespace:\n{}", pod_stdout);
+                        error!("Current pods in {} namespace:\n{}", namespace, pod_stdout);
                     }
                 }
                 
@@ -0,0 +1,159 @@
+use axum::{
+    extract::Request,
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::env;
+use std::net::SocketAddr;
+
+/// Address the web UI and API listen on
+pub const LISTEN_ADDR_ENV_VAR: &str = "DRAGONFLY_LISTEN_ADDR";
+/// Optional second address, on the provisioning network, that only serves what booting machines need
+pub const PROVISIONING_ADDR_ENV_VAR: &str = "DRAGONFLY_PROVISIONING_ADDR";
+
+pub const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:3000";
+
+/// Boot artifacts, and the rendered payloads installers fetch by token
+const PROVISIONING_PATHS: &[&str] = &["/ipxe/", "/grub/", "/api/render/"];
+
+/// What the agent calls under `/api/machines/{id}`, with `*` for any one segment. Everything else
+/// in the API, like everything outside it, is the UI's.
+const AGENT_ROUTES: &[&str] = &[
+    "",
+    "status",
+    "os-installed",
+    "clock",
+    "pci-devices",
+    "heartbeat",
+    "disk-key",
+    "agent/challenge",
+    "agent/enroll",
+    "commands/next",
+    "commands/*/result",
+    "commands/*/root-password",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Listeners {
+    pub management: SocketAddr,
+    /// None when a single address serves everything
+    pub provisioning: Option<SocketAddr>,
+}
+
+fn parse_addr(var: &str, value: &str) -> Result<SocketAddr, String> {
+    value.trim().parse().map_err(|_| format!("{} must be an address and port such as 10.0.0.1:3000, got '{}'", var, value))
+}
+
+impl Listeners {
+    pub fn from_env() -> Result<Self, String> {
+        let management = match env::var(LISTEN_ADDR_ENV_VAR) {
+            Ok(value) => parse_addr(LISTEN_ADDR_ENV_VAR, &value)?,
+            Err(_) => DEFAULT_LISTEN_ADDR.parse().unwrap(),
+        };
+        let provisioning = match env::var(PROVISIONING_ADDR_ENV_VAR) {
+            Ok(value) if !value.trim().is_empty() => Some(parse_addr(PROVISIONING_ADDR_ENV_VAR, &value)?),
+            _ => None,
+        };
+        let listeners = Self { management, provisioning };
+        listeners.validate()?;
+        Ok(listeners)
+    }
+
+    /// The two listeners must not cover the same address, or the UI would leak onto the provisioning network
+    pub fn validate(&self) -> Result<(), String> {
+        let Some(provisioning) = self.provisioning else {
+            return Ok(());
+        };
+        // 0.0.0.0 includes the provisioning interface, whatever the ports
+        if self.management.ip().is_unspecified() {
+            return Err(format!(
+                "{} ({}) listens on every interface, the provisioning network's too; give it the management interface's address",
+                LISTEN_ADDR_ENV_VAR, self.management
+            ));
+        }
+        let overlap = provisioning.port() == self.management.port()
+            && (provisioning.ip() == self.management.ip()
+                || provisioning.ip().is_unspecified()
+                || self.management.ip().is_unspecified());
+        if overlap {
+            return Err(format!(
+                "{} ({}) and {} ({}) overlap; give each its own interface address, or use different ports",
+                LISTEN_ADDR_ENV_VAR, self.management, PROVISIONING_ADDR_ENV_VAR, provisioning
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Whether a path is served on the provisioning network: iPXE scripts (`/<mac>`), boot artifacts,
+/// rendered payloads and the routes the agent registers and reports in through
+pub fn is_provisioning_path(path: &str) -> bool {
+    if PROVISIONING_PATHS.iter().any(|prefix| path.starts_with(prefix)) {
+        return true;
+    }
+    if matches!(path, "/api/machines" | "/api/installation/progress") {
+        return true;
+    }
+    if let Some(rest) = path.strip_prefix("/api/machines/") {
+        let (id, route) = rest.split_once('/').unwrap_or((rest, ""));
+        let route: Vec<&str> = route.split('/').collect();
+        return uuid::Uuid::parse_str(id).is_ok() && AGENT_ROUTES.iter().any(|agent| {
+            let agent: Vec<&str> = agent.split('/').collect();
+            agent.len() == route.len() && agent.iter().zip(&route).all(|(a, r)| *a == "*" || a == r)
+        });
+    }
+    let segment = path.trim_start_matches('/');
+    !segment.contains('/') && segment.split(':').count() == 6
+}
+
+/// Layer for the provisioning listener, hiding the web UI from machines being provisioned
+pub async fn provisioning_only(request: Request, next: Next) -> Response {
+    if is_provisioning_path(request.uri().path()) {
+        next.run(request).await
+    } else {
+        (StatusCode::NOT_FOUND, "The Dragonfly UI isn't served on the provisioning network").into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listeners_overlap() {
+        let listeners = |management: &str, provisioning: &str| Listeners {
+            management: management.parse().unwrap(),
+            provisioning: Some(provisioning.parse().unwrap()),
+        };
+        assert!(listeners("192.168.1.10:3000", "10.0.0.1:3000").validate().is_ok());
+        assert!(listeners("192.168.1.10:3000", "0.0.0.0:8080").validate().is_ok());
+        assert!(listeners("0.0.0.0:3000", "0.0.0.0:8080").validate().is_err());
+        assert!(listeners("0.0.0.0:3000", "10.0.0.1:8080").validate().is_err());
+        assert!(listeners("10.0.0.1:3000", "10.0.0.1:3000").validate().is_err());
+        assert!(listeners("192.168.1.10:3000", "0.0.0.0:3000").validate().is_err());
+        assert!(Listeners { management: "0.0.0.0:3000".parse().unwrap(), provisioning: None }.validate().is_ok());
+
+        assert!(is_provisioning_path("/aa:bb:cc:dd:ee:ff"));
+        assert!(is_provisioning_path("/ipxe/ubuntu/vmlinuz"));
+        assert!(!is_provisioning_path("/machines"));
+        assert!(!is_provisioning_path("/"));
+    }
+
+    #[test]
+    fn test_provisioning_api_is_only_the_machines_own() {
+        let id = "6f1c2a4e-1b7d-4c1e-9a53-0d2f1e7b9c10";
+        for path in ["/api/machines", "/api/render/tok3n/user-data"] {
+            assert!(is_provisioning_path(path), "{} is hidden", path);
+        }
+        for route in ["", "/heartbeat", "/agent/enroll", "/commands/next", "/commands/42/result", "/os-installed"] {
+            assert!(is_provisioning_path(&format!("/api/machines/{}{}", id, route)), "{} is hidden", route);
+        }
+        for path in ["/api/settings", "/api/machines/export", "/api/users", "/api/events", "/api/graphql"] {
+            assert!(!is_provisioning_path(path), "{} is served", path);
+        }
+        for route in ["/root-password", "/root-password/reveal", "/disk-keys", "/bmc/jobs", "/commands", "/agent", "/vnc"] {
+            assert!(!is_provisioning_path(&format!("/api/machines/{}{}", id, route)), "{} is served", route);
+        }
+    }
+}
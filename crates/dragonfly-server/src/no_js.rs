@@ -0,0 +1,246 @@
+//! The UI without JavaScript.
+//!
+//! Browsers in locked-down environments may not run scripts at all. The machine actions the
+//! JavaScript UI sends to the API (assigning an OS, reimaging, approving and rejecting) are also
+//! plain form POSTs here, answered with a redirect back to the page the form was on. Their outcome
+//! rides along in the redirect's query string as `notice` or `action_error`, and `base.html`
+//! shows it on whatever page that is.
+//!
+//! No-JS mode, toggled with `/no-js/toggle` and kept in a cookie, renders those forms in place of
+//! the scripted controls and leaves the scripts out of the page.
+
+use axum::{
+    body::to_bytes,
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    Form, Json,
+};
+use dragonfly_common::state_machine::StatusCause;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::api::{DryRunQuery, ReimageRequest};
+use crate::auth::AuthSession;
+use crate::policy::{self, Permission};
+use crate::AppState;
+
+pub const COOKIE: &str = "dragonfly_no_js";
+
+/// Longest response body read for the message to show
+const MAX_MESSAGE_BODY: usize = 64 * 1024;
+
+/// What the page being rendered needs to know
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PageState {
+    pub enabled: bool,
+    /// The outcome of the form action that led here
+    pub notice: Option<String>,
+    pub action_error: Option<String>,
+}
+
+tokio::task_local! {
+    static CURRENT: PageState;
+}
+
+pub fn current() -> PageState {
+    CURRENT.try_with(PageState::clone).unwrap_or_default()
+}
+
+/// Layer reading the no-JS cookie and any form action's outcome for the page
+pub async fn detect(request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if path.starts_with("/api/") || path.starts_with("/static/") {
+        return next.run(request).await;
+    }
+    let params = Query::<HashMap<String, String>>::try_from_uri(request.uri())
+        .map(|Query(params)| params)
+        .unwrap_or_default();
+    let state = PageState {
+        enabled: crate::preferences::cookie_value(&request, COOKIE).as_deref() == Some("true"),
+        notice: params.get("notice").cloned(),
+        action_error: params.get("action_error").cloned(),
+    };
+    CURRENT.scope(state, next.run(request)).await
+}
+
+// Only paths on this server, so a crafted form can't redirect elsewhere
+fn local_path(path: Option<&String>) -> Option<&str> {
+    path.map(String::as_str).filter(|path| path.starts_with('/') && !path.starts_with("//") && !path.contains('\\'))
+}
+
+/// Turn no-JS mode on (`?enabled=true`) or off
+pub async fn toggle(Query(params): Query<HashMap<String, String>>) -> Response {
+    let enabled = params.get("enabled").is_some_and(|value| value == "true");
+    let cookie = crate::ui::preference_cookie(COOKIE, if enabled { "true" } else { "false" });
+    let return_to = local_path(params.get("return_to")).unwrap_or("/");
+    ([(header::SET_COOKIE, cookie.to_string())], Redirect::to(return_to)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ReturnTo {
+    pub return_to: Option<String>,
+}
+
+// Text of an HTML fragment, without its scripts
+fn strip_tags(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let tag = &rest[start..];
+        let skip = if tag.starts_with("<script") {
+            tag.find("</script>").map(|end| end + "</script>".len())
+        } else {
+            tag.find('>').map(|end| end + 1)
+        };
+        rest = skip.map(|skip| &tag[skip..]).unwrap_or("");
+        text.push(' ');
+    }
+    text.push_str(rest);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// What an API response has to say: the `message` of a JSON error, or the text of an HTML or
+/// plain one
+async fn response_message(response: Response) -> String {
+    let status = response.status();
+    let is_html = response.headers().get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"));
+    let body = to_bytes(response.into_body(), MAX_MESSAGE_BODY).await.unwrap_or_default();
+    let message = match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(json) => ["message", "error"].iter()
+            .find_map(|key| json.get(key).and_then(|value| value.as_str()).map(str::to_string))
+            .unwrap_or_default(),
+        Err(_) if is_html => strip_tags(&String::from_utf8_lossy(&body)),
+        Err(_) => String::from_utf8_lossy(&body).trim().to_string(),
+    };
+    if message.is_empty() {
+        status.canonical_reason().unwrap_or("Failed").to_string()
+    } else {
+        message
+    }
+}
+
+/// Send the browser back where the form was, with `done` if the action went through. Accepted
+/// actions, such as installs held for a maintenance window, say why they are waiting instead.
+async fn redirect_back(response: Response, done: String, return_to: Option<&String>, id: &Uuid) -> Response {
+    let fallback = format!("/machines/{}", id);
+    let return_to = local_path(return_to).unwrap_or(&fallback);
+    let (key, message) = match response.status() {
+        StatusCode::OK => ("notice", done),
+        status if status.is_success() => ("notice", response_message(response).await),
+        _ => ("action_error", response_message(response).await),
+    };
+    let separator = if return_to.contains('?') { '&' } else { '?' };
+    Redirect::to(&format!("{}{}{}={}", return_to, separator, key, urlencoding::encode(&message))).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct AssignOsForm {
+    pub os_choice: String,
+    pub return_to: Option<String>,
+}
+
+pub async fn assign_os(
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Form(form): Form<AssignOsForm>,
+) -> Response {
+    // These routes sit outside /api, so the freeze guard doesn't see them
+    if let Some(response) = crate::freeze::locked() {
+        return redirect_back(response, String::new(), form.return_to.as_ref(), &id).await;
+    }
+    let response = match policy::authorize(&auth_session, &id, Permission::Operate).await {
+        Ok(()) => {
+            let cause = StatusCause::Admin(policy::principal(&auth_session));
+            crate::api::assign_os_internal(id, form.os_choice.clone(), cause).await
+        },
+        Err(response) => response,
+    };
+    redirect_back(response, format!("Installing {}", form.os_choice), form.return_to.as_ref(), &id).await
+}
+
+#[derive(Deserialize)]
+pub struct ReimageForm {
+    /// Defaults to the OS the machine has now
+    pub os_choice: Option<String>,
+    /// A checkbox, so only sent when ticked
+    pub power_cycle: Option<String>,
+    pub return_to: Option<String>,
+}
+
+pub async fn reimage(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Form(form): Form<ReimageForm>,
+) -> Response {
+    if let Some(response) = crate::freeze::locked() {
+        return redirect_back(response, String::new(), form.return_to.as_ref(), &id).await;
+    }
+    let request = ReimageRequest {
+        os_choice: form.os_choice.filter(|os| !os.is_empty()),
+        power_cycle: form.power_cycle.is_some(),
+    };
+    let response = crate::api::reimage_machine(
+        State(state), auth_session, Path(id), Query(DryRunQuery { dry_run: false }), Json(request),
+    ).await;
+    if !response.status().is_success() {
+        return redirect_back(response, String::new(), form.return_to.as_ref(), &id).await;
+    }
+    // As the machine page says it: a note when there is one, else whether the install started
+    let body = to_bytes(response.into_body(), MAX_MESSAGE_BODY).await.unwrap_or_default();
+    let reimaged: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+    let message = match reimaged["note"].as_str() {
+        Some(note) => note.to_string(),
+        None if reimaged["status"] == "waiting" => "Reimage queued".to_string(),
+        None => "Reimage started".to_string(),
+    };
+    redirect_back(StatusCode::OK.into_response(), message, form.return_to.as_ref(), &id).await
+}
+
+pub async fn approve(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Form(form): Form<ReturnTo>,
+) -> Response {
+    let response = crate::api::approve_machine(State(state), auth_session, Path(id)).await;
+    redirect_back(response, "Machine approved".to_string(), form.return_to.as_ref(), &id).await
+}
+
+pub async fn reject(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Form(form): Form<ReturnTo>,
+) -> Response {
+    let response = crate::api::reject_machine(State(state), auth_session, Path(id), Query(DryRunQuery { dry_run: false })).await;
+    redirect_back(response, "Machine rejected".to_string(), form.return_to.as_ref(), &id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_tags_and_local_path() {
+        let html = r#"
+            <div class="p-4" role="alert">
+                <span class="font-medium">Queued.</span> ubuntu-2204 will be installed in the next maintenance window.
+            </div>
+            <script>showToast('hi');</script>
+        "#;
+        assert_eq!(strip_tags(html), "Queued. ubuntu-2204 will be installed in the next maintenance window.");
+        assert_eq!(strip_tags("no tags"), "no tags");
+
+        assert_eq!(local_path(Some(&"/machines?q=status:error".to_string())), Some("/machines?q=status:error"));
+        assert_eq!(local_path(Some(&"//evil.example".to_string())), None);
+        assert_eq!(local_path(Some(&"https://evil.example".to_string())), None);
+        assert_eq!(local_path(None), None);
+    }
+}
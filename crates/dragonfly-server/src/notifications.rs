@@ -0,0 +1,159 @@
+use anyhow::Result;
+use dragonfly_common::models::{Machine, MachineStatus};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, error, warn};
+
+use crate::db;
+
+/// Events a chat channel can be told about
+pub const EVENTS: &[&str] = &["install_completed", "install_failed", "machine_discovered"];
+
+/// The chat services messages can be posted to. Each takes an incoming webhook URL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelKind {
+    Slack,
+    Mattermost,
+    Discord,
+}
+
+impl ChannelKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChannelKind::Slack => "slack",
+            ChannelKind::Mattermost => "mattermost",
+            ChannelKind::Discord => "discord",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "slack" => Some(ChannelKind::Slack),
+            "mattermost" => Some(ChannelKind::Mattermost),
+            "discord" => Some(ChannelKind::Discord),
+            _ => None,
+        }
+    }
+
+    /// The webhook body for a message
+    pub fn payload(&self, text: &str) -> serde_json::Value {
+        match self {
+            ChannelKind::Slack => json!({ "text": text }),
+            ChannelKind::Mattermost => json!({ "text": text, "username": "Dragonfly" }),
+            ChannelKind::Discord => json!({ "content": text, "username": "Dragonfly" }),
+        }
+    }
+}
+
+/// A chat channel that is told about installs and newly discovered machines
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotificationChannel {
+    #[serde(default)]
+    pub id: i64,
+    pub name: String,
+    pub kind: ChannelKind,
+    pub webhook_url: String,
+    /// Events to post about, from `EVENTS`. Empty means all of them.
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl NotificationChannel {
+    pub fn wants(&self, event: &str) -> bool {
+        EVENTS.contains(&event) && (self.events.is_empty() || self.events.iter().any(|e| e == event))
+    }
+}
+
+pub fn validate(channel: &NotificationChannel) -> Result<(), String> {
+    if channel.name.trim().is_empty() {
+        return Err("name must not be empty".to_string());
+    }
+    if !matches!(url::Url::parse(&channel.webhook_url).map(|u| u.scheme().to_string()).as_deref(), Ok("http") | Ok("https")) {
+        return Err(format!("'{}' is not an http(s) URL", channel.webhook_url));
+    }
+    match channel.events.iter().find(|e| !EVENTS.contains(&e.as_str())) {
+        Some(event) => Err(format!("Unknown event '{}'; expected one of {}", event, EVENTS.join(", "))),
+        None => Ok(()),
+    }
+}
+
+/// The message posted for an event, or None for events channels aren't told about
+pub fn format_message(event: &str, machine: &Machine) -> Option<String> {
+    let name = machine.hostname.clone()
+        .or_else(|| machine.memorable_name.clone())
+        .unwrap_or_else(|| machine.mac_address.clone());
+    let os = machine.os_choice.as_deref().map(crate::api::format_os_name).unwrap_or_else(|| "an OS".to_string());
+    match event {
+        "install_completed" => Some(format!("✅ {} finished installing {} and is ready", name, os)),
+        "install_failed" => {
+            let reason = match &machine.status {
+                MachineStatus::Error(reason) => format!(": {}", reason),
+                _ => String::new(),
+            };
+            Some(format!("❌ {} failed to install {}{}", name, os, reason))
+        },
+        "machine_discovered" => Some(format!("🆕 New machine discovered: {} ({}, {})", name, machine.mac_address, machine.ip_address)),
+        _ => None,
+    }
+}
+
+/// Post a message to one channel
+pub async fn post(client: &reqwest::Client, channel: &NotificationChannel, text: &str) -> Result<()> {
+    crate::webhooks::post(client, &channel.webhook_url, "application/json", channel.kind.payload(text).to_string()).await
+}
+
+/// The enabled channels that want an event
+pub async fn channels_for(event: &str) -> Vec<NotificationChannel> {
+    if !EVENTS.contains(&event) {
+        return Vec::new();
+    }
+    match db::get_notification_channels().await {
+        Ok(channels) => channels.into_iter().filter(|c| c.enabled && c.wants(event)).collect(),
+        Err(e) => {
+            error!("Failed to load notification channels: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Post an event to chat channels. Called by the webhook dispatcher, which looked the machine up.
+pub async fn notify(client: &reqwest::Client, channels: &[NotificationChannel], event: &str, machine: Option<&Machine>) {
+    let Some(text) = machine.and_then(|m| format_message(event, m)) else {
+        return;
+    };
+    for channel in channels {
+        match post(client, channel, &text).await {
+            Ok(()) => debug!("Posted {} to notification channel '{}'", event, channel.name),
+            Err(e) => warn!("Failed to post {} to notification channel '{}': {}", event, channel.name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_filter_and_payload() {
+        let channel = NotificationChannel {
+            id: 1,
+            name: "ops".to_string(),
+            kind: ChannelKind::Discord,
+            webhook_url: "https://discord.example.invalid/api/webhooks/1".to_string(),
+            events: vec!["install_failed".to_string()],
+            enabled: true,
+        };
+        assert!(channel.wants("install_failed"));
+        assert!(!channel.wants("install_completed"));
+        assert!(!NotificationChannel { events: vec![], ..channel.clone() }.wants("machine_updated"));
+        assert_eq!(channel.kind.payload("hi")["content"], "hi");
+        assert_eq!(ChannelKind::Slack.payload("hi")["text"], "hi");
+    }
+}
@@ -0,0 +1,720 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use dragonfly_common::models::{Machine, MachineStatus};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use uuid::Uuid;
+
+use crate::db;
+
+/// A metric exported on `/api/metrics`. Alert rules and the Grafana dashboard
+/// are generated from these definitions so they can't drift from what we export.
+pub struct MetricDef {
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub help: &'static str,
+}
+
+pub const MACHINES: MetricDef = MetricDef {
+    name: "dragonfly_machines",
+    kind: "gauge",
+    help: "Number of machines known to Dragonfly, by status",
+};
+pub const INSTALL_PROGRESS: MetricDef = MetricDef {
+    name: "dragonfly_machine_installation_progress",
+    kind: "gauge",
+    help: "OS installation progress of machines currently installing, in percent",
+};
+pub const CLOCK_SKEW: MetricDef = MetricDef {
+    name: "dragonfly_machine_clock_skew_seconds",
+    kind: "gauge",
+    help: "Agent clock minus server clock, as last reported by each machine",
+};
+pub const TEMPLATE_USAGE: MetricDef = MetricDef {
+    name: "dragonfly_template_usage_total",
+    kind: "counter",
+    help: "Number of times each OS template has been assigned",
+};
+pub const KUBERNETES_UP: MetricDef = MetricDef {
+    name: "dragonfly_kubernetes_up",
+    kind: "gauge",
+    help: "Whether Dragonfly can reach the Kubernetes API (1) or not (0)",
+};
+
+pub const CLEANUP_REMOVED: MetricDef = MetricDef {
+    name: "dragonfly_cleanup_removed_total",
+    kind: "counter",
+    help: "Expired sessions, tokens and credentials removed by the cleanup task, by kind",
+};
+
+pub const API_RATE_LIMITED: MetricDef = MetricDef {
+    name: "dragonfly_api_rate_limited_total",
+    kind: "counter",
+    help: "API requests refused for exceeding a rate limit, by the limit hit (address or token)",
+};
+
+pub const EVENT_SUBSCRIBERS: MetricDef = MetricDef {
+    name: "dragonfly_event_subscribers",
+    kind: "gauge",
+    help: "Live event subscribers (SSE and WebSocket clients, gRPC streams, dispatchers)",
+};
+
+pub const EVENT_QUEUE_DEPTH: MetricDef = MetricDef {
+    name: "dragonfly_event_queue_depth_max",
+    kind: "gauge",
+    help: "Events waiting in the fullest subscriber queue",
+};
+
+pub const EVENTS_DROPPED: MetricDef = MetricDef {
+    name: "dragonfly_events_dropped_total",
+    kind: "counter",
+    help: "Events dropped from full subscriber queues",
+};
+
+pub const EVENT_SUBSCRIBERS_DISCONNECTED: MetricDef = MetricDef {
+    name: "dragonfly_event_subscribers_disconnected_total",
+    kind: "counter",
+    help: "Event subscribers disconnected for falling behind",
+};
+
+pub const DB_QUERY_DURATION: MetricDef = MetricDef {
+    name: "dragonfly_db_query_duration_seconds",
+    kind: "histogram",
+    help: "Time taken by database queries on the boot, heartbeat and dashboard paths, by query",
+};
+
+pub const MACHINE_CACHE_LOOKUPS: MetricDef = MetricDef {
+    name: "dragonfly_machine_cache_lookups_total",
+    kind: "counter",
+    help: "Machine lookups on the boot and dashboard paths, by whether the cache answered them (hit) or the database did (miss)",
+};
+
+pub const METRICS: &[&MetricDef] = &[
+    &MACHINES, &INSTALL_PROGRESS, &CLOCK_SKEW, &TEMPLATE_USAGE, &KUBERNETES_UP, &CLEANUP_REMOVED, &API_RATE_LIMITED,
+    &EVENT_SUBSCRIBERS, &EVENT_QUEUE_DEPTH, &EVENTS_DROPPED, &EVENT_SUBSCRIBERS_DISCONNECTED, &DB_QUERY_DURATION,
+    &MACHINE_CACHE_LOOKUPS,
+];
+
+/// Label value used for a machine status
+pub fn status_label(status: &MachineStatus) -> &'static str {
+    match status {
+        MachineStatus::ExistingOS => "existing_os",
+        MachineStatus::AwaitingAssignment => "awaiting_assignment",
+        MachineStatus::InstallingOS => "installing_os",
+        MachineStatus::Ready => "ready",
+        MachineStatus::Offline => "offline",
+        MachineStatus::PendingApproval => "pending_approval",
+        MachineStatus::Rejected => "rejected",
+        MachineStatus::Error(_) => "error",
+    }
+}
+
+const ALL_STATUSES: &[&str] = &[
+    "existing_os", "awaiting_assignment", "installing_os", "ready",
+    "offline", "pending_approval", "rejected", "error",
+];
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, metric: &MetricDef) {
+    let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+    let _ = writeln!(out, "# TYPE {} {}", metric.name, metric.kind);
+}
+
+/// Render all metrics in the Prometheus text exposition format
+pub async fn render_metrics() -> Result<String> {
+    let machines = db::get_all_machines().await?;
+    let mut out = String::new();
+
+    let mut by_status: BTreeMap<&str, usize> = ALL_STATUSES.iter().map(|s| (*s, 0)).collect();
+    for machine in &machines {
+        *by_status.entry(status_label(&machine.status)).or_default() += 1;
+    }
+    write_header(&mut out, &MACHINES);
+    for (status, count) in by_status {
+        let _ = writeln!(out, "{}{{status=\"{}\"}} {}", MACHINES.name, status, count);
+    }
+
+    write_header(&mut out, &INSTALL_PROGRESS);
+    for machine in machines.iter().filter(|m| m.status == MachineStatus::InstallingOS) {
+        let _ = writeln!(out, "{}{{machine_id=\"{}\",hostname=\"{}\"}} {}",
+            INSTALL_PROGRESS.name, machine.id, escape_label(machine.hostname.as_deref().unwrap_or("")), machine.installation_progress);
+    }
+
+    write_header(&mut out, &CLOCK_SKEW);
+    for machine in &machines {
+        if let Some(skew) = machine.clock_skew_seconds {
+            let _ = writeln!(out, "{}{{machine_id=\"{}\",hostname=\"{}\"}} {}",
+                CLOCK_SKEW.name, machine.id, escape_label(machine.hostname.as_deref().unwrap_or("")), skew);
+        }
+    }
+
+    write_header(&mut out, &TEMPLATE_USAGE);
+    for (template, count) in db::get_template_usage().await? {
+        let _ = writeln!(out, "{}{{template=\"{}\"}} {}", TEMPLATE_USAGE.name, escape_label(&template), count);
+    }
+
+    write_header(&mut out, &KUBERNETES_UP);
+    let kubernetes_up = crate::status::check_kubernetes_connectivity().await.is_ok();
+    let _ = writeln!(out, "{} {}", KUBERNETES_UP.name, kubernetes_up as u8);
+
+    write_header(&mut out, &CLEANUP_REMOVED);
+    for (kind, count) in crate::cleanup::removed_totals() {
+        let _ = writeln!(out, "{}{{kind=\"{}\"}} {}", CLEANUP_REMOVED.name, kind, count);
+    }
+
+    write_header(&mut out, &API_RATE_LIMITED);
+    for (limit, count) in crate::rate_limit::rate_limited_totals() {
+        let _ = writeln!(out, "{}{{limit=\"{}\"}} {}", API_RATE_LIMITED.name, limit, count);
+    }
+
+    let event_stats = crate::EVENT_MANAGER_REF.read().ok()
+        .and_then(|guard| guard.as_ref().map(|events| events.stats()))
+        .unwrap_or_default();
+    write_header(&mut out, &EVENT_SUBSCRIBERS);
+    let _ = writeln!(out, "{} {}", EVENT_SUBSCRIBERS.name, event_stats.subscribers);
+    write_header(&mut out, &EVENT_QUEUE_DEPTH);
+    let _ = writeln!(out, "{} {}", EVENT_QUEUE_DEPTH.name, event_stats.max_queue_depth);
+    write_header(&mut out, &EVENTS_DROPPED);
+    let _ = writeln!(out, "{} {}", EVENTS_DROPPED.name, event_stats.events_dropped);
+    write_header(&mut out, &EVENT_SUBSCRIBERS_DISCONNECTED);
+    let _ = writeln!(out, "{} {}", EVENT_SUBSCRIBERS_DISCONNECTED.name, event_stats.subscribers_disconnected);
+
+    write_header(&mut out, &DB_QUERY_DURATION);
+    for (query, durations) in db::query_durations() {
+        for (count, le) in durations.buckets.iter().zip(db::QUERY_DURATION_BUCKETS) {
+            let _ = writeln!(out, "{}_bucket{{query=\"{}\",le=\"{}\"}} {}", DB_QUERY_DURATION.name, query, le, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{query=\"{}\",le=\"+Inf\"}} {}", DB_QUERY_DURATION.name, query, durations.count);
+        let _ = writeln!(out, "{}_sum{{query=\"{}\"}} {}", DB_QUERY_DURATION.name, query, durations.sum_secs);
+        let _ = writeln!(out, "{}_count{{query=\"{}\"}} {}", DB_QUERY_DURATION.name, query, durations.count);
+    }
+
+    let (hits, misses) = crate::machine_cache::lookup_totals();
+    write_header(&mut out, &MACHINE_CACHE_LOOKUPS);
+    let _ = writeln!(out, "{}{{result=\"hit\"}} {}", MACHINE_CACHE_LOOKUPS.name, hits);
+    let _ = writeln!(out, "{}{{result=\"miss\"}} {}", MACHINE_CACHE_LOOKUPS.name, misses);
+
+    Ok(out)
+}
+
+/// Compact fleet summary for external status pages and chat bots that don't want the full machine list
+#[derive(Debug, Serialize)]
+pub struct FleetStats {
+    pub generated_at: DateTime<Utc>,
+    pub total: usize,
+    pub by_status: BTreeMap<&'static str, usize>,
+    /// Keyed by installed OS, or the assigned OS for machines that haven't installed it yet
+    pub by_os: BTreeMap<String, usize>,
+    /// Keyed by the site of each machine's rack; machines not placed in a rack count as `unplaced`
+    pub by_site: BTreeMap<String, usize>,
+    pub active_installs: usize,
+    /// Machines waiting on an operator: OS assignment or registration approval
+    pub queue_depth: usize,
+    pub installs_24h: InstallOutcomes,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InstallOutcomes {
+    pub succeeded: i64,
+    pub failed: i64,
+    /// Failed share of finished installations, or null when none finished
+    pub failure_rate: Option<f64>,
+}
+
+impl FleetStats {
+    fn new(machines: &[Machine], sites: &HashMap<Uuid, String>, succeeded: i64, failed: i64) -> Self {
+        let mut by_status: BTreeMap<&str, usize> = ALL_STATUSES.iter().map(|s| (*s, 0)).collect();
+        let mut by_os: BTreeMap<String, usize> = BTreeMap::new();
+        let mut by_site: BTreeMap<String, usize> = BTreeMap::new();
+        for machine in machines {
+            *by_status.entry(status_label(&machine.status)).or_default() += 1;
+            let os = machine.os_installed.as_deref().or(machine.os_choice.as_deref()).unwrap_or("none");
+            *by_os.entry(os.to_string()).or_default() += 1;
+            let site = sites.get(&machine.id).map_or("unplaced", String::as_str);
+            *by_site.entry(site.to_string()).or_default() += 1;
+        }
+        let finished = succeeded + failed;
+
+        Self {
+            generated_at: Utc::now(),
+            total: machines.len(),
+            active_installs: by_status["installing_os"],
+            queue_depth: by_status["awaiting_assignment"] + by_status["pending_approval"],
+            by_status,
+            by_os,
+            by_site,
+            installs_24h: InstallOutcomes {
+                succeeded,
+                failed,
+                failure_rate: (finished > 0).then(|| failed as f64 / finished as f64),
+            },
+        }
+    }
+}
+
+pub async fn fleet_stats() -> Result<FleetStats> {
+    let machines = db::get_all_machines().await?;
+    let (succeeded, failed) = db::count_install_outcomes(Utc::now() - Duration::hours(24)).await?;
+    let racks = db::get_racks().await?;
+    let sites = db::get_placements().await?
+        .into_iter()
+        .filter_map(|p| racks.iter().find(|r| r.name == p.rack).map(|r| (p.machine_id, r.site.clone())))
+        .collect();
+    Ok(FleetStats::new(&machines, &sites, succeeded, failed))
+}
+
+/// Machines listed in each section of the fleet summary
+const SUMMARY_LIMIT: usize = 5;
+
+/// The fleet at a glance for phone widgets and status boards, small enough to poll. Carries no
+/// timestamp of its own, so its ETag only changes when the fleet does.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct FleetSummary {
+    pub total: usize,
+    /// Only statuses some machine is in
+    pub by_status: BTreeMap<&'static str, usize>,
+    pub failed_24h: i64,
+    /// Machines in error, most recent first
+    pub failures: Vec<SummaryFailure>,
+    /// Machines installing an OS, furthest along first
+    pub installs: Vec<SummaryInstall>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SummaryFailure {
+    pub id: Uuid,
+    pub name: String,
+    pub error: String,
+    pub since: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SummaryInstall {
+    pub id: Uuid,
+    pub name: String,
+    pub os: Option<String>,
+    pub progress: u8,
+    pub step: Option<String>,
+}
+
+impl FleetSummary {
+    fn new(machines: &[Machine], failed_24h: i64) -> Self {
+        let mut by_status: BTreeMap<&str, usize> = BTreeMap::new();
+        for machine in machines {
+            *by_status.entry(status_label(&machine.status)).or_default() += 1;
+        }
+
+        let mut failed: Vec<&Machine> = machines.iter().filter(|m| matches!(m.status, MachineStatus::Error(_))).collect();
+        failed.sort_by_key(|m| std::cmp::Reverse(m.updated_at));
+        let failures = failed.into_iter().take(SUMMARY_LIMIT)
+            .map(|machine| SummaryFailure {
+                id: machine.id,
+                name: machine.display_name(),
+                error: match &machine.status {
+                    MachineStatus::Error(message) => message.clone(),
+                    _ => String::new(),
+                },
+                since: machine.updated_at,
+            })
+            .collect();
+
+        let mut installing: Vec<&Machine> = machines.iter().filter(|m| m.status == MachineStatus::InstallingOS).collect();
+        installing.sort_by_key(|m| std::cmp::Reverse(m.installation_progress));
+        let installs = installing.into_iter().take(SUMMARY_LIMIT)
+            .map(|machine| SummaryInstall {
+                id: machine.id,
+                name: machine.display_name(),
+                os: machine.os_choice.clone(),
+                progress: machine.installation_progress,
+                step: machine.installation_step.clone(),
+            })
+            .collect();
+
+        Self { total: machines.len(), by_status, failed_24h, failures, installs }
+    }
+
+    /// Changes whenever anything in the summary does
+    pub fn etag(&self) -> String {
+        let digest = Sha256::digest(serde_json::to_vec(self).unwrap_or_default());
+        format!("\"{}\"", hex::encode(&digest[..16]))
+    }
+}
+
+/// The summary of the machines `principal` may view, or of every machine for an admin (None)
+pub async fn fleet_summary(principal: Option<&str>) -> Result<FleetSummary> {
+    let mut machines = db::get_all_machines().await?;
+    if let Some(principal) = principal {
+        let mut visible = Vec::with_capacity(machines.len());
+        for machine in machines {
+            if crate::policy::check_principal(principal, &machine.id, crate::policy::Permission::View).await? {
+                visible.push(machine);
+            }
+        }
+        machines = visible;
+    }
+    let (_, failed) = db::count_install_outcomes(Utc::now() - Duration::hours(24)).await?;
+    Ok(FleetSummary::new(&machines, failed))
+}
+
+/// Installs are kept for 30 days, so trends can't look further back
+pub const MAX_TREND_DAYS: i64 = 30;
+
+/// Installations per day and per template, for the dashboard charts
+#[derive(Debug, Serialize)]
+pub struct ProvisioningTrends {
+    /// One entry per day, oldest first, including days without installs
+    pub days: Vec<DayTrend>,
+    /// Templates by number of installs, most used first
+    pub templates: Vec<TemplateTrend>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct DayTrend {
+    /// UTC date, e.g. `2026-10-16`
+    pub date: String,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Of installs that finished that day, successful or not; null when there were none
+    pub median_duration_secs: Option<i64>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct TemplateTrend {
+    pub template: String,
+    pub installs: usize,
+    pub failed: usize,
+    pub failure_rate: f64,
+}
+
+fn median(mut values: Vec<i64>) -> Option<i64> {
+    values.sort_unstable();
+    let middle = values.len() / 2;
+    match values.len() {
+        0 => None,
+        n if n % 2 == 0 => Some((values[middle - 1] + values[middle]) / 2),
+        _ => Some(values[middle]),
+    }
+}
+
+impl ProvisioningTrends {
+    fn new(outcomes: &[db::InstallOutcome], days: i64, now: DateTime<Utc>) -> Self {
+        let first = now.date_naive() - Duration::days(days - 1);
+        let mut by_day: Vec<(usize, usize, Vec<i64>)> = (0..days).map(|_| (0, 0, Vec::new())).collect();
+        let mut by_template: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+        for outcome in outcomes {
+            let day = (outcome.finished_at.date_naive() - first).num_days();
+            let Some((succeeded, failed, durations)) = usize::try_from(day).ok().and_then(|day| by_day.get_mut(day)) else {
+                continue;
+            };
+            if outcome.succeeded {
+                *succeeded += 1;
+            } else {
+                *failed += 1;
+            }
+            durations.extend(outcome.duration_secs.filter(|secs| *secs >= 0));
+            let template = by_template.entry(outcome.template_name.clone().unwrap_or_else(|| "unknown".to_string())).or_default();
+            template.0 += 1;
+            if !outcome.succeeded {
+                template.1 += 1;
+            }
+        }
+
+        let days = by_day.into_iter().enumerate()
+            .map(|(day, (succeeded, failed, durations))| DayTrend {
+                date: (first + Duration::days(day as i64)).to_string(),
+                succeeded,
+                failed,
+                median_duration_secs: median(durations),
+            })
+            .collect();
+        let mut templates: Vec<TemplateTrend> = by_template.into_iter()
+            .map(|(template, (installs, failed))| TemplateTrend {
+                template,
+                installs,
+                failed,
+                failure_rate: failed as f64 / installs as f64,
+            })
+            .collect();
+        templates.sort_by_key(|template| std::cmp::Reverse(template.installs));
+        Self { days, templates }
+    }
+}
+
+/// Trends over the last `days` days, up to [`MAX_TREND_DAYS`]
+pub async fn provisioning_trends(days: i64) -> Result<ProvisioningTrends> {
+    let days = days.clamp(1, MAX_TREND_DAYS);
+    let now = Utc::now();
+    let since = (now.date_naive() - Duration::days(days - 1)).and_hms_opt(0, 0, 0).unwrap().and_utc();
+    let outcomes = if crate::demo::is_active() {
+        crate::demo::install_outcomes()
+    } else {
+        db::get_install_outcomes(since).await?
+    };
+    Ok(ProvisioningTrends::new(&outcomes, days, now))
+}
+
+struct AlertRule {
+    name: &'static str,
+    expr: String,
+    duration: &'static str,
+    severity: &'static str,
+    summary: &'static str,
+}
+
+fn alert_rules() -> Vec<AlertRule> {
+    vec![
+        AlertRule {
+            name: "DragonflyDown",
+            expr: format!("absent({})", KUBERNETES_UP.name),
+            duration: "5m",
+            severity: "critical",
+            summary: "Dragonfly metrics are missing; the server is down or not being scraped",
+        },
+        AlertRule {
+            name: "DragonflyKubernetesUnreachable",
+            expr: format!("{} == 0", KUBERNETES_UP.name),
+            duration: "5m",
+            severity: "critical",
+            summary: "Dragonfly cannot reach the Kubernetes API; provisioning is stalled",
+        },
+        AlertRule {
+            name: "DragonflyMachinesInError",
+            expr: format!("{}{{status=\"error\"}} > 0", MACHINES.name),
+            duration: "10m",
+            severity: "warning",
+            summary: "{{ $value }} machine(s) are in an error state",
+        },
+        AlertRule {
+            name: "DragonflyInstallationStalled",
+            expr: format!("changes({name}[30m]) == 0 and {name} < 100", name = INSTALL_PROGRESS.name),
+            duration: "15m",
+            severity: "warning",
+            summary: "Installation on {{ $labels.hostname }} has not progressed for 45 minutes",
+        },
+        AlertRule {
+            name: "DragonflyClockSkew",
+            expr: format!("abs({}) > 60", CLOCK_SKEW.name),
+            duration: "10m",
+            severity: "warning",
+            summary: "Clock on {{ $labels.hostname }} is off by {{ $value }}s; TLS and workflows may fail",
+        },
+        AlertRule {
+            name: "DragonflyMachinesAwaitingApproval",
+            expr: format!("{}{{status=\"pending_approval\"}} > 0", MACHINES.name),
+            duration: "1h",
+            severity: "info",
+            summary: "{{ $value }} discovered machine(s) have been waiting for approval for over an hour",
+        },
+    ]
+}
+
+/// Prometheus alerting rules file
+pub fn prometheus_rules() -> Result<String> {
+    let rules: Vec<Value> = alert_rules().into_iter().map(|rule| json!({
+        "alert": rule.name,
+        "expr": rule.expr,
+        "for": rule.duration,
+        "labels": { "severity": rule.severity },
+        "annotations": { "summary": rule.summary },
+    })).collect();
+    let file = json!({ "groups": [{ "name": "dragonfly", "rules": rules }] });
+    Ok(serde_yaml::to_string(&file)?)
+}
+
+/// Prometheus scrape job for a Dragonfly server at `base_url`
+pub fn scrape_config(base_url: &str) -> Result<String> {
+    let url = url::Url::parse(base_url)?;
+    let target = match (url.host_str(), url.port_or_known_default()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        _ => "localhost:3000".to_string(),
+    };
+    let config = json!({
+        "scrape_configs": [{
+            "job_name": "dragonfly",
+            "scheme": url.scheme(),
+            "metrics_path": "/api/metrics",
+            "static_configs": [{ "targets": [target] }],
+        }]
+    });
+    Ok(serde_yaml::to_string(&config)?)
+}
+
+fn panel(id: u32, title: &str, kind: &str, grid: (u32, u32, u32, u32), targets: Vec<(String, &str)>) -> Value {
+    let (x, y, w, h) = grid;
+    json!({
+        "id": id,
+        "title": title,
+        "type": kind,
+        "datasource": { "type": "prometheus", "uid": "${DS_PROMETHEUS}" },
+        "gridPos": { "x": x, "y": y, "w": w, "h": h },
+        "targets": targets.into_iter().enumerate().map(|(i, (expr, legend))| json!({
+            "refId": ((b'A' + i as u8) as char).to_string(),
+            "expr": expr,
+            "legendFormat": legend,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+/// Grafana dashboard, importable or usable with file-based provisioning
+pub fn grafana_dashboard() -> Value {
+    let panels = vec![
+        panel(1, "Machines", "stat", (0, 0, 6, 4), vec![(format!("sum({})", MACHINES.name), "total")]),
+        panel(2, "Machines in error", "stat", (6, 0, 6, 4), vec![(format!("{}{{status=\"error\"}}", MACHINES.name), "error")]),
+        panel(3, "Awaiting approval", "stat", (12, 0, 6, 4), vec![(format!("{}{{status=\"pending_approval\"}}", MACHINES.name), "pending")]),
+        panel(4, "Kubernetes reachable", "stat", (18, 0, 6, 4), vec![(KUBERNETES_UP.name.to_string(), "up")]),
+        panel(5, "Machines by status", "timeseries", (0, 4, 12, 8), vec![(MACHINES.name.to_string(), "{{status}}")]),
+        panel(6, "Installation progress", "bargauge", (12, 4, 12, 8), vec![(INSTALL_PROGRESS.name.to_string(), "{{hostname}}")]),
+        panel(7, "Clock skew", "timeseries", (0, 12, 12, 8), vec![(CLOCK_SKEW.name.to_string(), "{{hostname}}")]),
+        panel(8, "Template assignments (24h)", "barchart", (12, 12, 12, 8), vec![(format!("increase({}[24h])", TEMPLATE_USAGE.name), "{{template}}")]),
+        panel(9, "Database query time (p95)", "timeseries", (0, 20, 24, 8),
+            vec![(format!("histogram_quantile(0.95, sum by (query, le) (rate({}_bucket[5m])))", DB_QUERY_DURATION.name), "{{query}}")]),
+    ];
+
+    json!({
+        "__inputs": [{
+            "name": "DS_PROMETHEUS",
+            "label": "Prometheus",
+            "type": "datasource",
+            "pluginId": "prometheus",
+        }],
+        "uid": "dragonfly",
+        "title": "Dragonfly",
+        "tags": ["dragonfly"],
+        "timezone": "browser",
+        "schemaVersion": 39,
+        "refresh": "30s",
+        "time": { "from": "now-6h", "to": "now" },
+        "panels": panels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alerts_and_dashboard_only_use_exported_metrics() {
+        let known: Vec<&str> = METRICS.iter().map(|m| m.name).collect();
+        let uses_known = |expr: &str| known.iter().any(|name| expr.contains(name));
+
+        for rule in alert_rules() {
+            assert!(uses_known(&rule.expr), "alert {} uses no exported metric", rule.name);
+        }
+        for panel in grafana_dashboard()["panels"].as_array().unwrap() {
+            for target in panel["targets"].as_array().unwrap() {
+                assert!(uses_known(target["expr"].as_str().unwrap()), "panel {} uses no exported metric", panel["title"]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_provisioning_trends() {
+        let now = Utc::now();
+        let outcome = |days_ago: i64, template: &str, succeeded: bool, duration_secs: i64| db::InstallOutcome {
+            template_name: Some(template.to_string()),
+            succeeded,
+            duration_secs: Some(duration_secs),
+            finished_at: now - Duration::days(days_ago),
+        };
+        let outcomes = vec![
+            outcome(9, "ubuntu-2204", true, 100),
+            outcome(2, "ubuntu-2204", true, 300),
+            outcome(2, "ubuntu-2204", true, 500),
+            outcome(2, "debian-12", false, 60),
+            outcome(0, "debian-12", true, 400),
+        ];
+        let trends = ProvisioningTrends::new(&outcomes, 7, now);
+        assert_eq!(trends.days.len(), 7);
+        assert_eq!(trends.days[6].date, now.date_naive().to_string());
+        assert_eq!(trends.days[4], DayTrend { date: trends.days[4].date.clone(), succeeded: 2, failed: 1, median_duration_secs: Some(300) });
+        assert_eq!(trends.days[0].median_duration_secs, None);
+        assert_eq!(trends.templates[0], TemplateTrend { template: "debian-12".to_string(), installs: 2, failed: 1, failure_rate: 0.5 });
+        assert_eq!(trends.templates[1].installs, 2);
+        assert_eq!(median(vec![400, 100]), Some(250));
+    }
+
+    #[test]
+    fn test_fleet_summary() {
+        let now = Utc::now();
+        let machine = |mac: &str, status: MachineStatus, progress: u8, minutes_ago: i64| Machine {
+            os_choice: Some("ubuntu-2204".to_string()),
+            status,
+            created_at: now,
+            updated_at: now - Duration::minutes(minutes_ago),
+            installation_progress: progress,
+            ..crate::test_support::machine(mac)
+        };
+        let mut machines = vec![
+            machine("aa", MachineStatus::Error("disk not found".to_string()), 0, 30),
+            machine("bb", MachineStatus::Error("PXE timeout".to_string()), 0, 5),
+            machine("cc", MachineStatus::InstallingOS, 20, 0),
+            machine("dd", MachineStatus::InstallingOS, 80, 0),
+            machine("ee", MachineStatus::Ready, 0, 0),
+        ];
+        let summary = FleetSummary::new(&machines, 3);
+        assert_eq!(summary.total, 5);
+        assert_eq!(summary.by_status, BTreeMap::from([("error", 2), ("installing_os", 2), ("ready", 1)]));
+        assert_eq!(summary.failures[0].error, "PXE timeout");
+        assert_eq!(summary.failures[1].name, "aa");
+        assert_eq!(summary.installs.iter().map(|i| i.progress).collect::<Vec<_>>(), vec![80, 20]);
+
+        // Polling an unchanged fleet gets the same ETag
+        assert_eq!(summary.etag(), FleetSummary::new(&machines, 3).etag());
+        machines[3].installation_progress = 90;
+        assert_ne!(summary.etag(), FleetSummary::new(&machines, 3).etag());
+    }
+
+    #[test]
+    fn test_fleet_stats() {
+        let machine = |mac: &str, status: MachineStatus, os_choice: Option<&str>, os_installed: Option<&str>| Machine {
+            status,
+            os_choice: os_choice.map(str::to_string),
+            os_installed: os_installed.map(str::to_string),
+            ..crate::test_support::machine(mac)
+        };
+        let machines = vec![
+            machine("aa", MachineStatus::Ready, Some("ubuntu-2204"), Some("ubuntu-2404")),
+            machine("bb", MachineStatus::InstallingOS, Some("debian-12"), None),
+            machine("cc", MachineStatus::AwaitingAssignment, None, None),
+            machine("dd", MachineStatus::PendingApproval, None, None),
+        ];
+        let sites = HashMap::from([(machines[0].id, "ams1".to_string())]);
+
+        let stats = FleetStats::new(&machines, &sites, 3, 1);
+        assert_eq!(stats.total, 4);
+        assert_eq!(stats.by_status["offline"], 0);
+        assert_eq!(stats.active_installs, 1);
+        assert_eq!(stats.queue_depth, 2);
+        // What is installed counts, not what was once assigned
+        assert_eq!(stats.by_os, BTreeMap::from([("debian-12".to_string(), 1), ("none".to_string(), 2), ("ubuntu-2404".to_string(), 1)]));
+        assert_eq!(stats.by_site, BTreeMap::from([("ams1".to_string(), 1), ("unplaced".to_string(), 3)]));
+        assert_eq!(stats.installs_24h.failure_rate, Some(0.25));
+        assert_eq!(FleetStats::new(&[], &HashMap::new(), 0, 0).installs_24h.failure_rate, None);
+    }
+
+    #[test]
+    fn test_scrape_config_target() {
+        let config = scrape_config("http://10.0.0.1:3000").unwrap();
+        assert!(config.contains("10.0.0.1:3000"));
+        assert!(config.contains("/api/metrics"));
+    }
+
+    #[test]
+    fn test_query_duration_buckets() {
+        let mut durations = db::QueryDurations::default();
+        durations.record(0.02);
+        durations.record(0.0005);
+        durations.record(9.0);
+        // Each bucket counts every query at or under its bound, and the slowest fall in none
+        assert_eq!(durations.buckets, [1, 1, 1, 2, 2, 2, 2, 2]);
+        assert_eq!(durations.count, 3);
+        assert!((durations.sum_secs - 9.0205).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,215 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Finished operations kept for status lookups; older ones are forgotten first
+pub const MAX_FINISHED: usize = 500;
+
+lazy_static::lazy_static! {
+    static ref OPERATIONS: Mutex<HashMap<Uuid, Tracked>> = Mutex::new(HashMap::new());
+}
+
+struct Tracked {
+    operation: Operation,
+    cancel: Arc<AtomicBool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationState {
+    Running,
+    Succeeded,
+    Failed,
+    /// Stopped early at the caller's request; `result` holds whatever was done before it stopped
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Progress {
+    pub completed: usize,
+    pub total: usize,
+}
+
+/// A slow action running in the background, such as an import or a rack-wide install
+#[derive(Debug, Clone, Serialize)]
+pub struct Operation {
+    pub id: Uuid,
+    /// e.g. `machine_import`, `rack_assign_os`, `discovery_scan`
+    pub kind: String,
+    pub description: String,
+    pub state: OperationState,
+    pub started_by: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<Progress>,
+    /// Cancellation was asked for; the operation stops at its next safe point
+    pub cancel_requested: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Operation {
+    pub fn is_finished(&self) -> bool {
+        self.state != OperationState::Running
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum OperationError {
+    #[error("Operation not found")]
+    NotFound,
+    #[error("Operation already finished")]
+    Finished,
+}
+
+/// Handed to an operation's work so it can report progress and notice cancellation
+#[derive(Clone)]
+pub struct OperationContext {
+    id: Uuid,
+    cancel: Arc<AtomicBool>,
+}
+
+impl OperationContext {
+    /// Work should check this between steps and return early when it is set
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::SeqCst)
+    }
+
+    pub fn set_progress(&self, completed: usize, total: usize) {
+        update(&self.id, |op| op.progress = Some(Progress { completed, total }));
+    }
+}
+
+fn update(id: &Uuid, change: impl FnOnce(&mut Operation)) {
+    if let Some(tracked) = OPERATIONS.lock().unwrap().get_mut(id) {
+        change(&mut tracked.operation);
+        tracked.operation.updated_at = Utc::now();
+    }
+}
+
+fn prune(operations: &mut HashMap<Uuid, Tracked>) {
+    let mut finished: Vec<(DateTime<Utc>, Uuid)> = operations.values()
+        .filter(|t| t.operation.is_finished())
+        .map(|t| (t.operation.updated_at, t.operation.id))
+        .collect();
+    if finished.len() <= MAX_FINISHED {
+        return;
+    }
+    finished.sort();
+    for (_, id) in &finished[..finished.len() - MAX_FINISHED] {
+        operations.remove(id);
+    }
+}
+
+/// Run `work` in the background as a trackable operation, returning it as it starts
+pub fn start<F, Fut>(kind: &str, description: String, started_by: &str, work: F) -> Operation
+where
+    F: FnOnce(OperationContext) -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<Value>> + Send + 'static,
+{
+    let now = Utc::now();
+    let operation = Operation {
+        id: Uuid::new_v4(),
+        kind: kind.to_string(),
+        description,
+        state: OperationState::Running,
+        started_by: started_by.to_string(),
+        created_at: now,
+        updated_at: now,
+        progress: None,
+        cancel_requested: false,
+        result: None,
+        error: None,
+    };
+    let cancel = Arc::new(AtomicBool::new(false));
+    OPERATIONS.lock().unwrap().insert(operation.id, Tracked { operation: operation.clone(), cancel: cancel.clone() });
+    info!("Operation {} ({}) started by {}: {}", operation.id, kind, started_by, operation.description);
+
+    let context = OperationContext { id: operation.id, cancel };
+    tokio::spawn(async move {
+        let id = context.id;
+        let cancelled = context.clone();
+        let outcome = work(context).await;
+        let state = match (&outcome, cancelled.is_cancelled()) {
+            (_, true) => OperationState::Cancelled,
+            (Ok(_), false) => OperationState::Succeeded,
+            (Err(_), false) => OperationState::Failed,
+        };
+        match &outcome {
+            Ok(_) => info!("Operation {} finished: {:?}", id, state),
+            Err(e) => warn!("Operation {} failed: {}", id, e),
+        }
+        update(&id, |op| {
+            op.state = state;
+            match outcome {
+                Ok(result) => op.result = Some(result),
+                Err(e) => op.error = Some(e.to_string()),
+            }
+        });
+        prune(&mut OPERATIONS.lock().unwrap());
+    });
+    operation
+}
+
+pub fn get(id: &Uuid) -> Option<Operation> {
+    OPERATIONS.lock().unwrap().get(id).map(|t| t.operation.clone())
+}
+
+/// Every operation still remembered, newest first
+pub fn list() -> Vec<Operation> {
+    let mut operations: Vec<Operation> = OPERATIONS.lock().unwrap().values().map(|t| t.operation.clone()).collect();
+    operations.sort_by_key(|op| std::cmp::Reverse(op.created_at));
+    operations
+}
+
+/// Ask a running operation to stop. It finishes as cancelled once its work notices.
+pub fn cancel(id: &Uuid) -> Result<Operation, OperationError> {
+    let mut operations = OPERATIONS.lock().unwrap();
+    let tracked = operations.get_mut(id).ok_or(OperationError::NotFound)?;
+    if tracked.operation.is_finished() {
+        return Err(OperationError::Finished);
+    }
+    tracked.cancel.store(true, Ordering::SeqCst);
+    tracked.operation.cancel_requested = true;
+    tracked.operation.updated_at = Utc::now();
+    info!("Cancellation requested for operation {}", id);
+    Ok(tracked.operation.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_operation_lifecycle() {
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let op = start("test", "waits for the test".to_string(), "tester", |ctx| async move {
+            let _ = rx.await;
+            Ok(serde_json::json!({ "cancelled": ctx.is_cancelled() }))
+        });
+        assert_eq!(op.state, OperationState::Running);
+        assert!(cancel(&op.id).unwrap().cancel_requested);
+        tx.send(()).unwrap();
+
+        for _ in 0..100 {
+            if get(&op.id).unwrap().is_finished() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let finished = get(&op.id).unwrap();
+        assert_eq!(finished.state, OperationState::Cancelled);
+        assert_eq!(finished.result.unwrap()["cancelled"], true);
+        assert!(matches!(cancel(&op.id), Err(OperationError::Finished)));
+    }
+}
@@ -0,0 +1,161 @@
+use chrono::{NaiveDate, Utc};
+use dragonfly_common::models::{Machine, MachineStatus};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::db;
+
+/// Templates whose end of life is this close are flagged as ending soon
+pub const WARNING_DAYS: i64 = 90;
+
+/// When an OS template stops getting security updates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OsLifecycle {
+    #[serde(default)]
+    pub template: String,
+    pub eol_date: NaiveDate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecycleState {
+    Supported,
+    EndingSoon,
+    EndOfLife,
+    /// No end-of-life date has been recorded for the template
+    Unknown,
+}
+
+pub fn state(eol_date: Option<NaiveDate>, today: NaiveDate) -> LifecycleState {
+    match eol_date {
+        None => LifecycleState::Unknown,
+        Some(date) if date <= today => LifecycleState::EndOfLife,
+        Some(date) if (date - today).num_days() <= WARNING_DAYS => LifecycleState::EndingSoon,
+        Some(_) => LifecycleState::Supported,
+    }
+}
+
+pub fn validate(entry: &OsLifecycle) -> Result<(), String> {
+    let template = &entry.template;
+    if template.is_empty() || template.len() > 64 || !template.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_') {
+        return Err("Template names must be 1 to 64 lowercase letters, digits, '-' or '_'".to_string());
+    }
+    Ok(())
+}
+
+/// The template a machine is running. The agent reports the installed OS by its own name, such
+/// as "Ubuntu 22.04.4 LTS", so that is matched against template names and their display names;
+/// machines Dragonfly installed fall back to the template they were given.
+pub fn template_of(machine: &Machine, templates: &[String]) -> Option<String> {
+    if let Some(installed) = machine.os_installed.as_deref().map(str::to_lowercase) {
+        let found = templates.iter().find(|template| {
+            let name = crate::api::format_os_name(template).to_lowercase();
+            installed == **template || installed.starts_with(&name)
+        });
+        if found.is_some() {
+            return found.cloned();
+        }
+    }
+    machine.os_choice.clone().filter(|_| machine.status == MachineStatus::Ready)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TemplateCompliance {
+    pub template: String,
+    pub name: String,
+    pub eol_date: Option<NaiveDate>,
+    pub state: LifecycleState,
+    pub machine_count: usize,
+}
+
+/// A machine running a template that has reached, or is about to reach, end of life
+#[derive(Debug, Clone, Serialize)]
+pub struct MachineCompliance {
+    pub machine_id: Uuid,
+    pub name: String,
+    pub template: String,
+    pub eol_date: NaiveDate,
+    pub state: LifecycleState,
+    /// Negative once the template is past end of life
+    pub days_left: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ComplianceReport {
+    pub today: NaiveDate,
+    pub warning_days: i64,
+    pub templates: Vec<TemplateCompliance>,
+    pub machines: Vec<MachineCompliance>,
+}
+
+pub fn report(machines: &[Machine], lifecycle: &[OsLifecycle], today: NaiveDate) -> ComplianceReport {
+    let mut names: Vec<String> = lifecycle.iter().map(|l| l.template.clone())
+        .chain(machines.iter().filter_map(|m| m.os_choice.clone()))
+        .collect();
+    names.sort();
+    names.dedup();
+    let eol_date = |template: &str| lifecycle.iter().find(|l| l.template == template).map(|l| l.eol_date);
+    let running: Vec<(&Machine, Option<String>)> = machines.iter().map(|m| (m, template_of(m, &names))).collect();
+
+    let templates = names.iter().map(|template| TemplateCompliance {
+        template: template.clone(),
+        name: crate::api::format_os_name(template),
+        eol_date: eol_date(template),
+        state: state(eol_date(template), today),
+        machine_count: running.iter().filter(|(_, t)| t.as_deref() == Some(template.as_str())).count(),
+    }).collect();
+
+    let mut at_risk: Vec<MachineCompliance> = running.iter()
+        .filter_map(|(machine, template)| {
+            let template = template.as_ref()?;
+            let date = eol_date(template)?;
+            let state = state(Some(date), today);
+            (state != LifecycleState::Supported).then(|| MachineCompliance {
+                machine_id: machine.id,
+                name: machine.display_name(),
+                template: template.clone(),
+                eol_date: date,
+                state,
+                days_left: (date - today).num_days(),
+            })
+        })
+        .collect();
+    at_risk.sort_by_key(|m| (m.days_left, m.name.clone()));
+
+    ComplianceReport { today, warning_days: WARNING_DAYS, templates, machines: at_risk }
+}
+
+pub async fn compliance() -> anyhow::Result<ComplianceReport> {
+    let (machines, lifecycle) = tokio::try_join!(db::get_all_machines(), db::get_os_lifecycle())?;
+    Ok(report(&machines, &lifecycle, Utc::now().date_naive()))
+}
+
+/// Why a template may not be assigned, when end-of-life templates are blocked and it is past its date
+pub async fn check_assignment(template: &str) -> anyhow::Result<Result<(), String>> {
+    if !db::get_app_settings().await?.block_eol_assignments {
+        return Ok(Ok(()));
+    }
+    let lifecycle = db::get_os_lifecycle().await?;
+    match lifecycle.iter().find(|l| l.template == template) {
+        Some(entry) if state(Some(entry.eol_date), Utc::now().date_naive()) == LifecycleState::EndOfLife => Ok(Err(format!(
+            "{} reached end of life on {} and can no longer be assigned",
+            crate::api::format_os_name(template),
+            entry.eol_date,
+        ))),
+        _ => Ok(Ok(())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lifecycle_state() {
+        let today = NaiveDate::from_ymd_opt(2026, 10, 16).unwrap();
+        assert_eq!(state(None, today), LifecycleState::Unknown);
+        assert_eq!(state(Some(today), today), LifecycleState::EndOfLife);
+        assert_eq!(state(NaiveDate::from_ymd_opt(2026, 12, 1), today), LifecycleState::EndingSoon);
+        assert_eq!(state(NaiveDate::from_ymd_opt(2029, 4, 30), today), LifecycleState::Supported);
+    }
+}
@@ -34,12 +34,22 @@ pub async fn init_os_templates() -> Result<()> {
         return Err(anyhow!("Failed to install ubuntu-2204 template: {}", e));
     }
     
+    // Firmware flashing is only used by pipelines, so a missing template isn't fatal
+    if let Err(e) = install_template(client, crate::firmware::WORKFLOW_TEMPLATE, &base_url_bare).await {
+        warn!("Failed to install {} template: {}", crate::firmware::WORKFLOW_TEMPLATE, e);
+    }
+    
+    // Templates edited through the API replace the shipped ones
+    if let Err(e) = crate::workflow_templates::apply_stored().await {
+        error!("Failed to apply stored workflow templates: {}", e);
+    }
+    
     info!("OS templates initialization complete");
     Ok(())
 }
 
 /// Extract base URL without port from DRAGONFLY_BASE_URL environment variable
-fn get_base_url_without_port() -> Result<String> {
+pub(crate) fn get_base_url_without_port() -> Result<String> {
     // Read required base URL from environment variable
     let base_url = match env::var("DRAGONFLY_BASE_URL") {
         Ok(url) => url,
@@ -82,16 +92,7 @@ fn get_base_url_without_port() -> Result<String> {
 
 /// Check if a template exists in Kubernetes, and install it if it doesn't
 async fn install_template(client: &Client, template_name: &str, base_url_bare: &str) -> Result<()> {
-    // Create the API resource for Template CRD
-    let template_api_resource = kube::core::ApiResource {
-        group: "tinkerbell.org".to_string(),
-        version: "v1alpha1".to_string(),
-        kind: "Template".to_string(),
-        api_version: "tinkerbell.org/v1alpha1".to_string(),
-        plural: "templates".to_string(),
-    };
-    
-    let template_api: Api<DynamicObject> = Api::namespaced_with(client.clone(), "tink", &template_api_resource);
+    let template_api = template_api(client);
     
     // Check if template already exists
     match template_api.get(template_name).await {
@@ -112,15 +113,7 @@ async fn install_template(client: &Client, template_name: &str, base_url_bare: &
 
 /// Install a template from a YAML file
 async fn install_template_from_file(client: &Client, template_name: &str, base_url_bare: &str) -> Result<()> {
-    // Determine file paths
-    let os_templates_dir = Path::new("/var/lib/dragonfly/os-templates");
-    let fallback_dir = Path::new("os-templates");
-    
-    let template_path = if os_templates_dir.exists() {
-        os_templates_dir.join(format!("{}.yml", template_name))
-    } else {
-        fallback_dir.join(format!("{}.yml", template_name))
-    };
+    let template_path = templates_dir().join(format!("{}.yml", template_name));
     
     info!("Loading template from: {:?}", template_path);
     
@@ -151,28 +144,8 @@ async fn install_template_from_file(client: &Client, template_name: &str, base_u
         }
     };
     
-    // Fix metadata_urls to work with the correct port
-    let template_yaml = fix_metadata_urls(&template_yaml, base_url_bare);
-    
-    // Parse YAML to get the DynamicObject
-    let dynamic_obj: DynamicObject = match serde_yaml::from_str(&template_yaml) {
-        Ok(obj) => obj,
-        Err(e) => {
-            error!("Failed to parse template YAML: {}", e);
-            return Err(anyhow!("Failed to parse template YAML: {}", e));
-        }
-    };
-    
-    // Create the API resource for Template CRD
-    let template_api_resource = kube::core::ApiResource {
-        group: "tinkerbell.org".to_string(),
-        version: "v1alpha1".to_string(),
-        kind: "Template".to_string(),
-        api_version: "tinkerbell.org/v1alpha1".to_string(),
-        plural: "templates".to_string(),
-    };
-    
-    let template_api: Api<DynamicObject> = Api::namespaced_with(client.clone(), "tink", &template_api_resource);
+    let dynamic_obj = prepare_template(&template_yaml, base_url_bare)?;
+    let template_api = template_api(client);
     
     // Create the template
     match template_api.create(&PostParams::default(), &dynamic_obj).await {
@@ -187,6 +160,60 @@ async fn install_template_from_file(client: &Client, template_name: &str, base_u
     }
 }
 
+/// The Tinkerbell Template resources in Dragonfly's namespace
+pub(crate) fn template_api(client: &Client) -> Api<DynamicObject> {
+    let resource = crate::tinkerbell::tinkerbell_resource("Template", "templates");
+    Api::namespaced_with(client.clone(), &crate::status::dragonfly_namespace(), &resource)
+}
+
+/// Substitute Dragonfly's placeholders into template YAML and parse it into the resource to apply
+pub(crate) fn prepare_template(template_yaml: &str, base_url_bare: &str) -> Result<DynamicObject> {
+    // Fix metadata_urls to work with the correct port
+    let template_yaml = fix_metadata_urls(template_yaml, base_url_bare);
+    
+    // Parse YAML to get the DynamicObject
+    let mut dynamic_obj: DynamicObject = match serde_yaml::from_str(&template_yaml) {
+        Ok(obj) => obj,
+        Err(e) => {
+            error!("Failed to parse template YAML: {}", e);
+            return Err(anyhow!("Failed to parse template YAML: {}", e));
+        }
+    };
+    
+    // Templates ship with namespace 'tink'; deploy them into the configured namespace instead
+    dynamic_obj.metadata.namespace = Some(crate::status::dragonfly_namespace());
+    Ok(dynamic_obj)
+}
+
+/// Where the templates shipped with Dragonfly are read from
+pub fn templates_dir() -> &'static Path {
+    let installed = Path::new("/var/lib/dragonfly/os-templates");
+    if installed.exists() { installed } else { Path::new("os-templates") }
+}
+
+/// Names of the workflow templates shipped in the templates directory
+pub async fn builtin_template_names() -> Vec<String> {
+    let mut names = Vec::new();
+    if let Ok(mut entries) = fs::read_dir(templates_dir()).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if let Some(name) = entry.file_name().to_str().and_then(|f| f.strip_suffix(".yml")) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.sort();
+    names
+}
+
+/// A shipped workflow template's YAML, if there is one with this name
+pub async fn read_builtin_template(template_name: &str) -> Result<Option<String>> {
+    match fs::read_to_string(templates_dir().join(format!("{}.yml", template_name))).await {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!("Failed to read template '{}': {}", template_name, e)),
+    }
+}
+
 /// Download a template from GitHub
 async fn download_template_from_github(url: &str) -> Result<String> {
     info!("Downloading template from: {}", url);
@@ -238,14 +265,38 @@ async fn save_template_to_filesystem(template_name: &str, content: &str) -> Resu
     }
 }
 
+/// Get the NTP servers to configure on provisioned machines as a YAML flow list.
+/// Reads a comma-separated list from DRAGONFLY_NTP_SERVERS, defaulting to pool.ntp.org.
+fn get_ntp_servers() -> String {
+    ntp_servers(env::var("DRAGONFLY_NTP_SERVERS").ok().as_deref())
+}
+
+fn ntp_servers(configured: Option<&str>) -> String {
+    let servers: Vec<String> = configured
+        .unwrap_or("pool.ntp.org")
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| format!("\"{}\"", s))
+        .collect();
+    
+    if servers.is_empty() {
+        "[\"pool.ntp.org\"]".to_string()
+    } else {
+        format!("[{}]", servers.join(", "))
+    }
+}
+
 /// Fix the metadata_urls in the template YAML to work with the correct port
-fn fix_metadata_urls(yaml: &str, base_url_bare: &str) -> String {
+pub(crate) fn fix_metadata_urls(yaml: &str, base_url_bare: &str) -> String {
     // Replace both {{ base_url }} and {{ base_url_bare }} with the actual base_url_bare value
     // to ensure the port will be correctly appended
     let replacement_vars = HashMap::from([
         // Use the same base_url_bare for both placeholders
         ("base_url".to_string(), base_url_bare.to_string()),
         ("base_url_bare".to_string(), base_url_bare.to_string()),
+        // NTP servers for cloud-init, so skewed clocks don't break TLS after install
+        ("ntp_servers".to_string(), get_ntp_servers()),
     ]);
     
     let mut result = yaml.to_string();
@@ -312,4 +363,11 @@ mod tests {
             assert_eq!(result, expected, "Failed parsing URL: {}", input);
         }
     }
+
+    #[test]
+    fn test_ntp_servers() {
+        assert_eq!(ntp_servers(None), "[\"pool.ntp.org\"]");
+        assert_eq!(ntp_servers(Some(" time.lab.internal, 10.0.0.1 ,")), "[\"time.lab.internal\", \"10.0.0.1\"]");
+        assert_eq!(ntp_servers(Some(" , ")), "[\"pool.ntp.org\"]");
+    }
 } 
\ No newline at end of file
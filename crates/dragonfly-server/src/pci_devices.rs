@@ -0,0 +1,148 @@
+use dragonfly_common::models::PciDevice;
+use serde::Serialize;
+use std::collections::HashMap;
+
+// PCI class codes (class and subclass) we derive capabilities from
+const CLASS_VGA: &str = "0300";
+const CLASS_3D: &str = "0302";
+const CLASS_NVME: &str = "0108";
+const CLASS_ETHERNET: &str = "0200";
+
+// Vendors whose VGA devices are BMC or onboard display chips rather than GPUs
+const DISPLAY_ONLY_VENDORS: [&str; 2] = [
+    "1a03", // ASPEED
+    "102b", // Matrox
+];
+
+/// What a machine's PCI devices let it do, for scheduling
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct Capabilities {
+    /// Model names of each GPU, one entry per card
+    pub gpus: Vec<String>,
+    pub nvme_count: usize,
+    /// NICs that can offer SR-IOV virtual functions
+    pub sriov_nics: usize,
+    pub sriov_total_vfs: u32,
+}
+
+impl Capabilities {
+    pub fn from_devices(devices: &[PciDevice]) -> Self {
+        let mut capabilities = Capabilities::default();
+        for device in devices {
+            if is_gpu(device) {
+                capabilities.gpus.push(display_name(device));
+            } else if device.class == CLASS_NVME {
+                capabilities.nvme_count += 1;
+            } else if device.class == CLASS_ETHERNET {
+                if let Some(vfs) = device.sriov_total_vfs.filter(|&vfs| vfs > 0) {
+                    capabilities.sriov_nics += 1;
+                    capabilities.sriov_total_vfs += vfs;
+                }
+            }
+        }
+        capabilities
+    }
+}
+
+pub fn is_gpu(device: &PciDevice) -> bool {
+    match device.class.as_str() {
+        CLASS_3D => true,
+        CLASS_VGA => !DISPLAY_ONLY_VENDORS.contains(&device.vendor_id.as_str()),
+        _ => false,
+    }
+}
+
+/// The device's name from pci.ids, or its vendor:device IDs when the agent had no names
+pub fn display_name(device: &PciDevice) -> String {
+    match &device.device_name {
+        Some(name) => name.clone(),
+        None => format!("{}:{}", device.vendor_id, device.device_id),
+    }
+}
+
+/// Capability query parameters: `gpu=<model>`, `min_gpus=<n>`, `min_nvme=<n>` and `sriov=true`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CapabilityFilter {
+    /// Matched case-insensitively against GPU names, e.g. "a100"
+    pub gpu: Option<String>,
+    pub min_gpus: Option<usize>,
+    pub min_nvme: Option<usize>,
+    pub sriov: Option<bool>,
+}
+
+impl CapabilityFilter {
+    /// None when the query doesn't filter on capabilities at all
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        let count = |key: &str| -> Result<Option<usize>, String> {
+            query.get(key)
+                .map(|value| value.parse().map_err(|_| format!("{} must be a whole number", key)))
+                .transpose()
+        };
+        let filter = CapabilityFilter {
+            gpu: query.get("gpu").map(|gpu| gpu.trim().to_lowercase()).filter(|gpu| !gpu.is_empty()),
+            min_gpus: count("min_gpus")?,
+            min_nvme: count("min_nvme")?,
+            sriov: query.get("sriov")
+                .map(|value| value.parse().map_err(|_| "sriov must be true or false".to_string()))
+                .transpose()?,
+        };
+        Ok((filter != CapabilityFilter::default()).then_some(filter))
+    }
+
+    /// With `gpu`, only GPUs of that model count towards `min_gpus`, so
+    /// `gpu=a100&min_gpus=4` means four A100s, not an A100 and three others
+    pub fn matches(&self, capabilities: &Capabilities) -> bool {
+        let gpus = match &self.gpu {
+            Some(model) => capabilities.gpus.iter().filter(|gpu| gpu.to_lowercase().contains(model)).count(),
+            None => capabilities.gpus.len(),
+        };
+        let min_gpus = self.min_gpus.unwrap_or(if self.gpu.is_some() { 1 } else { 0 });
+        gpus >= min_gpus
+            && capabilities.nvme_count >= self.min_nvme.unwrap_or(0)
+            && self.sriov.is_none_or(|sriov| sriov == (capabilities.sriov_nics > 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(address: &str, class: &str, vendor_id: &str, device_name: Option<&str>, sriov_total_vfs: Option<u32>) -> PciDevice {
+        PciDevice {
+            address: address.to_string(),
+            class: class.to_string(),
+            vendor_id: vendor_id.to_string(),
+            device_id: "0000".to_string(),
+            vendor_name: None,
+            device_name: device_name.map(str::to_string),
+            driver: None,
+            sriov_total_vfs,
+        }
+    }
+
+    #[test]
+    fn test_capabilities_and_filters() {
+        let devices = vec![
+            device("0000:03:00.0", "0300", "1a03", Some("ASPEED Graphics Family"), None),
+            device("0000:17:00.0", "0302", "10de", Some("GA100 [A100 SXM4 40GB]"), None),
+            device("0000:31:00.0", "0302", "10de", Some("GA100 [A100 SXM4 40GB]"), None),
+            device("0000:4b:00.0", "0108", "144d", None, None),
+            device("0000:5e:00.0", "0200", "8086", Some("Ethernet Controller E810-C"), Some(128)),
+            device("0000:5e:00.1", "0200", "8086", Some("I350 Gigabit"), None),
+        ];
+        let capabilities = Capabilities::from_devices(&devices);
+        assert_eq!(capabilities.gpus.len(), 2);
+        assert_eq!((capabilities.nvme_count, capabilities.sriov_nics, capabilities.sriov_total_vfs), (1, 1, 128));
+
+        let query = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        let filter = |pairs: &[(&str, &str)]| CapabilityFilter::from_query(&query(pairs)).unwrap().unwrap();
+        assert!(filter(&[("gpu", "A100"), ("min_gpus", "2")]).matches(&capabilities));
+        assert!(!filter(&[("gpu", "a100"), ("min_gpus", "4")]).matches(&capabilities));
+        assert!(!filter(&[("gpu", "h100")]).matches(&capabilities));
+        assert!(filter(&[("sriov", "true"), ("min_nvme", "1")]).matches(&capabilities));
+        assert!(CapabilityFilter::from_query(&query(&[("site", "syd1")])).unwrap().is_none());
+        assert!(CapabilityFilter::from_query(&query(&[("min_gpus", "lots")])).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::db;
+
+/// Machines tagged `profile:<name>` run the pipeline of that name before their OS is installed
+pub const TAG_PREFIX: &str = "profile:";
+
+/// Workflow templates run, in order, before a machine's OS template
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pipeline {
+    pub name: String,
+    pub stages: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunState {
+    Running,
+    Failed,
+    Completed,
+}
+
+impl RunState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunState::Running => "running",
+            RunState::Failed => "failed",
+            RunState::Completed => "completed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "running" => Some(RunState::Running),
+            "failed" => Some(RunState::Failed),
+            "completed" => Some(RunState::Completed),
+            _ => None,
+        }
+    }
+}
+
+/// A machine's way through its pipeline. The last stage is always its OS template.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipelineRun {
+    pub machine_id: Uuid,
+    pub pipeline: String,
+    pub stages: Vec<String>,
+    /// Index into `stages` of the stage running, or that failed
+    pub current_stage: usize,
+    pub state: RunState,
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl PipelineRun {
+    pub fn current_template(&self) -> &str {
+        &self.stages[self.current_stage]
+    }
+
+    /// e.g. "stage 2 of 3 (raid-config)", for status history and logs
+    pub fn describe_stage(&self) -> String {
+        format!("stage {} of {} ({})", self.current_stage + 1, self.stages.len(), self.current_template())
+    }
+
+    /// The stage and state the run moves to once the workflow for `template` succeeds, or None
+    /// when that success isn't for the stage running
+    fn advance(&self, template: &str) -> Option<(usize, RunState)> {
+        if self.state != RunState::Running || self.current_template() != template {
+            return None;
+        }
+        if self.current_stage + 1 == self.stages.len() {
+            Some((self.current_stage, RunState::Completed))
+        } else {
+            Some((self.current_stage + 1, RunState::Running))
+        }
+    }
+}
+
+/// What to do once a stage's workflow has succeeded
+#[derive(Debug, Clone)]
+pub enum StageOutcome {
+    /// Start the next stage, which the run now points at
+    Next(PipelineRun),
+    /// The machine isn't in a pipeline, or that was its last stage: the install is done
+    Finished,
+    /// The success was already acted on, e.g. a resync seeing the same workflow again
+    Stale,
+}
+
+pub fn validate(pipeline: &Pipeline) -> Result<(), String> {
+    crate::workflow_templates::validate_name(&pipeline.name)?;
+    if pipeline.stages.is_empty() {
+        return Err("A pipeline needs at least one stage".to_string());
+    }
+    if let Some(stage) = pipeline.stages.iter().find(|s| crate::workflow_templates::validate_name(s).is_err()) {
+        return Err(format!("'{}' is not a workflow template name", stage));
+    }
+    Ok(())
+}
+
+pub fn profile_of(tags: &[String]) -> Option<&str> {
+    tags.iter().find_map(|tag| tag.strip_prefix(TAG_PREFIX)).filter(|p| !p.is_empty())
+}
+
+/// The pipeline a machine runs, if its profile has one. A `profile:` tag wins over the
+/// machine profile the machine was adopted into.
+pub async fn for_machine(machine_id: &Uuid) -> Result<Option<Pipeline>> {
+    let tags = db::get_machine_tags(machine_id).await?;
+    let profile = match profile_of(&tags) {
+        Some(profile) => Some(profile.to_string()),
+        None => crate::machine_profiles::for_machine(machine_id).await?.map(|profile| profile.name),
+    };
+    match profile {
+        Some(profile) => db::get_pipeline(&profile).await,
+        None => Ok(None),
+    }
+}
+
+/// Start an install. Machines with a pipeline start a fresh run and get its first stage's
+/// template; everyone else goes straight to their OS template.
+pub async fn begin(machine_id: &Uuid, os_template: &str) -> Result<String> {
+    let Some(pipeline) = for_machine(machine_id).await? else {
+        db::delete_pipeline_run(machine_id).await?;
+        return Ok(os_template.to_string());
+    };
+    let now = Utc::now();
+    let run = PipelineRun {
+        machine_id: *machine_id,
+        pipeline: pipeline.name,
+        stages: pipeline.stages.into_iter().chain(std::iter::once(os_template.to_string())).collect(),
+        current_stage: 0,
+        state: RunState::Running,
+        error: None,
+        started_at: now,
+        updated_at: now,
+    };
+    db::save_pipeline_run(&run).await?;
+    info!("Machine {} starting pipeline '{}' at {}", machine_id, run.pipeline, run.describe_stage());
+    Ok(run.current_template().to_string())
+}
+
+/// Record that the workflow for `template` succeeded and work out what comes next
+pub async fn complete_stage(machine_id: &Uuid, template: &str) -> Result<StageOutcome> {
+    let Some(run) = db::get_pipeline_run(machine_id).await? else {
+        return Ok(StageOutcome::Finished);
+    };
+    let Some((next_stage, state)) = run.advance(template) else {
+        return Ok(match run.state {
+            RunState::Completed => StageOutcome::Finished,
+            _ => StageOutcome::Stale,
+        });
+    };
+    // Only one caller gets to move the run on from a given stage
+    if !db::advance_pipeline_run(machine_id, run.current_stage, next_stage, state).await? {
+        return Ok(StageOutcome::Stale);
+    }
+    if state == RunState::Completed {
+        info!("Machine {} finished pipeline '{}'", machine_id, run.pipeline);
+        return Ok(StageOutcome::Finished);
+    }
+    let next = PipelineRun { current_stage: next_stage, updated_at: Utc::now(), ..run };
+    info!("Machine {} moving on to {} of pipeline '{}'", machine_id, next.describe_stage(), next.pipeline);
+    Ok(StageOutcome::Next(next))
+}
+
+/// Record that the current stage failed, returning the run so the failure can name the stage
+pub async fn fail_stage(machine_id: &Uuid, error: &str) -> Result<Option<PipelineRun>> {
+    let Some(mut run) = db::get_pipeline_run(machine_id).await? else {
+        return Ok(None);
+    };
+    if run.state == RunState::Running {
+        run.state = RunState::Failed;
+        run.error = Some(error.to_string());
+        run.updated_at = Utc::now();
+        db::save_pipeline_run(&run).await?;
+    }
+    Ok(Some(run))
+}
+
+/// Pick a failed run up again at the stage that failed, returning that stage's template
+pub async fn resume(machine_id: &Uuid) -> Result<Result<PipelineRun, String>> {
+    let Some(mut run) = db::get_pipeline_run(machine_id).await? else {
+        return Ok(Err("This machine has no pipeline run".to_string()));
+    };
+    if run.state != RunState::Failed {
+        return Ok(Err(format!("The pipeline run is {}, not failed", run.state.as_str())));
+    }
+    run.state = RunState::Running;
+    run.error = None;
+    run.updated_at = Utc::now();
+    db::save_pipeline_run(&run).await?;
+    info!("Machine {} resuming pipeline '{}' at {}", machine_id, run.pipeline, run.describe_stage());
+    Ok(Ok(run))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipeline_validation() {
+        let pipeline = Pipeline { name: "gpu-node".to_string(), stages: vec!["bios-update".to_string(), "raid-config".to_string()] };
+        assert!(validate(&pipeline).is_ok());
+        assert!(validate(&Pipeline { stages: vec![], ..pipeline.clone() }).is_err());
+        assert!(validate(&Pipeline { stages: vec!["Bad Name".to_string()], ..pipeline }).is_err());
+        assert_eq!(profile_of(&["rack:a".to_string(), "profile:gpu-node".to_string()]), Some("gpu-node"));
+    }
+
+    #[test]
+    fn test_stage_advancement() {
+        let run = PipelineRun {
+            machine_id: Uuid::new_v4(),
+            pipeline: "gpu-node".to_string(),
+            stages: vec!["bios-update".to_string(), "raid-config".to_string(), "ubuntu-2404".to_string()],
+            current_stage: 0,
+            state: RunState::Running,
+            error: None,
+            started_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        assert_eq!(run.advance("bios-update"), Some((1, RunState::Running)));
+        // A resync seeing a finished stage's workflow again moves nothing
+        assert_eq!(run.advance("ubuntu-2404"), None);
+
+        let last = PipelineRun { current_stage: 2, ..run.clone() };
+        assert_eq!(last.describe_stage(), "stage 3 of 3 (ubuntu-2404)");
+        assert_eq!(last.advance("ubuntu-2404"), Some((2, RunState::Completed)));
+        assert_eq!(PipelineRun { state: RunState::Failed, ..run }.advance("bios-update"), None);
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::Result;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, error};
+use uuid::Uuid;
+
+use crate::auth::AuthSession;
+use crate::db;
+
+/// Principal used for requests without a logged-in user
+pub const ANONYMOUS: &str = "anonymous";
+
+/// Rights on a machine, each level including the ones below it
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// See the machine and its status
+    View,
+    /// Change hostname, tags and OS assignment, trigger reimaging
+    Operate,
+    /// Approve, reject, delete, BMC credentials and iPXE overrides
+    Admin,
+}
+
+/// What an ACL entry applies to
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", content = "id", rename_all = "snake_case")]
+pub enum AclScope {
+    Machine(Uuid),
+    /// Every machine carrying this tag
+    Group(String),
+}
+
+/// Grants `permission` on `scope` to a principal (a username, `anonymous`, or a chat channel as `chat:<channel_id>`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AclEntry {
+    #[serde(default)]
+    pub id: i64,
+    pub principal: String,
+    pub scope: AclScope,
+    pub permission: Permission,
+}
+
+/// The principal ACLs are matched against for this session
+pub fn principal(auth_session: &AuthSession) -> String {
+    auth_session.user.as_ref()
+        .map(|user| user.username.clone())
+        .unwrap_or_else(|| ANONYMOUS.to_string())
+}
+
+/// Whether any of the entries grant `required` on the machine to the principal
+fn grants(entries: &[AclEntry], principal: &str, machine_id: &Uuid, groups: &[String], required: Permission) -> bool {
+    entries.iter()
+        .filter(|entry| entry.principal == principal && entry.permission >= required)
+        .any(|entry| match &entry.scope {
+            AclScope::Machine(id) => id == machine_id,
+            AclScope::Group(group) => groups.contains(group),
+        })
+}
+
+/// Check whether the session may act on a machine with the given permission.
+/// Administrators may do anything; other users, and anonymous requests, need an ACL grant.
+pub async fn check(auth_session: &AuthSession, machine_id: &Uuid, required: Permission) -> Result<bool> {
+    if crate::auth::is_admin(auth_session) {
+        return Ok(true);
+    }
+
+    check_principal(&principal(auth_session), machine_id, required).await
+}
+
+/// Check ACL grants for a principal that isn't a session, such as a chat channel
+pub async fn check_principal(principal: &str, machine_id: &Uuid, required: Permission) -> Result<bool> {
+    let entries = db::get_acl_entries_for_principal(principal).await?;
+    if entries.is_empty() {
+        return Ok(false);
+    }
+
+    let groups = db::get_machine_tags(machine_id).await?;
+    let allowed = grants(&entries, principal, machine_id, &groups, required);
+    debug!("ACL check for {} on machine {} ({:?}): {}", principal, machine_id, required, allowed);
+    Ok(allowed)
+}
+
+/// Like `check`, but produces the error response handlers should return when access is denied
+pub async fn authorize(auth_session: &AuthSession, machine_id: &Uuid, required: Permission) -> Result<(), Response> {
+    match check(auth_session, machine_id, required).await {
+        Ok(true) => Ok(()),
+        // Signed in but not allowed is 403; anonymous callers may be let in by signing in
+        Ok(false) => Err(denied_status(auth_session.user.is_some(), required)),
+        Err(e) => {
+            error!("Failed to evaluate permissions on machine {}: {}", machine_id, e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "error": "Database Error",
+                "message": format!("Failed to evaluate permissions: {}", e)
+            }))).into_response())
+        }
+    }
+}
+
+fn denied_status(logged_in: bool, required: Permission) -> Response {
+    let (status, error) = if logged_in {
+        (StatusCode::FORBIDDEN, "Forbidden")
+    } else {
+        (StatusCode::UNAUTHORIZED, "Unauthorized")
+    };
+    (status, Json(json!({
+        "error": error,
+        "message": format!("{:?} permission on this machine is required for this operation", required)
+    }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(principal: &str, scope: AclScope, permission: Permission) -> AclEntry {
+        AclEntry { id: 0, principal: principal.to_string(), scope, permission }
+    }
+
+    #[test]
+    fn test_machine_and_group_grants() {
+        let machine = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let entries = vec![
+            entry("alice", AclScope::Machine(machine), Permission::Operate),
+            entry("bob", AclScope::Group("lab".to_string()), Permission::View),
+        ];
+
+        assert!(grants(&entries, "alice", &machine, &[], Permission::View));
+        assert!(grants(&entries, "alice", &machine, &[], Permission::Operate));
+        assert!(!grants(&entries, "alice", &machine, &[], Permission::Admin));
+        assert!(!grants(&entries, "alice", &other, &[], Permission::View));
+
+        let lab = vec!["lab".to_string()];
+        assert!(grants(&entries, "bob", &other, &lab, Permission::View));
+        assert!(!grants(&entries, "bob", &other, &lab, Permission::Operate));
+        assert!(!grants(&entries, "bob", &other, &[], Permission::View));
+    }
+
+    #[test]
+    fn test_denied_status() {
+        assert_eq!(denied_status(true, Permission::Operate).status(), StatusCode::FORBIDDEN);
+        assert_eq!(denied_status(false, Permission::Operate).status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,206 @@
+//! Per-user UI preferences.
+//!
+//! A signed-in user's theme, status palette, machine list page size, timezone and language are
+//! kept in the database, so they follow the user from browser to browser. Cookies still decide
+//! for anonymous visitors, and they are brought in line with the stored preferences whenever a
+//! signed-in user loads a page, so the login page looks the same after signing out.
+
+use anyhow::Result;
+use axum::{
+    extract::Request,
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use cookie::Cookie;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tracing::warn;
+
+use crate::auth::AuthSession;
+use crate::db;
+
+pub const THEMES: &[&str] = &["light", "dark", "system"];
+/// Page sizes the machine list offers
+pub const PAGE_SIZES: &[u32] = &[25, 50, 100, 250];
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiPreferences {
+    pub theme: Option<String>,
+    pub palette: Option<String>,
+    /// Machines per page of the machine list; None lists them all
+    pub page_size: Option<u32>,
+    /// IANA name such as `Europe/Berlin`; None shows dates in the browser's timezone
+    pub timezone: Option<String>,
+    /// One of `i18n::LANGUAGES`; None goes by the browser's Accept-Language
+    pub language: Option<String>,
+    /// The saved view `/machines` opens with. Stored as that view's `is_default`, not with the rest.
+    pub default_view: Option<i64>,
+}
+
+// Not a full check against the timezone database, which the server doesn't have; browsers that
+// don't know a name fall back to their own timezone
+fn valid_timezone(timezone: &str) -> bool {
+    if timezone == "UTC" {
+        return true;
+    }
+    let parts: Vec<&str> = timezone.split('/').collect();
+    timezone.len() <= 64
+        && parts.len() >= 2
+        && parts[0].starts_with(|c: char| c.is_ascii_uppercase())
+        && parts.iter().all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || "_-+".contains(c)))
+}
+
+pub fn validate(preferences: &UiPreferences) -> Result<(), String> {
+    if let Some(theme) = preferences.theme.as_deref().filter(|theme| !THEMES.contains(theme)) {
+        return Err(format!("Unknown theme '{}'; use one of {}", theme, THEMES.join(", ")));
+    }
+    if let Some(palette) = preferences.palette.as_deref().filter(|palette| !crate::ui::PALETTES.contains(palette)) {
+        return Err(format!("Unknown palette '{}'; use one of {}", palette, crate::ui::PALETTES.join(", ")));
+    }
+    if let Some(page_size) = preferences.page_size.filter(|size| !PAGE_SIZES.contains(size)) {
+        return Err(format!(
+            "Page size {} isn't offered; use one of {}",
+            page_size,
+            PAGE_SIZES.iter().map(|size| size.to_string()).collect::<Vec<_>>().join(", ")
+        ));
+    }
+    if let Some(timezone) = preferences.timezone.as_deref().filter(|timezone| !valid_timezone(timezone)) {
+        return Err(format!("'{}' isn't a timezone name like Europe/Berlin or UTC", timezone));
+    }
+    if let Some(language) = preferences.language.as_deref().filter(|language| !crate::i18n::is_supported(language)) {
+        return Err(format!(
+            "The UI isn't available in '{}'; use one of {}",
+            language,
+            crate::i18n::LANGUAGES.iter().map(|(code, _)| *code).collect::<Vec<_>>().join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Stored preferences by username, without the default view
+static CACHE: Lazy<RwLock<HashMap<String, UiPreferences>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+tokio::task_local! {
+    static CURRENT: UiPreferences;
+}
+
+/// The signed-in user's preferences, while a page request is being handled
+pub fn current() -> Option<UiPreferences> {
+    CURRENT.try_with(UiPreferences::clone).ok()
+}
+
+/// A user's preferences, without the default view
+pub async fn get(username: &str) -> Result<UiPreferences> {
+    if let Some(preferences) = CACHE.read().unwrap().get(username) {
+        return Ok(preferences.clone());
+    }
+    let preferences = db::get_user_preferences(username).await?.unwrap_or_default();
+    CACHE.write().unwrap().insert(username.to_string(), preferences.clone());
+    Ok(preferences)
+}
+
+/// Save a user's preferences, including which saved view is their default
+pub async fn save(username: &str, mut preferences: UiPreferences) -> Result<()> {
+    db::set_default_view(username, preferences.default_view).await?;
+    preferences.default_view = None;
+    db::save_user_preferences(username, &preferences).await?;
+    CACHE.write().unwrap().insert(username.to_string(), preferences);
+    Ok(())
+}
+
+/// Change the theme, and the palette if given, leaving the other preferences alone
+pub async fn save_appearance(username: &str, theme: &str, palette: Option<&str>) -> Result<()> {
+    let mut preferences = get(username).await?;
+    preferences.theme = THEMES.contains(&theme).then(|| theme.to_string());
+    if let Some(palette) = palette {
+        preferences.palette = Some(palette.to_string());
+    }
+    db::save_user_preferences(username, &preferences).await?;
+    CACHE.write().unwrap().insert(username.to_string(), preferences);
+    Ok(())
+}
+
+pub(crate) fn cookie_value(request: &Request, name: &str) -> Option<String> {
+    request.headers().get_all(header::COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| Cookie::parse(pair.trim()).ok())
+        .find(|cookie| cookie.name() == name)
+        .map(|cookie| cookie.value().to_string())
+}
+
+/// Layer applying a signed-in user's preferences to the pages they load
+pub async fn apply(auth_session: AuthSession, request: Request, next: Next) -> Response {
+    let Some(username) = auth_session.user.as_ref().map(|user| user.username.clone()) else {
+        return next.run(request).await;
+    };
+    let path = request.uri().path();
+    if path.starts_with("/api/") || path.starts_with("/static/") {
+        return next.run(request).await;
+    }
+    let preferences = match get(&username).await {
+        Ok(preferences) => preferences,
+        Err(e) => {
+            warn!("Failed to load UI preferences for {}: {}", username, e);
+            return next.run(request).await;
+        },
+    };
+    let theme_cookie = cookie_value(&request, crate::ui::THEME_COOKIE);
+    let palette_cookie = cookie_value(&request, crate::ui::PALETTE_COOKIE);
+
+    let mut response = CURRENT.scope(preferences.clone(), next.run(request)).await;
+
+    // Pages that set the cookies, as the settings form does, have just saved the preferences too
+    if response.headers().contains_key(header::SET_COOKIE) {
+        return response;
+    }
+    let stale = [
+        (preferences.theme.filter(|theme| theme_cookie.as_ref() != Some(theme)), crate::ui::THEME_COOKIE),
+        (preferences.palette.filter(|palette| palette_cookie.as_ref() != Some(palette)), crate::ui::PALETTE_COOKIE),
+    ];
+    for (value, name) in stale {
+        if let Some(value) = value {
+            if let Ok(cookie) = crate::ui::preference_cookie(name, &value).to_string().parse() {
+                response.headers_mut().append(header::SET_COOKIE, cookie);
+            }
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let mut preferences = UiPreferences {
+            theme: Some("dark".to_string()),
+            palette: Some("color-blind".to_string()),
+            page_size: Some(50),
+            timezone: Some("America/Argentina/Buenos_Aires".to_string()),
+            language: Some("de".to_string()),
+            default_view: None,
+        };
+        assert!(validate(&preferences).is_ok());
+        assert!(validate(&UiPreferences::default()).is_ok());
+        preferences.page_size = Some(7);
+        assert!(validate(&preferences).is_err());
+        preferences.page_size = None;
+        preferences.theme = Some("neon".to_string());
+        assert!(validate(&preferences).is_err());
+        preferences.theme = None;
+        preferences.language = Some("fr".to_string());
+        assert!(validate(&preferences).is_err());
+
+        assert!(valid_timezone("UTC"));
+        assert!(valid_timezone("Etc/GMT+5"));
+        assert!(!valid_timezone("Europe/"));
+        assert!(!valid_timezone("<script>"));
+        assert!(serde_json::from_str::<UiPreferences>(r#"{"colour": "red"}"#).is_err());
+    }
+}
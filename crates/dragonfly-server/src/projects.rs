@@ -0,0 +1,81 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::db;
+
+/// A machine belongs to a project through a tag such as `project:acme`. Machines without one
+/// only see global resources.
+pub const TAG_PREFIX: &str = "project:";
+
+/// Artifacts under `projects/<name>/` are only served to machines in that project
+const ARTIFACT_PREFIX: &str = "projects/";
+
+/// Restricts an OS template to one project's machines. Templates without a scope are shared by everyone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateScope {
+    pub template: String,
+    pub project: String,
+}
+
+pub fn validate_name(project: &str) -> Result<(), String> {
+    if project.is_empty() || project.len() > 64 || !project.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_')) {
+        return Err("Project names must be 1 to 64 letters, digits, '-' or '_'".to_string());
+    }
+    Ok(())
+}
+
+/// The project named by a machine's tags, if any
+pub fn project_of(tags: &[String]) -> Option<&str> {
+    tags.iter().find_map(|tag| tag.strip_prefix(TAG_PREFIX)).filter(|p| !p.is_empty())
+}
+
+pub async fn machine_project(machine_id: &Uuid) -> Result<Option<String>> {
+    let tags = db::get_machine_tags(machine_id).await?;
+    Ok(project_of(&tags).map(str::to_string))
+}
+
+/// The project that owns an artifact path, if it isn't a global artifact
+pub fn artifact_project(path: &str) -> Option<&str> {
+    path.strip_prefix(ARTIFACT_PREFIX)?.split('/').next().filter(|p| !p.is_empty())
+}
+
+/// Name under which a project's own version of an iPXE template is stored
+pub fn scoped_template_name(name: &str, project: &str) -> String {
+    format!("{}@{}", name, project)
+}
+
+/// Split a possibly project-scoped iPXE template name into the base name and project
+pub fn split_template_name(name: &str) -> (&str, Option<&str>) {
+    match name.split_once('@') {
+        Some((base, project)) => (base, Some(project)),
+        None => (name, None),
+    }
+}
+
+/// Whether a machine may be given an OS template. The error explains why not.
+pub async fn check_template(machine_id: &Uuid, template: &str) -> Result<Result<(), String>> {
+    let Some(owner) = db::get_template_scope(template).await? else {
+        return Ok(Ok(()));
+    };
+    match machine_project(machine_id).await? {
+        Some(project) if project == owner => Ok(Ok(())),
+        _ => Ok(Err(format!("OS template '{}' belongs to project '{}' and can't be used by this machine", template, owner))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_project_lookup() {
+        let tags = vec!["gpu".to_string(), "project:acme".to_string()];
+        assert_eq!(project_of(&tags), Some("acme"));
+        assert_eq!(project_of(&["project:".to_string()]), None);
+        assert_eq!(artifact_project("projects/acme/ubuntu/vmlinuz"), Some("acme"));
+        assert_eq!(artifact_project("ubuntu/vmlinuz"), None);
+        assert_eq!(split_template_name("machine@acme"), ("machine", Some("acme")));
+        assert_eq!(split_template_name("discovery"), ("discovery", None));
+    }
+}
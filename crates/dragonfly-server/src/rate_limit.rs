@@ -0,0 +1,488 @@
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+use futures::future::BoxFuture;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{Layer, Service};
+use tracing::warn;
+
+/// Failures allowed before the first lockout. Many users can share one address behind NAT,
+/// so addresses get more room than single accounts.
+const FREE_ACCOUNT_FAILURES: u32 = 5;
+const FREE_ADDRESS_FAILURES: u32 = 20;
+const BASE_LOCKOUT: Duration = Duration::from_secs(30);
+const MAX_LOCKOUT: Duration = Duration::from_secs(3600);
+/// Failures are forgotten after this long without another one
+const FORGET_AFTER: Duration = Duration::from_secs(3600);
+/// Accounts and addresses tracked at once. Account names come from whoever is failing to log
+/// in, so they could otherwise grow the map without end.
+const MAX_TRACKED: usize = 10_000;
+/// Login forms are tiny; anything bigger isn't worth buffering to find the account name
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Lockout doubles with every failure past the free ones
+fn lockout_for(failures: u32, free: u32) -> Option<Duration> {
+    let over = failures.checked_sub(free)?;
+    Some(BASE_LOCKOUT.saturating_mul(2u32.saturating_pow(over)).min(MAX_LOCKOUT))
+}
+
+#[derive(Debug, Default)]
+struct Limiter {
+    attempts: Mutex<HashMap<String, Attempts>>,
+}
+
+impl Limiter {
+    fn locked_for(&self, key: &str, now: Instant) -> Option<Duration> {
+        let attempts = self.attempts.lock().unwrap();
+        attempts.get(key)?.locked_until.and_then(|until| until.checked_duration_since(now))
+    }
+
+    /// Count a failure, returning the lockout it triggers, if any
+    fn record_failure(&self, key: &str, free: u32, now: Instant) -> Option<Duration> {
+        let mut attempts = self.attempts.lock().unwrap();
+        if attempts.len() >= MAX_TRACKED && !attempts.contains_key(key) {
+            attempts.retain(|_, a| now.duration_since(a.last_failure) < FORGET_AFTER);
+            // Still full: whoever failed longest ago makes room
+            if attempts.len() >= MAX_TRACKED {
+                let stalest = attempts.iter().min_by_key(|(_, a)| a.last_failure).map(|(k, _)| k.clone());
+                if let Some(stalest) = stalest {
+                    attempts.remove(&stalest);
+                }
+            }
+        }
+        let entry = attempts.entry(key.to_string()).or_insert(Attempts { failures: 0, last_failure: now, locked_until: None });
+        if now.duration_since(entry.last_failure) >= FORGET_AFTER {
+            entry.failures = 0;
+        }
+        entry.failures += 1;
+        entry.last_failure = now;
+        let lockout = lockout_for(entry.failures, free);
+        entry.locked_until = lockout.map(|lockout| now + lockout);
+        lockout
+    }
+
+    fn record_success(&self, key: &str) {
+        self.attempts.lock().unwrap().remove(key);
+    }
+}
+
+/// How the layer finds the account a request is trying to get into
+#[derive(Debug, Clone, Copy)]
+pub enum AccountFrom {
+    /// A field of a form-encoded body, e.g. `username` on the login form
+    FormField(&'static str),
+    /// A path segment, e.g. the machine ID in `/machines/{id}/agent/enroll`
+    PathSegment(usize),
+    /// Only limit by address
+    None,
+}
+
+/// Tower layer limiting failed attempts per client address and per account, with exponentially
+/// growing lockouts. A response counts as failed when it is a 401 or 403, or redirects back with
+/// an `error=` query. Failures and lockouts are published as `login_failed` and `login_locked` events.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<Limiter>,
+    account_from: AccountFrom,
+    /// Browsers are sent here while locked out; API clients get a 429
+    locked_redirect: Option<&'static str>,
+}
+
+impl RateLimitLayer {
+    pub fn new(account_from: AccountFrom) -> Self {
+        Self { limiter: Arc::new(Limiter::default()), account_from, locked_redirect: None }
+    }
+
+    pub fn redirect_when_locked(mut self, location: &'static str) -> Self {
+        self.locked_redirect = Some(location);
+        self
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit { inner, layer: self.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    layer: RateLimitLayer,
+}
+
+fn failed(response: &Response) -> bool {
+    if matches!(response.status(), StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+        return true;
+    }
+    response.status().is_redirection()
+        && response.headers().get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|location| location.contains("error="))
+}
+
+fn publish(event: &str, subject: &str) {
+    if let Ok(event_manager) = crate::EVENT_MANAGER_REF.read() {
+        if let Some(event_manager) = event_manager.as_ref() {
+            event_manager.send(format!("{}:{}", event, subject));
+        }
+    }
+}
+
+impl RateLimitLayer {
+    fn locked_response(&self, retry_after: Duration) -> Response {
+        let secs = retry_after.as_secs().max(1);
+        let mut response = match self.locked_redirect {
+            Some(location) => Redirect::to(location).into_response(),
+            None => (StatusCode::TOO_MANY_REQUESTS, Json(json!({
+                "error": "Too Many Requests",
+                "message": format!("Too many failed attempts; try again in {} seconds", secs),
+            }))).into_response(),
+        };
+        response.headers_mut().insert(header::RETRY_AFTER, secs.into());
+        response
+    }
+}
+
+impl<S> Service<Request<Body>> for RateLimit<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // Take the instance that was polled ready and leave a fresh clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+
+        Box::pin(async move {
+            if request.method() != Method::POST {
+                return inner.call(request).await;
+            }
+
+            let address = crate::access::request_client_ip(&request).map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+            let (parts, body) = request.into_parts();
+            let body = match axum::body::to_bytes(body, MAX_BODY_BYTES).await {
+                Ok(body) => body,
+                Err(_) => return Ok((StatusCode::PAYLOAD_TOO_LARGE, "Request body too large").into_response()),
+            };
+            let account = match layer.account_from {
+                AccountFrom::FormField(field) => url::form_urlencoded::parse(&body)
+                    .find(|(key, _)| key == field)
+                    .map(|(_, value)| value.into_owned()),
+                AccountFrom::PathSegment(index) => parts.uri.path().split('/').filter(|s| !s.is_empty()).nth(index).map(str::to_string),
+                AccountFrom::None => None,
+            };
+            let address_key = format!("address:{}", address);
+            let account_key = account.as_deref().map(account_key);
+
+            let now = Instant::now();
+            let locked = [Some(&address_key), account_key.as_ref()].into_iter().flatten()
+                .filter_map(|key| layer.limiter.locked_for(key, now))
+                .max();
+            if let Some(retry_after) = locked {
+                warn!("Refused attempt on {} from {} for {:?}: locked out", parts.uri.path(), address, account);
+                return Ok(layer.locked_response(retry_after));
+            }
+
+            let response = inner.call(Request::from_parts(parts, Body::from(body))).await?;
+
+            let subject = match &account {
+                Some(account) => format!("{}@{}", account, address),
+                None => address.clone(),
+            };
+            if failed(&response) {
+                warn!("Failed attempt from {} for {:?}", address, account);
+                publish("login_failed", &subject);
+                let address_lockout = layer.limiter.record_failure(&address_key, FREE_ADDRESS_FAILURES, now);
+                let account_lockout = account_key.as_ref()
+                    .and_then(|key| layer.limiter.record_failure(key, FREE_ACCOUNT_FAILURES, now));
+                if let Some(lockout) = address_lockout.max(account_lockout) {
+                    warn!("Locking out {} for {} seconds after repeated failures", subject, lockout.as_secs());
+                    publish("login_locked", &subject);
+                }
+            } else if let Some(key) = &account_key {
+                layer.limiter.record_success(key);
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// Requests per minute each client address may make to `/api`; 0 turns the limit off
+pub const API_RATE_LIMIT_ENV_VAR: &str = "DRAGONFLY_API_RATE_LIMIT";
+/// Requests per minute each credential (Authorization header or session) may make to `/api`
+pub const API_TOKEN_RATE_LIMIT_ENV_VAR: &str = "DRAGONFLY_API_TOKEN_RATE_LIMIT";
+pub const DEFAULT_API_RATE_LIMIT: u32 = 600;
+pub const DEFAULT_API_TOKEN_RATE_LIMIT: u32 = 1200;
+/// tower-sessions' cookie
+const SESSION_COOKIE: &str = "id";
+/// Buckets idle this long are full again, so they can be forgotten
+const BUCKET_IDLE: Duration = Duration::from_secs(60);
+const MAX_BUCKETS: usize = 10_000;
+
+static LIMITED_BY_ADDRESS: AtomicU64 = AtomicU64::new(0);
+static LIMITED_BY_TOKEN: AtomicU64 = AtomicU64::new(0);
+
+/// Requests refused with a 429, by the limit they hit (`address` or `token`)
+pub fn rate_limited_totals() -> [(&'static str, u64); 2] {
+    [("address", LIMITED_BY_ADDRESS.load(Ordering::Relaxed)), ("token", LIMITED_BY_TOKEN.load(Ordering::Relaxed))]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ApiQuota {
+    /// Requests per minute; None when unlimited
+    pub per_address: Option<u32>,
+    pub per_token: Option<u32>,
+}
+
+fn parse_limit(var: &str, default: u32) -> Result<Option<u32>, String> {
+    let limit = match env::var(var) {
+        Ok(value) if !value.trim().is_empty() => value.trim().parse::<u32>()
+            .map_err(|_| format!("{} must be a number of requests per minute, got '{}'", var, value))?,
+        _ => default,
+    };
+    Ok((limit > 0).then_some(limit))
+}
+
+impl ApiQuota {
+    pub fn from_env() -> Result<Self, String> {
+        Ok(ApiQuota {
+            per_address: parse_limit(API_RATE_LIMIT_ENV_VAR, DEFAULT_API_RATE_LIMIT)?,
+            per_token: parse_limit(API_TOKEN_RATE_LIMIT_ENV_VAR, DEFAULT_API_TOKEN_RATE_LIMIT)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets holding a minute's worth of requests and refilling steadily, so short bursts are fine
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Buckets {
+    /// Spend one request from the key's bucket, or say how long until one is available
+    fn take(&self, key: &str, per_minute: u32, now: Instant) -> Result<(), Duration> {
+        let capacity = per_minute as f64;
+        let per_second = capacity / 60.0;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            buckets.retain(|_, b| now.duration_since(b.updated) < BUCKET_IDLE);
+            // Still full of active clients: the one heard from longest ago makes room
+            if buckets.len() >= MAX_BUCKETS {
+                let stalest = buckets.iter().min_by_key(|(_, b)| b.updated).map(|(k, _)| k.clone());
+                if let Some(stalest) = stalest {
+                    buckets.remove(&stalest);
+                }
+            }
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket { tokens: capacity, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_second).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+// Hashed, so a name of any length costs the limiter the same
+fn account_key(account: &str) -> String {
+    format!("account:{}", hex::encode(Sha256::digest(account.to_ascii_lowercase().as_bytes())))
+}
+
+// The caller's credential, hashed so the limiter never holds a usable secret
+fn credential_key(request: &Request<Body>) -> Option<String> {
+    let credential = request.headers().get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| request.headers().get_all(header::COOKIE).iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|cookies| cookies.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(name, _)| *name == SESSION_COOKIE)
+            .map(|(_, value)| value.to_string()))?;
+    Some(format!("token:{}", hex::encode(Sha256::digest(credential.as_bytes()))))
+}
+
+/// Tower layer applying per-address and per-credential request quotas to the API, so one busy
+/// script can't crowd out agents and booting machines. Refused requests get a 429 with `Retry-After`.
+#[derive(Clone)]
+pub struct ApiQuotaLayer {
+    buckets: Arc<Buckets>,
+    quota: ApiQuota,
+}
+
+impl ApiQuotaLayer {
+    pub fn new(quota: ApiQuota) -> Self {
+        Self { buckets: Arc::new(Buckets::default()), quota }
+    }
+}
+
+impl<S> Layer<S> for ApiQuotaLayer {
+    type Service = ApiQuotaService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ApiQuotaService { inner, layer: self.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct ApiQuotaService<S> {
+    inner: S,
+    layer: ApiQuotaLayer,
+}
+
+fn too_many_requests(retry_after: Duration) -> Response {
+    let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    let mut response = (StatusCode::TOO_MANY_REQUESTS, Json(json!({
+        "error": "Too Many Requests",
+        "message": format!("API rate limit exceeded; try again in {} seconds", secs),
+    }))).into_response();
+    response.headers_mut().insert(header::RETRY_AFTER, secs.into());
+    response
+}
+
+impl<S> Service<Request<Body>> for ApiQuotaService<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+
+        Box::pin(async move {
+            let now = Instant::now();
+            let address = crate::access::request_client_ip(&request).map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+            if let Some(per_minute) = layer.quota.per_address {
+                if let Err(retry_after) = layer.buckets.take(&format!("address:{}", address), per_minute, now) {
+                    LIMITED_BY_ADDRESS.fetch_add(1, Ordering::Relaxed);
+                    warn!("Rate limited {} {} from {}", request.method(), request.uri().path(), address);
+                    return Ok(too_many_requests(retry_after));
+                }
+            }
+            if let (Some(per_minute), Some(key)) = (layer.quota.per_token, credential_key(&request)) {
+                if let Err(retry_after) = layer.buckets.take(&key, per_minute, now) {
+                    LIMITED_BY_TOKEN.fetch_add(1, Ordering::Relaxed);
+                    warn!("Rate limited {} {} from {}: credential over its quota", request.method(), request.uri().path(), address);
+                    return Ok(too_many_requests(retry_after));
+                }
+            }
+            inner.call(request).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockout_grows_exponentially() {
+        assert_eq!(lockout_for(4, 5), None);
+        assert_eq!(lockout_for(5, 5), Some(Duration::from_secs(30)));
+        assert_eq!(lockout_for(7, 5), Some(Duration::from_secs(120)));
+        assert_eq!(lockout_for(40, 5), Some(MAX_LOCKOUT));
+    }
+
+    #[test]
+    fn test_success_clears_failures() {
+        let limiter = Limiter::default();
+        let now = Instant::now();
+        for _ in 0..FREE_ACCOUNT_FAILURES - 1 {
+            assert_eq!(limiter.record_failure("account:alice", FREE_ACCOUNT_FAILURES, now), None);
+        }
+        assert!(limiter.record_failure("account:alice", FREE_ACCOUNT_FAILURES, now).is_some());
+        assert!(limiter.locked_for("account:alice", now).is_some());
+        limiter.record_success("account:alice");
+        assert!(limiter.locked_for("account:alice", now).is_none());
+    }
+
+    #[test]
+    fn test_quota_refills_over_time() {
+        let buckets = Buckets::default();
+        let now = Instant::now();
+        for _ in 0..60 {
+            assert!(buckets.take("address:10.0.0.1", 60, now).is_ok());
+        }
+        assert_eq!(buckets.take("address:10.0.0.1", 60, now), Err(Duration::from_secs(1)));
+        assert!(buckets.take("address:10.0.0.2", 60, now).is_ok());
+        assert!(buckets.take("address:10.0.0.1", 60, now + Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn test_bucket_map_is_bounded() {
+        let buckets = Buckets::default();
+        let start = Instant::now();
+        for i in 0..MAX_BUCKETS {
+            let _ = buckets.take(&format!("token:{}", i), 60, start + Duration::from_millis(i as u64));
+        }
+        let now = start + Duration::from_secs(30);
+        assert!(buckets.take("token:new", 60, now).is_ok());
+        let held = buckets.buckets.lock().unwrap();
+        assert_eq!(held.len(), MAX_BUCKETS);
+        assert!(!held.contains_key("token:0"));
+        assert!(held.contains_key("token:new"));
+    }
+
+    #[test]
+    fn test_failure_map_is_bounded() {
+        let limiter = Limiter::default();
+        let start = Instant::now();
+        for i in 0..MAX_TRACKED {
+            limiter.record_failure(&account_key(&format!("user{}", i)), FREE_ACCOUNT_FAILURES, start + Duration::from_millis(i as u64));
+        }
+        let now = start + Duration::from_secs(30);
+        limiter.record_failure(&account_key(&"x".repeat(MAX_BODY_BYTES)), FREE_ACCOUNT_FAILURES, now);
+        let held = limiter.attempts.lock().unwrap();
+        assert_eq!(held.len(), MAX_TRACKED);
+        assert!(!held.contains_key(&account_key("user0")));
+        assert!(held.keys().all(|key| key.len() == account_key("").len()));
+        assert_eq!(account_key("Alice"), account_key("alice"));
+    }
+}
@@ -0,0 +1,465 @@
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Timelike, Utc};
+use dragonfly_common::models::MachineStatus;
+use dragonfly_common::state_machine::StatusCause;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::db;
+use crate::event_manager::EventManager;
+use crate::machine_profiles::MatchRules;
+use crate::rollouts::MemberState;
+
+/// Schedules are due to the minute, so this checks twice a minute
+const SCHEDULE_TICK: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How far ahead a schedule's next run is looked for, so `0 0 29 2 *` still finds a leap year
+const SEARCH_DAYS: i64 = 366 * 8;
+
+/// Held while a run is started, so the task and "run now" can't start the same schedule twice
+pub(crate) static RUNS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// A five-field cron expression (minute, hour, day of month, month, day of week), in UTC
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether day of month or day of week was `*`. When both are restricted, cron runs on
+    /// days matching either.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Parse one field into a bit set of the values it allows, e.g. `*/15`, `1-5` or `0,30`
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| format!("Invalid step in '{}'", part))?;
+                if step == 0 {
+                    return Err(format!("Invalid step in '{}'", part));
+                }
+                (range, Some(step))
+            },
+            None => (part, None),
+        };
+        let value = |s: &str| -> Result<u32, String> {
+            let value: u32 = s.parse().map_err(|_| format!("Invalid value '{}'", s))?;
+            if value < min || value > max {
+                return Err(format!("'{}' is outside {}-{}", s, min, max));
+            }
+            Ok(value)
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (value(from)?, value(to)?),
+                // `5/10` means every 10 starting at 5
+                None if step.is_some() => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if from > to {
+            return Err(format!("Invalid range '{}'", range));
+        }
+        for value in (from..=to).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Cron, String> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expression => expression,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err("A cron expression has five fields: minute hour day-of-month month day-of-week".to_string());
+        };
+        let mut weekdays = parse_field(weekday, 0, 7)?;
+        // 7 is Sunday too
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        let cron = Cron {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        };
+        if cron.next_after(Utc::now()).is_none() {
+            return Err(format!("'{}' never runs", expression));
+        }
+        Ok(cron)
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first time the expression matches strictly after `after`
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let first_day = start.date_naive();
+        for offset in 0..SEARCH_DAYS {
+            let date = first_day + Duration::days(offset);
+            if !self.matches_date(date) {
+                continue;
+            }
+            let first_hour = if offset == 0 { start.hour() } else { 0 };
+            for hour in (first_hour..24).filter(|hour| self.hours & (1 << hour) != 0) {
+                let first_minute = if offset == 0 && hour == start.hour() { start.minute() } else { 0 };
+                if let Some(minute) = (first_minute..60).find(|minute| self.minutes & (1 << minute) != 0) {
+                    return Some(date.and_hms_opt(hour, minute, 0)?.and_utc());
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Reimage the machines matching a label selector on a cron schedule, e.g. a CI lab every night
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleSpec {
+    pub name: String,
+    /// e.g. `0 2 * * *` for 02:00 UTC every night
+    pub cron: String,
+    /// Which machines to reimage, with the same rules as machine profiles
+    #[serde(default)]
+    pub machines: MatchRules,
+    /// Reimage the whole fleet. Required when `machines` has no rules, so a forgotten selector
+    /// can't wipe every machine.
+    #[serde(default)]
+    pub all: bool,
+    /// The OS to install. Each machine gets its current OS again when this isn't set.
+    #[serde(default)]
+    pub os_template: Option<String>,
+    /// PXE boot and power-cycle machines that have BMC credentials
+    #[serde(default)]
+    pub power_cycle: bool,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// A reimage that hasn't become Ready in this long counts as failed
+    #[serde(default = "default_install_timeout_minutes")]
+    pub install_timeout_minutes: u32,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_install_timeout_minutes() -> u32 {
+    120
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReimageSchedule {
+    pub id: i64,
+    #[serde(flatten)]
+    pub spec: ScheduleSpec,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub last_run_at: Option<DateTime<Utc>>,
+    /// None while the schedule is disabled
+    pub next_run_at: Option<DateTime<Utc>>,
+}
+
+/// A machine a run reimaged, and how that went
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunMachine {
+    pub machine_id: Uuid,
+    pub name: String,
+    #[serde(flatten)]
+    pub state: MemberState,
+    /// e.g. why the machine couldn't be power-cycled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+/// A matching machine a run left alone
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SkippedMachine {
+    pub machine_id: Uuid,
+    pub name: String,
+    pub reason: String,
+}
+
+/// The report of one run of a schedule
+#[derive(Debug, Clone, Serialize)]
+pub struct ReimageRun {
+    pub id: i64,
+    pub schedule_id: i64,
+    pub schedule_name: String,
+    /// "schedule", or who started the run by hand
+    pub requested_by: String,
+    pub started_at: DateTime<Utc>,
+    /// Set once every machine has succeeded or failed
+    pub finished_at: Option<DateTime<Utc>>,
+    pub machines: Vec<RunMachine>,
+    pub skipped: Vec<SkippedMachine>,
+    /// Why the run reimaged nothing at all, e.g. a provisioning freeze
+    pub note: Option<String>,
+}
+
+impl ReimageRun {
+    fn new(schedule: &ReimageSchedule, requested_by: &str) -> Self {
+        ReimageRun {
+            id: 0,
+            schedule_id: schedule.id,
+            schedule_name: schedule.spec.name.clone(),
+            requested_by: requested_by.to_string(),
+            started_at: Utc::now(),
+            finished_at: None,
+            machines: Vec::new(),
+            skipped: Vec::new(),
+            note: None,
+        }
+    }
+
+    fn finish_if_done(&mut self) {
+        if self.finished_at.is_none() && self.machines.iter().all(|machine| machine.state.is_finished()) {
+            self.finished_at = Some(Utc::now());
+        }
+    }
+}
+
+pub fn validate(spec: &ScheduleSpec) -> Result<Cron, String> {
+    if spec.name.trim().is_empty() {
+        return Err("A schedule needs a name".to_string());
+    }
+    if spec.os_template.as_deref().is_some_and(|template| template.trim().is_empty()) {
+        return Err("Leave the OS template out to reinstall each machine's current OS".to_string());
+    }
+    if spec.install_timeout_minutes == 0 {
+        return Err("Reimages need at least a minute to finish".to_string());
+    }
+    if spec.machines == MatchRules::default() && !spec.all {
+        return Err("Give the schedule machine rules, or set all to reimage every machine".to_string());
+    }
+    Cron::parse(&spec.cron)
+}
+
+/// When an enabled schedule next runs after `after`
+pub fn next_run(spec: &ScheduleSpec, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if !spec.enabled {
+        return None;
+    }
+    Cron::parse(&spec.cron).ok()?.next_after(after)
+}
+
+/// Reimage every machine the schedule matches. Machines that are installing, waiting to, in a
+/// rollout or behind the approval gate are skipped, so a run never overlaps the last one.
+pub async fn start_run(schedule: &ReimageSchedule, requested_by: &str, event_manager: &EventManager) -> Result<ReimageRun> {
+    let _runs = RUNS.lock().await;
+    let mut run = ReimageRun::new(schedule, requested_by);
+    let busy = crate::rollouts::busy_machines().await?;
+    let waiting = crate::rollouts::waiting_installs().await?;
+    let mut machines = Vec::new();
+    for machine in db::get_all_machines().await? {
+        let facts = crate::machine_profiles::facts_for(&machine).await?;
+        // Schedules saved before `all` existed may have no rules; they match nothing
+        let selected = schedule.spec.all || schedule.spec.machines != MatchRules::default();
+        if selected && schedule.spec.machines.matches(&facts) {
+            machines.push(machine);
+        }
+    }
+    machines.sort_by_key(|machine| machine.hostname.clone().or_else(|| machine.memorable_name.clone()));
+
+    let who = if requested_by == "schedule" { String::new() } else { format!(", run by {}", requested_by) };
+    let cause = StatusCause::Automatic(format!("Reimage schedule {} ({}){}", schedule.id, schedule.spec.name, who));
+    for machine in machines {
+        let name = machine.hostname.clone()
+            .or_else(|| machine.memorable_name.clone())
+            .unwrap_or_else(|| machine.id.to_string());
+        let os_choice = schedule.spec.os_template.clone().or_else(|| machine.os_choice.clone());
+        let skip = if machine.status.is_approval_gated() {
+            Some(format!("It is {}", machine.status))
+        } else if machine.status == MachineStatus::InstallingOS {
+            Some("It is already installing".to_string())
+        } else if waiting.contains(&machine.id) {
+            Some("It already has a held or queued install".to_string())
+        } else if busy.contains(&machine.id) {
+            Some("It is in an active rollout".to_string())
+        } else if os_choice.is_none() {
+            Some("It has no OS to reinstall".to_string())
+        } else {
+            None
+        };
+        if let Some(reason) = skip {
+            run.skipped.push(SkippedMachine { machine_id: machine.id, name, reason });
+            continue;
+        }
+        let os_choice = os_choice.unwrap_or_default();
+
+        let bmc_requested_by = format!("reimage schedule {}", schedule.spec.name);
+        let power_cycle = schedule.spec.power_cycle && machine.bmc_credentials.is_some();
+        let (state, note) = match crate::api::reimage_internal(&machine, os_choice, power_cycle, cause.clone(), &bmc_requested_by).await {
+            Ok(reimaged) if reimaged.status == "waiting" => (MemberState::Waiting, reimaged.note),
+            Ok(reimaged) => (MemberState::Installing { since: Utc::now() }, reimaged.note),
+            Err(response) => (MemberState::Failed { reason: format!("The reimage couldn't be started ({})", response.status()) }, None),
+        };
+        if let MemberState::Failed { reason } = &state {
+            warn!("Reimage schedule {} ({}): machine {} failed: {}", schedule.id, schedule.spec.name, name, reason);
+        }
+        run.machines.push(RunMachine { machine_id: machine.id, name, state, note });
+        let _ = event_manager.send(format!("machine_updated:{}", machine.id));
+    }
+    run.finish_if_done();
+    info!("Reimage schedule {} ({}) started {} reimages, skipping {} machines",
+        schedule.id, schedule.spec.name, run.machines.len(), run.skipped.len());
+    db::add_reimage_run(&run).await
+}
+
+/// Follow the reimages of unfinished runs until they succeed or fail
+async fn check_runs() -> Result<()> {
+    let runs = db::get_unfinished_reimage_runs().await?;
+    if runs.is_empty() {
+        return Ok(());
+    }
+    let waiting = crate::rollouts::waiting_installs().await?;
+    for mut run in runs {
+        let timeout = match db::get_reimage_schedule(run.schedule_id).await? {
+            Some(schedule) => Duration::minutes(schedule.spec.install_timeout_minutes as i64),
+            None => continue,
+        };
+        let mut changed = false;
+        for machine in run.machines.iter_mut().filter(|machine| !machine.state.is_finished()) {
+            let state = crate::rollouts::check(&machine.machine_id, &machine.state, timeout, &waiting).await?;
+            if state != machine.state {
+                machine.state = state;
+                changed = true;
+            }
+        }
+        run.finish_if_done();
+        if run.finished_at.is_some() {
+            let failed = run.machines.iter().filter(|machine| matches!(machine.state, MemberState::Failed { .. })).count();
+            info!("Reimage run {} of schedule '{}' finished: {} succeeded, {} failed",
+                run.id, run.schedule_name, run.machines.len() - failed, failed);
+        }
+        if changed || run.finished_at.is_some() {
+            db::save_reimage_run(&run).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Start the runs that are due. During a provisioning freeze a due run is recorded but
+/// reimages nothing, rather than catching up once the freeze ends.
+async fn run_due(event_manager: &EventManager) -> Result<()> {
+    let now = Utc::now();
+    for mut schedule in db::get_reimage_schedules().await? {
+        if schedule.next_run_at.filter(|next| *next <= now).is_none() {
+            continue;
+        }
+        // Move the schedule on first, so a run that fails to start isn't retried every tick
+        schedule.last_run_at = Some(now);
+        schedule.next_run_at = next_run(&schedule.spec, now);
+        db::save_reimage_schedule(&schedule).await?;
+        if let Some(freeze) = crate::freeze::current() {
+            let mut run = ReimageRun::new(&schedule, "schedule");
+            run.note = Some(crate::freeze::refusal(&freeze));
+            run.finished_at = Some(now);
+            db::add_reimage_run(&run).await?;
+            warn!("Skipped reimage schedule {} ({}): provisioning is frozen", schedule.id, schedule.spec.name);
+            continue;
+        }
+        if let Err(e) = start_run(&schedule, "schedule", event_manager).await {
+            error!("Failed to run reimage schedule {} ({}): {}", schedule.id, schedule.spec.name, e);
+        }
+    }
+    Ok(())
+}
+
+/// Run reimage schedules when they are due and follow their runs
+pub async fn start_schedule_task(
+    event_manager: Arc<EventManager>,
+    mut shutdown_rx: tokio::sync::watch::Receiver<()>,
+) {
+    tokio::spawn(async move {
+        info!("Starting reimage schedule task");
+        let mut ticker = tokio::time::interval(SCHEDULE_TICK);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    if let Err(e) = run_due(&event_manager).await {
+                        error!("Failed to run reimage schedules: {}", e);
+                    }
+                    if let Err(e) = check_runs().await {
+                        error!("Failed to check reimage runs: {}", e);
+                    }
+                },
+                _ = shutdown_rx.changed() => {
+                    info!("Shutdown signal received, stopping reimage schedule task.");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    #[test]
+    fn test_cron_next_after() {
+        let nightly = Cron::parse("0 2 * * *").unwrap();
+        assert_eq!(nightly.next_after(at(2025, 6, 10, 1, 59)), Some(at(2025, 6, 10, 2, 0)));
+        assert_eq!(nightly.next_after(at(2025, 6, 10, 2, 0)), Some(at(2025, 6, 11, 2, 0)));
+
+        // Weekdays only; 2025-06-13 is a Friday
+        let weekdays = Cron::parse("*/30 22 * * 1-5").unwrap();
+        assert_eq!(weekdays.next_after(at(2025, 6, 13, 22, 30)), Some(at(2025, 6, 16, 22, 0)));
+
+        // Either day field matches when both are set
+        let either = Cron::parse("0 0 1 * 7").unwrap();
+        assert_eq!(either.next_after(at(2025, 6, 10, 0, 0)), Some(at(2025, 6, 15, 0, 0)));
+
+        assert_eq!(Cron::parse("@daily").unwrap(), Cron::parse("0 0 * * *").unwrap());
+        assert_eq!(Cron::parse("0 0 29 2 *").unwrap().next_after(at(2025, 3, 1, 0, 0)), Some(at(2028, 2, 29, 0, 0)));
+        assert!(Cron::parse("0 0 30 2 *").is_err());
+        assert!(Cron::parse("60 * * * *").is_err());
+        assert!(Cron::parse("0 2 * *").is_err());
+        assert!(Cron::parse("*/0 * * * *").is_err());
+    }
+
+    #[test]
+    fn test_validate_requires_a_selection() {
+        let spec: ScheduleSpec = serde_json::from_value(serde_json::json!({"name": "nightly", "cron": "0 2 * * *"})).unwrap();
+        assert!(validate(&spec).is_err());
+        assert!(validate(&ScheduleSpec { all: true, ..spec.clone() }).is_ok());
+
+        let mut ci = spec;
+        ci.machines.labels.insert("role".to_string(), "ci".to_string());
+        assert!(validate(&ci).is_ok());
+    }
+}
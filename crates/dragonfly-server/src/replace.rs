@@ -0,0 +1,235 @@
+use chrono::{DateTime, Utc};
+use dragonfly_common::models::Machine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::custom_fields::{self, FieldDefinition};
+use crate::db;
+use crate::template_vars::{self, TemplateVariable, VariableError, MASK};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplaceRequest {
+    pub find: String,
+    pub replace: String,
+}
+
+/// Where a value was found
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Target {
+    TemplateVariable { name: String, secret: bool },
+    IpxeTemplate { name: String },
+    MachineField { machine_id: Uuid, machine: String, field: String },
+}
+
+/// One changed line of a value, numbered from 1. Secrets are shown masked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LineChange {
+    pub line: usize,
+    pub before: String,
+    pub after: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Change {
+    pub target: Target,
+    pub occurrences: usize,
+    pub lines: Vec<LineChange>,
+}
+
+/// A match that can't be replaced, with the reason
+#[derive(Debug, Clone, Serialize)]
+pub struct Skipped {
+    pub target: Target,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplaceReport {
+    pub dry_run: bool,
+    pub changes: Vec<Change>,
+    pub skipped: Vec<Skipped>,
+    /// The audit entry recording the change, once applied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audit_id: Option<i64>,
+}
+
+/// A completed search and replace, as kept in the audit log
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplaceAudit {
+    pub id: i64,
+    pub actor: String,
+    /// Masked when the search touched a secret
+    pub find: String,
+    pub replace: String,
+    pub changes: Vec<Change>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A value to write, once the whole set has been worked out
+#[derive(Debug, Clone)]
+pub enum Edit {
+    /// Already sealed when the variable is secret
+    TemplateVariable { name: String, value: String, secret: bool },
+    IpxeTemplate { name: String, content: String },
+    MachineField { machine_id: Uuid, field: String, value: Value },
+}
+
+fn line_changes(before: &str, after: &str, secret: bool) -> Vec<LineChange> {
+    before.lines().zip(after.lines()).enumerate()
+        .filter(|(_, (b, a))| b != a)
+        .map(|(i, (b, a))| LineChange {
+            line: i + 1,
+            before: if secret { MASK.to_string() } else { b.to_string() },
+            after: if secret { MASK.to_string() } else { a.to_string() },
+        })
+        .collect()
+}
+
+fn change(target: Target, before: &str, after: &str, find: &str) -> Change {
+    let secret = matches!(target, Target::TemplateVariable { secret: true, .. });
+    Change { lines: line_changes(before, after, secret), occurrences: before.matches(find).count(), target }
+}
+
+/// Work out every replacement. `variables` hold decrypted values; secrets that couldn't be
+/// decrypted are passed as None and reported as skipped.
+pub fn plan(
+    request: &ReplaceRequest,
+    variables: &[(TemplateVariable, Option<String>)],
+    ipxe_templates: &[(String, String)],
+    field_values: &HashMap<Uuid, HashMap<String, Value>>,
+    fields: &[FieldDefinition],
+    machines: &[Machine],
+) -> (Vec<(Change, Edit)>, Vec<Skipped>) {
+    let find = request.find.as_str();
+    let mut planned = Vec::new();
+    let mut skipped = Vec::new();
+
+    for (variable, plain) in variables {
+        let target = Target::TemplateVariable { name: variable.name.clone(), secret: variable.secret };
+        match plain {
+            Some(value) if value.contains(find) => {
+                let after = value.replace(find, &request.replace);
+                planned.push((change(target, value, &after, find), Edit::TemplateVariable { name: variable.name.clone(), value: after, secret: variable.secret }));
+            },
+            Some(_) => {},
+            None => skipped.push(Skipped { target, reason: "Secret can't be decrypted; set DRAGONFLY_SECRETS_KEY".to_string() }),
+        }
+    }
+
+    for (name, content) in ipxe_templates.iter().filter(|(_, content)| content.contains(find)) {
+        let after = content.replace(find, &request.replace);
+        let target = Target::IpxeTemplate { name: name.clone() };
+        planned.push((change(target, content, &after, find), Edit::IpxeTemplate { name: name.clone(), content: after }));
+    }
+
+    let mut machine_ids: Vec<&Uuid> = field_values.keys().collect();
+    machine_ids.sort();
+    for machine_id in machine_ids {
+        let mut names: Vec<&String> = field_values[machine_id].keys().collect();
+        names.sort();
+        for name in names {
+            let Value::String(value) = &field_values[machine_id][name] else { continue };
+            if !value.contains(find) {
+                continue;
+            }
+            let target = Target::MachineField {
+                machine_id: *machine_id,
+                machine: machines.iter().find(|m| &m.id == machine_id).map_or_else(|| machine_id.to_string(), Machine::display_name),
+                field: name.clone(),
+            };
+            let after = value.replace(find, &request.replace);
+            let normalized = match fields.iter().find(|f| &f.name == name) {
+                Some(field) => custom_fields::normalize_value(field, &Value::String(after.clone())),
+                None => Err(format!("Custom field '{}' no longer exists", name)),
+            };
+            match normalized {
+                Ok(normalized) => planned.push((change(target, value, &after, find), Edit::MachineField { machine_id: *machine_id, field: name.clone(), value: normalized })),
+                Err(reason) => skipped.push(Skipped { target, reason }),
+            }
+        }
+    }
+
+    (planned, skipped)
+}
+
+/// Preview a search and replace across template variables, stored iPXE templates and machine
+/// custom fields, or apply it in one transaction with an audit entry
+pub async fn run(request: &ReplaceRequest, dry_run: bool, actor: &str) -> Result<ReplaceReport, VariableError> {
+    if request.find.is_empty() {
+        return Err(VariableError::Invalid("Give a value to find".to_string()));
+    }
+    if request.find == request.replace {
+        return Err(VariableError::Invalid("The replacement is the same as the value to find".to_string()));
+    }
+
+    let (stored, ipxe_templates, field_values, fields, machines) = tokio::try_join!(
+        db::get_template_variables(),
+        db::get_ipxe_templates(),
+        db::get_all_field_values(),
+        db::get_custom_fields(),
+        db::get_all_machines(),
+    )?;
+    let key = template_vars::master_key().ok();
+    let variables: Vec<(TemplateVariable, Option<String>)> = stored.into_iter().map(|variable| {
+        let plain = match (variable.secret, &key) {
+            (false, _) => Some(variable.value.clone()),
+            (true, Some(key)) => template_vars::open(key, variable.name.as_bytes(), &variable.value).ok(),
+            (true, None) => None,
+        };
+        (variable, plain)
+    }).collect();
+
+    let (planned, skipped) = plan(request, &variables, &ipxe_templates, &field_values, &fields, &machines);
+    let changes: Vec<Change> = planned.iter().map(|(change, _)| change.clone()).collect();
+    if dry_run || planned.is_empty() {
+        return Ok(ReplaceReport { dry_run, changes, skipped, audit_id: None });
+    }
+
+    let mut edits = Vec::new();
+    for (_, edit) in planned {
+        edits.push(match edit {
+            Edit::TemplateVariable { name, value, secret: true } => {
+                let key = key.as_ref().ok_or(VariableError::Disabled)?;
+                let sealed = template_vars::seal(key, name.as_bytes(), &value)?;
+                Edit::TemplateVariable { name, value: sealed, secret: true }
+            },
+            edit => edit,
+        });
+    }
+    // The values searched for may themselves be secret, so they aren't kept when a secret matched
+    let touched_secret = changes.iter().any(|c| matches!(c.target, Target::TemplateVariable { secret: true, .. }));
+    let (find, replace) = match touched_secret {
+        true => (MASK, MASK),
+        false => (request.find.as_str(), request.replace.as_str()),
+    };
+    let audit_id = db::apply_replacements(&edits, actor, find, replace, &changes).await?;
+    info!("{} replaced a value in {} places (audit entry {})", actor, changes.len(), audit_id);
+    Ok(ReplaceReport { dry_run, changes, skipped, audit_id: Some(audit_id) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_masks_secrets_and_diffs_lines() {
+        let request = ReplaceRequest { find: "old.mirror".to_string(), replace: "new.mirror".to_string() };
+        let variables = vec![
+            (TemplateVariable { name: "mirror".to_string(), value: "http://old.mirror/ubuntu".to_string(), secret: false }, Some("http://old.mirror/ubuntu".to_string())),
+            (TemplateVariable { name: "token".to_string(), value: "sealed".to_string(), secret: true }, Some("key@old.mirror".to_string())),
+            (TemplateVariable { name: "vault".to_string(), value: "sealed".to_string(), secret: true }, None),
+        ];
+        let templates = vec![("machine".to_string(), "#!ipxe\nchain http://old.mirror/boot.ipxe".to_string())];
+        let (planned, skipped) = plan(&request, &variables, &templates, &HashMap::new(), &[], &[]);
+
+        assert_eq!(planned.len(), 3);
+        assert_eq!(planned[0].0.lines[0].after, "http://new.mirror/ubuntu");
+        assert_eq!(planned[1].0.lines[0].before, MASK);
+        assert_eq!(planned[2].0.lines[0].line, 2);
+        assert_eq!(skipped.len(), 1);
+    }
+}
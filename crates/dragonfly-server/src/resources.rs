@@ -0,0 +1,255 @@
+use chrono::{DateTime, Utc};
+use dragonfly_common::models::{Machine, MachineStatus};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::db;
+use crate::import::{self, ManifestEntry};
+
+lazy_static::lazy_static! {
+    // Serializes writes through this API, so an If-Match check and the write it guards can't
+    // interleave with another's
+    static ref WRITES: Mutex<()> = Mutex::new(());
+}
+
+/// A machine as infrastructure-as-code tools see it: the attributes they manage, plus read-only state
+#[derive(Debug, Clone, Serialize)]
+pub struct MachineResource {
+    pub id: Uuid,
+    pub mac_address: String,
+    pub hostname: Option<String>,
+    pub ip_address: String,
+    pub os_choice: Option<String>,
+    pub labels: BTreeMap<String, serde_json::Value>,
+    pub status: MachineStatus,
+    pub claim: Option<Claim>,
+}
+
+impl MachineResource {
+    /// Changes whenever any attribute of the resource does
+    pub fn etag(&self) -> String {
+        let digest = Sha256::digest(serde_json::to_vec(self).unwrap_or_default());
+        format!("\"{}\"", hex::encode(&digest[..16]))
+    }
+}
+
+/// A machine set aside for one consumer, such as a Terraform resource, until it is released
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claim {
+    /// Chosen by the claimant; claiming again with the same key returns the same machine
+    pub key: String,
+    pub claimed_by: String,
+    pub claimed_at: DateTime<Utc>,
+}
+
+/// The attributes a caller manages. Absent attributes are left alone; a label set to null is cleared.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MachineSpec {
+    #[serde(default)]
+    pub mac_address: Option<String>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub ip_address: Option<String>,
+    #[serde(default)]
+    pub os_choice: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, Option<String>>,
+}
+
+/// What a claimed machine must have; every criterion given must match
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClaimRequest {
+    pub key: String,
+    #[serde(default)]
+    pub site: Option<String>,
+    #[serde(default)]
+    pub rack: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub min_cpu_cores: Option<u32>,
+    #[serde(default)]
+    pub min_ram_bytes: Option<u64>,
+}
+
+#[derive(Debug, Error)]
+pub enum ResourceError {
+    #[error("Machine not found")]
+    NotFound,
+    #[error("The machine has changed since it was read; its current ETag is {0}")]
+    PreconditionFailed(String),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error(transparent)]
+    Database(#[from] anyhow::Error),
+}
+
+pub async fn get(id: &Uuid) -> Result<MachineResource, ResourceError> {
+    let machine = db::get_machine_by_id(id).await?.ok_or(ResourceError::NotFound)?;
+    to_resource(machine).await
+}
+
+async fn to_resource(machine: Machine) -> Result<MachineResource, ResourceError> {
+    let labels = db::get_machine_field_values(&machine.id).await?.into_iter().collect();
+    let claim = db::get_machine_claim(&machine.id).await?;
+    Ok(MachineResource {
+        id: machine.id,
+        mac_address: machine.mac_address,
+        hostname: machine.hostname,
+        ip_address: machine.ip_address,
+        os_choice: machine.os_choice,
+        labels,
+        status: machine.status,
+        claim,
+    })
+}
+
+/// Whether an `If-Match` header allows writing to a resource with the given current ETag
+pub fn matches(if_match: &str, etag: &str) -> bool {
+    if_match.split(',').map(str::trim).any(|tag| tag == "*" || tag == etag)
+}
+
+fn check(if_match: Option<&str>, current: &MachineResource) -> Result<(), ResourceError> {
+    let etag = current.etag();
+    match if_match {
+        Some(if_match) if !matches(if_match, &etag) => Err(ResourceError::PreconditionFailed(etag)),
+        _ => Ok(()),
+    }
+}
+
+// Creating and updating both go through the import path, so they get the same validation
+async fn apply(mac_address: &str, spec: &MachineSpec, actor: &str) -> Result<Uuid, ResourceError> {
+    let entry = ManifestEntry {
+        mac_address: mac_address.to_string(),
+        hostname: spec.hostname.clone(),
+        ip_address: spec.ip_address.clone(),
+        labels: spec.labels.iter().filter_map(|(k, v)| Some((k.clone(), v.clone()?))).collect(),
+        line: None,
+    };
+    let report = import::import(vec![entry], true, false, actor).await?;
+    if let Some(error) = report.errors.first() {
+        return Err(ResourceError::Invalid(error.message.clone()));
+    }
+    let machine_id = report.created.iter().chain(&report.updated).find_map(|m| m.machine_id)
+        .ok_or_else(|| ResourceError::Invalid("The machine could not be saved".to_string()))?;
+
+    let cleared: Vec<(String, Option<serde_json::Value>)> = spec.labels.iter()
+        .filter(|(_, v)| v.is_none())
+        .map(|(k, _)| (k.clone(), None))
+        .collect();
+    if !cleared.is_empty() {
+        db::set_machine_field_values(&machine_id, &cleared).await?;
+    }
+    Ok(machine_id)
+}
+
+/// Create a machine, or converge the existing machine with the same MAC address onto the spec, so
+/// retrying a create is safe. Returns the machine and whether it was new.
+pub async fn create(spec: &MachineSpec, actor: &str) -> Result<(MachineResource, bool), ResourceError> {
+    let mac_address = spec.mac_address.as_deref()
+        .and_then(import::normalize_mac)
+        .ok_or_else(|| ResourceError::Invalid("A valid mac_address is required".to_string()))?;
+    let _guard = WRITES.lock().await;
+    let existed = db::get_machine_by_mac(&mac_address).await?.is_some();
+    let machine_id = apply(&mac_address, spec, actor).await?;
+    Ok((get(&machine_id).await?, !existed))
+}
+
+/// Update the attributes given in the spec, if the machine still matches `if_match`. The OS is
+/// left to the caller, since assigning one starts an installation.
+pub async fn update(id: &Uuid, spec: &MachineSpec, if_match: Option<&str>, actor: &str) -> Result<MachineResource, ResourceError> {
+    let _guard = WRITES.lock().await;
+    let current = get(id).await?;
+    check(if_match, &current)?;
+    if spec.mac_address.as_deref().and_then(import::normalize_mac).is_some_and(|mac| mac != current.mac_address.to_lowercase()) {
+        return Err(ResourceError::Invalid("A machine's MAC address can't be changed".to_string()));
+    }
+    apply(&current.mac_address, spec, actor).await?;
+    get(id).await
+}
+
+/// Check a delete against `if_match`. A machine that is already gone counts as deleted.
+pub async fn check_delete(id: &Uuid, if_match: Option<&str>) -> Result<Option<MachineResource>, ResourceError> {
+    match get(id).await {
+        Ok(current) => check(if_match, &current).map(|_| Some(current)),
+        Err(ResourceError::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn matches_claim(request: &ClaimRequest, machine: &Machine, location: Option<&(String, String)>, labels: Option<&std::collections::HashMap<String, serde_json::Value>>) -> bool {
+    let label_matches = |name: &String, wanted: &String| match labels.and_then(|l| l.get(name)) {
+        Some(serde_json::Value::String(value)) => value == wanted,
+        Some(value) => &value.to_string() == wanted,
+        None => false,
+    };
+    machine.status == MachineStatus::AwaitingAssignment
+        && request.site.as_ref().map_or(true, |site| location.is_some_and(|(s, _)| s == site))
+        && request.rack.as_ref().map_or(true, |rack| location.is_some_and(|(_, r)| r == rack))
+        && request.min_cpu_cores.map_or(true, |min| machine.cpu_cores.is_some_and(|cores| cores >= min))
+        && request.min_ram_bytes.map_or(true, |min| machine.total_ram_bytes.is_some_and(|ram| ram >= min))
+        && request.labels.iter().all(|(name, wanted)| label_matches(name, wanted))
+}
+
+/// Set aside an unclaimed machine that is awaiting an OS and matches the request. Claiming again
+/// with the same key returns the machine already claimed. Returns the machine and whether the
+/// claim is new.
+pub async fn claim(request: &ClaimRequest, actor: &str) -> Result<(MachineResource, bool), ResourceError> {
+    if request.key.trim().is_empty() || request.key.len() > 256 {
+        return Err(ResourceError::Invalid("Give the claim a key of up to 256 characters".to_string()));
+    }
+    let _guard = WRITES.lock().await;
+    if let Some(machine_id) = db::get_claimed_machine(&request.key).await? {
+        return Ok((get(&machine_id).await?, false));
+    }
+
+    let (machines, claims, placements, racks, values) = tokio::try_join!(
+        db::get_all_machines(),
+        db::get_claimed_machine_ids(),
+        db::get_placements(),
+        db::get_racks(),
+        db::get_all_field_values(),
+    )?;
+    let location = |id: &Uuid| placements.iter().find(|p| &p.machine_id == id)
+        .and_then(|p| racks.iter().find(|r| r.name == p.rack))
+        .map(|r| (r.site.clone(), r.name.clone()));
+    let machine = machines.into_iter()
+        .filter(|m| !claims.contains(&m.id))
+        .find(|m| matches_claim(request, m, location(&m.id).as_ref(), values.get(&m.id)))
+        .ok_or_else(|| ResourceError::Conflict("No unclaimed machine awaiting an OS matches the request".to_string()))?;
+
+    db::save_machine_claim(&machine.id, &Claim { key: request.key.clone(), claimed_by: actor.to_string(), claimed_at: Utc::now() }).await?;
+    info!("Machine {} claimed by '{}' with key '{}'", machine.id, actor, request.key);
+    Ok((to_resource(machine).await?, true))
+}
+
+/// Release a claim. Releasing a claim that doesn't exist succeeds, so it is safe to retry.
+pub async fn release(key: &str) -> Result<(), ResourceError> {
+    let _guard = WRITES.lock().await;
+    if let Some(machine_id) = db::get_claimed_machine(key).await? {
+        db::delete_machine_claim(&machine_id).await?;
+        info!("Claim '{}' on machine {} released", key, machine_id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_match() {
+        assert!(matches("*", "\"abc\""));
+        assert!(matches("\"xyz\", \"abc\"", "\"abc\""));
+        assert!(!matches("\"xyz\"", "\"abc\""));
+        assert!(!matches("W/\"abc\"", "\"abc\""));
+    }
+}
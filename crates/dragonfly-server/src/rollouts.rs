@@ -0,0 +1,456 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use dragonfly_common::models::MachineStatus;
+use dragonfly_common::state_machine::StatusCause;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::db;
+use crate::event_manager::EventManager;
+use crate::machine_profiles::MatchRules;
+
+/// How often rollouts check on their machines and move on to the next batch
+const ROLLOUT_TICK: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Held while a rollout is loaded, changed and saved, so a pause isn't lost to a concurrent step
+pub(crate) static ROLLOUTS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// A new OS template to roll out across a group of machines: canaries first, then batches
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RolloutSpec {
+    pub name: String,
+    pub os_template: String,
+    /// Which machines to reimage, with the same rules as machine profiles
+    #[serde(default)]
+    pub machines: MatchRules,
+    /// Machines installed, and checked, before anything else. Any canary failing pauses the rollout.
+    #[serde(default = "default_canaries")]
+    pub canaries: usize,
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// The rollout pauses after a batch if more than this share of its installs failed
+    #[serde(default = "default_max_failure_percent")]
+    pub max_failure_percent: u8,
+    /// An install that hasn't become Ready in this long counts as failed
+    #[serde(default = "default_install_timeout_minutes")]
+    pub install_timeout_minutes: u32,
+}
+
+fn default_canaries() -> usize {
+    1
+}
+
+fn default_batch_size() -> usize {
+    5
+}
+
+fn default_max_failure_percent() -> u8 {
+    10
+}
+
+fn default_install_timeout_minutes() -> u32 {
+    120
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum RolloutState {
+    Canary,
+    Rolling,
+    Paused { reason: String },
+    Completed,
+    Cancelled,
+}
+
+impl RolloutState {
+    pub fn is_active(&self) -> bool {
+        matches!(self, RolloutState::Canary | RolloutState::Rolling | RolloutState::Paused { .. })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum MemberState {
+    Pending,
+    /// Assigned, but held for a maintenance window or queued for an install slot
+    Waiting,
+    Installing { since: DateTime<Utc> },
+    /// The machine became Ready on the new OS
+    Succeeded,
+    Failed { reason: String },
+}
+
+impl MemberState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, MemberState::Succeeded | MemberState::Failed { .. })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RolloutMember {
+    pub machine_id: Uuid,
+    pub name: String,
+    /// 0 for canaries, then 1, 2, ... for each batch
+    pub batch: usize,
+    #[serde(flatten)]
+    pub state: MemberState,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Rollout {
+    pub id: i64,
+    #[serde(flatten)]
+    pub spec: RolloutSpec,
+    #[serde(flatten)]
+    pub state: RolloutState,
+    /// The batch under way, or that the rollout paused after
+    pub batch: usize,
+    pub members: Vec<RolloutMember>,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+pub fn validate(spec: &RolloutSpec) -> Result<(), String> {
+    if spec.name.trim().is_empty() {
+        return Err("A rollout needs a name".to_string());
+    }
+    crate::workflow_templates::validate_name(&spec.os_template)?;
+    if let Some(subnet) = &spec.machines.subnet {
+        crate::boot_filter::parse_subnet(subnet).map_err(|e| e.to_string())?;
+    }
+    if spec.batch_size == 0 {
+        return Err("The batch size must be at least 1".to_string());
+    }
+    if spec.max_failure_percent > 100 {
+        return Err("The failure threshold is a percentage, from 0 to 100".to_string());
+    }
+    if spec.install_timeout_minutes == 0 {
+        return Err("The install timeout must be at least a minute".to_string());
+    }
+    Ok(())
+}
+
+/// Split machines into the canaries and then batches, in the order given
+pub fn plan_batches(machines: Vec<(Uuid, String)>, canaries: usize, batch_size: usize) -> Vec<RolloutMember> {
+    machines.into_iter()
+        .enumerate()
+        .map(|(index, (machine_id, name))| RolloutMember {
+            machine_id,
+            name,
+            batch: if index < canaries { 0 } else { (index - canaries) / batch_size + 1 },
+            state: MemberState::Pending,
+        })
+        .collect()
+}
+
+impl Rollout {
+    fn current_batch_mut(&mut self) -> impl Iterator<Item = &mut RolloutMember> {
+        let batch = self.batch;
+        self.members.iter_mut().filter(move |member| member.batch == batch)
+    }
+
+    /// Start the next batch, or complete the rollout if that was the last
+    fn next_batch(&mut self) {
+        self.batch += 1;
+        self.state = if self.members.iter().any(|member| member.batch >= self.batch) {
+            RolloutState::Rolling
+        } else {
+            RolloutState::Completed
+        };
+    }
+
+    /// Once the batch under way has finished, pause if it went badly or move on to the next.
+    /// Returns whether the rollout moved.
+    pub fn advance(&mut self) -> bool {
+        if !matches!(self.state, RolloutState::Canary | RolloutState::Rolling) {
+            return false;
+        }
+        let batch: Vec<&RolloutMember> = self.members.iter().filter(|member| member.batch == self.batch).collect();
+        if batch.iter().any(|member| !member.state.is_finished()) {
+            return false;
+        }
+        let failed = batch.iter().filter(|member| matches!(member.state, MemberState::Failed { .. })).count();
+        let percent = (failed * 100).checked_div(batch.len()).unwrap_or(0);
+        if self.batch == 0 && failed > 0 {
+            self.state = RolloutState::Paused { reason: format!("{} of {} canaries failed", failed, batch.len()) };
+        } else if percent > self.spec.max_failure_percent as usize {
+            self.state = RolloutState::Paused {
+                reason: format!("{} of {} installs in batch {} failed, over the {}% threshold", failed, batch.len(), self.batch, self.spec.max_failure_percent),
+            };
+        } else {
+            self.next_batch();
+        }
+        true
+    }
+
+    pub fn pause(&mut self, reason: String) -> Result<(), String> {
+        match self.state {
+            RolloutState::Canary | RolloutState::Rolling => {
+                self.state = RolloutState::Paused { reason };
+                Ok(())
+            },
+            _ => Err("Only a running rollout can be paused".to_string()),
+        }
+    }
+
+    /// Carry on. A rollout paused by a failure moves on to the next batch, leaving the failed
+    /// machines as they are; one paused by hand picks up where it stopped.
+    pub fn resume(&mut self) -> Result<(), String> {
+        if !matches!(self.state, RolloutState::Paused { .. }) {
+            return Err("Only a paused rollout can be resumed".to_string());
+        }
+        let finished = self.members.iter().filter(|member| member.batch == self.batch).all(|member| member.state.is_finished());
+        if finished {
+            self.next_batch();
+        } else {
+            self.state = if self.batch == 0 { RolloutState::Canary } else { RolloutState::Rolling };
+        }
+        Ok(())
+    }
+
+    pub fn cancel(&mut self) -> Result<(), String> {
+        if !self.state.is_active() {
+            return Err("The rollout has already finished".to_string());
+        }
+        self.state = RolloutState::Cancelled;
+        Ok(())
+    }
+}
+
+/// Machines taken by an active rollout
+pub(crate) async fn busy_machines() -> Result<HashSet<Uuid>> {
+    Ok(db::get_rollouts().await?.into_iter()
+        .filter(|rollout| rollout.state.is_active())
+        .flat_map(|rollout| rollout.members.into_iter().map(|member| member.machine_id))
+        .collect())
+}
+
+/// Plan a rollout over the machines its rules match. Machines waiting for approval, already
+/// installing or in another active rollout are left out.
+pub async fn plan(spec: RolloutSpec, created_by: &str) -> Result<Rollout> {
+    let busy = busy_machines().await?;
+    let mut machines = Vec::new();
+    for machine in db::get_all_machines().await? {
+        if machine.status.is_approval_gated() || machine.status == MachineStatus::InstallingOS || busy.contains(&machine.id) {
+            continue;
+        }
+        let facts = crate::machine_profiles::facts_for(&machine).await?;
+        if spec.machines.matches(&facts) {
+            let name = machine.hostname.clone()
+                .or_else(|| machine.memorable_name.clone())
+                .unwrap_or_else(|| machine.id.to_string());
+            machines.push((machine.id, name));
+        }
+    }
+    machines.sort_by(|a, b| a.1.cmp(&b.1));
+    let now = Utc::now();
+    Ok(Rollout {
+        id: 0,
+        members: plan_batches(machines, spec.canaries, spec.batch_size),
+        state: if spec.canaries > 0 { RolloutState::Canary } else { RolloutState::Rolling },
+        batch: if spec.canaries > 0 { 0 } else { 1 },
+        spec,
+        created_by: created_by.to_string(),
+        created_at: now,
+        updated_at: now,
+    })
+}
+
+/// Assign the new OS to a machine, through the same checks, holds and queue as any assignment
+async fn start(rollout: &Rollout, member: &RolloutMember) -> MemberState {
+    let cause = StatusCause::Automatic(format!("Rollout {} ({}) assigned {}", rollout.id, rollout.spec.name, rollout.spec.os_template));
+    let response = crate::api::assign_os_internal(member.machine_id, rollout.spec.os_template.clone(), cause).await;
+    match response.status() {
+        axum::http::StatusCode::OK => MemberState::Installing { since: Utc::now() },
+        axum::http::StatusCode::ACCEPTED => MemberState::Waiting,
+        status => MemberState::Failed { reason: format!("The install couldn't be started ({})", status) },
+    }
+}
+
+/// Machines with a held or queued install, read under the admission lock so none is between the two
+pub(crate) async fn waiting_installs() -> Result<HashSet<Uuid>> {
+    let _admission = crate::install_queue::ADMISSION.lock().await;
+    let held = db::get_provisioning_holds().await?.into_iter().map(|hold| hold.machine_id);
+    let queued = db::get_queued_installs().await?.into_iter().map(|queued| queued.machine_id);
+    Ok(held.chain(queued).collect())
+}
+
+/// Where a started install has got to. Reimage schedules follow their installs with this too.
+pub(crate) async fn check(machine_id: &Uuid, state: &MemberState, timeout: Duration, waiting: &HashSet<Uuid>) -> Result<MemberState> {
+    let Some(machine) = db::get_machine_by_id(machine_id).await? else {
+        return Ok(MemberState::Failed { reason: "The machine was deleted".to_string() });
+    };
+    let now = Utc::now();
+    Ok(match (state, machine.status) {
+        (MemberState::Waiting, MachineStatus::InstallingOS) => MemberState::Installing { since: now },
+        (MemberState::Waiting, _) if waiting.contains(machine_id) => MemberState::Waiting,
+        (MemberState::Waiting, _) => MemberState::Failed { reason: "The held or queued install was cancelled".to_string() },
+        (MemberState::Installing { .. }, MachineStatus::Ready) => MemberState::Succeeded,
+        (MemberState::Installing { .. }, MachineStatus::Error(message)) => MemberState::Failed { reason: message },
+        (MemberState::Installing { since }, MachineStatus::InstallingOS | MachineStatus::Offline) if now - *since > timeout => {
+            MemberState::Failed { reason: format!("The install didn't finish within {} minutes", timeout.num_minutes()) }
+        },
+        (MemberState::Installing { .. }, MachineStatus::InstallingOS | MachineStatus::Offline) => state.clone(),
+        (MemberState::Installing { .. }, status) => MemberState::Failed { reason: format!("The machine ended up {}", status) },
+        (state, _) => state.clone(),
+    })
+}
+
+/// Start, check on and move a rollout along. Returns whether anything changed.
+pub async fn step(rollout: &mut Rollout, event_manager: &EventManager) -> Result<bool> {
+    if !matches!(rollout.state, RolloutState::Canary | RolloutState::Rolling) {
+        return Ok(false);
+    }
+    let mut changed = false;
+    loop {
+        let waiting = waiting_installs().await?;
+        let timeout = Duration::minutes(rollout.spec.install_timeout_minutes as i64);
+        let snapshot = rollout.clone();
+        for member in rollout.current_batch_mut() {
+            let state = match member.state {
+                MemberState::Pending => start(&snapshot, member).await,
+                MemberState::Waiting | MemberState::Installing { .. } => check(&member.machine_id, &member.state, timeout, &waiting).await?,
+                _ => continue,
+            };
+            if state != member.state {
+                if let MemberState::Failed { reason } = &state {
+                    warn!("Rollout {} ({}): machine {} failed: {}", snapshot.id, snapshot.spec.name, member.name, reason);
+                }
+                member.state = state;
+                changed = true;
+                let _ = event_manager.send(format!("machine_updated:{}", member.machine_id));
+            }
+        }
+        if !rollout.advance() {
+            break;
+        }
+        changed = true;
+        match &rollout.state {
+            RolloutState::Paused { reason } => warn!("Rollout {} ({}) paused: {}", rollout.id, rollout.spec.name, reason),
+            RolloutState::Completed => info!("Rollout {} ({}) completed", rollout.id, rollout.spec.name),
+            _ => {
+                // Start the next batch straight away
+                info!("Rollout {} ({}) moving on to batch {}", rollout.id, rollout.spec.name, rollout.batch);
+                continue;
+            },
+        }
+        break;
+    }
+    if changed {
+        rollout.updated_at = Utc::now();
+    }
+    Ok(changed)
+}
+
+/// Move running rollouts along as their installs finish
+pub async fn start_rollout_task(
+    event_manager: Arc<EventManager>,
+    mut shutdown_rx: tokio::sync::watch::Receiver<()>,
+) {
+    tokio::spawn(async move {
+        info!("Starting rollout task");
+        let mut ticker = tokio::time::interval(ROLLOUT_TICK);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    // Rollouts start nothing new during a provisioning freeze
+                    if crate::freeze::is_frozen() {
+                        continue;
+                    }
+                    let _rollouts = ROLLOUTS.lock().await;
+                    let rollouts = match db::get_rollouts().await {
+                        Ok(rollouts) => rollouts,
+                        Err(e) => {
+                            error!("Failed to load rollouts: {}", e);
+                            continue;
+                        }
+                    };
+                    for mut rollout in rollouts.into_iter().filter(|rollout| rollout.state.is_active()) {
+                        match step(&mut rollout, &event_manager).await {
+                            Ok(true) => if let Err(e) = db::save_rollout(&rollout).await {
+                                error!("Failed to save rollout {}: {}", rollout.id, e);
+                            },
+                            Ok(false) => {},
+                            Err(e) => error!("Failed to step rollout {}: {}", rollout.id, e),
+                        }
+                    }
+                },
+                _ = shutdown_rx.changed() => {
+                    info!("Shutdown signal received, stopping rollout task.");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rollout(machines: usize, canaries: usize) -> Rollout {
+        let spec = RolloutSpec {
+            name: "noble".to_string(),
+            os_template: "ubuntu-2404".to_string(),
+            machines: MatchRules::default(),
+            canaries,
+            batch_size: 2,
+            max_failure_percent: 25,
+            install_timeout_minutes: 60,
+        };
+        let members = (0..machines).map(|i| (Uuid::new_v4(), format!("node-{}", i))).collect();
+        Rollout {
+            id: 1,
+            members: plan_batches(members, spec.canaries, spec.batch_size),
+            state: RolloutState::Canary,
+            batch: 0,
+            spec,
+            created_by: "admin".to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn finish(rollout: &mut Rollout, failures: usize) {
+        let batch = rollout.batch;
+        for (i, member) in rollout.members.iter_mut().filter(|m| m.batch == batch).enumerate() {
+            member.state = if i < failures { MemberState::Failed { reason: "boom".to_string() } } else { MemberState::Succeeded };
+        }
+    }
+
+    #[test]
+    fn test_rollout_batches() {
+        let mut rollout = rollout(5, 1);
+        let batches: Vec<usize> = rollout.members.iter().map(|m| m.batch).collect();
+        assert_eq!(batches, vec![0, 1, 1, 2, 2]);
+
+        // Nothing moves while the canary is installing
+        assert!(!rollout.advance());
+        finish(&mut rollout, 0);
+        assert!(rollout.advance());
+        assert_eq!((rollout.state.clone(), rollout.batch), (RolloutState::Rolling, 1));
+
+        // One failure in a batch of two is over the 25% threshold
+        finish(&mut rollout, 1);
+        assert!(rollout.advance());
+        assert!(matches!(rollout.state, RolloutState::Paused { .. }));
+        rollout.resume().unwrap();
+        assert_eq!((rollout.state.clone(), rollout.batch), (RolloutState::Rolling, 2));
+        finish(&mut rollout, 0);
+        assert!(rollout.advance());
+        assert_eq!(rollout.state, RolloutState::Completed);
+    }
+
+    #[test]
+    fn test_failed_canary_pauses() {
+        let mut rollout = rollout(4, 2);
+        finish(&mut rollout, 1);
+        rollout.spec.max_failure_percent = 100;
+        assert!(rollout.advance());
+        assert_eq!(rollout.state, RolloutState::Paused { reason: "1 of 2 canaries failed".to_string() });
+    }
+}
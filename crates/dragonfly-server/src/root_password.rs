@@ -0,0 +1,104 @@
+use chrono::{DateTime, Utc};
+use dragonfly_common::agent_protocol::{CommandKind, CommandStatus};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::db;
+use crate::template_vars::{self, VariableError};
+
+const PASSWORD_LEN: usize = 24;
+
+/// A machine's current root password, without the password itself
+#[derive(Debug, Clone, Serialize)]
+pub struct RootPasswordInfo {
+    pub machine_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub revealed_by: Option<String>,
+    pub revealed_at: Option<DateTime<Utc>>,
+    /// A rotation has been handed to the agent and hasn't finished yet
+    pub rotation_pending: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum PasswordError {
+    #[error("This machine has no generated root password")]
+    NotFound,
+    #[error("The root password was already revealed to '{by}'; rotate it to get a new one")]
+    AlreadyRevealed { by: String },
+    #[error("Command {0} is not a running root password rotation for this machine")]
+    NoRotation(i64),
+    #[error(transparent)]
+    Secret(#[from] VariableError),
+    #[error(transparent)]
+    Database(#[from] anyhow::Error),
+}
+
+fn generate() -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(PASSWORD_LEN).map(char::from).collect()
+}
+
+/// Give a machine that is about to install a fresh root password, when the setting is on.
+/// Earlier passwords are dropped, since the new install won't have them.
+pub async fn issue_for_install(machine_id: &Uuid) -> Result<(), PasswordError> {
+    if !db::get_app_settings().await?.generate_root_passwords {
+        return Ok(());
+    }
+    let key = template_vars::master_key()?;
+    let sealed = template_vars::seal(&key, machine_id.as_bytes(), &generate())?;
+    db::replace_root_password(machine_id, &sealed).await?;
+    info!("Generated a root password for machine {}", machine_id);
+    Ok(())
+}
+
+/// The machine's current password, for rendering its install payloads
+pub async fn for_render(machine_id: &Uuid) -> Result<Option<String>, PasswordError> {
+    let Some(sealed) = db::get_sealed_root_password(machine_id).await? else {
+        return Ok(None);
+    };
+    let key = template_vars::master_key()?;
+    Ok(Some(template_vars::open(&key, machine_id.as_bytes(), &sealed)?))
+}
+
+/// Show the current password to an admin. Each password can be revealed once; after that only a
+/// rotation produces one that can be seen again.
+pub async fn reveal(actor: &str, machine_id: &Uuid) -> Result<String, PasswordError> {
+    let info = db::get_root_password_info(machine_id).await?.ok_or(PasswordError::NotFound)?;
+    if let Some(by) = info.revealed_by {
+        return Err(PasswordError::AlreadyRevealed { by });
+    }
+    let password = for_render(machine_id).await?.ok_or(PasswordError::NotFound)?;
+    // Marking is conditional on the password still being unrevealed, so two admins can't both see it
+    if !db::mark_root_password_revealed(machine_id, actor).await? {
+        return Err(PasswordError::AlreadyRevealed { by: "another admin".to_string() });
+    }
+    warn!("Root password of machine {} revealed to '{}'", machine_id, actor);
+    Ok(password)
+}
+
+/// Generate the password a rotation command sets, handed to the machine's agent when it runs the
+/// command. It only replaces the current password once the agent reports success.
+pub async fn issue_for_rotation(machine_id: &Uuid, command_id: i64) -> Result<String, PasswordError> {
+    match db::get_agent_command(command_id).await? {
+        Some(command) if command.machine_id == *machine_id
+            && command.kind == CommandKind::RotateRootPassword
+            && command.status == CommandStatus::Running => {},
+        _ => return Err(PasswordError::NoRotation(command_id)),
+    }
+    let key = template_vars::master_key()?;
+    let password = generate();
+    db::save_pending_root_password(machine_id, command_id, &template_vars::seal(&key, machine_id.as_bytes(), &password)?).await?;
+    Ok(password)
+}
+
+/// Make a rotation's password current if the agent set it, or throw it away if it didn't
+pub async fn finish_rotation(machine_id: &Uuid, command_id: i64, succeeded: bool) -> Result<(), PasswordError> {
+    db::finish_root_password_rotation(machine_id, command_id, succeeded).await?;
+    match succeeded {
+        true => info!("Rotated root password of machine {}", machine_id),
+        false => warn!("Root password rotation {} failed on machine {}; the previous password still applies", command_id, machine_id),
+    }
+    Ok(())
+}
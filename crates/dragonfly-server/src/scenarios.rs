@@ -0,0 +1,505 @@
+//! Recording and replaying scenarios.
+//!
+//! While a recording runs, every event the server publishes and every API call it answers is
+//! kept with its offset from the start. Stopping the recording saves it as a scenario file. A
+//! test instance can replay a scenario at any speed: events are published again, so SSE clients
+//! see the same stream, and API calls are made against the instance in the same order and with
+//! the same gaps. Replays report calls whose status differs from the recording, which is how a
+//! provisioning race shows up.
+
+use anyhow::{anyhow, Result};
+use axum::{
+    body::{to_bytes, Body},
+    extract::{OriginalUri, Request},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::env;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::event_manager::EventManager;
+
+/// Directory scenario files are kept in
+pub const DIR_ENV_VAR: &str = "DRAGONFLY_SCENARIO_DIR";
+pub const DEFAULT_DIR: &str = "scenarios";
+/// Replays make real API calls, so an instance must opt in to them
+pub const REPLAY_ENV_VAR: &str = "DRAGONFLY_SCENARIO_REPLAY";
+/// Set on replayed calls, so they aren't recorded again
+pub const REPLAY_HEADER: &str = "X-Dragonfly-Replay";
+
+/// Request bodies larger than this are recorded without their body
+const MAX_BODY_BYTES: usize = 256 * 1024;
+/// Replay mismatches kept for the status report
+const MAX_MISMATCHES: usize = 100;
+/// Keys whose values are never written to a scenario file
+const REDACTED_KEYS: &[&str] = &["password", "secret", "token", "key", "totp", "code"];
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Entry {
+    Event {
+        event: String,
+    },
+    Request {
+        method: String,
+        /// Path and query, including `/api`
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        body: Option<Value>,
+        /// The status the server answered with when recorded
+        status: u16,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TimedEntry {
+    /// Milliseconds since the recording started
+    pub offset_ms: u64,
+    #[serde(flatten)]
+    pub entry: Entry,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scenario {
+    pub name: String,
+    pub recorded_by: String,
+    pub recorded_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub entries: Vec<TimedEntry>,
+}
+
+/// A saved scenario without its entries, for listing
+#[derive(Debug, Clone, Serialize)]
+pub struct ScenarioSummary {
+    pub name: String,
+    pub recorded_by: String,
+    pub recorded_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub events: usize,
+    pub requests: usize,
+}
+
+impl From<&Scenario> for ScenarioSummary {
+    fn from(scenario: &Scenario) -> Self {
+        let events = scenario.entries.iter().filter(|e| matches!(e.entry, Entry::Event { .. })).count();
+        Self {
+            name: scenario.name.clone(),
+            recorded_by: scenario.recorded_by.clone(),
+            recorded_at: scenario.recorded_at,
+            duration_ms: scenario.duration_ms,
+            events,
+            requests: scenario.entries.len() - events,
+        }
+    }
+}
+
+struct Recording {
+    scenario: Scenario,
+    started: Instant,
+}
+
+impl Recording {
+    fn push(&mut self, entry: Entry) {
+        let offset_ms = self.started.elapsed().as_millis() as u64;
+        self.scenario.entries.push(TimedEntry { offset_ms, entry });
+    }
+}
+
+static RECORDING: Lazy<Mutex<Option<Recording>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplayStatus {
+    pub name: String,
+    pub speed: f64,
+    pub repeat: u32,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub cancelled: bool,
+    pub events_sent: u64,
+    pub requests_sent: u64,
+    /// Calls that failed outright
+    pub errors: u64,
+    /// Calls answered with a different status than when recorded
+    pub mismatches: Vec<String>,
+}
+
+static REPLAY: Lazy<Mutex<Option<ReplayStatus>>> = Lazy::new(|| Mutex::new(None));
+static CANCEL: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplayOptions {
+    /// 2.0 replays twice as fast; 0 sends everything without waiting
+    #[serde(default = "default_speed")]
+    pub speed: f64,
+    /// Run the scenario this many times over, for load tests
+    #[serde(default = "default_repeat")]
+    pub repeat: u32,
+    #[serde(default = "default_true")]
+    pub events: bool,
+    #[serde(default = "default_true")]
+    pub requests: bool,
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
+fn default_repeat() -> u32 {
+    1
+}
+
+fn default_true() -> bool {
+    true
+}
+
+pub fn dir() -> PathBuf {
+    PathBuf::from(env::var(DIR_ENV_VAR).unwrap_or_else(|_| DEFAULT_DIR.to_string()))
+}
+
+pub fn replay_allowed() -> bool {
+    env::var(REPLAY_ENV_VAR).is_ok_and(|v| v == "true" || v == "1")
+}
+
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 64 || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("Scenario names are 1-64 letters, digits, '-' and '_'".to_string());
+    }
+    Ok(())
+}
+
+fn path_for(name: &str) -> PathBuf {
+    dir().join(format!("{}.json", name))
+}
+
+/// Replace credentials in a recorded body
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_lowercase();
+                if REDACTED_KEYS.iter().any(|redacted| key.contains(redacted)) {
+                    *value = Value::String("[redacted]".to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        },
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {},
+    }
+}
+
+pub fn is_recording() -> bool {
+    RECORDING.lock().unwrap().is_some()
+}
+
+/// The recording under way, without its entries
+pub fn recording() -> Option<ScenarioSummary> {
+    RECORDING.lock().unwrap().as_ref().map(|recording| {
+        let mut summary = ScenarioSummary::from(&recording.scenario);
+        summary.duration_ms = recording.started.elapsed().as_millis() as u64;
+        summary
+    })
+}
+
+/// Start recording events and API calls
+pub fn start_recording(name: &str, recorded_by: &str, event_manager: &EventManager) -> Result<(), String> {
+    validate_name(name)?;
+    let mut recording = RECORDING.lock().unwrap();
+    if recording.is_some() {
+        return Err("A recording is already running".to_string());
+    }
+    *recording = Some(Recording {
+        scenario: Scenario {
+            name: name.to_string(),
+            recorded_by: recorded_by.to_string(),
+            recorded_at: Utc::now(),
+            duration_ms: 0,
+            entries: Vec::new(),
+        },
+        started: Instant::now(),
+    });
+    drop(recording);
+
+    // Follows events until the recording stops; the subscription ends with it
+    let mut subscription = event_manager.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = subscription.recv().await;
+            let mut recording = RECORDING.lock().unwrap();
+            let Some(recording) = recording.as_mut() else {
+                break;
+            };
+            match event {
+                Ok(event) => recording.push(Entry::Event { event }),
+                Err(crate::event_manager::RecvError::Lagged(skipped)) => {
+                    warn!("Scenario recording missed {} events", skipped);
+                },
+                Err(crate::event_manager::RecvError::Closed) => break,
+            }
+        }
+    });
+    info!("Recording scenario {}", name);
+    Ok(())
+}
+
+/// Stop recording and save the scenario
+pub async fn stop_recording() -> Result<ScenarioSummary> {
+    let recording = RECORDING.lock().unwrap().take().ok_or_else(|| anyhow!("No recording is running"))?;
+    let mut scenario = recording.scenario;
+    scenario.duration_ms = recording.started.elapsed().as_millis() as u64;
+    save(&scenario).await?;
+    info!("Saved scenario {} ({} entries)", scenario.name, scenario.entries.len());
+    Ok(ScenarioSummary::from(&scenario))
+}
+
+pub async fn save(scenario: &Scenario) -> Result<()> {
+    validate_name(&scenario.name).map_err(|e| anyhow!(e))?;
+    tokio::fs::create_dir_all(dir()).await?;
+    tokio::fs::write(path_for(&scenario.name), serde_json::to_vec_pretty(scenario)?).await?;
+    Ok(())
+}
+
+pub async fn load(name: &str) -> Result<Option<Scenario>> {
+    validate_name(name).map_err(|e| anyhow!(e))?;
+    match tokio::fs::read(path_for(name)).await {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn delete(name: &str) -> Result<bool> {
+    validate_name(name).map_err(|e| anyhow!(e))?;
+    match tokio::fs::remove_file(path_for(name)).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Saved scenarios, newest first
+pub async fn list() -> Result<Vec<ScenarioSummary>> {
+    let mut summaries = Vec::new();
+    let mut entries = match tokio::fs::read_dir(dir()).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(summaries),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        match tokio::fs::read(&path).await.map_err(anyhow::Error::from)
+            .and_then(|bytes| serde_json::from_slice::<Scenario>(&bytes).map_err(anyhow::Error::from))
+        {
+            Ok(scenario) => summaries.push(ScenarioSummary::from(&scenario)),
+            Err(e) => warn!("Skipping unreadable scenario {}: {}", path.display(), e),
+        }
+    }
+    summaries.sort_by_key(|summary| std::cmp::Reverse(summary.recorded_at));
+    Ok(summaries)
+}
+
+/// Whether a call is part of what gets recorded; the scenario API and the event stream aren't
+fn records(path: &str) -> bool {
+    !path.starts_with("/api/scenarios") && !path.starts_with("/api/events")
+}
+
+/// Layer on the API recording each call while a recording runs
+pub async fn record_requests(request: Request, next: Next) -> Response {
+    let path = request.extensions().get::<OriginalUri>()
+        .map(|uri| uri.0.clone())
+        .unwrap_or_else(|| request.uri().clone());
+    let path = path.path_and_query().map_or_else(|| path.path().to_string(), |pq| pq.to_string());
+    if !is_recording() || !records(&path) || request.headers().contains_key(REPLAY_HEADER) {
+        return next.run(request).await;
+    }
+
+    let method = request.method().to_string();
+    let is_json = request.headers().get(axum::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    let (parts, body) = request.into_parts();
+    let (body, recorded) = match to_bytes(body, MAX_BODY_BYTES).await {
+        Ok(bytes) => {
+            let recorded = (is_json && !bytes.is_empty())
+                .then(|| serde_json::from_slice::<Value>(&bytes).ok())
+                .flatten()
+                .map(|mut value| {
+                    redact(&mut value);
+                    value
+                });
+            (Body::from(bytes), recorded)
+        },
+        // Too big to buffer: let the handler refuse it as it normally would
+        Err(_) => (Body::empty(), None),
+    };
+
+    let response = next.run(Request::from_parts(parts, body)).await;
+    if let Some(recording) = RECORDING.lock().unwrap().as_mut() {
+        recording.push(Entry::Request { method, path, body: recorded, status: response.status().as_u16() });
+    }
+    response
+}
+
+pub fn replay_status() -> Option<ReplayStatus> {
+    REPLAY.lock().unwrap().clone()
+}
+
+pub fn replay_running() -> bool {
+    REPLAY.lock().unwrap().as_ref().is_some_and(|status| status.finished_at.is_none())
+}
+
+pub fn cancel_replay() -> bool {
+    if !replay_running() {
+        return false;
+    }
+    CANCEL.store(true, Ordering::Relaxed);
+    true
+}
+
+fn update(change: impl FnOnce(&mut ReplayStatus)) {
+    if let Some(status) = REPLAY.lock().unwrap().as_mut() {
+        change(status);
+    }
+}
+
+/// When an entry is due, relative to the start of its pass
+fn due(offset_ms: u64, speed: f64) -> Duration {
+    if speed <= 0.0 {
+        Duration::ZERO
+    } else {
+        Duration::from_secs_f64(offset_ms as f64 / 1000.0 / speed)
+    }
+}
+
+/// Replay a scenario against this instance in the background. `base_url` is where API calls are
+/// sent, and `cookie` is the session they are made with.
+pub fn start_replay(
+    scenario: Scenario,
+    options: ReplayOptions,
+    base_url: String,
+    cookie: Option<String>,
+    event_manager: Arc<EventManager>,
+) -> Result<ReplayStatus, String> {
+    if !(0.0..=1000.0).contains(&options.speed) {
+        return Err("speed must be between 0 and 1000".to_string());
+    }
+    if !(1..=1000).contains(&options.repeat) {
+        return Err("repeat must be between 1 and 1000".to_string());
+    }
+    let status = {
+        let mut replay = REPLAY.lock().unwrap();
+        if replay.as_ref().is_some_and(|status| status.finished_at.is_none()) {
+            return Err("A replay is already running".to_string());
+        }
+        let status = ReplayStatus {
+            name: scenario.name.clone(),
+            speed: options.speed,
+            repeat: options.repeat,
+            started_at: Utc::now(),
+            finished_at: None,
+            cancelled: false,
+            events_sent: 0,
+            requests_sent: 0,
+            errors: 0,
+            mismatches: Vec::new(),
+        };
+        *replay = Some(status.clone());
+        status
+    };
+    CANCEL.store(false, Ordering::Relaxed);
+
+    tokio::spawn(async move {
+        info!("Replaying scenario {} at {}x, {} time(s)", scenario.name, options.speed, options.repeat);
+        let client = reqwest::Client::new();
+        'passes: for _ in 0..options.repeat {
+            let started = tokio::time::Instant::now();
+            for timed in &scenario.entries {
+                if CANCEL.load(Ordering::Relaxed) {
+                    update(|status| status.cancelled = true);
+                    break 'passes;
+                }
+                tokio::time::sleep_until(started + due(timed.offset_ms, options.speed)).await;
+                match &timed.entry {
+                    Entry::Event { event } if options.events => {
+                        let _ = event_manager.send(event.clone());
+                        update(|status| status.events_sent += 1);
+                    },
+                    Entry::Request { method, path, body, status: recorded } if options.requests => {
+                        let Ok(method) = method.parse::<reqwest::Method>() else {
+                            continue;
+                        };
+                        let mut request = client.request(method.clone(), format!("{}{}", base_url, path))
+                            .header(REPLAY_HEADER, scenario.name.as_str());
+                        if let Some(cookie) = &cookie {
+                            request = request.header(reqwest::header::COOKIE, cookie.as_str());
+                        }
+                        if let Some(body) = body {
+                            request = request.json(body);
+                        }
+                        let result = request.send().await;
+                        update(|status| {
+                            status.requests_sent += 1;
+                            match result {
+                                Ok(response) if response.status().as_u16() != *recorded => {
+                                    if status.mismatches.len() < MAX_MISMATCHES {
+                                        status.mismatches.push(format!(
+                                            "{} {} at {}ms: recorded {}, got {}",
+                                            method, path, timed.offset_ms, recorded, response.status().as_u16()
+                                        ));
+                                    }
+                                },
+                                Ok(_) => {},
+                                Err(_) => status.errors += 1,
+                            }
+                        });
+                    },
+                    _ => {},
+                }
+            }
+        }
+        update(|status| status.finished_at = Some(Utc::now()));
+        info!("Finished replaying scenario {}", scenario.name);
+    });
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_entries_and_redaction() {
+        let entry = TimedEntry {
+            offset_ms: 1500,
+            entry: Entry::Request { method: "POST".to_string(), path: "/api/machines/x/os".to_string(), body: None, status: 202 },
+        };
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json, json!({"offset_ms": 1500, "type": "request", "method": "POST", "path": "/api/machines/x/os", "status": 202}));
+        assert_eq!(serde_json::from_value::<TimedEntry>(json).unwrap(), entry);
+
+        let mut body = json!({"username": "ops", "password": "hunter2", "bmc": [{"api_key": "k", "address": "10.0.0.9"}]});
+        redact(&mut body);
+        assert_eq!(body, json!({"username": "ops", "password": "[redacted]", "bmc": [{"api_key": "[redacted]", "address": "10.0.0.9"}]}));
+
+        assert!(records("/api/machines"));
+        assert!(!records("/api/scenarios/recording"));
+        assert!(!records("/api/events"));
+        assert!(validate_name("race-1_b").is_ok());
+        assert!(validate_name("../etc").is_err());
+        assert_eq!(due(3000, 2.0), Duration::from_millis(1500));
+        assert_eq!(due(3000, 0.0), Duration::ZERO);
+    }
+}
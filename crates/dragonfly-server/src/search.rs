@@ -0,0 +1,333 @@
+//! Machine search queries.
+//!
+//! A query is a list of terms separated by spaces, and a machine has to match all of them:
+//! `status:error os:ubuntu label:rack=b12 mac:aa:bb* web-01`. Structured terms are
+//! `status:`, `os:`, `hostname:`, `mac:`, `ip:` and `label:<field>[=<value>]`, where labels are
+//! custom field values. `*` matches any run of characters. Without one, a value has to match
+//! exactly, except `os:`, which matches the start of the OS name (`os:ubuntu` finds
+//! `ubuntu-2204`). Anything else is free text, found anywhere in the hostname, MAC address, IP
+//! address or serial number. Double quotes keep spaces in a term: `hostname:"lab 3*"`.
+
+use dragonfly_common::models::{Machine, MachineStatus};
+
+/// Status names a query can use, with the variant each stands for
+const STATUSES: &[(&str, &str)] = &[
+    ("ready", "Ready"),
+    ("installing", "InstallingOS"),
+    ("awaiting", "AwaitingAssignment"),
+    ("offline", "Offline"),
+    ("pending", "PendingApproval"),
+    ("rejected", "Rejected"),
+    ("existing", "ExistingOS"),
+    ("error", "Error"),
+];
+
+/// Free text shorter than this can't use the trigram index and is matched with LIKE
+const MIN_INDEXED_TEXT: usize = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    /// The name of a `MachineStatus` variant
+    Status(&'static str),
+    Os(String),
+    Hostname(String),
+    Mac(String),
+    Ip(String),
+    Label { field: String, value: Option<String> },
+    Text(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Search {
+    pub terms: Vec<Term>,
+}
+
+/// A WHERE clause over the machines table and the values to bind to it, in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub sql: String,
+    pub binds: Vec<String>,
+}
+
+// Split on spaces outside double quotes, dropping the quotes
+fn tokenize(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+    for c in input.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            },
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+fn parse_status(value: &str) -> Result<&'static str, String> {
+    let value = value.to_lowercase();
+    STATUSES.iter()
+        .find(|(name, variant)| *name == value || variant.to_lowercase() == value)
+        .map(|(_, variant)| *variant)
+        .ok_or_else(|| format!(
+            "Unknown status '{}'; use one of {}",
+            value,
+            STATUSES.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+        ))
+}
+
+impl Search {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let mut terms = Vec::new();
+        for token in tokenize(input) {
+            let Some((key, value)) = token.split_once(':') else {
+                terms.push(Term::Text(token));
+                continue;
+            };
+            let known = ["status", "os", "hostname", "mac", "ip", "label"].contains(&key.to_lowercase().as_str());
+            if known && value.is_empty() {
+                return Err(format!("'{}:' needs a value", key));
+            }
+            let term = match key.to_lowercase().as_str() {
+                "status" => Term::Status(parse_status(value)?),
+                "os" => Term::Os(value.to_string()),
+                "hostname" => Term::Hostname(value.to_string()),
+                "mac" => Term::Mac(value.to_lowercase().replace('-', ":")),
+                "ip" => Term::Ip(value.to_string()),
+                "label" => match value.split_once('=') {
+                    Some(("", _)) => return Err("label: needs a field name, e.g. label:rack=b12".to_string()),
+                    Some((field, value)) => Term::Label { field: field.to_string(), value: Some(value.to_string()) },
+                    None => Term::Label { field: value.to_string(), value: None },
+                },
+                // Not a key we know, e.g. part of a MAC address: search for it as text
+                _ => Term::Text(token),
+            };
+            terms.push(term);
+        }
+        Ok(Self { terms })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// The query as SQL, for `db::search_machines`
+    pub fn to_filter(&self) -> Filter {
+        let mut clauses = Vec::new();
+        let mut binds = Vec::new();
+        for term in &self.terms {
+            match term {
+                Term::Status("Error") => {
+                    clauses.push(r#"(status LIKE '{"Error"%' OR status LIKE 'Error: %')"#.to_string());
+                },
+                // Written as "Existing OS" or "ExistingOS: <os>" by the legacy schema, which
+                // db::parse_status still reads
+                Term::Status("ExistingOS") => {
+                    clauses.push("(status IN (?, ?, 'Existing OS') OR status LIKE 'ExistingOS: %')".to_string());
+                    binds.push("\"ExistingOS\"".to_string());
+                    binds.push("ExistingOS".to_string());
+                },
+                // Stored as JSON, or as the bare name by older versions
+                Term::Status(variant) => {
+                    clauses.push("status IN (?, ?)".to_string());
+                    binds.push(format!("\"{}\"", variant));
+                    binds.push(variant.to_string());
+                },
+                Term::Os(os) => {
+                    clauses.push(r"(os_installed LIKE ? ESCAPE '\' OR os_choice LIKE ? ESCAPE '\')".to_string());
+                    let pattern = like_pattern(os, true);
+                    binds.push(pattern.clone());
+                    binds.push(pattern);
+                },
+                Term::Hostname(hostname) => {
+                    clauses.push(r"hostname LIKE ? ESCAPE '\'".to_string());
+                    binds.push(like_pattern(hostname, false));
+                },
+                Term::Mac(mac) => {
+                    clauses.push(r"mac_address LIKE ? ESCAPE '\'".to_string());
+                    binds.push(like_pattern(mac, false));
+                },
+                Term::Ip(ip) => {
+                    clauses.push(r"ip_address LIKE ? ESCAPE '\'".to_string());
+                    binds.push(like_pattern(ip, false));
+                },
+                Term::Label { field, value: None } => {
+                    clauses.push("id IN (SELECT machine_id FROM machine_field_values WHERE field = ?)".to_string());
+                    binds.push(field.clone());
+                },
+                Term::Label { field, value: Some(value) } => {
+                    clauses.push(
+                        r"id IN (SELECT machine_id FROM machine_field_values WHERE field = ? AND CAST(json_extract(value, '$') AS TEXT) LIKE ? ESCAPE '\')".to_string()
+                    );
+                    binds.push(field.clone());
+                    binds.push(like_pattern(value, false));
+                },
+                Term::Text(text) if text.chars().count() >= MIN_INDEXED_TEXT => {
+                    clauses.push("id IN (SELECT machine_id FROM machine_search WHERE machine_search MATCH ?)".to_string());
+                    binds.push(format!("\"{}\"", text.replace('"', "\"\"")));
+                },
+                Term::Text(text) => {
+                    clauses.push(
+                        r"id IN (SELECT machine_id FROM machine_search WHERE hostname LIKE ? ESCAPE '\' OR mac_address LIKE ? ESCAPE '\' OR ip_address LIKE ? ESCAPE '\' OR serial LIKE ? ESCAPE '\')".to_string()
+                    );
+                    let pattern = format!("%{}%", escape_like(text));
+                    binds.extend(vec![pattern; 4]);
+                },
+            }
+        }
+        let sql = if clauses.is_empty() { "1 = 1".to_string() } else { clauses.join(" AND ") };
+        Filter { sql, binds }
+    }
+
+    /// Match a machine that isn't in the database, such as one of the demo fleet. Labels and
+    /// serial numbers aren't known for those, so label terms never match.
+    pub fn matches(&self, machine: &Machine) -> bool {
+        self.terms.iter().all(|term| match term {
+            Term::Status(variant) => status_variant(&machine.status) == *variant,
+            Term::Os(os) => [&machine.os_installed, &machine.os_choice].into_iter()
+                .flatten()
+                .any(|name| glob_matches(os, name, true)),
+            Term::Hostname(hostname) => machine.hostname.as_deref().is_some_and(|name| glob_matches(hostname, name, false)),
+            Term::Mac(mac) => glob_matches(mac, &machine.mac_address, false),
+            Term::Ip(ip) => glob_matches(ip, &machine.ip_address, false),
+            Term::Label { .. } => false,
+            Term::Text(text) => {
+                let text = text.to_lowercase();
+                [machine.hostname.as_deref(), Some(machine.mac_address.as_str()), Some(machine.ip_address.as_str())]
+                    .into_iter()
+                    .flatten()
+                    .any(|value| value.to_lowercase().contains(&text))
+            },
+        })
+    }
+}
+
+/// The machines a search finds: the demo fleet in demo mode, the machines table otherwise
+pub async fn find(search: &Search) -> anyhow::Result<Vec<Machine>> {
+    if crate::demo::is_active() {
+        return Ok(crate::demo::machines().into_iter().filter(|machine| search.matches(machine)).collect());
+    }
+    if search.is_empty() {
+        crate::db::get_all_machines().await
+    } else {
+        crate::db::search_machines(search).await
+    }
+}
+
+fn status_variant(status: &MachineStatus) -> &'static str {
+    match status {
+        MachineStatus::ExistingOS => "ExistingOS",
+        MachineStatus::AwaitingAssignment => "AwaitingAssignment",
+        MachineStatus::InstallingOS => "InstallingOS",
+        MachineStatus::Ready => "Ready",
+        MachineStatus::Offline => "Offline",
+        MachineStatus::PendingApproval => "PendingApproval",
+        MachineStatus::Rejected => "Rejected",
+        MachineStatus::Error(_) => "Error",
+    }
+}
+
+fn escape_like(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+// `*` becomes `%`; a prefix pattern also matches anything after the value
+fn like_pattern(value: &str, prefix: bool) -> String {
+    let pattern = escape_like(value).replace('*', "%");
+    if prefix && !pattern.ends_with('%') {
+        format!("{}%", pattern)
+    } else {
+        pattern
+    }
+}
+
+// Case-insensitive match of a value with `*` wildcards, as the LIKE patterns above match
+fn glob_matches(pattern: &str, value: &str, prefix: bool) -> bool {
+    let pattern = pattern.to_lowercase();
+    let value = value.to_lowercase();
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let Some((first, rest)) = parts.split_first() else {
+        return true;
+    };
+    let Some(mut remaining) = value.strip_prefix(first) else {
+        return false;
+    };
+    if rest.is_empty() {
+        return prefix || remaining.is_empty();
+    }
+    let (last, middle) = rest.split_last().unwrap();
+    for part in middle {
+        match remaining.find(part) {
+            Some(index) => remaining = &remaining[index + part.len()..],
+            None => return false,
+        }
+    }
+    prefix || remaining.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_filter() {
+        let search = Search::parse(r#"status:error os:ubuntu label:rack=b12 mac:AA-BB* "web 01" aa:bb"#).unwrap();
+        assert_eq!(search.terms, vec![
+            Term::Status("Error"),
+            Term::Os("ubuntu".to_string()),
+            Term::Label { field: "rack".to_string(), value: Some("b12".to_string()) },
+            Term::Mac("aa:bb*".to_string()),
+            Term::Text("web 01".to_string()),
+            Term::Text("aa:bb".to_string()),
+        ]);
+        let filter = search.to_filter();
+        assert_eq!(filter.sql.matches('?').count(), filter.binds.len());
+        assert_eq!(filter.binds, vec!["ubuntu%", "ubuntu%", "rack", "b12", "aa:bb%", "\"web 01\"", "\"aa:bb\""]);
+
+        let short = Search::parse("status:ready a_").unwrap().to_filter();
+        assert_eq!(short.binds[..2], ["\"Ready\"".to_string(), "Ready".to_string()]);
+        assert_eq!(short.binds[2], "%a\\_%");
+        assert_eq!(Search::parse("").unwrap().to_filter().sql, "1 = 1");
+
+        assert!(Search::parse("status:melted").is_err());
+        assert!(Search::parse("os:").is_err());
+        assert!(Search::parse("label:=b12").is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(glob_matches("aa:bb*", "AA:BB:CC:DD:EE:FF", false));
+        assert!(!glob_matches("aa:bb", "aa:bb:cc", false));
+        assert!(glob_matches("ubuntu", "ubuntu-2204", true));
+        assert!(glob_matches("*-2204", "ubuntu-2204", false));
+        assert!(glob_matches("10.*.5.*", "10.0.5.20", false));
+        assert!(!glob_matches("10.*.6.*", "10.0.5.20", false));
+    }
+
+    #[tokio::test]
+    async fn test_existing_os_finds_legacy_rows() {
+        let pool = crate::test_support::database().await;
+        for (mac, status) in [("aa:00", "\"ExistingOS\""), ("aa:01", "Existing OS"), ("aa:02", "ExistingOS: Debian 12"), ("aa:03", "\"Ready\"")] {
+            sqlx::query("INSERT INTO machines (id, mac_address, ip_address, status, created_at, updated_at) VALUES (?, ?, '10.0.0.5', ?, ?, ?)")
+                .bind(uuid::Uuid::new_v4().to_string())
+                .bind(mac)
+                .bind(status)
+                .bind(chrono::Utc::now().to_rfc3339())
+                .bind(chrono::Utc::now().to_rfc3339())
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let found = crate::db::find_machines(&pool, &Search::parse("status:existing").unwrap()).await.unwrap();
+        let mut macs: Vec<&str> = found.iter().map(|machine| machine.mac_address.as_str()).collect();
+        macs.sort();
+        assert_eq!(macs, ["aa:00", "aa:01", "aa:02"]);
+    }
+}
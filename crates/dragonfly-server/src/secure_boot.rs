@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt::Write;
+
+use dragonfly_common::models::Machine;
+
+use crate::boot_arch::{BootArch, BootTarget, Firmware};
+
+/// Signed image key used for machines Dragonfly has never seen
+pub const DISCOVERY_IMAGE: &str = "discovery";
+
+/// Whether Secure Boot machines get the signed shim+GRUB chain. Off by default, as it needs
+/// signed kernels registered for every template those machines will boot.
+pub fn chain_enabled() -> bool {
+    env::var("DRAGONFLY_SECURE_BOOT_CHAIN")
+        .map(|s| s.parse().unwrap_or(false))
+        .unwrap_or(false)
+}
+
+/// Path of the signed shim for a target, relative to `/ipxe/`. Shim loads `grubx64.efi`/`grubaa64.efi`
+/// from the same directory. `None` for BIOS machines, which have no Secure Boot.
+pub fn shim_path(target: &BootTarget) -> Option<&'static str> {
+    match (target.arch, target.firmware) {
+        (_, Firmware::Bios) => None,
+        (BootArch::X86_64, Firmware::Uefi) => Some("bootloader/secureboot/shimx64.efi"),
+        (BootArch::Aarch64, Firmware::Uefi) => Some("bootloader/secureboot/shimaa64.efi"),
+    }
+}
+
+/// Signing metadata for the kernel and initrd a template boots under Secure Boot.
+/// Keyed by OS template name (e.g. `ubuntu-2204`), or `discovery` for unknown machines.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedBootImage {
+    #[serde(default)]
+    pub template: String,
+    /// URL of a kernel signed by a key the machines' shim trusts
+    pub kernel: String,
+    pub initrd: String,
+    #[serde(default)]
+    pub cmdline: String,
+    /// Certificate or vendor the kernel is signed with, for reference (e.g. "Canonical Ltd. Secure Boot Signing")
+    #[serde(default)]
+    pub signer: Option<String>,
+}
+
+/// Turn an http(s) URL into a GRUB device path, e.g. `(http,10.0.0.1:3000)/ipxe/vmlinuz`.
+/// GRUB only speaks plain HTTP, so https URLs are rejected.
+pub fn grub_path(url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    if url.scheme() != "http" {
+        return None;
+    }
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str()?, port),
+        None => url.host_str()?.to_string(),
+    };
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    Some(format!("(http,{}){}", host, path))
+}
+
+/// Check that an image's URLs can be fetched by GRUB
+pub fn validate(image: &SignedBootImage) -> Result<(), String> {
+    for (field, url) in [("kernel", &image.kernel), ("initrd", &image.initrd)] {
+        if grub_path(url).is_none() {
+            return Err(format!("{} must be a plain http:// URL GRUB can fetch, got '{}'", field, url));
+        }
+    }
+    Ok(())
+}
+
+/// GRUB config that shows a message and falls back to the next boot device (local disk)
+pub fn local_boot_config(message: &str) -> String {
+    let mut out = String::from("set timeout=0\n");
+    let _ = writeln!(out, "echo \"{}\"", message.replace('"', "'"));
+    out.push_str("echo \"Booting from local disk...\"\nsleep 5\nexit\n");
+    out
+}
+
+/// The signed image a machine should boot: its assigned OS template, or the discovery image
+pub fn image_key(machine: Option<&Machine>) -> &str {
+    machine
+        .and_then(|m| m.os_choice.as_deref())
+        .unwrap_or(DISCOVERY_IMAGE)
+}
+
+/// GRUB config for the first request, handing off to the per-machine config
+pub fn bootstrap_config() -> String {
+    "set timeout=0\nconfigfile ${prefix}/${net_default_mac}.cfg\n".to_string()
+}
+
+/// GRUB config for a machine booting through the signed chain.
+/// Machines without a signed image for what they should run are sent to local disk.
+pub fn render_config(mac: &str, machine: Option<&Machine>, image: Option<&SignedBootImage>) -> String {
+    let image = match image {
+        Some(image) => image,
+        None => {
+            let what = image_key(machine);
+            return local_boot_config(&format!("Dragonfly has no signed kernel for '{}', so {} cannot be booted with Secure Boot enabled.", what, mac));
+        }
+    };
+
+    // validate() guarantees these, but the image may have been stored by an older version
+    let (Some(kernel), Some(initrd)) = (grub_path(&image.kernel), grub_path(&image.initrd)) else {
+        return local_boot_config(&format!("Signed image for '{}' has invalid URLs.", image.template));
+    };
+
+    let mut out = String::from("set timeout=0\n");
+    let _ = writeln!(out, "echo \"Loading signed {} image via Dragonfly...\"", image.template);
+    let _ = writeln!(out, "linux {} {} worker_id={}", kernel, image.cmdline, mac);
+    let _ = writeln!(out, "initrd {}", initrd);
+    out.push_str("boot\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> SignedBootImage {
+        SignedBootImage {
+            template: "ubuntu-2204".to_string(),
+            kernel: "http://10.0.0.1:3000/ipxe/ubuntu/jammy/vmlinuz".to_string(),
+            initrd: "http://10.0.0.1:3000/ipxe/ubuntu/jammy/initrd".to_string(),
+            cmdline: "ip=dhcp".to_string(),
+            signer: None,
+        }
+    }
+
+    #[test]
+    fn test_grub_paths() {
+        assert_eq!(grub_path("http://10.0.0.1:3000/ipxe/vmlinuz").unwrap(), "(http,10.0.0.1:3000)/ipxe/vmlinuz");
+        assert_eq!(grub_path("http://boot.example.com/linux").unwrap(), "(http,boot.example.com)/linux");
+        assert!(grub_path("https://boot.example.com/linux").is_none());
+    }
+
+    #[test]
+    fn test_render_config() {
+        let config = render_config("aa:bb:cc:dd:ee:ff", None, Some(&image()));
+        assert!(config.contains("linux (http,10.0.0.1:3000)/ipxe/ubuntu/jammy/vmlinuz ip=dhcp worker_id=aa:bb:cc:dd:ee:ff"));
+        assert!(config.contains("initrd (http,10.0.0.1:3000)/ipxe/ubuntu/jammy/initrd"));
+
+        let config = render_config("aa:bb:cc:dd:ee:ff", None, None);
+        assert!(config.contains("no signed kernel for 'discovery'"));
+        assert!(config.contains("exit"));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&image()).is_ok());
+        let mut bad = image();
+        bad.kernel = "https://example.com/vmlinuz".to_string();
+        assert!(validate(&bad).is_err());
+        let mut bad = image();
+        bad.initrd = "(http,10.0.0.1)/initrd".to_string();
+        assert!(validate(&bad).is_err());
+    }
+
+    #[test]
+    fn test_shim_paths() {
+        let arm = BootTarget::from_client_arch(11).unwrap();
+        assert_eq!(shim_path(&arm), Some("bootloader/secureboot/shimaa64.efi"));
+        assert_eq!(shim_path(&BootTarget::from_client_arch(0).unwrap()), None);
+    }
+}
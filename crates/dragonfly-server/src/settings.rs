@@ -0,0 +1,210 @@
+//! Application settings that change while the server runs.
+//!
+//! Settings are saved in the database and held in `AppState::settings`. [`publish`] is the one way
+//! a change reaches the rest of the server: it replaces the shared settings and the copy that
+//! [`current`] reads, and tells open pages, so nothing needs a restart to see them.
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{watch, Mutex};
+
+use crate::auth::Settings;
+use crate::boot_filter::BootFilter;
+use crate::hostname_policy::HostnamePolicy;
+use crate::status_board::StatusBoardConfig;
+
+/// Themes a browser can start with before it has picked its own
+pub const THEMES: &[&str] = &["light", "dark", "system"];
+
+static CHANNEL: Lazy<watch::Sender<Settings>> = Lazy::new(|| watch::channel(Settings::default()).0);
+
+/// The settings as `/api/settings` shows them; credentials are left out
+#[derive(Debug, Serialize)]
+pub struct SettingsView {
+    pub require_login: bool,
+    pub require_approval: bool,
+    pub generate_root_passwords: bool,
+    pub block_eol_assignments: bool,
+    pub default_os: Option<String>,
+    pub default_theme: Option<String>,
+    pub hostname_policy: HostnamePolicy,
+    pub boot_filter: BootFilter,
+    pub require_boot_auth: bool,
+    pub boot_auth_exempt_subnets: Vec<String>,
+    pub status_board: StatusBoardConfig,
+    pub setup_completed: bool,
+}
+
+impl From<&Settings> for SettingsView {
+    fn from(settings: &Settings) -> Self {
+        Self {
+            require_login: settings.require_login,
+            require_approval: settings.require_approval,
+            generate_root_passwords: settings.generate_root_passwords,
+            block_eol_assignments: settings.block_eol_assignments,
+            default_os: settings.default_os.clone(),
+            default_theme: settings.default_theme.clone(),
+            hostname_policy: settings.hostname_policy.clone(),
+            boot_filter: settings.boot_filter.clone(),
+            require_boot_auth: settings.require_boot_auth,
+            boot_auth_exempt_subnets: settings.boot_auth_exempt_subnets.clone(),
+            status_board: settings.status_board.clone(),
+            setup_completed: settings.setup_completed,
+        }
+    }
+}
+
+/// A change to the settings. Fields left out keep their current value; an empty `default_os` or
+/// `default_theme` clears it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SettingsUpdate {
+    pub require_login: Option<bool>,
+    pub require_approval: Option<bool>,
+    pub generate_root_passwords: Option<bool>,
+    pub block_eol_assignments: Option<bool>,
+    pub default_os: Option<String>,
+    pub default_theme: Option<String>,
+    pub hostname_policy: Option<HostnamePolicy>,
+    pub boot_filter: Option<BootFilter>,
+    pub require_boot_auth: Option<bool>,
+    pub boot_auth_exempt_subnets: Option<Vec<String>>,
+    pub status_board: Option<StatusBoardConfig>,
+}
+
+fn valid_hostname_prefix(prefix: &str) -> bool {
+    prefix.len() <= 50
+        && !prefix.starts_with('-')
+        && prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+pub fn validate_hostname_policy(policy: &HostnamePolicy) -> Result<(), String> {
+    match policy {
+        HostnamePolicy::None => Ok(()),
+        HostnamePolicy::Sequence { prefix, width, .. } => {
+            if !valid_hostname_prefix(prefix) {
+                return Err(format!("'{}' can't start a hostname", prefix));
+            }
+            if !(1..=10).contains(width) {
+                return Err("Sequence numbers must be 1 to 10 digits wide".to_string());
+            }
+            Ok(())
+        },
+        HostnamePolicy::MacDerived { prefix } if !valid_hostname_prefix(prefix) => {
+            Err(format!("'{}' can't start a hostname", prefix))
+        },
+        HostnamePolicy::MacDerived { .. } => Ok(()),
+        HostnamePolicy::Csv { source } if source.trim().is_empty() => {
+            Err("A CSV hostname policy needs a file path or URL".to_string())
+        },
+        HostnamePolicy::Csv { .. } => Ok(()),
+    }
+}
+
+impl SettingsUpdate {
+    /// The current settings with this change made, or why it can't be
+    pub fn apply_to(self, current: &Settings) -> Result<Settings, String> {
+        let mut settings = current.clone();
+        if let Some(require_login) = self.require_login {
+            settings.require_login = require_login;
+        }
+        if let Some(require_approval) = self.require_approval {
+            settings.require_approval = require_approval;
+        }
+        if let Some(generate_root_passwords) = self.generate_root_passwords {
+            settings.generate_root_passwords = generate_root_passwords;
+        }
+        if let Some(block_eol_assignments) = self.block_eol_assignments {
+            settings.block_eol_assignments = block_eol_assignments;
+        }
+        if let Some(default_os) = self.default_os {
+            settings.default_os = Some(default_os.trim().to_string()).filter(|os| !os.is_empty());
+        }
+        if let Some(default_theme) = self.default_theme {
+            let default_theme = default_theme.trim().to_string();
+            if !default_theme.is_empty() && !THEMES.contains(&default_theme.as_str()) {
+                return Err(format!("Unknown theme '{}'; use one of {}", default_theme, THEMES.join(", ")));
+            }
+            settings.default_theme = Some(default_theme).filter(|theme| !theme.is_empty());
+        }
+        if let Some(policy) = self.hostname_policy {
+            validate_hostname_policy(&policy)?;
+            settings.hostname_policy = policy;
+        }
+        if let Some(filter) = self.boot_filter {
+            // Parse the rules the same way the settings form does, so bad ones are refused here
+            settings.boot_filter = BootFilter::from_lists(&filter.allow.join("\n"), &filter.deny.join("\n"))
+                .map_err(|e| e.to_string())?;
+        }
+        if let Some(require_boot_auth) = self.require_boot_auth {
+            settings.require_boot_auth = require_boot_auth;
+        }
+        if let Some(subnets) = self.boot_auth_exempt_subnets {
+            settings.boot_auth_exempt_subnets = crate::access::parse_subnets(&subnets.join("\n")).map_err(|e| e.to_string())?;
+        }
+        crate::access::check_boot_auth(settings.require_boot_auth, &settings.boot_auth_exempt_subnets)?;
+        if let Some(status_board) = self.status_board {
+            crate::status_board::validate(&status_board)?;
+            settings.status_board = status_board;
+        }
+        Ok(settings)
+    }
+}
+
+/// Seed [`current`] with the settings loaded at startup
+pub fn init(settings: Settings) {
+    CHANNEL.send_replace(settings);
+}
+
+/// Make saved settings live: replace the shared copy and the one [`current`] reads, then tell open pages
+pub async fn publish(shared: &Arc<Mutex<Settings>>, settings: Settings) {
+    *shared.lock().await = settings.clone();
+    CHANNEL.send_replace(settings);
+    if let Ok(event_manager) = crate::EVENT_MANAGER_REF.read() {
+        if let Some(event_manager) = event_manager.as_ref() {
+            let _ = event_manager.send("settings_updated".to_string());
+        }
+    }
+}
+
+/// The live settings, for code without access to the app state
+pub fn current() -> Settings {
+    CHANNEL.borrow().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_validation() {
+        let current = Settings::default();
+        let update: SettingsUpdate = serde_json::from_str(r#"{"require_login": true, "default_theme": "dark"}"#).unwrap();
+        let updated = update.apply_to(&current).unwrap();
+        assert!(updated.require_login);
+        assert_eq!(updated.default_theme.as_deref(), Some("dark"));
+        assert_eq!(updated.require_approval, current.require_approval);
+
+        let cleared = SettingsUpdate { default_theme: Some(String::new()), ..Default::default() };
+        assert_eq!(cleared.apply_to(&updated).unwrap().default_theme, None);
+
+        let bad_theme = SettingsUpdate { default_theme: Some("neon".to_string()), ..Default::default() };
+        assert!(bad_theme.apply_to(&current).is_err());
+        let bad_policy = SettingsUpdate {
+            hostname_policy: Some(HostnamePolicy::Sequence { prefix: "-node".to_string(), start: 1, width: 3 }),
+            ..Default::default()
+        };
+        assert!(bad_policy.apply_to(&current).is_err());
+        let bad_filter = SettingsUpdate {
+            boot_filter: Some(BootFilter { allow: vec!["not-a-rule".to_string()], deny: vec![] }),
+            ..Default::default()
+        };
+        assert!(bad_filter.apply_to(&current).is_err());
+        let locked_out = SettingsUpdate { require_boot_auth: Some(true), ..Default::default() };
+        assert!(locked_out.apply_to(&current).is_err());
+        let too_eager = SettingsUpdate { status_board: Some(StatusBoardConfig { enabled: true, title: None, refresh_secs: 1 }), ..Default::default() };
+        assert!(too_eager.apply_to(&current).is_err());
+        assert!(serde_json::from_str::<SettingsUpdate>(r#"{"admin_password_hash": "x"}"#).is_err());
+    }
+}
@@ -1,41 +1,200 @@
 use color_eyre::eyre::{Result, eyre};
 use color_eyre::eyre::WrapErr;
 use kube::{Client, Api, Error as KubeError};
+use kube::config::{Kubeconfig, KubeConfigOptions};
 use k8s_openapi::api::apps::v1::StatefulSet;
-use k8s_openapi::api::core::v1::{Service};
+use k8s_openapi::api::core::v1::{Pod, Service};
+use kube::api::{ListParams, LogParams};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tracing::{debug, warn, info};
 
-const DRAGONFLY_NAMESPACE: &str = "tink";
-const DRAGONFLY_STATEFULSET: &str = "dragonfly";
-const WEBUI_NAMESPACE: &str = "tink";
+const DEFAULT_NAMESPACE: &str = "tink";
+pub const DRAGONFLY_STATEFULSET: &str = "dragonfly";
 const WEBUI_SERVICE: &str = "tink-stack";
 const WEBUI_EXTERNAL_PORT: i32 = 3000;
+pub const CLUSTER_CONFIG_FILE: &str = "dragonfly-cluster.json";
+const DEFAULT_KUBE_TIMEOUT_SECS: u64 = 30;
+/// Tinkerbell components on the stack health page, by the `app` label of their pods
+pub const STACK_COMPONENTS: &[&str] = &["smee", "hegel", "tink-server", "rufio"];
 
-/// Checks if the Kubernetes API server is reachable by attempting to get the 'dragonfly' service in the 'tink' namespace.
+/// Records which cluster Dragonfly was installed into, written by `dragonfly install`.
+/// Absent for the default k3s install, which uses the default kubeconfig and the 'tink' namespace.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    /// Kubeconfig used to reach the cluster (None = default kubeconfig resolution)
+    pub kubeconfig: Option<String>,
+    /// Namespace Tinkerbell and Dragonfly are deployed into
+    pub namespace: String,
+    /// True when Dragonfly was installed into an existing cluster instead of a bundled k3s
+    pub existing_cluster: bool,
+    /// Kubeconfig context to use (None = current context)
+    #[serde(default)]
+    pub context: Option<String>,
+    /// Connect/read timeout for each Kubernetes API call, in seconds
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Interface booting machines reach Smee and the artifact server on (None = single-homed)
+    #[serde(default)]
+    pub provisioning_interface: Option<String>,
+    /// Interface the web UI is published on, when separate from the provisioning one
+    #[serde(default)]
+    pub management_interface: Option<String>,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            kubeconfig: None,
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            existing_cluster: false,
+            context: None,
+            timeout_secs: None,
+            provisioning_interface: None,
+            management_interface: None,
+        }
+    }
+}
+
+/// Path of the cluster config file, overridable with DRAGONFLY_CLUSTER_CONFIG
+pub fn cluster_config_path() -> PathBuf {
+    std::env::var("DRAGONFLY_CLUSTER_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(CLUSTER_CONFIG_FILE))
+}
+
+/// Loads the cluster config written by the installer, if there is one.
+pub fn load_cluster_config() -> Option<ClusterConfig> {
+    load_cluster_config_from(&cluster_config_path())
+}
+
+fn load_cluster_config_from(path: &Path) -> Option<ClusterConfig> {
+    let content = std::fs::read_to_string(path).ok()?;
+    match serde_json::from_str(&content) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            warn!("Ignoring invalid cluster config at {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Saves the cluster config so later status checks target the same cluster.
+pub fn save_cluster_config(config: &ClusterConfig) -> Result<()> {
+    save_cluster_config_to(&cluster_config_path(), config)
+}
+
+fn save_cluster_config_to(path: &Path, config: &ClusterConfig) -> Result<()> {
+    let content = serde_json::to_string_pretty(config)?;
+    std::fs::write(path, content)
+        .wrap_err_with(|| format!("Failed to write cluster config to {:?}", path))
+}
+
+/// Effective Kubernetes settings: the installer's cluster config, overridden by
+/// DRAGONFLY_KUBECONFIG, DRAGONFLY_KUBE_CONTEXT, DRAGONFLY_NAMESPACE and DRAGONFLY_KUBE_TIMEOUT.
+pub fn kube_settings() -> ClusterConfig {
+    with_kube_overrides(load_cluster_config().unwrap_or_default(), |name| std::env::var(name).ok())
+}
+
+fn with_kube_overrides(mut config: ClusterConfig, var: impl Fn(&str) -> Option<String>) -> ClusterConfig {
+    if let Some(path) = var("DRAGONFLY_KUBECONFIG") {
+        config.kubeconfig = Some(path);
+    }
+    if let Some(context) = var("DRAGONFLY_KUBE_CONTEXT") {
+        config.context = Some(context);
+    }
+    if let Some(namespace) = var("DRAGONFLY_NAMESPACE") {
+        config.namespace = namespace;
+    }
+    if let Some(timeout) = var("DRAGONFLY_KUBE_TIMEOUT").and_then(|v| v.parse().ok()) {
+        config.timeout_secs = Some(timeout);
+    }
+    config
+}
+
+/// Creates a Kubernetes client for the configured cluster and context.
+/// Without an explicit kubeconfig or context, uses the in-cluster config or the default kubeconfig.
+pub async fn cluster_client() -> Result<Client> {
+    #[cfg(feature = "chaos")]
+    crate::chaos::tinkerbell_fault().map_err(|e| eyre!(e))?;
+    let settings = kube_settings();
+    let options = KubeConfigOptions {
+        context: settings.context.clone(),
+        ..Default::default()
+    };
+
+    let mut config = match (&settings.kubeconfig, &settings.context) {
+        (Some(path), _) => {
+            debug!("Using kubeconfig {} (context: {:?})", path, settings.context);
+            let kubeconfig = Kubeconfig::read_from(path)
+                .wrap_err_with(|| format!("Failed to read kubeconfig {}", path))?;
+            kube::Config::from_custom_kubeconfig(kubeconfig, &options).await
+                .wrap_err_with(|| format!("Failed to load kubeconfig {} (context: {:?})", path, settings.context))?
+        }
+        (None, Some(context)) => {
+            debug!("Using default kubeconfig with context {}", context);
+            kube::Config::from_kubeconfig(&options).await
+                .wrap_err_with(|| format!("Failed to load context '{}' from the default kubeconfig", context))?
+        }
+        (None, None) => kube::Config::infer().await
+            .wrap_err("Failed to create Kubernetes client. Is k3s running and KUBECONFIG configured?")?,
+    };
+
+    let timeout = Duration::from_secs(settings.timeout_secs.unwrap_or(DEFAULT_KUBE_TIMEOUT_SECS));
+    config.connect_timeout = Some(timeout);
+    config.read_timeout = Some(timeout);
+
+    Client::try_from(config).wrap_err("Failed to create Kubernetes client")
+}
+
+/// Namespace Dragonfly is deployed into
+pub fn dragonfly_namespace() -> String {
+    kube_settings().namespace
+}
+
+/// Describes a Kubernetes API error, with a clear hint when RBAC permissions or credentials are the problem.
+pub fn describe_kube_error(e: &KubeError, action: &str) -> String {
+    match e {
+        KubeError::Api(ae) if ae.code == 403 => format!(
+            "Permission denied to {} in namespace '{}': {}. Grant the Dragonfly service account or kubeconfig user access with a Role/RoleBinding.",
+            action, dragonfly_namespace(), ae.message
+        ),
+        KubeError::Api(ae) if ae.code == 401 => format!(
+            "Unauthorized to {}: {}. The kubeconfig credentials may be invalid or expired.",
+            action, ae.message
+        ),
+        _ => format!("Failed to {}: {}", action, e),
+    }
+}
+
+/// Checks if the Kubernetes API server is reachable by attempting to get the 'dragonfly' service in the Dragonfly namespace.
 pub async fn check_kubernetes_connectivity() -> Result<()> {
-    debug!("Attempting to connect to Kubernetes API server by checking for 'dragonfly' service in 'tink' namespace...");
-    let client = Client::try_default().await
-        .wrap_err("Failed to create Kubernetes client. Is k3s running and KUBECONFIG configured?")?;
+    let namespace = dragonfly_namespace();
+    debug!("Attempting to connect to Kubernetes API server by checking for 'dragonfly' service in '{}' namespace...", namespace);
+    let client = cluster_client().await?;
 
-    // Get handle for Services in the 'tink' namespace
-    let services: Api<Service> = Api::namespaced(client, "tink");
+    // Get handle for Services in the Dragonfly namespace
+    let services: Api<Service> = Api::namespaced(client, &namespace);
 
     // Attempt to get the specific service
     match services.get("dragonfly").await {
         Ok(_) => {
             // Service found, connection is definitely working
-            debug!("Successfully connected to Kubernetes API server and found 'dragonfly' service in 'tink' namespace.");
+            debug!("Successfully connected to Kubernetes API server and found 'dragonfly' service in '{}' namespace.", namespace);
             Ok(())
         }
         Err(KubeError::Api(ae)) if ae.code == 404 => {
             // Service not found, but the API server responded, so connection is working
-            debug!("Successfully connected to Kubernetes API server (service 'dragonfly' not found in 'tink', but API responded).");
+            debug!("Successfully connected to Kubernetes API server (service 'dragonfly' not found in '{}', but API responded).", namespace);
             Ok(()) // Treat 404 as success for connectivity check
         }
         Err(e) => {
             // Other errors (network, auth, server error) indicate a connectivity problem
             debug!("Failed to get 'dragonfly' service: {}", e); // Log the actual KubeError
-            Err(e).wrap_err("Failed to query Kubernetes API server for 'dragonfly' service. Cluster might be unreachable, unresponsive, or permissions insufficient.")
+            Err(eyre!(describe_kube_error(&e, "get service 'dragonfly'")))
+                .wrap_err("Failed to query Kubernetes API server for 'dragonfly' service. Cluster might be unreachable or unresponsive.")
         }
     }
 }
@@ -43,8 +202,9 @@ pub async fn check_kubernetes_connectivity() -> Result<()> {
 /// Checks the status of the Dragonfly StatefulSet.
 /// Returns Ok(true) if ready, Ok(false) if not ready, Err if API call fails.
 pub async fn check_dragonfly_statefulset_status() -> Result<bool> {
-    debug!("Checking status of StatefulSet '{}/{}'...", DRAGONFLY_NAMESPACE, DRAGONFLY_STATEFULSET);
-    let client = match Client::try_default().await {
+    let namespace = dragonfly_namespace();
+    debug!("Checking status of StatefulSet '{}/{}'...", namespace, DRAGONFLY_STATEFULSET);
+    let client = match cluster_client().await {
         Ok(c) => c,
         Err(e) => {
             // If client creation fails, k8s is likely unavailable or not configured.
@@ -53,7 +213,7 @@ pub async fn check_dragonfly_statefulset_status() -> Result<bool> {
         }
     };
     
-    let sts: Api<StatefulSet> = Api::namespaced(client, DRAGONFLY_NAMESPACE);
+    let sts: Api<StatefulSet> = Api::namespaced(client, &namespace);
     
     match sts.get(DRAGONFLY_STATEFULSET).await {
         Ok(stateful_set) => {
@@ -64,35 +224,36 @@ pub async fn check_dragonfly_statefulset_status() -> Result<bool> {
             let ready_replicas = status.ready_replicas.unwrap_or(0);
             
             debug!("StatefulSet '{}/{}': Desired replicas = {}, Ready replicas = {}", 
-                   DRAGONFLY_NAMESPACE, DRAGONFLY_STATEFULSET, desired_replicas, ready_replicas);
+                   namespace, DRAGONFLY_STATEFULSET, desired_replicas, ready_replicas);
                    
             // Consider ready if desired > 0 and ready == desired
             if desired_replicas > 0 && ready_replicas == desired_replicas {
-                info!("StatefulSet '{}/{}' is ready.", DRAGONFLY_NAMESPACE, DRAGONFLY_STATEFULSET);
+                info!("StatefulSet '{}/{}' is ready.", namespace, DRAGONFLY_STATEFULSET);
                 Ok(true)
             } else {
                 debug!("StatefulSet '{}/{}' is not ready (desired={}, ready={}).", 
-                       DRAGONFLY_NAMESPACE, DRAGONFLY_STATEFULSET, desired_replicas, ready_replicas);
+                       namespace, DRAGONFLY_STATEFULSET, desired_replicas, ready_replicas);
                 Ok(false)
             }
         }
         Err(kube::Error::Api(ae)) if ae.code == 404 => {
-            debug!("StatefulSet '{}/{}' not found.", DRAGONFLY_NAMESPACE, DRAGONFLY_STATEFULSET);
+            debug!("StatefulSet '{}/{}' not found.", namespace, DRAGONFLY_STATEFULSET);
             Ok(false) // Not found means not ready
         }
         Err(e) => {
             // Other API errors are actual errors in checking status
-            Err(e).wrap_err_with(|| format!("Failed to get StatefulSet '{}/{}'", DRAGONFLY_NAMESPACE, DRAGONFLY_STATEFULSET))
+            Err(eyre!(describe_kube_error(&e, &format!("get StatefulSet '{}'", DRAGONFLY_STATEFULSET))))
         }
     }
 }
 
 /// Attempts to determine the WebUI access address by inspecting the Kubernetes Service.
 pub async fn get_webui_address() -> Result<Option<String>> {
-    debug!("Attempting to determine WebUI address from Service '{}/{}'...", WEBUI_NAMESPACE, WEBUI_SERVICE);
-    let client = Client::try_default().await.wrap_err("Failed to create Kubernetes client")?;
+    let namespace = dragonfly_namespace();
+    debug!("Attempting to determine WebUI address from Service '{}/{}'...", namespace, WEBUI_SERVICE);
+    let client = cluster_client().await?;
     
-    let services: Api<Service> = Api::namespaced(client, WEBUI_NAMESPACE);
+    let services: Api<Service> = Api::namespaced(client, &namespace);
     let service_name = WEBUI_SERVICE;
 
     match services.get(service_name).await {
@@ -162,11 +323,233 @@ pub async fn get_webui_address() -> Result<Option<String>> {
             }
         }
         Err(kube::Error::Api(ae)) if ae.code == 404 => {
-            warn!("WebUI Service '{}' not found in namespace '{}'.", service_name, WEBUI_NAMESPACE);
+            warn!("WebUI Service '{}' not found in namespace '{}'.", service_name, namespace);
             Ok(None) // Service not found
         }
         Err(e) => {
-            Err(e).wrap_err_with(|| format!("Failed to get Service '{}' in namespace '{}'", service_name, WEBUI_NAMESPACE))
+            Err(eyre!(describe_kube_error(&e, &format!("get Service '{}' in namespace '{}'", service_name, namespace))))
+        }
+    }
+} 
+
+/// One pod of a Tinkerbell component
+#[derive(Debug, Clone, Serialize)]
+pub struct PodHealth {
+    pub name: String,
+    pub phase: String,
+    pub ready: bool,
+    pub restarts: i32,
+    /// When a container of the pod last exited, and why
+    pub last_restart: Option<DateTime<Utc>>,
+    pub last_restart_reason: Option<String>,
+    pub node: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ComponentHealth {
+    pub name: String,
+    /// At least one pod, and all of them ready
+    pub healthy: bool,
+    /// Image tags the pods run; more than one means a rollout is in progress
+    pub versions: Vec<String>,
+    pub restarts: i32,
+    pub pods: Vec<PodHealth>,
+    pub logs_url: String,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StackHealth {
+    pub namespace: String,
+    pub kubernetes_reachable: bool,
+    pub healthy: bool,
+    pub components: Vec<ComponentHealth>,
+    pub checked_at: DateTime<Utc>,
+}
+
+/// The version in an image reference: its tag, or a short digest
+fn image_version(image: &str) -> String {
+    if let Some((_, digest)) = image.split_once('@') {
+        return digest.trim_start_matches("sha256:").chars().take(12).collect();
+    }
+    let name = image.rsplit('/').next().unwrap_or(image);
+    match name.split_once(':') {
+        Some((_, tag)) => tag.to_string(),
+        None => "latest".to_string(),
+    }
+}
+
+fn pod_health(pod: &Pod) -> PodHealth {
+    let status = pod.status.clone().unwrap_or_default();
+    let containers = status.container_statuses.unwrap_or_default();
+    let last_exit = containers.iter()
+        .filter_map(|c| c.last_state.as_ref()?.terminated.as_ref())
+        .max_by_key(|t| t.finished_at.as_ref().map(|time| time.0));
+    PodHealth {
+        name: pod.metadata.name.clone().unwrap_or_default(),
+        phase: status.phase.unwrap_or_else(|| "Unknown".to_string()),
+        ready: !containers.is_empty() && containers.iter().all(|c| c.ready),
+        restarts: containers.iter().map(|c| c.restart_count).sum(),
+        last_restart: last_exit.and_then(|t| t.finished_at.as_ref()).map(|time| time.0),
+        last_restart_reason: last_exit.and_then(|t| t.reason.clone()),
+        node: pod.spec.as_ref().and_then(|spec| spec.node_name.clone()),
+    }
+}
+
+async fn component_health(pods: &Api<Pod>, component: &str) -> ComponentHealth {
+    let mut health = ComponentHealth {
+        name: component.to_string(),
+        healthy: false,
+        versions: Vec::new(),
+        restarts: 0,
+        pods: Vec::new(),
+        logs_url: format!("/api/v1/stack/{}/logs", component),
+        error: None,
+    };
+    match pods.list(&ListParams::default().labels(&format!("app={}", component))).await {
+        Ok(list) => {
+            for pod in &list.items {
+                for container in pod.spec.iter().flat_map(|spec| &spec.containers) {
+                    let version = image_version(container.image.as_deref().unwrap_or_default());
+                    if !health.versions.contains(&version) {
+                        health.versions.push(version);
+                    }
+                }
+                health.pods.push(pod_health(pod));
+            }
+            health.restarts = health.pods.iter().map(|p| p.restarts).sum();
+            health.healthy = !health.pods.is_empty() && health.pods.iter().all(|p| p.ready);
+            if health.pods.is_empty() {
+                health.error = Some(format!("No pods labelled app={} found", component));
+            }
+        }
+        Err(e) => health.error = Some(describe_kube_error(&e, &format!("list {} pods", component))),
+    }
+    health
+}
+
+/// Pod status, versions and restarts of each Tinkerbell component
+pub async fn stack_health() -> StackHealth {
+    let namespace = dragonfly_namespace();
+    let client = match cluster_client().await {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Failed to create Kubernetes client for stack health: {}", e);
+            return StackHealth {
+                namespace,
+                kubernetes_reachable: false,
+                healthy: false,
+                components: Vec::new(),
+                checked_at: Utc::now(),
+            };
         }
+    };
+    let kubernetes_reachable = check_kubernetes_connectivity().await.is_ok();
+    let pods: Api<Pod> = Api::namespaced(client, &namespace);
+    let mut components = Vec::new();
+    for component in STACK_COMPONENTS {
+        components.push(component_health(&pods, component).await);
     }
-} 
\ No newline at end of file
+    StackHealth {
+        kubernetes_reachable,
+        healthy: components.iter().all(|c| c.healthy),
+        namespace,
+        components,
+        checked_at: Utc::now(),
+    }
+}
+
+/// The last lines of each of a component's pods' logs
+pub async fn component_logs(component: &str, tail_lines: i64) -> Result<String> {
+    if !STACK_COMPONENTS.contains(&component) {
+        return Err(eyre!("Unknown stack component '{}'", component));
+    }
+    let pods: Api<Pod> = Api::namespaced(cluster_client().await?, &dragonfly_namespace());
+    let list = pods.list(&ListParams::default().labels(&format!("app={}", component))).await
+        .map_err(|e| eyre!(describe_kube_error(&e, &format!("list {} pods", component))))?;
+
+    let mut logs = String::new();
+    for pod in list.items {
+        let name = pod.metadata.name.unwrap_or_default();
+        let params = LogParams { tail_lines: Some(tail_lines), timestamps: true, ..Default::default() };
+        logs.push_str(&format!("==> {} <==\n", name));
+        match pods.logs(&name, &params).await {
+            Ok(text) => logs.push_str(&text),
+            Err(e) => logs.push_str(&format!("{}\n", describe_kube_error(&e, &format!("read logs of pod '{}'", name)))),
+        }
+        logs.push('\n');
+    }
+    Ok(logs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_version() {
+        assert_eq!(image_version("quay.io/tinkerbell/smee:v0.15.1"), "v0.15.1");
+        assert_eq!(image_version("localhost:5000/hegel"), "latest");
+        assert_eq!(image_version("ghcr.io/tinkerbell/rufio@sha256:0123456789abcdef0123"), "0123456789ab");
+    }
+
+    #[test]
+    fn test_cluster_config_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CLUSTER_CONFIG_FILE);
+        assert_eq!(load_cluster_config_from(&path), None);
+
+        let config = ClusterConfig {
+            kubeconfig: Some("/etc/rancher/rke2/rke2.yaml".to_string()),
+            namespace: "provisioning".to_string(),
+            existing_cluster: true,
+            ..Default::default()
+        };
+        save_cluster_config_to(&path, &config).unwrap();
+        assert_eq!(load_cluster_config_from(&path), Some(config));
+
+        // Written before the context, timeout and interfaces were recorded
+        std::fs::write(&path, r#"{"kubeconfig": null, "namespace": "tink", "existing_cluster": false}"#).unwrap();
+        assert_eq!(load_cluster_config_from(&path), Some(ClusterConfig::default()));
+
+        std::fs::write(&path, "namespace: tink").unwrap();
+        assert_eq!(load_cluster_config_from(&path), None);
+    }
+
+    #[test]
+    fn test_kube_overrides() {
+        let installed = ClusterConfig {
+            kubeconfig: Some("/root/.kube/config".to_string()),
+            namespace: "provisioning".to_string(),
+            existing_cluster: true,
+            ..Default::default()
+        };
+        assert_eq!(with_kube_overrides(installed.clone(), |_| None), installed);
+
+        let env = std::collections::HashMap::from([
+            ("DRAGONFLY_KUBE_CONTEXT", "lab"),
+            ("DRAGONFLY_NAMESPACE", "metal"),
+            ("DRAGONFLY_KUBE_TIMEOUT", "soon"),
+        ]);
+        let config = with_kube_overrides(installed, |name| env.get(name).map(|v| v.to_string()));
+        assert_eq!(config.kubeconfig.as_deref(), Some("/root/.kube/config"));
+        assert_eq!(config.context.as_deref(), Some("lab"));
+        assert_eq!(config.namespace, "metal");
+        assert_eq!(config.timeout_secs, None);
+    }
+
+    #[test]
+    fn test_describe_kube_error() {
+        let api_error = |code: u16| KubeError::Api(kube::core::ErrorResponse {
+            status: "Failure".to_string(),
+            message: "workflows is forbidden".to_string(),
+            reason: "Forbidden".to_string(),
+            code,
+        });
+        let forbidden = describe_kube_error(&api_error(403), "list workflows");
+        assert!(forbidden.starts_with("Permission denied to list workflows"));
+        assert!(forbidden.contains("RoleBinding"));
+        assert!(describe_kube_error(&api_error(401), "list workflows").contains("credentials may be invalid or expired"));
+        assert!(describe_kube_error(&api_error(500), "list workflows").starts_with("Failed to list workflows"));
+    }
+}
@@ -0,0 +1,165 @@
+//! The public status board.
+//!
+//! A page for a wall monitor in the datacenter, served at `/status-board` to anyone once an admin
+//! turns it on. It shows how provisioning is going without saying which machine is which: counts
+//! by status and a progress bar per install, but no MACs, addresses, hostnames or error messages.
+//! The page reloads itself, so the monitor needs no login and no JavaScript.
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, Utc};
+use dragonfly_common::models::{Machine, MachineStatus};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::error;
+
+use crate::observability::status_label;
+use crate::AppState;
+
+/// Shortest and longest time between reloads of the page
+pub const REFRESH_RANGE: std::ops::RangeInclusive<u32> = 5..=3600;
+const MAX_TITLE: usize = 64;
+
+fn default_refresh_secs() -> u32 {
+    30
+}
+
+/// How the status board is set up, in the settings
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StatusBoardConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Heading of the page; "Dragonfly" when unset
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default = "default_refresh_secs")]
+    pub refresh_secs: u32,
+}
+
+impl Default for StatusBoardConfig {
+    fn default() -> Self {
+        Self { enabled: false, title: None, refresh_secs: default_refresh_secs() }
+    }
+}
+
+pub fn validate(config: &StatusBoardConfig) -> Result<(), String> {
+    if config.title.as_ref().is_some_and(|title| title.chars().count() > MAX_TITLE) {
+        return Err(format!("The status board title can be at most {} characters", MAX_TITLE));
+    }
+    if !REFRESH_RANGE.contains(&config.refresh_secs) {
+        return Err(format!(
+            "The status board must refresh every {} to {} seconds",
+            REFRESH_RANGE.start(), REFRESH_RANGE.end()
+        ));
+    }
+    Ok(())
+}
+
+/// An install as the board shows it: what is being installed and how far along it is
+#[derive(Debug, Serialize, PartialEq)]
+pub struct InstallBar {
+    pub os: Option<String>,
+    pub progress: u8,
+}
+
+/// What the page shows
+#[derive(Debug, Serialize)]
+pub struct Board {
+    pub title: String,
+    pub refresh_secs: u32,
+    pub total: usize,
+    /// Keyed like the `dragonfly_machines` metric, e.g. `installing_os`
+    pub by_status: BTreeMap<&'static str, usize>,
+    /// Furthest along first
+    pub installs: Vec<InstallBar>,
+    pub failed_24h: i64,
+    pub generated_at: DateTime<Utc>,
+}
+
+impl Board {
+    fn new(config: &StatusBoardConfig, machines: &[Machine], failed_24h: i64) -> Self {
+        let mut by_status: BTreeMap<&str, usize> = BTreeMap::new();
+        for machine in machines {
+            *by_status.entry(status_label(&machine.status)).or_default() += 1;
+        }
+        let mut installs: Vec<InstallBar> = machines.iter()
+            .filter(|machine| machine.status == MachineStatus::InstallingOS)
+            .map(|machine| InstallBar { os: machine.os_choice.clone(), progress: machine.installation_progress.min(100) })
+            .collect();
+        installs.sort_by_key(|install| std::cmp::Reverse(install.progress));
+
+        Self {
+            title: config.title.clone().filter(|title| !title.trim().is_empty()).unwrap_or_else(|| "Dragonfly".to_string()),
+            refresh_secs: config.refresh_secs,
+            total: machines.len(),
+            by_status,
+            installs,
+            failed_24h,
+            generated_at: Utc::now(),
+        }
+    }
+}
+
+/// The status board, or 404 while it is turned off
+pub async fn page(State(app_state): State<AppState>) -> Response {
+    let config = crate::settings::current().status_board;
+    if !config.enabled {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let machines = match crate::db::get_all_machines().await {
+        Ok(machines) => machines,
+        Err(e) => {
+            error!("Failed to load machines for the status board: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Status unavailable").into_response();
+        }
+    };
+    let failed_24h = match crate::db::count_install_outcomes(Utc::now() - Duration::hours(24)).await {
+        Ok((_, failed)) => failed,
+        Err(e) => {
+            error!("Failed to count install outcomes for the status board: {}", e);
+            0
+        }
+    };
+    crate::ui::render_minijinja(&app_state, "status_board.html", Board::new(&config, &machines, failed_24h))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_board_is_anonymous() {
+        let machine = |status: MachineStatus, progress: u8| Machine {
+            ip_address: "10.0.5.20".to_string(),
+            hostname: Some("db-01".to_string()),
+            os_choice: Some("debian-12".to_string()),
+            status,
+            memorable_name: Some("brave-otter".to_string()),
+            installation_progress: progress,
+            installation_step: Some("Writing image to db-01".to_string()),
+            ..crate::test_support::machine("bc:24:11:b9:54:89")
+        };
+        let machines = vec![
+            machine(MachineStatus::InstallingOS, 40),
+            machine(MachineStatus::InstallingOS, 75),
+            machine(MachineStatus::Error("disk on db-01 not found".to_string()), 0),
+            machine(MachineStatus::Ready, 0),
+        ];
+        let board = Board::new(&StatusBoardConfig { enabled: true, title: Some(" ".to_string()), refresh_secs: 10 }, &machines, 2);
+        assert_eq!(board.title, "Dragonfly");
+        assert_eq!(board.by_status["installing_os"], 2);
+        assert_eq!(board.installs[0], InstallBar { os: Some("debian-12".to_string()), progress: 75 });
+
+        let json = serde_json::to_string(&board).unwrap();
+        for identifying in ["bc:24:11", "10.0.5.20", "db-01", "brave-otter"] {
+            assert!(!json.contains(identifying), "the board gives away {}", identifying);
+        }
+
+        assert!(validate(&StatusBoardConfig::default()).is_ok());
+        assert!(validate(&StatusBoardConfig { refresh_secs: 1, ..Default::default() }).is_err());
+        assert!(validate(&StatusBoardConfig { title: Some("x".repeat(65)), ..Default::default() }).is_err());
+    }
+}
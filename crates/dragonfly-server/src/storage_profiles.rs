@@ -0,0 +1,400 @@
+use anyhow::Result;
+use dragonfly_common::models::{DiskInfo, Machine};
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::workflow_templates::format_partition;
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// Filesystems the storage script knows how to make
+const FILESYSTEMS: [&str; 4] = ["ext4", "xfs", "vfat", "swap"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RaidLevel {
+    #[default]
+    None,
+    Raid0,
+    Raid1,
+    Raid5,
+    Raid6,
+    Raid10,
+}
+
+impl RaidLevel {
+    /// Fewest disks the level can be built from
+    pub fn min_disks(&self) -> usize {
+        match self {
+            RaidLevel::None => 1,
+            RaidLevel::Raid0 | RaidLevel::Raid1 => 2,
+            RaidLevel::Raid5 => 3,
+            RaidLevel::Raid6 | RaidLevel::Raid10 => 4,
+        }
+    }
+
+    fn mdadm_level(&self) -> &'static str {
+        match self {
+            RaidLevel::None => "",
+            RaidLevel::Raid0 => "0",
+            RaidLevel::Raid1 => "1",
+            RaidLevel::Raid5 => "5",
+            RaidLevel::Raid6 => "6",
+            RaidLevel::Raid10 => "10",
+        }
+    }
+}
+
+/// Which of a machine's disks a profile uses. Matching disks are taken in device order.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DiskSelection {
+    /// How many disks to use; all matching disks when unset
+    #[serde(default)]
+    pub count: Option<usize>,
+    #[serde(default)]
+    pub min_size_gb: Option<u64>,
+    #[serde(default)]
+    pub max_size_gb: Option<u64>,
+    /// e.g. `/dev/nvme` to only use NVMe drives
+    #[serde(default)]
+    pub device_prefix: Option<String>,
+    #[serde(default)]
+    pub model_contains: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LayoutKind {
+    /// GPT partitions straight on the disk or array
+    #[default]
+    Partitions,
+    /// One LVM volume group, with a logical volume per entry
+    Lvm,
+    /// One ZFS pool, with a dataset per entry. ZFS does its own RAID, so mdadm isn't used.
+    Zfs,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Volume {
+    pub name: String,
+    /// e.g. `512M` or `100G`, or `rest` for the remaining space (last volume only)
+    pub size: String,
+    #[serde(default)]
+    pub filesystem: Option<String>,
+    #[serde(default)]
+    pub mountpoint: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageProfile {
+    pub name: String,
+    #[serde(default)]
+    pub raid: RaidLevel,
+    #[serde(default)]
+    pub disks: DiskSelection,
+    #[serde(default)]
+    pub layout: LayoutKind,
+    /// Volume group or pool name for LVM and ZFS layouts
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub volumes: Vec<Volume>,
+}
+
+impl StorageProfile {
+    fn group_name(&self) -> &str {
+        match (&self.group, self.layout) {
+            (Some(group), _) => group,
+            (None, LayoutKind::Zfs) => "rpool",
+            (None, _) => "vg0",
+        }
+    }
+}
+
+/// The disks a profile picked on a machine, and the block device the OS goes onto
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StoragePlan {
+    pub profile: String,
+    pub disks: Vec<String>,
+    pub target: String,
+}
+
+fn valid_size(size: &str) -> bool {
+    let Some(unit) = size.chars().last() else { return false };
+    matches!(unit, 'K' | 'M' | 'G' | 'T') && size.len() > 1 && size[..size.len() - 1].chars().all(|c| c.is_ascii_digit())
+}
+
+pub fn validate(profile: &StorageProfile) -> Result<(), String> {
+    crate::workflow_templates::validate_name(&profile.name)?;
+    if let Some(count) = profile.disks.count {
+        if count < profile.raid.min_disks() {
+            return Err(format!("{:?} needs at least {} disks", profile.raid, profile.raid.min_disks()));
+        }
+        if profile.raid == RaidLevel::Raid10 && count % 2 != 0 {
+            return Err("RAID 10 needs an even number of disks".to_string());
+        }
+    }
+    if let Some(group) = &profile.group {
+        if group.is_empty() || !group.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err("Volume group and pool names may only contain letters, digits, '-' and '_'".to_string());
+        }
+    }
+    for (i, volume) in profile.volumes.iter().enumerate() {
+        if volume.name.is_empty() || !volume.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(format!("'{}' isn't a valid volume name", volume.name));
+        }
+        if volume.size == "rest" {
+            if i + 1 != profile.volumes.len() {
+                return Err("Only the last volume can take the rest of the space".to_string());
+            }
+        } else if !valid_size(&volume.size) {
+            return Err(format!("Volume '{}' has size '{}'; use e.g. 512M, 100G or rest", volume.name, volume.size));
+        }
+        if let Some(fs) = &volume.filesystem {
+            if profile.layout == LayoutKind::Zfs {
+                return Err("ZFS datasets don't take a filesystem".to_string());
+            }
+            if !FILESYSTEMS.contains(&fs.as_str()) {
+                return Err(format!("Unknown filesystem '{}'; use one of {}", fs, FILESYSTEMS.join(", ")));
+            }
+        }
+        if let Some(mountpoint) = &volume.mountpoint {
+            if !mountpoint.starts_with('/') {
+                return Err(format!("Mountpoint '{}' must be an absolute path", mountpoint));
+            }
+            if !mountpoint.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '_' | '.' | '-')) {
+                return Err(format!("Mountpoint '{}' may only contain letters, digits, '/', '_', '.' and '-'", mountpoint));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn disk_matches(selection: &DiskSelection, disk: &DiskInfo) -> bool {
+    selection.min_size_gb.is_none_or(|min| disk.size_bytes >= min * GIB)
+        && selection.max_size_gb.is_none_or(|max| disk.size_bytes <= max * GIB)
+        && selection.device_prefix.as_ref().is_none_or(|prefix| disk.device.starts_with(prefix.as_str()))
+        && selection.model_contains.as_ref().is_none_or(|text| {
+            disk.model.as_ref().is_some_and(|model| model.to_lowercase().contains(&text.to_lowercase()))
+        })
+}
+
+/// Pick a machine's disks for a profile
+pub fn plan(profile: &StorageProfile, disks: &[DiskInfo]) -> Result<StoragePlan, String> {
+    let mut matching: Vec<&DiskInfo> = disks.iter().filter(|disk| disk_matches(&profile.disks, disk)).collect();
+    matching.sort_by(|a, b| a.device.cmp(&b.device));
+    // Without RAID only the first disk is used, so don't wipe the rest. RAID 10 is built from
+    // pairs, so an odd disk out is left alone.
+    let default_count = match profile.raid {
+        RaidLevel::None => profile.raid.min_disks(),
+        RaidLevel::Raid10 => matching.len() - matching.len() % 2,
+        _ => matching.len(),
+    };
+    let wanted = profile.disks.count.unwrap_or(default_count).max(profile.raid.min_disks());
+    if profile.raid == RaidLevel::Raid10 && wanted % 2 != 0 {
+        return Err(format!("Storage profile '{}' asks for {} disks, but RAID 10 needs an even number", profile.name, wanted));
+    }
+    if matching.len() < wanted {
+        return Err(format!(
+            "Storage profile '{}' needs {} disk(s) but only {} of the machine's {} match",
+            profile.name, wanted, matching.len(), disks.len()
+        ));
+    }
+    let disks: Vec<String> = matching.into_iter().take(wanted).map(|disk| disk.device.clone()).collect();
+    let target = match (profile.layout, profile.raid) {
+        (LayoutKind::Zfs, _) | (_, RaidLevel::None) => disks[0].clone(),
+        _ => "/dev/md0".to_string(),
+    };
+    Ok(StoragePlan { profile: profile.name.clone(), disks, target })
+}
+
+/// Device paths come from what the agent reported, so they are quoted wherever they reach the script
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn make_filesystem(script: &mut String, device: &str, filesystem: Option<&str>, label: &str) {
+    let device = shell_quote(device);
+    match filesystem {
+        Some("swap") => script.push_str(&format!("mkswap -L {} {}\n", label, device)),
+        Some("vfat") => script.push_str(&format!("mkfs.vfat -n {} {}\n", label.to_uppercase(), device)),
+        Some("xfs") => script.push_str(&format!("mkfs.xfs -f -L {} {}\n", label, device)),
+        Some(fs) => script.push_str(&format!("mkfs.{} -F -L {} {}\n", fs, label, device)),
+        None => {},
+    }
+}
+
+/// Shell script that wipes the planned disks and builds the profile's array and volumes on them
+pub fn render_script(profile: &StorageProfile, plan: &StoragePlan) -> String {
+    let disks = plan.disks.iter().map(|disk| shell_quote(disk)).collect::<Vec<_>>().join(" ");
+    let target = shell_quote(&plan.target);
+    // The disks are the positional parameters, so "$@" keeps each one a single word
+    let mut script = format!(
+        "#!/bin/sh\n# Storage profile '{}', rendered by Dragonfly\nset -eu\n\
+         if command -v apk >/dev/null; then apk add --no-cache mdadm sgdisk lvm2 e2fsprogs xfsprogs dosfstools wipefs zfs >/dev/null; fi\n\
+         set -- {}\nfor disk in \"$@\"; do\n    wipefs -a \"$disk\"\n    sgdisk --zap-all \"$disk\"\ndone\n",
+        profile.name, disks
+    );
+
+    if profile.raid != RaidLevel::None && profile.layout != LayoutKind::Zfs {
+        script.push_str(&format!(
+            "mdadm --create {} --run --metadata=1.2 --level={} --raid-devices={} \"$@\"\n",
+            target, profile.raid.mdadm_level(), plan.disks.len()
+        ));
+    }
+
+    match profile.layout {
+        LayoutKind::Partitions => {
+            for (i, volume) in profile.volumes.iter().enumerate() {
+                let size = if volume.size == "rest" { "0".to_string() } else { format!("+{}", volume.size) };
+                let type_code = match volume.filesystem.as_deref() {
+                    Some("vfat") => "ef00",
+                    Some("swap") => "8200",
+                    _ => "8300",
+                };
+                script.push_str(&format!("sgdisk -n {0}:0:{1} -t {0}:{2} -c {0}:{3} {4}\n", i + 1, size, type_code, volume.name, target));
+            }
+            script.push_str(&format!("partprobe {} || true\n", target));
+            for (i, volume) in profile.volumes.iter().enumerate() {
+                make_filesystem(&mut script, &format_partition(&plan.target, &(i + 1).to_string()), volume.filesystem.as_deref(), &volume.name);
+            }
+        },
+        LayoutKind::Lvm => {
+            let group = profile.group_name();
+            script.push_str(&format!("pvcreate -ff -y {0}\nvgcreate {1} {0}\n", target, group));
+            for volume in &profile.volumes {
+                let size = if volume.size == "rest" { "-l 100%FREE".to_string() } else { format!("-L {}", volume.size) };
+                script.push_str(&format!("lvcreate -y {} -n {} {}\n", size, volume.name, group));
+                make_filesystem(&mut script, &format!("/dev/{}/{}", group, volume.name), volume.filesystem.as_deref(), &volume.name);
+            }
+        },
+        LayoutKind::Zfs => {
+            let pool = profile.group_name();
+            let vdevs = match profile.raid {
+                RaidLevel::None | RaidLevel::Raid0 => disks.clone(),
+                RaidLevel::Raid1 => format!("mirror {}", disks),
+                RaidLevel::Raid5 => format!("raidz1 {}", disks),
+                RaidLevel::Raid6 => format!("raidz2 {}", disks),
+                RaidLevel::Raid10 => plan.disks.chunks_exact(2)
+                    .map(|pair| format!("mirror {} {}", shell_quote(&pair[0]), shell_quote(&pair[1])))
+                    .collect::<Vec<_>>().join(" "),
+            };
+            script.push_str(&format!("modprobe zfs\nzpool create -f -o ashift=12 -O mountpoint=none {} {}\n", pool, vdevs));
+            for volume in &profile.volumes {
+                let mut options = vec![format!("mountpoint={}", volume.mountpoint.as_deref().unwrap_or("none"))];
+                if volume.size != "rest" {
+                    options.push(format!("quota={}", volume.size));
+                }
+                script.push_str(&format!("zfs create -o {} {}/{}\n", options.join(" -o "), pool, volume.name));
+            }
+        },
+    }
+    script
+}
+
+/// The profile for a machine's install: its own, or else the one attached to the template
+pub async fn for_install(machine_id: &uuid::Uuid, template: &str) -> Result<Option<StorageProfile>> {
+    if let Some(profile) = db::get_machine_storage_profile(machine_id).await? {
+        return Ok(Some(profile));
+    }
+    db::get_template_storage_profile(template).await
+}
+
+/// Hardware map entries a workflow gets when a storage profile applies, so templates can use
+/// `{{.storage_target}}` in place of the first disk
+pub async fn workflow_vars(machine: &Machine, template: &str) -> Result<Vec<(String, String)>> {
+    let Some(profile) = for_install(&machine.id, template).await? else {
+        return Ok(Vec::new());
+    };
+    let plan = plan(&profile, &machine.disks).map_err(|e| anyhow::anyhow!(e))?;
+    Ok(vec![
+        ("storage_profile".to_string(), plan.profile),
+        ("storage_disks".to_string(), plan.disks.join(" ")),
+        ("storage_target".to_string(), plan.target),
+    ])
+}
+
+/// The storage script for a machine's current install, if a profile applies to it
+pub async fn script_for(machine: &Machine) -> Result<Option<String>> {
+    let template = match db::get_pipeline_run(&machine.id).await? {
+        Some(run) if run.state == crate::pipelines::RunState::Running => run.current_template().to_string(),
+        _ => machine.os_choice.clone().unwrap_or_else(|| "ubuntu-2204".to_string()),
+    };
+    let Some(profile) = for_install(&machine.id, &template).await? else {
+        return Ok(None);
+    };
+    let plan = plan(&profile, &machine.disks).map_err(|e| anyhow::anyhow!(e))?;
+    Ok(Some(render_script(&profile, &plan)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disk(device: &str, size_gb: u64, model: &str) -> DiskInfo {
+        DiskInfo { device: device.to_string(), size_bytes: size_gb * GIB, model: Some(model.to_string()), calculated_size: None }
+    }
+
+    #[test]
+    fn test_mirrored_nvme_plan() {
+        let profile = StorageProfile {
+            name: "nvme-mirror".to_string(),
+            raid: RaidLevel::Raid1,
+            disks: DiskSelection { count: Some(2), device_prefix: Some("/dev/nvme".to_string()), ..Default::default() },
+            layout: LayoutKind::Partitions,
+            group: None,
+            volumes: vec![
+                Volume { name: "root".to_string(), size: "100G".to_string(), filesystem: Some("ext4".to_string()), mountpoint: Some("/".to_string()) },
+                Volume { name: "data".to_string(), size: "rest".to_string(), filesystem: Some("xfs".to_string()), mountpoint: Some("/data".to_string()) },
+            ],
+        };
+        assert!(validate(&profile).is_ok());
+
+        let disks = [disk("/dev/sda", 4000, "HDD"), disk("/dev/nvme1n1", 960, "PM9A3"), disk("/dev/nvme0n1", 960, "PM9A3")];
+        let plan = plan(&profile, &disks).unwrap();
+        assert_eq!(plan.disks, vec!["/dev/nvme0n1", "/dev/nvme1n1"]);
+        assert_eq!(plan.target, "/dev/md0");
+
+        let script = render_script(&profile, &plan);
+        assert!(script.contains("--level=1 --raid-devices=2"));
+        assert!(script.contains("mkfs.xfs -f -L data '/dev/md0p2'"));
+        assert!(script.contains("set -- '/dev/nvme0n1' '/dev/nvme1n1'"));
+
+        // Only one disk is wiped without RAID, unless the profile asks for more
+        let single = StorageProfile { raid: RaidLevel::None, disks: DiskSelection::default(), ..profile.clone() };
+        assert_eq!(super::plan(&single, &disks).unwrap().disks, vec!["/dev/nvme0n1"]);
+
+        let hostile = StoragePlan { disks: vec!["/dev/sda$(reboot)".to_string()], target: "/dev/sda$(reboot)".to_string(), ..plan.clone() };
+        let script = render_script(&single, &hostile);
+        assert!(script.contains("'/dev/sda$(reboot)'"));
+        assert!(!script.contains(" /dev/sda$(reboot)"));
+
+        let bad_mountpoint = StorageProfile { volumes: vec![Volume { mountpoint: Some("/data;reboot".to_string()), ..profile.volumes[0].clone() }], ..profile.clone() };
+        assert!(validate(&bad_mountpoint).is_err());
+
+        let too_few = StorageProfile { disks: DiskSelection { count: Some(3), ..profile.disks.clone() }, ..profile.clone() };
+        assert!(super::plan(&too_few, &disks).is_err());
+        let bad_size = StorageProfile { volumes: vec![Volume { size: "lots".to_string(), ..profile.volumes[0].clone() }], ..profile };
+        assert!(validate(&bad_size).is_err());
+    }
+
+    #[test]
+    fn test_raid10_takes_disks_in_pairs() {
+        let profile = StorageProfile {
+            name: "zfs-striped-mirrors".to_string(),
+            raid: RaidLevel::Raid10,
+            disks: DiskSelection::default(),
+            layout: LayoutKind::Zfs,
+            group: None,
+            volumes: vec![Volume { name: "root".to_string(), size: "rest".to_string(), filesystem: None, mountpoint: Some("/".to_string()) }],
+        };
+        let disks: Vec<DiskInfo> = (0..5).map(|i| disk(&format!("/dev/sd{}", (b'a' + i) as char), 4000, "HDD")).collect();
+        let plan = plan(&profile, &disks).unwrap();
+        assert_eq!(plan.disks, vec!["/dev/sda", "/dev/sdb", "/dev/sdc", "/dev/sdd"]);
+        let script = render_script(&profile, &plan);
+        assert!(script.contains("rpool mirror '/dev/sda' '/dev/sdb' mirror '/dev/sdc' '/dev/sdd'\n"));
+
+        let odd = StorageProfile { disks: DiskSelection { count: Some(5), ..Default::default() }, ..profile };
+        assert!(super::plan(&odd, &disks).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use std::env;
+use std::sync::OnceLock;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Service name reported when `OTEL_SERVICE_NAME` isn't set
+pub const SERVICE_NAME: &str = "dragonfly";
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Whether spans should be exported: an OTLP endpoint is set, and the standard switches don't
+/// turn exporting off
+pub fn enabled() -> bool {
+    export_enabled(|var| env::var(var).ok())
+}
+
+fn export_enabled(var: impl Fn(&str) -> Option<String>) -> bool {
+    let set = |name: &str| var(name).is_some_and(|v| !v.trim().is_empty());
+    let disabled = var("OTEL_SDK_DISABLED").is_some_and(|v| v.eq_ignore_ascii_case("true"))
+        || var("OTEL_TRACES_EXPORTER").is_some_and(|v| v.eq_ignore_ascii_case("none"));
+    !disabled && (set("OTEL_EXPORTER_OTLP_ENDPOINT") || set("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"))
+}
+
+// The traces protocol wins over the general one, as in the OpenTelemetry specification
+fn protocol(var: impl Fn(&str) -> Option<String>) -> Result<Protocol, String> {
+    let protocol = var("OTEL_EXPORTER_OTLP_TRACES_PROTOCOL")
+        .or_else(|| var("OTEL_EXPORTER_OTLP_PROTOCOL"))
+        .unwrap_or_else(|| "http/protobuf".to_string());
+    match protocol.trim() {
+        "http/protobuf" => Ok(Protocol::HttpBinary),
+        "http/json" => Ok(Protocol::HttpJson),
+        // gRPC would need a Tokio-aware exporter; Jaeger and Tempo both take OTLP over HTTP on port 4318
+        other => Err(format!("Unsupported OTEL_EXPORTER_OTLP_PROTOCOL '{}'; use http/protobuf or http/json", other)),
+    }
+}
+
+fn exporter() -> Result<SpanExporter, String> {
+    SpanExporter::builder()
+        .with_http()
+        .with_protocol(protocol(|var| env::var(var).ok())?)
+        .build()
+        .map_err(|e| format!("Failed to create the OTLP span exporter: {}", e))
+}
+
+/// A layer exporting spans over OTLP, configured by the standard `OTEL_*` environment variables
+/// (endpoint, protocol, headers, service name, resource attributes and sampler). None when no
+/// endpoint is set.
+pub fn layer<S>() -> Result<Option<OpenTelemetryLayer<S, SdkTracer>>, String>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    if !enabled() {
+        return Ok(None);
+    }
+    let mut resource = Resource::builder();
+    if env::var("OTEL_SERVICE_NAME").is_err() {
+        resource = resource.with_service_name(SERVICE_NAME);
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter()?)
+        .with_resource(resource.build())
+        .build();
+    let tracer = provider.tracer(SERVICE_NAME);
+    let _ = PROVIDER.set(provider);
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Send any spans still buffered. Called once the server has stopped.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("Failed to flush OpenTelemetry spans: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_standard_variables() {
+        let vars = |pairs: &[(&str, &str)]| {
+            let vars: HashMap<String, String> = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            move |name: &str| vars.get(name).cloned()
+        };
+        assert!(!export_enabled(vars(&[])));
+        assert!(export_enabled(vars(&[("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", "http://tempo:4318/v1/traces")])));
+        assert!(!export_enabled(vars(&[("OTEL_EXPORTER_OTLP_ENDPOINT", "http://tempo:4318"), ("OTEL_SDK_DISABLED", "TRUE")])));
+        assert!(!export_enabled(vars(&[("OTEL_EXPORTER_OTLP_ENDPOINT", "http://tempo:4318"), ("OTEL_TRACES_EXPORTER", "none")])));
+
+        assert_eq!(protocol(vars(&[])), Ok(Protocol::HttpBinary));
+        assert_eq!(protocol(vars(&[("OTEL_EXPORTER_OTLP_PROTOCOL", "grpc"), ("OTEL_EXPORTER_OTLP_TRACES_PROTOCOL", "http/json")])), Ok(Protocol::HttpJson));
+        assert!(protocol(vars(&[("OTEL_EXPORTER_OTLP_PROTOCOL", "grpc")])).is_err());
+    }
+}
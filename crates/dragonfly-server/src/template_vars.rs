@@ -0,0 +1,287 @@
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chrono::{Duration, Utc};
+use dragonfly_common::models::Machine;
+use minijinja::{context, Environment};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::env;
+use thiserror::Error;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::db;
+use crate::machine_profiles::NetworkConfig;
+use crate::root_password::PasswordError;
+
+/// 32-byte key, hex-encoded, that secret template variables are encrypted with. Secret variables
+/// can't be stored or rendered when unset; plain variables still work.
+pub const KEY_ENV_VAR: &str = "DRAGONFLY_SECRETS_KEY";
+
+/// Shown in place of a secret's value. Saving a secret with this value keeps the stored one.
+pub const MASK: &str = "********";
+
+/// How long after a workflow is created its machine may fetch its rendered payloads
+pub const RENDER_TOKEN_TTL_HOURS: i64 = 24;
+
+const NONCE_LEN: usize = 12;
+
+/// A value substituted into provisioning payloads as `{{ vars.<name> }}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateVariable {
+    pub name: String,
+    pub value: String,
+    /// Encrypted at rest, masked in the API and UI, and only decrypted to render a payload
+    #[serde(default)]
+    pub secret: bool,
+}
+
+impl TemplateVariable {
+    /// The variable as it may be shown to anyone: secrets have their value replaced by `MASK`
+    pub fn masked(&self) -> Self {
+        match self.secret {
+            true => TemplateVariable { value: MASK.to_string(), ..self.clone() },
+            false => self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum VariableError {
+    #[error("Set DRAGONFLY_SECRETS_KEY on the server to use secret template variables")]
+    Disabled,
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Template variable not found")]
+    NotFound,
+    #[error(transparent)]
+    Database(#[from] anyhow::Error),
+}
+
+pub(crate) fn master_key() -> Result<Key, VariableError> {
+    let value = env::var(KEY_ENV_VAR).map_err(|_| VariableError::Disabled)?;
+    match hex::decode(value.trim()) {
+        Ok(bytes) if bytes.len() == 32 => Ok(*Key::from_slice(&bytes)),
+        _ => Err(VariableError::Invalid(format!("{} must be 64 hex characters", KEY_ENV_VAR))),
+    }
+}
+
+// What the value belongs to, such as the variable name, is bound in as associated data, so a
+// sealed value can't be moved to another row
+pub(crate) fn seal(key: &Key, aad: &[u8], value: &str) -> Result<String, VariableError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let sealed = ChaCha20Poly1305::new(key)
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: value.as_bytes(), aad })
+        .map_err(|_| VariableError::Invalid("Failed to encrypt secret".to_string()))?;
+    Ok(hex::encode([nonce.as_slice(), &sealed].concat()))
+}
+
+pub(crate) fn open(key: &Key, aad: &[u8], sealed: &str) -> Result<String, VariableError> {
+    let failed = || VariableError::Invalid("A secret can't be decrypted; was DRAGONFLY_SECRETS_KEY changed?".to_string());
+    let bytes = hex::decode(sealed).map_err(|_| failed())?;
+    if bytes.len() < NONCE_LEN {
+        return Err(failed());
+    }
+    let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+    let value = ChaCha20Poly1305::new(key)
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad })
+        .map_err(|_| failed())?;
+    String::from_utf8(value).map_err(|_| failed())
+}
+
+pub fn validate_name(name: &str) -> Result<(), VariableError> {
+    if name.is_empty() || name.len() > 64 || !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+        return Err(VariableError::Invalid("Variable names must be 1 to 64 lowercase letters, digits or '_'".to_string()));
+    }
+    Ok(())
+}
+
+/// Store a variable, encrypting it if it is secret. A secret saved with the masked value keeps its
+/// current value, so the UI can change a variable's other settings without knowing the secret.
+pub async fn save(variable: &TemplateVariable) -> Result<(), VariableError> {
+    validate_name(&variable.name)?;
+    if !variable.secret {
+        return Ok(db::save_template_variable(&variable.name, &variable.value, false).await?);
+    }
+
+    let key = master_key()?;
+    let value = match variable.value.as_str() {
+        MASK => match db::get_template_variable(&variable.name).await? {
+            Some((stored, true)) => open(&key, variable.name.as_bytes(), &stored)?,
+            Some((stored, false)) => stored,
+            None => return Err(VariableError::Invalid(format!("Give secret '{}' a value", variable.name))),
+        },
+        value => value.to_string(),
+    };
+    db::save_template_variable(&variable.name, &seal(&key, variable.name.as_bytes(), &value)?, true).await?;
+    Ok(())
+}
+
+pub async fn delete(name: &str) -> Result<(), VariableError> {
+    match db::delete_template_variable(name).await? {
+        true => Ok(()),
+        false => Err(VariableError::NotFound),
+    }
+}
+
+/// Every variable, masked
+pub async fn list() -> Result<Vec<TemplateVariable>, VariableError> {
+    Ok(db::get_template_variables().await?.iter().map(TemplateVariable::masked).collect())
+}
+
+/// Every variable with secrets decrypted, for rendering a payload and nothing else, along with
+/// the secret values so they can be masked out of anything logged
+async fn reveal_all() -> Result<(BTreeMap<String, String>, Vec<String>), VariableError> {
+    let variables = db::get_template_variables().await?;
+    let key = if variables.iter().any(|v| v.secret) { Some(master_key()?) } else { None };
+    let mut vars = BTreeMap::new();
+    let mut secrets = Vec::new();
+    for variable in variables {
+        let value = match (&key, variable.secret) {
+            (Some(key), true) => {
+                let value = open(key, variable.name.as_bytes(), &variable.value)?;
+                secrets.push(value.clone());
+                value
+            },
+            _ => variable.value,
+        };
+        vars.insert(variable.name, value);
+    }
+    Ok((vars, secrets))
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Issue the token a machine's install uses to fetch its rendered payloads, replacing any earlier one.
+/// Only a hash is stored, so the token is known only to the workflow it is handed to.
+pub async fn issue_render_token(machine_id: &Uuid) -> anyhow::Result<String> {
+    let token: String = rand::thread_rng().sample_iter(&Alphanumeric).take(40).map(char::from).collect();
+    let expires_at = Utc::now() + Duration::hours(RENDER_TOKEN_TTL_HOURS);
+    db::save_render_token(machine_id, &hash_token(&token), expires_at).await?;
+    Ok(token)
+}
+
+/// The machine a render token belongs to, if it is current
+pub async fn machine_for_token(token: &str) -> anyhow::Result<Option<Machine>> {
+    let Some(machine_id) = db::get_render_token_machine(&hash_token(token), Utc::now()).await? else {
+        return Ok(None);
+    };
+    db::get_machine_by_id(&machine_id).await
+}
+
+/// Replace every occurrence of a secret in some text with `MASK`, so rendered payloads and
+/// template errors can be logged safely
+pub fn mask_secrets(text: &str, secrets: &[&str]) -> String {
+    secrets.iter().filter(|s| !s.is_empty()).fold(text.to_string(), |text, secret| text.replace(secret, MASK))
+}
+
+fn render(source: &str, machine: &Machine, vars: &BTreeMap<String, String>, root_password: Option<&str>, network: Option<&NetworkConfig>) -> Result<String, minijinja::Error> {
+    let env = Environment::new();
+    let ctx = context! {
+        machine => machine,
+        hostname => machine.hostname.clone().or_else(|| machine.memorable_name.clone()),
+        vars => vars,
+        root_password => root_password,
+        network => network,
+    };
+    env.render_str(source, ctx)
+}
+
+async fn read_user_data_template(os: &str) -> Result<Option<String>, VariableError> {
+    // The name becomes a path, so nothing like `../` gets this far
+    crate::workflow_templates::validate_name(os).map_err(VariableError::Invalid)?;
+    let path = crate::os_templates::templates_dir().join(format!("{}.user-data", os));
+    match tokio::fs::read_to_string(path).await {
+        Ok(source) => Ok(Some(source)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(VariableError::Database(e.into())),
+    }
+}
+
+/// Render a machine's cloud-init user-data from `<os>.user-data` in the OS templates directory,
+/// with every template variable available as `{{ vars.<name> }}`, the machine's generated
+/// password, if any, as `{{ root_password }}` and its machine profile's network config as
+/// `{{ network }}`. Returns `None` when the machine's OS has no user-data template.
+pub async fn render_user_data(machine: &Machine) -> Result<Option<String>, VariableError> {
+    let os = machine.os_choice.as_deref().unwrap_or("ubuntu-2204");
+    let Some(source) = read_user_data_template(os).await? else {
+        return Ok(None);
+    };
+
+    let (vars, mut secrets) = reveal_all().await?;
+    let root_password = match crate::root_password::for_render(&machine.id).await {
+        Ok(password) => password,
+        Err(PasswordError::Secret(e)) => return Err(e),
+        Err(e) => return Err(VariableError::Database(anyhow::anyhow!("Failed to load root password: {}", e))),
+    };
+    secrets.extend(root_password.clone());
+    let network = crate::machine_profiles::network_for(&machine.id).await?;
+    match render(&source, machine, &vars, root_password.as_deref(), network.as_ref()) {
+        Ok(rendered) => {
+            info!("Rendered {} user-data for machine {}", os, machine.id);
+            Ok(Some(rendered))
+        },
+        Err(e) => {
+            let message = mask_secrets(&e.to_string(), &secrets.iter().map(String::as_str).collect::<Vec<_>>());
+            warn!("Failed to render {} user-data for machine {}: {}", os, machine.id, message);
+            Err(VariableError::Invalid(format!("Failed to render user-data: {}", message)))
+        }
+    }
+}
+
+/// Render `<os>.user-data` for a machine as an install would, but with secrets and the root
+/// password shown as `MASK`, so template authors can check it without decrypting anything
+pub async fn preview_user_data(machine: &Machine, os: &str) -> Result<Option<String>, VariableError> {
+    let Some(source) = read_user_data_template(os).await? else {
+        return Ok(None);
+    };
+    let vars = list().await?.into_iter().map(|v| (v.name, v.value)).collect();
+    let network = crate::machine_profiles::network_for(&machine.id).await?;
+    render(&source, machine, &vars, Some(MASK), network.as_ref())
+        .map(Some)
+        .map_err(|e| VariableError::Invalid(format!("Failed to render user-data: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_round_trip_and_masking() {
+        let key = Key::from([7u8; 32]);
+        let sealed = seal(&key, b"root_password_hash", "$6$salt$hash").unwrap();
+        assert_eq!(open(&key, b"root_password_hash", &sealed).unwrap(), "$6$salt$hash");
+        assert!(open(&key, b"license_key", &sealed).is_err());
+
+        let variable = TemplateVariable { name: "license_key".to_string(), value: "ABCD-1234".to_string(), secret: true };
+        assert_eq!(variable.masked().value, MASK);
+        assert_eq!(mask_secrets("key=ABCD-1234;", &["ABCD-1234", ""]), "key=********;");
+        assert!(validate_name("Root Password").is_err());
+    }
+    #[tokio::test]
+    async fn test_user_data_template_name_is_checked() {
+        let machine = crate::test_support::machine("52:54:00:12:34:56");
+        for os in ["../../etc/shadow", "ubuntu-2204/../x", ""] {
+            assert!(matches!(preview_user_data(&machine, os).await, Err(VariableError::Invalid(_))), "{:?} was read", os);
+        }
+    }
+    #[test]
+    fn test_shipped_user_data_sets_the_root_password() {
+        let machine = crate::test_support::machine("52:54:00:12:34:56");
+        for source in [include_str!("../../../os-templates/ubuntu-2204.user-data"), include_str!("../../../os-templates/ubuntu-2404.user-data")] {
+            let rendered = render(source, &machine, &BTreeMap::new(), Some("Xk3pQ9"), None).unwrap();
+            assert!(rendered.starts_with("#cloud-config"));
+            let config: serde_yaml::Value = serde_yaml::from_str(&rendered).unwrap();
+            let root = &config["chpasswd"]["users"][0];
+            assert_eq!((root["name"].as_str(), root["password"].as_str()), (Some("root"), Some("Xk3pQ9")));
+
+            let rendered = render(source, &machine, &BTreeMap::new(), None, None).unwrap();
+            assert!(!rendered.contains("chpasswd"));
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! Fixtures shared by the unit tests.
+
+use chrono::Utc;
+use dragonfly_common::models::{Machine, MachineStatus};
+use uuid::Uuid;
+
+/// An empty database with every migration applied, for tests of queries that take a pool
+pub async fn database() -> sqlx::SqlitePool {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+    crate::migrations::run(&pool).await.unwrap();
+    pool
+}
+
+/// A Ready machine with the given MAC at 10.0.0.5 and nothing else known about it. Tests set
+/// what they care about with struct update syntax.
+pub fn machine(mac: &str) -> Machine {
+    Machine {
+        id: Uuid::new_v4(),
+        mac_address: mac.to_string(),
+        ip_address: "10.0.0.5".to_string(),
+        hostname: None,
+        os_choice: None,
+        os_installed: None,
+        status: MachineStatus::Ready,
+        disks: Vec::new(),
+        nameservers: Vec::new(),
+        created_at: Utc::now(),
+        updated_at: Utc::now(),
+        memorable_name: None,
+        bmc_credentials: None,
+        installation_progress: 0,
+        installation_step: None,
+        last_deployment_duration: None,
+        cpu_model: None,
+        cpu_cores: None,
+        total_ram_bytes: None,
+        clock_skew_seconds: None,
+    }
+}
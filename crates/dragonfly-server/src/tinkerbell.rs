@@ -5,13 +5,18 @@ use kube::{
 };
 use serde::{Deserialize, Serialize};
 use tokio::sync::OnceCell;
-use tracing::{error, info, warn};
+use tracing::{error, info, instrument, warn, Instrument};
 use dragonfly_common::models::Machine;
 use std::str::FromStr;
 
 // Define a static Kubernetes client
 static KUBE_CLIENT: OnceCell<Client> = OnceCell::const_new();
 
+// Namespace holding Tinkerbell resources (configurable, defaults to "tink")
+fn namespace() -> String {
+    crate::status::dragonfly_namespace()
+}
+
 // Initialize the Kubernetes client using KUBECONFIG
 pub async fn init() -> Result<()> {
     // Expand the tilde in KUBECONFIG if present
@@ -26,9 +31,9 @@ pub async fn init() -> Result<()> {
         }
     }
     
-    // Create a new client using the current environment (KUBECONFIG)
-    let client = Client::try_default().await
-        .map_err(|e| anyhow!("Failed to create Kubernetes client: {}", e))?;
+    // Create a new client for the configured kubeconfig/context (falls back to KUBECONFIG)
+    let client = crate::status::cluster_client().await
+        .map_err(|e| anyhow!("{:#}", e))?;
     
     // Test the client to ensure it can connect to the cluster
     client
@@ -47,6 +52,8 @@ pub async fn init() -> Result<()> {
 
 // Get the Kubernetes client
 pub async fn get_client() -> Result<&'static Client> {
+    #[cfg(feature = "chaos")]
+    crate::chaos::tinkerbell_fault().map_err(|e| anyhow!(e))?;
     if KUBE_CLIENT.get().is_none() {
         info!("Kubernetes client not initialized, initializing now");
         
@@ -62,11 +69,11 @@ pub async fn get_client() -> Result<&'static Client> {
             }
         }
         
-        // Create a new client using the current environment (KUBECONFIG)
-        let client = match Client::try_default().await {
+        // Create a new client for the configured kubeconfig/context (falls back to KUBECONFIG)
+        let client = match crate::status::cluster_client().await {
             Ok(client) => client,
             Err(e) => {
-                return Err(anyhow!("Failed to create Kubernetes client: {}", e));
+                return Err(anyhow!("{:#}", e));
             }
         };
         
@@ -175,6 +182,7 @@ struct NetbootSpec {
 }
 
 // Register a machine with Tinkerbell
+#[instrument(skip_all, fields(machine_id = %machine.id, otel.kind = "client"))]
 pub async fn register_machine(machine: &Machine) -> Result<()> {
     // Get the Kubernetes client
     let client = match get_client().await {
@@ -186,7 +194,7 @@ pub async fn register_machine(machine: &Machine) -> Result<()> {
     };
     
     // Create a unique name for the hardware resource based on MAC address
-    let resource_name = format!("machine-{}", machine.mac_address.replace(":", "-"));
+    let resource_name = hardware_resource_name(&machine.mac_address);
     
     // --- Determine Hostname (Final Complete Rewrite) ---
     // Start with the fallback/default (MAC-based name)
@@ -197,8 +205,16 @@ pub async fn register_machine(machine: &Machine) -> Result<()> {
         resolved_hostname = hostname.clone();
     }
 
+    // When a hostname policy is active, the assigned hostname is authoritative
+    let policy_active = match crate::db::get_app_settings().await {
+        Ok(settings) => settings.hostname_policy.is_active() && machine.hostname.is_some(),
+        Err(_) => false,
+    };
+
     // Attempt reverse DNS lookup if we have a valid IP
-    if let Ok(ip_addr) = std::net::IpAddr::from_str(&machine.ip_address) {
+    if policy_active {
+        info!("Hostname policy active, using assigned hostname {} for {}", resolved_hostname, resource_name);
+    } else if let Ok(ip_addr) = std::net::IpAddr::from_str(&machine.ip_address) {
         // Create resolver - note: this function returns the resolver itself, not a Result
         let resolver = hickory_resolver::AsyncResolver::tokio(
             hickory_resolver::config::ResolverConfig::default(), 
@@ -237,6 +253,12 @@ async fn register_machine_internal(
 
     info!("Registering machine {} with Tinkerbell", resource_name);
     
+    // Smee picks the iPXE binary from these; fall back to x86_64 UEFI until the machine tells us otherwise
+    let boot_target = crate::db::get_boot_target(&machine.id).await
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    
     // Create the Hardware resource, focusing only on the specific fields we need to set
     // to reduce conflicts with other field managers
     let hardware = Hardware {
@@ -244,7 +266,7 @@ async fn register_machine_internal(
         kind: "Hardware".to_string(),
         metadata: Metadata {
             name: resource_name.to_string(),
-            namespace: "tink".to_string(),
+            namespace: namespace(),
             labels: None,
         },
         spec: HardwareSpec {
@@ -259,9 +281,10 @@ async fn register_machine_internal(
             }).collect()),
             interfaces: Some(vec![InterfaceSpec {
                 dhcp: Some(DHCPSpec {
-                    arch: Some("x86_64".to_string()),
+                    arch: Some(boot_target.arch.as_str().to_string()),
                     hostname: Some(resolved_hostname.to_string()),
-                    ip: Some(IPSpec {
+                    // Machines imported without an address get one from DHCP
+                    ip: (!machine.ip_address.is_empty()).then(|| IPSpec {
                         address: machine.ip_address.clone(),
                         gateway: None,
                         netmask: None,
@@ -269,7 +292,7 @@ async fn register_machine_internal(
                     lease_time: Some(86400),
                     mac: machine.mac_address.clone(),
                     name_servers: Some(machine.nameservers.clone()),
-                    uefi: Some(true),
+                    uefi: Some(boot_target.firmware == crate::boot_arch::Firmware::Uefi),
                 }),
                 netboot: Some(NetbootSpec {
                     allow_pxe: Some(true),
@@ -282,26 +305,14 @@ async fn register_machine_internal(
     // Convert the Hardware resource to JSON
     let hardware_json = serde_json::to_value(&hardware)?;
     
-    // Create the ApiResource for the Hardware CRD
-    let api_resource = kube::core::ApiResource {
-        group: "tinkerbell.org".to_string(),
-        version: "v1alpha1".to_string(),
-        kind: "Hardware".to_string(),
-        api_version: "tinkerbell.org/v1alpha1".to_string(),
-        plural: "hardware".to_string(),
-    };
-    
-    info!("Using Kubernetes API Resource: group={}, version={}, kind={}, plural={}", 
-          api_resource.group, api_resource.version, api_resource.kind, api_resource.plural);
-    
     // Create a dynamic API to interact with the Hardware custom resource
-    let api: Api<DynamicObject> = Api::namespaced_with(client.clone(), "tink", &api_resource);
+    let api = hardware_api(client.clone());
     
     // Create a DynamicObject from our hardware_json
     let mut dynamic_obj = DynamicObject {
         metadata: kube::core::ObjectMeta {
             name: Some(resource_name.to_string()),
-            namespace: Some("tink".to_string()),
+            namespace: Some(namespace()),
             ..Default::default()
         },
         types: Some(kube::core::TypeMeta {
@@ -332,8 +343,9 @@ async fn register_machine_internal(
                     Ok(())
                 },
                 Err(e) => {
-                    error!("Failed to update Hardware resource in Tinkerbell: {}", e);
-                    Err(anyhow!("Failed to update Hardware resource: {}", e))
+                    let message = crate::status::describe_kube_error(&e, "update Hardware resource");
+                    error!("{}", message);
+                    Err(anyhow!(message))
                 }
             }
         },
@@ -343,7 +355,7 @@ async fn register_machine_internal(
             // For creation, ensure we have a clean metadata without resourceVersion
             dynamic_obj.metadata = kube::core::ObjectMeta {
                 name: Some(resource_name.to_string()),
-                namespace: Some("tink".to_string()),
+                namespace: Some(namespace()),
                 ..Default::default()
             };
             
@@ -358,19 +370,47 @@ async fn register_machine_internal(
                     Ok(())
                 },
                 Err(e) => {
-                    error!("Failed to create Hardware resource in Tinkerbell: {}", e);
-                    Err(anyhow!("Failed to create Hardware resource: {}", e))
+                    let message = crate::status::describe_kube_error(&e, "create Hardware resource");
+                    error!("{}", message);
+                    Err(anyhow!(message))
                 }
             }
         },
         Err(e) => {
-            error!("Error checking Hardware resource in Tinkerbell: {}", e);
-            Err(anyhow!("Error checking Hardware resource: {}", e))
+            let message = crate::status::describe_kube_error(&e, "get Hardware resource");
+            error!("{}", message);
+            Err(anyhow!(message))
         }
     }
 }
 
+// Name of the Hardware resource register_machine creates for a MAC address
+pub fn hardware_resource_name(mac_address: &str) -> String {
+    format!("machine-{}", mac_address.replace(":", "-"))
+}
+
+/// Every Hardware resource in Dragonfly's namespace
+pub async fn list_hardware() -> Result<Vec<DynamicObject>> {
+    let client = get_client().await?;
+    let api = hardware_api(client.clone());
+    let list = api.list(&kube::api::ListParams::default()).await
+        .map_err(|e| anyhow!(crate::status::describe_kube_error(&e, "list Hardware resources")))?;
+    Ok(list.items)
+}
+
+// Name of the OS installation Workflow for a MAC address
+pub fn workflow_resource_name(mac_address: &str) -> String {
+    format!("os-install-{}", mac_address.replace(":", "-"))
+}
+
+// Names of the Hardware and Workflow resources delete_hardware removes for a MAC address
+pub fn deletion_targets(mac_address: &str) -> (String, String) {
+    let mac_address = mac_address.to_lowercase();
+    (hardware_resource_name(&mac_address), workflow_resource_name(&mac_address))
+}
+
 // Add this function to delete hardware resources
+#[instrument(skip_all, fields(mac_address = %mac_address, otel.kind = "client"))]
 pub async fn delete_hardware(mac_address: &str) -> Result<()> {
     // Get the Kubernetes client
     let client = match get_client().await {
@@ -381,42 +421,23 @@ pub async fn delete_hardware(mac_address: &str) -> Result<()> {
         }
     };
     
-    let resource_name = mac_address.to_lowercase();
+    let (resource_name, workflow_name) = deletion_targets(mac_address);
     info!("Deleting hardware resource from Tinkerbell: {}", resource_name);
     
-    // Create the ApiResource for the Hardware CRD
-    let api_resource = kube::core::ApiResource {
-        group: "tinkerbell.org".to_string(),
-        version: "v1alpha1".to_string(),
-        kind: "Hardware".to_string(),
-        api_version: "tinkerbell.org/v1alpha1".to_string(),
-        plural: "hardware".to_string(),
-    };
-    
     // Create a dynamic API to interact with the Hardware custom resource
-    let api: Api<DynamicObject> = Api::namespaced_with(client.clone(), "tink", &api_resource);
+    let api = hardware_api(client.clone());
     
     // Delete the hardware resource
     let hardware_result = api.delete(&resource_name, &kube::api::DeleteParams::default()).await;
 
     // Also delete any associated workflow
-    let workflow_name = format!("os-install-{}", mac_address.replace(":", "-"));
     info!("Deleting workflow resource from Tinkerbell: {}", workflow_name);
 
-    // Create the ApiResource for the Workflow CRD
-    let workflow_api_resource = kube::core::ApiResource {
-        group: "tinkerbell.org".to_string(),
-        version: "v1alpha1".to_string(),
-        kind: "Workflow".to_string(),
-        api_version: "tinkerbell.org/v1alpha1".to_string(),
-        plural: "workflows".to_string(),
-    };
-
     // Create a dynamic API to interact with the Workflow custom resource
-    let workflow_api: Api<DynamicObject> = Api::namespaced_with(client.clone(), "tink", &workflow_api_resource);
+    let workflows = workflow_api(client.clone());
 
     // Delete the workflow resource
-    let workflow_result = workflow_api.delete(&workflow_name, &kube::api::DeleteParams::default()).await;
+    let workflow_result = workflows.delete(&workflow_name, &kube::api::DeleteParams::default()).await;
 
     // Handle results
     match (hardware_result, workflow_result) {
@@ -437,18 +458,73 @@ pub async fn delete_hardware(mac_address: &str) -> Result<()> {
             Ok(())
         },
         (Err(e), _) => {
-            error!("Failed to delete hardware resource from Tinkerbell: {}", e);
-            Err(anyhow!("Failed to delete hardware resource: {}", e))
+            let message = crate::status::describe_kube_error(&e, "delete Hardware resource");
+            error!("{}", message);
+            Err(anyhow!(message))
         },
         (_, Err(e)) => {
-            error!("Failed to delete workflow resource from Tinkerbell: {}", e);
-            Err(anyhow!("Failed to delete workflow resource: {}", e))
+            let message = crate::status::describe_kube_error(&e, "delete Workflow resource");
+            error!("{}", message);
+            Err(anyhow!(message))
         }
     }
 }
 
 // Create a Workflow for OS installation
-pub async fn create_workflow(machine: &Machine, _os_choice: &str) -> Result<()> {
+#[instrument(skip_all, fields(machine_id = %machine.id, os_choice = %os_choice, otel.kind = "client"))]
+pub async fn create_workflow(machine: &Machine, os_choice: &str) -> Result<()> {
+    // Nothing new installs during a provisioning freeze, however it was requested
+    if let Some(freeze) = crate::freeze::current() {
+        return Err(anyhow!(crate::freeze::refusal(&freeze)));
+    }
+    
+    // Every install goes through here, so this is the last line of defence for project templates
+    if let Err(reason) = crate::projects::check_template(&machine.id, os_choice).await? {
+        return Err(anyhow!(reason));
+    }
+    
+    // Map OS choice to template reference
+    let os_template = match machine.os_choice.as_ref() {
+        Some(os) if os == "ubuntu-2204" => "ubuntu-2204",
+        Some(os) if os == "ubuntu-2404" => "ubuntu-2404",
+        Some(os) if os == "debian-12" => "debian-12",
+        Some(os) if os == "proxmox" => "proxmox",
+        Some(os) if os == "talos" => "talos",
+        Some(os) => os,
+        None => "ubuntu-2204", // Default if no OS choice is specified
+    };
+    
+    // Machines whose profile has a pipeline start with its first stage instead
+    let template_ref = match crate::pipelines::begin(&machine.id, os_template).await {
+        Ok(template) => template,
+        Err(e) => {
+            warn!("Failed to start pipeline for machine {}, installing the OS directly: {}", machine.id, e);
+            os_template.to_string()
+        }
+    };
+    
+    start_workflow(machine, &template_ref).await
+}
+
+// Move a machine on to the next stage of its pipeline. Every stage uses the same workflow
+// name, so the finished stage's workflow goes first.
+pub async fn start_pipeline_stage(machine: &Machine, run: &crate::pipelines::PipelineRun) -> Result<()> {
+    use dragonfly_common::models::MachineStatus;
+    use dragonfly_common::state_machine::StatusCause;
+    
+    let api = workflow_api(get_client().await?.clone());
+    match api.delete(&workflow_resource_name(&machine.mac_address), &kube::api::DeleteParams::default()).await {
+        Ok(_) => {},
+        Err(KubeError::Api(ae)) if ae.code == 404 => {},
+        Err(e) => return Err(anyhow!(crate::status::describe_kube_error(&e, "delete Workflow resource"))),
+    }
+    
+    crate::db::update_status(&machine.id, MachineStatus::InstallingOS, &StatusCause::PipelineStage(run.describe_stage())).await?;
+    start_workflow(machine, run.current_template()).await
+}
+
+// Create or replace a machine's workflow with one running the given template
+async fn start_workflow(machine: &Machine, template_ref: &str) -> Result<()> {
     // Get the Kubernetes client
     let client = match get_client().await {
         Ok(c) => c,
@@ -459,34 +535,15 @@ pub async fn create_workflow(machine: &Machine, _os_choice: &str) -> Result<()>
     };
     
     // Use MAC address without colons as part of the workflow name
-    let resource_name = format!("os-install-{}", machine.mac_address.replace(":", "-"));
+    let resource_name = workflow_resource_name(&machine.mac_address);
     
     // Hardware reference name (matches what we create in register_machine)
     let hardware_ref = format!("machine-{}", machine.mac_address.replace(":", "-"));
     
-    info!("Creating workflow {} for machine {}", resource_name, machine.id);
-    
-    // Map OS choice to template reference
-    let template_ref = match machine.os_choice.as_ref() {
-        Some(os) if os == "ubuntu-2204" => "ubuntu-2204",
-        Some(os) if os == "ubuntu-2404" => "ubuntu-2404",
-        Some(os) if os == "debian-12" => "debian-12",
-        Some(os) if os == "proxmox" => "proxmox",
-        Some(os) if os == "talos" => "talos",
-        Some(os) => os,
-        None => "ubuntu-2204", // Default if no OS choice is specified
-    };
+    info!("Creating workflow {} for machine {} with template {}", resource_name, machine.id, template_ref);
     
     // First check if the Template exists
-    let template_api_resource = kube::core::ApiResource {
-        group: "tinkerbell.org".to_string(),
-        version: "v1alpha1".to_string(),
-        kind: "Template".to_string(),
-        api_version: "tinkerbell.org/v1alpha1".to_string(),
-        plural: "templates".to_string(),
-    };
-    
-    let template_api: Api<DynamicObject> = Api::namespaced_with(client.clone(), "tink", &template_api_resource);
+    let template_api: Api<DynamicObject> = Api::namespaced_with(client.clone(), &namespace(), &tinkerbell_resource("Template", "templates"));
     
     match template_api.get(template_ref).await {
         Ok(_) => {
@@ -501,43 +558,56 @@ pub async fn create_workflow(machine: &Machine, _os_choice: &str) -> Result<()>
         }
     }
     
+    // Templates fetch secret-bearing payloads from /api/render/{{.render_token}}/ at install time,
+    // so secrets never end up in the Workflow or Template resources
+    let render_token = match crate::template_vars::issue_render_token(&machine.id).await {
+        Ok(token) => token,
+        Err(e) => {
+            warn!("Failed to issue render token for machine {}: {}", machine.id, e);
+            String::new()
+        }
+    };
+    
+    if let Err(e) = crate::root_password::issue_for_install(&machine.id).await {
+        warn!("Failed to generate root password for machine {}: {}", machine.id, e);
+    }
+    
+    // A storage profile picks the disks up front, so a machine that doesn't fit fails here
+    // rather than halfway through the install
+    let storage_vars = crate::storage_profiles::workflow_vars(machine, template_ref).await?;
+    let network_vars = crate::machine_profiles::workflow_vars(&machine.id).await?;
+    let arch_vars = crate::boot_arch::workflow_vars(&machine.id).await?;
+    
     // Create the Workflow resource
-    let workflow_json = serde_json::json!({
+    let mut workflow_json = serde_json::json!({
         "apiVersion": "tinkerbell.org/v1alpha1",
         "kind": "Workflow",
         "metadata": {
             "name": resource_name,
-            "namespace": "tink"
+            "namespace": namespace()
         },
         "spec": {
             "templateRef": template_ref,
             "hardwareRef": hardware_ref,
             "hardwareMap": {
-                "device_1": machine.mac_address
+                "device_1": machine.mac_address,
+                "machine_id": machine.id.to_string(),
+                "render_token": render_token
             }
         }
     });
-    
-    // Create the ApiResource for the Workflow CRD
-    let api_resource = kube::core::ApiResource {
-        group: "tinkerbell.org".to_string(),
-        version: "v1alpha1".to_string(),
-        kind: "Workflow".to_string(),
-        api_version: "tinkerbell.org/v1alpha1".to_string(),
-        plural: "workflows".to_string(),
-    };
-    
-    info!("Using Kubernetes API Resource for Workflow: group={}, version={}, kind={}, plural={}", 
-          api_resource.group, api_resource.version, api_resource.kind, api_resource.plural);
+    for (key, value) in storage_vars.into_iter().chain(network_vars).chain(arch_vars) {
+        workflow_json["spec"]["hardwareMap"][key] = serde_json::Value::String(value);
+    }
     
     // Create a dynamic API to interact with the Workflow custom resource
-    let api: Api<DynamicObject> = Api::namespaced_with(client.clone(), "tink", &api_resource);
+    let api = workflow_api(client.clone());
     
     // Create a DynamicObject from our workflow_json
     let dynamic_obj = DynamicObject {
         metadata: kube::core::ObjectMeta {
             name: Some(resource_name.clone()),
-            namespace: Some("tink".to_string()),
+            namespace: Some(namespace()),
             ..Default::default()
         },
         types: Some(kube::core::TypeMeta {
@@ -743,8 +813,34 @@ async fn save_timing_to_db(template_name: String, action_name: String, durations
     }
 }
 
+/// Dynamic API for Tinkerbell Workflow resources in Dragonfly's namespace
+/// A Tinkerbell custom resource type, e.g. `tinkerbell_resource("Workflow", "workflows")`
+pub(crate) fn tinkerbell_resource(kind: &str, plural: &str) -> kube::core::ApiResource {
+    kube::core::ApiResource {
+        group: "tinkerbell.org".to_string(),
+        version: "v1alpha1".to_string(),
+        kind: kind.to_string(),
+        api_version: "tinkerbell.org/v1alpha1".to_string(),
+        plural: plural.to_string(),
+    }
+}
+
+fn hardware_api(client: Client) -> Api<DynamicObject> {
+    Api::namespaced_with(client, &namespace(), &tinkerbell_resource("Hardware", "hardware"))
+}
+
+fn workflow_api(client: Client) -> Api<DynamicObject> {
+    Api::namespaced_with(client, &namespace(), &tinkerbell_resource("Workflow", "workflows"))
+}
+
 // Get workflow information from Kubernetes for a specific machine
+#[instrument(skip_all, fields(machine_id = %machine.id, otel.kind = "client"))]
 pub async fn get_workflow_info(machine: &Machine) -> Result<Option<WorkflowInfo>> {
+    // The demo fleet's installs are simulated, not run by Tinkerbell
+    if crate::demo::is_active() {
+        return Ok(crate::demo::workflow_info(machine));
+    }
+
     // First check if we have a recently completed workflow
     if let Ok(Some((workflow_info, _completed_at))) = crate::db::get_completed_workflow(&machine.id).await {
         return Ok(Some(workflow_info));
@@ -763,398 +859,332 @@ pub async fn get_workflow_info(machine: &Machine) -> Result<Option<WorkflowInfo>
     // Create the workflow resource name based on the MAC address
     let workflow_name = format!("os-install-{}", machine.mac_address.replace(":", "-"));
     
-    // Create the ApiResource for the Workflow CRD
-    let api_resource = kube::core::ApiResource {
-        group: "tinkerbell.org".to_string(),
-        version: "v1alpha1".to_string(),
-        kind: "Workflow".to_string(),
-        api_version: "tinkerbell.org/v1alpha1".to_string(),
-        plural: "workflows".to_string(),
-    };
-    
     // Create a dynamic API to interact with the Workflow custom resource
-    let api: Api<DynamicObject> = Api::namespaced_with(client.clone(), "tink", &api_resource);
+    let api = workflow_api(client.clone());
     
     // Try to get the workflow
     match api.get(&workflow_name).await {
-        Ok(workflow) => {
-            // Extract template reference from the workflow spec for time tracking
-            let template_ref = workflow.data.get("spec")
-                .and_then(|spec| spec.get("templateRef"))
-                .and_then(|t| t.as_str())
-                .unwrap_or("unknown");
+        Ok(workflow) => workflow_info_from(machine, &api, &workflow_name, workflow).await,
+        Err(KubeError::Api(ae)) if ae.code == 404 => {
+            info!("No workflow found with name: {}", workflow_name);
+            Ok(None)
+        },
+        Err(e) => {
+            error!("Error fetching workflow {}: {}", workflow_name, e);
+            Err(anyhow!("Error fetching workflow: {}", e))
+        }
+    }
+}
+
+/// Work out a machine's install progress from its Workflow, finishing the install when it is done.
+/// `api` is used to delete workflows that finished by kexec-ing into the new OS.
+async fn workflow_info_from(machine: &Machine, api: &Api<DynamicObject>, workflow_name: &str, workflow: DynamicObject) -> Result<Option<WorkflowInfo>> {
+    // Extract template reference from the workflow spec for time tracking
+    let template_ref = workflow.data.get("spec")
+        .and_then(|spec| spec.get("templateRef"))
+        .and_then(|t| t.as_str())
+        .unwrap_or("unknown");
+    
+    // Process workflow status from the DynamicObject
+    if let Some(status) = workflow.data.get("status") {
+        let state = status.get("state").and_then(|s| s.as_str()).unwrap_or("UNKNOWN");
+        let current_action = status.get("currentAction").and_then(|a| a.as_str()).map(|s| s.to_string());
+        
+        // HACK: If a machine is stuck in STATE_RUNNING with current action "kexec to boot OS", 
+        // it has likely successfully booted the OS. Mark it as Ready and delete the workflow.
+        // STATE_FAILED is always considered a failure regardless of the current action.
+        if finished_by_kexec(status) {
+            info!("HACK: Detected machine {} in STATE_RUNNING for 'kexec to boot OS' or timed out. Marking as Ready and deleting workflow.", 
+                  machine.id);
             
-            // Process workflow status from the DynamicObject
-            if let Some(status) = workflow.data.get("status") {
-                let state = status.get("state").and_then(|s| s.as_str()).unwrap_or("UNKNOWN");
-                let current_action = status.get("currentAction").and_then(|a| a.as_str()).map(|s| s.to_string());
-                
-                // HACK: If a machine is stuck in STATE_RUNNING with current action "kexec to boot OS", 
-                // it has likely successfully booted the OS. Mark it as Ready and delete the workflow.
-                // STATE_FAILED is always considered a failure regardless of the current action.
-                if (state == "STATE_RUNNING" && current_action.as_deref() == Some("kexec to boot OS")) ||
-                   is_workflow_timed_out(status, current_action.as_deref()) {
-                    info!("HACK: Detected machine {} in STATE_RUNNING for 'kexec to boot OS' or timed out. Marking as Ready and deleting workflow.", 
-                          machine.id);
-                    
-                    // Extract tasks to get timing data before marking as complete
-                    let mut tasks = Vec::new();
-                    if let Some(task_array) = status.get("tasks") {
-                        if let Some(task_array) = task_array.as_array() {
-                            for task_obj in task_array {
-                                if let Some(actions) = task_obj.get("actions") {
-                                    if let Some(actions) = actions.as_array() {
-                                        for action in actions {
-                                            let name = action.get("name").and_then(|n| n.as_str()).unwrap_or("unknown").to_string();
-                                            let status = action.get("status").and_then(|s| s.as_str()).unwrap_or("UNKNOWN").to_string();
-                                            let started_at = action.get("startedAt").and_then(|s| s.as_str()).unwrap_or("").to_string();
-                                            
-                                            // Only collect completed tasks for timing data
-                                            if status == "STATE_SUCCESS" {
-                                                let reported_seconds = action.get("seconds").and_then(|s| s.as_i64()).unwrap_or(0) as u64;
-                                                let estimated_seconds = get_avg_time_for_action(template_ref, &name).unwrap_or(0);
-                                                
-                                                tasks.push(TaskInfo {
-                                                    name,
-                                                    status,
-                                                    started_at,
-                                                    duration: reported_seconds,
-                                                    reported_duration: reported_seconds,
-                                                    estimated_duration: estimated_seconds,
-                                                    progress: 0,
-                                                });
-                                            }
-                                            // Also capture the kexec action even if it's not officially completed
-                                            else if status == "STATE_RUNNING" && name == "kexec to boot OS" {
-                                                let reported_seconds = action.get("seconds").and_then(|s| s.as_i64()).unwrap_or(0) as u64;
-                                                let estimated_seconds = get_avg_time_for_action(template_ref, &name).unwrap_or(0);
-                                                
-                                                // Add the kexec task but mark it as successful since we're considering the workflow complete
-                                                tasks.push(TaskInfo {
-                                                    name,
-                                                    status: "STATE_SUCCESS".to_string(), // Override status to SUCCESS
-                                                    started_at,
-                                                    duration: reported_seconds,
-                                                    reported_duration: reported_seconds,
-                                                    estimated_duration: estimated_seconds,
-                                                    progress: 0,
-                                                });
-                                            }
-                                        }
-                                    }
+            // Extract tasks to get timing data before marking as complete
+            let tasks = kexec_completed_tasks(status, template_ref);
+            
+            // Store timing data for completed tasks
+            if !tasks.is_empty() {
+                info!("Storing timing data for {} completed tasks from kexec-detected workflow", tasks.len());
+                store_timing_info(template_ref, &tasks);
+            }
+            
+            // Create a special WorkflowInfo to indicate this was handled by the hack
+            let workflow_info = WorkflowInfo {
+                state: "STATE_SUCCESS".to_string(),
+                current_action: Some("Completed via kexec detection".to_string()),
+                progress: 100,
+                tasks,  // Use the extracted tasks instead of empty vector
+                estimated_completion: Some("Deployment complete".to_string()),
+                template_name: template_ref.to_string(),
+            };
+
+            // Store the completed workflow info; later checks read it back instead of finishing again
+            if let Err(e) = crate::db::store_completed_workflow(&machine.id, &workflow_info).await {
+                warn!("Failed to store completed workflow info: {}", e);
+            } else {
+                info!("Successfully stored completed workflow info for {}", machine.id);
+            }
+            
+            // Send a machine_updated event to refresh the UI
+            if let Some(event_manager) = get_event_manager() {
+                info!("Sending machine_updated event after kexec detection success for: {}", machine.id);
+                event_manager.send(format!("machine_updated:{}", machine.id));
+            }
+            
+            // The rest waits for the UI, so it runs on its own rather than holding up the workflow watch
+            tokio::spawn(finish_kexec_workflow(api.clone(), machine.clone(), workflow_name.to_string(), template_ref.to_string()));
+            
+            return Ok(Some(workflow_info));
+        }
+        
+        // Extract all tasks from the workflow
+        let mut tasks = Vec::new();
+        let mut total_seconds = 0;
+        let mut completed_seconds = 0;
+        let mut running_task_info = None;
+        let mut running_task_started_at = None;
+        
+        if let Some(task_array) = status.get("tasks") {
+            if let Some(task_array) = task_array.as_array() {
+                for task_obj in task_array {
+                    if let Some(actions) = task_obj.get("actions") {
+                        if let Some(actions) = actions.as_array() {
+                            for action in actions {
+                                let name = action.get("name").and_then(|n| n.as_str()).unwrap_or("unknown").to_string();
+                                let status = action.get("status").and_then(|s| s.as_str()).unwrap_or("UNKNOWN").to_string();
+                                let started_at = action.get("startedAt").and_then(|s| s.as_str()).unwrap_or("").to_string();
+                                
+                                // Get actual duration from completed actions or estimate from template history
+                                let reported_seconds = action.get("seconds").and_then(|s| s.as_i64()).unwrap_or(0) as u64;
+                                
+                                // Only rely on historical timing data or reported seconds
+                                let estimated_seconds = get_avg_time_for_action(template_ref, &name);
+                                
+                                // Use the reported seconds for completed tasks, or estimated seconds if available
+                                let seconds = if status == "STATE_SUCCESS" {
+                                    reported_seconds  // Use actual time for completed tasks
+                                } else if let Some(est) = estimated_seconds {
+                                    est // Use estimated time from history
+                                } else if reported_seconds > 0 {
+                                    reported_seconds // Fall back to reported seconds if non-zero
+                                } else {
+                                    // We have no data at all
+                                    0 // Can't make any assumptions
+                                };
+                                
+                                total_seconds += seconds;
+                                
+                                if status == "STATE_SUCCESS" {
+                                    completed_seconds += seconds;
+                                } else if status == "STATE_RUNNING" {
+                                    // Parse started_at for later use
+                                    let started_at_parsed = if !started_at.is_empty() {
+                                        chrono::DateTime::parse_from_rfc3339(&started_at)
+                                            .ok()
+                                            .map(|dt| dt.with_timezone(&chrono::Utc))
+                                    } else {
+                                        None
+                                    };
+                                    
+                                    // Store the current running task info for later progress calculation
+                                    running_task_info = Some((name.clone(), seconds));
+                                    running_task_started_at = started_at_parsed;
                                 }
+                                
+                                // Add tasks to the array
+                                let _current_date = chrono::Utc::now();
+                                tasks.push(TaskInfo {
+                                    name: name.clone(),
+                                    status: status.clone(),
+                                    started_at: started_at.clone(),
+                                    duration: seconds,
+                                    reported_duration: reported_seconds,
+                                    estimated_duration: estimated_seconds.unwrap_or(0),
+                                    progress: 0, // Initialize to 0, will calculate after all tasks are collected
+                                });
                             }
                         }
                     }
+                }
+            }
+        }
+        
+        // Calculate progress for all tasks based on elapsed time
+        let current_time = chrono::Utc::now();
+        for (i, task) in tasks.iter_mut().enumerate() {
+            if task.status == "STATE_RUNNING" && !task.started_at.is_empty() {
+                if let Ok(started_at) = chrono::DateTime::parse_from_rfc3339(&task.started_at) {
+                    let started_at_utc = started_at.with_timezone(&chrono::Utc);
+                    let elapsed_seconds = current_time.signed_duration_since(started_at_utc).num_seconds().max(0) as u64;
                     
-                    // Store timing data for completed tasks
-                    if !tasks.is_empty() {
-                        info!("Storing timing data for {} completed tasks from kexec-detected workflow", tasks.len());
-                        store_timing_info(template_ref, &tasks);
-                    }
-                    
-                    // Create a special WorkflowInfo to indicate this was handled by the hack
-                    let workflow_info = WorkflowInfo {
-                        state: "STATE_SUCCESS".to_string(),
-                        current_action: Some("Completed via kexec detection".to_string()),
-                        progress: 100,
-                        tasks,  // Use the extracted tasks instead of empty vector
-                        estimated_completion: Some("Deployment complete".to_string()),
-                        template_name: template_ref.to_string(),
-                    };
-
-                    // Store the completed workflow info
-                    if let Err(e) = crate::db::store_completed_workflow(&machine.id, &workflow_info).await {
-                        warn!("Failed to store completed workflow info: {}", e);
-                    } else {
-                        info!("Successfully stored completed workflow info for {}", machine.id);
+                    if task.estimated_duration > 0 {
+                        // Calculate raw progress as elapsed/estimated, capped at 100%
+                        let progress_pct = (elapsed_seconds as f64 / task.estimated_duration as f64 * 100.0).min(100.0);
+                        
+                        // Update the task progress
+                        task.progress = progress_pct as u8;
+                        
+                        // Log the progress calculation
+                        info!("Task '{}'(#{}) progress updated: {}% ({}/{}s elapsed)", 
+                            task.name, i, task.progress, elapsed_seconds, task.estimated_duration);
                     }
+                }
+            } else if task.status == "STATE_SUCCESS" {
+                task.progress = 100;
+            }
+        }
+        
+        // Calculate fluid progress percentage using timing data
+        let progress = if total_seconds > 0 {
+            // Start with progress from completed tasks
+            let mut time_based_progress = completed_seconds as f64 / total_seconds as f64 * 100.0;
+            
+            // If there's a running task, use its task-specific progress for the overall calculation
+            if let (Some((running_task_name, expected_duration)), Some(started_at)) = (&running_task_info, &running_task_started_at) {
+                // Find the actual task in our tasks list to get its calculated progress
+                if let Some(task) = tasks.iter().find(|t| t.name == *running_task_name && t.status == "STATE_RUNNING") {
+                    // Use the task's progress percentage (0-100)
+                    let task_progress_ratio = task.progress as f64 / 100.0;
                     
-                    // Send a machine_updated event to refresh the UI
-                    if let Some(event_manager) = get_event_manager() {
-                        info!("Sending machine_updated event after kexec detection success for: {}", machine.id);
-                        event_manager.send(format!("machine_updated:{}", machine.id));
-                    }
+                    // Weight of this task in the overall time
+                    let task_weight = *expected_duration as f64 / total_seconds as f64;
                     
-                    // Add a short delay to ensure the UI has time to update and show the completion message
-                    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+                    // Add weighted progress from running task
+                    time_based_progress += task_weight * task_progress_ratio * 100.0;
+                } else {
+                    // Fallback if we can't find the task - calculate progress directly
+                    let now = chrono::Utc::now();
+                    let elapsed = now.signed_duration_since(*started_at).num_seconds() as f64;
                     
-                    // Update machine status to Ready AFTER UI has chance to show completion message
-                    if let Err(e) = update_machine_status_on_success(machine).await {
-                        warn!("Failed to update machine status after kexec detection: {}", e);
+                    // Ratio of elapsed time to expected duration, capped at 100%
+                    let task_progress_ratio = if *expected_duration > 0 {
+                        (elapsed / *expected_duration as f64).min(1.0)
                     } else {
-                        info!("Successfully marked machine {} as Ready", machine.id);
-                    }
+                        0.0
+                    };
                     
-                    // Delete the workflow
-                    let delete_params = kube::api::DeleteParams::default();
-                    match api.delete(&workflow_name, &delete_params).await {
-                        Ok(_) => info!("Successfully deleted workflow {}", workflow_name),
-                        Err(e) => warn!("Failed to delete workflow {}: {}", workflow_name, e),
-                    }
+                    // Weight of this task in the overall time
+                    let task_weight = *expected_duration as f64 / total_seconds as f64;
                     
-                    return Ok(Some(workflow_info));
+                    // Add partial progress from running task
+                    time_based_progress += task_weight * task_progress_ratio * 100.0;
                 }
-                
-                // Extract all tasks from the workflow
-                let mut tasks = Vec::new();
-                let mut total_seconds = 0;
-                let mut completed_seconds = 0;
-                let mut running_task_info = None;
-                let mut running_task_started_at = None;
-                
-                if let Some(task_array) = status.get("tasks") {
-                    if let Some(task_array) = task_array.as_array() {
-                        for task_obj in task_array {
-                            if let Some(actions) = task_obj.get("actions") {
-                                if let Some(actions) = actions.as_array() {
-                                    for action in actions {
-                                        let name = action.get("name").and_then(|n| n.as_str()).unwrap_or("unknown").to_string();
-                                        let status = action.get("status").and_then(|s| s.as_str()).unwrap_or("UNKNOWN").to_string();
-                                        let started_at = action.get("startedAt").and_then(|s| s.as_str()).unwrap_or("").to_string();
-                                        
-                                        // Get actual duration from completed actions or estimate from template history
-                                        let reported_seconds = action.get("seconds").and_then(|s| s.as_i64()).unwrap_or(0) as u64;
-                                        
-                                        // Only rely on historical timing data or reported seconds
-                                        let estimated_seconds = get_avg_time_for_action(template_ref, &name);
-                                        
-                                        // Use the reported seconds for completed tasks, or estimated seconds if available
-                                        let seconds = if status == "STATE_SUCCESS" {
-                                            reported_seconds  // Use actual time for completed tasks
-                                        } else if let Some(est) = estimated_seconds {
-                                            est // Use estimated time from history
-                                        } else if reported_seconds > 0 {
-                                            reported_seconds // Fall back to reported seconds if non-zero
-                                        } else {
-                                            // We have no data at all
-                                            0 // Can't make any assumptions
-                                        };
-                                        
-                                        total_seconds += seconds;
-                                        
-                                        if status == "STATE_SUCCESS" {
-                                            completed_seconds += seconds;
-                                        } else if status == "STATE_RUNNING" {
-                                            // Parse started_at for later use
-                                            let started_at_parsed = if !started_at.is_empty() {
-                                                chrono::DateTime::parse_from_rfc3339(&started_at)
-                                                    .ok()
-                                                    .map(|dt| dt.with_timezone(&chrono::Utc))
-                                            } else {
-                                                None
-                                            };
-                                            
-                                            // Store the current running task info for later progress calculation
-                                            running_task_info = Some((name.clone(), seconds));
-                                            running_task_started_at = started_at_parsed;
-                                        }
-                                        
-                                        // Add tasks to the array
-                                        let _current_date = chrono::Utc::now();
-                                        tasks.push(TaskInfo {
-                                            name: name.clone(),
-                                            status: status.clone(),
-                                            started_at: started_at.clone(),
-                                            duration: seconds,
-                                            reported_duration: reported_seconds,
-                                            estimated_duration: estimated_seconds.unwrap_or(0),
-                                            progress: 0, // Initialize to 0, will calculate after all tasks are collected
-                                        });
-                                    }
-                                }
-                            }
-                        }
+            }
+
+            // Ratchet mechanism - ensure progress doesn't go backwards
+            // Get the previous progress for this workflow from the database
+            let previous_progress = match crate::db::get_completed_workflow(&machine.id).await {
+                Ok(Some((existing_wf, _))) => {
+                    // Only use previous progress if we're in the same workflow state
+                    if existing_wf.state == state {
+                        existing_wf.progress as f64
+                    } else {
+                        0.0 // Different state, reset progress
                     }
-                }
+                },
+                _ => 0.0, // No previous workflow info
+            };
+            
+            // Take the maximum of current progress and previous progress
+            let final_progress = time_based_progress.max(previous_progress);
+            
+            // Cap at 100%
+            final_progress.min(100.0) as u8
+        } else {
+            0
+        };
+        
+        // Calculate estimated completion time using template-specific timing data
+        let estimated_completion = if state != "STATE_SUCCESS" && state != "STATE_FAILED" && !tasks.is_empty() {
+            if let (Some((_task_name, expected_duration)), Some(started_at)) = (&running_task_info, &running_task_started_at) {
+                // Calculate elapsed time since the task started
+                let now = chrono::Utc::now();
+                let elapsed = now.signed_duration_since(*started_at).num_seconds() as i64;
                 
-                // Calculate progress for all tasks based on elapsed time
-                let current_time = chrono::Utc::now();
-                for (i, task) in tasks.iter_mut().enumerate() {
-                    if task.status == "STATE_RUNNING" && !task.started_at.is_empty() {
-                        if let Ok(started_at) = chrono::DateTime::parse_from_rfc3339(&task.started_at) {
-                            let started_at_utc = started_at.with_timezone(&chrono::Utc);
-                            let elapsed_seconds = current_time.signed_duration_since(started_at_utc).num_seconds().max(0) as u64;
-                            
-                            if task.estimated_duration > 0 {
-                                // Calculate raw progress as elapsed/estimated, capped at 100%
-                                let progress_pct = (elapsed_seconds as f64 / task.estimated_duration as f64 * 100.0).min(100.0);
-                                
-                                // Update the task progress
-                                task.progress = progress_pct as u8;
-                                
-                                // Log the progress calculation
-                                info!("Task '{}'(#{}) progress updated: {}% ({}/{}s elapsed)", 
-                                    task.name, i, task.progress, elapsed_seconds, task.estimated_duration);
-                            }
-                        }
-                    } else if task.status == "STATE_SUCCESS" {
-                        task.progress = 100;
-                    }
-                }
+                // Calculate remaining time for current task
+                let remaining_seconds = *expected_duration as i64 - elapsed;
+                let remaining_seconds = remaining_seconds.max(0); // Ensure non-negative
                 
-                // Calculate fluid progress percentage using timing data
-                let progress = if total_seconds > 0 {
-                    // Start with progress from completed tasks
-                    let mut time_based_progress = completed_seconds as f64 / total_seconds as f64 * 100.0;
+                // If we're near completion of this task, look ahead to how much time is left overall
+                if remaining_seconds < 10 {
+                    // Sum the durations of all remaining tasks
+                    let mut remaining_total = remaining_seconds;
+                    let mut found_current = false;
                     
-                    // If there's a running task, use its task-specific progress for the overall calculation
-                    if let (Some((running_task_name, expected_duration)), Some(started_at)) = (&running_task_info, &running_task_started_at) {
-                        // Find the actual task in our tasks list to get its calculated progress
-                        if let Some(task) = tasks.iter().find(|t| t.name == *running_task_name && t.status == "STATE_RUNNING") {
-                            // Use the task's progress percentage (0-100)
-                            let task_progress_ratio = task.progress as f64 / 100.0;
-                            
-                            // Weight of this task in the overall time
-                            let task_weight = *expected_duration as f64 / total_seconds as f64;
-                            
-                            // Add weighted progress from running task
-                            time_based_progress += task_weight * task_progress_ratio * 100.0;
-                        } else {
-                            // Fallback if we can't find the task - calculate progress directly
-                            let now = chrono::Utc::now();
-                            let elapsed = now.signed_duration_since(*started_at).num_seconds() as f64;
-                            
-                            // Ratio of elapsed time to expected duration, capped at 100%
-                            let task_progress_ratio = if *expected_duration > 0 {
-                                (elapsed / *expected_duration as f64).min(1.0)
-                            } else {
-                                0.0
-                            };
-                            
-                            // Weight of this task in the overall time
-                            let task_weight = *expected_duration as f64 / total_seconds as f64;
-                            
-                            // Add partial progress from running task
-                            time_based_progress += task_weight * task_progress_ratio * 100.0;
+                    for task in &tasks {
+                        if found_current {
+                            // This is a future task
+                            remaining_total += task.duration as i64;
+                        } else if task.name == *_task_name && task.status == "STATE_RUNNING" {
+                            // This is the current task, we've found it
+                            found_current = true;
                         }
                     }
-
-                    // Ratchet mechanism - ensure progress doesn't go backwards
-                    // Get the previous progress for this workflow from the database
-                    let previous_progress = match crate::db::get_completed_workflow(&machine.id).await {
-                        Ok(Some((existing_wf, _))) => {
-                            // Only use previous progress if we're in the same workflow state
-                            if existing_wf.state == state {
-                                existing_wf.progress as f64
-                            } else {
-                                0.0 // Different state, reset progress
-                            }
-                        },
-                        _ => 0.0, // No previous workflow info
-                    };
                     
-                    // Take the maximum of current progress and previous progress
-                    let final_progress = time_based_progress.max(previous_progress);
-                    
-                    // Cap at 100%
-                    final_progress.min(100.0) as u8
+                    format_remaining_time(remaining_total)
                 } else {
-                    0
-                };
-                
-                // Calculate estimated completion time using template-specific timing data
-                let estimated_completion = if state != "STATE_SUCCESS" && state != "STATE_FAILED" && !tasks.is_empty() {
-                    if let (Some((_task_name, expected_duration)), Some(started_at)) = (&running_task_info, &running_task_started_at) {
-                        // Calculate elapsed time since the task started
-                        let now = chrono::Utc::now();
-                        let elapsed = now.signed_duration_since(*started_at).num_seconds() as i64;
-                        
-                        // Calculate remaining time for current task
-                        let remaining_seconds = *expected_duration as i64 - elapsed;
-                        let remaining_seconds = remaining_seconds.max(0); // Ensure non-negative
-                        
-                        // If we're near completion of this task, look ahead to how much time is left overall
-                        if remaining_seconds < 10 {
-                            // Sum the durations of all remaining tasks
-                            let mut remaining_total = remaining_seconds;
-                            let mut found_current = false;
-                            
-                            for task in &tasks {
-                                if found_current {
-                                    // This is a future task
-                                    remaining_total += task.duration as i64;
-                                } else if task.name == *_task_name && task.status == "STATE_RUNNING" {
-                                    // This is the current task, we've found it
-                                    found_current = true;
-                                }
-                            }
-                            
-                            format_remaining_time(remaining_total)
-                        } else {
-                            // Just focus on current task
-                            format_remaining_time(remaining_seconds)
-                        }
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                };
-                
-                // If the workflow completed successfully, store the timing information with template reference
-                if state == "STATE_SUCCESS" && tasks.iter().all(|t| t.status == "STATE_SUCCESS") {
-                    store_timing_info(template_ref, &tasks);
-                    
-                    // Send a machine_updated event
-                    if let Some(event_manager) = get_event_manager() {
-                        info!("Sending machine_updated event for completed workflow: {}", machine.id);
-                        event_manager.send(format!("machine_updated:{}", machine.id));
-                    }
+                    // Just focus on current task
+                    format_remaining_time(remaining_seconds)
                 }
-                
-                // If the workflow failed, update the machine status to Error
-                if state == "STATE_FAILED" {
-                    if let Err(e) = update_machine_status_on_failure(machine).await {
-                        warn!("Failed to update machine status after workflow failure: {}", e);
-                    }
-                    
-                    // Send a machine_updated event
-                    if let Some(event_manager) = get_event_manager() {
-                        info!("Sending machine_updated event for failed workflow: {}", machine.id);
-                        event_manager.send(format!("machine_updated:{}", machine.id));
-                    }
-                }
-                
-                // Only mark as Ready if ALL tasks are complete successfully
-                if state == "STATE_SUCCESS" && tasks.iter().all(|t| t.status == "STATE_SUCCESS") {
-                    if let Err(e) = update_machine_status_on_success(machine).await {
-                        warn!("Failed to update machine status after workflow success: {}", e);
-                    }
-                }
-                
-                // Also send an update event for normal workflow progress
-                if state == "STATE_RUNNING" {
-                    // Send a machine_updated event for real-time progress updates
-                    if let Some(event_manager) = get_event_manager() {
-                        info!("Sending machine_updated event for workflow progress: {}", machine.id);
-                        event_manager.send(format!("machine_updated:{}", machine.id));
-                    }
-                }
-                
-                let workflow_info = WorkflowInfo {
-                    state: state.to_string(),
-                    current_action,
-                    progress,
-                    tasks,
-                    estimated_completion,
-                    template_name: template_ref.to_string(),
-                };
-                
-                Ok(Some(workflow_info))
             } else {
-                info!("No status information found for workflow {}", workflow_name);
-                Ok(None)
+                None
+            }
+        } else {
+            None
+        };
+        
+        // If the workflow completed successfully, store the timing information with template reference
+        if state == "STATE_SUCCESS" && tasks.iter().all(|t| t.status == "STATE_SUCCESS") {
+            store_timing_info(template_ref, &tasks);
+            
+            // Send a machine_updated event
+            if let Some(event_manager) = get_event_manager() {
+                info!("Sending machine_updated event for completed workflow: {}", machine.id);
+                event_manager.send(format!("machine_updated:{}", machine.id));
+            }
+        }
+        
+        // If the workflow failed, update the machine status to Error
+        if state == "STATE_FAILED" {
+            let failed_task = tasks.iter().find(|t| t.status == "STATE_FAILED").map(|t| t.name.as_str());
+            if let Err(e) = update_machine_status_on_failure(machine, failed_task).await {
+                warn!("Failed to update machine status after workflow failure: {}", e);
+            }
+            
+            // Send a machine_updated event
+            if let Some(event_manager) = get_event_manager() {
+                info!("Sending machine_updated event for failed workflow: {}", machine.id);
+                event_manager.send(format!("machine_updated:{}", machine.id));
             }
-        },
-        Err(KubeError::Api(ae)) if ae.code == 404 => {
-            info!("No workflow found with name: {}", workflow_name);
-            Ok(None)
-        },
-        Err(e) => {
-            error!("Error fetching workflow {}: {}", workflow_name, e);
-            Err(anyhow!("Error fetching workflow: {}", e))
         }
+        
+        // Only mark as Ready if ALL tasks are complete successfully
+        if state == "STATE_SUCCESS" && tasks.iter().all(|t| t.status == "STATE_SUCCESS") {
+            if let Err(e) = update_machine_status_on_success(machine, template_ref).await {
+                warn!("Failed to update machine status after workflow success: {}", e);
+            }
+        }
+        
+        // Also send an update event for normal workflow progress
+        if state == "STATE_RUNNING" {
+            // Send a machine_updated event for real-time progress updates
+            if let Some(event_manager) = get_event_manager() {
+                info!("Sending machine_updated event for workflow progress: {}", machine.id);
+                event_manager.send(format!("machine_updated:{}", machine.id));
+            }
+        }
+        
+        let workflow_info = WorkflowInfo {
+            state: state.to_string(),
+            current_action,
+            progress,
+            tasks,
+            estimated_completion,
+            template_name: template_ref.to_string(),
+        };
+        
+        Ok(Some(workflow_info))
+    } else {
+        info!("No status information found for workflow {}", workflow_name);
+        Ok(None)
     }
 }
 
@@ -1182,28 +1212,79 @@ fn get_event_manager() -> Option<&'static crate::event_manager::EventManager> {
 }
 
 // Update machine status when workflow fails
-async fn update_machine_status_on_failure(machine: &Machine) -> Result<()> {
+async fn update_machine_status_on_failure(machine: &Machine, failed_task: Option<&str>) -> Result<()> {
     use dragonfly_common::models::MachineStatus;
+    use dragonfly_common::state_machine::StatusCause;
     
     info!("Workflow failed for machine {}, updating status to Error", machine.id);
     
     let mut updated_machine = machine.clone();
     updated_machine.status = MachineStatus::Error("OS installation failed".to_string());
     
-    crate::db::update_machine(&updated_machine).await?;
+    let mut detail = match failed_task {
+        Some(task) => format!("task {} failed", task),
+        None => "workflow failed".to_string(),
+    };
+    // The run stays at the failed stage so it can be resumed from there
+    match crate::pipelines::fail_stage(&machine.id, &detail).await {
+        Ok(Some(run)) => detail = format!("{} in {} of pipeline '{}'", detail, run.describe_stage(), run.pipeline),
+        Ok(None) => {},
+        Err(e) => warn!("Failed to record pipeline failure for machine {}: {}", machine.id, e),
+    }
+    let cause = StatusCause::Workflow(detail);
+    crate::db::update_machine(&updated_machine, &cause).await?;
+    
+    // Workflow status is polled repeatedly, so only count the transition out of installing
+    if machine.status == MachineStatus::InstallingOS {
+        if let Err(e) = crate::db::record_install_outcome(machine, false).await {
+            warn!("Failed to record installation outcome: {}", e);
+        }
+        if let Some(event_manager) = get_event_manager() {
+            let _ = event_manager.send(format!("install_failed:{}", machine.id));
+        }
+    }
     Ok(())
 }
 
 // Update machine status when workflow succeeds
-async fn update_machine_status_on_success(machine: &Machine) -> Result<()> {
+async fn update_machine_status_on_success(machine: &Machine, template_ref: &str) -> Result<()> {
     use dragonfly_common::models::MachineStatus;
-    use dragonfly_common::models::Machine;
-    use anyhow::anyhow;
+    use dragonfly_common::state_machine::StatusCause;
+    use crate::pipelines::StageOutcome;
+    
+    // A pipeline stage finishing only means the next one can start
+    match crate::pipelines::complete_stage(&machine.id, template_ref).await {
+        Ok(StageOutcome::Next(run)) => return start_pipeline_stage(machine, &run).await,
+        Ok(StageOutcome::Stale) => return Ok(()),
+        Ok(StageOutcome::Finished) => {},
+        Err(e) => warn!("Failed to check pipeline for machine {}: {}", machine.id, e),
+    }
+    
+    // The OS template's health checks have to pass before the machine counts as Ready
+    match crate::health_checks::for_template(machine.os_choice.as_deref()).await {
+        Ok(Some(checks)) => {
+            // Workflow status is polled repeatedly; only a machine still installing gets checked
+            if machine.status == MachineStatus::InstallingOS {
+                crate::health_checks::start(machine.clone(), checks);
+            }
+            return Ok(());
+        },
+        Ok(None) => {},
+        Err(e) => warn!("Failed to load health checks for machine {}: {}", machine.id, e),
+    }
     
     info!("Workflow completed successfully for machine {}, updating status to Ready", machine.id);
+    mark_ready(machine, StatusCause::Workflow("workflow completed".to_string())).await
+}
+
+// Mark a machine whose install has finished as Ready, recording how it went
+pub(crate) async fn mark_ready(machine: &Machine, cause: dragonfly_common::state_machine::StatusCause) -> Result<()> {
+    use dragonfly_common::models::MachineStatus;
+    use dragonfly_common::models::Machine;
+    use anyhow::anyhow;
     
     // First update just the status for reliability
-    match crate::db::update_status(&machine.id, MachineStatus::Ready).await {
+    match crate::db::update_status(&machine.id, MachineStatus::Ready, &cause).await {
         Ok(true) => {
             info!("Successfully updated status to Ready for machine {}", machine.id);
             
@@ -1214,11 +1295,18 @@ async fn update_machine_status_on_success(machine: &Machine) -> Result<()> {
                 
                 // Try to update the duration separately
                 if let Err(e) = crate::db::update_machine(&Machine {
+                    status: MachineStatus::Ready,
                     last_deployment_duration: Some(duration),
                     ..machine.clone()
-                }).await {
+                }, &cause).await {
                     warn!("Failed to update deployment duration: {}", e);
                 }
+                if let Err(e) = crate::db::record_install_outcome(machine, true).await {
+                    warn!("Failed to record installation outcome: {}", e);
+                }
+                if let Some(event_manager) = get_event_manager() {
+                    let _ = event_manager.send(format!("install_completed:{}", machine.id));
+                }
             }
             
             Ok(())
@@ -1450,104 +1538,214 @@ async fn estimate_completion_time(template_name: &str, current_action: &str, tas
     (time_remaining, progress)
 }
 
-// Start a background task to poll for workflow updates
-pub async fn start_workflow_polling_task(
+// Watch Tinkerbell workflows and send machine events as they change
+pub async fn start_workflow_watch_task(
     event_manager: std::sync::Arc<crate::event_manager::EventManager>,
     mut shutdown_rx: tokio::sync::watch::Receiver<()>
 ) {
-    use dragonfly_common::models::MachineStatus;
+    use futures::StreamExt;
+    use kube::runtime::{watcher, WatchStreamExt};
     use std::collections::HashMap;
-    use std::time::Duration;
-    
-    // Clone the event manager for the task
-    let event_manager_clone = event_manager.clone();
-    
+
     tokio::spawn(async move {
-        let poll_interval = Duration::from_secs(1);
-        info!("Starting workflow polling task with interval of {:?}", poll_interval);
-        
-        // Track the last seen workflow state by machine ID
-        let mut last_seen_states: HashMap<uuid::Uuid, (String, Option<String>)> = HashMap::new();
-        
+        // Keep trying until Kubernetes is reachable
+        let mut retry = WORKFLOW_WATCH_RETRY_MIN;
+        let client = loop {
+            match get_client().await {
+                Ok(client) => break client.clone(),
+                Err(e) => {
+                    warn!("Workflow watch waiting for Kubernetes ({}), retrying in {:?}", e, retry);
+                    tokio::select! {
+                        _ = tokio::time::sleep(retry) => retry = (retry * 2).min(WORKFLOW_WATCH_RETRY_MAX),
+                        _ = shutdown_rx.changed() => return,
+                    }
+                }
+            }
+        };
+        let api = workflow_api(client);
+        info!("Watching Tinkerbell workflows (resync every {:?})", WORKFLOW_RESYNC_INTERVAL);
+
+        // The watcher relists after losing its place and backs off while the API server is unavailable
+        let mut stream = watcher(api.clone(), watcher::Config::default()).default_backoff().boxed();
+        // Latest copy of each workflow, re-evaluated on every resync so time-based progress and
+        // timeouts advance without changes to the workflow itself
+        let mut workflows: HashMap<String, DynamicObject> = HashMap::new();
+        let mut watch = WorkflowWatch { api, event_manager, last_seen_states: HashMap::new() };
+        let mut resync = tokio::time::interval(WORKFLOW_RESYNC_INTERVAL);
+
         loop {
-            // Wait for the poll interval or shutdown signal
             tokio::select! {
-                _ = tokio::time::sleep(poll_interval) => { 
-                    // Get all machines with InstallingOS status
-                    let machines = match crate::db::get_machines_by_status(MachineStatus::InstallingOS).await {
-                        Ok(machines) => machines,
-                        Err(e) => {
-                            error!("Failed to get machines for workflow polling: {}", e);
-                            continue;
+                event = stream.next() => match event {
+                    Some(Ok(watcher::Event::Applied(workflow))) => {
+                        let name = workflow.metadata.name.clone().unwrap_or_default();
+                        watch.check(&name, &workflow).await;
+                        workflows.insert(name, workflow);
+                    },
+                    Some(Ok(watcher::Event::Deleted(workflow))) => {
+                        let name = workflow.metadata.name.clone().unwrap_or_default();
+                        workflows.remove(&name);
+                        watch.gone(&name).await;
+                    },
+                    Some(Ok(watcher::Event::Restarted(listed))) => {
+                        let listed: HashMap<String, DynamicObject> = listed.into_iter()
+                            .map(|workflow| (workflow.metadata.name.clone().unwrap_or_default(), workflow))
+                            .collect();
+                        for name in workflows.keys().filter(|name| !listed.contains_key(*name)) {
+                            watch.gone(name).await;
                         }
-                    };
-                    
-                    if machines.is_empty() {
-                        // No machines are currently installing OS
-                        continue;
-                    }
-                    
-                    // Check each machine's workflow
-                    for machine in machines.iter() {
-                        match get_workflow_info(machine).await {
-                            Ok(Some(info)) => {
-                                let current_state = (info.state.clone(), info.current_action.clone());
-                                
-                                if let Some(last_state) = last_seen_states.get(&machine.id) {
-                                    if *last_state != current_state {
-                                        info!("Workflow update: machine={} old_state={} -> new_state={} action={:?}", 
-                                            machine.id, 
-                                            last_state.0, 
-                                            current_state.0, 
-                                            current_state.1
-                                        );
-                                        // Send machine updated event on state change
-                                        event_manager_clone.send(format!("machine_updated:{}", machine.id));
-                                        last_seen_states.insert(machine.id, current_state);
-                                    }
-                                } else {
-                                    // First time seeing this machine - log it once
-                                    info!("New workflow: machine={} state={} action={:?}",
-                                        machine.id, 
-                                        current_state.0, 
-                                        current_state.1
-                                    );
-                                    
-                                    // Send initial machine updated event
-                                    event_manager_clone.send(format!("machine_updated:{}", machine.id));
-                                    
-                                    // Add to last seen states
-                                    last_seen_states.insert(machine.id, current_state);
-                                }
-                            },
-                            Ok(None) => {
-                                // If we previously had a workflow but now it's gone, send an event
-                                if last_seen_states.remove(&machine.id).is_some() {
-                                    info!("Workflow completed for machine {}", machine.id);
-                                    event_manager_clone.send(format!("machine_updated:{}", machine.id));
-                                }
-                            },
-                            Err(e) => {
-                                error!("Error fetching workflow for machine {}: {}", machine.id, e);
-                            }
+                        workflows = listed;
+                        for (name, workflow) in &workflows {
+                            watch.check(name, workflow).await;
                         }
+                    },
+                    Some(Err(e)) => warn!("Workflow watch error, retrying: {}", e),
+                    None => {
+                        error!("Workflow watch ended unexpectedly");
+                        break;
                     }
-                    
-                    // Clean up stale entries without logging - just remove machines no longer installing OS
-                    let active_machine_ids: std::collections::HashSet<uuid::Uuid> = 
-                        machines.iter().map(|m| m.id).collect();
-                    
-                    last_seen_states.retain(|machine_id, _| active_machine_ids.contains(machine_id));
-                }
+                },
+                _ = resync.tick() => {
+                    let resync_span = tracing::info_span!("workflow_resync", workflows = workflows.len());
+                    async {
+                        for (name, workflow) in &workflows {
+                            watch.check(name, workflow).await;
+                        }
+                    }.instrument(resync_span).await;
+                },
                 _ = shutdown_rx.changed() => {
-                    info!("Shutdown signal received, stopping workflow polling task.");
-                    break; // Exit the loop
+                    info!("Shutdown signal received, stopping workflow watch task.");
+                    break;
                 }
             }
         }
     });
 }
 
+/// How often watched workflows are re-evaluated
+const WORKFLOW_RESYNC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+const WORKFLOW_WATCH_RETRY_MIN: std::time::Duration = std::time::Duration::from_secs(1);
+const WORKFLOW_WATCH_RETRY_MAX: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Turns workflow changes into machine events, as the old per-second poll did
+struct WorkflowWatch {
+    api: Api<DynamicObject>,
+    event_manager: std::sync::Arc<crate::event_manager::EventManager>,
+    /// Last seen workflow state and action, by machine
+    last_seen_states: std::collections::HashMap<uuid::Uuid, (String, Option<String>)>,
+}
+
+impl WorkflowWatch {
+    /// The installing machine an `os-install-<mac>` workflow belongs to
+    async fn machine_for(&self, workflow_name: &str) -> Option<Machine> {
+        let mac = workflow_name.strip_prefix("os-install-")?.replace('-', ":");
+        match crate::machine_cache::get_machine_by_mac(&mac).await {
+            Ok(machine) => machine.filter(|m| m.status == dragonfly_common::models::MachineStatus::InstallingOS),
+            Err(e) => {
+                error!("Failed to look up machine for workflow {}: {}", workflow_name, e);
+                None
+            }
+        }
+    }
+
+    async fn check(&mut self, workflow_name: &str, workflow: &DynamicObject) {
+        let Some(machine) = self.machine_for(workflow_name).await else {
+            return;
+        };
+        let info = match crate::db::get_completed_workflow(&machine.id).await {
+            Ok(Some((info, _completed_at))) => Ok(Some(info)),
+            _ => workflow_info_from(&machine, &self.api, workflow_name, workflow.clone()).await,
+        };
+        match info {
+            Ok(Some(info)) => {
+                let current_state = (info.state.clone(), info.current_action.clone());
+                match self.last_seen_states.get(&machine.id) {
+                    Some(last_state) if *last_state == current_state => return,
+                    Some(last_state) => info!("Workflow update: machine={} old_state={} -> new_state={} action={:?}",
+                        machine.id, last_state.0, current_state.0, current_state.1),
+                    None => info!("New workflow: machine={} state={} action={:?}",
+                        machine.id, current_state.0, current_state.1),
+                }
+                let _ = self.event_manager.send(format!("machine_updated:{}", machine.id));
+                self.last_seen_states.insert(machine.id, current_state);
+            },
+            Ok(None) => {},
+            Err(e) => error!("Error processing workflow {} for machine {}: {}", workflow_name, machine.id, e),
+        }
+    }
+
+    async fn gone(&mut self, workflow_name: &str) {
+        let Some(mac) = workflow_name.strip_prefix("os-install-").map(|mac| mac.replace('-', ":")) else {
+            return;
+        };
+        let Ok(Some(machine)) = crate::machine_cache::get_machine_by_mac(&mac).await else {
+            return;
+        };
+        if self.last_seen_states.remove(&machine.id).is_some() {
+            info!("Workflow completed for machine {}", machine.id);
+            let _ = self.event_manager.send(format!("machine_updated:{}", machine.id));
+        }
+    }
+}
+
+/// Whether a workflow has got as far as kexec-ing into the new OS, which it never reports back from
+fn finished_by_kexec(status: &serde_json::Value) -> bool {
+    let state = status.get("state").and_then(|s| s.as_str()).unwrap_or("UNKNOWN");
+    let current_action = status.get("currentAction").and_then(|a| a.as_str());
+    (state == "STATE_RUNNING" && current_action == Some("kexec to boot OS")) ||
+        is_workflow_timed_out(status, current_action)
+}
+
+/// The actions of a kexec-finished workflow worth keeping timings for: the completed ones, and
+/// the kexec itself, counted as a success
+fn kexec_completed_tasks(status: &serde_json::Value, template_ref: &str) -> Vec<TaskInfo> {
+    let actions = status.get("tasks")
+        .and_then(|tasks| tasks.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|task| task.get("actions").and_then(|actions| actions.as_array()))
+        .flatten();
+
+    let mut tasks = Vec::new();
+    for action in actions {
+        let name = action.get("name").and_then(|n| n.as_str()).unwrap_or("unknown").to_string();
+        let status = action.get("status").and_then(|s| s.as_str()).unwrap_or("UNKNOWN");
+        if status != "STATE_SUCCESS" && !(status == "STATE_RUNNING" && name == "kexec to boot OS") {
+            continue;
+        }
+        let reported_seconds = action.get("seconds").and_then(|s| s.as_i64()).unwrap_or(0) as u64;
+        tasks.push(TaskInfo {
+            estimated_duration: get_avg_time_for_action(template_ref, &name).unwrap_or(0),
+            name,
+            status: "STATE_SUCCESS".to_string(),
+            started_at: action.get("startedAt").and_then(|s| s.as_str()).unwrap_or("").to_string(),
+            duration: reported_seconds,
+            reported_duration: reported_seconds,
+            progress: 0,
+        });
+    }
+    tasks
+}
+
+/// Delete a kexec-finished workflow and mark its machine Ready, once the UI has had a moment to
+/// show the completion message
+async fn finish_kexec_workflow(api: Api<DynamicObject>, machine: Machine, workflow_name: String, template_ref: String) {
+    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    
+    // Delete the workflow first, so it can't take the next pipeline stage's with it
+    let delete_params = kube::api::DeleteParams::default();
+    match api.delete(&workflow_name, &delete_params).await {
+        Ok(_) => info!("Successfully deleted workflow {}", workflow_name),
+        Err(e) => warn!("Failed to delete workflow {}: {}", workflow_name, e),
+    }
+    
+    // Update machine status to Ready AFTER UI has chance to show completion message
+    if let Err(e) = update_machine_status_on_success(&machine, &template_ref).await {
+        warn!("Failed to update machine status after kexec detection: {}", e);
+    } else {
+        info!("Successfully marked machine {} as Ready", machine.id);
+    }
+}
+
 // Get workflow information from Kubernetes for a specific machine ID
 pub async fn get_workflow_info_by_id(id: &uuid::Uuid) -> Result<Option<WorkflowInfo>> {
     // First, find the machine by ID
@@ -1565,4 +1763,40 @@ pub async fn get_workflow_info_by_id(id: &uuid::Uuid) -> Result<Option<WorkflowI
             Err(anyhow!("Error fetching machine: {}", e))
         }
     }
-} 
\ No newline at end of file
+} 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finished_by_kexec() {
+        let status = serde_json::json!({
+            "state": "STATE_RUNNING",
+            "currentAction": "kexec to boot OS",
+            "tasks": [{"actions": [
+                {"name": "stream image", "status": "STATE_SUCCESS", "seconds": 120, "startedAt": "2026-10-16T10:00:00Z"},
+                {"name": "write netplan", "status": "STATE_PENDING"},
+                {"name": "kexec to boot OS", "status": "STATE_RUNNING", "seconds": 3, "startedAt": "2026-10-16T10:02:00Z"},
+            ]}],
+        });
+        assert!(finished_by_kexec(&status));
+        let tasks = kexec_completed_tasks(&status, "test-template");
+        let names: Vec<&str> = tasks.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["stream image", "kexec to boot OS"]);
+        assert!(tasks.iter().all(|t| t.status == "STATE_SUCCESS"));
+        assert_eq!(tasks[0].duration, 120);
+
+        let streaming = serde_json::json!({"state": "STATE_RUNNING", "currentAction": "stream image"});
+        assert!(!finished_by_kexec(&streaming));
+        let failed = serde_json::json!({"state": "STATE_FAILED", "currentAction": "kexec to boot OS"});
+        assert!(!finished_by_kexec(&failed));
+    }
+
+    #[test]
+    fn test_deletion_targets_match_registration() {
+        let (hardware, workflow) = deletion_targets("BC-24-11-B9-54-89");
+        assert_eq!(hardware, hardware_resource_name("bc:24:11:b9:54:89"));
+        assert_eq!(workflow, "os-install-bc-24-11-b9-54-89");
+    }
+}
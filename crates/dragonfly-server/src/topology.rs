@@ -0,0 +1,149 @@
+use dragonfly_common::models::{Machine, MachineStatus};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Height of a rack created without one, in rack units
+pub const DEFAULT_RACK_HEIGHT: u32 = 42;
+
+/// A datacenter or room holding racks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Site {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rack {
+    pub name: String,
+    pub site: String,
+    #[serde(default = "default_rack_height")]
+    pub height_u: u32,
+}
+
+fn default_rack_height() -> u32 {
+    DEFAULT_RACK_HEIGHT
+}
+
+/// Where a machine sits: its lowest rack unit, counted from 1 at the bottom, and how many units it takes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Placement {
+    #[serde(default)]
+    pub machine_id: Uuid,
+    pub rack: String,
+    pub position_u: u32,
+    #[serde(default = "default_machine_height")]
+    pub height_u: u32,
+}
+
+fn default_machine_height() -> u32 {
+    1
+}
+
+/// One row of a rack elevation, listed top to bottom. A machine taking several units has one row,
+/// at its top unit, spanning `height_u`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RackUnit {
+    pub position_u: u32,
+    pub height_u: u32,
+    pub machine_id: Option<Uuid>,
+    pub name: Option<String>,
+    pub status: Option<String>,
+    /// Tailwind colour for the machine's status
+    pub color: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RackElevation {
+    pub rack: Rack,
+    pub units: Vec<RackUnit>,
+    pub machine_count: usize,
+}
+
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 64 || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err("Site and rack names must be 1 to 64 letters, digits, '-', '_' or '.'".to_string());
+    }
+    Ok(())
+}
+
+fn overlaps(a: &Placement, b: &Placement) -> bool {
+    a.position_u < b.position_u + b.height_u && b.position_u < a.position_u + a.height_u
+}
+
+/// Check a placement fits in its rack and doesn't overlap another machine's units
+pub fn check_placement(rack: &Rack, placement: &Placement, others: &[Placement]) -> Result<(), String> {
+    if placement.height_u == 0 || placement.position_u == 0 {
+        return Err("Rack positions and heights start at 1".to_string());
+    }
+    if placement.position_u + placement.height_u - 1 > rack.height_u {
+        return Err(format!("Rack {} is only {}U tall", rack.name, rack.height_u));
+    }
+    if let Some(other) = others.iter().find(|o| o.machine_id != placement.machine_id && o.rack == placement.rack && overlaps(o, placement)) {
+        return Err(format!("U{} of rack {} is taken by machine {}", other.position_u, rack.name, other.machine_id));
+    }
+    Ok(())
+}
+
+fn status_color(status: &MachineStatus) -> &'static str {
+    match status {
+        MachineStatus::Ready | MachineStatus::ExistingOS => "bg-green-500",
+        MachineStatus::InstallingOS => "bg-yellow-500",
+        MachineStatus::AwaitingAssignment => "bg-blue-500",
+        MachineStatus::PendingApproval => "bg-amber-500",
+        MachineStatus::Offline | MachineStatus::Rejected => "bg-gray-500",
+        MachineStatus::Error(_) => "bg-red-500",
+    }
+}
+
+/// Lay out a rack's machines unit by unit, from the top of the rack down
+pub fn elevation(rack: &Rack, placements: &[Placement], machines: &HashMap<Uuid, &Machine>) -> RackElevation {
+    let in_rack: Vec<&Placement> = placements.iter().filter(|p| p.rack == rack.name).collect();
+    let mut units = Vec::new();
+    let mut position = rack.height_u;
+    while position > 0 {
+        match in_rack.iter().find(|p| p.position_u + p.height_u - 1 == position) {
+            Some(placement) => {
+                let machine = machines.get(&placement.machine_id);
+                units.push(RackUnit {
+                    position_u: position,
+                    height_u: placement.height_u,
+                    machine_id: Some(placement.machine_id),
+                    name: machine.map(|m| m.hostname.clone().or_else(|| m.memorable_name.clone()).unwrap_or_else(|| m.id.to_string())),
+                    status: machine.map(|m| m.status.to_string()),
+                    color: machine.map_or("bg-gray-300", |m| status_color(&m.status)),
+                });
+                position = position.saturating_sub(placement.height_u);
+            },
+            None => {
+                units.push(RackUnit { position_u: position, height_u: 1, machine_id: None, name: None, status: None, color: "" });
+                position -= 1;
+            },
+        }
+    }
+    RackElevation { rack: rack.clone(), units, machine_count: in_rack.len() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placement(position_u: u32, height_u: u32) -> Placement {
+        Placement { machine_id: Uuid::new_v4(), rack: "b12".to_string(), position_u, height_u }
+    }
+
+    #[test]
+    fn test_placement_and_elevation() {
+        let rack = Rack { name: "b12".to_string(), site: "syd1".to_string(), height_u: 4 };
+        let existing = vec![placement(1, 2)];
+        assert!(check_placement(&rack, &placement(3, 2), &existing).is_ok());
+        assert!(check_placement(&rack, &placement(2, 1), &existing).is_err());
+        assert!(check_placement(&rack, &placement(4, 2), &existing).is_err());
+
+        let units = elevation(&rack, &existing, &HashMap::new()).units;
+        let positions: Vec<u32> = units.iter().map(|u| u.position_u).collect();
+        assert_eq!(positions, vec![4, 3, 2]);
+        assert_eq!(units[2].height_u, 2);
+    }
+}
@@ -0,0 +1,288 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use serde::Serialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::auth::AdminUser;
+use crate::db;
+use crate::users::{self, UserError};
+
+const ISSUER: &str = "Dragonfly";
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Codes from one step either side of now are accepted, to allow for clock drift and slow typing
+const WINDOW: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Two-factor state stored with an account. A secret without `enabled` is an enrollment that
+/// hasn't been confirmed with a code yet.
+#[derive(Debug, Clone)]
+pub struct TotpState {
+    pub secret: String,
+    pub enabled: bool,
+    /// SHA-256 hashes of the unused recovery codes
+    pub recovery_codes: Vec<String>,
+    pub last_step: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpStatus {
+    pub enabled: bool,
+    pub recovery_codes_left: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Enrollment {
+    pub secret: String,
+    /// `otpauth://` URI for authenticator apps
+    pub uri: String,
+    /// The URI as an SVG QR code, drawn here so the settings page loads no script for it
+    pub qr_svg: String,
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(5) {
+        let mut buf = [0u8; 5];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let bits = u64::from_be_bytes([0, 0, 0, buf[0], buf[1], buf[2], buf[3], buf[4]]);
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            out.push(BASE32_ALPHABET[((bits >> (35 - i * 5)) & 31) as usize] as char);
+        }
+    }
+    out
+}
+
+fn base32_decode(value: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in value.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let index = BASE32_ALPHABET.iter().position(|&b| b == c.to_ascii_uppercase() as u8)?;
+        buffer = (buffer << 5) | index as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+// RFC 4226 HOTP with the RFC 6238 defaults: HMAC-SHA1, six digits
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// The time step a code matches, if any, so the caller can refuse to accept it twice
+fn matching_step(secret: &str, code: &str, now: DateTime<Utc>) -> Option<i64> {
+    let code = code.trim().replace(' ', "");
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = base32_decode(secret)?;
+    let current = now.timestamp() / STEP_SECS;
+    (current - WINDOW..=current + WINDOW).find(|step| *step >= 0 && hotp(&key, *step as u64) == code)
+}
+
+fn generate_secret() -> String {
+    let mut key = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut key);
+    base32_encode(&key)
+}
+
+fn provisioning_uri(username: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{user}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = urlencoding::encode(ISSUER),
+        user = urlencoding::encode(username),
+        secret = secret,
+        digits = DIGITS,
+        period = STEP_SECS,
+    )
+}
+
+fn qr_svg(uri: &str) -> String {
+    qrcode::QrCode::with_error_correction_level(uri, qrcode::EcLevel::M)
+        .map(|code| code.render::<qrcode::render::svg::Color>().min_dimensions(200, 200).build())
+        .unwrap_or_default()
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.trim().to_ascii_lowercase().replace(['-', ' '], "")
+}
+
+// Recovery codes are long random strings, so a fast hash is enough
+fn hash_recovery_code(code: &str) -> String {
+    hex::encode(Sha256::digest(normalize_recovery_code(code).as_bytes()))
+}
+
+fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).map(char::from).collect::<String>().to_ascii_lowercase();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+pub async fn status(user_id: i64) -> Result<TotpStatus, UserError> {
+    let state = db::get_user_totp(user_id).await?;
+    Ok(TotpStatus {
+        enabled: state.as_ref().is_some_and(|s| s.enabled),
+        recovery_codes_left: state.filter(|s| s.enabled).map_or(0, |s| s.recovery_codes.len()),
+    })
+}
+
+/// Whether signing in to this account needs a second factor
+pub async fn is_required(user_id: i64) -> anyhow::Result<bool> {
+    // Break-glass logins are for when everything else is broken, so they never ask for one
+    if user_id < 0 {
+        return Ok(false);
+    }
+    Ok(db::get_user_totp(user_id).await?.is_some_and(|s| s.enabled))
+}
+
+/// Start enrolling: a fresh secret is stored but not enforced until a code from it is confirmed
+pub async fn begin_enrollment(user: &AdminUser) -> Result<Enrollment, UserError> {
+    if user.id < 0 {
+        return Err(UserError::Invalid("Break-glass sessions can't enroll in two-factor authentication".to_string()));
+    }
+    if db::get_user_totp(user.id).await?.is_some_and(|s| s.enabled) {
+        return Err(UserError::Invalid("Two-factor authentication is already enabled; disable it first to re-enroll".to_string()));
+    }
+    let secret = generate_secret();
+    if !db::set_user_totp_secret(user.id, &secret).await? {
+        return Err(UserError::NotFound);
+    }
+    let uri = provisioning_uri(&user.username, &secret);
+    Ok(Enrollment { qr_svg: qr_svg(&uri), uri, secret })
+}
+
+/// Finish enrolling with a code from the authenticator app. Returns the recovery codes, which are only shown this once.
+pub async fn confirm_enrollment(user: &AdminUser, code: &str) -> Result<Vec<String>, UserError> {
+    let state = db::get_user_totp(user.id).await?
+        .filter(|s| !s.enabled)
+        .ok_or_else(|| UserError::Invalid("Start enrollment before confirming it".to_string()))?;
+    let Some(step) = matching_step(&state.secret, code, Utc::now()) else {
+        return Err(UserError::Invalid("That code doesn't match; check the authenticator app's clock".to_string()));
+    };
+
+    let codes = generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|c| hash_recovery_code(c)).collect();
+    db::enable_user_totp(user.id, &hashes, step).await?;
+    info!("User '{}' enabled two-factor authentication", user.username);
+    Ok(codes)
+}
+
+/// Turn off two-factor authentication for your own account; the password is required
+pub async fn disable(user: &AdminUser, password: &str) -> Result<(), UserError> {
+    let Some(password_hash) = db::get_user_password_hash(user.id).await? else {
+        return Err(UserError::NotFound);
+    };
+    if !users::verify_password(password_hash, password.to_string()).await {
+        return Err(UserError::WrongPassword);
+    }
+    db::clear_user_totp(user.id).await?;
+    info!("User '{}' disabled two-factor authentication", user.username);
+    Ok(())
+}
+
+/// Clear another operator's second factor after they lose their device
+pub async fn reset(actor: &AdminUser, id: i64) -> Result<(), UserError> {
+    let account = db::get_user_account(id).await?.ok_or(UserError::NotFound)?;
+    db::clear_user_totp(id).await?;
+    warn!("Two-factor authentication for '{}' reset by '{}'", account.username, actor.username);
+    Ok(())
+}
+
+/// Replace the recovery codes, invalidating the old ones
+pub async fn regenerate_recovery_codes(user: &AdminUser) -> Result<Vec<String>, UserError> {
+    if !db::get_user_totp(user.id).await?.is_some_and(|s| s.enabled) {
+        return Err(UserError::Invalid("Two-factor authentication is not enabled".to_string()));
+    }
+    let codes = generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|c| hash_recovery_code(c)).collect();
+    db::set_user_recovery_codes(user.id, &hashes).await?;
+    info!("User '{}' regenerated their recovery codes", user.username);
+    Ok(codes)
+}
+
+/// Check the second factor at login: an authenticator code, each usable once, or an unused recovery code
+pub async fn verify_login(user_id: i64, code: &str) -> anyhow::Result<bool> {
+    let Some(state) = db::get_user_totp(user_id).await?.filter(|s| s.enabled) else {
+        return Ok(false);
+    };
+
+    if let Some(step) = matching_step(&state.secret, code, Utc::now()) {
+        if state.last_step.is_some_and(|last| step <= last) {
+            warn!("Rejected a reused authenticator code for user {}", user_id);
+            return Ok(false);
+        }
+        return db::record_totp_step(user_id, step).await;
+    }
+
+    match db::use_recovery_code(user_id, &hash_recovery_code(code)).await? {
+        Some(left) => {
+            warn!("User {} signed in with a recovery code; {} left", user_id, left);
+            Ok(true)
+        },
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        // The SHA-1 test secret from RFC 6238 appendix B, truncated to six digits
+        let secret = base32_encode(b"12345678901234567890");
+        let at = |secs| Utc.timestamp_opt(secs, 0).unwrap();
+        assert_eq!(matching_step(&secret, "287082", at(59)), Some(1));
+        assert_eq!(matching_step(&secret, "005924", at(1234567890)), Some(41152263));
+        assert_eq!(matching_step(&secret, "279037", at(2000000000)), Some(66666666));
+        assert_eq!(matching_step(&secret, "000000", at(59)), None);
+    }
+
+    #[test]
+    fn test_base32_round_trip() {
+        let secret = generate_secret();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(base32_encode(&base32_decode(&secret).unwrap()), secret);
+        assert_eq!(hash_recovery_code("ABCDE-fghij"), hash_recovery_code("abcdefghij"));
+        assert!(qr_svg(&provisioning_uri("ops", &secret)).contains("<svg"));
+    }
+
+    #[tokio::test]
+    async fn test_recovery_codes_are_spent_once() {
+        let pool = crate::test_support::database().await;
+        for statement in [
+            "INSERT INTO users (id, username, password_hash, created_at, updated_at, totp_secret, totp_enabled) VALUES (1, 'ops', 'hash', 'now', 'now', 'SECRET', 1)",
+            "INSERT INTO totp_recovery_codes (user_id, code_hash) VALUES (1, 'a1'), (1, 'b2')",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+        assert_eq!(db::spend_recovery_code(&pool, 1, "a1").await.unwrap(), Some(1));
+        assert_eq!(db::spend_recovery_code(&pool, 1, "a1").await.unwrap(), None);
+        assert_eq!(db::spend_recovery_code(&pool, 1, "zz").await.unwrap(), None);
+
+        // Not once two-factor is off
+        sqlx::query("UPDATE users SET totp_enabled = 0").execute(&pool).await.unwrap();
+        assert_eq!(db::spend_recovery_code(&pool, 1, "b2").await.unwrap(), None);
+    }
+}
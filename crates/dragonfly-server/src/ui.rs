@@ -5,19 +5,22 @@ use axum::{
     routing::{get, post},
     Form, Router,
 };
-use dragonfly_common::models::{Machine, MachineStatus, DiskInfo};
+use dragonfly_common::models::{Machine, MachineStatus};
 use tracing::{error, info, warn};
 use std::collections::HashMap;
-use chrono::{DateTime, Utc, TimeZone};
+use chrono::{DateTime, Utc};
 use cookie::{Cookie, SameSite};
 use std::fs;
 use serde::Serialize;
 use crate::db::{self, get_app_settings, save_app_settings, mark_setup_completed};
 use crate::auth::{self, AuthSession, Settings, Credentials};
+use crate::hostname_policy::HostnamePolicy;
+use crate::boot_filter::BootFilter;
+use crate::status_board::StatusBoardConfig;
+use crate::custom_fields::FieldDefinition;
 use crate::mode;
 use minijinja::{Error as MiniJinjaError, ErrorKind as MiniJinjaErrorKind};
 use std::sync::Arc;
-use std::net::{IpAddr, Ipv4Addr};
 use crate::tinkerbell::WorkflowInfo;
 use uuid::Uuid;
 
@@ -27,20 +30,61 @@ use crate::{AppState, INSTALL_STATE_REF, InstallationState};
 // Import format_os_name from api.rs
 use crate::api::{format_os_name, get_os_icon, get_os_info};
 
-// Extract theme from cookies
+pub const THEME_COOKIE: &str = "dragonfly_theme";
+pub const PALETTE_COOKIE: &str = "dragonfly_palette";
+
+// Extract theme from the signed-in user's preferences, or else from cookies
 pub fn get_theme_from_cookie(headers: &HeaderMap) -> String {
+    if let Some(theme) = crate::preferences::current().and_then(|preferences| preferences.theme) {
+        return theme;
+    }
     if let Some(cookie_header) = headers.get(header::COOKIE) {
         if let Ok(cookie_str) = cookie_header.to_str() {
             for cookie_pair in cookie_str.split(';') {
                 if let Ok(cookie) = Cookie::parse(cookie_pair.trim()) {
-                    if cookie.name() == "dragonfly_theme" {
+                    if cookie.name() == THEME_COOKIE {
                         return cookie.value().to_string();
                     }
                 }
             }
         }
     }
-    "light".to_string()
+    // Browsers that haven't picked a theme get the one an admin chose
+    crate::settings::current().default_theme.unwrap_or_else(|| "light".to_string())
+}
+
+/// Status colour palettes, applied on top of the light or dark theme
+pub const PALETTES: [&str; 3] = ["default", "high-contrast", "color-blind"];
+
+// Extract the status palette from the signed-in user's preferences or cookies, falling back to
+// the default for unknown values
+pub fn get_palette_from_cookie(headers: &HeaderMap) -> String {
+    let palette = crate::preferences::current().and_then(|preferences| preferences.palette).or_else(|| {
+        headers.get_all(header::COOKIE).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| Cookie::parse(pair.trim()).ok())
+            .find(|cookie| cookie.name() == PALETTE_COOKIE)
+            .map(|cookie| cookie.value().to_string())
+    });
+    normalize_palette(palette.as_deref())
+}
+
+fn normalize_palette(palette: Option<&str>) -> String {
+    palette.filter(|p| PALETTES.contains(p)).unwrap_or(PALETTES[0]).to_string()
+}
+
+/// A year-long cookie holding an appearance choice, for when nobody is signed in
+pub fn preference_cookie(name: &'static str, value: &str) -> Cookie<'static> {
+    let mut cookie = Cookie::new(name, value.to_string());
+    cookie.set_path("/");
+    cookie.set_max_age(time::Duration::days(365));
+    cookie.set_same_site(SameSite::Lax);
+    cookie
+}
+
+fn palette_cookie(palette: &str) -> Cookie<'static> {
+    preference_cookie(PALETTE_COOKIE, palette)
 }
 
 // Update struct for MiniJinja context, matching data from api.rs handler
@@ -57,23 +101,51 @@ pub struct IndexTemplate {
     pub machines: Vec<Machine>,
     pub status_counts: HashMap<String, usize>,
     pub status_counts_json: String,
+    pub trends_json: String, // Installs per day and by template, see `observability::ProvisioningTrends`
     pub theme: String,
+    pub palette: String,
     pub is_authenticated: bool,
     pub display_dates: HashMap<String, String>,
     pub installation_in_progress: bool,
     pub initial_install_message: String,
     pub initial_animation_class: String,
     pub is_demo_mode: bool,
+    pub pinned_views: Vec<PinnedView>,
     pub current_path: String,
 }
 
+/// A saved view pinned to the dashboard, with what it finds now
+#[derive(Serialize)]
+pub struct PinnedView {
+    pub id: i64,
+    pub name: String,
+    pub query: String,
+    pub total: usize,
+    pub status_counts: HashMap<String, usize>,
+}
+
 #[derive(Serialize)]
 pub struct MachineListTemplate {
     pub machines: Vec<Machine>,
     pub theme: String,
+    pub palette: String,
     pub is_authenticated: bool,
     pub is_admin: bool,
     pub workflow_infos: HashMap<uuid::Uuid, crate::tinkerbell::WorkflowInfo>,
+    pub conflicts: HashMap<uuid::Uuid, Vec<String>>, // Address conflicts per machine
+    pub drift: HashMap<uuid::Uuid, Vec<String>>, // Hardware resources changed outside Dragonfly
+    pub custom_fields: Vec<FieldDefinition>, // Only those shown in the list
+    pub field_values: HashMap<uuid::Uuid, HashMap<String, serde_json::Value>>,
+    pub export_columns: Vec<String>, // Every column the inventory export offers
+    pub search: String, // The search box, e.g. `status:error os:ubuntu`
+    pub search_error: Option<String>,
+    pub column_choices: Vec<String>, // Columns a saved view can show
+    pub columns: Vec<String>, // Built-in columns shown, see `views::COLUMNS`
+    pub sort: String,
+    pub saved_views: Vec<crate::views::SavedView>,
+    pub active_view: Option<crate::views::SavedView>,
+    pub page: crate::views::Page,
+    pub page_query: String, // The list's query string without the page, for page links
     pub current_path: String,
 }
 
@@ -82,6 +154,7 @@ pub struct MachineListTemplate {
 pub struct MachineDetailsTemplate {
     pub machine_json: String, // Serialized machine data
     pub theme: String,
+    pub palette: String,
     pub is_authenticated: bool,
     pub created_at_formatted: String,
     pub updated_at_formatted: String,
@@ -90,20 +163,135 @@ pub struct MachineDetailsTemplate {
     pub workflow_info: Option<WorkflowInfo>, // Original workflow info for convenience
     pub current_path: String,
     pub ip_address_type: String, // New field for IP address type
+    pub clock_skew_threshold: i64, // Seconds of agent clock skew before we warn
+    pub conflicts: Vec<String>, // Address conflicts blocking provisioning
+    pub status_history: Vec<StatusHistoryEntry>, // Newest first
+    pub custom_fields: Vec<FieldDefinition>,
+    pub field_values: HashMap<String, serde_json::Value>,
+    pub bmc_jobs: Vec<BmcJobEntry>, // Newest first
+}
+
+/// One status change as shown on the machine details timeline
+#[derive(Serialize)]
+pub struct StatusHistoryEntry {
+    pub from: Option<String>,
+    pub to: String,
+    pub is_error: bool,
+    pub cause: String,
+    pub changed_at: String,
+}
+
+/// A power or boot-device job as shown on the machine details page
+#[derive(Serialize)]
+pub struct BmcJobEntry {
+    pub summary: String,
+    pub backend: &'static str,
+    pub state: &'static str,
+    pub message: Option<String>,
+    pub requested_by: String,
+    pub created_at: String,
+}
+
+impl From<crate::bmc::BmcJob> for BmcJobEntry {
+    fn from(job: crate::bmc::BmcJob) -> Self {
+        Self {
+            summary: job.tasks.iter().map(|task| task.to_string()).collect::<Vec<_>>().join(", then "),
+            backend: job.backend.as_str(),
+            state: job.state.as_str(),
+            message: job.message,
+            requested_by: job.requested_by,
+            created_at: job.created_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        }
+    }
+}
+
+impl From<db::StatusChange> for StatusHistoryEntry {
+    fn from(change: db::StatusChange) -> Self {
+        Self {
+            from: change.from.map(|status| status.to_string()),
+            is_error: matches!(change.to, MachineStatus::Error(_)),
+            to: change.to.to_string(),
+            cause: change.cause.map_or_else(|| "Unknown".to_string(), |cause| cause.to_string()),
+            changed_at: change.changed_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct StackHealthTemplate {
+    pub theme: String,
+    pub palette: String,
+    pub is_authenticated: bool,
+    pub current_path: String,
+    pub health: crate::status::StackHealth,
+}
+
+#[derive(Serialize)]
+pub struct RacksTemplate {
+    pub theme: String,
+    pub palette: String,
+    pub is_authenticated: bool,
+    pub current_path: String,
+    pub sites: Vec<SiteRacks>,
+}
+
+#[derive(Serialize)]
+pub struct ComplianceTemplate {
+    pub theme: String,
+    pub palette: String,
+    pub is_authenticated: bool,
+    pub current_path: String,
+    pub report: Option<crate::os_lifecycle::ComplianceReport>,
+}
+
+/// The workflow template editor, which loads templates through the API
+#[derive(Serialize)]
+pub struct WorkflowTemplatesTemplate {
+    pub theme: String,
+    pub palette: String,
+    pub is_authenticated: bool,
+    pub current_path: String,
+}
+
+/// The maintenance window calendar and held installs, loaded through the API
+#[derive(Serialize)]
+pub struct MaintenanceTemplate {
+    pub theme: String,
+    pub palette: String,
+    pub is_authenticated: bool,
+    pub current_path: String,
+}
+
+/// A site with the elevation of each of its racks
+#[derive(Serialize)]
+pub struct SiteRacks {
+    pub site: crate::topology::Site,
+    pub racks: Vec<crate::topology::RackElevation>,
 }
 
 #[derive(Serialize)]
 pub struct SettingsTemplate {
     pub theme: String,
+    pub palette: String,
     pub is_authenticated: bool,
     pub admin_username: String,
     pub require_login: bool,
+    pub require_approval: bool,
+    pub generate_root_passwords: bool,
+    pub block_eol_assignments: bool,
     pub default_os_none: bool,
     pub default_os_ubuntu2204: bool,
     pub default_os_ubuntu2404: bool,
     pub default_os_debian12: bool,
     pub default_os_proxmox: bool,
     pub default_os_talos: bool,
+    /// Admin-chosen theme for browsers without one; empty when unset
+    pub default_theme: String,
+    pub hostname_policy: HostnamePolicy,
+    pub boot_filter: BootFilter,
+    pub require_boot_auth: bool,
+    pub boot_auth_exempt_subnets: Vec<String>,
+    pub status_board: StatusBoardConfig,
     pub has_initial_password: bool,
     pub rendered_password: String,
     pub show_admin_settings: bool,
@@ -114,6 +302,7 @@ pub struct SettingsTemplate {
 #[derive(Serialize)]
 pub struct WelcomeTemplate {
     pub theme: String,
+    pub palette: String,
     pub is_authenticated: bool,
     pub hide_footer: bool,
     pub current_path: String,
@@ -122,6 +311,7 @@ pub struct WelcomeTemplate {
 #[derive(Serialize)]
 pub struct ErrorTemplate {
     pub theme: String,
+    pub palette: String,
     pub is_authenticated: bool,
     pub title: String,
     pub message: String,
@@ -139,8 +329,23 @@ pub fn render_minijinja<T: Serialize>(
     template_name: &str, 
     context: T
 ) -> Response {
+    match render_to_string(app_state, template_name, context) {
+        Ok(html) => Html(html).into_response(),
+        Err(e) => {
+            error!("MiniJinja render/load error for {}: {}", template_name, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Template error: {}", e)).into_response()
+        }
+    }
+}
+
+// Render a template without wrapping it in a response, for output that isn't an HTML page
+pub fn render_to_string<T: Serialize>(
+    app_state: &crate::AppState,
+    template_name: &str,
+    context: T
+) -> Result<String, MiniJinjaError> {
     // Get the environment based on the mode (static or reloading)
-    let render_result = match &app_state.template_env {
+    match &app_state.template_env {
         crate::TemplateEnv::Static(env) => {
             env.get_template(template_name)
                .and_then(|tmpl| tmpl.render(context))
@@ -161,15 +366,6 @@ pub fn render_minijinja<T: Serialize>(
                 }
             }
         }
-    };
-
-    // Handle the final rendering result
-    match render_result {
-        Ok(html) => Html(html).into_response(),
-        Err(e) => {
-            error!("MiniJinja render/load error for {}: {}", template_name, e);
-            (StatusCode::INTERNAL_SERVER_ERROR, format!("Template error: {}", e)).into_response()
-        }
     }
 }
 
@@ -180,6 +376,19 @@ pub fn ui_router() -> Router<crate::AppState> {
         .route("/machines", get(machine_list))
         .route("/machines/{id}", get(machine_details))
         .route("/theme/toggle", get(toggle_theme))
+        .route("/no-js/toggle", get(crate::no_js::toggle))
+        .route("/status-board", get(crate::status_board::page))
+        .route("/kiosk", get(crate::kiosk::page))
+        // Form versions of the machine actions, for browsers without JavaScript
+        .route("/machines/{id}/os", post(crate::no_js::assign_os))
+        .route("/machines/{id}/reimage", post(crate::no_js::reimage))
+        .route("/machines/{id}/approve", post(crate::no_js::approve))
+        .route("/machines/{id}/reject", post(crate::no_js::reject))
+        .route("/monitoring", get(stack_health_page))
+        .route("/racks", get(racks_page))
+        .route("/compliance", get(compliance_page))
+        .route("/templates", get(workflow_templates_page))
+        .route("/maintenance", get(maintenance_page))
         .route("/settings", get(settings_page))
         .route("/settings", post(update_settings))
         .route("/welcome", get(welcome_page))
@@ -199,6 +408,8 @@ fn count_machines_by_status(machines: &[Machine]) -> HashMap<String, usize> {
     counts.insert("Installing OS".to_string(), 0);
     counts.insert("Ready".to_string(), 0);
     counts.insert("Offline".to_string(), 0);
+    counts.insert("Pending Approval".to_string(), 0);
+    counts.insert("Rejected".to_string(), 0);
     counts.insert("Error".to_string(), 0);
     
     // Count actual statuses
@@ -209,6 +420,8 @@ fn count_machines_by_status(machines: &[Machine]) -> HashMap<String, usize> {
             MachineStatus::InstallingOS => "Installing OS",
             MachineStatus::Ready => "Ready",
             MachineStatus::Offline => "Offline",
+            MachineStatus::PendingApproval => "Pending Approval",
+            MachineStatus::Rejected => "Rejected",
             MachineStatus::Error(_) => "Error",
         };
         
@@ -223,157 +436,8 @@ fn format_datetime(dt: &DateTime<Utc>) -> String {
     dt.format("%Y-%m-%d %H:%M:%S UTC").to_string()
 }
 
-// Function to generate demo machines
-fn generate_demo_machines() -> Vec<Machine> {
-    let mut machines = Vec::new();
-    let base_time = Utc.with_ymd_and_hms(2023, 4, 15, 12, 0, 0).unwrap();
-    let base_mac = [0x52, 0x54, 0x00, 0xAB, 0xCD, 0x00];
-    let base_ip = Ipv4Addr::new(10, 0, 42, 0);
-
-    // Generate topaz-control[01:03]
-    for i in 1..=3 {
-        let hostname = format!("topaz-control{:02}", i);
-        let mac_suffix = i as u8;
-        let ip_suffix = 10 + i as u8;
-        machines.push(create_demo_machine(
-            &hostname, 
-            base_mac, 
-            mac_suffix, 
-            base_ip, 
-            ip_suffix, 
-            base_time.clone(), 
-            MachineStatus::Ready,
-            Some(500), // 500GB disk
-        ));
-    }
-
-    // Generate topaz-worker[01:06]
-    for i in 1..=6 {
-        let hostname = format!("topaz-worker{:02}", i);
-        let mac_suffix = 10 + i as u8;
-        let ip_suffix = 20 + i as u8;
-        machines.push(create_demo_machine(
-            &hostname, 
-            base_mac, 
-            mac_suffix, 
-            base_ip, 
-            ip_suffix, 
-            base_time.clone(), 
-            MachineStatus::Ready,
-            Some(2000), // 2TB disk
-        ));
-    }
-
-    // Generate cubefs-master[01:03]
-    for i in 1..=3 {
-        let hostname = format!("cubefs-master{:02}", i);
-        let mac_suffix = 20 + i as u8;
-        let ip_suffix = 30 + i as u8;
-        machines.push(create_demo_machine(
-            &hostname, 
-            base_mac, 
-            mac_suffix,
-            base_ip, 
-            ip_suffix, 
-            base_time.clone(), 
-            MachineStatus::Ready,
-            Some(500), // 500GB disk
-        ));
-    }
-
-    // Generate cubefs-datanode[01:06]
-    for i in 1..=6 {
-        let hostname = format!("cubefs-datanode{:02}", i);
-        let mac_suffix = 30 + i as u8;
-        let ip_suffix = 40 + i as u8;
-        let status = if i <= 5 { 
-            MachineStatus::Ready 
-        } else { 
-            // Make one datanode show as "installing" for variety
-            MachineStatus::InstallingOS 
-        };
-        machines.push(create_demo_machine(
-            &hostname, 
-            base_mac, 
-            mac_suffix, 
-            base_ip, 
-            ip_suffix, 
-            base_time.clone(), 
-            status,
-            Some(4000), // 4TB disk
-        ));
-    }
-
-    machines
-}
-
-// Helper function to create a demo machine
-fn create_demo_machine(
-    hostname: &str,
-    base_mac: [u8; 6],
-    mac_suffix: u8,
-    base_ip: Ipv4Addr,
-    ip_suffix: u8,
-    base_time: DateTime<Utc>,
-    status: MachineStatus,
-    disk_size_gb: Option<u64>,
-) -> Machine {
-    // Generate a deterministic UUID based on hostname
-    let mut mac = base_mac;
-    mac[5] = mac_suffix;
-    
-    // Use UUID v5 to create a deterministic UUID from the hostname
-    // This allows machine details to be found consistently in demo mode
-    let namespace = uuid::Uuid::NAMESPACE_DNS;
-    let uuid = uuid::Uuid::new_v5(&namespace, hostname.as_bytes());
-    let created_at = base_time + chrono::Duration::minutes(mac_suffix as i64);
-    let updated_at = created_at + chrono::Duration::hours(1);
-    
-    let mut ip_octets = base_ip.octets();
-    ip_octets[3] = ip_suffix;
-    let ip = IpAddr::V4(Ipv4Addr::from(ip_octets));
-
-    // Format MAC address with colons
-    let mac_string = format!(
-        "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
-        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
-    );
-
-    // Generate memorable name using BIP39 words based on MAC address
-    let memorable_name = dragonfly_common::mac_to_words::mac_to_words_safe(&mac_string);
-
-    // Create a disk to match the requested disk size
-    let disk = DiskInfo {
-        device: format!("/dev/sda"),
-        size_bytes: disk_size_gb.unwrap_or(500) * 1_073_741_824, // Convert GB to bytes
-        model: Some(format!("Demo Disk {}", disk_size_gb.unwrap_or(500))),
-        calculated_size: Some(format!("{} GB", disk_size_gb.unwrap_or(500))),
-    };
-
-    // Create the machine with the correct fields
-    Machine {
-        id: uuid,
-        hostname: Some(hostname.to_string()),
-        mac_address: mac_string,
-        ip_address: ip.to_string(), // No Option<> here, ip_address is a String
-        status,
-        os_choice: Some("ubuntu-2204".to_string()),
-        os_installed: Some("Ubuntu 22.04".to_string()),
-        disks: vec![disk],
-        nameservers: vec!["8.8.8.8".to_string(), "1.1.1.1".to_string()],
-        memorable_name: Some(memorable_name),
-        created_at,
-        updated_at,
-        bmc_credentials: None,
-        installation_progress: 0,
-        installation_step: None,
-        last_deployment_duration: None,
-        // Initialize new hardware fields to None for demo data
-        cpu_model: None,
-        cpu_cores: None,
-        total_ram_bytes: None,
-    }
-}
+/// Days of install history the dashboard charts cover
+const DASHBOARD_TREND_DAYS: i64 = 14;
 
 #[axum::debug_handler]
 pub async fn index(
@@ -383,6 +447,7 @@ pub async fn index(
     uri: OriginalUri,
 ) -> Response {
     let theme = get_theme_from_cookie(&headers);
+    let palette = get_palette_from_cookie(&headers);
     let is_authenticated = auth_session.user.is_some();
     let require_login = app_state.settings.lock().await.require_login;
     let current_path = uri.path().to_string();
@@ -423,6 +488,7 @@ pub async fn index(
             warn!("Root route accessed in unexpected state (not demo, not installed, not install server). Rendering error.");
             let context = ErrorTemplate {
                 theme,
+                palette,
                 is_authenticated: false, // Assume not authenticated
                 title: "Unexpected Server State".to_string(),
                 message: "The server is in an unexpected state. Installation might be incomplete or the server requires setup.".to_string(),
@@ -470,8 +536,8 @@ pub async fn index(
     // Fetch real/demo data based on app_state.is_demo_mode
     let (machines, status_counts, status_counts_json, display_dates) = if !installation_in_progress {
         if app_state.is_demo_mode { // Check the state flag now
-            // In demo mode, generate fake demo machines
-            let demo_machines = generate_demo_machines();
+            // In demo mode, show the simulated fleet
+            let demo_machines = crate::demo::machines();
             let counts = count_machines_by_status(&demo_machines);
             let counts_json = serde_json::to_string(&counts).unwrap_or_else(|_| "{}".to_string());
             let dates = demo_machines.iter()
@@ -479,8 +545,8 @@ pub async fn index(
                 .collect();
             (demo_machines, counts, counts_json, dates)
         } else {
-            // Normal mode - fetch real machines from database
-            match db::get_all_machines().await {
+            // Normal mode - fetch real machines, usually from the machine cache
+            match crate::machine_cache::get_all_machines().await {
                 Ok(m) => {
                     let counts = count_machines_by_status(&m);
                     let counts_json = serde_json::to_string(&counts).unwrap_or_else(|_| "{}".to_string());
@@ -500,18 +566,58 @@ pub async fn index(
         (vec![], HashMap::new(), "{}".to_string(), HashMap::new())
     };
 
+    let trends_json = if installation_in_progress {
+        "null".to_string()
+    } else {
+        match crate::observability::provisioning_trends(DASHBOARD_TREND_DAYS).await {
+            // Embedded in a script tag, so template names mustn't be able to close it
+            Ok(trends) => serde_json::to_string(&trends).map_or_else(|_| "null".to_string(), |json| json.replace("</", "<\\/")),
+            Err(e) => {
+                error!("Error computing provisioning trends: {}", e);
+                "null".to_string()
+            }
+        }
+    };
+
+    let mut pinned_views = Vec::new();
+    if is_authenticated && !installation_in_progress {
+        let views = db::get_saved_views(&crate::policy::principal(&auth_session)).await.unwrap_or_else(|e| {
+            error!("Error fetching saved views: {}", e);
+            Vec::new()
+        });
+        for view in views.into_iter().filter(|view| view.pinned) {
+            // Views are validated when saved, but the search syntax may have changed since
+            let Ok(search) = crate::search::Search::parse(&view.query) else {
+                continue;
+            };
+            match crate::search::find(&search).await {
+                Ok(found) => pinned_views.push(PinnedView {
+                    id: view.id,
+                    name: view.name,
+                    query: view.query,
+                    total: found.len(),
+                    status_counts: count_machines_by_status(&found),
+                }),
+                Err(e) => error!("Error counting machines for view {}: {}", view.name, e),
+            }
+        }
+    }
+
     let context = IndexTemplate {
         title: "Dragonfly".to_string(),
         machines,
         status_counts,
         status_counts_json,
+        trends_json,
         theme,
+        palette,
         is_authenticated,
         display_dates,
         installation_in_progress,
         initial_install_message,
         initial_animation_class,
         is_demo_mode: app_state.is_demo_mode, // Use the state flag
+        pinned_views,
         current_path,
     };
 
@@ -523,10 +629,12 @@ pub async fn machine_list(
     headers: HeaderMap,
     auth_session: AuthSession,
     uri: OriginalUri,
+    Query(params): Query<HashMap<String, String>>,
 ) -> Response {
     let theme = get_theme_from_cookie(&headers);
+    let palette = get_palette_from_cookie(&headers);
     let is_authenticated = auth_session.user.is_some();
-    let is_admin = is_authenticated;
+    let is_admin = auth::is_admin(&auth_session);
     let current_path = uri.path().to_string();
 
     let require_login = app_state.settings.lock().await.require_login;
@@ -557,26 +665,91 @@ pub async fn machine_list(
     // Determine if we are in demo mode (using the state flag)
     let is_demo_mode = app_state.is_demo_mode;
 
+    // A saved view, or the user's default one when the list is opened without a search
+    let saved_views = if is_authenticated {
+        db::get_saved_views(&crate::policy::principal(&auth_session)).await.unwrap_or_else(|e| {
+            error!("Error fetching saved views: {}", e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    let active_view = match params.get("view").and_then(|id| id.parse::<i64>().ok()) {
+        Some(id) => saved_views.iter().find(|view| view.id == id).cloned(),
+        None if !params.contains_key("q") && !params.contains_key("sort") => saved_views.iter().find(|view| view.is_default).cloned(),
+        None => None,
+    };
+    let view_columns = active_view.as_ref().and_then(|view| view.columns.clone());
+    let columns = crate::views::visible_columns(view_columns.as_deref());
+    let sort = params.get("sort").cloned()
+        .or_else(|| active_view.as_ref().and_then(|view| view.sort.clone()))
+        .unwrap_or_default();
+
+    // A query that doesn't parse lists nothing, with the reason next to the search box
+    let search = params.get("q").map(|q| q.trim().to_string())
+        .or_else(|| active_view.as_ref().map(|view| view.query.clone()))
+        .unwrap_or_default();
+    let parsed = crate::search::Search::parse(&search);
+    let search_error = parsed.as_ref().err().cloned();
+
+    // Long lists are split into pages of the size the user prefers
+    let page_size = crate::preferences::current().and_then(|preferences| preferences.page_size);
+    let requested_page = params.get("page").and_then(|page| page.parse().ok()).unwrap_or(1);
+    // The search is kept even when empty, so later pages don't switch to the default view
+    let page_query = [("q", Some(search.clone())), ("sort", Some(sort.clone()).filter(|sort| !sort.is_empty())), ("view", active_view.as_ref().map(|view| view.id.to_string()))]
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| format!("{}={}", key, urlencoding::encode(&value))))
+        .collect::<Vec<_>>()
+        .join("&");
+
     // If in demo mode, show demo machines
     if is_demo_mode {
-        // Generate demo machines
-        let machines = generate_demo_machines();
-        // Create an empty workflow info map
-        let workflow_infos = HashMap::new();
+        // Show the simulated fleet, with its installs' progress
+        let mut machines: Vec<Machine> = match &parsed {
+            Ok(query) => crate::demo::machines().into_iter().filter(|machine| query.matches(machine)).collect(),
+            Err(_) => Vec::new(),
+        };
+        crate::views::sort_machines(&mut machines, &sort);
+        let page = crate::views::paginate(&mut machines, requested_page, page_size);
+        let workflow_infos = machines.iter()
+            .filter_map(|machine| crate::demo::workflow_info(machine).map(|info| (machine.id, info)))
+            .collect();
 
         let context = MachineListTemplate {
             machines,
             theme,
+            palette,
             is_authenticated,
             is_admin,
             workflow_infos,
+            conflicts: HashMap::new(),
+            drift: HashMap::new(),
+            custom_fields: Vec::new(),
+            field_values: HashMap::new(),
+            export_columns: crate::export::COLUMNS.iter().map(|c| c.to_string()).collect(),
+            search,
+            search_error,
+            column_choices: crate::views::COLUMNS.iter().map(|c| c.to_string()).collect(),
+            columns,
+            sort,
+            saved_views,
+            active_view,
+            page,
+            page_query,
             current_path,
         };
         return render_minijinja(&app_state, "machine_list.html", context);
     } else { // Normal mode
         // Normal mode - fetch machines from database
-        match db::get_all_machines().await {
-            Ok(machines) => {
+        let machines = match &parsed {
+            Ok(query) if !query.is_empty() => db::search_machines(query).await,
+            Ok(_) => db::get_all_machines().await,
+            Err(_) => Ok(Vec::new()),
+        };
+        match machines {
+            Ok(mut machines) => {
+                crate::views::sort_machines(&mut machines, &sort);
+                let page = crate::views::paginate(&mut machines, requested_page, page_size);
                 let mut workflow_infos = HashMap::new();
                 for machine in &machines {
                     if machine.status == MachineStatus::InstallingOS {
@@ -593,13 +766,47 @@ pub async fn machine_list(
                     }
                 }
 
+                let conflicts = crate::conflicts::reasons(&machines);
+
+                let custom_fields = db::get_custom_fields().await.unwrap_or_else(|e| {
+                    error!("Error fetching custom fields: {}", e);
+                    Vec::new()
+                });
+                let export_columns = crate::export::COLUMNS.iter().map(|c| c.to_string())
+                    .chain(custom_fields.iter().map(|f| format!("{}{}", crate::custom_fields::FILTER_PREFIX, f.name)))
+                    .collect();
+                let column_choices = crate::views::COLUMNS.iter().map(|c| c.to_string())
+                    .chain(custom_fields.iter().map(|f| format!("{}{}", crate::custom_fields::FILTER_PREFIX, f.name)))
+                    .collect();
+                let custom_fields: Vec<FieldDefinition> = crate::views::visible_fields(view_columns.as_deref(), custom_fields);
+                let field_values = if custom_fields.is_empty() {
+                    HashMap::new()
+                } else {
+                    db::get_all_field_values().await.unwrap_or_default()
+                };
+
                 // Replace Askama render with placeholder
                 let context = MachineListTemplate {
                     machines,
                     theme,
+                    palette,
                     is_authenticated,
                     is_admin,
                     workflow_infos,
+                    conflicts,
+                    drift: crate::hardware_sync::reasons(),
+                    custom_fields,
+                    field_values,
+                    export_columns,
+                    search,
+                    search_error,
+                    column_choices,
+                    columns,
+                    sort,
+                    saved_views,
+                    active_view,
+                    page,
+                    page_query,
                     current_path,
                 };
                 // Pass AppState to render_minijinja
@@ -611,9 +818,24 @@ pub async fn machine_list(
                 let context = MachineListTemplate {
                     machines: vec![],
                     theme,
+                    palette,
                     is_authenticated,
                     is_admin,
                     workflow_infos: HashMap::new(),
+                    conflicts: HashMap::new(),
+                    drift: HashMap::new(),
+                    custom_fields: Vec::new(),
+                    field_values: HashMap::new(),
+                    export_columns: Vec::new(),
+                    search,
+                    search_error,
+                    column_choices: Vec::new(),
+                    columns,
+                    sort,
+                    saved_views,
+                    active_view,
+                    page: crate::views::Page { number: 1, count: 1, total: 0 },
+                    page_query,
                     current_path,
                 };
                 // Pass AppState to render_minijinja
@@ -632,6 +854,7 @@ pub async fn machine_details(
 ) -> Response {
     // Get theme preference from cookie
     let theme = get_theme_from_cookie(&headers);
+    let palette = get_palette_from_cookie(&headers);
     let is_authenticated = auth_session.user.is_some();
     let current_path = uri.path().to_string();
     
@@ -639,48 +862,32 @@ pub async fn machine_details(
     let require_login = app_state.settings.lock().await.require_login;
     
     // If require_login is enabled and user is not authenticated,
-    // redirect to login page
+    // redirect to login page unless an ACL lets them view this machine
     if require_login && !is_authenticated {
-        return Redirect::to("/login").into_response();
+        let can_view = match uuid::Uuid::parse_str(&id) {
+            Ok(uuid) => crate::policy::check(&auth_session, &uuid, crate::policy::Permission::View).await.unwrap_or(false),
+            Err(_) => false,
+        };
+        if !can_view {
+            return Redirect::to("/login").into_response();
+        }
     }
     
-    // Check if we are in demo mode
-    let is_demo_mode = std::env::var("DRAGONFLY_DEMO_MODE").is_ok();
+    // Check if we are showing the simulated demo fleet
+    let is_demo_mode = crate::demo::is_active();
     
     // Parse UUID from string
     match uuid::Uuid::parse_str(&id) {
         Ok(uuid) => {
-            // If in demo mode, find the machine in our demo dataset
+            // If in demo mode, find the machine in the simulated fleet
             if is_demo_mode {
-                let demo_machines = generate_demo_machines();
-                // Use string comparison for more reliable matching in templates
-                if let Some(machine) = demo_machines.iter().find(|m| m.id.to_string() == uuid.to_string()) {
+                let demo_machines = crate::demo::machines();
+                if let Some(machine) = demo_machines.iter().find(|m| m.id == uuid) {
                     let created_at_formatted = machine.created_at.format("%Y-%m-%d %H:%M:%S UTC").to_string();
                     let updated_at_formatted = machine.updated_at.format("%Y-%m-%d %H:%M:%S UTC").to_string();
                     
-                    // Create a mock workflow info if the machine is in installing status
-                    let workflow_info = if machine.status == MachineStatus::InstallingOS {
-                        Some(crate::tinkerbell::WorkflowInfo {
-                            state: "running".to_string(),
-                            current_action: Some("Writing disk image".to_string()),
-                            progress: 65,
-                            tasks: vec![
-                                crate::tinkerbell::TaskInfo {
-                                    name: "Installing operating system".to_string(),
-                                    status: "STATE_RUNNING".to_string(),
-                                    started_at: (Utc::now() - chrono::Duration::minutes(15)).to_rfc3339(),
-                                    duration: 900, // 15 minutes in seconds
-                                    reported_duration: 900,
-                                    estimated_duration: 1800, // 30 minutes in seconds
-                                    progress: 65,
-                                }
-                            ],
-                            estimated_completion: Some("About 10 minutes remaining".to_string()),
-                            template_name: "ubuntu-2204".to_string(),
-                        })
-                    } else {
-                        None
-                    };
+                    // Installing machines carry their simulated workflow
+                    let workflow_info = crate::demo::workflow_info(machine);
 
                     // Serialize machine and workflow_info to JSON strings
                     let machine_json = serde_json::to_string(machine)
@@ -711,6 +918,7 @@ pub async fn machine_details(
                     let context = MachineDetailsTemplate {
                         machine_json, // Pass JSON string
                         theme,
+                        palette,
                         is_authenticated,
                         created_at_formatted,
                         updated_at_formatted,
@@ -719,6 +927,12 @@ pub async fn machine_details(
                         workflow_info, // Pass original option too
                         current_path,
                         ip_address_type, // Pass the determined type
+                        clock_skew_threshold: crate::api::clock_skew_threshold_secs(),
+                        conflicts: Vec::new(),
+                        status_history: Vec::new(),
+                        custom_fields: Vec::new(),
+                        field_values: HashMap::new(),
+                        bmc_jobs: Vec::new(),
                     };
                     // Use render_minijinja
                     return render_minijinja(&app_state, "machine_details.html", context);
@@ -726,6 +940,7 @@ pub async fn machine_details(
                     // Machine not found in demo mode, show error using MiniJinja
                     let context = ErrorTemplate {
                         theme,
+                        palette,
                         is_authenticated,
                         title: "Demo Machine Not Found".to_string(),
                         message: "The requested demo machine was not found.".to_string(),
@@ -798,6 +1013,7 @@ pub async fn machine_details(
                     let context = MachineDetailsTemplate {
                         machine_json, // Pass JSON string
                         theme,
+                        palette,
                         is_authenticated,
                         created_at_formatted,
                         updated_at_formatted,
@@ -806,6 +1022,20 @@ pub async fn machine_details(
                         workflow_info, // Pass original option too
                         current_path,
                         ip_address_type, // Pass the determined type
+                        clock_skew_threshold: crate::api::clock_skew_threshold_secs(),
+                        conflicts: crate::conflicts::for_machine(&machine.id).await.unwrap_or_default(),
+                        status_history: match db::get_status_history(&machine.id).await {
+                            Ok(history) => history.into_iter().rev().map(StatusHistoryEntry::from).collect(),
+                            Err(e) => {
+                                error!("Error fetching status history for machine {}: {}", machine.id, e);
+                                Vec::new()
+                            }
+                        },
+                        custom_fields: db::get_custom_fields().await.unwrap_or_default(),
+                        field_values: db::get_machine_field_values(&machine.id).await.unwrap_or_default(),
+                        bmc_jobs: db::get_bmc_jobs(&machine.id, 10).await
+                            .map(|jobs| jobs.into_iter().map(BmcJobEntry::from).collect())
+                            .unwrap_or_default(),
                     };
                     // Use render_minijinja
                     return render_minijinja(&app_state, "machine_details.html", context);
@@ -815,6 +1045,7 @@ pub async fn machine_details(
                     // Use MiniJinja for error template
                     let context = ErrorTemplate { // Use ErrorTemplate for consistency
                         theme,
+                        palette,
                         is_authenticated,
                         title: "Machine Not Found".to_string(),
                         message: "The requested machine could not be found.".to_string(),
@@ -832,6 +1063,7 @@ pub async fn machine_details(
                     // Use MiniJinja for error template
                     let context = ErrorTemplate { // Use ErrorTemplate
                         theme,
+                        palette,
                         is_authenticated,
                         title: "Database Error".to_string(),
                         message: "An error occurred while fetching machine details.".to_string(),
@@ -851,6 +1083,7 @@ pub async fn machine_details(
             // Use MiniJinja for error template
             let context = ErrorTemplate { // Use ErrorTemplate
                 theme,
+                palette,
                 is_authenticated,
                 title: "Invalid Request".to_string(),
                 message: "The provided machine ID was not a valid format.".to_string(),
@@ -868,16 +1101,21 @@ pub async fn machine_details(
 
 // Handler for theme toggling
 pub async fn toggle_theme(
+    auth_session: AuthSession,
     Query(params): Query<HashMap<String, String>>,
 ) -> Response {
     // Get theme from URL parameters, default to "light"
     let theme = params.get("theme").cloned().unwrap_or_else(|| "light".to_string());
+
+    // Signed-in users get the theme in every browser
+    if let Some(user) = auth_session.user.as_ref() {
+        if let Err(e) = crate::preferences::save_appearance(&user.username, &theme, None).await {
+            error!("Failed to save theme preference: {}", e);
+        }
+    }
     
     // Create cookie with proper builder pattern
-    let mut cookie = Cookie::new("dragonfly_theme", theme);
-    cookie.set_path("/");
-    cookie.set_max_age(time::Duration::days(365));
-    cookie.set_same_site(SameSite::Lax);
+    let cookie = preference_cookie(THEME_COOKIE, &theme);
     
     // Get the return URL from parameters or default to home page
     let return_to = params.get("return_to").cloned().unwrap_or_else(|| "/".to_string());
@@ -890,6 +1128,146 @@ pub async fn toggle_theme(
 }
 
 // Handler for the settings page
+// Health of the Tinkerbell components Dragonfly provisions through
+pub async fn stack_health_page(
+    State(app_state): State<crate::AppState>,
+    auth_session: AuthSession,
+    headers: HeaderMap,
+    uri: OriginalUri,
+) -> Response {
+    let theme = get_theme_from_cookie(&headers);
+    let palette = get_palette_from_cookie(&headers);
+    let is_authenticated = auth_session.user.is_some();
+    let require_login = app_state.settings.lock().await.require_login;
+    if require_login && !is_authenticated {
+        return Redirect::to("/login").into_response();
+    }
+
+    let context = StackHealthTemplate {
+        theme,
+        palette,
+        is_authenticated,
+        current_path: uri.path().to_string(),
+        health: crate::status::stack_health().await,
+    };
+    render_minijinja(&app_state, "stack_health.html", context)
+}
+
+pub async fn racks_page(
+    State(app_state): State<crate::AppState>,
+    auth_session: AuthSession,
+    headers: HeaderMap,
+    uri: OriginalUri,
+) -> Response {
+    let theme = get_theme_from_cookie(&headers);
+    let palette = get_palette_from_cookie(&headers);
+    let is_authenticated = auth_session.user.is_some();
+    let require_login = app_state.settings.lock().await.require_login;
+    if require_login && !is_authenticated {
+        return Redirect::to("/login").into_response();
+    }
+
+    let loaded = async {
+        anyhow::Ok((db::get_sites().await?, db::get_racks().await?, db::get_placements().await?, db::get_all_machines().await?))
+    }.await;
+    let (sites, racks, placements, machines) = loaded.unwrap_or_else(|e| {
+        error!("Error loading racks: {}", e);
+        (Vec::new(), Vec::new(), Vec::new(), Vec::new())
+    });
+    let machines: HashMap<uuid::Uuid, &Machine> = machines.iter().map(|m| (m.id, m)).collect();
+    let sites = sites.into_iter()
+        .map(|site| SiteRacks {
+            racks: racks.iter()
+                .filter(|rack| rack.site == site.name)
+                .map(|rack| crate::topology::elevation(rack, &placements, &machines))
+                .collect(),
+            site,
+        })
+        .collect();
+
+    let context = RacksTemplate {
+        theme,
+        palette,
+        is_authenticated,
+        current_path: uri.path().to_string(),
+        sites,
+    };
+    render_minijinja(&app_state, "racks.html", context)
+}
+
+pub async fn compliance_page(
+    State(app_state): State<crate::AppState>,
+    auth_session: AuthSession,
+    headers: HeaderMap,
+    uri: OriginalUri,
+) -> Response {
+    let theme = get_theme_from_cookie(&headers);
+    let palette = get_palette_from_cookie(&headers);
+    let is_authenticated = auth_session.user.is_some();
+    let require_login = app_state.settings.lock().await.require_login;
+    if require_login && !is_authenticated {
+        return Redirect::to("/login").into_response();
+    }
+
+    let report = crate::os_lifecycle::compliance().await
+        .map_err(|e| error!("Error building compliance report: {}", e))
+        .ok();
+    let context = ComplianceTemplate {
+        theme,
+        palette,
+        is_authenticated,
+        current_path: uri.path().to_string(),
+        report,
+    };
+    render_minijinja(&app_state, "compliance.html", context)
+}
+
+pub async fn workflow_templates_page(
+    State(app_state): State<crate::AppState>,
+    auth_session: AuthSession,
+    headers: HeaderMap,
+    uri: OriginalUri,
+) -> Response {
+    // Templates can only be read and edited by admins, so there is nothing to show anyone else
+    if auth_session.user.is_none() {
+        return Redirect::to("/login").into_response();
+    }
+    if !auth::is_admin(&auth_session) {
+        return Redirect::to("/").into_response();
+    }
+
+    let context = WorkflowTemplatesTemplate {
+        theme: get_theme_from_cookie(&headers),
+        palette: get_palette_from_cookie(&headers),
+        is_authenticated: true,
+        current_path: uri.path().to_string(),
+    };
+    render_minijinja(&app_state, "workflow_templates.html", context)
+}
+
+pub async fn maintenance_page(
+    State(app_state): State<crate::AppState>,
+    auth_session: AuthSession,
+    headers: HeaderMap,
+    uri: OriginalUri,
+) -> Response {
+    // Windows and holds are managed by admins
+    if auth_session.user.is_none() {
+        return Redirect::to("/login").into_response();
+    }
+    if !auth::is_admin(&auth_session) {
+        return Redirect::to("/").into_response();
+    }
+
+    let context = MaintenanceTemplate {
+        theme: get_theme_from_cookie(&headers),
+        palette: get_palette_from_cookie(&headers),
+        is_authenticated: true,
+        current_path: uri.path().to_string(),
+    };
+    render_minijinja(&app_state, "maintenance.html", context)
+}
+
 pub async fn settings_page(
     State(app_state): State<crate::AppState>,
     auth_session: AuthSession,
@@ -898,15 +1276,26 @@ pub async fn settings_page(
 ) -> Response {
     // Get current theme from cookie
     let theme = get_theme_from_cookie(&headers);
+    let palette = get_palette_from_cookie(&headers);
     
     // Check if user is authenticated
     let is_authenticated = auth_session.user.is_some();
+    let is_admin = auth::is_admin(&auth_session);
     let current_path = uri.path().to_string();
     
     // Get current settings
     let settings_lock = app_state.settings.lock().await;
     let require_login = settings_lock.require_login;
+    let require_approval = settings_lock.require_approval;
+    let generate_root_passwords = settings_lock.generate_root_passwords;
+    let block_eol_assignments = settings_lock.block_eol_assignments;
     let default_os = settings_lock.default_os.clone();
+    let hostname_policy = settings_lock.hostname_policy.clone();
+    let boot_filter = settings_lock.boot_filter.clone();
+    let default_theme = settings_lock.default_theme.clone().unwrap_or_default();
+    let require_boot_auth = settings_lock.require_boot_auth;
+    let boot_auth_exempt_subnets = settings_lock.boot_auth_exempt_subnets.clone();
+    let status_board = settings_lock.status_board.clone();
     drop(settings_lock);
     
     // If require_login is enabled and user is not authenticated,
@@ -915,7 +1304,7 @@ pub async fn settings_page(
         return Redirect::to("/login").into_response();
     }
     
-    let show_admin_settings = is_authenticated;
+    let show_admin_settings = is_admin;
     
     // Get admin username if authenticated
     let admin_username = if let Some(user) = &auth_session.user {
@@ -950,15 +1339,25 @@ pub async fn settings_page(
     // Replace Askama render with placeholder
     let context = SettingsTemplate {
         theme,
+        palette,
         is_authenticated,
         admin_username,
         require_login,
+        require_approval,
+        generate_root_passwords,
+        block_eol_assignments,
         default_os_none: default_os.is_none(),
         default_os_ubuntu2204: default_os.as_deref() == Some("ubuntu-2204"),
         default_os_ubuntu2404: default_os.as_deref() == Some("ubuntu-2404"),
         default_os_debian12: default_os.as_deref() == Some("debian-12"),
         default_os_proxmox: default_os.as_deref() == Some("proxmox"),
         default_os_talos: default_os.as_deref() == Some("talos"),
+        default_theme,
+        hostname_policy,
+        boot_filter,
+        require_boot_auth,
+        boot_auth_exempt_subnets,
+        status_board,
         has_initial_password,
         rendered_password,
         show_admin_settings,
@@ -972,12 +1371,72 @@ pub async fn settings_page(
 #[derive(serde::Deserialize)]
 pub struct SettingsForm {
     pub theme: String,
+    pub palette: Option<String>,
     pub require_login: Option<String>,
+    pub require_approval: Option<String>,
+    pub generate_root_passwords: Option<String>,
+    pub block_eol_assignments: Option<String>,
     pub default_os: Option<String>,
+    pub default_theme: Option<String>,
     pub username: Option<String>,
+    pub old_password: Option<String>,
     pub password: Option<String>,
     pub password_confirm: Option<String>,
     pub setup_completed: Option<String>,
+    pub hostname_policy_type: Option<String>,
+    pub hostname_prefix: Option<String>,
+    pub hostname_sequence_start: Option<String>,
+    pub hostname_sequence_width: Option<String>,
+    pub hostname_csv_source: Option<String>,
+    pub boot_allow: Option<String>,
+    pub boot_deny: Option<String>,
+    pub require_boot_auth: Option<String>,
+    pub boot_auth_exempt_subnets: Option<String>,
+    pub status_board_enabled: Option<String>,
+    pub status_board_title: Option<String>,
+    pub status_board_refresh_secs: Option<String>,
+}
+
+// Build a hostname policy from the settings form, keeping the current one if the form didn't include it
+fn hostname_policy_from_form(form: &SettingsForm, current: &HostnamePolicy) -> HostnamePolicy {
+    let prefix = form.hostname_prefix.clone().unwrap_or_default();
+    match form.hostname_policy_type.as_deref() {
+        None => current.clone(),
+        Some("sequence") => HostnamePolicy::Sequence {
+            prefix,
+            start: form.hostname_sequence_start.as_deref().and_then(|v| v.trim().parse().ok()).unwrap_or(1),
+            width: form.hostname_sequence_width.as_deref().and_then(|v| v.trim().parse().ok()).unwrap_or(3),
+        },
+        Some("mac_derived") => HostnamePolicy::MacDerived { prefix },
+        Some("csv") => match form.hostname_csv_source.as_deref().map(str::trim) {
+            Some(source) if !source.is_empty() => HostnamePolicy::Csv { source: source.to_string() },
+            _ => HostnamePolicy::None,
+        },
+        Some(_) => HostnamePolicy::None,
+    }
+}
+
+// Build the iPXE boot filter from the settings form, keeping the current one if the form didn't include it
+fn boot_filter_from_form(form: &SettingsForm, current: &BootFilter) -> anyhow::Result<BootFilter> {
+    match (form.boot_allow.as_deref(), form.boot_deny.as_deref()) {
+        (None, None) => Ok(current.clone()),
+        (allow, deny) => BootFilter::from_lists(allow.unwrap_or_default(), deny.unwrap_or_default()),
+    }
+}
+
+// Build the status board settings from the form, keeping the current ones if the form didn't include them
+fn status_board_from_form(form: &SettingsForm, current: &StatusBoardConfig) -> anyhow::Result<StatusBoardConfig> {
+    let Some(refresh_secs) = form.status_board_refresh_secs.as_deref() else {
+        return Ok(current.clone());
+    };
+    let config = StatusBoardConfig {
+        enabled: form.status_board_enabled.is_some(),
+        title: form.status_board_title.as_deref().map(str::trim).filter(|title| !title.is_empty()).map(str::to_string),
+        refresh_secs: refresh_secs.trim().parse()
+            .map_err(|_| anyhow::anyhow!("'{}' isn't a number of seconds", refresh_secs))?,
+    };
+    crate::status_board::validate(&config).map_err(|e| anyhow::anyhow!(e))?;
+    Ok(config)
 }
 
 // Handler for settings form submission
@@ -989,22 +1448,43 @@ pub async fn update_settings(
     Form(form): Form<SettingsForm>,
 ) -> Response {
     let is_authenticated = auth_session.user.is_some();
+    let is_admin = auth::is_admin(&auth_session);
     let theme = form.theme.clone();
+    let palette = normalize_palette(form.palette.as_deref());
     let current_path = uri.path().to_string();
 
     // Only require admin authentication for admin settings
-    // If trying to change admin settings but not authenticated, redirect to login
+    // If trying to change admin settings without administrator rights, redirect to login
     if (form.require_login.is_some() || 
+        form.require_approval.is_some() || 
+        form.generate_root_passwords.is_some() || 
+        form.block_eol_assignments.is_some() || 
         form.default_os.is_some() || 
+        form.default_theme.is_some() || 
         form.username.is_some() || 
         form.password.is_some() || 
         form.password_confirm.is_some() ||
-        form.setup_completed.is_some()) && !is_authenticated {
+        form.setup_completed.is_some() ||
+        form.hostname_policy_type.is_some() ||
+        form.boot_allow.is_some() ||
+        form.boot_deny.is_some() ||
+        form.require_boot_auth.is_some() ||
+        form.boot_auth_exempt_subnets.is_some() ||
+        form.status_board_enabled.is_some() ||
+        form.status_board_title.is_some() ||
+        form.status_board_refresh_secs.is_some()) && !is_admin {
         return Redirect::to("/login").into_response();
     }
 
-    // Only update admin settings if user is authenticated
-    if is_authenticated {
+    // Signed-in users get their appearance in every browser
+    if let Some(user) = auth_session.user.as_ref() {
+        if let Err(e) = crate::preferences::save_appearance(&user.username, &theme, Some(&palette)).await {
+            error!("Failed to save appearance preferences: {}", e);
+        }
+    }
+
+    // Only update admin settings if user is an administrator
+    if is_admin {
         // Load current settings to get existing setup_completed value
         let current_settings = match get_app_settings().await {
             Ok(settings) => settings,
@@ -1015,11 +1495,22 @@ pub async fn update_settings(
             }
         };
 
+        // Invalid boot filter rules are reported instead of being silently dropped
+        let boot_filter = boot_filter_from_form(&form, &current_settings.boot_filter);
+        let exempt_subnets = match form.boot_auth_exempt_subnets.as_deref() {
+            Some(list) => crate::access::parse_subnets(list),
+            None => Ok(current_settings.boot_auth_exempt_subnets.clone()),
+        };
+        let status_board = status_board_from_form(&form, &current_settings.status_board);
+
         // Construct the new settings, preserving existing setup_completed
         let new_settings = Settings {
             require_login: form.require_login.is_some(),
+            require_approval: form.require_approval.is_some(),
+            generate_root_passwords: form.generate_root_passwords.is_some(),
+            block_eol_assignments: form.block_eol_assignments.is_some(),
             // Handle optional default_os correctly by filtering out empty strings
-            default_os: form.default_os.filter(|os| !os.is_empty()),
+            default_os: form.default_os.clone().filter(|os| !os.is_empty()),
             // Use the setup_completed value from the form if present (checkbox is checked),
             // otherwise keep the current value from the database.
             setup_completed: form.setup_completed.is_some().then_some(true).unwrap_or(current_settings.setup_completed),
@@ -1030,13 +1521,29 @@ pub async fn update_settings(
             oauth_auth_url: current_settings.oauth_auth_url.clone(),
             oauth_token_url: current_settings.oauth_token_url.clone(),
             oauth_redirect_url: current_settings.oauth_redirect_url.clone(),
+            hostname_policy: hostname_policy_from_form(&form, &current_settings.hostname_policy),
+            boot_filter: boot_filter.as_ref().cloned().unwrap_or_else(|_| current_settings.boot_filter.clone()),
+            default_theme: match &form.default_theme {
+                Some(theme) => Some(theme.clone()).filter(|theme| crate::settings::THEMES.contains(&theme.as_str())),
+                None => current_settings.default_theme.clone(),
+            },
+            require_boot_auth: form.require_boot_auth.is_some(),
+            boot_auth_exempt_subnets: exempt_subnets.as_ref().cloned().unwrap_or_else(|_| current_settings.boot_auth_exempt_subnets.clone()),
+            status_board: status_board.as_ref().cloned().unwrap_or_else(|_| current_settings.status_board.clone()),
         };
 
         info!("Saving settings: require_login={}, default_os={:?}, setup_completed={:?}", 
               new_settings.require_login, new_settings.default_os, new_settings.setup_completed);
 
         // Save the general settings
-        if let Err(e) = save_app_settings(&new_settings).await {
+        let save_result = match (&boot_filter, &exempt_subnets, &status_board) {
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => Err(anyhow::anyhow!("{}", e)),
+            _ => match crate::access::check_boot_auth(new_settings.require_boot_auth, &new_settings.boot_auth_exempt_subnets) {
+                Ok(()) => save_app_settings(&new_settings).await,
+                Err(e) => Err(anyhow::anyhow!(e)),
+            },
+        };
+        if let Err(e) = save_result {
             error!("Failed to save settings: {}", e);
             // Prepare error message and template for display
             let error_message = Some(format!("Failed to save settings: {}", e));
@@ -1049,20 +1556,30 @@ pub async fn update_settings(
             // These fields are not in Settings, use defaults
             let has_initial_password = false;
             let rendered_password = "".to_string();
-            let show_admin_settings = is_authenticated;
+            let show_admin_settings = is_admin;
             
             // Create template with error message
             let context = SettingsTemplate {
                 theme: theme.clone(),
+                palette: palette.clone(),
                 is_authenticated,
                 admin_username,
                 require_login,
+                require_approval: current_settings.require_approval,
+                generate_root_passwords: current_settings.generate_root_passwords,
+                block_eol_assignments: current_settings.block_eol_assignments,
                 default_os_none: default_os.is_none(),
                 default_os_ubuntu2204: default_os.as_deref() == Some("ubuntu-2204"),
                 default_os_ubuntu2404: default_os.as_deref() == Some("ubuntu-2404"),
                 default_os_debian12: default_os.as_deref() == Some("debian-12"),
                 default_os_proxmox: default_os.as_deref() == Some("proxmox"),
                 default_os_talos: default_os.as_deref() == Some("talos"),
+                default_theme: current_settings.default_theme.clone().unwrap_or_default(),
+                hostname_policy: current_settings.hostname_policy.clone(),
+                boot_filter: current_settings.boot_filter.clone(),
+                require_boot_auth: current_settings.require_boot_auth,
+                boot_auth_exempt_subnets: current_settings.boot_auth_exempt_subnets.clone(),
+                status_board: current_settings.status_board.clone(),
                 has_initial_password,
                 rendered_password,
                 show_admin_settings,
@@ -1071,143 +1588,114 @@ pub async fn update_settings(
             };
             
             // Return the error template
-            let mut cookie = Cookie::new("dragonfly_theme", theme.clone());
-            cookie.set_path("/");
-            cookie.set_max_age(time::Duration::days(365));
-            cookie.set_same_site(SameSite::Lax);
+            let cookie = preference_cookie(THEME_COOKIE, &theme);
             
             return (
-                [(header::SET_COOKIE, cookie.to_string())],
+                [(header::SET_COOKIE, cookie.to_string()), (header::SET_COOKIE, palette_cookie(&palette).to_string())],
                 render_minijinja(&app_state, "settings.html", context)
             ).into_response();
         } else {
-            // Update settings in app state ONLY after successful save
-            if let Ok(mut guard) = app_state.settings.try_lock() {
-                *guard = new_settings.clone(); // Update the in-memory state
-                info!("In-memory AppState settings updated.");
-            } else {
-                error!("Failed to acquire lock to update in-memory AppState settings.");
-                // The settings are saved in DB, but the live state might be stale until restart/reload
-            }
+            // Make the saved settings live ONLY after a successful save
+            crate::settings::publish(&app_state.settings, new_settings.clone()).await;
         }
 
-        // Update admin password if provided and confirmed
-        // Check form.password instead of form.password
+        // Update the password if provided and confirmed. Accounts change their own password and must
+        // confirm the current one; a break-glass session resets the bootstrap admin account instead.
         if let (Some(password), Some(confirm)) = (&form.password, &form.password_confirm) {
             if !password.is_empty() && password == confirm {
-                // Load current credentials to get username (or use default 'admin')
-                let username = match auth::load_credentials().await {
-                    Ok(creds) => creds.username,
-                    Err(_) => {
-                        warn!("Could not load current credentials, defaulting username to 'admin' for password change.");
-                        "admin".to_string()
+                let result = match auth_session.user.as_ref() {
+                    Some(user) if !crate::break_glass::is_break_glass(user) => {
+                        let old_password = form.old_password.as_deref().unwrap_or("");
+                        crate::users::change_password(user, old_password, password).await
+                            .map_err(|e| format!("Failed to change password: {}", e))
                     }
-                };
-
-                // Hash the new password
-                match Credentials::create(username, password.clone()) {
-                    Ok(new_creds) => {
-                        if let Err(e) = auth::save_credentials(&new_creds).await {
-                            error!("Failed to save new admin password: {}", e);
-                            // Prepare error message and template for display
-                            let error_message = Some(format!("Failed to save credentials: {}", e));
-                            
-                            // Get current settings for template
-                            let admin_username = current_settings.admin_username.clone();
-                            let require_login = current_settings.require_login;
-                            let default_os = current_settings.default_os.clone();
-                            
-                            // These fields are not in Settings, use defaults
-                            let has_initial_password = false;
-                            let rendered_password = "".to_string();
-                            let show_admin_settings = is_authenticated;
-                            
-                            // Create template with error message
-                            let context = SettingsTemplate {
-                                theme: theme.clone(),
-                                is_authenticated,
-                                admin_username,
-                                require_login,
-                                default_os_none: default_os.is_none(),
-                                default_os_ubuntu2204: default_os.as_deref() == Some("ubuntu-2204"),
-                                default_os_ubuntu2404: default_os.as_deref() == Some("ubuntu-2404"),
-                                default_os_debian12: default_os.as_deref() == Some("debian-12"),
-                                default_os_proxmox: default_os.as_deref() == Some("proxmox"),
-                                default_os_talos: default_os.as_deref() == Some("talos"),
-                                has_initial_password,
-                                rendered_password,
-                                show_admin_settings,
-                                error_message,
-                                current_path, // Add current_path here
-                            };
-                            
-                            // Return the error template
-                            let mut cookie = Cookie::new("dragonfly_theme", theme.clone());
-                            cookie.set_path("/");
-                            cookie.set_max_age(time::Duration::days(365));
-                            cookie.set_same_site(SameSite::Lax);
-                            
-                            return (
-                                [(header::SET_COOKIE, cookie.to_string())],
-                                render_minijinja(&app_state, "settings.html", context)
-                            ).into_response();
-                        } else {
-                            // Password updated successfully, delete initial password file if it exists
-                            if std::path::Path::new("initial_password.txt").exists() {
-                                if let Err(e) = std::fs::remove_file("initial_password.txt") {
-                                    warn!("Failed to remove initial_password.txt: {}", e);
-                                }
+                    _ => {
+                        // Load current credentials to get username (or use default 'admin')
+                        let username = match auth::load_credentials().await {
+                            Ok(creds) => creds.username,
+                            Err(_) => {
+                                warn!("Could not load current credentials, defaulting username to 'admin' for password change.");
+                                "admin".to_string()
                             }
-                            // Force logout after password change
-                            let _ = auth_session.logout().await;
-                            return Redirect::to("/login?message=password_updated").into_response();
+                        };
+                        match Credentials::create(username, password.clone()) {
+                            Ok(new_creds) => auth::save_credentials(&new_creds).await
+                                .map_err(|e| format!("Failed to save credentials: {}", e)),
+                            Err(e) => Err(format!("Failed to hash password: {}", e)),
                         }
                     }
+                };
+
+                match result {
                     Err(e) => {
-                        error!("Failed to hash new password: {}", e);
+                        error!("Failed to update password: {}", e);
                         // Prepare error message and template for display
-                        let error_message = Some(format!("Failed to hash password: {}", e));
-                        
+                        let error_message = Some(e);
+
                         // Get current settings for template
                         let admin_username = current_settings.admin_username.clone();
                         let require_login = current_settings.require_login;
                         let default_os = current_settings.default_os.clone();
-                        
+
                         // These fields are not in Settings, use defaults
                         let has_initial_password = false;
                         let rendered_password = "".to_string();
-                        let show_admin_settings = is_authenticated;
-                        
+                        let show_admin_settings = is_admin;
+
                         // Create template with error message
                         let context = SettingsTemplate {
                             theme: theme.clone(),
+                            palette: palette.clone(),
                             is_authenticated,
                             admin_username,
                             require_login,
+                            require_approval: current_settings.require_approval,
+                            generate_root_passwords: current_settings.generate_root_passwords,
+                            block_eol_assignments: current_settings.block_eol_assignments,
                             default_os_none: default_os.is_none(),
                             default_os_ubuntu2204: default_os.as_deref() == Some("ubuntu-2204"),
                             default_os_ubuntu2404: default_os.as_deref() == Some("ubuntu-2404"),
                             default_os_debian12: default_os.as_deref() == Some("debian-12"),
                             default_os_proxmox: default_os.as_deref() == Some("proxmox"),
                             default_os_talos: default_os.as_deref() == Some("talos"),
+                            default_theme: current_settings.default_theme.clone().unwrap_or_default(),
+                            hostname_policy: current_settings.hostname_policy.clone(),
+                            boot_filter: current_settings.boot_filter.clone(),
+                            require_boot_auth: current_settings.require_boot_auth,
+                            boot_auth_exempt_subnets: current_settings.boot_auth_exempt_subnets.clone(),
+                            status_board: current_settings.status_board.clone(),
                             has_initial_password,
                             rendered_password,
                             show_admin_settings,
                             error_message,
                             current_path, // Add current_path here
                         };
-                        
+
                         // Return the error template
-                        let mut cookie = Cookie::new("dragonfly_theme", theme.clone());
-                        cookie.set_path("/");
-                        cookie.set_max_age(time::Duration::days(365));
-                        cookie.set_same_site(SameSite::Lax);
-                        
+                        let cookie = preference_cookie(THEME_COOKIE, &theme);
+
                         return (
-                            [(header::SET_COOKIE, cookie.to_string())],
+                            [(header::SET_COOKIE, cookie.to_string()), (header::SET_COOKIE, palette_cookie(&palette).to_string())],
                             render_minijinja(&app_state, "settings.html", context)
                         ).into_response();
                     }
+                    Ok(()) => {
+                        // Password updated successfully, delete initial password file if it exists
+                        if std::path::Path::new("initial_password.txt").exists() {
+                            if let Err(e) = std::fs::remove_file("initial_password.txt") {
+                                warn!("Failed to remove initial_password.txt: {}", e);
+                            }
+                        }
+                        // A break-glass session ends once it has rotated the admin password
+                        if let Some(user) = auth_session.user.as_ref().filter(|user| crate::break_glass::is_break_glass(user)) {
+                            if let Err(e) = crate::break_glass::complete_rotation(user).await {
+                                error!("Failed to record break-glass password rotation: {}", e);
+                            }
+                        }
+                        // Force logout after password change
+                        let _ = auth_session.logout().await;
+                        return Redirect::to("/login?message=password_updated").into_response();
+                    }
                 }
             }
         }
@@ -1231,20 +1719,30 @@ pub async fn update_settings(
                 // These fields are not in Settings, use defaults
                 let has_initial_password = false;
                 let rendered_password = "".to_string();
-                let show_admin_settings = is_authenticated;
+                let show_admin_settings = is_admin;
                 
                 // Create template with error message
                 let context = SettingsTemplate {
                     theme: theme.clone(),
+                    palette: palette.clone(),
                     is_authenticated,
                     admin_username,
                     require_login,
+                    require_approval: current_settings.require_approval,
+                    generate_root_passwords: current_settings.generate_root_passwords,
+                    block_eol_assignments: current_settings.block_eol_assignments,
                     default_os_none: default_os.is_none(),
                     default_os_ubuntu2204: default_os.as_deref() == Some("ubuntu-2204"),
                     default_os_ubuntu2404: default_os.as_deref() == Some("ubuntu-2404"),
                     default_os_debian12: default_os.as_deref() == Some("debian-12"),
                     default_os_proxmox: default_os.as_deref() == Some("proxmox"),
                     default_os_talos: default_os.as_deref() == Some("talos"),
+                    default_theme: current_settings.default_theme.clone().unwrap_or_default(),
+                    hostname_policy: current_settings.hostname_policy.clone(),
+                    boot_filter: current_settings.boot_filter.clone(),
+                    require_boot_auth: current_settings.require_boot_auth,
+                    boot_auth_exempt_subnets: current_settings.boot_auth_exempt_subnets.clone(),
+                    status_board: current_settings.status_board.clone(),
                     has_initial_password,
                     rendered_password,
                     show_admin_settings,
@@ -1253,13 +1751,10 @@ pub async fn update_settings(
                 };
                 
                 // Return the error template
-                let mut cookie = Cookie::new("dragonfly_theme", theme.clone());
-                cookie.set_path("/");
-                cookie.set_max_age(time::Duration::days(365));
-                cookie.set_same_site(SameSite::Lax);
+                let cookie = preference_cookie(THEME_COOKIE, &theme);
                 
                 return (
-                    [(header::SET_COOKIE, cookie.to_string())],
+                    [(header::SET_COOKIE, cookie.to_string()), (header::SET_COOKIE, palette_cookie(&palette).to_string())],
                     render_minijinja(&app_state, "settings.html", context)
                 ).into_response();
             }
@@ -1268,14 +1763,11 @@ pub async fn update_settings(
 
     // Theme can be updated by all users (even non-authenticated)
     // Create cookie with proper builder pattern
-    let mut cookie = Cookie::new("dragonfly_theme", theme);
-    cookie.set_path("/");
-    cookie.set_max_age(time::Duration::days(365));
-    cookie.set_same_site(SameSite::Lax);
+    let cookie = preference_cookie(THEME_COOKIE, &theme);
     
     // Set cookie header and redirect back to settings page
     (
-        [(header::SET_COOKIE, cookie.to_string())],
+        [(header::SET_COOKIE, cookie.to_string()), (header::SET_COOKIE, palette_cookie(&palette).to_string())],
         Redirect::to("/settings")
     ).into_response()
 }
@@ -1289,12 +1781,14 @@ pub async fn welcome_page(
 ) -> Response {
     // Get theme preference from cookie
     let theme = get_theme_from_cookie(&headers);
+    let palette = get_palette_from_cookie(&headers);
     let is_authenticated = auth_session.user.is_some();
     let current_path = uri.path().to_string();
     
     // Replace Askama render with placeholder
     let context = WelcomeTemplate {
         theme,
+        palette,
         is_authenticated,
         hide_footer: true,
         current_path,
@@ -1312,6 +1806,7 @@ pub async fn setup_simple(
 ) -> Response {
     // Get theme preference from cookie
     let theme = get_theme_from_cookie(&headers);
+    let palette = get_palette_from_cookie(&headers);
     let is_authenticated = auth_session.user.is_some();
     let current_path = uri.path().to_string();
     
@@ -1322,6 +1817,7 @@ pub async fn setup_simple(
         // Return error template
         let context = ErrorTemplate {
             theme,
+            palette,
             is_authenticated,
             title: "Setup Failed".to_string(),
             message: "There was a problem setting up Simple mode.".to_string(),
@@ -1341,24 +1837,27 @@ pub async fn setup_simple(
     } else {
         info!("Setup marked as completed");
         
-        // Also update the in-memory settings
-        let mut settings = app_state.settings.lock().await;
+        // Also make it live, so settings::current() and open pages see it
+        let mut settings = app_state.settings.lock().await.clone();
         settings.setup_completed = true;
+        crate::settings::publish(&app_state.settings, settings).await;
     }
     
     // Configure the system for Simple mode in the background
     let event_manager = app_state.event_manager.clone();
-    tokio::spawn(async move {
+    crate::operations::start("mode_configuration", "Configure Simple mode".to_string(), "setup", move |_| async move {
         match mode::configure_simple_mode().await {
             Ok(_) => {
                 info!("Simple mode configuration completed successfully in background");
                 // Send event for successful configuration
                 let _ = event_manager.send("mode_configured:simple".to_string());
+                Ok(serde_json::json!({ "mode": "simple" }))
             },
             Err(e) => {
                 error!("Background Simple mode configuration failed: {}", e);
                 // Send event for failed configuration
                 let _ = event_manager.send(format!("mode_configuration_failed:simple:{}", e));
+                Err(e)
             }
         }
     });
@@ -1376,6 +1875,7 @@ pub async fn setup_flight(
 ) -> Response {
     // Get theme preference from cookie
     let theme = get_theme_from_cookie(&headers);
+    let palette = get_palette_from_cookie(&headers);
     let is_authenticated = auth_session.user.is_some();
     let current_path = uri.path().to_string();
     
@@ -1386,6 +1886,7 @@ pub async fn setup_flight(
         // Return error template
         let context = ErrorTemplate {
             theme,
+            palette,
             is_authenticated,
             title: "Setup Failed".to_string(),
             message: "There was a problem setting up Flight mode.".to_string(),
@@ -1405,24 +1906,27 @@ pub async fn setup_flight(
     } else {
         info!("Setup marked as completed");
         
-        // Also update the in-memory settings
-        let mut settings = app_state.settings.lock().await;
+        // Also make it live, so settings::current() and open pages see it
+        let mut settings = app_state.settings.lock().await.clone();
         settings.setup_completed = true;
+        crate::settings::publish(&app_state.settings, settings).await;
     }
     
     // Configure the system for Flight mode in the background
     let event_manager = app_state.event_manager.clone();
-    tokio::spawn(async move {
+    crate::operations::start("mode_configuration", "Configure Flight mode".to_string(), "setup", move |_| async move {
         match mode::configure_flight_mode().await {
             Ok(_) => {
                 info!("Flight mode configuration completed successfully in background");
                 // Send event for successful configuration
                 let _ = event_manager.send("mode_configured:flight".to_string());
+                Ok(serde_json::json!({ "mode": "flight" }))
             },
             Err(e) => {
                 error!("Background Flight mode configuration failed: {}", e);
                 // Send event for failed configuration
                 let _ = event_manager.send(format!("mode_configuration_failed:flight:{}", e));
+                Err(e)
             }
         }
     });
@@ -1440,6 +1944,7 @@ pub async fn setup_swarm(
 ) -> Response {
     // Get theme preference from cookie
     let theme = get_theme_from_cookie(&headers);
+    let palette = get_palette_from_cookie(&headers);
     let is_authenticated = auth_session.user.is_some();
     let current_path = uri.path().to_string();
     
@@ -1450,6 +1955,7 @@ pub async fn setup_swarm(
         // Return error template
         let context = ErrorTemplate {
             theme,
+            palette,
             is_authenticated,
             title: "Setup Failed".to_string(),
             message: "There was a problem setting up Swarm mode.".to_string(),
@@ -1469,24 +1975,27 @@ pub async fn setup_swarm(
     } else {
         info!("Setup marked as completed");
         
-        // Also update the in-memory settings
-        let mut settings = app_state.settings.lock().await;
+        // Also make it live, so settings::current() and open pages see it
+        let mut settings = app_state.settings.lock().await.clone();
         settings.setup_completed = true;
+        crate::settings::publish(&app_state.settings, settings).await;
     }
     
     // Configure the system for Swarm mode in the background
     let event_manager = app_state.event_manager.clone();
-    tokio::spawn(async move {
+    crate::operations::start("mode_configuration", "Configure Swarm mode".to_string(), "setup", move |_| async move {
         match mode::configure_swarm_mode().await {
             Ok(_) => {
                 info!("Swarm mode configuration completed successfully in background");
                 // Send event for successful configuration
                 let _ = event_manager.send("mode_configured:swarm".to_string());
+                Ok(serde_json::json!({ "mode": "swarm" }))
             },
             Err(e) => {
                 error!("Background Swarm mode configuration failed: {}", e);
                 // Send event for failed configuration
                 let _ = event_manager.send(format!("mode_configuration_failed:swarm:{}", e));
+                Err(e)
             }
         }
     });
@@ -1498,6 +2007,40 @@ pub async fn setup_swarm(
 
 // Environment setup for MiniJinja
 pub fn setup_minijinja_environment(env: &mut minijinja::Environment) -> Result<(), anyhow::Error> {
+    // Every page shows a banner while the server runs with background tasks disabled
+    env.add_global("is_safe_mode", std::env::var("DRAGONFLY_SAFE_MODE").is_ok());
+    
+    // ...and while provisioning is frozen, which can change at any time
+    env.add_function("provisioning_freeze", || -> minijinja::Value {
+        crate::freeze::current().map_or(minijinja::Value::from(()), |record| minijinja::Value::from_serialize(&record))
+    });
+
+    // ...and the signed-in user's preferences, which differ per request
+    env.add_function("ui_preferences", || -> minijinja::Value {
+        minijinja::Value::from_serialize(crate::preferences::current().unwrap_or_default())
+    });
+
+    // Whether to render plain forms instead of scripted controls, and the outcome of the last one
+    env.add_function("no_js", || -> bool {
+        crate::no_js::current().enabled
+    });
+    env.add_function("flash", || -> minijinja::Value {
+        minijinja::Value::from_serialize(crate::no_js::current())
+    });
+
+    // Translations, in the language negotiated for the request
+    env.add_global("languages", minijinja::Value::from_serialize(crate::i18n::LANGUAGES));
+    env.add_function("language", || -> String {
+        crate::i18n::current().to_string()
+    });
+    env.add_function("t", |text: &str, kwargs: minijinja::value::Kwargs| -> Result<String, minijinja::Error> {
+        let mut values = Vec::new();
+        for name in kwargs.args() {
+            values.push((name, kwargs.get::<minijinja::Value>(name)?.to_string()));
+        }
+        Ok(crate::i18n::translate(crate::i18n::current(), text, &values))
+    });
+    
     // Add OS name formatter
     env.add_filter("format_os", |os: &str| -> String {
         format_os_name(os)
@@ -1621,4 +2164,70 @@ impl AddAlert for Response {
         }
         self
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dragonfly_common::state_machine::StatusCause;
+
+    #[tokio::test]
+    async fn test_status_timeline() {
+        let pool = crate::test_support::database().await;
+        let id = Uuid::new_v4();
+        sqlx::query("INSERT INTO machines (id, mac_address, ip_address, status, created_at, updated_at) VALUES (?, 'bc:24:11:b9:54:89', '10.0.0.5', 'Ready', 'now', 'now')")
+            .bind(id.to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let installing = MachineStatus::InstallingOS;
+        let failed = MachineStatus::Error("stream-image failed".to_string());
+        let mut conn = pool.acquire().await.unwrap();
+        assert!(db::insert_transition(&mut conn, &id, None, &MachineStatus::AwaitingAssignment, &StatusCause::Registration).await.unwrap());
+        assert!(db::insert_transition(&mut conn, &id, Some(&MachineStatus::AwaitingAssignment), &installing, &StatusCause::Admin("alice".to_string())).await.unwrap());
+        // Rewriting the same status isn't kept, but a pipeline stage is
+        assert!(!db::insert_transition(&mut conn, &id, Some(&installing), &installing, &StatusCause::Machine).await.unwrap());
+        assert!(db::insert_transition(&mut conn, &id, Some(&installing), &installing, &StatusCause::PipelineStage("stage 2 of 3".to_string())).await.unwrap());
+        assert!(db::insert_transition(&mut conn, &id, Some(&installing), &failed, &StatusCause::Workflow("stream-image failed".to_string())).await.unwrap());
+        drop(conn);
+        // Recorded before causes were
+        sqlx::query("INSERT INTO status_history (machine_id, from_status, to_status, changed_at) VALUES (?, NULL, '\"Offline\"', ?)")
+            .bind(id.to_string())
+            .bind(Utc::now().to_rfc3339())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let timeline: Vec<StatusHistoryEntry> = db::status_history(&pool, &id).await.unwrap()
+            .into_iter().rev().map(StatusHistoryEntry::from).collect();
+        let causes: Vec<&str> = timeline.iter().map(|entry| entry.cause.as_str()).collect();
+        assert_eq!(causes, ["Unknown", "Workflow: stream-image failed", "Pipeline: stage 2 of 3", "Changed by alice", "Machine registered"]);
+        assert!(timeline[1].is_error);
+        assert_eq!(timeline[4].from, None);
+    }
+
+    #[test]
+    fn test_palette_cookie_and_chart_colours() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(get_palette_from_cookie(&headers), "default");
+        headers.insert(header::COOKIE, "dragonfly_theme=dark; dragonfly_palette=color-blind".parse().unwrap());
+        assert_eq!(get_palette_from_cookie(&headers), "color-blind");
+        headers.insert(header::COOKIE, "dragonfly_palette=neon".parse().unwrap());
+        assert_eq!(get_palette_from_cookie(&headers), "default");
+
+        // The dashboard charts take every status colour from the palette, so each palette sets them all
+        let base = include_str!("../templates/base.html");
+        let index = include_str!("../templates/index.html");
+        let charted: Vec<&str> = index.match_indices("statusColour('")
+            .map(|(at, call)| index[at + call.len()..].split('\'').next().unwrap())
+            .collect();
+        assert!(charted.contains(&"offline"));
+        for selector in ["html {", "html[data-palette=\"color-blind\"] {", "html[data-palette=\"high-contrast\"] {", "html.dark[data-palette=\"high-contrast\"] {"] {
+            let block = base[base.find(selector).unwrap()..].split('}').next().unwrap();
+            for status in &charted {
+                assert!(block.contains(&format!("--status-{}:", status)), "{} doesn't set --status-{}", selector, status);
+            }
+        }
+    }
+}
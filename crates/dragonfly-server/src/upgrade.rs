@@ -0,0 +1,371 @@
+//! Upgrading a running Dragonfly in place.
+//!
+//! The server checks the project's releases for a newer version. An upgrade backs up the database,
+//! upgrades the `dragonfly` Helm release to the new image and rolls the StatefulSet. The new
+//! server migrates the database when it starts and records how the upgrade ended. Progress goes
+//! out as the same `install_status` events the installer sends.
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::apps::v1::StatefulSet;
+use kube::api::{Api, Patch, PatchParams};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use tracing::{error, info, warn};
+
+use crate::db;
+
+/// The version of this server
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Overrides where the latest release is looked up, e.g. for a mirror on an air-gapped network
+pub const RELEASES_URL_ENV_VAR: &str = "DRAGONFLY_RELEASES_URL";
+const DEFAULT_RELEASES_URL: &str = "https://api.github.com/repos/Zorlin/dragonfly/releases/latest";
+const CHARTS_REPO: &str = "https://github.com/Zorlin/dragonfly-charts.git";
+const RELEASE_NAME: &str = "dragonfly";
+
+/// How long a release check is reused before asking again
+const CHECK_TTL: Duration = Duration::from_secs(3600);
+
+/// When the latest release was looked up, and what was found
+type ReleaseCheck = (Instant, Result<String, String>);
+
+static LATEST: Lazy<Mutex<Option<ReleaseCheck>>> = Lazy::new(|| Mutex::new(None));
+
+/// Set while this server is upgrading itself, so two upgrades can't run at once
+static IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpgradeState {
+    /// Backing up, fetching the chart and upgrading the release
+    Running,
+    /// Waiting for the new server to replace this one
+    Rolling,
+    Completed,
+    Failed,
+}
+
+/// An upgrade and how it went
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Upgrade {
+    pub id: i64,
+    pub from_version: String,
+    pub to_version: String,
+    pub requested_by: String,
+    pub state: UpgradeState,
+    /// The step under way, or how it ended
+    pub message: String,
+    /// Where the database was copied before the upgrade
+    pub backup: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// What `/api/admin/version` reports
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub current: String,
+    pub latest: Option<String>,
+    pub upgrade_available: bool,
+    /// Why the latest release couldn't be looked up
+    pub check_error: Option<String>,
+    pub last_upgrade: Option<Upgrade>,
+}
+
+/// The numeric parts of a version such as `v0.2.1`, ignoring any pre-release or build suffix
+pub fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim().trim_start_matches('v');
+    let core = version.split(['-', '+']).next()?;
+    let mut parts: Vec<u64> = core.split('.').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    // 0.2 and 0.2.0 are the same version
+    while parts.len() > 1 && parts.last() == Some(&0) {
+        parts.pop();
+    }
+    Some(parts)
+}
+
+/// Whether `version` is a release tag we will hand to git and Helm: `v0.2.1` or `0.2.1-rc.1`, i.e.
+/// `^v?\d+(\.\d+)*(-[0-9A-Za-z.]+)?$`
+pub fn valid_release_tag(version: &str) -> bool {
+    let version = version.strip_prefix('v').unwrap_or(version);
+    let (core, pre_release) = match version.split_once('-') {
+        Some((core, pre_release)) => (core, Some(pre_release)),
+        None => (version, None),
+    };
+    core.split('.').all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+        && pre_release.is_none_or(|pre_release| {
+            !pre_release.is_empty() && pre_release.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'.')
+        })
+}
+
+/// Whether `candidate` is a later version than `current`
+pub fn is_newer(candidate: &str, current: &str) -> bool {
+    match (parse_version(candidate), parse_version(current)) {
+        (Some(candidate), Some(current)) => candidate > current,
+        _ => false,
+    }
+}
+
+/// Whether two version strings name the same release, e.g. `v0.2.0` and `0.2.0`
+pub fn same_version(a: &str, b: &str) -> bool {
+    match (parse_version(a), parse_version(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a.trim() == b.trim(),
+    }
+}
+
+async fn fetch_latest() -> Result<String> {
+    let url = std::env::var(RELEASES_URL_ENV_VAR).unwrap_or_else(|_| DEFAULT_RELEASES_URL.to_string());
+    let release: serde_json::Value = reqwest::Client::new()
+        .get(&url)
+        .header("User-Agent", format!("dragonfly/{}", VERSION))
+        .timeout(Duration::from_secs(15))
+        .send().await?
+        .error_for_status()?
+        .json().await?;
+    let tag = release.get("tag_name").and_then(|tag| tag.as_str())
+        .ok_or_else(|| anyhow!("{} returned no tag_name", url))?;
+    if parse_version(tag).is_none() {
+        bail!("The latest release's tag '{}' isn't a version", tag);
+    }
+    Ok(tag.to_string())
+}
+
+/// The latest released version, looked up at most once an hour unless `refresh` is set
+pub async fn latest_version(refresh: bool) -> Result<String, String> {
+    if !refresh {
+        if let Some((checked_at, latest)) = LATEST.lock().unwrap().as_ref() {
+            if checked_at.elapsed() < CHECK_TTL {
+                return latest.clone();
+            }
+        }
+    }
+    let latest = fetch_latest().await.map_err(|e| e.to_string());
+    if let Err(e) = &latest {
+        warn!("Failed to check for a newer Dragonfly release: {}", e);
+    }
+    *LATEST.lock().unwrap() = Some((Instant::now(), latest.clone()));
+    latest
+}
+
+pub async fn version_info(refresh: bool) -> Result<VersionInfo> {
+    let (latest, check_error) = match latest_version(refresh).await {
+        Ok(latest) => (Some(latest), None),
+        Err(e) => (None, Some(e)),
+    };
+    Ok(VersionInfo {
+        current: VERSION.to_string(),
+        upgrade_available: latest.as_deref().is_some_and(|latest| is_newer(latest, VERSION)),
+        latest,
+        check_error,
+        last_upgrade: db::get_upgrades(1).await?.into_iter().next(),
+    })
+}
+
+/// Tell anyone watching how the upgrade is going, the same way the installer does
+fn report(message: &str, animation: &str) {
+    let payload = json!({ "message": message, "animation": animation });
+    if let Ok(event_manager) = crate::EVENT_MANAGER_REF.read() {
+        if let Some(event_manager) = event_manager.as_ref() {
+            let _ = event_manager.send(format!("install_status:{}", payload));
+        }
+    }
+}
+
+async fn progress(upgrade: &mut Upgrade, message: String, animation: &str) -> Result<()> {
+    info!("Upgrade to {}: {}", upgrade.to_version, message);
+    report(&message, animation);
+    upgrade.message = message;
+    db::save_upgrade(upgrade).await
+}
+
+async fn run_command(program: &str, args: &[&str], description: &str) -> Result<()> {
+    let output = Command::new(program).args(args).output().await
+        .with_context(|| format!("Failed to run {} to {}", program, description))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("Failed to {}: {}", description, stderr.trim());
+    }
+    Ok(())
+}
+
+/// Restart the StatefulSet's pods the way `kubectl rollout restart` does, unless the Helm upgrade
+/// already changed their image
+async fn roll_statefulset(version: &str) -> Result<()> {
+    let client = crate::status::cluster_client().await.map_err(|e| anyhow!("{}", e))?;
+    let namespace = crate::status::dragonfly_namespace();
+    let statefulsets: Api<StatefulSet> = Api::namespaced(client, &namespace);
+    let statefulset = statefulsets.get(crate::status::DRAGONFLY_STATEFULSET).await
+        .map_err(|e| anyhow!(crate::status::describe_kube_error(&e, "read the Dragonfly StatefulSet")))?;
+    let images: Vec<String> = statefulset.spec.and_then(|spec| spec.template.spec)
+        .map(|pod| pod.containers.into_iter().filter_map(|container| container.image).collect())
+        .unwrap_or_default();
+    if images.iter().any(|image| image.ends_with(&format!(":{}", version))) {
+        info!("The Dragonfly StatefulSet is already rolling to {}", version);
+        return Ok(());
+    }
+    let patch = json!({
+        "spec": { "template": { "metadata": { "annotations": {
+            "dragonfly.riff.cc/restartedAt": Utc::now().to_rfc3339(),
+        }}}}
+    });
+    statefulsets.patch(crate::status::DRAGONFLY_STATEFULSET, &PatchParams::default(), &Patch::Merge(&patch)).await
+        .map_err(|e| anyhow!(crate::status::describe_kube_error(&e, "roll the Dragonfly StatefulSet")))?;
+    Ok(())
+}
+
+/// Where the database is copied before upgrading: next to the database itself, so the backup lands
+/// on the same volume rather than wherever the server happened to be started from
+fn backup_path(upgrade: &Upgrade) -> Result<String> {
+    let database = std::path::Path::new(db::DB_PATH).canonicalize()
+        .with_context(|| format!("Failed to locate {}", db::DB_PATH))?;
+    let backup = database.with_file_name(format!(
+        "sqlite.db.pre-{}-{}", upgrade.to_version, upgrade.started_at.format("%Y%m%d%H%M%S")
+    ));
+    backup.to_str().map(str::to_string).ok_or_else(|| anyhow!("Backup path is not valid UTF-8"))
+}
+
+async fn run(upgrade: &mut Upgrade) -> Result<()> {
+    progress(upgrade, "Dragonfly is backing up its database.".to_string(), "rocket-glowing").await?;
+    let backup = backup_path(upgrade)?;
+    db::backup_database(&backup).await.context("Failed to back up the database")?;
+    upgrade.backup = Some(backup);
+
+    progress(upgrade, "Dragonfly is fetching its Helm chart.".to_string(), "rocket-smoke").await?;
+    let repo_dir = std::env::temp_dir().join("dragonfly-charts-upgrade");
+    if repo_dir.exists() {
+        tokio::fs::remove_dir_all(&repo_dir).await
+            .with_context(|| format!("Failed to clean up {}", repo_dir.display()))?;
+    }
+    let repo = repo_dir.to_str().ok_or_else(|| anyhow!("Chart path is not valid UTF-8"))?;
+    // The chart is tagged alongside each release, so the templates match the image they deploy
+    run_command(
+        "git", &["clone", "--depth", "1", "--branch", upgrade.to_version.as_str(), CHARTS_REPO, repo],
+        "clone the Dragonfly Helm charts",
+    ).await?;
+    let chart = repo_dir.join("dragonfly");
+    let chart = chart.to_str().ok_or_else(|| anyhow!("Chart path is not valid UTF-8"))?;
+
+    progress(upgrade, format!("Dragonfly is upgrading to {}.", upgrade.to_version), "rocket-flicker").await?;
+    let namespace = crate::status::dragonfly_namespace();
+    let image_tag = format!("image.tag={}", upgrade.to_version);
+    // No --wait: rolling the StatefulSet replaces this server
+    let mut args = vec![
+        "upgrade", RELEASE_NAME, chart,
+        "--namespace", namespace.as_str(),
+        "--reuse-values",
+        "--set-string", image_tag.as_str(),
+    ];
+    let kubeconfig = crate::status::kube_settings().kubeconfig;
+    if let Some(kubeconfig) = &kubeconfig {
+        args.extend(["--kubeconfig", kubeconfig.as_str()]);
+    }
+    run_command("helm", &args, "upgrade the Dragonfly Helm release").await?;
+
+    upgrade.state = UpgradeState::Rolling;
+    progress(upgrade, format!("Dragonfly is restarting on {}.", upgrade.to_version), "rocket-fire").await?;
+    roll_statefulset(&upgrade.to_version).await
+}
+
+pub fn in_progress() -> bool {
+    IN_PROGRESS.load(Ordering::SeqCst)
+}
+
+/// Start upgrading to `version` in the background. Returns the upgrade as recorded.
+pub async fn start(version: String, requested_by: &str) -> Result<Upgrade> {
+    if !valid_release_tag(&version) {
+        bail!("'{}' isn't a release version", version);
+    }
+    if IN_PROGRESS.swap(true, Ordering::SeqCst) {
+        bail!("An upgrade is already running");
+    }
+    let upgrade = Upgrade {
+        id: 0,
+        from_version: VERSION.to_string(),
+        to_version: version,
+        requested_by: requested_by.to_string(),
+        state: UpgradeState::Running,
+        message: "Upgrade requested".to_string(),
+        backup: None,
+        started_at: Utc::now(),
+        finished_at: None,
+    };
+    let mut upgrade = match db::add_upgrade(&upgrade).await {
+        Ok(upgrade) => upgrade,
+        Err(e) => {
+            IN_PROGRESS.store(false, Ordering::SeqCst);
+            return Err(e);
+        },
+    };
+    info!("{} started an upgrade from {} to {}", requested_by, VERSION, upgrade.to_version);
+    let started = upgrade.clone();
+    tokio::spawn(async move {
+        if let Err(e) = run(&mut upgrade).await {
+            error!("Upgrade to {} failed: {}", upgrade.to_version, e);
+            report(&format!("The upgrade to {} failed: {}", upgrade.to_version, e), "rocket-error");
+            upgrade.state = UpgradeState::Failed;
+            upgrade.message = e.to_string();
+            upgrade.finished_at = Some(Utc::now());
+            if let Err(e) = db::save_upgrade(&upgrade).await {
+                error!("Failed to record the failed upgrade: {}", e);
+            }
+            IN_PROGRESS.store(false, Ordering::SeqCst);
+        }
+        // On success this server is replaced; the new one records how the upgrade ended
+    });
+    Ok(started)
+}
+
+/// Record how an upgrade that restarted the server ended, once the database is migrated
+pub async fn finish_pending() -> Result<()> {
+    let Some(mut upgrade) = db::get_upgrades(1).await?.into_iter().next() else {
+        return Ok(());
+    };
+    match upgrade.state {
+        UpgradeState::Rolling if same_version(&upgrade.to_version, VERSION) => {
+            upgrade.state = UpgradeState::Completed;
+            upgrade.message = format!("Upgraded from {} to {}", upgrade.from_version, VERSION);
+            report("Dragonfly is ready.", "rocket-fire rocket-shift");
+        },
+        UpgradeState::Rolling => {
+            upgrade.state = UpgradeState::Failed;
+            upgrade.message = format!("Dragonfly came back on {} instead of {}", VERSION, upgrade.to_version);
+        },
+        UpgradeState::Running => {
+            upgrade.state = UpgradeState::Failed;
+            upgrade.message = format!("Dragonfly restarted before the upgrade finished ({})", upgrade.message);
+        },
+        UpgradeState::Completed | UpgradeState::Failed => return Ok(()),
+    }
+    info!("Upgrade to {}: {}", upgrade.to_version, upgrade.message);
+    upgrade.finished_at = Some(Utc::now());
+    db::save_upgrade(&upgrade).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_comparison() {
+        assert_eq!(parse_version("v0.2.10-rc1"), Some(vec![0, 2, 10]));
+        assert_eq!(parse_version("latest"), None);
+        assert!(is_newer("v0.2.10", "0.2.9"));
+        assert!(is_newer("1.0", "0.9.9"));
+        assert!(!is_newer("0.1.0", "0.1.0"));
+        assert!(!is_newer("main", "0.1.0"));
+        assert!(same_version("v0.2.0", "0.2"));
+
+        assert!(valid_release_tag("v0.2.10"));
+        assert!(valid_release_tag("1.0-rc.1"));
+        for tag in ["", "v", "0.2.", "0..2", "v0.2+build", "0.2-", "0.2,image.pullPolicy=Never", "--help", "0.2-rc 1"] {
+            assert!(!valid_release_tag(tag), "{:?} passed", tag);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::auth::{AdminUser, Credentials};
+use crate::db;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Break-glass logins are looked up by this prefix when no account matches, so accounts can't use it
+const RESERVED_PREFIX: &str = "breakglass-";
+
+/// An operator account. Each operator signs in as themselves and shows up under their own name in
+/// logs and audit trails. Administrators may do anything; other accounts get what ACLs grant them.
+#[derive(Debug, Clone, Serialize)]
+pub struct UserAccount {
+    pub id: i64,
+    pub username: String,
+    pub disabled: bool,
+    pub is_admin: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub last_login_at: Option<DateTime<Utc>>,
+    pub totp_enabled: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum UserError {
+    #[error("{0}")]
+    Invalid(String),
+    #[error("A user named '{0}' already exists")]
+    Exists(String),
+    #[error("User not found")]
+    NotFound,
+    #[error("Current password is incorrect")]
+    WrongPassword,
+    #[error(transparent)]
+    Database(#[from] anyhow::Error),
+}
+
+pub fn validate_username(username: &str) -> Result<(), UserError> {
+    if username.is_empty() || username.len() > 64 {
+        return Err(UserError::Invalid("Usernames must be 1 to 64 characters long".to_string()));
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '@')) {
+        return Err(UserError::Invalid("Usernames may only contain letters, digits, '.', '_', '-' and '@'".to_string()));
+    }
+    if username.to_ascii_lowercase().starts_with(RESERVED_PREFIX) {
+        return Err(UserError::Invalid(format!("Usernames starting with '{}' are reserved", RESERVED_PREFIX)));
+    }
+    Ok(())
+}
+
+pub fn validate_password(password: &str) -> Result<(), UserError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(UserError::Invalid(format!("Passwords must be at least {} characters long", MIN_PASSWORD_LENGTH)));
+    }
+    Ok(())
+}
+
+/// Check a password against an Argon2 hash off the async runtime
+pub async fn verify_password(password_hash: String, password: String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&password_hash)
+            .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+            .unwrap_or(false)
+    }).await.unwrap_or(false)
+}
+
+fn hash_password(username: &str, password: &str) -> Result<String, UserError> {
+    Credentials::create(username.to_string(), password.to_string())
+        .map(|credentials| credentials.password_hash)
+        .map_err(|e| UserError::Database(e.into()))
+}
+
+pub async fn create(username: &str, password: &str, is_admin: bool, created_by: &str) -> Result<UserAccount, UserError> {
+    let username = username.trim();
+    validate_username(username)?;
+    validate_password(password)?;
+
+    let password_hash = hash_password(username, password)?;
+    let account = db::add_user_account(username, &password_hash, is_admin).await?
+        .ok_or_else(|| UserError::Exists(username.to_string()))?;
+    info!("User '{}' created by '{}'", account.username, created_by);
+    Ok(account)
+}
+
+/// Disable or re-enable an account. Disabling takes effect immediately: open sessions end on their next request.
+pub async fn set_disabled(actor: &AdminUser, id: i64, disabled: bool) -> Result<UserAccount, UserError> {
+    if disabled {
+        ensure_not_last_or_self(actor, id, "disable").await?;
+    }
+    if !db::set_user_disabled(id, disabled).await? {
+        return Err(UserError::NotFound);
+    }
+    let account = db::get_user_account(id).await?.ok_or(UserError::NotFound)?;
+    info!("User '{}' {} by '{}'", account.username, if disabled { "disabled" } else { "enabled" }, actor.username);
+    Ok(account)
+}
+
+/// Grant or revoke administrator rights; the last enabled administrator keeps them
+pub async fn set_admin(actor: &AdminUser, id: i64, is_admin: bool) -> Result<UserAccount, UserError> {
+    if !is_admin {
+        ensure_not_last_or_self(actor, id, "demote").await?;
+    }
+    if !db::set_user_admin(id, is_admin).await? {
+        return Err(UserError::NotFound);
+    }
+    let account = db::get_user_account(id).await?.ok_or(UserError::NotFound)?;
+    info!("User '{}' {} by '{}'", account.username, if is_admin { "made an administrator" } else { "no longer an administrator" }, actor.username);
+    Ok(account)
+}
+
+pub async fn delete(actor: &AdminUser, id: i64) -> Result<(), UserError> {
+    ensure_not_last_or_self(actor, id, "delete").await?;
+    let account = db::get_user_account(id).await?.ok_or(UserError::NotFound)?;
+    if !db::delete_user_account(id).await? {
+        return Err(UserError::NotFound);
+    }
+    info!("User '{}' deleted by '{}'", account.username, actor.username);
+    Ok(())
+}
+
+/// Self-service password change; the current password must be supplied
+pub async fn change_password(user: &AdminUser, current_password: &str, new_password: &str) -> Result<(), UserError> {
+    validate_password(new_password)?;
+    let Some(password_hash) = db::get_user_password_hash(user.id).await? else {
+        return Err(UserError::NotFound);
+    };
+    if !verify_password(password_hash, current_password.to_string()).await {
+        warn!("Password change for '{}' rejected: wrong current password", user.username);
+        return Err(UserError::WrongPassword);
+    }
+
+    let password_hash = hash_password(&user.username, new_password)?;
+    if !db::update_user_password(user.id, &password_hash).await? {
+        return Err(UserError::NotFound);
+    }
+    info!("User '{}' changed their password", user.username);
+    Ok(())
+}
+
+// Nobody can lock themselves out, and there must always be an enabled administrator to sign in with
+async fn ensure_not_last_or_self(actor: &AdminUser, id: i64, action: &str) -> Result<(), UserError> {
+    if actor.id == id {
+        return Err(UserError::Invalid(format!("You can't {} your own account", action)));
+    }
+    let account = db::get_user_account(id).await?.ok_or(UserError::NotFound)?;
+    if !account.disabled && db::count_enabled_users().await? <= 1 {
+        return Err(UserError::Invalid(format!("Can't {} the last enabled account", action)));
+    }
+    if !account.disabled && account.is_admin && db::count_enabled_admins().await? <= 1 {
+        return Err(UserError::Invalid(format!("Can't {} the last enabled administrator", action)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_username_rules() {
+        assert!(validate_username("alice").is_ok());
+        assert!(validate_username("ops.bob@example.com").is_ok());
+        assert!(validate_username("").is_err());
+        assert!(validate_username("has space").is_err());
+        assert!(validate_username("BreakGlass-abc123").is_err());
+    }
+}
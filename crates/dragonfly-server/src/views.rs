@@ -0,0 +1,184 @@
+//! Saved views of the machine list.
+//!
+//! A view is a search query, a sort order and a set of columns, saved under a name by the user
+//! who made it. Pinned views get a card on the dashboard, and a user's default view is what
+//! `/machines` shows them when they haven't searched for anything.
+
+use dragonfly_common::models::Machine;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+use crate::custom_fields::{FieldDefinition, FILTER_PREFIX};
+use crate::search::Search;
+
+/// Columns of the machine list that can be hidden; the name and actions are always shown
+pub const COLUMNS: &[&str] = &["mac", "ip", "status", "os"];
+/// What the list can be sorted by; a leading `-` sorts descending
+pub const SORTS: &[&str] = &["name", "mac", "ip", "status", "os", "created", "updated"];
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SavedView {
+    #[serde(default)]
+    pub id: i64,
+    /// The user the view belongs to; set by the server
+    #[serde(default, skip_deserializing)]
+    pub owner: String,
+    pub name: String,
+    /// A machine search, see `search::Search`
+    #[serde(default)]
+    pub query: String,
+    #[serde(default)]
+    pub sort: Option<String>,
+    /// Columns to show, from [`COLUMNS`] and `field.<name>`. None shows the usual ones.
+    #[serde(default)]
+    pub columns: Option<Vec<String>>,
+    /// Shown on the dashboard
+    #[serde(default)]
+    pub pinned: bool,
+    /// Shown on the machine list when nothing else is asked for; one per user
+    #[serde(default)]
+    pub is_default: bool,
+}
+
+pub fn validate(view: &SavedView, fields: &[FieldDefinition]) -> Result<(), String> {
+    let name = view.name.trim();
+    if name.is_empty() || name.len() > 64 {
+        return Err("A view needs a name of at most 64 characters".to_string());
+    }
+    Search::parse(&view.query)?;
+    if let Some(sort) = &view.sort {
+        if !SORTS.contains(&sort.trim_start_matches('-')) {
+            return Err(format!("Can't sort by '{}'; use one of {}", sort, SORTS.join(", ")));
+        }
+    }
+    for column in view.columns.iter().flatten() {
+        let known = match column.strip_prefix(FILTER_PREFIX) {
+            Some(field) => fields.iter().any(|f| f.name == field),
+            None => COLUMNS.contains(&column.as_str()),
+        };
+        if !known {
+            return Err(format!("Unknown column '{}'", column));
+        }
+    }
+    Ok(())
+}
+
+/// The built-in columns a view shows
+pub fn visible_columns(columns: Option<&[String]>) -> Vec<String> {
+    COLUMNS.iter()
+        .filter(|column| columns.is_none_or(|columns| columns.iter().any(|c| c == *column)))
+        .map(|column| column.to_string())
+        .collect()
+}
+
+/// The custom fields a view shows: those listed, or else those shown in the list by default
+pub fn visible_fields(columns: Option<&[String]>, fields: Vec<FieldDefinition>) -> Vec<FieldDefinition> {
+    fields.into_iter()
+        .filter(|field| match columns {
+            Some(columns) => columns.iter().any(|c| c.strip_prefix(FILTER_PREFIX) == Some(field.name.as_str())),
+            None => field.show_in_list,
+        })
+        .collect()
+}
+
+fn name(machine: &Machine) -> String {
+    machine.hostname.as_ref().or(machine.memorable_name.as_ref()).cloned().unwrap_or_default().to_lowercase()
+}
+
+// Addresses sort numerically where they parse, after those that don't
+fn compare_ips(a: &str, b: &str) -> Ordering {
+    match (a.parse::<std::net::IpAddr>(), b.parse::<std::net::IpAddr>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+/// Sort machines by one of [`SORTS`]; anything else leaves them as they are
+pub fn sort_machines(machines: &mut [Machine], sort: &str) {
+    let descending = sort.starts_with('-');
+    let compare: fn(&Machine, &Machine) -> Ordering = match sort.trim_start_matches('-') {
+        "name" => |a, b| name(a).cmp(&name(b)),
+        "mac" => |a, b| a.mac_address.to_lowercase().cmp(&b.mac_address.to_lowercase()),
+        "ip" => |a, b| compare_ips(&a.ip_address, &b.ip_address),
+        "status" => |a, b| a.status.to_string().cmp(&b.status.to_string()),
+        "os" => |a, b| a.os_installed.as_ref().or(a.os_choice.as_ref()).cmp(&b.os_installed.as_ref().or(b.os_choice.as_ref())),
+        "created" => |a, b| a.created_at.cmp(&b.created_at),
+        "updated" => |a, b| a.updated_at.cmp(&b.updated_at),
+        _ => return,
+    };
+    machines.sort_by(|a, b| if descending { compare(b, a) } else { compare(a, b) });
+}
+
+/// Where a page of the machine list is in the whole list
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Page {
+    /// From 1
+    pub number: usize,
+    pub count: usize,
+    /// Machines in the whole list
+    pub total: usize,
+}
+
+/// Keep only one page of the list; a page past the end shows the last one
+pub fn paginate<T>(items: &mut Vec<T>, requested: usize, page_size: Option<u32>) -> Page {
+    let total = items.len();
+    let Some(size) = page_size.map(|size| size as usize).filter(|size| *size > 0) else {
+        return Page { number: 1, count: 1, total };
+    };
+    let count = total.div_ceil(size).max(1);
+    let number = requested.clamp(1, count);
+    items.truncate(number * size);
+    items.drain(..(number - 1) * size);
+    Page { number, count, total }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::custom_fields::FieldType;
+
+    fn view(query: &str, sort: Option<&str>, columns: Option<Vec<&str>>) -> SavedView {
+        SavedView {
+            id: 0,
+            owner: String::new(),
+            name: "prod storage".to_string(),
+            query: query.to_string(),
+            sort: sort.map(str::to_string),
+            columns: columns.map(|c| c.into_iter().map(str::to_string).collect()),
+            pinned: true,
+            is_default: false,
+        }
+    }
+
+    #[test]
+    fn test_validate_and_columns() {
+        let fields = vec![FieldDefinition { name: "owner".to_string(), label: "Owner".to_string(), field_type: FieldType::Text, show_in_list: true }];
+        assert!(validate(&view("status:ready label:role=storage", Some("-updated"), Some(vec!["ip", "field.owner"])), &fields).is_ok());
+        assert!(validate(&view("status:melted", None, None), &fields).is_err());
+        assert!(validate(&view("", Some("colour"), None), &fields).is_err());
+        assert!(validate(&view("", None, Some(vec!["field.rack"])), &fields).is_err());
+
+        let columns = vec!["ip".to_string()];
+        assert_eq!(visible_columns(Some(&columns)), vec!["ip"]);
+        assert_eq!(visible_columns(None).len(), COLUMNS.len());
+        assert!(visible_fields(Some(&columns), fields.clone()).is_empty());
+        assert_eq!(visible_fields(None, fields).len(), 1);
+        assert_eq!(compare_ips("10.0.0.9", "10.0.0.10"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_paginate() {
+        let mut items: Vec<u32> = (1..=7).collect();
+        assert_eq!(paginate(&mut items, 2, Some(3)), Page { number: 2, count: 3, total: 7 });
+        assert_eq!(items, vec![4, 5, 6]);
+        let mut items: Vec<u32> = (1..=7).collect();
+        assert_eq!(paginate(&mut items, 9, Some(3)).number, 3);
+        assert_eq!(items, vec![7]);
+        let mut items: Vec<u32> = (1..=7).collect();
+        assert_eq!(paginate(&mut items, 2, None), Page { number: 1, count: 1, total: 7 });
+        assert_eq!(items.len(), 7);
+        assert_eq!(paginate(&mut Vec::<u32>::new(), 1, Some(25)).count, 1);
+    }
+}
@@ -0,0 +1,106 @@
+use axum::extract::ws::{Message, WebSocket};
+use dragonfly_common::models::Machine;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::console::bmc_host;
+
+pub const DEFAULT_VNC_PORT: u16 = 5900;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_port() -> u16 {
+    DEFAULT_VNC_PORT
+}
+
+/// Where a machine's VNC server lives: the KVM built into its BMC (OpenBMC, Supermicro, iDRAC with
+/// VNC enabled). Only the BMC's address is ever connected to, so the relay can't reach other hosts.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct VncTarget {
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+impl Default for VncTarget {
+    fn default() -> Self {
+        VncTarget { port: DEFAULT_VNC_PORT }
+    }
+}
+
+impl VncTarget {
+    /// The `host:port` to connect to for a machine
+    pub fn resolve(&self, machine: &Machine) -> Result<String, String> {
+        let address = machine.bmc_credentials.as_ref()
+            .ok_or_else(|| "The machine has no BMC credentials".to_string())?
+            .address.as_str();
+        let host = bmc_host(address).ok_or_else(|| format!("Invalid BMC address '{}'", address))?;
+        if host.is_empty() || self.port == 0 {
+            return Err("VNC target needs a host and a port".to_string());
+        }
+        // IPv6 literals need brackets to carry a port
+        Ok(if host.contains(':') { format!("[{}]:{}", host, self.port) } else { format!("{}:{}", host, self.port) })
+    }
+}
+
+/// Relay RFB traffic between noVNC in the browser and the machine's VNC server, like websockify
+pub async fn run_vnc_session(mut socket: WebSocket, machine_id: Uuid, address: String) {
+    let stream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&address)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            warn!("Failed to connect to VNC server {} for machine {}: {}", address, machine_id, e);
+            let _ = socket.send(Message::Close(None)).await;
+            return;
+        },
+        Err(_) => {
+            warn!("Timed out connecting to VNC server {} for machine {}", address, machine_id);
+            let _ = socket.send(Message::Close(None)).await;
+            return;
+        }
+    };
+    let (mut reader, mut writer) = stream.into_split();
+
+    info!("VNC session opened for machine {} via {}", machine_id, address);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        tokio::select! {
+            read = reader.read(&mut buf) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => if socket.send(Message::Binary(buf[..n].to_vec().into())).await.is_err() { break },
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Binary(data))) => if writer.write_all(&data).await.is_err() { break },
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                Some(Ok(_)) => {},
+            },
+        }
+    }
+
+    let _ = socket.send(Message::Close(None)).await;
+    info!("VNC session closed for machine {}", machine_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_is_the_bmc() {
+        // Targets saved before only the BMC was allowed keep their port
+        let target: VncTarget = serde_json::from_str(r#"{"source": "address", "host": "10.0.0.9", "port": 5901}"#).unwrap();
+        assert_eq!(target, VncTarget { port: 5901 });
+
+        let mut machine = crate::test_support::machine("bc:24:11:b9:54:89");
+        assert!(target.resolve(&machine).is_err());
+        machine.bmc_credentials = Some(dragonfly_common::models::BmcCredentials {
+            address: "10.0.9.20".to_string(),
+            username: "admin".to_string(),
+            password: None,
+            bmc_type: dragonfly_common::models::BmcType::IPMI,
+        });
+        assert_eq!(target.resolve(&machine).unwrap(), "10.0.9.20:5901");
+    }
+}
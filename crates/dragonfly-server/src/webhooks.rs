@@ -0,0 +1,219 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use dragonfly_common::models::Machine;
+use minijinja::{context, Environment, Value};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::db;
+use crate::event_manager::{EventManager, RecvError};
+
+/// Events delivered to endpoints that don't list any explicitly
+pub const DEFAULT_EVENTS: &[&str] = &["machine_discovered", "machine_updated", "machine_deleted", "machine_offline", "machine_online", "machine_conflict", "discovery_unexpected_device"];
+
+/// An outbound webhook receiver
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookEndpoint {
+    #[serde(default)]
+    pub id: i64,
+    pub name: String,
+    pub url: String,
+    /// Event types to deliver, e.g. `machine_updated`. Empty means `DEFAULT_EVENTS`.
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// MiniJinja template producing the request body. Without one the standard JSON payload is sent.
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl WebhookEndpoint {
+    pub fn wants(&self, event: &str) -> bool {
+        if self.events.is_empty() {
+            DEFAULT_EVENTS.contains(&event)
+        } else {
+            self.events.iter().any(|e| e == event || e == "*")
+        }
+    }
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    // Always available, so templates can embed objects into JSON bodies
+    env.add_filter("tojson", |value: Value| -> Result<String, minijinja::Error> {
+        serde_json::to_string(&value).map_err(|e| minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, e.to_string()))
+    });
+    env
+}
+
+/// Check that a payload template compiles before it is stored
+pub fn validate_template(template: &str) -> Result<()> {
+    environment().template_from_str(template)
+        .map(|_| ())
+        .map_err(|e| anyhow!("Template syntax error: {}", e))
+}
+
+/// Build the request body for an event. Templates see `event`, `subject`, `machine` and `timestamp`.
+pub fn render_payload(endpoint: &WebhookEndpoint, event: &str, subject: &str, machine: Option<&Machine>) -> Result<String> {
+    let timestamp = Utc::now().to_rfc3339();
+    match &endpoint.template {
+        Some(template) => {
+            let ctx = context! {
+                event => event,
+                subject => subject,
+                machine => machine,
+                timestamp => timestamp,
+                webhook => endpoint.name,
+            };
+            Ok(environment().render_str(template, ctx)?)
+        },
+        None => Ok(serde_json::to_string(&serde_json::json!({
+            "event": event,
+            "subject": subject,
+            "machine": machine,
+            "timestamp": timestamp,
+        }))?),
+    }
+}
+
+/// POST a body to a URL, failing unless the receiver answers with a success status
+pub async fn post(client: &reqwest::Client, url: &str, content_type: &str, body: String) -> Result<()> {
+    let response = client.post(url)
+        .header(reqwest::header::CONTENT_TYPE, content_type)
+        .body(body)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("{} responded with status {}", url, response.status()));
+    }
+    Ok(())
+}
+
+/// POST an already rendered body to an endpoint
+pub async fn deliver(client: &reqwest::Client, endpoint: &WebhookEndpoint, body: String) -> Result<()> {
+    post(client, &endpoint.url, endpoint.content_type.as_deref().unwrap_or("application/json"), body).await
+}
+
+/// Render and send one event to one endpoint
+pub async fn send_event(client: &reqwest::Client, endpoint: &WebhookEndpoint, event: &str, subject: &str, machine: Option<&Machine>) -> Result<()> {
+    let body = render_payload(endpoint, event, subject, machine)?;
+    deliver(client, endpoint, body).await
+}
+
+async fn dispatch(client: &reqwest::Client, message: &str) {
+    let (event, subject) = message.split_once(':').unwrap_or((message, ""));
+
+    let endpoints: Vec<WebhookEndpoint> = match db::get_webhooks().await {
+        Ok(endpoints) => endpoints.into_iter().filter(|e| e.enabled && e.wants(event)).collect(),
+        Err(e) => {
+            error!("Failed to load webhook endpoints: {}", e);
+            Vec::new()
+        }
+    };
+    let channels = crate::notifications::channels_for(event).await;
+    if endpoints.is_empty() && channels.is_empty() {
+        return;
+    }
+
+    // Deleted machines are looked up in the archive, so IPAM and DNS hooks can release what they held
+    let machine = match Uuid::parse_str(subject) {
+        Ok(id) => match db::get_machine_by_id(&id).await.ok().flatten() {
+            Some(machine) => Some(machine),
+            None => db::get_archived_machine(&id).await.ok().flatten().map(|archived| archived.machine),
+        },
+        Err(_) => None,
+    };
+
+    for endpoint in endpoints {
+        match send_event(client, &endpoint, event, subject, machine.as_ref()).await {
+            Ok(()) => debug!("Delivered {} to webhook '{}'", event, endpoint.name),
+            Err(e) => warn!("Failed to deliver {} to webhook '{}': {}", event, endpoint.name, e),
+        }
+    }
+    crate::notifications::notify(client, &channels, event, machine.as_ref()).await;
+}
+
+/// Forward events to the configured webhook endpoints and chat channels until shutdown
+pub async fn start_webhook_dispatcher(
+    event_manager: Arc<EventManager>,
+    mut shutdown_rx: tokio::sync::watch::Receiver<()>,
+) {
+    let mut rx = event_manager.subscribe();
+    tokio::spawn(async move {
+        let client = match reqwest::Client::builder().timeout(Duration::from_secs(10)).build() {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Failed to create webhook HTTP client, webhooks disabled: {}", e);
+                return;
+            }
+        };
+        info!("Webhook dispatcher started");
+
+        loop {
+            tokio::select! {
+                message = rx.recv() => match message {
+                    Ok(message) => dispatch(&client, &message).await,
+                    Err(RecvError::Lagged(skipped)) => warn!("Webhook dispatcher fell behind, {} events were not delivered", skipped),
+                    Err(RecvError::Closed) => break,
+                },
+                _ = shutdown_rx.changed() => {
+                    info!("Shutdown signal received, stopping webhook dispatcher.");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(template: Option<&str>, events: &[&str]) -> WebhookEndpoint {
+        WebhookEndpoint {
+            id: 1,
+            name: "pagerduty".to_string(),
+            url: "http://example.invalid".to_string(),
+            events: events.iter().map(|e| e.to_string()).collect(),
+            template: template.map(str::to_string),
+            content_type: None,
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn test_event_filter() {
+        assert!(endpoint(None, &[]).wants("machine_updated"));
+        assert!(!endpoint(None, &[]).wants("mode_configured"));
+        assert!(endpoint(None, &["mode_configured"]).wants("mode_configured"));
+        assert!(!endpoint(None, &["machine_deleted"]).wants("machine_updated"));
+        assert!(endpoint(None, &["*"]).wants("template_changed"));
+    }
+
+    #[test]
+    fn test_templated_payload() {
+        let template = r#"{"routing_key": "abc", "event_action": "trigger", "payload": {"summary": "{{ event }} {{ subject }}", "source": {{ webhook | tojson }}}}"#;
+        let body = render_payload(&endpoint(Some(template), &[]), "machine_deleted", "1234", None).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(parsed["payload"]["summary"], "machine_deleted 1234");
+        assert_eq!(parsed["payload"]["source"], "pagerduty");
+    }
+
+    #[test]
+    fn test_default_payload() {
+        let body = render_payload(&endpoint(None, &[]), "machine_updated", "1234", None).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(parsed["event"], "machine_updated");
+        assert!(parsed["machine"].is_null());
+    }
+}
@@ -0,0 +1,468 @@
+use chrono::{DateTime, Utc};
+use dragonfly_common::models::Machine;
+use kube::api::{DeleteParams, Patch, PatchParams};
+use serde::Serialize;
+use serde_yaml::Value;
+use std::collections::{BTreeMap, HashSet};
+use thiserror::Error;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{db, os_templates};
+
+/// Names that would collide with other `/api/templates/...` routes
+const RESERVED_NAMES: &[&str] = &["stats"];
+
+/// A Tinkerbell workflow template, either shipped with Dragonfly or stored through the API
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkflowTemplate {
+    pub name: String,
+    pub content: String,
+    /// The stored version in use, or 0 for a shipped template nobody has edited
+    pub version: i64,
+    /// Whether the template in use was stored through the API
+    pub custom: bool,
+    /// Whether Dragonfly ships a template with this name, which deleting the custom one restores
+    pub builtin: bool,
+    pub updated_by: Option<String>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// One saved revision of a template
+#[derive(Debug, Clone, Serialize)]
+pub struct TemplateVersion {
+    pub name: String,
+    pub version: i64,
+    pub content: String,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl TemplateVersion {
+    fn into_template(self, builtin: bool) -> WorkflowTemplate {
+        WorkflowTemplate {
+            name: self.name,
+            content: self.content,
+            version: self.version,
+            custom: true,
+            builtin,
+            updated_by: Some(self.created_by),
+            updated_at: Some(self.created_at),
+        }
+    }
+}
+
+/// What a template would produce for one machine, rendered without applying anything
+#[derive(Debug, Clone, Serialize)]
+pub struct RenderPreview {
+    pub template: String,
+    pub version: i64,
+    pub machine_id: Uuid,
+    /// The workflow Tinkerbell would run, after Dragonfly's and Tinkerbell's substitutions
+    pub workflow: String,
+    /// The iPXE script the machine would boot
+    pub ipxe: Option<String>,
+    /// cloud-init user-data, if the OS has a user-data template, with secrets masked
+    pub user_data: Option<String>,
+    /// Anything the preview couldn't render, such as Go template actions it doesn't evaluate
+    pub warnings: Vec<String>,
+}
+
+/// Stands in for the render token a real workflow is issued, as a dry run mustn't issue one
+const PREVIEW_RENDER_TOKEN: &str = "RENDER-TOKEN";
+
+#[derive(Debug, Error)]
+pub enum TemplateError {
+    #[error("{0}")]
+    Invalid(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("Tinkerbell rejected the template: {0}")]
+    Tinkerbell(anyhow::Error),
+    #[error(transparent)]
+    Database(#[from] anyhow::Error),
+}
+
+/// Template names become Kubernetes resource names, so they must be DNS labels
+pub fn validate_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.len() <= 63
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !name.starts_with('-')
+        && !name.ends_with('-');
+    if !valid {
+        return Err(format!("'{}' is not a valid template name; use up to 63 lowercase letters, digits and dashes", name));
+    }
+    if RESERVED_NAMES.contains(&name) {
+        return Err(format!("'{}' is reserved", name));
+    }
+    Ok(())
+}
+
+/// Stand in for Go template actions so the workflow in `spec.data` can be parsed as YAML.
+/// Tinkerbell renders the Go template before parsing, so `{{ index .Hardware.Disks 0 }}` may
+/// appear where YAML expects a scalar, and `{{ if }}`/`{{ end }}` on their own lines.
+fn stand_in_actions(data: &str) -> Result<String, String> {
+    let mut out = Vec::new();
+    for (number, line) in data.lines().enumerate() {
+        let mut rest = line;
+        let mut replaced = String::new();
+        while let Some(start) = rest.find("{{") {
+            let end = rest[start..].find("}}")
+                .ok_or_else(|| format!("workflow line {}: unclosed {{{{", number + 1))?;
+            replaced.push_str(&rest[..start]);
+            replaced.push_str("template-value");
+            rest = &rest[start + end + 2..];
+        }
+        replaced.push_str(rest);
+        // A line holding nothing but actions is control flow rather than a value
+        if line.contains("{{") && replaced.replace("template-value", "").trim().is_empty() {
+            continue;
+        }
+        out.push(replaced);
+    }
+    Ok(out.join("\n"))
+}
+
+fn require_str<'a>(value: &'a Value, field: &str, at: &str) -> Result<&'a str, String> {
+    value.get(field).and_then(Value::as_str).filter(|s| !s.trim().is_empty())
+        .ok_or_else(|| format!("{}: '{}' must be a non-empty string", at, field))
+}
+
+fn require_seq<'a>(value: &'a Value, field: &str, at: &str) -> Result<&'a Vec<Value>, String> {
+    value.get(field).and_then(Value::as_sequence).filter(|s| !s.is_empty())
+        .ok_or_else(|| format!("{}: '{}' must be a non-empty list", at, field))
+}
+
+/// Check the workflow in a template's `spec.data` against Tinkerbell's workflow schema
+fn validate_workflow(data: &str) -> Result<(), String> {
+    let workflow: Value = serde_yaml::from_str(&stand_in_actions(data)?)
+        .map_err(|e| format!("spec.data is not valid YAML: {}", e))?;
+    require_str(&workflow, "name", "workflow")?;
+    if !matches!(workflow.get("version"), Some(Value::String(_)) | Some(Value::Number(_))) {
+        return Err("workflow: 'version' is required".to_string());
+    }
+    if workflow.get("global_timeout").and_then(Value::as_u64).is_none() {
+        return Err("workflow: 'global_timeout' must be a whole number of seconds".to_string());
+    }
+    for (t, task) in require_seq(&workflow, "tasks", "workflow")?.iter().enumerate() {
+        let at = format!("tasks[{}]", t);
+        require_str(task, "name", &at)?;
+        require_str(task, "worker", &at)?;
+        let mut names = HashSet::new();
+        for (a, action) in require_seq(task, "actions", &at)?.iter().enumerate() {
+            let at = format!("tasks[{}].actions[{}]", t, a);
+            let name = require_str(action, "name", &at)?;
+            require_str(action, "image", &at)?;
+            if action.get("timeout").and_then(Value::as_u64).is_none() {
+                return Err(format!("{}: 'timeout' must be a whole number of seconds", at));
+            }
+            if !names.insert(name) {
+                return Err(format!("{}: action name '{}' is used twice in this task", at, name));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check a template is a Tinkerbell Template resource named `name` with a valid workflow
+pub fn validate(name: &str, content: &str) -> Result<(), String> {
+    let doc: Value = serde_yaml::from_str(content).map_err(|e| format!("Invalid YAML: {}", e))?;
+    if doc.get("apiVersion").and_then(Value::as_str) != Some("tinkerbell.org/v1alpha1") {
+        return Err("apiVersion must be tinkerbell.org/v1alpha1".to_string());
+    }
+    if doc.get("kind").and_then(Value::as_str) != Some("Template") {
+        return Err("kind must be Template".to_string());
+    }
+    if doc.get("metadata").and_then(|m| m.get("name")).and_then(Value::as_str) != Some(name) {
+        return Err(format!("metadata.name must be '{}'", name));
+    }
+    let data = doc.get("spec").and_then(|s| s.get("data")).and_then(Value::as_str)
+        .ok_or_else(|| "spec.data must hold the workflow as a string".to_string())?;
+    validate_workflow(data)
+}
+
+/// Tinkerbell's `formatPartition`: devices whose names end in a digit, like NVMe, get a `p`
+/// before the partition number
+pub(crate) fn format_partition(disk: &str, partition: &str) -> String {
+    if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p{}", disk, partition)
+    } else {
+        format!("{}{}", disk, partition)
+    }
+}
+
+/// Evaluate the Go template actions Dragonfly's templates use: hardware map fields like
+/// `.device_1`, `index .Hardware.Disks N` and `formatPartition`
+fn eval_action(expr: &str, hardware_map: &BTreeMap<String, String>, disks: &[String]) -> Option<String> {
+    let expr = expr.trim().trim_start_matches('-').trim_end_matches('-').trim();
+    if let Some(rest) = expr.strip_prefix("formatPartition ") {
+        let rest = rest.trim();
+        let (disk, partition) = match rest.strip_prefix('(') {
+            Some(inner) => {
+                let close = inner.rfind(')')?;
+                (&inner[..close], inner[close + 1..].trim())
+            },
+            None => rest.rsplit_once(' ')?,
+        };
+        partition.parse::<u32>().ok()?;
+        return Some(format_partition(&eval_action(disk, hardware_map, disks)?, partition));
+    }
+    match expr.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["index", ".Hardware.Disks", n] => disks.get(n.parse::<usize>().ok()?).cloned(),
+        [field] => hardware_map.get(field.strip_prefix('.')?).cloned(),
+        _ => None,
+    }
+}
+
+/// Render the workflow in a template's `spec.data` as Tinkerbell would for one machine
+pub fn render_workflow(
+    content: &str,
+    base_url_bare: &str,
+    hardware_map: &BTreeMap<String, String>,
+    disks: &[String],
+) -> Result<(String, Vec<String>), String> {
+    let content = os_templates::fix_metadata_urls(content, base_url_bare);
+    let doc: Value = serde_yaml::from_str(&content).map_err(|e| format!("Invalid YAML: {}", e))?;
+    let data = doc.get("spec").and_then(|s| s.get("data")).and_then(Value::as_str)
+        .ok_or_else(|| "spec.data must hold the workflow as a string".to_string())?;
+
+    let mut warnings = Vec::new();
+    let mut rendered = String::new();
+    let mut rest = data;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..].find("}}").ok_or_else(|| "unclosed {{ in spec.data".to_string())?;
+        let action = &rest[start..start + end + 2];
+        rendered.push_str(&rest[..start]);
+        match eval_action(&action[2..action.len() - 2], hardware_map, disks) {
+            Some(value) => rendered.push_str(&value),
+            None => {
+                warnings.push(format!("Left {} as written; the preview can't evaluate it", action));
+                rendered.push_str(action);
+            }
+        }
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok((rendered, warnings))
+}
+
+/// Render a template, or one of its stored versions, for a machine: the workflow, the iPXE
+/// script and the cloud-init user-data. Nothing is applied and no render token is issued.
+pub async fn preview(name: &str, version: Option<i64>, machine: &Machine) -> Result<RenderPreview, TemplateError> {
+    let (content, version) = match version {
+        Some(version) => db::get_workflow_template_version(name, version).await?
+            .map(|v| (v.content, v.version))
+            .ok_or_else(|| TemplateError::NotFound(format!("Workflow template '{}' has no version {}", name, version)))?,
+        None => get(name).await?
+            .map(|t| (t.content, t.version))
+            .ok_or_else(|| TemplateError::NotFound(format!("No workflow template named '{}'", name)))?,
+    };
+
+    let mut hardware_map = BTreeMap::from([
+        ("device_1".to_string(), machine.mac_address.clone()),
+        ("machine_id".to_string(), machine.id.to_string()),
+        ("render_token".to_string(), PREVIEW_RENDER_TOKEN.to_string()),
+    ]);
+    let mut var_warnings = Vec::new();
+    match crate::storage_profiles::workflow_vars(machine, name).await {
+        Ok(vars) => hardware_map.extend(vars),
+        Err(e) => var_warnings.push(e.to_string()),
+    }
+    match crate::machine_profiles::workflow_vars(&machine.id).await {
+        Ok(vars) => hardware_map.extend(vars),
+        Err(e) => var_warnings.push(format!("Failed to load the machine's network config: {}", e)),
+    }
+    match crate::boot_arch::workflow_vars(&machine.id).await {
+        Ok(vars) => hardware_map.extend(vars),
+        Err(e) => var_warnings.push(format!("Failed to load the machine's architecture: {}", e)),
+    }
+    let disks: Vec<String> = machine.disks.iter().map(|d| d.device.clone()).collect();
+    let base_url_bare = os_templates::get_base_url_without_port()?;
+    let (workflow, mut warnings) = render_workflow(&content, &base_url_bare, &hardware_map, &disks)
+        .map_err(TemplateError::Invalid)?;
+    warnings.extend(var_warnings);
+    if disks.is_empty() {
+        warnings.push("The machine has reported no disks, so disk references can't be filled in".to_string());
+    }
+
+    let ipxe = match std::env::var("DRAGONFLY_BASE_URL") {
+        Ok(base_url) => {
+            let target = db::get_boot_target(&machine.id).await.ok().flatten();
+            match crate::ipxe_templates::render_boot_script(&base_url, &machine.mac_address, Some(machine), target.as_ref()).await {
+                Ok(script) => Some(script),
+                Err(e) => {
+                    warnings.push(format!("iPXE script failed to render: {}", e));
+                    None
+                }
+            }
+        },
+        Err(_) => {
+            warnings.push("DRAGONFLY_BASE_URL is not set, so the iPXE script can't be rendered".to_string());
+            None
+        }
+    };
+
+    let user_data = match crate::template_vars::preview_user_data(machine, name).await {
+        Ok(user_data) => user_data,
+        Err(e) => {
+            warnings.push(e.to_string());
+            None
+        }
+    };
+
+    Ok(RenderPreview {
+        template: name.to_string(),
+        version,
+        machine_id: machine.id,
+        workflow,
+        ipxe,
+        user_data,
+        warnings,
+    })
+}
+
+/// A template as currently used: the latest stored version, or the shipped one
+pub async fn get(name: &str) -> Result<Option<WorkflowTemplate>, TemplateError> {
+    // The name becomes a file path when looking for a shipped template
+    validate_name(name).map_err(TemplateError::Invalid)?;
+    let builtin = os_templates::read_builtin_template(name).await?;
+    if let Some(latest) = db::get_workflow_template_versions(name).await?.into_iter().next() {
+        return Ok(Some(latest.into_template(builtin.is_some())));
+    }
+    Ok(builtin.map(|content| WorkflowTemplate {
+        name: name.to_string(),
+        content,
+        version: 0,
+        custom: false,
+        builtin: true,
+        updated_by: None,
+        updated_at: None,
+    }))
+}
+
+/// Every template, stored and shipped
+pub async fn list() -> Result<Vec<WorkflowTemplate>, TemplateError> {
+    let mut templates: BTreeMap<String, WorkflowTemplate> = BTreeMap::new();
+    for name in os_templates::builtin_template_names().await {
+        if let Some(template) = get(&name).await? {
+            templates.insert(name, template);
+        }
+    }
+    for latest in db::get_latest_workflow_templates().await? {
+        if !templates.contains_key(&latest.name) {
+            templates.insert(latest.name.clone(), latest.into_template(false));
+        }
+    }
+    Ok(templates.into_values().collect())
+}
+
+/// Create or replace the Template resource in Tinkerbell
+async fn apply(content: &str) -> anyhow::Result<()> {
+    let client = crate::tinkerbell::get_client().await?;
+    let template = os_templates::prepare_template(content, &os_templates::get_base_url_without_port()?)?;
+    let name = template.metadata.name.clone().unwrap_or_default();
+    let params = PatchParams::apply("dragonfly").force();
+    os_templates::template_api(client).patch(&name, &params, &Patch::Apply(&template)).await
+        .map_err(|e| anyhow::anyhow!(crate::status::describe_kube_error(&e, &format!("apply Template '{}'", name))))?;
+    Ok(())
+}
+
+/// Validate a template, apply it to Tinkerbell and store it as a new version
+pub async fn save(name: &str, content: &str, author: &str) -> Result<WorkflowTemplate, TemplateError> {
+    validate_name(name).map_err(TemplateError::Invalid)?;
+    validate(name, content).map_err(TemplateError::Invalid)?;
+    apply(content).await.map_err(TemplateError::Tinkerbell)?;
+    let version = db::add_workflow_template_version(name, content, author).await?;
+    info!("{} saved workflow template '{}' version {}", author, name, version.version);
+    let builtin = os_templates::read_builtin_template(name).await?.is_some();
+    Ok(version.into_template(builtin))
+}
+
+/// Delete a stored template and its history. A shipped template of the same name is re-applied
+/// and returned; otherwise the Template resource is removed from Tinkerbell.
+pub async fn delete(name: &str, author: &str) -> Result<Option<WorkflowTemplate>, TemplateError> {
+    validate_name(name).map_err(TemplateError::Invalid)?;
+    if !db::delete_workflow_template(name).await? {
+        return Err(TemplateError::NotFound(format!("No stored workflow template named '{}'", name)));
+    }
+    info!("{} deleted workflow template '{}'", author, name);
+    match os_templates::read_builtin_template(name).await? {
+        Some(content) => {
+            apply(&content).await.map_err(TemplateError::Tinkerbell)?;
+            get(name).await
+        },
+        None => {
+            let client = crate::tinkerbell::get_client().await.map_err(TemplateError::Tinkerbell)?;
+            match os_templates::template_api(client).delete(name, &DeleteParams::default()).await {
+                Ok(_) => {},
+                Err(kube::Error::Api(ae)) if ae.code == 404 => {},
+                Err(e) => return Err(TemplateError::Tinkerbell(anyhow::anyhow!(
+                    crate::status::describe_kube_error(&e, &format!("delete Template '{}'", name))))),
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Apply the latest version of every stored template, so edits survive a Tinkerbell reinstall
+pub async fn apply_stored() -> anyhow::Result<()> {
+    for latest in db::get_latest_workflow_templates().await? {
+        if let Err(e) = apply(&latest.content).await {
+            warn!("Failed to apply workflow template '{}' version {}: {}", latest.name, latest.version, e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shipped_templates_validate() {
+        assert!(validate("ubuntu-2204", include_str!("../../../os-templates/ubuntu-2204.yml")).is_ok());
+        assert!(validate("ubuntu-2404", include_str!("../../../os-templates/ubuntu-2404.yml")).is_ok());
+        assert!(validate("debian-12", include_str!("../../../os-templates/ubuntu-2204.yml")).is_err());
+    }
+
+    #[test]
+    fn test_workflow_schema() {
+        let workflow = |action: &str| format!(
+            "name: x\nversion: \"0.1\"\nglobal_timeout: 600\ntasks:\n  - name: t\n    worker: \"{{{{.device_1}}}}\"\n    actions:\n{}",
+            action,
+        );
+        assert!(validate_workflow(&workflow("      - name: a\n        image: img\n        timeout: 60\n        environment:\n          DISK: {{ index .Hardware.Disks 0 }}\n")).is_ok());
+        assert!(validate_workflow(&workflow("      - name: a\n        timeout: 60\n")).unwrap_err().contains("image"));
+        assert!(validate_workflow(&workflow("      - name: a\n        image: img\n        timeout: 60\n      - name: a\n        image: img\n        timeout: 60\n")).unwrap_err().contains("twice"));
+        assert!(validate_name("Ubuntu_22").is_err());
+        assert!(validate_name("stats").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_path_names_are_validated() {
+        assert!(matches!(get("../../etc/passwd").await, Err(TemplateError::Invalid(_))));
+        assert!(matches!(delete("../ubuntu-2204", "alice").await, Err(TemplateError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_render_workflow() {
+        let hardware_map = BTreeMap::from([
+            ("device_1".to_string(), "aa:bb:cc:dd:ee:ff".to_string()),
+            ("render_token".to_string(), "tok3n".to_string()),
+            ("arch".to_string(), "arm64".to_string()),
+        ]);
+        let disks = vec!["/dev/nvme0n1".to_string()];
+        let (workflow, warnings) = render_workflow(
+            include_str!("../../../os-templates/ubuntu-2204.yml"), "10.0.0.1", &hardware_map, &disks,
+        ).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert!(workflow.contains("worker: \"aa:bb:cc:dd:ee:ff\""));
+        assert!(workflow.contains("DEST_DISK: /dev/nvme0n1p1"));
+        assert!(workflow.contains("IMG_URL: \"http://10.0.0.1:3000/ipxe/ubuntu/jammy-server-cloudimg-arm64.img\""));
+        assert!(workflow.contains("seedfrom: \"http://10.0.0.1:3000/api/render/tok3n/\""));
+
+        assert_eq!(eval_action(" formatPartition ( index .Hardware.Disks 0 ) 2 ", &hardware_map, &["/dev/sda".to_string()]), Some("/dev/sda2".to_string()));
+        assert_eq!(eval_action("if .foo", &hardware_map, &disks), None);
+    }
+}
@@ -0,0 +1,101 @@
+#!/bin/sh
+# Flashes the firmware Dragonfly plans for this machine. This is the action of the
+# firmware-update workflow template, and DRAGONFLY_FIRMWARE_URL is the machine's
+# /api/render/<token>/firmware URL.
+#
+# Images are the vendors' self-contained update packages (e.g. Dell DUPs). Each is run with
+# DRAGONFLY_FLASH_ARGS, which is empty unless the template sets it.
+set -eu
+
+: "${DRAGONFLY_FIRMWARE_URL:?DRAGONFLY_FIRMWARE_URL must be set}"
+FLASH_ARGS="${DRAGONFLY_FLASH_ARGS:-}"
+WORK=$(mktemp -d)
+
+dmi() {
+    tr -d '\n' < "/sys/class/dmi/id/$1" 2>/dev/null || true
+}
+
+component() {
+    jq -nc --arg component "$1" --arg device "$2" --arg version "$3" \
+        '{component: $component, device: $device, version: $version}'
+}
+
+# What is installed now, in the shape Dragonfly plans from
+inventory() {
+    {
+        component bios "" "$(dmi bios_version)"
+        bmc=$(ipmitool mc info 2>/dev/null | awk -F': ' '/^Firmware Revision/ { print $2; exit }')
+        if [ -n "$bmc" ]; then
+            component bmc "" "$bmc"
+        fi
+        for device in /sys/class/net/*/device; do
+            [ -e "$device" ] || continue
+            iface=$(basename "$(dirname "$device")")
+            version=$(ethtool -i "$iface" 2>/dev/null | awk -F': ' '/^firmware-version/ { print $2 }')
+            if [ -n "$version" ]; then
+                component nic "$(basename "$(readlink -f "$device")")" "$version"
+            fi
+        done
+    } | jq -sc --arg vendor "$(dmi sys_vendor)" --arg model "$(dmi product_name)" \
+        '{vendor: $vendor, model: $model, components: .}'
+}
+
+installed_version() {
+    inventory | jq -r --arg component "$1" --arg device "$2" \
+        '.components[] | select(.component == $component and .device == $device) | .version'
+}
+
+steps=$(inventory | curl -fsS -H 'Content-Type: application/json' --data-binary @- "$DRAGONFLY_FIRMWARE_URL")
+count=$(echo "$steps" | jq length)
+echo "Dragonfly planned $count firmware update(s)"
+
+results=""
+i=0
+while [ "$i" -lt "$count" ]; do
+    step=$(echo "$steps" | jq -c ".[$i]")
+    i=$((i + 1))
+    image_id=$(echo "$step" | jq -r .image_id)
+    component=$(echo "$step" | jq -r .component)
+    device=$(echo "$step" | jq -r .device)
+    before=$(echo "$step" | jq -r .from_version)
+    sha256=$(echo "$step" | jq -r '.sha256 // empty')
+    package="$WORK/$image_id.bin"
+    echo "Flashing $component $device: $before -> $(echo "$step" | jq -r .version)"
+
+    success=true
+    message=""
+    if ! curl -fsSL -o "$package" "$(echo "$step" | jq -r .url)"; then
+        success=false
+        message="Failed to download image $image_id"
+    elif [ -n "$sha256" ] && ! echo "$sha256  $package" | sha256sum -c - >/dev/null; then
+        success=false
+        message="Image $image_id doesn't match its sha256"
+    else
+        chmod +x "$package"
+        # shellcheck disable=SC2086
+        if ! "$package" $FLASH_ARGS; then
+            success=false
+            message="The update package for image $image_id failed"
+        fi
+    fi
+
+    after=$(installed_version "$component" "$device")
+    after=${after:-$before}
+    if $success && [ "$after" = "$before" ]; then
+        message="Flashed; the new version shows after a reboot"
+    fi
+    results="$results$(jq -nc \
+        --arg component "$component" --arg device "$device" --argjson image_id "$image_id" \
+        --arg before "$before" --arg after "$after" --argjson success "$success" --arg message "$message" \
+        '{component: $component, device: $device, image_id: $image_id, version_before: $before,
+          version_after: $after, success: $success, message: (if $message == "" then null else $message end)}')
+"
+done
+
+printf '%s' "$results" | jq -sc . | curl -fsS -H 'Content-Type: application/json' --data-binary @- "$DRAGONFLY_FIRMWARE_URL/report"
+rm -rf "$WORK"
+
+# A failed flash fails the workflow, so the pipeline stops before the OS install
+if printf '%s' "$results" | jq -se 'any(.success == false)' >/dev/null; then
+    exit 1
+fi
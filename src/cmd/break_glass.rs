@@ -0,0 +1,34 @@
+use clap::Args;
+use color_eyre::eyre::{eyre, Result};
+
+use dragonfly_server::break_glass::{self, DEFAULT_TTL_MINUTES, MAX_TTL_MINUTES};
+
+#[derive(Args, Debug)]
+pub struct BreakGlassArgs {
+    /// Minutes until the credential expires (at most 60).
+    #[arg(long, default_value_t = DEFAULT_TTL_MINUTES)]
+    pub ttl_minutes: i64,
+}
+
+/// Issue a break-glass credential and print it. Run this where the server keeps its database.
+pub async fn run_break_glass(args: BreakGlassArgs) -> Result<()> {
+    if !(1..=MAX_TTL_MINUTES).contains(&args.ttl_minutes) {
+        return Err(eyre!("--ttl-minutes must be between 1 and {}", MAX_TTL_MINUTES));
+    }
+
+    // Record who asked, as far as the local system can tell us
+    let issued_by = std::env::var("SUDO_USER")
+        .or_else(|_| std::env::var("USER"))
+        .map(|user| format!("{} (cli)", user))
+        .unwrap_or_else(|_| "cli".to_string());
+
+    let credential = break_glass::issue_from_cli(args.ttl_minutes, &issued_by)
+        .await
+        .map_err(|e| eyre!("{:#}", e))?;
+
+    println!("Break-glass admin credential (works once, expires {}):", credential.expires_at.format("%Y-%m-%d %H:%M:%S UTC"));
+    println!("  Username: {}", credential.username);
+    println!("  Password: {}", credential.password);
+    println!("After signing in you must set a new admin password before doing anything else.");
+    Ok(())
+}
@@ -16,6 +16,7 @@ use std::collections::VecDeque;
  // Import task_local
  // Import signal for Ctrl+C
 use tokio::sync::watch; // Import watch
+use serde::{Deserialize, Serialize};
 
 // Import state and globals from server crate
 use dragonfly_server::{
@@ -23,13 +24,23 @@ use dragonfly_server::{
     INSTALL_STATE_REF, 
     EVENT_MANAGER_REF
 };
+use dragonfly_server::status::{self, ClusterConfig};
 
 #[derive(Args, Debug)]
 pub struct InstallArgs {
-    /// Optional: Specify the network interface to use for IP detection.
-    #[arg(long)]
+    /// Optional: Specify the network interface to use for IP detection. This is the provisioning
+    /// interface: DHCP, TFTP and boot artifacts are served on it.
+    #[arg(long, alias = "provisioning-interface")]
     pub interface: Option<String>, // Made public if needed elsewhere, or keep private
 
+    /// Optional: Separate interface to publish the web UI on, for servers with a public NIC.
+    #[arg(long)]
+    pub management_interface: Option<String>,
+
+    /// Optional: IP to publish the web UI on, instead of the management interface's own address.
+    #[arg(long, requires = "management_interface")]
+    pub management_ip: Option<Ipv4Addr>,
+
     /// Optional: Specify the starting IP address offset from the host IP.
     #[arg(long, default_value_t = 1)]
     pub start_offset: u8,
@@ -38,9 +49,141 @@ pub struct InstallArgs {
     #[arg(long, default_value_t = 20)]
     pub max_ip_search: u8,
 
+    /// Optional: Install into an existing Kubernetes cluster using this kubeconfig instead of installing k3s.
+    #[arg(long)]
+    pub kubeconfig: Option<PathBuf>,
+
+    /// Optional: Namespace to deploy Tinkerbell and Dragonfly into.
+    #[arg(long, default_value = "tink")]
+    pub namespace: String,
+
+    /// Optional: Public IP for Tinkerbell and Dragonfly services, instead of probing for a free one.
+    #[arg(long)]
+    pub public_ip: Option<Ipv4Addr>,
+
+    /// Optional: Ignore the checkpoint of an interrupted install and run every phase again.
+    #[arg(long)]
+    pub restart: bool,
+
     // Add other install-specific args here
 }
 
+/// Checkpoint file, overridable with DRAGONFLY_INSTALL_CHECKPOINT
+const CHECKPOINT_FILE: &str = "dragonfly-install-checkpoint.json";
+
+/// Install phases that are recorded once they finish, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Phase {
+    /// k3s installed and its node ready, or the existing cluster reached
+    Cluster,
+    Helm,
+    Tinkerbell,
+    Dragonfly,
+}
+
+/// What an interrupted install had finished, saved after each phase so rerunning
+/// `dragonfly install` resumes instead of reinstalling
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    /// The IP the services were deployed with. Probing again would find it taken by them.
+    bootstrap_ip: Option<Ipv4Addr>,
+    namespace: String,
+    existing_cluster: bool,
+    kubeconfig: Option<PathBuf>,
+    completed: Vec<Phase>,
+}
+
+impl Checkpoint {
+    fn path() -> PathBuf {
+        std::env::var("DRAGONFLY_INSTALL_CHECKPOINT")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(CHECKPOINT_FILE))
+    }
+
+    /// The last install's checkpoint, if it was run with the same settings. Anything else
+    /// starts a fresh one.
+    fn load(args: &InstallArgs) -> Checkpoint {
+        let fresh = Checkpoint {
+            namespace: args.namespace.clone(),
+            existing_cluster: args.kubeconfig.is_some(),
+            ..Default::default()
+        };
+        let path = Self::path();
+        if args.restart {
+            info!("Ignoring any install checkpoint at {:?} (--restart)", path);
+            return fresh;
+        }
+        let Ok(content) = std::fs::read_to_string(&path) else {
+            return fresh;
+        };
+        let checkpoint: Checkpoint = match serde_json::from_str(&content) {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                warn!("Ignoring invalid install checkpoint at {:?}: {}", path, e);
+                return fresh;
+            }
+        };
+        if !checkpoint.same_settings(args) {
+            warn!("The install checkpoint at {:?} was made with different settings; starting over", path);
+            return fresh;
+        }
+        checkpoint
+    }
+
+    /// Whether this install is being run as the checkpointed one was
+    fn same_settings(&self, args: &InstallArgs) -> bool {
+        let same_ip = args.public_ip.is_none() || args.public_ip == self.bootstrap_ip;
+        let same_cluster = match &args.kubeconfig {
+            Some(kubeconfig) => std::fs::canonicalize(kubeconfig).ok() == self.kubeconfig,
+            None => !self.existing_cluster,
+        };
+        self.namespace == args.namespace && same_cluster && same_ip
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = Self::path();
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .wrap_err_with(|| format!("Failed to write install checkpoint to {:?}", path))
+    }
+
+    fn complete(&mut self, phase: Phase) -> Result<()> {
+        if !self.completed.contains(&phase) {
+            self.completed.push(phase);
+        }
+        self.save()
+    }
+
+    /// Whether a phase can be skipped: it finished last time and `verify` finds it still in
+    /// place. A phase that has to run again takes every later phase with it.
+    async fn resume<F, Fut>(&mut self, phase: Phase, verify: F) -> bool
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = bool>,
+    {
+        let Some(index) = self.completed.iter().position(|done| *done == phase) else {
+            return false;
+        };
+        if verify().await {
+            info!("Resuming install: {:?} already done", phase);
+            return true;
+        }
+        warn!("{:?} was done by the last install but is no longer in place; redoing it and everything after", phase);
+        self.completed.truncate(index);
+        false
+    }
+
+    /// Forget the checkpoint once the install has finished
+    fn clear() {
+        let path = Self::path();
+        if let Err(e) = std::fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove install checkpoint {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
 // Helper function to update the global installation state and send SSE event
 async fn update_install_state(new_state: InstallationState) {
     info!("[update_install_state] Called with state: {:?}", new_state);
@@ -149,7 +292,8 @@ pub async fn run_install(args: InstallArgs, mut shutdown_rx: watch::Receiver<()>
 
     // Only show sudo message if passwordless sudo is not available
     // Note: Need to check passwordless_sudo before this point
-    let passwordless_sudo = check_passwordless_sudo().await;
+    // Existing clusters don't need sudo, as nothing is installed on this host
+    let passwordless_sudo = args.kubeconfig.is_some() || check_passwordless_sudo().await;
     if !passwordless_sudo {
         println!("🔐 Meanwhile, you'll need to enter your sudo password for the next stages of the installer.");
         // Handle the Result from sudo_prompt
@@ -159,6 +303,11 @@ pub async fn run_install(args: InstallArgs, mut shutdown_rx: watch::Receiver<()>
     // Set initial state (WaitingSudo) - use the helper
     // This should now reliably update the global state and send the event because we waited
     update_install_state(InstallationState::WaitingSudo).await;
+
+    let mut checkpoint = Checkpoint::load(&args);
+    if !checkpoint.completed.is_empty() {
+        println!("♻️  Resuming the last install, which got as far as {:?}. Pass --restart to start over.", checkpoint.completed);
+    }
     
     let install_handle = tokio::spawn(async move {
         // Now `shutdown_rx` is moved into this task
@@ -173,36 +322,102 @@ pub async fn run_install(args: InstallArgs, mut shutdown_rx: watch::Receiver<()>
                 update_install_state(InstallationState::DetectingNetwork).await;
                 let (host_ip, _netmask, network) = get_host_ip_and_mask(args.interface.as_deref())
                     .wrap_err("Failed to determine host IP (required for install)")?;
+                let management = match &args.management_interface {
+                    Some(name) => {
+                        let (management_host_ip, _netmask, management_network) = get_host_ip_and_mask(Some(name))
+                            .wrap_err("Failed to determine management interface IP")?;
+                        check_separate_networks(args.interface.as_deref(), network, name, management_network)?;
+                        let management_ip = args.management_ip.unwrap_or(management_host_ip);
+                        if !management_network.contains(management_ip) {
+                            bail!("Management IP {} is not on {}'s network {}", management_ip, name, management_network);
+                        }
+                        info!("Dual-homed install: provisioning on {}, web UI on {} ({})", network, management_ip, name);
+                        Some(management_ip)
+                    }
+                    None => None,
+                };
                 
                 // --- 2. Find Available Floating IP --- 
-                let bootstrap_ip = find_available_ip(host_ip, network, args.start_offset, args.max_ip_search)
-                    .await
-                    .wrap_err("Failed to find an available IP address for the bootstrap node")?;
+                let bootstrap_ip = match args.public_ip.or(checkpoint.bootstrap_ip) {
+                    Some(ip) => {
+                        info!("Using public IP {}", ip);
+                        ip
+                    }
+                    None => find_available_ip(host_ip, network, args.start_offset, args.max_ip_search)
+                        .await
+                        .wrap_err("Failed to find an available IP address for the bootstrap node")?,
+                };
+                checkpoint.bootstrap_ip = Some(bootstrap_ip);
+                checkpoint.save()?;
                 
-                // --- 3. Install k3s --- 
-                update_install_state(InstallationState::InstallingK3s).await;
-                install_k3s().await.wrap_err("Failed to set up k3s")?;
-
-                // --- 4. Configure kubectl --- 
-                let kubeconfig_path = configure_kubectl().await.wrap_err("Failed to configure kubectl")?;
-                std::env::set_var("KUBECONFIG", kubeconfig_path.to_string_lossy().to_string());
-
-                // --- 5. Wait for Node Ready --- 
-                update_install_state(InstallationState::WaitingK3s).await;
-                wait_for_node_ready(&kubeconfig_path).await.wrap_err("Timed out waiting for Kubernetes node")?;
+                let existing_cluster = args.kubeconfig.is_some();
+                let resumed_kubeconfig = checkpoint.kubeconfig.clone();
+                let cluster_done = match &resumed_kubeconfig {
+                    Some(path) => checkpoint.resume(Phase::Cluster, || cluster_reachable(path)).await,
+                    None => false,
+                };
+                let kubeconfig_path = if let (true, Some(kubeconfig_path)) = (cluster_done, resumed_kubeconfig) {
+                    std::env::set_var("KUBECONFIG", kubeconfig_path.to_string_lossy().to_string());
+                    kubeconfig_path
+                } else if let Some(kubeconfig) = &args.kubeconfig {
+                    // --- 3-5. Use the existing cluster instead of k3s --- 
+                    update_install_state(InstallationState::ConnectingCluster).await;
+                    let kubeconfig_path = use_existing_cluster(kubeconfig).await.wrap_err("Failed to connect to existing cluster")?;
+                    std::env::set_var("KUBECONFIG", kubeconfig_path.to_string_lossy().to_string());
+                    kubeconfig_path
+                } else {
+                    // --- 3. Install k3s --- 
+                    update_install_state(InstallationState::InstallingK3s).await;
+                    install_k3s().await.wrap_err("Failed to set up k3s")?;
+
+                    // --- 4. Configure kubectl --- 
+                    let kubeconfig_path = configure_kubectl().await.wrap_err("Failed to configure kubectl")?;
+                    std::env::set_var("KUBECONFIG", kubeconfig_path.to_string_lossy().to_string());
+
+                    // --- 5. Wait for Node Ready --- 
+                    update_install_state(InstallationState::WaitingK3s).await;
+                    wait_for_node_ready(&kubeconfig_path).await.wrap_err("Timed out waiting for Kubernetes node")?;
+                    kubeconfig_path
+                };
+                if !cluster_done {
+                    checkpoint.kubeconfig = Some(kubeconfig_path.clone());
+                    checkpoint.complete(Phase::Cluster)?;
+                }
 
                 // --- 6. Install Helm --- 
-                install_helm().await.wrap_err("Failed to set up Helm")?;
+                if !checkpoint.resume(Phase::Helm, || async { is_command_present("helm") }).await {
+                    install_helm().await.wrap_err("Failed to set up Helm")?;
+                    checkpoint.complete(Phase::Helm)?;
+                }
 
                 // --- 7. Install Tinkerbell Stack --- 
-                update_install_state(InstallationState::DeployingTinkerbell).await;
-                install_tinkerbell_stack(bootstrap_ip, network, &kubeconfig_path).await.wrap_err("Failed to install Tinkerbell stack")?;
+                if !checkpoint.resume(Phase::Tinkerbell, || async { helm_release_exists("tink-stack", &args.namespace) }).await {
+                    update_install_state(InstallationState::DeployingTinkerbell).await;
+                    install_tinkerbell_stack(bootstrap_ip, network, &kubeconfig_path, &args.namespace, existing_cluster).await.wrap_err("Failed to install Tinkerbell stack")?;
+                    checkpoint.complete(Phase::Tinkerbell)?;
+                }
 
                 // --- 8. Install Dragonfly Helm Chart (if applicable) --- 
-                update_install_state(InstallationState::DeployingDragonfly).await;
-                install_dragonfly_chart(bootstrap_ip, &kubeconfig_path).await.wrap_err("Failed to install Dragonfly chart")?;
+                if !checkpoint.resume(Phase::Dragonfly, || async { helm_release_exists("dragonfly", &args.namespace) }).await {
+                    update_install_state(InstallationState::DeployingDragonfly).await;
+                    install_dragonfly_chart(bootstrap_ip, management, &kubeconfig_path, &args.namespace).await.wrap_err("Failed to install Dragonfly chart")?;
+                    checkpoint.complete(Phase::Dragonfly)?;
+                }
+
+                // Record the cluster so status checks and the server can find it later
+                if existing_cluster || args.namespace != "tink" || management.is_some() {
+                    status::save_cluster_config(&ClusterConfig {
+                        kubeconfig: Some(kubeconfig_path.to_string_lossy().to_string()),
+                        namespace: args.namespace.clone(),
+                        existing_cluster,
+                        provisioning_interface: management.and(args.interface.clone()),
+                        management_interface: args.management_interface.clone(),
+                        ..Default::default()
+                    }).wrap_err("Failed to save cluster config")?;
+                }
 
                 // --- 9. Mark as Ready --- 
+                Checkpoint::clear();
                 update_install_state(InstallationState::Ready).await;
                 
                 let elapsed = start_time.elapsed();
@@ -212,7 +427,7 @@ pub async fn run_install(args: InstallArgs, mut shutdown_rx: watch::Receiver<()>
                 tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
                 
                 // --- 11. Send browser redirect event ---
-                send_redirect_event(bootstrap_ip).await;
+                send_redirect_event(management.unwrap_or(bootstrap_ip)).await;
                 
                 // --- 12. Automatically shut down the installer after 2 more seconds ---
                 tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
@@ -242,6 +457,7 @@ pub async fn run_install(args: InstallArgs, mut shutdown_rx: watch::Receiver<()>
             error!("Background installation task failed: {:#}", e);
             update_install_state(InstallationState::Failed(e.to_string())).await;
             eprintln!("Installation failed in background: {}", e);
+            eprintln!("Run `dragonfly install` again to pick up where it stopped.");
              // Propagate error to main
              return Err(e);
         }
@@ -426,6 +642,18 @@ fn get_host_ip_and_mask(interface_name: Option<&str>) -> Result<(Ipv4Addr, Ipv4A
     }
 }
 
+// Dual-homed installs must keep provisioning traffic (DHCP, TFTP, artifacts) and the web UI on different networks
+fn check_separate_networks(provisioning_interface: Option<&str>, provisioning: Ipv4Network, management_interface: &str, management: Ipv4Network) -> Result<()> {
+    if provisioning_interface == Some(management_interface) {
+        bail!("The provisioning and management interfaces must be different (both are '{}')", management_interface);
+    }
+    if provisioning.contains(management.network()) || management.contains(provisioning.network()) {
+        bail!("Provisioning network {} and management network {} ({}) overlap; choose interfaces on separate networks",
+              provisioning, management, management_interface);
+    }
+    Ok(())
+}
+
 // Check if an IP is private (RFC1918) or link-local
 fn is_private_or_local_ip(ip: Ipv4Addr) -> bool {
     ip.is_private() || ip.is_link_local() || ip.is_loopback() || ip.is_unspecified()
@@ -718,6 +946,45 @@ async fn configure_kubectl() -> Result<PathBuf> {
     Ok(dest_path)
 }
 
+// Validate a user-provided kubeconfig and return its absolute path
+async fn use_existing_cluster(kubeconfig: &PathBuf) -> Result<PathBuf> {
+    info!("Using existing Kubernetes cluster from {:?}", kubeconfig);
+    let kubeconfig_path = fs::canonicalize(kubeconfig).await
+        .wrap_err_with(|| format!("Kubeconfig not found at {:?}", kubeconfig))?;
+
+    let output = Command::new("kubectl")
+        .args(["cluster-info"])
+        .env("KUBECONFIG", &kubeconfig_path)
+        .output()
+        .wrap_err("Failed to run kubectl. Is kubectl installed?")?;
+
+    if !output.status.success() {
+        bail!("Cluster at {:?} is not reachable: {}", kubeconfig_path, String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    debug!("Existing cluster is reachable");
+    Ok(kubeconfig_path)
+}
+
+// Whether the cluster behind a kubeconfig answers, e.g. to check that k3s is still up
+async fn cluster_reachable(kubeconfig_path: &PathBuf) -> bool {
+    Command::new("kubectl")
+        .args(["cluster-info"])
+        .env("KUBECONFIG", kubeconfig_path)
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+// Whether a Helm release is installed in the namespace
+fn helm_release_exists(release: &str, namespace: &str) -> bool {
+    Command::new("helm")
+        .args(["list", "-n", namespace, "--filter", release, "--short"])
+        .output()
+        .map(|output| output.status.success() && !String::from_utf8_lossy(&output.stdout).trim().is_empty())
+        .unwrap_or(false)
+}
+
 async fn wait_for_node_ready(kubeconfig_path: &PathBuf) -> Result<()> {
     info!("Waiting for Kubernetes node to become ready...");
     let max_wait = std::time::Duration::from_secs(300); // 5 minutes timeout
@@ -847,7 +1114,7 @@ async fn install_helm() -> Result<()> {
     Ok(())
 }
 
-async fn install_dragonfly_chart(bootstrap_ip: Ipv4Addr, kubeconfig_path: &PathBuf) -> Result<()> {
+async fn install_dragonfly_chart(bootstrap_ip: Ipv4Addr, management_ip: Option<Ipv4Addr>, kubeconfig_path: &PathBuf, namespace: &str) -> Result<()> {
     // --- Clone the GitHub repository for the Helm chart ---
     info!("Fetching Dragonfly Helm charts from GitHub...");
     
@@ -876,12 +1143,21 @@ async fn install_dragonfly_chart(bootstrap_ip: Ipv4Addr, kubeconfig_path: &PathB
     }
     
     // --- Generate values.yaml ---
-    let values_content = format!(
-        r#"global:
+    // On dual-homed servers the UI is published on the management IP, and the provisioning IP only
+    // serves what booting machines need
+    let values_content = match management_ip {
+        Some(management_ip) => format!(
+            r#"global:
+    publicIP: {management_ip}
+    provisioningIP: {bootstrap_ip}
+"#,
+        ),
+        None => format!(
+            r#"global:
     publicIP: {bootstrap_ip}
 "#,
-        bootstrap_ip = bootstrap_ip,
-    );
+        ),
+    };
 
     let values_path = PathBuf::from("values.yaml");
     fs::write(&values_path, values_content).await
@@ -893,7 +1169,7 @@ async fn install_dragonfly_chart(bootstrap_ip: Ipv4Addr, kubeconfig_path: &PathB
         "upgrade", "--install", "dragonfly",
         chart_path.to_str().ok_or_else(|| color_eyre::eyre::eyre!("Chart path is not valid UTF-8"))?,
         "--create-namespace",
-        "--namespace", "tink",
+        "--namespace", namespace,
         "--wait",
         "--timeout", "10m",
         "-f", values_path.to_str().ok_or_else(|| color_eyre::eyre::eyre!("values.yaml path is not valid UTF-8"))?
@@ -904,16 +1180,9 @@ async fn install_dragonfly_chart(bootstrap_ip: Ipv4Addr, kubeconfig_path: &PathB
     Ok(())
 }
 
-async fn install_tinkerbell_stack(bootstrap_ip: Ipv4Addr, network: Ipv4Network, kubeconfig_path: &PathBuf) -> Result<()> {
+async fn install_tinkerbell_stack(bootstrap_ip: Ipv4Addr, network: Ipv4Network, kubeconfig_path: &PathBuf, namespace: &str, existing_cluster: bool) -> Result<()> {
     // Check if the Tinkerbell stack is already installed
-    let release_exists = {
-        let release_check = Command::new("helm")
-            .args(["list", "-n", "tink", "--filter", "tink-stack", "--short"])
-            .output()?;
-            
-        release_check.status.success() && 
-        !String::from_utf8_lossy(&release_check.stdout).trim().is_empty()
-    };
+    let release_exists = helm_release_exists("tink-stack", namespace);
     
     // Log whether we're installing or upgrading
     if release_exists {
@@ -953,7 +1222,10 @@ async fn install_tinkerbell_stack(bootstrap_ip: Ipv4Addr, network: Ipv4Network,
         .map(|s| s.to_string())
         .collect();
     
-    if pod_cidrs.is_empty() {
+    if pod_cidrs.is_empty() && existing_cluster {
+        // Many CNIs (Calico, Cilium, cloud providers) don't populate spec.podCIDR
+        warn!("Could not detect Pod CIDR on the existing cluster; only the host network will be trusted as a proxy");
+    } else if pod_cidrs.is_empty() {
         bail!("Failed to detect Pod CIDR. This is required for Tinkerbell installation.\nVerify the Kubernetes node is fully initialized with 'kubectl --kubeconfig={} get nodes -o wide'", 
               kubeconfig_path.display());
     }
@@ -1047,7 +1319,7 @@ stack:
         "upgrade", "--install", "tink-stack",
         chart_path.to_str().ok_or_else(|| color_eyre::eyre::eyre!("Chart path is not valid UTF-8"))?,
         "--create-namespace",
-        "--namespace", "tink",
+        "--namespace", namespace,
         "--wait",
         "--timeout", "10m",
         "-f", values_path.to_str().ok_or_else(|| color_eyre::eyre::eyre!("values.yaml path is not valid UTF-8"))?,
@@ -1058,7 +1330,7 @@ stack:
 
     // Verify the deployment
     let deployment_check = Command::new("kubectl")
-        .args(["get", "pods", "-n", "tink", "--no-headers"])
+        .args(["get", "pods", "-n", namespace, "--no-headers"])
         .env("KUBECONFIG", kubeconfig_path)
         .output()
         .wrap_err("Failed to check deployment status")?;
@@ -1071,8 +1343,8 @@ stack:
            !pods_output.contains("CrashLoopBackOff") {
             info!("Dragonfly Tinkerbell stack is running properly");
         } else {
-            warn!("Dragonfly Tinkerbell stack deployed but some pods may not be ready. Check with 'kubectl --kubeconfig={} get pods -n tink'", 
-                  kubeconfig_path.display());
+            warn!("Dragonfly Tinkerbell stack deployed but some pods may not be ready. Check with 'kubectl --kubeconfig={} get pods -n {}'", 
+                  kubeconfig_path.display(), namespace);
         }
     }
 
@@ -1128,3 +1400,42 @@ async fn check_passwordless_sudo() -> bool {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_checkpoint_resumes_what_is_still_in_place() {
+        let mut checkpoint = Checkpoint { completed: vec![Phase::Cluster, Phase::Helm, Phase::Tinkerbell], ..Default::default() };
+        assert!(checkpoint.resume(Phase::Cluster, || async { true }).await);
+        assert!(!checkpoint.resume(Phase::Dragonfly, || async { true }).await);
+        // Helm went missing, so it and the Tinkerbell stack installed with it run again
+        assert!(!checkpoint.resume(Phase::Helm, || async { false }).await);
+        assert_eq!(checkpoint.completed, [Phase::Cluster]);
+    }
+
+    #[test]
+    fn test_checkpoint_needs_the_same_settings() {
+        let args = |namespace: &str, public_ip: Option<Ipv4Addr>| InstallArgs {
+            interface: None,
+            management_interface: None,
+            management_ip: None,
+            start_offset: 1,
+            max_ip_search: 20,
+            kubeconfig: None,
+            namespace: namespace.to_string(),
+            public_ip,
+            restart: false,
+        };
+        let checkpoint = Checkpoint {
+            bootstrap_ip: Some(Ipv4Addr::new(10, 0, 0, 2)),
+            namespace: "tink".to_string(),
+            ..Default::default()
+        };
+        assert!(checkpoint.same_settings(&args("tink", None)));
+        assert!(checkpoint.same_settings(&args("tink", Some(Ipv4Addr::new(10, 0, 0, 2)))));
+        assert!(!checkpoint.same_settings(&args("tink", Some(Ipv4Addr::new(10, 0, 0, 3)))));
+        assert!(!checkpoint.same_settings(&args("provisioning", None)));
+        assert!(!Checkpoint { existing_cluster: true, ..checkpoint }.same_settings(&args("tink", None)));
+    }
+}
@@ -1,5 +1,6 @@
 // Declare the install subcommand module
 pub mod install;
+pub mod break_glass;
 
 // Declare other subcommand modules as you create them
 // pub mod server;
@@ -8,7 +8,7 @@ use clap::{Parser, Subcommand};
 use color_eyre::eyre::Result;
 use tracing::{error, info, Level};
 // Updated imports: Add EnvFilter
-use tracing_subscriber::{fmt, prelude::*, registry, EnvFilter};
+use tracing_subscriber::EnvFilter;
 use tokio::sync::watch; // For shutdown signal
 use clap::CommandFactory; // Needed for print_help
 
@@ -16,12 +16,11 @@ use clap::CommandFactory; // Needed for print_help
 mod cmd;
 // Reference the actual install args from its module
 use cmd::install::InstallArgs;
-
-// Import necessary file handling modules
-use std::io::stderr; // For foreground logging
+use cmd::break_glass::BreakGlassArgs;
 
 // Import status module and run function from server crate
-use dragonfly_server::{status, run as run_server, database_exists}; // Import run and database_exists
+use dragonfly_server::{logging, status, run as run_server, database_exists}; // Import run and database_exists
+use dragonfly_server::migrations::MigrationState;
 
 // --- Structs and Enums for Default Invocation Logic --- 
 
@@ -88,11 +87,14 @@ struct Cli {
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Runs the main Dragonfly server (default action).
+    #[command(alias = "serve")]
     Server(ServerArgs), // Add arguments struct if needed later
-    /// Installs and configures k3s and the Tinkerbell stack.
+    /// Installs and configures k3s (or uses an existing cluster) and the Tinkerbell stack.
     Install(InstallArgs), // Use the actual InstallArgs from cmd::install
     /// Runs the setup wizard for Dragonfly.
     Setup(SetupArgs),
+    /// Prints a one-time, short-lived local admin login for when normal sign-in is unavailable.
+    BreakGlass(BreakGlassArgs),
     // Add Agent command later if needed
     // Agent(AgentArgs),
 }
@@ -100,7 +102,16 @@ enum Commands {
 // Placeholder arguments for Server (can be empty if no args needed yet)
 // This could eventually move to `src/cmd/server.rs` if server logic is extracted
 #[derive(Parser, Debug)]
-struct ServerArgs {}
+struct ServerArgs {
+    /// Serve the web UI and API only, with every background task (polling, reconcilers,
+    /// discovery, webhooks) disabled, to inspect and repair state after a bad upgrade.
+    #[arg(long, default_value_t = false)]
+    safe_mode: bool,
+    /// List the database migrations and whether each is applied, then exit without starting.
+    /// Exits non-zero when any is pending, failed or changed since it was applied.
+    #[arg(long, default_value_t = false)]
+    check_migrations: bool,
+}
 
 // Setup command arguments (empty for now)
 #[derive(Parser, Debug)]
@@ -143,9 +154,11 @@ async fn main() -> Result<()> {
         }
     };
 
-    // Initialize the global logger ONCE
-    // TODO: Add file logging here maybe, depending on mode?
-    registry().with(filter).with(fmt::layer().with_writer(stderr)).init();
+    // Initialize the global logger ONCE. DRAGONFLY_LOG_FORMAT and DRAGONFLY_SYSLOG choose where lines go.
+    if let Err(e) = logging::init(filter) {
+        eprintln!("Invalid logging configuration: {}", e);
+        std::process::exit(1);
+    }
 
     info!("Global logger initialized."); // Should appear based on filter settings
     // --- End Centralized Logging Initialization ---
@@ -178,8 +191,36 @@ async fn main() -> Result<()> {
                  // let _ = shutdown_tx.send(()); // Optional: Signal server to stop
             }
         }
+        Some(Commands::BreakGlass(args)) => {
+            if let Err(e) = cmd::break_glass::run_break_glass(args).await {
+                error!("Failed to issue break-glass credential: {:#}", e);
+                eprintln!("Error issuing break-glass credential: {}", e);
+                std::process::exit(1);
+            }
+        }
         // Separate Server command logic
-        Some(Commands::Server(_args)) => {
+        Some(Commands::Server(args)) => {
+            if args.check_migrations {
+                match dragonfly_server::migrations::check().await {
+                    Ok(statuses) => {
+                        for status in &statuses {
+                            println!("{} {:<32} {}", status.version, status.description, status.state);
+                        }
+                        let current = statuses.iter().all(|status| status.state == MigrationState::Applied);
+                        println!("{}", if current { "The database is up to date." } else { "The database is not up to date." });
+                        std::process::exit(if current { 0 } else { 1 });
+                    },
+                    Err(e) => {
+                        eprintln!("Error checking migrations: {}", e);
+                        std::process::exit(2);
+                    },
+                }
+            }
+            if args.safe_mode {
+                // Read by the server the same way as demo mode
+                std::env::set_var("DRAGONFLY_SAFE_MODE", "true");
+                println!("Safe mode: background tasks are disabled. Restart without --safe-mode to resume normal operation.");
+            }
             info!("Checking Dragonfly installation status for server mode...");
             // Use the comprehensive installation check from the server crate
             let is_installed = dragonfly_server::is_dragonfly_installed().await;
@@ -236,7 +277,9 @@ async fn main() -> Result<()> {
         Some(Commands::Setup(_)) | None => {
             // Scenario A: Handle default 'dragonfly' invocation (and potentially Setup)
             // Gather status first
-            let db_exists = dragonfly_server::database_exists().await;
+            // Installs into an existing cluster have no local database, so the cluster config counts too
+            let db_exists = dragonfly_server::database_exists().await
+                || status::load_cluster_config().map_or(false, |c| c.existing_cluster);
             
             // Perform k8s checks only if DB exists
             let mut k8s_conn_status = Err("Skipped (DB does not exist)".to_string());
@@ -0,0 +1,35 @@
+//! Recovery from injected faults. Run with `cargo test --features chaos --test chaos`.
+#![cfg(feature = "chaos")]
+
+use dragonfly_server::chaos::{self, Faults};
+use dragonfly_server::event_manager::EventManager;
+use dragonfly_server::status;
+
+// One test, as the faults are shared by the whole process
+#[tokio::test]
+async fn test_recovers_from_injected_faults() {
+    // The first two Kubernetes calls fail, and the next is really attempted
+    chaos::set(Faults { tinkerbell_failures: 2, ..Default::default() });
+    for _ in 0..2 {
+        let error = status::check_kubernetes_connectivity().await.unwrap_err();
+        assert!(error.to_string().contains("injected fault"), "{}", error);
+    }
+    if let Err(error) = status::check_kubernetes_connectivity().await {
+        assert!(!error.to_string().contains("injected fault"), "{}", error);
+    }
+    assert_eq!(chaos::status().injected.tinkerbell_failures, 2);
+
+    // Only the matching event is lost; subscribers carry on with the next
+    chaos::set(Faults { dropped_events: 1, drop_event_prefix: Some("machine_updated".to_string()), ..Default::default() });
+    let events = EventManager::new();
+    let mut subscription = events.subscribe();
+    events.send("machine_discovered:a".to_string()).unwrap();
+    assert_eq!(events.send("machine_updated:a".to_string()).unwrap(), 0);
+    events.send("machine_updated:b".to_string()).unwrap();
+    assert_eq!(subscription.recv().await.unwrap(), "machine_discovered:a");
+    assert_eq!(subscription.recv().await.unwrap(), "machine_updated:b");
+    assert_eq!(chaos::status().injected.dropped_events, 1);
+
+    chaos::clear();
+    assert_eq!(chaos::status().faults, Faults::default());
+}